@@ -0,0 +1,71 @@
+/*
+ * The interface for frawk plugins, which are shared libraries loaded with
+ * `frawk --plugin=LIB` that add builtin functions.
+ *
+ * A plugin exports frawk_plugin_init, which registers each of its functions
+ * with the registrar it is passed and returns 0 on success. For example:
+ *
+ *     #include "frawk_plugin.h"
+ *
+ *     static void twice(const frawk_value *args, size_t nargs, frawk_value *ret) {
+ *         ret->tag = FRAWK_INT;
+ *         ret->i = 2 * args[0].i;
+ *     }
+ *
+ *     int frawk_plugin_init(const frawk_registrar *r) {
+ *         if (r->abi_version != FRAWK_ABI_VERSION) return 1;
+ *         uint32_t args[] = {FRAWK_INT};
+ *         return r->register_function(r->ctx, "twice", args, 1, FRAWK_INT, twice);
+ *     }
+ *
+ * Arguments are converted to the types given when the function was registered
+ * before it is called. A function may return a value of another type, which is
+ * converted the way awk converts values. Strings passed to a function are only
+ * valid until it returns, and are not NUL-terminated; they may hold any bytes,
+ * including invalid UTF-8, as may strings that a function returns. A string
+ * returned by a function must stay valid until the function returns, after
+ * which frawk copies it; a buffer local to the calling thread is enough, as
+ * functions may be called from several threads at once when frawk runs in
+ * parallel.
+ */
+#ifndef FRAWK_PLUGIN_H
+#define FRAWK_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define FRAWK_ABI_VERSION 1
+
+/* At most this many arguments are supported. */
+#define FRAWK_MAX_ARGS 8
+
+enum frawk_type {
+    FRAWK_INT = 0,
+    FRAWK_FLOAT = 1,
+    FRAWK_STR = 2,
+};
+
+typedef struct {
+    /* One of the frawk_type values; only the matching field is set. */
+    uint32_t tag;
+    int64_t i;
+    double f;
+    const char *s;
+    size_t len;
+} frawk_value;
+
+typedef void (*frawk_function)(const frawk_value *args, size_t nargs,
+                               frawk_value *ret);
+
+typedef struct {
+    uint32_t abi_version;
+    void *ctx;
+    /* Returns 0 on success, or nonzero if the name is already taken or the
+     * signature is invalid. */
+    int (*register_function)(void *ctx, const char *name, const uint32_t *args,
+                             size_t nargs, uint32_t ret, frawk_function f);
+} frawk_registrar;
+
+int frawk_plugin_init(const frawk_registrar *registrar);
+
+#endif
@@ -13,15 +13,17 @@
 ///    actions to execute when the input matches that pattern is desugared in this module. We do
 ///    not handle it specially.
 ///
-///    TODO It is not clear that this is the right move long-term: lots of regex implementations
-///    (like HyperScan, or BurntSushi's engine in use here) achieve higher throughput by matching a
-///    string against several patterns at once. There is probably a transormation we could do here
-///    to take advantage of that, but it would probably involve building out def-use chains (which
-///    we currently don't do), and we'd want to verify that performance didn't degrade when the
-///    patterns are _not sparse_ in the input.
+///    The one exception is a run of rules whose patterns are all regex literals matched against
+///    `$0`. Regex engines (like BurntSushi's engine in use here) achieve higher throughput by
+///    matching a string against several patterns at once, so we match `$0` against all of the
+///    patterns in the run with a single `RegexSet` and have each rule check its bit in the result.
+///    This is only valid if the actions in the run cannot change `$0`, which we check
+///    conservatively.
 use crate::arena::{self, Arena};
-use crate::builtins::Function;
+use crate::builtins::{Bitwise, Function, Native, Variable};
 use crate::common::{Either, FileSpec, Stage};
+use crate::lexer::SourceLoc;
+use std::convert::TryFrom;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Unop {
@@ -45,6 +47,16 @@ pub struct FunDec<'a, 'b, I> {
     pub body: &'a Stmt<'a, 'b, I>,
 }
 
+/// The kinds of toplevel items in a program, for saying which one an error occurred in.
+#[derive(Debug, Clone)]
+pub enum Rule<I> {
+    Begin,
+    Prepare,
+    End,
+    Pattern,
+    Function(I),
+}
+
 pub enum Pattern<'a, 'b, I> {
     Null,
     Bool(&'a Expr<'a, 'b, I>),
@@ -57,7 +69,14 @@ pub struct Prog<'a, 'b, I> {
 
     // FS
     pub field_sep: Option<&'b [u8]>,
+    // RS
+    pub record_sep: Option<&'b [u8]>,
     pub prelude_vardecs: Vec<(I, &'a Expr<'a, 'b, I>)>,
+    // Assignments of the form var=value among the input files, along with their position in the
+    // list of operands.
+    pub operand_vardecs: Vec<(usize, I, &'a Expr<'a, 'b, I>)>,
+    // The input files, along with their position in the list of operands.
+    pub operand_files: Vec<(usize, &'b str)>,
     // OFS
     pub output_sep: Option<&'b [u8]>,
     // ORS
@@ -67,9 +86,25 @@ pub struct Prog<'a, 'b, I> {
     pub prepare: arena::Vec<'a, &'a Stmt<'a, 'b, I>>,
     pub end: arena::Vec<'a, &'a Stmt<'a, 'b, I>>,
     pub pats: arena::Vec<'a, (Pattern<'a, 'b, I>, Option<&'a Stmt<'a, 'b, I>>)>,
+    // The source line on which each BEGIN block, END block and pattern starts.
+    pub begin_lines: Vec<usize>,
+    pub end_lines: Vec<usize>,
+    pub pat_lines: Vec<usize>,
+    // Where each toplevel item starts, in the order they appear in the program.
+    pub rules: Vec<(SourceLoc, Rule<I>)>,
+    // Mark the start of each pattern and action with a ProfileRule call, for --profile.
+    pub profile_rules: bool,
     pub stage: Stage<()>,
     pub argv: Vec<&'b str>,
     pub parse_header: bool,
+    // Set when the input format names its own columns (e.g. JSON), which puts FI in scope even
+    // without -H.
+    pub input_names_columns: bool,
+    // Functions registered by a program embedding frawk, which are called like builtins.
+    pub native_functions: Vec<(I, Native)>,
+    // Keep every global variable in a register of its own, so that an embedding program can read
+    // its final value.
+    pub export_globals: bool,
 }
 
 fn parse_header<'a, 'b, I: From<&'b str> + Clone>(
@@ -120,11 +155,122 @@ fn parse_header<'a, 'b, I: From<&'b str> + Clone>(
     )));
 }
 
+// Illegal frawk identifiers used to track which var=value operands have been performed.
+const NEXT_OPERAND: &str = "--next-operand";
+const FILE_OPERAND: &str = "--file-operand";
+
+// The smallest run of regex rules that we match with a single regex set; for fewer rules than this
+// matching each pattern separately is about as fast.
+const MIN_REGEX_SET_RULES: usize = 4;
+// Matches are reported as bits in an integer.
+const MAX_REGEX_SET_RULES: usize = 63;
+
+/// If `pat` matches `$0` against a regex literal, return the regex.
+fn record_regex<'a, 'b, I>(pat: &Pattern<'a, 'b, I>) -> Option<&'b [u8]> {
+    use Expr::*;
+    match pat {
+        Pattern::Bool(PatLit(re)) => Some(re),
+        Pattern::Bool(Binop(
+            self::Binop::IsMatch,
+            Unop(self::Unop::Column, ILit(0)),
+            PatLit(re),
+        )) => Some(re),
+        _ => None,
+    }
+}
+
+/// Whether assigning to `e` might change `$0`: that is, whether it is a field or NF.
+fn is_record_lvalue<'a, 'b, I: Clone>(e: &Expr<'a, 'b, I>) -> bool
+where
+    Variable: TryFrom<I>,
+{
+    match e {
+        Expr::Unop(Unop::Column, _) => true,
+        Expr::Var(v) => matches!(Variable::try_from(v.clone()), Ok(Variable::NF)),
+        _ => false,
+    }
+}
+
+/// Whether evaluating `e` might change `$0`. Calls to user-defined functions are assumed to.
+fn expr_may_set_record<'a, 'b, I: Clone>(e: &Expr<'a, 'b, I>) -> bool
+where
+    Function: TryFrom<I>,
+    Variable: TryFrom<I>,
+{
+    use Expr::*;
+    let any = |es: &[&Expr<'a, 'b, I>]| es.iter().any(|e| expr_may_set_record(e));
+    match e {
+        ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | Var(_) | Cond(_) => false,
+        // Reading the next record happens at the top of the main loop.
+        ReadStdin => true,
+        Unop(_, e) => expr_may_set_record(e),
+        Binop(_, l, r) | Index(l, r) | And(l, r) | Or(l, r) => any(&[l, r]),
+        ITE(c, t, f) => any(&[c, t, f]),
+        Assign(l, r) | AssignOp(l, _, r) => is_record_lvalue(l) || any(&[l, r]),
+        Inc { x, .. } => is_record_lvalue(x) || expr_may_set_record(x),
+        Getline { into, from, .. } => {
+            into.is_none_or(|into| is_record_lvalue(into) || expr_may_set_record(into))
+                || from.is_some_and(|from| expr_may_set_record(from))
+        }
+        Call(f, args) => {
+            let f = match f {
+                Either::Left(name) => match Function::try_from(name.clone()) {
+                    Ok(f) => f,
+                    Err(_) => return true,
+                },
+                Either::Right(f) => *f,
+            };
+            // sub and gsub modify their third argument, which defaults to $0.
+            let modified = match f {
+                Function::Sub | Function::GSub => args.get(2).is_none_or(|e| is_record_lvalue(e)),
+                _ => false,
+            };
+            modified || any(args)
+        }
+    }
+}
+
+/// Whether running `stmt` might change `$0`. See `expr_may_set_record`.
+fn may_set_record<'a, 'b, I: Clone>(stmt: &Stmt<'a, 'b, I>) -> bool
+where
+    Function: TryFrom<I>,
+    Variable: TryFrom<I>,
+{
+    use Stmt::*;
+    let expr = |e: &self::Expr<'a, 'b, I>| expr_may_set_record(e);
+    let out = |o: &Option<(&self::Expr<'a, 'b, I>, FileSpec)>| o.is_some_and(|(e, _)| expr(e));
+    match stmt {
+        StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile => false,
+        Expr(e) => expr(e),
+        Block(stmts) => stmts.iter().any(|s| may_set_record(s)),
+        Print(args, o) => args.iter().any(|e| expr(e)) || out(o),
+        Printf(fmt, args, o) => expr(fmt) || args.iter().any(|e| expr(e)) || out(o),
+        If(c, t, f) => expr(c) || may_set_record(t) || f.is_some_and(may_set_record),
+        For(init, cond, update, body) => {
+            init.is_some_and(may_set_record)
+                || cond.is_some_and(expr)
+                || update.is_some_and(may_set_record)
+                || may_set_record(body)
+        }
+        DoWhile(c, body) | While(_, c, body) => expr(c) || may_set_record(body),
+        ForEach(v, arr, body) => {
+            matches!(Variable::try_from(v.clone()), Ok(Variable::NF))
+                || expr(arr)
+                || may_set_record(body)
+        }
+        Return(e) => e.is_some_and(expr),
+        Loc(_, s) => may_set_record(s),
+    }
+}
+
 impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
     pub(crate) fn from_stage(arena: &'a Arena, stage: Stage<()>) -> Self {
         Prog {
             field_sep: None,
+            record_sep: None,
             prelude_vardecs: Vec::new(),
+            operand_vardecs: Vec::new(),
+            operand_files: Vec::new(),
             output_sep: None,
             output_record_sep: None,
             decs: arena.new_vec(),
@@ -132,12 +278,108 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             prepare: arena.new_vec(),
             end: arena.new_vec(),
             pats: arena.new_vec(),
+            begin_lines: Vec::new(),
+            end_lines: Vec::new(),
+            pat_lines: Vec::new(),
+            rules: Vec::new(),
+            profile_rules: false,
             argv: Vec::new(),
             parse_header: false,
+            input_names_columns: false,
+            native_functions: Vec::new(),
+            export_globals: false,
             stage,
         }
     }
-    pub(crate) fn desugar_stage(&self, arena: &'a Arena) -> Stage<&'a Stmt<'a, 'b, I>> {
+    /// If we are profiling, build a call marking the start of the pattern (or action) of the `i`th
+    /// rule, whose starting lines are in `lines`. See the profile module for how these are numbered.
+    fn mark_rule(
+        &self,
+        arena: &'a Arena,
+        lines: &[usize],
+        i: usize,
+        action: bool,
+    ) -> Option<&'a Stmt<'a, 'b, I>> {
+        if !self.profile_rules {
+            return None;
+        }
+        let rule = 2 * *lines.get(i)? as i64 + action as i64;
+        Some(arena.alloc(Stmt::Expr(arena.alloc(Expr::Call(
+            Either::Right(Function::ProfileRule),
+            arena.alloc_slice(&[arena.alloc(Expr::ILit(rule))]),
+        )))))
+    }
+
+    /// Build the statement that performs the var=value operands that come before the current input
+    /// file, run at the start of each record.
+    ///
+    /// The input is read without regard for these assignments, so we tell that we have reached one
+    /// by watching for the first record of each file. NEXT_OPERAND holds the position of the first
+    /// operand that we have not yet reached. When a new file starts, we look for the first file
+    /// operand from there on with the current FILENAME: files that are empty or cannot be read are
+    /// skipped over, as are the assignments before them. If there is no such file (for example,
+    /// when reading standard input because all of the operands are assignments) we perform all of
+    /// the assignments that remain.
+    ///
+    /// Because this happens once the first record has been read, assignments to RS only take
+    /// effect from the second record of a file.
+    fn operand_assignments(&self, arena: &'a Arena) -> &'a Stmt<'a, 'b, I> {
+        use {self::Binop::*, self::Expr::*, Stmt::*};
+        let var = |name: &'b str| arena.alloc(Var(name.into()));
+        let lit = |ix: usize| arena.alloc(ILit(ix as i64));
+        let num_operands = self.operand_vardecs.len() + self.operand_files.len();
+        let mut block = arena.vec_with_capacity(self.operand_vardecs.len() + 2);
+
+        // FILE_OPERAND = position of the current file
+        let mut find_file = arena.alloc(Expr(
+            arena.alloc(Assign(var(FILE_OPERAND), lit(num_operands))),
+        ));
+        for (ix, name) in self.operand_files.iter().rev() {
+            find_file = arena.alloc(If(
+                arena.alloc(And(
+                    arena.alloc(Binop(LTE, var(NEXT_OPERAND), lit(*ix))),
+                    arena.alloc(Binop(
+                        EQ,
+                        var("FILENAME"),
+                        arena.alloc(StrLit(name.as_bytes())),
+                    )),
+                )),
+                arena.alloc(Expr(arena.alloc(Assign(var(FILE_OPERAND), lit(*ix))))),
+                Some(find_file),
+            ));
+        }
+        block.push(find_file);
+
+        // Perform the assignments between NEXT_OPERAND and FILE_OPERAND.
+        for (ix, ident, exp) in self.operand_vardecs.iter() {
+            block.push(arena.alloc(If(
+                arena.alloc(And(
+                    arena.alloc(Binop(LTE, var(NEXT_OPERAND), lit(*ix))),
+                    arena.alloc(Binop(LT, lit(*ix), var(FILE_OPERAND))),
+                )),
+                arena.alloc(Expr(
+                    arena.alloc(Assign(arena.alloc(Var(ident.clone())), exp)),
+                )),
+                None,
+            )));
+        }
+        block.push(arena.alloc(Expr(arena.alloc(Assign(
+            var(NEXT_OPERAND),
+            arena.alloc(Binop(Plus, var(FILE_OPERAND), lit(1))),
+        )))));
+
+        arena.alloc(If(
+            arena.alloc(Binop(EQ, var("FNR"), lit(1))),
+            arena.alloc(Block(block)),
+            None,
+        ))
+    }
+
+    pub(crate) fn desugar_stage(&self, arena: &'a Arena) -> Stage<&'a Stmt<'a, 'b, I>>
+    where
+        Function: TryFrom<I>,
+        Variable: TryFrom<I>,
+    {
         use {self::Binop::*, self::Expr::*, Stmt::*};
         let mut conds = 0;
 
@@ -153,6 +395,14 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             )))));
         }
 
+        // Desugar -z flag
+        if let Some(sep) = self.record_sep {
+            begin.push(arena.alloc(Expr(arena.alloc(Assign(
+                arena.alloc(Var("RS".into())),
+                arena.alloc(StrLit(sep)),
+            )))));
+        }
+
         // for -H
         if self.parse_header {
             parse_header(arena, &mut begin);
@@ -199,7 +449,10 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             }
         }
 
-        begin.extend(self.begin.iter().cloned());
+        for (i, stmt) in self.begin.iter().enumerate() {
+            begin.extend(self.mark_rule(arena, &self.begin_lines, i, true));
+            begin.push(stmt);
+        }
 
         // Desugar patterns into if statements, with the usual desugaring for an empty action.
         let mut inner = arena.vec_with_capacity(10);
@@ -213,16 +466,87 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             is_post: false,
             x: arena.alloc(Var("FNR".into())),
         }))));
+        // Desugar var=value operands
+        if !self.operand_vardecs.is_empty() {
+            inner.push(self.operand_assignments(arena));
+        }
         let init_len = inner.len();
-        for (pat, body) in self.pats.iter() {
+        // Pick an illegal frawk identifier for the regex set matches.
+        const MATCHES_VAR: &str = "--matches";
+        // The rules in set_start..set_end check their bit in MATCHES_VAR.
+        let (mut set_start, mut set_end) = (0, 0);
+        // Errors while matching a pattern are reported at the start of its rule.
+        let pat_locs: Vec<SourceLoc> = self
+            .rules
+            .iter()
+            .filter_map(|(loc, rule)| matches!(rule, Rule::Pattern).then_some(*loc))
+            .collect();
+        for (i, (pat, body)) in self.pats.iter().enumerate() {
+            let at = |s: &'a Stmt<'a, 'b, I>| -> &'a Stmt<'a, 'b, I> {
+                match pat_locs.get(i) {
+                    Some(loc) => arena.alloc(Loc(*loc, s)),
+                    None => s,
+                }
+            };
             let body = if let Some(body) = body {
                 body
             } else {
                 arena.alloc(Print(&[], None))
             };
+            let body = match self.mark_rule(arena, &self.pat_lines, i, true) {
+                Some(mark) => {
+                    let mut block = arena.vec_with_capacity(2);
+                    block.push(mark);
+                    block.push(body);
+                    arena.alloc(Block(block))
+                }
+                None => body,
+            };
+            inner.extend(self.mark_rule(arena, &self.pat_lines, i, false));
+            if i >= set_end {
+                // See if this rule starts a run of regex rules that we can match all at once.
+                let mut pats = Vec::new();
+                for (pat, body) in self.pats[i..].iter().take(MAX_REGEX_SET_RULES) {
+                    let re = match record_regex(pat) {
+                        Some(re) => re,
+                        None => break,
+                    };
+                    pats.push(arena.alloc(StrLit(re)));
+                    // The rules after one that may change $0 have to see the new value.
+                    if body.is_some_and(may_set_record) {
+                        break;
+                    }
+                }
+                if pats.len() >= MIN_REGEX_SET_RULES {
+                    set_start = i;
+                    set_end = i + pats.len();
+                    pats.insert(
+                        0,
+                        arena.alloc(Unop(self::Unop::Column, arena.alloc(ILit(0)))),
+                    );
+                    inner.push(at(arena.alloc(Expr(arena.alloc(Assign(
+                        arena.alloc(Var(MATCHES_VAR.into())),
+                        arena.alloc(Call(
+                            Either::Right(Function::MatchSet),
+                            arena.alloc_slice(&pats[..]),
+                        )),
+                    ))))));
+                }
+            }
+            if i < set_end {
+                let bit = arena.alloc(Call(
+                    Either::Right(Function::IntFunc(Bitwise::And)),
+                    arena.alloc_slice(&[
+                        arena.alloc(Var(MATCHES_VAR.into())),
+                        arena.alloc(ILit(1 << (i - set_start))),
+                    ]),
+                ));
+                inner.push(at(arena.alloc(If(bit, body, None))));
+                continue;
+            }
             match pat {
                 Pattern::Null => inner.push(body),
-                Pattern::Bool(pat) => inner.push(arena.alloc(If(pat, body, None))),
+                Pattern::Bool(pat) => inner.push(at(arena.alloc(If(pat, body, None)))),
                 Pattern::Comma(l, r) => {
                     let mut block = arena.vec_with_capacity(2);
                     // Comma patterns run the corresponding action between pairs of lines matching
@@ -264,19 +588,19 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
                     //      if (Cond(0) == 2) EndCond(0); # _cond_0 = 0;
                     //      next;
                     //  }
-                    inner.push(arena.alloc(If(l, arena.alloc(StartCond(conds)), None)));
-                    inner.push(arena.alloc(If(r, arena.alloc(LastCond(conds)), None)));
+                    inner.push(at(arena.alloc(If(l, arena.alloc(StartCond(conds)), None))));
+                    inner.push(at(arena.alloc(If(r, arena.alloc(LastCond(conds)), None))));
                     block.push(arena.alloc(If(
                         arena.alloc(Binop(EQ, arena.alloc(Cond(conds)), arena.alloc(ILit(2)))),
                         arena.alloc(EndCond(conds)),
                         None,
                     )));
                     block.push(body);
-                    inner.push(arena.alloc(If(
+                    inner.push(at(arena.alloc(If(
                         arena.alloc(Cond(conds)),
                         arena.alloc(Block(block)),
                         None,
-                    )));
+                    ))));
                     conds += 1;
                 }
             }
@@ -299,7 +623,26 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             });
         }
         if !self.end.is_empty() {
-            end = Some(arena.alloc(Stmt::Block(self.end.clone())));
+            // Assignments after the last input file take effect before END.
+            let mut block = arena.vec_with_capacity(self.operand_vardecs.len() + self.end.len());
+            for (ix, ident, exp) in self.operand_vardecs.iter() {
+                block.push(arena.alloc(If(
+                    arena.alloc(Binop(
+                        LTE,
+                        arena.alloc(Var(NEXT_OPERAND.into())),
+                        arena.alloc(ILit(*ix as i64)),
+                    )),
+                    arena.alloc(Expr(
+                        arena.alloc(Assign(arena.alloc(Var(ident.clone())), exp)),
+                    )),
+                    None,
+                )));
+            }
+            for (i, stmt) in self.end.iter().enumerate() {
+                block.extend(self.mark_rule(arena, &self.end_lines, i, true));
+                block.push(stmt);
+            }
+            end = Some(arena.alloc(Stmt::Block(block)));
         }
         match self.stage {
             Stage::Main(_) => {
@@ -320,6 +663,7 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Binop {
     Plus,
     Minus,
@@ -354,6 +698,7 @@ static_map!(
 );
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Expr<'a, 'b, I> {
     ILit(i64),
     FLit(f64),
@@ -433,4 +778,16 @@ pub enum Stmt<'a, 'b, I> {
     Next,
     NextFile,
     Return(Option<&'a Expr<'a, 'b, I>>),
+    // Where the statement starts in the program text, used to report errors at runtime.
+    Loc(SourceLoc, &'a Stmt<'a, 'b, I>),
+}
+
+impl<'a, 'b, I> Stmt<'a, 'b, I> {
+    /// The statement wrapped by any `Loc`s around it.
+    pub fn without_loc(&self) -> &Stmt<'a, 'b, I> {
+        match self {
+            Stmt::Loc(_, s) => s.without_loc(),
+            s => s,
+        }
+    }
 }
@@ -17,6 +17,8 @@ pub enum Function {
     FloatFunc(FloatFunc),
     IntFunc(Bitwise),
     Close,
+    Flush,
+    FlushAll,
     ReadErr,
     ReadErrCmd,
     Nextline,
@@ -32,6 +34,10 @@ pub enum Function {
     Delete,
     Clear,
     Match,
+    MatchArray,
+    // Match a string against several patterns, setting bit i of the result if pattern i matches.
+    // Only generated by desugaring; see the ast module.
+    MatchSet,
     SubstrIndex,
     Sub,
     GSub,
@@ -41,6 +47,9 @@ pub enum Function {
     JoinCols,
     JoinCSV,
     JoinTSV,
+    JoinJSON,
+    JoinArray,
+    ToJSON,
     Substr,
     ToInt,
     HexToInt,
@@ -54,7 +63,64 @@ pub enum Function {
     ToUpper,
     ToLower,
     IncMap,
+    ReadArray,
+    WriteArray,
     Exit,
+    // Record that execution has reached a rule, for --profile. Only generated by desugaring; see
+    // the ast module.
+    ProfileRule,
+    // A function registered by a program embedding frawk.
+    Native(Native),
+}
+
+/// The largest number of arguments that a native function can take.
+pub(crate) const MAX_NATIVE_ARGS: usize = 8;
+
+/// A function implemented in Rust by a program embedding frawk (see `embed::Builder::function`),
+/// along with its signature. Arguments are converted to the types in the signature before the
+/// function is called, as they are for the float and bitwise functions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Native {
+    // The index of the function in the table passed to the interpreter.
+    pub(crate) id: u32,
+    arity: u8,
+    args: [compile::Ty; MAX_NATIVE_ARGS],
+    ret: compile::Ty,
+}
+
+impl Native {
+    /// Returns None if there are more than MAX_NATIVE_ARGS arguments.
+    pub(crate) fn new(id: u32, args: &[compile::Ty], ret: compile::Ty) -> Option<Native> {
+        if args.len() > MAX_NATIVE_ARGS {
+            return None;
+        }
+        let mut res = Native {
+            id,
+            arity: args.len() as u8,
+            args: [compile::Ty::Null; MAX_NATIVE_ARGS],
+            ret,
+        };
+        res.args[..args.len()].copy_from_slice(args);
+        Some(res)
+    }
+    pub fn arity(&self) -> usize {
+        self.arity as usize
+    }
+    fn sig(&self) -> (SmallVec<compile::Ty>, compile::Ty) {
+        (
+            self.args[..self.arity()].iter().cloned().collect(),
+            self.ret,
+        )
+    }
+    fn ret_state(&self) -> types::State {
+        use types::{BaseTy, TVar::Scalar};
+        Scalar(match self.ret {
+            compile::Ty::Int => BaseTy::Int,
+            compile::Ty::Float => BaseTy::Float,
+            _ => BaseTy::Str,
+        })
+        .abs()
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -90,16 +156,24 @@ impl Bitwise {
             }
         }
     }
+    /// Shifts behave as though the bits were moved one place at a time: shifting by 64 or more
+    /// places shifts every bit out, and shifting by a negative amount shifts the other way.
     pub fn eval2(&self, lhs: i64, rhs: i64) -> i64 {
         use Bitwise::*;
-        match self {
-            And => lhs & rhs,
-            Or => lhs | rhs,
-            LogicalRightShift => (lhs as usize).wrapping_shr(rhs as u32) as i64,
-            ArithmeticRightShift => lhs.wrapping_shr(rhs as u32),
-            LeftShift => lhs.wrapping_shl(rhs as u32),
-            Xor => lhs ^ rhs,
-            Complement => panic!("bitwise: mismatched arity!"),
+        let places = u32::try_from(rhs.unsigned_abs()).unwrap_or(u32::MAX);
+        let left = |x: i64| x.checked_shl(places).unwrap_or(0);
+        match (self, rhs < 0) {
+            (And, _) => lhs & rhs,
+            (Or, _) => lhs | rhs,
+            (Xor, _) => lhs ^ rhs,
+            (LeftShift, false) | (LogicalRightShift, true) | (ArithmeticRightShift, true) => {
+                left(lhs)
+            }
+            (LogicalRightShift, false) => (lhs as u64).checked_shr(places).unwrap_or(0) as i64,
+            (ArithmeticRightShift, false) | (LeftShift, true) => lhs
+                .checked_shr(places)
+                .unwrap_or(if lhs < 0 { -1 } else { 0 }),
+            (Complement, _) => panic!("bitwise: mismatched arity!"),
         }
     }
     pub fn arity(&self) -> usize {
@@ -199,6 +273,7 @@ impl FloatFunc {
 static_map!(
     FUNCTIONS<&'static str, Function>,
     ["close", Function::Close],
+    ["fflush", Function::Flush],
     ["split", Function::Split],
     ["length", Function::Length],
     ["match", Function::Match],
@@ -227,6 +302,8 @@ static_map!(
     ["join_fields", Function::JoinCols],
     ["join_csv", Function::JoinCSV],
     ["join_tsv", Function::JoinTSV],
+    ["join_json", Function::JoinJSON],
+    ["to_json", Function::ToJSON],
     ["escape_csv", Function::EscapeCSV],
     ["escape_tsv", Function::EscapeTSV],
     ["rand", Function::Rand],
@@ -235,6 +312,8 @@ static_map!(
     ["toupper", Function::ToUpper],
     ["tolower", Function::ToLower],
     ["system", Function::System],
+    ["reada", Function::ReadArray],
+    ["writea", Function::WriteArray],
     ["exit", Function::Exit]
 );
 
@@ -248,6 +327,21 @@ impl<'a> TryFrom<&'a str> for Function {
     }
 }
 
+impl Function {
+    /// Builtins that a program can replace by defining a function with the same name. These are
+    /// not in FUNCTIONS: a call to one of them is only resolved to the builtin if the program
+    /// declares no function of that name.
+    ///
+    /// `join` is the name of a function in many existing awk libraries, which would otherwise stop
+    /// compiling with the introduction of the builtin.
+    pub(crate) fn fallback(name: &str) -> Option<Function> {
+        match name {
+            "join" => Some(Function::JoinArray),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) trait IsSprintf {
     fn is_sprintf(&self) -> bool;
 }
@@ -275,6 +369,17 @@ impl Function {
                 );
                 ctx.nw.add_dep(arg1, args[1], Constraint::Flows(()));
             }
+            Function::MatchArray => {
+                // Capture groups are stored alongside their positions, which have string keys.
+                let arg2 = ctx.constant(
+                    Map {
+                        key: BaseTy::Str,
+                        val: BaseTy::Str,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg2, args[2], Constraint::Flows(()));
+            }
             Function::Clear => {
                 let is_map = ctx.constant(Some(Map {
                     key: None,
@@ -282,6 +387,31 @@ impl Function {
                 }));
                 ctx.nw.add_dep(is_map, args[0], Constraint::Flows(()));
             }
+            Function::ReadArray => {
+                // Saved keys and values are read back as strings, like the fields from split.
+                let arg1 = ctx.constant(
+                    Map {
+                        key: BaseTy::Str,
+                        val: BaseTy::Str,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg1, args[1], Constraint::Flows(()));
+            }
+            Function::WriteArray => {
+                let is_map = ctx.constant(Some(Map {
+                    key: None,
+                    val: None,
+                }));
+                ctx.nw.add_dep(is_map, args[1], Constraint::Flows(()));
+            }
+            Function::JoinArray => {
+                let is_map = ctx.constant(Some(Map {
+                    key: None,
+                    val: None,
+                }));
+                ctx.nw.add_dep(is_map, args[0], Constraint::Flows(()));
+            }
             Function::Contains => {
                 let arr = args[0];
                 let query = args[1];
@@ -339,6 +469,7 @@ impl Function {
         Ok(match self {
             FloatFunc(ff) => ff.sig(),
             IntFunc(bw) => bw.sig(),
+            Native(native) => native.sig(),
             Unop(Neg) | Unop(Pos) => match &incoming[0] {
                 Str | Float => (smallvec![Float], Float),
                 _ => (smallvec![Int], Int),
@@ -402,9 +533,16 @@ impl Function {
                     return err!("invalid input spec for delete (of a map): {:?}", incoming);
                 }
             }
+            ReadArray | WriteArray => {
+                if incoming[1].is_array() {
+                    (smallvec![Str, incoming[1]], Int)
+                } else {
+                    return err!("invalid input spec for {}: {:?}", self, incoming);
+                }
+            }
             Srand => (smallvec![Int], Int),
             System | HexToInt => (smallvec![Str], Int),
-            ReseedRng => (smallvec![], Int),
+            ReseedRng | FlushAll => (smallvec![], Int),
             Rand => (smallvec![], Float),
             ToInt => {
                 let inc = incoming[0];
@@ -426,13 +564,15 @@ impl Function {
             // irrelevant return type
             Setcol => (smallvec![Int, Str], Int),
             Length => (smallvec![incoming[0]], Int),
-            Close => (smallvec![Str], Str),
+            Close | Flush => (smallvec![Str], Int),
             Sub | GSub => (smallvec![Str, Str, Str], Int),
             GenSub => (smallvec![Str, Str, Str, Str], Str),
             ToUpper | ToLower | EscapeCSV | EscapeTSV => (smallvec![Str], Str),
             Substr => (smallvec![Str, Int, Int], Str),
             Match => (smallvec![Str, Str], Int),
-            Exit => (smallvec![Int], Null),
+            MatchArray => (smallvec![Str, Str, MapStrStr, Str], Int),
+            MatchSet => (smallvec![Str; incoming.len()], Int),
+            Exit | ProfileRule => (smallvec![Int], Null),
             // Split's second input can be a map of either type
             Split => {
                 if let MapIntStr | MapStrStr = incoming[1] {
@@ -442,7 +582,15 @@ impl Function {
                 }
             }
             JoinCols => (smallvec![Int, Int, Str], Str),
-            JoinCSV | JoinTSV => (smallvec![Int, Int], Str),
+            JoinCSV | JoinTSV | JoinJSON => (smallvec![Int, Int], Str),
+            JoinArray => {
+                if incoming[0].is_array() {
+                    (smallvec![incoming[0], Str, Int], Str)
+                } else {
+                    return err!("invalid input spec for join: {:?}", incoming);
+                }
+            }
+            ToJSON => (smallvec![incoming[0]], Str),
             SetFI => (smallvec![Int, Int], Int),
         })
     }
@@ -450,17 +598,19 @@ impl Function {
     pub(crate) fn arity(&self) -> Option<usize> {
         use Function::*;
         Some(match self {
+            MatchSet => return None,
             FloatFunc(ff) => ff.arity(),
             IntFunc(bw) => bw.arity(),
+            Native(native) => native.arity(),
             UpdateUsedFields | Rand | ReseedRng | ReadErrStdin | NextlineStdin | NextFile
-            | ReadLineStdinFused => 0,
-            Exit | ToUpper | ToLower | Clear | Srand | System | HexToInt | ToInt | EscapeCSV
-            | EscapeTSV | Close | Length | ReadErr | ReadErrCmd | Nextline | NextlineCmd
-            | Unop(_) => 1,
+            | ReadLineStdinFused | FlushAll => 0,
+            Exit | ProfileRule | ToUpper | ToLower | Clear | Srand | System | HexToInt | ToInt
+            | EscapeCSV | EscapeTSV | ToJSON | Close | Flush | Length | ReadErr | ReadErrCmd
+            | Nextline | NextlineCmd | Unop(_) => 1,
             SetFI | SubstrIndex | Match | Setcol | Binop(_) => 2,
-            JoinCSV | JoinTSV | Delete | Contains => 2,
-            IncMap | JoinCols | Substr | Sub | GSub | Split => 3,
-            GenSub => 4,
+            JoinCSV | JoinTSV | JoinJSON | Delete | Contains | ReadArray | WriteArray => 2,
+            IncMap | JoinCols | JoinArray | Substr | Sub | GSub | Split => 3,
+            GenSub | MatchArray => 4,
         })
     }
 
@@ -482,6 +632,7 @@ impl Function {
         match self {
             IntFunc(bw) => Ok(bw.ret_state()),
             FloatFunc(ff) => Ok(ff.ret_state()),
+            Native(native) => Ok(native.ret_state()),
             Unop(Neg) | Unop(Pos) => match &args[0] {
                 Some(Scalar(Some(BaseTy::Str))) | Some(Scalar(Some(BaseTy::Float))) => {
                     Ok(Scalar(BaseTy::Float).abs())
@@ -495,14 +646,16 @@ impl Function {
             Setcol => Ok(Scalar(BaseTy::Null).abs()),
             Clear | SubstrIndex | Srand | ReseedRng | Unop(Not) | Binop(IsMatch) | Binop(LT)
             | Binop(GT) | Binop(LTE) | Binop(GTE) | Binop(EQ) | Length | Split | ReadErr
-            | ReadErrCmd | ReadErrStdin | Contains | Delete | Match | Sub | GSub | ToInt
-            | System | HexToInt => Ok(Scalar(BaseTy::Int).abs()),
-            ToUpper | ToLower | JoinCSV | JoinTSV | JoinCols | EscapeCSV | EscapeTSV | Substr
-            | Unop(Column) | Binop(Concat) | Nextline | NextlineCmd | NextlineStdin | GenSub => {
-                Ok(Scalar(BaseTy::Str).abs())
-            }
+            | ReadErrCmd | ReadErrStdin | Contains | Delete | Match | MatchArray | MatchSet
+            | Sub | GSub | ToInt | System | HexToInt | ReadArray | WriteArray | Close | Flush
+            | FlushAll => Ok(Scalar(BaseTy::Int).abs()),
+            ToUpper | ToLower | JoinCSV | JoinTSV | JoinJSON | JoinCols | JoinArray | EscapeCSV
+            | EscapeTSV | ToJSON | Substr | Unop(Column) | Binop(Concat) | Nextline
+            | NextlineCmd | NextlineStdin | GenSub => Ok(Scalar(BaseTy::Str).abs()),
             IncMap => Ok(step_arith(&types::val_of(&args[0])?, &args[2])),
-            Exit | SetFI | UpdateUsedFields | NextFile | ReadLineStdinFused | Close => Ok(None),
+            Exit | ProfileRule | SetFI | UpdateUsedFields | NextFile | ReadLineStdinFused => {
+                Ok(None)
+            }
         }
     }
 }
@@ -526,6 +679,8 @@ pub(crate) enum Variable {
     FNR = 11,
     PID = 12,
     FI = 13,
+    PROCINFO = 14,
+    IGNORECASE = 15,
 }
 
 impl From<Variable> for compile::Ty {
@@ -533,9 +688,9 @@ impl From<Variable> for compile::Ty {
         use Variable::*;
         match v {
             FS | OFS | ORS | RS | FILENAME => compile::Ty::Str,
-            PID | ARGC | NF | NR | FNR | RSTART | RLENGTH => compile::Ty::Int,
+            PID | ARGC | NF | NR | FNR | RSTART | RLENGTH | IGNORECASE => compile::Ty::Int,
             ARGV => compile::Ty::MapIntStr,
-            FI => compile::Ty::MapStrInt,
+            FI | PROCINFO => compile::Ty::MapStrInt,
         }
     }
 }
@@ -555,10 +710,16 @@ pub(crate) struct Variables<'a> {
     pub rlength: Int,
     pub pid: Int,
     pub fi: StrMap<'a, Int>,
+    pub procinfo: StrMap<'a, Int>,
+    pub ignorecase: Int,
 }
 
 impl<'a> Default for Variables<'a> {
     fn default() -> Variables<'a> {
+        let procinfo: StrMap<'a, Int> = Default::default();
+        procinfo.insert("pid".into(), std::process::id() as Int);
+        procinfo.insert("worker".into(), 0);
+        procinfo.insert("workers".into(), 1);
         Variables {
             argc: 0,
             argv: Default::default(),
@@ -574,10 +735,29 @@ impl<'a> Default for Variables<'a> {
             pid: 0,
             rlength: -1,
             fi: Default::default(),
+            procinfo,
+            ignorecase: 0,
         }
     }
 }
 impl<'a> Variables<'a> {
+    /// Set `PID`, keeping `PROCINFO["worker"]` in sync with it.
+    pub fn set_pid(&mut self, pid: Int) {
+        self.pid = pid;
+        self.procinfo.insert("worker".into(), pid);
+    }
+
+    /// Whether string comparisons, `index` and sorted `for-in` loops should ignore the case of
+    /// ASCII letters, as set by `IGNORECASE`. The regex cache tracks the same flag for patterns.
+    pub fn ignore_case(&self) -> bool {
+        self.ignorecase != 0
+    }
+
+    /// Record the number of threads running the main loop in `PROCINFO["workers"]`.
+    pub fn set_workers(&mut self, workers: Int) {
+        self.procinfo.insert("workers".into(), workers);
+    }
+
     pub fn load_int(&self, var: Variable) -> Result<Int> {
         use Variable::*;
         Ok(match var {
@@ -588,7 +768,10 @@ impl<'a> Variables<'a> {
             RSTART => self.rstart,
             RLENGTH => self.rlength,
             PID => self.pid,
-            FI | ORS | OFS | FS | RS | FILENAME | ARGV => return err!("var {} not an int", var),
+            IGNORECASE => self.ignorecase,
+            FI | PROCINFO | ORS | OFS | FS | RS | FILENAME | ARGV => {
+                return err!("var {} not an int", var)
+            }
         })
     }
 
@@ -602,7 +785,10 @@ impl<'a> Variables<'a> {
             RSTART => self.rstart = i,
             RLENGTH => self.rlength = i,
             PID => self.pid = i,
-            FI | ORS | OFS | FS | RS | FILENAME | ARGV => return err!("var {} not an int", var),
+            IGNORECASE => self.ignorecase = i,
+            FI | PROCINFO | ORS | OFS | FS | RS | FILENAME | ARGV => {
+                return err!("var {} not an int", var)
+            }
         }
         Ok(())
     }
@@ -615,7 +801,7 @@ impl<'a> Variables<'a> {
             ORS => self.ors.clone(),
             RS => self.rs.clone(),
             FILENAME => self.filename.clone(),
-            FI | PID | ARGC | ARGV | NF | NR | FNR | RSTART | RLENGTH => {
+            FI | PROCINFO | PID | ARGC | ARGV | NF | NR | FNR | RSTART | RLENGTH | IGNORECASE => {
                 return err!("var {} not a string", var)
             }
         })
@@ -629,7 +815,7 @@ impl<'a> Variables<'a> {
             ORS => self.ors = s,
             RS => self.rs = s,
             FILENAME => self.filename = s,
-            FI | PID | ARGC | ARGV | NF | NR | FNR | RSTART | RLENGTH => {
+            FI | PROCINFO | PID | ARGC | ARGV | NF | NR | FNR | RSTART | RLENGTH | IGNORECASE => {
                 return err!("var {} not a string", var)
             }
         };
@@ -640,7 +826,8 @@ impl<'a> Variables<'a> {
         use Variable::*;
         match var {
             ARGV => Ok(self.argv.clone()),
-            FI | PID | ORS | OFS | ARGC | NF | NR | FNR | FS | RS | FILENAME | RSTART | RLENGTH => {
+            FI | PROCINFO | PID | ORS | OFS | ARGC | NF | NR | FNR | FS | RS | FILENAME
+            | RSTART | RLENGTH | IGNORECASE => {
                 err!("var {} is not an int-keyed map", var)
             }
         }
@@ -653,7 +840,8 @@ impl<'a> Variables<'a> {
                 self.argv = m;
                 Ok(())
             }
-            FI | PID | ORS | OFS | ARGC | NF | NR | FNR | FS | RS | FILENAME | RSTART | RLENGTH => {
+            FI | PROCINFO | PID | ORS | OFS | ARGC | NF | NR | FNR | FS | RS | FILENAME
+            | RSTART | RLENGTH | IGNORECASE => {
                 err!("var {} is not an int-keyed map", var)
             }
         }
@@ -662,8 +850,9 @@ impl<'a> Variables<'a> {
         use Variable::*;
         match var {
             FI => Ok(self.fi.clone()),
+            PROCINFO => Ok(self.procinfo.clone()),
             ARGV | PID | ORS | OFS | ARGC | NF | NR | FNR | FS | RS | FILENAME | RSTART
-            | RLENGTH => {
+            | RLENGTH | IGNORECASE => {
                 err!("var {} is not a string-keyed map", var)
             }
         }
@@ -676,8 +865,12 @@ impl<'a> Variables<'a> {
                 self.fi = m;
                 Ok(())
             }
+            PROCINFO => {
+                self.procinfo = m;
+                Ok(())
+            }
             ARGV | PID | ORS | OFS | ARGC | NF | NR | FNR | FS | RS | FILENAME | RSTART
-            | RLENGTH => {
+            | RLENGTH | IGNORECASE => {
                 err!("var {} is not a string-keyed map", var)
             }
         }
@@ -688,7 +881,7 @@ impl Variable {
     pub(crate) fn ty(&self) -> types::TVar<types::BaseTy> {
         use Variable::*;
         match self {
-            PID | ARGC | NF | FNR | NR | RSTART | RLENGTH => {
+            PID | ARGC | NF | FNR | NR | RSTART | RLENGTH | IGNORECASE => {
                 types::TVar::Scalar(types::BaseTy::Int)
             }
             // NB: For full compliance, this may have to be Str -> Str
@@ -717,7 +910,7 @@ impl Variable {
                 key: types::BaseTy::Int,
                 val: types::BaseTy::Str,
             },
-            FI => types::TVar::Map {
+            FI | PROCINFO => types::TVar::Map {
                 key: types::BaseTy::Str,
                 val: types::BaseTy::Int,
             },
@@ -755,6 +948,8 @@ impl TryFrom<usize> for Variable {
             11 => Ok(FNR),
             12 => Ok(PID),
             13 => Ok(FI),
+            14 => Ok(PROCINFO),
+            15 => Ok(IGNORECASE),
             _ => Err(()),
         }
     }
@@ -775,5 +970,7 @@ static_map!(
     ["RSTART", Variable::RSTART],
     ["RLENGTH", Variable::RLENGTH],
     ["PID", Variable::PID],
-    ["FI", Variable::FI]
+    ["IGNORECASE", Variable::IGNORECASE],
+    ["FI", Variable::FI],
+    ["PROCINFO", Variable::PROCINFO]
 );
@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::sync::Arc;
@@ -6,9 +7,10 @@ use crate::builtins::{Bitwise, FloatFunc, Variable};
 use crate::common::{FileSpec, NumTy};
 use crate::compile::{self, Ty};
 use crate::interp::{index, index_mut, Storage};
+use crate::lexer::SourceLoc;
 use crate::runtime::{self, Float, Int, Str, UniqueStr};
 
-use regex::bytes::Regex;
+use regex::bytes::{Regex, RegexSet};
 
 pub(crate) use crate::interp::Interp;
 
@@ -114,10 +116,24 @@ pub(crate) enum Instr<'a> {
     // String processing
     Concat(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Str<'a>>),
     StartsWithConst(Reg<Int>, Reg<Str<'a>>, Arc<[u8]>),
+    // A match against a regex that is just a literal string.
+    ContainsConst(Reg<Int>, Reg<Str<'a>>, Arc<[u8]>),
     IsMatch(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
     IsMatchConst(Reg<Int>, Reg<Str<'a>>, Arc<Regex>),
     Match(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
     MatchConst(Reg<Int>, Reg<Str<'a>>, Arc<Regex>),
+    // match(s, re, arr): Match, but also storing the matched text and the text of each capture
+    // group in arr, indexed by group number, along with the start and length of each group under
+    // the group number and "start" or "length" joined by SUBSEP.
+    MatchArr(
+        Reg<Int>,
+        Reg<Str<'a>>,
+        Reg<Str<'a>>,
+        Reg<runtime::StrMap<'a, Str<'a>>>,
+        /*subsep*/ Reg<Str<'a>>,
+    ),
+    // Bit i of the result is set if pattern i of the set matches the string.
+    MatchSet(Reg<Int>, Reg<Str<'a>>, Arc<RegexSet>),
     // index(s, t) returns index of substring t in s, 0 if it does not appear.
     SubstrIndex(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
     LenStr(Reg<Int>, Reg<Str<'a>>),
@@ -133,6 +149,18 @@ pub(crate) enum Instr<'a> {
         /*for*/ Reg<Str<'a>>,
         /*in*/ Reg<Str<'a>>,
     ),
+    SubConst(
+        Reg<Int>,
+        /*pat*/ Arc<Regex>,
+        /*for*/ Reg<Str<'a>>,
+        /*in*/ Reg<Str<'a>>,
+    ),
+    GSubConst(
+        Reg<Int>,
+        /*pat*/ Arc<Regex>,
+        /*for*/ Reg<Str<'a>>,
+        /*in*/ Reg<Str<'a>>,
+    ),
     GenSubDynamic(
         Reg<Str<'a>>,
         /*pat*/ Reg<Str<'a>>,
@@ -142,6 +170,8 @@ pub(crate) enum Instr<'a> {
     ),
     EscapeCSV(Reg<Str<'a>>, Reg<Str<'a>>),
     EscapeTSV(Reg<Str<'a>>, Reg<Str<'a>>),
+    EscapeJSON(Reg<Str<'a>>, Reg<Str<'a>>),
+    FloatToJSON(Reg<Str<'a>>, Reg<Float>),
     Substr(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Int>, Reg<Int>),
 
     // Comparison
@@ -160,6 +190,9 @@ pub(crate) enum Instr<'a> {
     EQFloat(Reg<Int>, Reg<Float>, Reg<Float>),
     EQInt(Reg<Int>, Reg<Int>, Reg<Int>),
     EQStr(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
+    // Compares two strings read from input: numerically if both look like numbers, and as strings
+    // otherwise. See strnum.rs.
+    StrNumCmp(Cmp, Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
 
     // Columns
     SetColumn(Reg<Int> /* dst column */, Reg<Str<'a>>),
@@ -174,6 +207,11 @@ pub(crate) enum Instr<'a> {
         Reg<Int>,     /* start col */
         Reg<Int>,     /* end col */
     ),
+    JoinJSON(
+        Reg<Str<'a>>, /* dst */
+        Reg<Int>,     /* start col */
+        Reg<Int>,     /* end col */
+    ),
     JoinColumns(
         Reg<Str<'a>>, /* dst */
         Reg<Int>,     /* start col */
@@ -210,31 +248,62 @@ pub(crate) enum Instr<'a> {
         Reg<runtime::StrMap<'a, Str<'a>>>,
         Reg<Str<'a>>,
     ),
+    // The printing instructions box their operands so they do not increase the size of
+    // every other instruction; see the size assertion below.
     Sprintf {
         dst: Reg<Str<'a>>,
         fmt: Reg<Str<'a>>,
-        args: Vec<(NumTy, Ty)>,
+        args: Box<[(NumTy, Ty)]>,
     },
     Printf {
-        output: Option<(Reg<Str<'a>>, FileSpec)>,
+        output: Option<Box<(Reg<Str<'a>>, FileSpec)>>,
         fmt: Reg<Str<'a>>,
-        args: Vec<(NumTy, Ty)>,
+        args: Box<[(NumTy, Ty)]>,
     },
     PrintAll {
-        output: Option<(Reg<Str<'a>>, FileSpec)>,
-        args: Vec<Reg<Str<'a>>>,
+        output: Option<Box<(Reg<Str<'a>>, FileSpec)>>,
+        args: Box<[Reg<Str<'a>>]>,
+    },
+    // Call the native function with index `func`, passing the values of `args` and storing the
+    // result in `dst`.
+    CallNative {
+        dst: (NumTy, Ty),
+        func: u32,
+        args: Box<[(NumTy, Ty)]>,
     },
-    Close(Reg<Str<'a>>),
+    // Close a file or command, storing the command's exit status (or 0 for files, -1 if nothing
+    // by that name is open) in the destination.
+    Close(Reg<Int>, Reg<Str<'a>>),
+    // Flush pending output to a file or command, storing 0 (or -1 if nothing by that name was
+    // opened) in the destination.
+    Flush(Reg<Int>, Reg<Str<'a>>),
+    // Flush pending output to stdout and every open file and command, storing 0 in the
+    // destination.
+    FlushAll(Reg<Int>),
+    // Run a command with `system`, flushing all pending output first.
     RunCmd(Reg<Int>, Reg<Str<'a>>),
     Exit(Reg<Int>),
+    // Mark the start of a rule, for --profile; see profile::Profile::mark_rule.
+    ProfileRule(Reg<Int>),
 
     // Map operations
+    //
+    // As in Awk, lookups of a missing key insert it with an uninitialized value (the default for
+    // the map's value type) before reading it. `Contains` (`k in m`) never inserts.
     Lookup {
         map_ty: Ty,
         dst: NumTy,
         map: NumTy,
         key: NumTy,
     },
+    // Lookup for maps with numeric values whose result is used as a string. Missing keys are
+    // inserted like they are for `Lookup`, and read as "" until they are assigned.
+    LookupStr {
+        map_ty: Ty,
+        dst: Reg<Str<'a>>,
+        map: NumTy,
+        key: NumTy,
+    },
     Contains {
         map_ty: Ty,
         dst: NumTy,
@@ -255,6 +324,35 @@ pub(crate) enum Instr<'a> {
         dst: NumTy,
         map: NumTy,
     },
+    // Format the contents of `map` as a JSON array or object.
+    ToJSON {
+        map_ty: Ty,
+        dst: Reg<Str<'a>>,
+        map: NumTy,
+    },
+    // Join the values at keys 1 through the length of `map` (or, if `all` is nonzero, all of its
+    // values in iteration order) separated by `sep`.
+    JoinArray {
+        map_ty: Ty,
+        dst: Reg<Str<'a>>,
+        map: NumTy,
+        sep: Reg<Str<'a>>,
+        all: Reg<Int>,
+    },
+    // Replace the contents of `map` with an array saved to `file` by `WriteArray`. `dst` is set
+    // to 1 on success and 0 on failure, in which case `map` is unchanged.
+    ReadArray {
+        map_ty: Ty,
+        dst: Reg<Int>,
+        file: Reg<Str<'a>>,
+        map: NumTy,
+    },
+    WriteArray {
+        map_ty: Ty,
+        dst: Reg<Int>,
+        file: Reg<Str<'a>>,
+        map: NumTy,
+    },
     Store {
         map_ty: Ty,
         map: NumTy,
@@ -315,18 +413,145 @@ pub(crate) enum Instr<'a> {
     JmpIf(Reg<Int>, Label),
     Jmp(Label),
 
+    // Superinstructions. These are only introduced by the peephole pass in the interpreter.
+    GetColumnFloat(Reg<Float>, Reg<Int>),
+    JmpIfInt(Cmp, Reg<Int>, Reg<Int>, Label),
+    JmpIfFloat(Cmp, Reg<Float>, Reg<Float>, Label),
+    // Jump if whether the string matches the regex is equal to the flag.
+    JmpIfConstMatch(bool, Reg<Str<'a>>, Arc<Regex>, Label),
+    // Arithmetic and comparisons with a constant operand, which does not need to be loaded into a
+    // register first.
+    AddIntImm(Reg<Int>, Reg<Int>, Int),
+    EQStrConst(Reg<Int>, Reg<Str<'a>>, Arc<[u8]>),
+    // Map operations on string-keyed maps where the key is a constant.
+    LookupConstKey {
+        map_ty: Ty,
+        dst: NumTy,
+        map: NumTy,
+        key: Box<ConstKey<'a>>,
+    },
+    StoreConstKey {
+        map_ty: Ty,
+        map: NumTy,
+        val: NumTy,
+        key: Box<ConstKey<'a>>,
+    },
+    IncIntConstKey {
+        map_ty: Ty,
+        map: NumTy,
+        dst: NumTy,
+        by: Reg<Int>,
+        key: Box<ConstKey<'a>>,
+    },
+    IncFloatConstKey {
+        map_ty: Ty,
+        map: NumTy,
+        dst: NumTy,
+        by: Reg<Float>,
+        key: Box<ConstKey<'a>>,
+    },
+
     // Functions
     // TODO: we may need to push iterators as well?
     Push(Ty, NumTy),
     Pop(Ty, NumTy),
     Call(usize),
     Ret,
+
+    // Where the instructions that follow come from in the program text. These are removed once
+    // the bytecode has been optimized, and recorded in a `source_map::SourceMap` instead.
+    Loc(Option<SourceLoc>),
+}
+
+// The interpreter dispatches on an array of these instructions; keeping them small keeps more of
+// them in cache in tight loops. Make sure new variants do not accidentally grow the enum.
+#[cfg(target_pointer_width = "64")]
+const _: [(); 32] = [(); std::mem::size_of::<Instr>()];
+
+/// The comparison performed by a fused compare-and-branch instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Cmp {
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+    Eq,
+    Neq,
+}
+
+impl Cmp {
+    #[inline(always)]
+    pub(crate) fn eval<T: PartialOrd>(self, l: T, r: T) -> bool {
+        match self {
+            Cmp::Lt => l < r,
+            Cmp::Gt => l > r,
+            Cmp::Lte => l <= r,
+            Cmp::Gte => l >= r,
+            Cmp::Eq => l == r,
+            Cmp::Neq => l != r,
+        }
+    }
+
+    /// Compare `l` and `r` as strings, ignoring the case of ASCII letters if `ignore_case` is set
+    /// (see `IGNORECASE`).
+    pub(crate) fn eval_str<'a>(self, l: &Str<'a>, r: &Str<'a>, ignore_case: bool) -> bool {
+        match (self, ignore_case) {
+            (_, false) => self.eval(l, r),
+            (Cmp::Eq, true) => l.eq_ignore_ascii_case(r),
+            (Cmp::Neq, true) => !l.eq_ignore_ascii_case(r),
+            (_, true) => self.eval(l.cmp_ignore_ascii_case(r), Ordering::Equal),
+        }
+    }
+
+    /// Compare `l` and `r` as numbers if both look numeric, and as strings otherwise.
+    pub(crate) fn eval_str_num<'a>(self, l: &Str<'a>, r: &Str<'a>, ignore_case: bool) -> bool {
+        let num = |s: &Str| s.with_bytes(runtime::strtod_exact);
+        match (num(l), num(r)) {
+            (Some(l), Some(r)) => self.eval(l, r),
+            _ => self.eval_str(l, r, ignore_case),
+        }
+    }
+
+    /// The comparison that holds exactly when `self` does not. This is only valid for totally
+    /// ordered operands: it does not hold for NaN floats.
+    pub(crate) fn negate(self) -> Cmp {
+        match self {
+            Cmp::Lt => Cmp::Gte,
+            Cmp::Gt => Cmp::Lte,
+            Cmp::Lte => Cmp::Gt,
+            Cmp::Gte => Cmp::Lt,
+            Cmp::Eq => Cmp::Neq,
+            Cmp::Neq => Cmp::Eq,
+        }
+    }
+}
+
+/// A constant map key, along with its hash. Every map hashes keys the same way, so the hash can be
+/// computed once when the instruction is created rather than on every access.
+#[derive(Debug, Clone)]
+pub(crate) struct ConstKey<'a> {
+    pub(crate) key: UniqueStr<'a>,
+    pub(crate) hash: u64,
+}
+
+impl<'a> ConstKey<'a> {
+    pub(crate) fn new(key: UniqueStr<'a>) -> ConstKey<'a> {
+        let hash = runtime::hash_key(&key.clone_str());
+        ConstKey { key, hash }
+    }
 }
 
 impl<T> Reg<T> {
     pub(crate) fn index(&self) -> usize {
         self.0 as usize
     }
+    pub(crate) fn accum_mut(&mut self, mut f: impl FnMut(&mut NumTy, compile::Ty))
+    where
+        Self: Accum,
+    {
+        let ty = self.reflect().1;
+        f(&mut self.0, ty)
+    }
 }
 
 // For accumulating register-specific metadata
@@ -422,7 +647,7 @@ impl<'a> Instr<'a> {
                 sr.accum(&mut f);
                 ir.accum(&mut f)
             }
-            FloatToStr(sr, fr) => {
+            FloatToStr(sr, fr) | FloatToJSON(sr, fr) => {
                 sr.accum(&mut f);
                 fr.accum(&mut f);
             }
@@ -527,7 +752,7 @@ impl<'a> Instr<'a> {
                 seed.accum(&mut f)
             }
             ReseedRng(res) => res.accum(&mut f),
-            StartsWithConst(res, s, _) => {
+            StartsWithConst(res, s, _) | ContainsConst(res, s, _) => {
                 res.accum(&mut f);
                 s.accum(&mut f);
             }
@@ -546,10 +771,17 @@ impl<'a> Instr<'a> {
                 l.accum(&mut f);
                 r.accum(&mut f);
             }
-            MatchConst(res, src, _) | IsMatchConst(res, src, _) => {
+            MatchConst(res, src, _) | IsMatchConst(res, src, _) | MatchSet(res, src, _) => {
                 res.accum(&mut f);
                 src.accum(&mut f);
             }
+            MatchArr(res, l, r, arr, subsep) => {
+                res.accum(&mut f);
+                l.accum(&mut f);
+                r.accum(&mut f);
+                arr.accum(&mut f);
+                subsep.accum(&mut f);
+            }
             SubstrIndex(res, s, t) => {
                 res.accum(&mut f);
                 s.accum(&mut f);
@@ -565,6 +797,11 @@ impl<'a> Instr<'a> {
                 s.accum(&mut f);
                 in_s.accum(&mut f);
             }
+            GSubConst(res, _, s, in_s) | SubConst(res, _, s, in_s) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                in_s.accum(&mut f);
+            }
             GenSubDynamic(res, pat, s, how, in_s) => {
                 res.accum(&mut f);
                 pat.accum(&mut f);
@@ -572,7 +809,7 @@ impl<'a> Instr<'a> {
                 how.accum(&mut f);
                 in_s.accum(&mut f);
             }
-            EscapeCSV(res, s) | EscapeTSV(res, s) => {
+            EscapeCSV(res, s) | EscapeTSV(res, s) | EscapeJSON(res, s) => {
                 res.accum(&mut f);
                 s.accum(&mut f);
             }
@@ -652,7 +889,7 @@ impl<'a> Instr<'a> {
                 l.accum(&mut f);
                 r.accum(&mut f);
             }
-            EQStr(res, l, r) => {
+            EQStr(res, l, r) | StrNumCmp(_, res, l, r) => {
                 res.accum(&mut f);
                 l.accum(&mut f);
                 r.accum(&mut f);
@@ -665,7 +902,7 @@ impl<'a> Instr<'a> {
                 dst.accum(&mut f);
                 src.accum(&mut f)
             }
-            JoinCSV(dst, start, end) | JoinTSV(dst, start, end) => {
+            JoinCSV(dst, start, end) | JoinTSV(dst, start, end) | JoinJSON(dst, start, end) => {
                 dst.accum(&mut f);
                 start.accum(&mut f);
                 end.accum(&mut f);
@@ -699,8 +936,14 @@ impl<'a> Instr<'a> {
                     f(reg, ty);
                 }
             }
+            CallNative { dst, args, .. } => {
+                f(dst.0, dst.1);
+                for (reg, ty) in args.iter().cloned() {
+                    f(reg, ty);
+                }
+            }
             Printf { output, fmt, args } => {
-                if let Some((path_reg, _)) = output {
+                if let Some((path_reg, _)) = output.as_deref() {
                     path_reg.accum(&mut f);
                 }
                 fmt.accum(&mut f);
@@ -709,19 +952,23 @@ impl<'a> Instr<'a> {
                 }
             }
             PrintAll { output, args } => {
-                if let Some((path_reg, _)) = output {
+                if let Some((path_reg, _)) = output.as_deref() {
                     path_reg.accum(&mut f);
                 }
                 for reg in args {
                     reg.accum(&mut f)
                 }
             }
-            Close(file) => file.accum(&mut f),
+            Close(dst, file) | Flush(dst, file) => {
+                dst.accum(&mut f);
+                file.accum(&mut f)
+            }
+            FlushAll(dst) => dst.accum(&mut f),
             RunCmd(dst, cmd) => {
                 dst.accum(&mut f);
                 cmd.accum(&mut f);
             }
-            Exit(code) => code.accum(&mut f),
+            Exit(code) | ProfileRule(code) => code.accum(&mut f),
             Lookup {
                 map_ty,
                 dst,
@@ -733,6 +980,16 @@ impl<'a> Instr<'a> {
                 f(*key, k);
                 f(*map, *map_ty);
             }
+            LookupStr {
+                map_ty,
+                dst,
+                map,
+                key,
+            } => {
+                dst.accum(&mut f);
+                f(*key, map_ty.key().unwrap());
+                f(*map, *map_ty);
+            }
             Contains {
                 map_ty,
                 dst,
@@ -754,6 +1011,38 @@ impl<'a> Instr<'a> {
                 f(*dst, Ty::Int);
                 f(*map, *map_ty);
             }
+            ToJSON { map_ty, dst, map } => {
+                dst.accum(&mut f);
+                f(*map, *map_ty);
+            }
+            JoinArray {
+                map_ty,
+                dst,
+                map,
+                sep,
+                all,
+            } => {
+                dst.accum(&mut f);
+                f(*map, *map_ty);
+                sep.accum(&mut f);
+                all.accum(&mut f);
+            }
+            ReadArray {
+                map_ty,
+                dst,
+                file,
+                map,
+            }
+            | WriteArray {
+                map_ty,
+                dst,
+                file,
+                map,
+            } => {
+                dst.accum(&mut f);
+                file.accum(&mut f);
+                f(*map, *map_ty);
+            }
             IterBegin { map_ty, map, dst } => {
                 f(*dst, map_ty.key_iter().unwrap());
                 f(*map, *map_ty);
@@ -828,13 +1117,616 @@ impl<'a> Instr<'a> {
             ReadErrStdin(dst) => dst.accum(&mut f),
             NextLineStdin(dst) => dst.accum(&mut f),
             JmpIf(cond, _lbl) => cond.accum(&mut f),
+            GetColumnFloat(dst, src) => {
+                dst.accum(&mut f);
+                src.accum(&mut f);
+            }
+            JmpIfInt(_, l, r, _) => {
+                l.accum(&mut f);
+                r.accum(&mut f);
+            }
+            JmpIfFloat(_, l, r, _) => {
+                l.accum(&mut f);
+                r.accum(&mut f);
+            }
+            JmpIfConstMatch(_, s, _, _) => s.accum(&mut f),
+            AddIntImm(dst, src, _) => {
+                dst.accum(&mut f);
+                src.accum(&mut f);
+            }
+            EQStrConst(dst, src, _) => {
+                dst.accum(&mut f);
+                src.accum(&mut f);
+            }
+            LookupConstKey {
+                map_ty, dst, map, ..
+            } => {
+                f(*dst, map_ty.val().unwrap());
+                f(*map, *map_ty);
+            }
+            StoreConstKey {
+                map_ty, map, val, ..
+            } => {
+                f(*map, *map_ty);
+                f(*val, map_ty.val().unwrap());
+            }
+            IncIntConstKey {
+                map_ty,
+                map,
+                dst,
+                by,
+                ..
+            } => {
+                f(*map, *map_ty);
+                f(*dst, map_ty.val().unwrap());
+                by.accum(&mut f);
+            }
+            IncFloatConstKey {
+                map_ty,
+                map,
+                dst,
+                by,
+                ..
+            } => {
+                f(*map, *map_ty);
+                f(*dst, map_ty.val().unwrap());
+                by.accum(&mut f);
+            }
             Push(ty, reg) => f(*reg, *ty),
             Pop(ty, reg) => f(*reg, *ty),
             SetFI(key, val) => {
                 key.accum(&mut f);
                 val.accum(&mut f);
             }
-            UpdateUsedFields() | NextFile() | NextLineStdinFused() | Call(_) | Jmp(_) | Ret => {}
+            UpdateUsedFields() | NextFile() | NextLineStdinFused() | Call(_) | Jmp(_) | Ret
+            | Loc(_) => {}
+        }
+    }
+
+    /// Like `accum`, but visits the registers mutably (in the same order) so they can be renamed.
+    pub(crate) fn accum_mut(&mut self, mut f: impl FnMut(&mut NumTy, compile::Ty)) {
+        use Instr::*;
+        match self {
+            StoreConstStr(sr, _s) => sr.accum_mut(&mut f),
+            StoreConstInt(ir, _i) => ir.accum_mut(&mut f),
+            StoreConstFloat(fr, _f) => fr.accum_mut(&mut f),
+            IntToStr(sr, ir) => {
+                sr.accum_mut(&mut f);
+                ir.accum_mut(&mut f)
+            }
+            FloatToStr(sr, fr) | FloatToJSON(sr, fr) => {
+                sr.accum_mut(&mut f);
+                fr.accum_mut(&mut f);
+            }
+            StrToInt(ir, sr) | HexStrToInt(ir, sr) => {
+                ir.accum_mut(&mut f);
+                sr.accum_mut(&mut f);
+            }
+            StrToFloat(fr, sr) => {
+                fr.accum_mut(&mut f);
+                sr.accum_mut(&mut f);
+            }
+            FloatToInt(ir, fr) => {
+                ir.accum_mut(&mut f);
+                fr.accum_mut(&mut f);
+            }
+            IntToFloat(fr, ir) => {
+                fr.accum_mut(&mut f);
+                ir.accum_mut(&mut f);
+            }
+            AddInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            AddFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            MulInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            MulFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            MinusInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            MinusFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            ModInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            ModFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            Pow(res, l, r) | Div(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            Not(res, ir) => {
+                res.accum_mut(&mut f);
+                ir.accum_mut(&mut f)
+            }
+            NotStr(res, sr) => {
+                res.accum_mut(&mut f);
+                sr.accum_mut(&mut f)
+            }
+            NegInt(res, ir) => {
+                res.accum_mut(&mut f);
+                ir.accum_mut(&mut f)
+            }
+            NegFloat(res, fr) => {
+                res.accum_mut(&mut f);
+                fr.accum_mut(&mut f)
+            }
+            Float1(_, dst, src) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            Float2(_, dst, x, y) => {
+                dst.accum_mut(&mut f);
+                x.accum_mut(&mut f);
+                y.accum_mut(&mut f);
+            }
+            Int1(_, dst, src) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            Int2(_, dst, x, y) => {
+                dst.accum_mut(&mut f);
+                x.accum_mut(&mut f);
+                y.accum_mut(&mut f);
+            }
+            Rand(res) => res.accum_mut(&mut f),
+            Srand(res, seed) => {
+                res.accum_mut(&mut f);
+                seed.accum_mut(&mut f)
+            }
+            ReseedRng(res) => res.accum_mut(&mut f),
+            StartsWithConst(res, s, _) | ContainsConst(res, s, _) => {
+                res.accum_mut(&mut f);
+                s.accum_mut(&mut f);
+            }
+            Concat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            Match(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            IsMatch(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            MatchConst(res, src, _) | IsMatchConst(res, src, _) | MatchSet(res, src, _) => {
+                res.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            MatchArr(res, l, r, arr, subsep) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+                arr.accum_mut(&mut f);
+                subsep.accum_mut(&mut f);
+            }
+            SubstrIndex(res, s, t) => {
+                res.accum_mut(&mut f);
+                s.accum_mut(&mut f);
+                t.accum_mut(&mut f);
+            }
+            LenStr(res, s) => {
+                res.accum_mut(&mut f);
+                s.accum_mut(&mut f)
+            }
+            GSub(res, pat, s, in_s) | Sub(res, pat, s, in_s) => {
+                res.accum_mut(&mut f);
+                pat.accum_mut(&mut f);
+                s.accum_mut(&mut f);
+                in_s.accum_mut(&mut f);
+            }
+            GSubConst(res, _, s, in_s) | SubConst(res, _, s, in_s) => {
+                res.accum_mut(&mut f);
+                s.accum_mut(&mut f);
+                in_s.accum_mut(&mut f);
+            }
+            GenSubDynamic(res, pat, s, how, in_s) => {
+                res.accum_mut(&mut f);
+                pat.accum_mut(&mut f);
+                s.accum_mut(&mut f);
+                how.accum_mut(&mut f);
+                in_s.accum_mut(&mut f);
+            }
+            EscapeCSV(res, s) | EscapeTSV(res, s) | EscapeJSON(res, s) => {
+                res.accum_mut(&mut f);
+                s.accum_mut(&mut f);
+            }
+            Substr(res, base, l, r) => {
+                res.accum_mut(&mut f);
+                base.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            LTFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            LTInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            LTStr(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            GTFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            GTInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            GTStr(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            LTEFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            LTEInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            LTEStr(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            GTEFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            GTEInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            GTEStr(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            EQFloat(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            EQInt(res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            EQStr(res, l, r) | StrNumCmp(_, res, l, r) => {
+                res.accum_mut(&mut f);
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            SetColumn(dst, src) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f)
+            }
+            GetColumn(dst, src) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f)
+            }
+            JoinCSV(dst, start, end) | JoinTSV(dst, start, end) | JoinJSON(dst, start, end) => {
+                dst.accum_mut(&mut f);
+                start.accum_mut(&mut f);
+                end.accum_mut(&mut f);
+            }
+            JoinColumns(dst, start, end, sep) => {
+                dst.accum_mut(&mut f);
+                start.accum_mut(&mut f);
+                end.accum_mut(&mut f);
+                sep.accum_mut(&mut f);
+            }
+            ToUpperAscii(dst, src) | ToLowerAscii(dst, src) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            SplitInt(flds, to_split, arr, pat) => {
+                flds.accum_mut(&mut f);
+                to_split.accum_mut(&mut f);
+                arr.accum_mut(&mut f);
+                pat.accum_mut(&mut f);
+            }
+            SplitStr(flds, to_split, arr, pat) => {
+                flds.accum_mut(&mut f);
+                to_split.accum_mut(&mut f);
+                arr.accum_mut(&mut f);
+                pat.accum_mut(&mut f);
+            }
+            Sprintf { dst, fmt, args } => {
+                dst.accum_mut(&mut f);
+                fmt.accum_mut(&mut f);
+                for (reg, ty) in args.iter_mut() {
+                    f(reg, *ty);
+                }
+            }
+            CallNative { dst, args, .. } => {
+                f(&mut dst.0, dst.1);
+                for (reg, ty) in args.iter_mut() {
+                    f(reg, *ty);
+                }
+            }
+            Printf { output, fmt, args } => {
+                if let Some((path_reg, _)) = output.as_deref_mut() {
+                    path_reg.accum_mut(&mut f);
+                }
+                fmt.accum_mut(&mut f);
+                for (reg, ty) in args.iter_mut() {
+                    f(reg, *ty);
+                }
+            }
+            PrintAll { output, args } => {
+                if let Some((path_reg, _)) = output.as_deref_mut() {
+                    path_reg.accum_mut(&mut f);
+                }
+                for reg in args.iter_mut() {
+                    reg.accum_mut(&mut f)
+                }
+            }
+            Close(dst, file) | Flush(dst, file) => {
+                dst.accum_mut(&mut f);
+                file.accum_mut(&mut f)
+            }
+            FlushAll(dst) => dst.accum_mut(&mut f),
+            RunCmd(dst, cmd) => {
+                dst.accum_mut(&mut f);
+                cmd.accum_mut(&mut f);
+            }
+            Exit(code) | ProfileRule(code) => code.accum_mut(&mut f),
+            Lookup {
+                map_ty,
+                dst,
+                map,
+                key,
+            } => {
+                let (k, v) = (map_ty.key().unwrap(), map_ty.val().unwrap());
+                f(dst, v);
+                f(key, k);
+                f(map, *map_ty);
+            }
+            LookupStr {
+                map_ty,
+                dst,
+                map,
+                key,
+            } => {
+                dst.accum_mut(&mut f);
+                f(key, map_ty.key().unwrap());
+                f(map, *map_ty);
+            }
+            Contains {
+                map_ty,
+                dst,
+                map,
+                key,
+            } => {
+                let k = map_ty.key().unwrap();
+                f(dst, Ty::Int);
+                f(key, k);
+                f(map, *map_ty);
+            }
+            Delete { map_ty, map, key } => {
+                let k = map_ty.key().unwrap();
+                f(key, k);
+                f(map, *map_ty);
+            }
+            Clear { map_ty, map } => f(map, *map_ty),
+            Len { map_ty, map, dst } => {
+                f(dst, Ty::Int);
+                f(map, *map_ty);
+            }
+            ToJSON { map_ty, dst, map } => {
+                dst.accum_mut(&mut f);
+                f(map, *map_ty);
+            }
+            JoinArray {
+                map_ty,
+                dst,
+                map,
+                sep,
+                all,
+            } => {
+                dst.accum_mut(&mut f);
+                f(map, *map_ty);
+                sep.accum_mut(&mut f);
+                all.accum_mut(&mut f);
+            }
+            ReadArray {
+                map_ty,
+                dst,
+                file,
+                map,
+            }
+            | WriteArray {
+                map_ty,
+                dst,
+                file,
+                map,
+            } => {
+                dst.accum_mut(&mut f);
+                file.accum_mut(&mut f);
+                f(map, *map_ty);
+            }
+            IterBegin { map_ty, map, dst } => {
+                f(dst, map_ty.key_iter().unwrap());
+                f(map, *map_ty);
+            }
+            Store {
+                map_ty,
+                map,
+                key,
+                val,
+            } => {
+                f(map, *map_ty);
+                f(key, map_ty.key().unwrap());
+                f(val, map_ty.val().unwrap());
+            }
+            IncInt {
+                map_ty,
+                map,
+                key,
+                dst,
+                by,
+            } => {
+                f(map, *map_ty);
+                f(key, map_ty.key().unwrap());
+                f(dst, map_ty.val().unwrap());
+                by.accum_mut(&mut f);
+            }
+            IncFloat {
+                map_ty,
+                map,
+                key,
+                dst,
+                by,
+            } => {
+                f(map, *map_ty);
+                f(key, map_ty.key().unwrap());
+                f(dst, map_ty.val().unwrap());
+                by.accum_mut(&mut f);
+            }
+            LoadVarStr(dst, _var) => dst.accum_mut(&mut f),
+            StoreVarStr(_var, src) => src.accum_mut(&mut f),
+            LoadVarInt(dst, _var) => dst.accum_mut(&mut f),
+            StoreVarInt(_var, src) => src.accum_mut(&mut f),
+            LoadVarIntMap(dst, _var) => dst.accum_mut(&mut f),
+            StoreVarIntMap(_var, src) => src.accum_mut(&mut f),
+            LoadVarStrMap(dst, _var) => dst.accum_mut(&mut f),
+            StoreVarStrMap(_var, src) => src.accum_mut(&mut f),
+
+            LoadSlot { ty, dst, .. } => f(dst, *ty),
+            StoreSlot { ty, src, .. } => f(src, *ty),
+
+            IterHasNext { iter_ty, dst, iter } => {
+                f(dst, Ty::Int);
+                f(iter, *iter_ty);
+            }
+            IterGetNext { iter_ty, dst, iter } => {
+                f(dst, iter_ty.iter().unwrap());
+                f(iter, *iter_ty);
+            }
+            Mov(ty, dst, src) => {
+                f(dst, *ty);
+                f(src, *ty);
+            }
+            AllocMap(ty, reg) => f(reg, *ty),
+            ReadErr(dst, file, _) => {
+                dst.accum_mut(&mut f);
+                file.accum_mut(&mut f)
+            }
+            NextLine(dst, file, _) => {
+                dst.accum_mut(&mut f);
+                file.accum_mut(&mut f)
+            }
+            ReadErrStdin(dst) => dst.accum_mut(&mut f),
+            NextLineStdin(dst) => dst.accum_mut(&mut f),
+            JmpIf(cond, _lbl) => cond.accum_mut(&mut f),
+            GetColumnFloat(dst, src) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            JmpIfInt(_, l, r, _) => {
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            JmpIfFloat(_, l, r, _) => {
+                l.accum_mut(&mut f);
+                r.accum_mut(&mut f);
+            }
+            JmpIfConstMatch(_, s, _, _) => s.accum_mut(&mut f),
+            AddIntImm(dst, src, _) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            EQStrConst(dst, src, _) => {
+                dst.accum_mut(&mut f);
+                src.accum_mut(&mut f);
+            }
+            LookupConstKey {
+                map_ty, dst, map, ..
+            } => {
+                f(dst, map_ty.val().unwrap());
+                f(map, *map_ty);
+            }
+            StoreConstKey {
+                map_ty, map, val, ..
+            } => {
+                f(map, *map_ty);
+                f(val, map_ty.val().unwrap());
+            }
+            IncIntConstKey {
+                map_ty,
+                map,
+                dst,
+                by,
+                ..
+            } => {
+                f(map, *map_ty);
+                f(dst, map_ty.val().unwrap());
+                by.accum_mut(&mut f);
+            }
+            IncFloatConstKey {
+                map_ty,
+                map,
+                dst,
+                by,
+                ..
+            } => {
+                f(map, *map_ty);
+                f(dst, map_ty.val().unwrap());
+                by.accum_mut(&mut f);
+            }
+            Push(ty, reg) => f(reg, *ty),
+            Pop(ty, reg) => f(reg, *ty),
+            SetFI(key, val) => {
+                key.accum_mut(&mut f);
+                val.accum_mut(&mut f);
+            }
+            UpdateUsedFields() | NextFile() | NextLineStdinFused() | Call(_) | Jmp(_) | Ret
+            | Loc(_) => {}
         }
     }
 }
@@ -0,0 +1,59 @@
+//! A cache of compiled programs on disk, for --cache.
+//!
+//! Each entry is a bytecode image (see `image`), stored under a name derived from a hash of its
+//! key: the program text, the options it was compiled with and the version of frawk that compiled
+//! it. The whole key is stored at the start of the entry as well, and an entry is only used if it
+//! matches exactly, so a collision between hashes costs a recompile rather than running the wrong
+//! program.
+//!
+//! Entries are written to a temporary file and then renamed into place, so that several copies of
+//! frawk running the same script at once never see a partly written entry. Failing to read or
+//! write the cache is not an error; the program is compiled as though there were no cache. Nothing
+//! is ever removed from the cache.
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The default cache directory: `$XDG_CACHE_HOME/frawk`, or `~/.cache/frawk`.
+pub(crate) fn default_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => Some(Path::new(&dir).join("frawk")),
+        _ => std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache").join("frawk")),
+    }
+}
+
+fn entry_path(dir: &Path, key: &[u8]) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    dir.join(format!("{:016x}", hasher.finish()))
+}
+
+/// The image cached in `dir` under `key`, if there is one.
+pub(crate) fn load(dir: &Path, key: &[u8]) -> Option<Vec<u8>> {
+    let mut entry = fs::read(entry_path(dir, key)).ok()?;
+    let len = u64::from_le_bytes(entry.get(..8)?.try_into().ok()?) as usize;
+    if entry.get(8..)?.get(..len)? != key {
+        return None;
+    }
+    Some(entry.split_off(8 + len))
+}
+
+/// Cache `image` in `dir` under `key`.
+pub(crate) fn store(dir: &Path, key: &[u8], image: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = entry_path(dir, key);
+    let mut entry = Vec::with_capacity(8 + key.len() + image.len());
+    entry.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    entry.extend_from_slice(key);
+    entry.extend_from_slice(image);
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&tmp, entry)?;
+    let res = fs::rename(&tmp, &path);
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
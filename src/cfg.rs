@@ -1,8 +1,10 @@
 use crate::arena;
 use crate::ast::{self, Expr, Stmt, Unop};
 use crate::builtins::{self, IsSprintf};
-use crate::common::{Either, FileSpec, Graph, NodeIx, NumTy, Result, Stage};
+use crate::common::{Aggregation, Either, FileSpec, Graph, NodeIx, NumTy, Result, Stage};
 use crate::dom;
+use crate::lexer::SourceLoc;
+use crate::parallel_safety;
 
 use hashbrown::{HashMap, HashSet};
 use petgraph::Direction;
@@ -49,6 +51,8 @@ impl<I> FunctionName<I> {
 pub(crate) struct BasicBlock<'a> {
     pub q: VecDeque<PrimStmt<'a>>,
     pub sealed: bool,
+    // The location of the last statement added to the block, if there is one. See `add_stmt`.
+    loc: Option<Option<SourceLoc>>,
 }
 
 // None indicates `else`
@@ -134,6 +138,7 @@ impl Ident {
 }
 
 #[derive(Copy, Clone, Debug, Default)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Escaper {
     CSV,
     TSV,
@@ -176,6 +181,9 @@ pub(crate) enum PrimStmt<'a> {
     SetBuiltin(builtins::Variable, PrimExpr<'a>),
     Return(PrimVal<'a>),
     IterDrop(PrimVal<'a>),
+    // The statements that follow come from this location in the program, if they come from the
+    // program text at all.
+    Loc(Option<SourceLoc>),
 
     // Printf is its own node because it is easier to handle varargs explicitly rather than to
     // refactor the whole `builtins` module to support them.
@@ -265,6 +273,7 @@ impl<'a> PrimStmt<'a> {
                 }
             }
             IterDrop(v) | Return(v) => v.replace(update),
+            Loc(_) => {}
         }
     }
 }
@@ -288,6 +297,14 @@ pub(crate) struct ProgramContext<'a, I> {
     pub fold_regex_constants: bool,
     // Thread through information regarding header columns used.
     pub parse_header: bool,
+    // How to combine the values of globals that the main loop of a parallel program accumulates
+    // into; see parallel_safety::analyze.
+    pub(crate) aggregations: HashMap<Ident, Aggregation>,
+    // Why running the main loop of a parallel program on several threads might change its meaning.
+    // This is empty for programs that run serially.
+    pub(crate) parallel_hazards: parallel_safety::Hazards,
+    // Where each toplevel item starts in the program text; see source_map::SourceMap.
+    pub(crate) rules: Vec<(SourceLoc, ast::Rule<I>)>,
 }
 
 impl<'a, I> ProgramContext<'a, I> {
@@ -318,7 +335,7 @@ impl<'a> ProgramContext<'a, &'a str> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SepAssign<'a> {
     Potential {
         field_sep: Option<&'a [u8]>,
@@ -422,6 +439,15 @@ where
         }
     }
 
+    /// The global variables named in the program.
+    pub(crate) fn globals(&self) -> impl Iterator<Item = (&I, Ident)> + '_ {
+        self.shared
+            .hm
+            .iter()
+            .filter(|(_, id)| id.global)
+            .map(|(name, id)| (name, *id))
+    }
+
     // for debugging: get a mapping from the raw identifiers to the synthetic ones.
     pub(crate) fn _invert_ident(&self) -> HashMap<Ident, I> {
         self.shared
@@ -444,6 +470,8 @@ where
             max: 1, // 0 reserved for assigning to "unused" var for side-effecting operations
             conds: Default::default(),
             esc,
+            natives: p.native_functions.iter().cloned().collect(),
+            export_globals: p.export_globals,
         };
         let mut func_table: HashMap<FunctionName<I>, NumTy> = Default::default();
         let mut funcs: Vec<Function<'a, I>> = Default::default();
@@ -460,6 +488,9 @@ where
             if let Ok(bi) = builtins::Function::try_from(fundec.name.clone()) {
                 return err!("attempted redefinition of builtin function {}", bi);
             }
+            if shared.natives.contains_key(&fundec.name) {
+                return err!("attempted redefinition of native function {}", fundec.name);
+            }
             // All exit blocks simply return the designated return node. Return statements in the
             // AST will becode assignments to this variable followed by an unconditional jump to
             // this block.
@@ -500,7 +531,7 @@ where
                         ctx: &mut shared,
                         f: &mut func,
                         func_table: &func_table,
-                        parse_header: p.parse_header,
+                        fi_in_scope: p.parse_header || p.input_names_columns,
                     }
                     .fill(s)?;
                     func_table.insert($name, offset as NumTy);
@@ -520,7 +551,7 @@ where
                 ctx: &mut shared,
                 f: funcs.get_mut(f as usize).unwrap(),
                 func_table: &func_table,
-                parse_header: p.parse_header,
+                fi_in_scope: p.parse_header || p.input_names_columns,
             }
             .fill(fundec.body)?;
         }
@@ -555,6 +586,18 @@ where
             }
         };
 
+        let (aggregations, parallel_hazards) = match main_offset {
+            Stage::Main(_) => Default::default(),
+            Stage::Par { .. } => {
+                let (aggregations, hazards) = parallel_safety::analyze(p);
+                let aggregations = aggregations
+                    .into_iter()
+                    .filter_map(|(x, agg)| Some((*shared.hm.get(&x)?, agg)))
+                    .collect();
+                (aggregations, hazards)
+            }
+        };
+
         Ok(ProgramContext {
             shared,
             funcs,
@@ -562,6 +605,9 @@ where
             allow_arbitrary_commands: false,
             fold_regex_constants: false,
             parse_header: p.parse_header,
+            aggregations,
+            parallel_hazards,
+            rules: p.rules.clone(),
         })
     }
 }
@@ -570,7 +616,7 @@ struct View<'a, 'b, I> {
     ctx: &'a mut GlobalContext<I>,
     f: &'a mut Function<'b, I>,
     func_table: &'a HashMap<FunctionName<I>, NumTy>,
-    parse_header: bool,
+    fi_in_scope: bool,
 }
 
 #[derive(Debug)]
@@ -591,6 +637,10 @@ struct GlobalContext<I> {
     max: NumTy,
     conds: HashMap<usize, Ident>,
     esc: Escaper,
+    // The functions registered by a program embedding frawk, by name.
+    natives: HashMap<I, builtins::Native>,
+    // Never treat globals as local to main, which would let them be renamed like locals.
+    export_globals: bool,
 }
 
 impl<I> GlobalContext<I> {
@@ -642,6 +692,8 @@ pub(crate) struct Function<'a, I> {
     //
     // NB: We only support doing this from main.
     toplevel_header: Option<NodeIx>,
+    // The location of the statement being converted, if it comes from the program text.
+    loc: Option<SourceLoc>,
 
     vars: VarAssigns<'a>,
 
@@ -668,6 +720,7 @@ impl<'a, I> Function<'a, I> {
             exit,
             loop_ctx: Default::default(),
             toplevel_header: None,
+            loc: None,
             vars: Default::default(),
             dt: Default::default(),
             df: Default::default(),
@@ -1004,6 +1057,12 @@ where
                 self.seal(current_open);
                 current_open
             }
+            Loc(loc, s) => {
+                let outer = self.f.loc.replace(*loc);
+                let res = self.convert_stmt(s, current_open);
+                self.f.loc = outer;
+                res?
+            }
         })
     }
 
@@ -1085,8 +1144,8 @@ where
             Var(id) => {
                 if let Ok(bi) = builtins::Variable::try_from(id.clone()) {
                     // To maximize compatibility with other scripts, we don't have FI in scope as a
-                    // builtin if we are not parsing the header line.
-                    if matches!(bi, builtins::Variable::FI) && !self.parse_header {
+                    // builtin unless we are parsing the header line or the input names its columns.
+                    if matches!(bi, builtins::Variable::FI) && !self.fi_in_scope {
                         let ident = self.get_identifier(id);
                         PrimExpr::Val(PrimVal::Var(ident))
                     } else {
@@ -1228,8 +1287,10 @@ where
                 // Another use of non-structural recursion for desugaring. Here we desugar:
                 //   getline var < file
                 // to
-                //   var = nextline(file)
-                //   readerr(file)
+                //   line = nextline(file)
+                //   res = readerr(file)
+                //   if (res > 0) var = line
+                //   res
                 // And we fill in various other pieces of sugar as well. Informally:
                 //  getline < file => getline $0 < file
                 //  getline var => getline var < stdin
@@ -1260,30 +1321,15 @@ where
                         )
                     }
                     (Some(from), Some(into)) => {
-                        let (next, _) = self.convert_expr(
-                            &ast::Expr::Assign(
-                                into,
-                                &ast::Expr::Call(Either::Right(next_line), &[from]),
-                            ),
-                            current_open,
-                        )?;
-                        return self.convert_expr(
-                            &ast::Expr::Call(Either::Right(read_err), &[from]),
-                            next,
-                        );
+                        let (next, from_v) = self.convert_val(from, current_open)?;
+                        let line = PrimExpr::CallBuiltin(next_line, smallvec![from_v.clone()]);
+                        let res = PrimExpr::CallBuiltin(read_err, smallvec![from_v]);
+                        return self.do_getline(into, line, res, next);
                     }
                     (None /*stdin*/, Some(into)) => {
-                        let (next, _) = self.convert_expr(
-                            &ast::Expr::Assign(
-                                into,
-                                &ast::Expr::Call(Either::Right(NextlineStdin), &[]),
-                            ),
-                            current_open,
-                        )?;
-                        return self.convert_expr(
-                            &ast::Expr::Call(Either::Right(ReadErrStdin), &[]),
-                            next,
-                        );
+                        let line = PrimExpr::CallBuiltin(NextlineStdin, smallvec![]);
+                        let res = PrimExpr::CallBuiltin(ReadErrStdin, smallvec![]);
+                        return self.do_getline(into, line, res, current_open);
                     }
                 };
             }
@@ -1291,6 +1337,58 @@ where
         Ok((current_open, res_expr))
     }
 
+    // Read `line` and then the status `res` of that read, assigning the line to `into` only if
+    // the read succeeded: at the end of the input or on an error, `into` keeps its old value.
+    fn do_getline<'c>(
+        &mut self,
+        into: &'c Expr<'c, 'b, I>,
+        line: PrimExpr<'b>,
+        res: PrimExpr<'b>,
+        mut current_open: NodeIx,
+    ) -> Result<(NodeIx, PrimExpr<'b>)> {
+        use builtins::Function;
+        // Subscripts (as in `getline a[n++]`) are evaluated whether or not a line is read.
+        let index = if let Expr::Index(arr, ix) = into {
+            let (next, arr_e) = self.convert_expr(arr, current_open)?;
+            let arr_id = if let PrimExpr::Val(PrimVal::Var(id)) = arr_e {
+                id
+            } else {
+                let arr_id = self.fresh_local();
+                self.add_stmt(next, PrimStmt::AsgnVar(arr_id, arr_e))?;
+                arr_id
+            };
+            let (next, ix_v) = self.convert_val(ix, next)?;
+            current_open = next;
+            Some((arr_id, ix_v))
+        } else {
+            None
+        };
+        let line_v = self.to_val(line, current_open)?;
+        let res_v = self.to_val(res, current_open)?;
+        let ok = self.to_val(
+            PrimExpr::CallBuiltin(
+                Function::Binop(ast::Binop::GT),
+                smallvec![res_v.clone(), PrimVal::ILit(0)],
+            ),
+            current_open,
+        )?;
+        let assign = self.f.cfg.add_node(Default::default());
+        let assign_end = if let Some((arr_id, ix_v)) = index {
+            let stmt = PrimStmt::AsgnIndex(arr_id, ix_v, PrimExpr::Val(line_v));
+            self.add_stmt(assign, stmt)?;
+            assign
+        } else {
+            self.do_assign(into, |_| PrimExpr::Val(line_v), assign)?.0
+        };
+        let next = self.f.cfg.add_node(Default::default());
+        self.f
+            .cfg
+            .add_edge(current_open, assign, Transition::new(ok));
+        self.f.cfg.add_edge(current_open, next, Transition::null());
+        self.f.cfg.add_edge(assign_end, next, Transition::null());
+        Ok((next, PrimExpr::Val(res_v)))
+    }
+
     fn guarded_else(&mut self, from: NodeIx, to: NodeIx) {
         if self.f.cfg.node_weight(from).unwrap().sealed {
             return;
@@ -1597,6 +1695,22 @@ where
                 if let Ok(bi) = builtins::Function::try_from(fname.clone()) {
                     // Okay, there's a builtin in here.
                     Either::Right(bi)
+                } else if let Some(native) = self.ctx.natives.get(fname) {
+                    if args.len() != native.arity() {
+                        return err!(
+                            "function {} expected {} inputs but got {}",
+                            fname,
+                            native.arity(),
+                            args.len()
+                        );
+                    }
+                    Either::Right(builtins::Function::Native(*native))
+                } else if let (false, Some(bi)) = (
+                    self.func_table
+                        .contains_key(&FunctionName::Named(fname.clone())),
+                    builtins::Function::fallback(&fname.to_string()),
+                ) {
+                    Either::Right(bi)
                 } else {
                     // We'll keep this as a raw identifier. Below, we'll check if it's a UDF, or if
                     // the function does not exist.
@@ -1663,6 +1777,22 @@ where
                     }
                 }
 
+                // join(arr) => join(arr, OFS, 0)
+                // join(arr, sep) => join(arr, sep, 0)
+                if bi == builtins::Function::JoinArray && !args.is_empty() {
+                    if args.len() == 1 {
+                        let ofs = self.fresh_local();
+                        self.add_stmt(
+                            current_open,
+                            PrimStmt::AsgnVar(ofs, PrimExpr::LoadBuiltin(builtins::Variable::OFS)),
+                        )?;
+                        prim_args.push(PrimVal::Var(ofs));
+                    }
+                    if args.len() <= 2 {
+                        prim_args.push(PrimVal::ILit(0));
+                    }
+                }
+
                 // substr(s, a) => substr(s, a, INT_MAX); as we always clamp the second value to
                 // the length of s.
                 if bi == builtins::Function::Substr && args.len() == 2 {
@@ -1676,6 +1806,11 @@ where
                     bi = builtins::Function::ReseedRng;
                 }
 
+                // fflush() => flush stdout and every open file and command
+                if bi == builtins::Function::Flush && args.is_empty() {
+                    bi = builtins::Function::FlushAll;
+                }
+
                 // sub/gsub are the most complicated cases. Why? Because they take their last
                 // argument as an out-param. Not only is the 3rd argument "implicitly $0", but we
                 // assign into $0 if that happens.
@@ -1784,7 +1919,7 @@ where
             let next = self.fresh();
             self.ctx.hm.insert(i.clone(), next);
             self.ctx.may_rename.push(next);
-            if self.f.name.is_main() {
+            if self.f.name.is_main() && !self.ctx.export_globals {
                 self.ctx.local_globals.insert(next.low);
             }
             next
@@ -1803,6 +1938,13 @@ where
                 bb.q.back().unwrap()
             );
         }
+        // Mark where statements come from whenever that changes, including at the start of every
+        // block: the markers end up in the bytecode in the order the blocks are laid out, not the
+        // order in which they run.
+        if bb.loc != Some(self.f.loc) {
+            bb.loc = Some(self.f.loc);
+            bb.q.push_back(PrimStmt::Loc(self.f.loc));
+        }
         bb.q.push_back(stmt);
         Ok(())
     }
@@ -0,0 +1,1715 @@
+//! The `frawk` command-line interface.
+use clap::{Arg, Command};
+
+use crate::arena::Arena;
+use crate::cache;
+use crate::cfg::{self, Escaper};
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
+use crate::codegen::{self, intrinsics::IntoRuntime};
+use crate::common::{CancelSignal, ExecutionStrategy, OutputOrder, Stage};
+use crate::coverage::{self, Outline};
+use crate::debugger::Debugger;
+use crate::embed::Callback;
+use crate::plugin;
+use crate::pushdown::FieldSet;
+use crate::repl::Session;
+#[cfg(feature = "parquet_input")]
+use crate::runtime::splitter::parquet::ParquetReader;
+use crate::runtime::{
+    self,
+    splitter::{
+        batch::{byte_splittable, ByteReader, CSVReader, InputFormat},
+        json::JsonReader,
+        regex::RegexSplitter,
+    },
+    ChainedReader, KeyOrder, LineReader, CHUNK_SIZE,
+};
+use crate::timing::{Counted, Timing};
+use crate::{
+    ast, builtins, bytecode, compile, image, lexer, lint, parsing, pretty, sandbox, types,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::iter::once;
+use std::mem;
+use std::path::PathBuf;
+
+macro_rules! fail {
+    ($($t:tt)*) => {{
+        eprintln_ignore!($($t)*);
+        std::process::exit(1)
+    }}
+}
+
+#[derive(Clone, Debug)]
+struct PreludeScalars {
+    arbitrary_shell: bool,
+    fold_regexes: bool,
+    parse_header: bool,
+    // Whether the input format names its own columns (JSON or Parquet).
+    input_names_columns: bool,
+    // Whether to compile the program for --profile.
+    profile_rules: bool,
+    // Whether to reject programs that run commands or use files, for --sandbox.
+    sandbox: bool,
+    // Whether to keep global variables in registers where --debug can find them.
+    debug: bool,
+    escaper: Escaper,
+    stage: Stage<()>,
+}
+
+/// Where to write a profile of the program, and which kind.
+#[derive(Copy, Clone)]
+enum ProfileOutput<'a> {
+    // --profile-bytecode: execution counts for each instruction.
+    Bytecode(&'a str),
+    // --profile: the time spent in each rule, alongside the program source.
+    Rules {
+        path: &'a str,
+        source: &'a str,
+    },
+    // --coverage: how often each line, pattern and branch of the program ran.
+    Coverage {
+        path: &'a str,
+        source: &'a str,
+        outline: &'a Outline,
+    },
+}
+
+#[derive(Debug)]
+struct RawPrelude {
+    argv: Vec<String>,
+    // The input files and var=value assignments following the program.
+    operands: Vec<String>,
+    // The files passed with -f, along with the line of the program text on which each one starts.
+    program_files: Vec<(String, usize)>,
+    var_decs: Vec<String>,
+    field_sep: Option<String>,
+    record_sep: Option<&'static str>,
+    output_sep: Option<&'static str>,
+    output_record_sep: Option<&'static str>,
+    // Functions provided by plugins, indexed by their id.
+    native_functions: Vec<(String, builtins::Native)>,
+    scalars: PreludeScalars,
+}
+
+struct Prelude<'a> {
+    var_decs: Vec<(&'a str, &'a ast::Expr<'a, 'a, &'a str>)>,
+    field_sep: Option<&'a [u8]>,
+    record_sep: Option<&'a [u8]>,
+    output_sep: Option<&'a [u8]>,
+    output_record_sep: Option<&'a [u8]>,
+    argv: Vec<&'a str>,
+    operand_vardecs: Vec<(usize, &'a str, &'a ast::Expr<'a, 'a, &'a str>)>,
+    operand_files: Vec<(usize, &'a str)>,
+    program_files: Vec<(String, usize)>,
+    native_functions: Vec<(&'a str, builtins::Native)>,
+    scalars: PreludeScalars,
+}
+
+// TODO: make file reading lazy
+fn open_file_read(f: &str) -> impl io::BufRead {
+    enum LazyReader<F, R> {
+        Uninit(F),
+        Init(R),
+    }
+
+    impl<R, F: FnMut() -> io::Result<R>> LazyReader<F, R> {
+        fn delegate<T>(&mut self, next: impl FnOnce(&mut R) -> io::Result<T>) -> io::Result<T> {
+            match self {
+                LazyReader::Uninit(f) => {
+                    *self = LazyReader::Init(f()?);
+                    self.delegate(next)
+                }
+                LazyReader::Init(r) => next(r),
+            }
+        }
+    }
+
+    // TODO: delegate other methods on read.
+    impl<R: io::Read, F: FnMut() -> io::Result<R>> io::Read for LazyReader<F, R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.delegate(|r| r.read(buf))
+        }
+    }
+
+    // As in other awks, "-" reads from standard input, and files that cannot be read are skipped
+    // with a warning rather than ending the program.
+    let filename = String::from(f);
+    BufReader::new(Counted(LazyReader::Uninit(
+        move || -> io::Result<Box<dyn io::Read + Send>> {
+            if filename == "-" {
+                return Ok(Box::new(io::stdin()));
+            }
+            File::open(filename.as_str())
+                .and_then(|file| {
+                    if file.metadata()?.is_dir() {
+                        Err(io::Error::other("is a directory"))
+                    } else {
+                        runtime::compress::decompress(&filename, file)
+                    }
+                })
+                .or_else(|e| {
+                    runtime::record_io_error(e.kind());
+                    eprintln_ignore!("warning: skipping input file {}: {}", filename, e);
+                    Ok(Box::new(io::empty()))
+                })
+        },
+    )))
+}
+
+fn chained<LR: LineReader>(lr: LR) -> ChainedReader<LR> {
+    ChainedReader::new(std::iter::once(lr))
+}
+
+/// Whether the operand `s` has the form var=value, in which case it is an assignment to perform
+/// when the input reaches it rather than the name of an input file.
+fn is_assignment(s: &str) -> bool {
+    s.split_once('=')
+        .is_some_and(|(ident, _)| !ident.is_empty() && lexer::is_ident(ident))
+}
+
+fn get_vars<'a, 'b>(
+    vars: impl Iterator<Item = &'b str>,
+    a: &'a Arena,
+    buf: &mut Vec<u8>,
+) -> Vec<(&'a str, &'a ast::Expr<'a, 'a, &'a str>)> {
+    let mut res = Vec::new();
+    let mut split_buf = Vec::new();
+    for var in vars {
+        buf.clear();
+        split_buf.clear();
+        split_buf.extend(var.splitn(2, '='));
+        if split_buf.len() != 2 {
+            fail!(
+                "received -v flag without an '=' sign: {} (split_buf={:?})",
+                var,
+                split_buf
+            );
+        }
+        let ident = a.alloc_str(split_buf[0].trim());
+        if !lexer::is_ident(ident) {
+            fail!(
+                "invalid identifier for left-hand side of -v flag: {}",
+                ident
+            );
+        }
+        let str_lit = lexer::parse_string_literal(split_buf[1], a, buf);
+        res.push((ident, a.alloc(ast::Expr::StrLit(str_lit))))
+    }
+    res
+}
+
+fn get_prelude<'a>(a: &'a Arena, raw: &RawPrelude) -> Prelude<'a> {
+    let mut buf = Vec::new();
+    let record_sep = raw
+        .record_sep
+        .map(|s| lexer::parse_string_literal(s, a, &mut buf));
+    let output_sep = raw
+        .output_sep
+        .map(|s| lexer::parse_string_literal(s, a, &mut buf));
+    let output_record_sep = raw
+        .output_record_sep
+        .map(|s| lexer::parse_string_literal(s, a, &mut buf));
+    let field_sep = raw
+        .field_sep
+        .as_ref()
+        .map(|s| lexer::parse_string_literal(s.as_str(), a, &mut buf));
+    let mut operand_vardecs = Vec::new();
+    let mut operand_files = Vec::new();
+    for (ix, operand) in raw.operands.iter().enumerate() {
+        if is_assignment(operand) {
+            let (ident, exp) = get_vars(once(operand.as_str()), a, &mut buf)[0];
+            operand_vardecs.push((ix, ident, exp));
+        } else {
+            operand_files.push((ix, a.alloc_str(operand.as_str())));
+        }
+    }
+    Prelude {
+        field_sep,
+        record_sep,
+        var_decs: get_vars(raw.var_decs.iter().map(|s| s.as_str()), a, &mut buf),
+        scalars: raw.scalars.clone(),
+        output_sep,
+        output_record_sep,
+        argv: raw.argv.iter().map(|s| a.alloc_str(s.as_str())).collect(),
+        operand_vardecs,
+        operand_files,
+        program_files: raw.program_files.clone(),
+        native_functions: raw
+            .native_functions
+            .iter()
+            .map(|(name, native)| (a.alloc_str(name.as_str()), *native))
+            .collect(),
+    }
+}
+
+/// Report a parse error in a program read from `program_files` relative to the file that it
+/// occurred in, rather than to the concatenated program text.
+fn program_file_error<'a>(
+    e: lalrpop_util::ParseError<lexer::Loc, lexer::Tok<'a>, lexer::Error>,
+    program_files: &[(String, usize)],
+) -> String {
+    use lalrpop_util::ParseError::*;
+    let loc = match &e {
+        InvalidToken { location } | UnrecognizedEOF { location, .. } => location,
+        UnrecognizedToken { token, .. } | ExtraToken { token } => &token.0,
+        User { error } => &error.location,
+    };
+    let (file, first_line) = match program_files
+        .iter()
+        .rev()
+        .find(|(_, first_line)| *first_line <= loc.line)
+    {
+        Some((file, first_line)) => (file, *first_line),
+        None => return e.to_string(),
+    };
+    let e = e
+        .map_location(|loc| loc.relative_to(first_line))
+        .map_error(|mut error| {
+            error.location = error.location.relative_to(first_line);
+            error
+        });
+    format!("{}: {}", file, e)
+}
+
+/// Pick how to split the input up among workers for --parallel, using the compiler's analysis of
+/// `ctx`, which must have been built to run in parallel. If it has to run serially, explain why.
+fn parallel_strategy(
+    ctx: &cfg::ProgramContext<&str>,
+    operands: &[String],
+) -> Result<ExecutionStrategy, String> {
+    if operands.iter().any(|op| is_assignment(op)) {
+        return Err("var=value operands are performed as the input reaches them".into());
+    }
+    let hazards = &ctx.parallel_hazards;
+    match hazards.get(ExecutionStrategy::ShardPerRecord) {
+        None => Ok(ExecutionStrategy::ShardPerRecord),
+        Some(reason) if operands.len() <= 1 => Err(reason.into()),
+        // Whole files can still be handed to different workers if the program only depends on
+        // where a record is within its file.
+        Some(_) => match hazards.get(ExecutionStrategy::ShardPerFile) {
+            None => Ok(ExecutionStrategy::ShardPerFile),
+            Some(reason) => Err(reason.into()),
+        },
+    }
+}
+
+/// Outline `prog` for --coverage. The program is parsed again just for this, after it has been
+/// compiled.
+fn coverage_outline(prog: &str) -> Outline {
+    let a = Arena::default();
+    let prog = a.alloc_str(prog);
+    let mut buf = Vec::new();
+    let mut parsed = ast::Prog::from_stage(&a, Stage::Main(()));
+    match parsing::syntax::ProgParser::new().parse(
+        &a,
+        &mut buf,
+        &mut parsed,
+        lexer::Tokenizer::new(prog),
+    ) {
+        Ok(()) => coverage::outline(&parsed),
+        Err(_) => Outline::default(),
+    }
+}
+
+/// Parse `prog` and apply the settings in `prelude` to it.
+fn parse<'a>(prog: &str, a: &'a Arena, mut prelude: Prelude<'a>) -> ast::Prog<'a, 'a, &'a str> {
+    let prog = a.alloc_str(prog);
+    let lexer = lexer::Tokenizer::new(prog);
+    let mut buf = Vec::new();
+    let parser = parsing::syntax::ProgParser::new();
+    let mut prog = ast::Prog::from_stage(a, prelude.scalars.stage.clone());
+    prog.argv = mem::take(&mut prelude.argv);
+    match parser.parse(a, &mut buf, &mut prog, lexer) {
+        Ok(()) => {
+            prog.field_sep = prelude.field_sep;
+            prog.record_sep = prelude.record_sep;
+            prog.prelude_vardecs = prelude.var_decs;
+            prog.operand_vardecs = prelude.operand_vardecs;
+            prog.operand_files = prelude.operand_files;
+            prog.output_sep = prelude.output_sep;
+            prog.output_record_sep = prelude.output_record_sep;
+            prog.parse_header = prelude.scalars.parse_header;
+            prog.profile_rules = prelude.scalars.profile_rules;
+            prog.input_names_columns = prelude.scalars.input_names_columns;
+            prog.export_globals = prelude.scalars.debug;
+            prog.native_functions = prelude.native_functions;
+            if prelude.scalars.sandbox {
+                if let Err(e) = sandbox::check(&prog) {
+                    fail!("{}", e);
+                }
+            }
+            prog
+        }
+        Err(e) => {
+            fail!("{}", program_file_error(e, &prelude.program_files));
+        }
+    }
+}
+
+fn get_context<'a>(
+    prog: &str,
+    a: &'a Arena,
+    prelude: Prelude<'a>,
+) -> cfg::ProgramContext<'a, &'a str> {
+    let scalars = prelude.scalars.clone();
+    context(&parse(prog, a, prelude), a, &scalars)
+}
+
+/// Build the control-flow graph for a parsed program.
+fn context<'a>(
+    prog: &ast::Prog<'a, 'a, &'a str>,
+    a: &'a Arena,
+    scalars: &PreludeScalars,
+) -> cfg::ProgramContext<'a, &'a str> {
+    match cfg::ProgramContext::from_prog(a, prog, scalars.escaper) {
+        Ok(mut ctx) => {
+            ctx.allow_arbitrary_commands = scalars.arbitrary_shell;
+            ctx.fold_regex_constants = scalars.fold_regexes;
+            ctx
+        }
+        Err(e) => fail!("failed to create program context: {}", e),
+    }
+}
+
+/// Print `prog` back out as formatted source. If `typed` is set, precede it with a comment listing
+/// the inferred types of the program's global variables and functions.
+fn dump_ast(prog: &str, raw: &RawPrelude, typed: bool) -> String {
+    let a = Arena::default();
+    let parsed = &parse(prog, &a, get_prelude(&a, raw));
+    let mut res = String::new();
+    if typed {
+        let ctx = match cfg::ProgramContext::from_prog(&a, parsed, raw.scalars.escaper) {
+            Ok(ctx) => ctx,
+            Err(e) => fail!("failed to create program context: {}", e),
+        };
+        let types::TypeInfo { var_tys, func_tys } = match types::get_types(&ctx) {
+            Ok(info) => info,
+            Err(e) => fail!("type error: {}", e),
+        };
+        // Only globals can be mapped back to their names. Skip builtin variables (including SUBSEP,
+        // which every program assigns to), and the hidden variables that the frontend introduces,
+        // whose names are not valid identifiers.
+        let names = ctx._invert_ident();
+        let mut globals = BTreeMap::<&str, compile::Ty>::new();
+        for ((ident, _, _), ty) in var_tys.iter() {
+            let name = match names.get(&ident._base()) {
+                Some(name) => *name,
+                None => continue,
+            };
+            if !lexer::is_ident(name)
+                || name == "SUBSEP"
+                || builtins::Variable::try_from(name).is_ok()
+            {
+                continue;
+            }
+            // Each SSA version of a variable gets its own entry; the one before the variable is
+            // first assigned to is null.
+            let cur = globals.entry(name).or_insert(*ty);
+            if *cur == compile::Ty::Null {
+                *cur = *ty;
+            }
+        }
+        let funcs: BTreeSet<String> = func_tys
+            .iter()
+            .filter_map(|((id, args), ret)| match &ctx.funcs[*id as usize].name {
+                cfg::FunctionName::Named(name) => Some(format!(
+                    "{}({}): {}",
+                    name,
+                    args.iter()
+                        .map(|ty| ty.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    ret
+                )),
+                _ => None,
+            })
+            .collect();
+        for (name, ty) in globals.iter() {
+            res.push_str(&format!("# {}: {}\n", name, ty));
+        }
+        for func in funcs.iter() {
+            res.push_str(&format!("# function {}\n", func));
+        }
+        if !res.is_empty() {
+            res.push('\n');
+        }
+    }
+    res.push_str(&pretty::Source(parsed).to_string());
+    res
+}
+
+/// Check `prog` for likely mistakes with `lint::check`, formatting each warning on a line of its
+/// own, either like a compiler's warnings or as a JSON object. Warnings in programs read with `-f`
+/// are reported relative to the file that they occur in.
+fn lint(prog: &str, raw: &RawPrelude, json: bool) -> Vec<String> {
+    fn json_string(s: &str) -> String {
+        let mut res = String::with_capacity(s.len() + 2);
+        res.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => res.push_str("\\\""),
+                '\\' => res.push_str("\\\\"),
+                '\n' => res.push_str("\\n"),
+                c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+                c => res.push(c),
+            }
+        }
+        res.push('"');
+        res
+    }
+    let a = Arena::default();
+    let parsed = &parse(prog, &a, get_prelude(&a, raw));
+    lint::check(parsed)
+        .into_iter()
+        .map(|w| {
+            let line = w.loc.line as usize;
+            let (file, first_line) = match raw
+                .program_files
+                .iter()
+                .rev()
+                .find(|(_, first_line)| *first_line <= line)
+            {
+                Some((file, first_line)) => (file.as_str(), *first_line),
+                None => ("<program>", 0),
+            };
+            let (line, col) = (line - first_line + 1, w.loc.col + 1);
+            if json {
+                format!(
+                    "{{\"file\":{},\"line\":{},\"column\":{},\"kind\":\"{}\",\"message\":{}}}",
+                    json_string(file),
+                    line,
+                    col,
+                    w.kind.name(),
+                    json_string(&w.message)
+                )
+            } else {
+                format!(
+                    "{}:{}:{}: warning: {} [{}]",
+                    file,
+                    line,
+                    col,
+                    w.message,
+                    w.kind.name()
+                )
+            }
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_interp_with_context<'a>(
+    mut ctx: cfg::ProgramContext<'a, &'a str>,
+    stdin: impl LineReader,
+    ff: impl runtime::writers::FileFactory,
+    num_workers: usize,
+    natives: Vec<Callback>,
+    output_order: OutputOrder,
+    ieee_division: bool,
+    key_order: KeyOrder,
+    profile: Option<ProfileOutput>,
+    mut timing: Option<Timing>,
+) {
+    let image = match &mut timing {
+        Some(timing) => compile::image_timed(&mut ctx, timing),
+        None => compile::image(&mut ctx),
+    };
+    let interp = match image.and_then(|image| image.into_interp(stdin, ff, num_workers)) {
+        Ok(ctx) => ctx,
+        Err(e) => fail!("bytecode compilation failure: {}", e),
+    };
+    if let Some(timing) = &mut timing {
+        timing.end_phase("bytecode generation");
+    }
+    run_interp(
+        interp,
+        natives,
+        output_order,
+        ieee_division,
+        key_order,
+        profile,
+        timing,
+    )
+}
+
+/// Run the program with the bytecode interpreter under the debugger, which reads commands from
+/// `commands` and shows lines of `source`.
+#[allow(clippy::too_many_arguments)]
+fn run_interp_debug<'a>(
+    mut ctx: cfg::ProgramContext<'a, &'a str>,
+    stdin: impl LineReader,
+    ff: impl runtime::writers::FileFactory,
+    natives: Vec<Callback>,
+    ieee_division: bool,
+    key_order: KeyOrder,
+    commands: BufReader<File>,
+    source: &str,
+) {
+    let (mut image, globals) = match compile::image_with_globals(&mut ctx) {
+        Ok(res) => res,
+        Err(e) => fail!("bytecode compilation failure: {}", e),
+    };
+    // Any field can be printed, not just the ones the program uses.
+    image.used_fields = FieldSet::all();
+    let mut interp = match image.into_interp(stdin, ff, /*num_workers=*/ 1) {
+        Ok(interp) => interp,
+        Err(e) => fail!("bytecode compilation failure: {}", e),
+    };
+    let debugger = Debugger::new(Box::new(commands), Box::new(io::stderr()), source, globals);
+    interp.set_debugger(debugger);
+    run_interp(
+        interp,
+        natives,
+        OutputOrder::Any,
+        ieee_division,
+        key_order,
+        /*profile=*/ None,
+        /*timing=*/ None,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_interp_with_image(
+    image: image::Image,
+    stdin: impl LineReader,
+    ff: impl runtime::writers::FileFactory,
+    num_workers: usize,
+    natives: Vec<Callback>,
+    output_order: OutputOrder,
+    ieee_division: bool,
+    key_order: KeyOrder,
+    profile: Option<ProfileOutput>,
+    mut timing: Option<Timing>,
+) {
+    let interp = match image.into_interp(stdin, ff, num_workers) {
+        Ok(interp) => interp,
+        Err(e) => fail!("failed to load bytecode: {}", e),
+    };
+    if let Some(timing) = &mut timing {
+        timing.end_phase("loading bytecode");
+    }
+    run_interp(
+        interp,
+        natives,
+        output_order,
+        ieee_division,
+        key_order,
+        profile,
+        timing,
+    )
+}
+
+fn run_interp(
+    mut interp: bytecode::Interp<impl LineReader>,
+    natives: Vec<Callback>,
+    output_order: OutputOrder,
+    ieee_division: bool,
+    key_order: KeyOrder,
+    profile: Option<ProfileOutput>,
+    timing: Option<Timing>,
+) {
+    interp.set_natives(natives);
+    interp.set_output_order(output_order);
+    interp.set_ieee_division(ieee_division);
+    interp.set_key_order(key_order);
+    if profile.is_some() {
+        interp.enable_profiling();
+    }
+    let res = interp.run();
+    if let Some(profile) = profile {
+        let path = match profile {
+            ProfileOutput::Bytecode(path)
+            | ProfileOutput::Rules { path, .. }
+            | ProfileOutput::Coverage { path, .. } => path,
+        };
+        let written = File::create(path).and_then(|f| {
+            let mut w = io::BufWriter::new(f);
+            match profile {
+                ProfileOutput::Bytecode(_) => interp.profile_report(&mut w)?,
+                ProfileOutput::Rules { source, .. } => interp.rule_report(source, &mut w)?,
+                ProfileOutput::Coverage {
+                    source, outline, ..
+                } => interp.coverage_report(source, outline, &mut w)?,
+            }
+            w.flush()
+        });
+        if let Err(e) = written {
+            fail!("failed to write profile to {}: {}", path, e);
+        }
+    }
+    let rc = match res {
+        // Writing to a closed pipe ends the program quietly.
+        Err(_) if runtime::output_pipe_closed() => std::process::exit(runtime::exit_status(1)),
+        Err(e) => {
+            eprintln_ignore!("fatal error during execution: {}", e);
+            std::process::exit(runtime::exit_status(1))
+        }
+        Ok(n) => n,
+    };
+    let records = interp.nr();
+    // Dropping the interpreter flushes any remaining output.
+    mem::drop(interp);
+    if let Some(mut timing) = timing {
+        timing.end_phase("execution");
+        let _ = timing.report(records, &mut io::stderr());
+    }
+    exit_with(rc);
+}
+
+/// Exit with status `rc`, unless an I/O error while running the program calls for a different
+/// status. Returns if the resulting status is 0.
+fn exit_with(rc: i32) {
+    let rc = runtime::exit_status(rc);
+    if rc != 0 {
+        std::process::exit(rc);
+    }
+}
+
+#[cfg(feature = "cranelift_backend")]
+fn run_cranelift_with_context<'a>(
+    mut ctx: cfg::ProgramContext<'a, &'a str>,
+    stdin: impl IntoRuntime,
+    ff: impl runtime::writers::FileFactory,
+    cfg: codegen::Config,
+    signal: CancelSignal,
+) {
+    if let Err(e) = compile::run_cranelift(&mut ctx, stdin, ff, cfg, signal) {
+        fail!("error compiling cranelift: {}", e)
+    }
+    exit_with(0);
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "llvm_backend")] {
+        fn run_llvm_with_context<'a>(
+            mut ctx: cfg::ProgramContext<'a, &'a str>,
+            stdin: impl IntoRuntime,
+            ff: impl runtime::writers::FileFactory,
+            cfg: codegen::Config,
+            signal: CancelSignal,
+        ) {
+            if let Err(e) = compile::run_llvm(&mut ctx, stdin, ff, cfg, signal) {
+                fail!("error compiling llvm: {}", e)
+            }
+            exit_with(0);
+        }
+
+        fn dump_llvm(prog: &str, cfg: codegen::Config, raw: &RawPrelude) -> String {
+            let a = Arena::default();
+            let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+            match compile::dump_llvm(&mut ctx, cfg) {
+                Ok(s) => s,
+                Err(e) => fail!("error compiling llvm: {}", e),
+            }
+        }
+
+    }
+}
+
+const DEFAULT_OPT_LEVEL: i32 = 3;
+
+#[cfg(feature = "cranelift_backend")]
+const DEFAULT_BACKEND: &str = "cranelift";
+#[cfg(not(feature = "cranelift_backend"))]
+const DEFAULT_BACKEND: &str = "interp";
+
+fn dump_bytecode(prog: &str, raw: &RawPrelude) -> String {
+    use std::io::Cursor;
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    let fake_inp: Box<dyn io::Read + Send> = Box::new(Cursor::new(vec![]));
+    let interp = match compile::bytecode(
+        &mut ctx,
+        chained(CSVReader::new(
+            once((fake_inp, String::from("unused"))),
+            InputFormat::CSV,
+            CHUNK_SIZE,
+            /*check_utf8=*/ false,
+            ExecutionStrategy::Serial,
+            Default::default(),
+        )),
+        runtime::writers::default_factory(/*level=*/ None),
+        /*num_workers=*/ 1,
+    ) {
+        Ok(ctx) => ctx,
+        Err(e) => fail!("bytecode compilation failure: {}", e),
+    };
+    let mut v = Vec::<u8>::new();
+    for (i, func) in interp.instrs().iter().enumerate() {
+        writeln!(&mut v, "function {} {{", i).unwrap();
+        for (j, inst) in func.iter().enumerate() {
+            writeln!(&mut v, "\t[{:2}] {:?}", j, inst).unwrap();
+        }
+        writeln!(&mut v, "}}\n").unwrap();
+    }
+    String::from_utf8(v).unwrap()
+}
+
+/// Compile `prog` to bytecode, in the format written by --save-bytecode.
+fn encoded_image(prog: &str, raw: &RawPrelude) -> Vec<u8> {
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    let image = match compile::image(&mut ctx) {
+        Ok(image) => image,
+        Err(e) => fail!("bytecode compilation failure: {}", e),
+    };
+    image.encode()
+}
+
+fn save_bytecode(prog: &str, raw: &RawPrelude, path: &str) {
+    if let Err(e) = std::fs::write(path, encoded_image(prog, raw)) {
+        fail!("failed to write bytecode to {}: {}", path, e);
+    }
+}
+
+/// The key under which --cache stores `prog` compiled with the options in `raw`.
+fn cache_key(prog: &str, raw: &RawPrelude) -> Vec<u8> {
+    format!(
+        "frawk {} bytecode {}\n{:?}\n{}",
+        env!("CARGO_PKG_VERSION"),
+        image::VERSION,
+        raw,
+        prog
+    )
+    .into_bytes()
+}
+
+pub fn main() {
+    let mut timing = Timing::default();
+    #[allow(unused_mut)]
+    let mut app = Command::new("frawk")
+        .version("0.4.8")
+        .author("Eli R.")
+        .about("frawk is a pattern scanning and (semi-structured) text processing language")
+        .arg(Arg::new("program-file")
+             .long("program-file")
+             .short('f')
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .help("Read the program source from the file program-file, instead of from the command line. Multiple '-f' options may be used"))
+        .arg(Arg::new("opt-level")
+             .long("opt-level")
+             .short('O')
+             .takes_value(true)
+             .allow_hyphen_values(true)
+             .help("The optimization level for the program. Positive levels determine the optimization level for LLVM. Level `-1` forces bytecode interpretation")
+             .possible_values(["-1", "0", "1", "2", "3"]))
+        .arg(Arg::new("out-file")
+             .long("out-file")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Write to specified output file instead of standard output"))
+        .arg(Arg::new("compression-level")
+             .long("compression-level")
+             .takes_value(true)
+             .value_name("N")
+             .help("Compression level for output files ending in .gz, .zst or .bz2, which are compressed as they are written. Levels are clamped to the range each format supports; by default each format's default level is used"))
+        .arg(Arg::new("max-open-files")
+             .long("max-open-files")
+             .takes_value(true)
+             .value_name("N")
+             .help("The number of files to keep open for reading with getline, and for writing with print and printf. Once more are open, the least recently used are closed, and reopened where they left off when they are next used. Defaults to a quarter of the limit on open file descriptors"))
+        .arg(Arg::new("utf8")
+             .long("utf8")
+             .takes_value(false)
+             .help("Validate all input as UTF-8, replacing invalid sequences with U+FFFD (the replacement character) so that scripts only see valid UTF-8. By default, input is processed as bytes and invalid sequences are left as-is"))
+        .arg(Arg::new("bytes")
+             .long("bytes")
+             .takes_value(false)
+             .conflicts_with("utf8")
+             .help("Match regular expressions against bytes rather than UTF-8 characters, so that `.` and bracket expressions match any single byte, including ones that are not valid UTF-8. Classes like \\w and [:alpha:] only match ASCII characters in this mode"))
+        .arg(Arg::new("ieee-div")
+             .long("ieee-div")
+             .takes_value(false)
+             .help("Divide by zero as IEEE 754 does, producing inf, -inf or NaN, rather than stopping with an error. Taking an integer modulo zero is still an error"))
+        .arg(Arg::new("dump-ast")
+             .long("dump-ast")
+             .takes_value(true)
+             .min_values(0)
+             .max_values(1)
+             .require_equals(true)
+             .possible_values(["typed"])
+             .value_name("typed")
+             .help("Print the input program back out in canonical formatting instead of running it. With --dump-ast=typed, precede it with the inferred types of its global variables and functions"))
+        .arg(Arg::new("lint")
+             .long("lint")
+             .takes_value(true)
+             .min_values(0)
+             .max_values(1)
+             .require_equals(true)
+             .possible_values(["json"])
+             .value_name("json")
+             .help("Check the input program for likely mistakes instead of running it: variables that are read but never assigned to (or that BEGIN reads before assigning to them), functions that are never called, assignments used as conditions, gawk and frawk extensions, and comparisons with strings that look like numbers. Each warning is printed as FILE:LINE:COLUMN: warning: MESSAGE [KIND], or with --lint=json as a JSON object on a line of its own. Exits with status 1 if there are any warnings"))
+        .arg(Arg::new("dump-cfg")
+             .long("dump-cfg")
+             .takes_value(false)
+             .help("Print untyped SSA form for input program"))
+        .arg(Arg::new("dump-bytecode")
+             .long("dump-bytecode")
+             .takes_value(false)
+             .help("Print bytecode for input program"))
+        .arg(Arg::new("save-bytecode")
+             .long("save-bytecode")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Compile the program to bytecode and write it to FILE instead of running it. The saved program can be run with --load-bytecode"))
+        .arg(Arg::new("cache")
+             .long("cache")
+             .takes_value(true)
+             .min_values(0)
+             .max_values(1)
+             .require_equals(true)
+             .value_name("DIR")
+             .conflicts_with_all(&["load-bytecode", "save-bytecode", "debug"])
+             .help("Run the program with the bytecode interpreter, keeping the compiled program in a cache so that later runs of the same program with the same options skip parsing and compiling it. The cache is kept in DIR, or in $XDG_CACHE_HOME/frawk (~/.cache/frawk by default) if --cache is given without one. Programs that refer to ARGV or ARGC, or that are followed by var=value operands, are cached separately for each list of operands"))
+        .arg(Arg::new("load-bytecode")
+             .long("load-bytecode")
+             .takes_value(true)
+             .value_name("FILE")
+             .conflicts_with_all(&["program-file", "save-bytecode", "dump-ast", "dump-cfg", "dump-bytecode"])
+             .help("Run a program saved with --save-bytecode using the bytecode interpreter. All positional arguments are treated as input files. Options that affect compilation (-v, -F, -o, -H, -A and the contents of ARGV) are fixed when the program is saved, and programs saved with -p must also be run with -p"))
+        .arg(Arg::new("profile-bytecode")
+             .long("profile-bytecode")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Run the program with the bytecode interpreter, and write the number of times each instruction and basic block executed to FILE on exit"))
+        .arg(Arg::new("profile")
+             .long("profile")
+             .takes_value(true)
+             .value_name("FILE")
+             .conflicts_with_all(&["profile-bytecode", "load-bytecode", "save-bytecode"])
+             .help("Run the program with the bytecode interpreter, and write a listing of the program to FILE on exit showing how many times the pattern and action of each rule ran and the time spent in each"))
+        .arg(Arg::new("coverage")
+             .long("coverage")
+             .takes_value(true)
+             .value_name("FILE")
+             .conflicts_with_all(&["profile", "profile-bytecode", "load-bytecode", "save-bytecode"])
+             .help("Run the program with the bytecode interpreter, and write a coverage report to FILE on exit: the program, with how many times each line ran (##### for lines that never did), how many records each pattern matched and how often each branch of each if statement was taken, followed by a summary"))
+        .arg(Arg::new("debug")
+             .long("debug")
+             .takes_value(true)
+             .min_values(0)
+             .max_values(1)
+             .require_equals(true)
+             .value_name("FILE")
+             .conflicts_with_all(&["parallel-strategy", "parallel", "load-bytecode", "save-bytecode", "profile", "profile-bytecode", "coverage", "timing"])
+             .help("Run the program with the bytecode interpreter under an interactive debugger, which stops before the first statement. Commands are read from FILE, or from the terminal if --debug is given without one, so that the program can still read standard input. Type `help` at the debugger's prompt for a list of commands"))
+        .arg(Arg::new("plugin")
+             .long("plugin")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .value_name("LIB")
+             .help("Load the shared library LIB, which can add builtin functions to the language; see include/frawk_plugin.h for the interface it must provide. Programs using these functions run with the bytecode interpreter. Multiple --plugin options may be used"))
+        .arg(Arg::new("repl")
+             .long("repl")
+             .takes_value(false)
+             .conflicts_with_all(&["program", "program-file", "plugin", "load-bytecode", "save-bytecode", "lint", "debug", "profile", "profile-bytecode", "coverage", "timing"])
+             .help("Start an interactive session instead of running a program. Statements typed at the prompt are run at once, and expressions have their values printed; function definitions and rules are kept, and variables keep their values from one line to the next. Commands starting with `:` feed records to the rules and show the values of variables; type `:help` for a list of them"))
+        .arg(Arg::new("timing")
+             .long("timing")
+             .takes_value(false)
+             .conflicts_with("save-bytecode")
+             .help("Run the program with the bytecode interpreter, and then print the time spent parsing it, inferring types, generating bytecode and running it to standard error, along with how quickly the input was processed"))
+        .arg(Arg::new("parse-header")
+             .long("parse-header")
+             .short('H')
+             .takes_value(false)
+             .help("Consume the first line of each input file and populate the `FI` variable with column names mapping to column indexes"))
+        .arg(Arg::new("input-format")
+             .long("input-format")
+             .short('i')
+             .value_name("csv|tsv|jsonl|parquet")
+             .conflicts_with("field-separator")
+             .help("Input is split according to the rules of (csv|tsv). $0 contains the unescaped line. Assigning to columns does nothing. With jsonl, each line is a JSON value whose scalars are flattened into columns, with FI mapping their paths (e.g. `a.b` or `c.0`) to column indexes. With parquet, each row of a Parquet file is a record, with FI mapping column names to indexes (requires the parquet_input feature)")
+             .possible_values(["csv", "tsv", "jsonl", "parquet"]))
+        .arg(Arg::new("var")
+             .short('v')
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .value_name("var=val")
+             .help("Assign the value <val> to the variable <var>, before execution of the frawk program begins. Multiple '-v' options may be used"))
+        .arg(Arg::new("field-separator")
+             .long("field-separator")
+             .short('F')
+             .takes_value(true)
+             .value_name("FS")
+             .conflicts_with("input-format")
+             .help("Field separator `FS` for frawk program"))
+        .arg(Arg::new("null-data")
+             .long("null-data")
+             .short('z')
+             .takes_value(false)
+             .help("Separate input and output records with NUL bytes rather than newlines, by setting RS and ORS, for use with `find -print0`, `xargs -0` and similar tools. Assign ORS with -v to only read NUL-separated input"))
+        .arg(Arg::new("backend")
+             .long("backend")
+             .short('B')
+             .help("The backend used to run the frawk program, ranging from fastest to compile and slowest to execute, and slowest to compile and fastest to execute. Cranelift is the default, or the interpreter if frawk is built without Cranelift support")
+             .possible_values(["interp", "cranelift", "llvm"]))
+        .arg(Arg::new("output-format")
+             .long("output-format")
+             .short('o')
+             .value_name("csv|tsv")
+             .help("If set, records output via print are escaped according to the rules of the corresponding format")
+             .possible_values(["csv", "tsv"]))
+        .arg(Arg::new("sorted-in")
+             .long("sorted-in")
+             .value_name("str-asc|str-desc|num-asc|num-desc")
+             .help("Visit the keys of arrays in `for (k in m)` loops in sorted order, comparing them as strings or as numbers, in ascending or descending order. By default keys are visited in no particular order")
+             .possible_values(["str-asc", "str-desc", "num-asc", "num-desc"]))
+        .arg(Arg::new("program")
+             .index(1)
+             .help("The frawk program to execute"))
+        .arg(Arg::new("input-files")
+             .index(2)
+             .multiple_values(true)
+             .help("Input files to be read by frawk program. An operand of the form var=value instead assigns value to var once the input files before it have been read"))
+        .arg(Arg::new("parallel-strategy")
+             .short('p')
+             .help("Attempt to execute the script in parallel. Strategy r[ecord] parallelizes within the current input file. Strategy f[ile] parallelizes between input files")
+             .possible_values(["r", "record", "f", "file"]))
+        .arg(Arg::new("chunk-size")
+             .long("chunk-size")
+             .takes_value(true)
+             .help("Buffer size when reading input. This is present primarily for debugging purposes; it's possible that tuning this will help performance, but it should not be necessary"))
+        .arg(Arg::new("arbitrary-shell")
+             .short('A')
+             .long("arbitrary-shell")
+             .takes_value(false)
+             .help("By default, strings that are passed to the shell via pipes or the 'system' function are restricted from potentially containing user input. This flag bypasses that check, for the cases where such a use is known to be safe"))
+        .arg(Arg::new("sandbox")
+             .long("sandbox")
+             .takes_value(false)
+             .conflicts_with_all(&["arbitrary-shell", "load-bytecode"])
+             .help("Refuse to run programs that call system(), pipe output to or read input from commands, redirect output to files, read files with getline, or use reada and writea. Redirections to \"/dev/stdout\" and \"/dev/stderr\", and getline from \"/dev/stdin\" or \"-\", are still allowed. For running untrusted programs"))
+        .arg(Arg::new("jobs")
+             .short('j')
+             .requires("parallel-strategy")
+             .takes_value(true)
+             .help("Number or worker threads to launch when executing in parallel, requires '-p' flag to be set. When using record-level parallelism, this value is an upper bound on the number of worker threads that will be spawned; the number of active worker threads is chosen dynamically"))
+        .arg(Arg::new("parallel")
+             .long("parallel")
+             .value_name("N")
+             .takes_value(true)
+             .conflicts_with_all(&["parallel-strategy", "jobs", "load-bytecode"])
+             .help("Process records on up to N worker threads, as with -pr -jN, if that cannot change the output of the program other than the order of its lines. Programs that use FNR or nextfile and are given several input files instead process whole files on each worker, as with -pf -jN. Otherwise, print a warning saying why and run the program serially"))
+        .arg(Arg::new("output-order")
+             .long("output-order")
+             .takes_value(true)
+             .possible_values(["any", "file", "input"])
+             .help("When input files are processed in parallel (-pf), write output to stdout as it is produced (any, the default), with the output of each file kept together (file), or with the output of each file kept together and in the order the files were given (input). The last two hold the output of each file in memory until the file has been processed"));
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "llvm_backend")] {
+            app = app.arg(Arg::new("dump-llvm")
+             .long("dump-llvm")
+             .takes_value(false)
+             .help("Print LLVM-IR for the input program"));
+        }
+    }
+    let matches = app.get_matches();
+    let json = matches.value_of("input-format") == Some("jsonl");
+    let parquet = matches.value_of("input-format") == Some("parquet");
+    let ifmt = match matches.value_of("input-format") {
+        Some("csv") => Some(InputFormat::CSV),
+        Some("tsv") => Some(InputFormat::TSV),
+        Some("jsonl") | Some("parquet") | None => None,
+        Some(x) => fail!("invalid input format: {}", x),
+    };
+    if parquet && !cfg!(feature = "parquet_input") {
+        fail!("input format specified as Parquet, but compiled without Parquet support");
+    }
+    let null_data = matches.is_present("null-data");
+    if null_data && ifmt.is_some() {
+        fail!("-z cannot be used with CSV or TSV input, whose records end with newlines");
+    }
+    if (json || parquet) && matches.is_present("parse-header") {
+        fail!("-H cannot be used with JSON or Parquet input, whose records name their own columns");
+    }
+    let key_order = match matches.value_of("sorted-in") {
+        Some("str-asc") => KeyOrder::StrAsc,
+        Some("str-desc") => KeyOrder::StrDesc,
+        Some("num-asc") => KeyOrder::NumAsc,
+        Some("num-desc") => KeyOrder::NumDesc,
+        Some(x) => fail!("invalid key order: {}", x),
+        None => KeyOrder::Unordered,
+    };
+    let exec_strategy = match matches.value_of("parallel-strategy") {
+        Some("r") | Some("record") => ExecutionStrategy::ShardPerRecord,
+        Some("f") | Some("file") => ExecutionStrategy::ShardPerFile,
+        None => ExecutionStrategy::Serial,
+        Some(x) => fail!(
+            "invalid execution strategy (clap arg parsing should handle this): {}",
+            x
+        ),
+    };
+
+    // NB: do we want this to be a command-line param?
+    let chunk_size = if let Some(cs) = matches.value_of("chunk-size") {
+        match cs.parse::<usize>() {
+            Ok(u) => u,
+            Err(e) => fail!("value of 'chunk-size' flag must be numeric: {}", e),
+        }
+    } else {
+        CHUNK_SIZE
+    };
+    let compression_level = matches.value_of("compression-level").map(|s| {
+        s.parse::<u32>()
+            .unwrap_or_else(|e| fail!("value of 'compression-level' flag must be numeric: {}", e))
+    });
+    if let Some(s) = matches.value_of("max-open-files") {
+        match s.parse::<usize>() {
+            Ok(n) if n > 0 => runtime::set_max_open_files(n),
+            _ => fail!("value of 'max-open-files' flag must be a positive integer"),
+        }
+    }
+    if matches.is_present("bytes") {
+        runtime::set_byte_regexes(true);
+    }
+    let ieee_division = matches.is_present("ieee-div");
+    let num_workers = match matches.value_of("jobs") {
+        Some(s) => match s.parse::<usize>() {
+            Ok(u) => u,
+            Err(e) => fail!("value of 'jobs' flag must be numeric: {}", e),
+        },
+        None => exec_strategy.num_workers(),
+    };
+    let parallel_workers = matches
+        .value_of("parallel")
+        .map(|s| match s.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => fail!("value of 'parallel' flag must be a positive integer"),
+        });
+    let argv: Vec<String> = std::env::args()
+        .next()
+        .into_iter()
+        .chain(
+            matches
+                .values_of("input-files")
+                .into_iter()
+                .flat_map(|x| x.map(String::from)),
+        )
+        .collect();
+    let mut input_files: Vec<String> = matches
+        .values_of("input-files")
+        .map(|x| x.map(String::from).collect())
+        .unwrap_or_else(Vec::new);
+    let mut plugin_functions = Vec::new();
+    for path in matches.values_of("plugin").into_iter().flatten() {
+        if let Err(e) = plugin::load(path, &mut plugin_functions) {
+            fail!("failed to load plugin {}: {}", path, e);
+        }
+    }
+    let natives: Vec<Callback> = plugin_functions
+        .iter()
+        .map(|f| f.callback.clone())
+        .collect();
+    if matches.is_present("repl") {
+        let stdin = std::io::stdin();
+        Session::new(std::io::stderr()).run(stdin.lock(), &mut std::io::stdout());
+        return;
+    }
+    let load_bytecode = matches.value_of("load-bytecode");
+    let mut program_files = Vec::new();
+    let program_string = {
+        if let Some(pfiles) = matches.values_of("program-file") {
+            // We specified a file on the command line, so the "program" will be
+            // interpreted as another input file.
+            if let Some(p) = matches.value_of("program") {
+                input_files.insert(0, p.into());
+            }
+            let mut prog = String::new();
+            let mut line = 0;
+            for pfile in pfiles {
+                match std::fs::read_to_string(pfile) {
+                    Ok(p) => {
+                        program_files.push((String::from(pfile), line));
+                        line += p.matches('\n').count() + 1;
+                        prog.push_str(p.as_str());
+                        prog.push('\n');
+                    }
+                    Err(e) => fail!("failed to read program from {}: {}", pfile, e),
+                }
+            }
+            prog
+        } else if load_bytecode.is_some() {
+            // As with -f, the "program" is another input file.
+            if let Some(p) = matches.value_of("program") {
+                input_files.insert(0, p.into());
+            }
+            String::new()
+        } else if let Some(p) = matches.value_of("program") {
+            String::from(p)
+        } else {
+            fail!("must specify program at command line, or in a file via -f");
+        }
+    };
+    // With --parallel, the strategy is settled once the program has been analyzed below.
+    let (mut exec_strategy, mut num_workers) = match parallel_workers {
+        Some(n) => (ExecutionStrategy::ShardPerRecord, n),
+        None => (exec_strategy, num_workers),
+    };
+    let output_order = match matches.value_of("output-order") {
+        None | Some("any") => OutputOrder::Any,
+        Some("file") => OutputOrder::File,
+        Some("input") => OutputOrder::Input,
+        Some(s) => fail!(
+            "invalid output order (clap arg parsing should handle this): {}",
+            s
+        ),
+    };
+    if output_order != OutputOrder::Any
+        && parallel_workers.is_none()
+        && !matches!(exec_strategy, ExecutionStrategy::ShardPerFile)
+    {
+        fail!("--output-order only applies when input files are processed in parallel with -pf");
+    }
+    let (escaper, output_sep, output_record_sep) = match matches.value_of("output-format") {
+        Some("csv") => (Escaper::CSV, Some(","), Some("\r\n")),
+        Some("tsv") => (Escaper::TSV, Some("\t"), Some("\n")),
+        Some(s) => fail!(
+            "invalid output format {:?}; expected csv or tsv (or the empty string)",
+            s
+        ),
+        None => (Escaper::Identity, None, None),
+    };
+    let (record_sep, output_record_sep) = if null_data {
+        (Some("\\0"), Some("\\0"))
+    } else {
+        (None, output_record_sep)
+    };
+    let arbitrary_shell = matches.is_present("arbitrary-shell");
+    let parse_header = matches.is_present("parse-header");
+
+    let opt_level: i32 = match matches.value_of("opt-level") {
+        Some("3") => 3,
+        Some("2") => 2,
+        Some("1") => 1,
+        Some("0") => 0,
+        Some("-1") => -1,
+        None => DEFAULT_OPT_LEVEL,
+        Some(x) => panic!("this case should be covered by clap argument validation: found unexpected opt-level value {}", x),
+    };
+    let mut raw = RawPrelude {
+        field_sep: matches.value_of("field-separator").map(String::from),
+        record_sep,
+        var_decs: matches
+            .values_of("var")
+            .map(|x| x.map(String::from).collect())
+            .unwrap_or_else(Vec::new),
+        output_sep,
+        scalars: PreludeScalars {
+            escaper,
+            arbitrary_shell,
+            fold_regexes: opt_level >= 3,
+            stage: exec_strategy.stage(),
+            parse_header,
+            input_names_columns: json || parquet,
+            profile_rules: matches.is_present("profile") || matches.is_present("coverage"),
+            sandbox: matches.is_present("sandbox"),
+            debug: matches.is_present("debug"),
+        },
+        output_record_sep,
+        native_functions: plugin_functions
+            .iter()
+            .map(|f| (f.name.clone(), f.native))
+            .collect(),
+        argv,
+        operands: if load_bytecode.is_some() {
+            Vec::new()
+        } else {
+            input_files.clone()
+        },
+        program_files,
+    };
+    let a = Arena::default();
+    let mut ctx = None;
+    if parallel_workers.is_some() && load_bytecode.is_none() {
+        let mut prog = parse(program_string.as_str(), &a, get_prelude(&a, &raw));
+        let par = context(&prog, &a, &raw.scalars);
+        match parallel_strategy(&par, &raw.operands) {
+            Ok(strategy) => {
+                exec_strategy = strategy;
+                ctx = Some(par);
+            }
+            Err(reason) => {
+                eprintln_ignore!("warning: running serially: {}", reason);
+                exec_strategy = ExecutionStrategy::Serial;
+                num_workers = 1;
+                raw.scalars.stage = exec_strategy.stage();
+                prog.stage = exec_strategy.stage();
+                ctx = Some(context(&prog, &a, &raw.scalars));
+            }
+        }
+    }
+    if load_bytecode.is_none() {
+        input_files.retain(|f| !is_assignment(f));
+    }
+    if matches.is_present("lint") {
+        let warnings = lint(
+            program_string.as_str(),
+            &raw,
+            matches.value_of("lint") == Some("json"),
+        );
+        let mut stdout = std::io::stdout();
+        for w in warnings.iter() {
+            let _ = writeln!(stdout, "{}", w);
+        }
+        let _ = stdout.flush();
+        std::process::exit(if warnings.is_empty() { 0 } else { 1 });
+    }
+    let opt_dump_bytecode = matches.is_present("dump-bytecode");
+    let opt_dump_ast = matches.is_present("dump-ast");
+    let opt_dump_cfg = matches.is_present("dump-cfg");
+    cfg_if::cfg_if! {
+        if #[cfg(feature="llvm_backend")] {
+            let opt_dump_llvm = matches.is_present("dump-llvm");
+            if opt_dump_llvm {
+                let config = codegen::Config {
+                    opt_level: if opt_level < 0 { 3 } else { opt_level as usize },
+                    num_workers,
+                    output_order,
+                    ieee_division,
+                    key_order,
+                };
+                let _ = write!(
+                    std::io::stdout(),
+                    "{}",
+                    dump_llvm(program_string.as_str(), config, &raw),
+                );
+            }
+        } else {
+            let opt_dump_llvm = false;
+        }
+    }
+    let skip_output = opt_dump_llvm || opt_dump_bytecode || opt_dump_cfg || opt_dump_ast;
+    if opt_dump_ast {
+        let _ = write!(
+            std::io::stdout(),
+            "{}",
+            dump_ast(
+                program_string.as_str(),
+                &raw,
+                matches.value_of("dump-ast") == Some("typed")
+            ),
+        );
+    }
+    if opt_dump_bytecode {
+        let _ = write!(
+            std::io::stdout(),
+            "{}",
+            dump_bytecode(program_string.as_str(), &raw),
+        );
+    }
+    if opt_dump_cfg {
+        let a = Arena::default();
+        let ctx = get_context(program_string.as_str(), &a, get_prelude(&a, &raw));
+        let mut stdout = std::io::stdout();
+        let _ = ctx.dbg_print(&mut stdout);
+    }
+    if skip_output {
+        return;
+    }
+    if let Some(path) = matches.value_of("save-bytecode") {
+        save_bytecode(program_string.as_str(), &raw, path);
+        return;
+    }
+    let check_utf8 = matches.is_present("utf8");
+    let signal = CancelSignal::default();
+
+    // This horrid macro is here because all of the different ways of reading input are different
+    // types, making functions hard to write. Still, there must be something to be done to clean
+    // this up here.
+    macro_rules! with_inp {
+        ($analysis:expr, $inp:ident, $body:expr) => {{
+            if parquet {
+                // Without the parquet_input feature, we fail before getting here.
+                #[cfg(feature = "parquet_input")]
+                {
+                    let files = if input_files.len() == 0 {
+                        vec![String::from("-")]
+                    } else {
+                        input_files.clone()
+                    };
+                    let $inp = ParquetReader::new(files.into_iter(), check_utf8);
+                    $body
+                }
+            } else if json {
+                let reader = if input_files.len() == 0 {
+                    let reader: Box<dyn io::Read + Send> = Box::new(Counted(io::stdin()));
+                    chained(RegexSplitter::new(reader, chunk_size, "-", check_utf8))
+                } else {
+                    ChainedReader::new(input_files.iter().cloned().map(|file| {
+                        let reader: Box<dyn io::Read + Send> =
+                            Box::new(open_file_read(file.as_str()));
+                        RegexSplitter::new(reader, chunk_size, file, check_utf8)
+                    }))
+                };
+                let $inp = JsonReader::new(reader);
+                $body
+            } else if input_files.len() == 0 {
+                let _reader: Box<dyn io::Read + Send> = Box::new(Counted(io::stdin()));
+                match (ifmt, $analysis) {
+                    (Some(ifmt), _) => {
+                        let $inp = CSVReader::new(
+                            once((_reader, String::from("-"))),
+                            ifmt,
+                            chunk_size,
+                            check_utf8,
+                            exec_strategy,
+                            signal.clone(),
+                        );
+                        $body
+                    }
+                    (
+                        None,
+                        cfg::SepAssign::Potential {
+                            field_sep,
+                            record_sep,
+                        },
+                    ) => {
+                        let field_sep = field_sep.unwrap_or(b" ");
+                        let record_sep = record_sep.unwrap_or(b"\n");
+                        if byte_splittable(field_sep, record_sep) {
+                            if field_sep == b" " && record_sep == b"\n" {
+                                let $inp = ByteReader::new_whitespace(
+                                    once((_reader, String::from("-"))),
+                                    chunk_size,
+                                    check_utf8,
+                                    exec_strategy,
+                                    signal.clone(),
+                                );
+                                $body
+                            } else {
+                                let $inp = ByteReader::new(
+                                    once((Counted(io::stdin()), String::from("-"))),
+                                    field_sep[0],
+                                    record_sep[0],
+                                    chunk_size,
+                                    check_utf8,
+                                    exec_strategy,
+                                    signal.clone(),
+                                );
+                                $body
+                            }
+                        } else if record_sep.len() == 1
+                            && matches!(exec_strategy, ExecutionStrategy::ShardPerRecord)
+                        {
+                            let $inp = ByteReader::new_lazy_fields(
+                                once((_reader, String::from("-"))),
+                                record_sep[0],
+                                chunk_size,
+                                check_utf8,
+                                exec_strategy,
+                                signal.clone(),
+                            );
+                            $body
+                        } else {
+                            let $inp =
+                                chained(RegexSplitter::new(_reader, chunk_size, "-", check_utf8));
+                            $body
+                        }
+                    }
+                    (None, cfg::SepAssign::Unsure) => {
+                        let $inp =
+                            chained(RegexSplitter::new(_reader, chunk_size, "-", check_utf8));
+                        $body
+                    }
+                }
+            } else if let Some(ifmt) = ifmt {
+                let file_handles: Vec<_> = input_files
+                    .iter()
+                    .cloned()
+                    .map(|file| (open_file_read(file.as_str()), file))
+                    .collect();
+                let $inp = CSVReader::new(
+                    file_handles.into_iter(),
+                    ifmt,
+                    chunk_size,
+                    check_utf8,
+                    exec_strategy,
+                    signal.clone(),
+                );
+                $body
+            } else {
+                match $analysis {
+                    cfg::SepAssign::Potential {
+                        field_sep,
+                        record_sep,
+                    } => {
+                        let field_sep = field_sep.unwrap_or(b" ");
+                        let record_sep = record_sep.unwrap_or(b"\n");
+                        if byte_splittable(field_sep, record_sep) {
+                            let file_handles: Vec<_> = input_files
+                                .iter()
+                                .cloned()
+                                .map(move |file| (open_file_read(file.as_str()), file))
+                                .collect();
+                            if field_sep == b" " && record_sep == b"\n" {
+                                let $inp = ByteReader::new_whitespace(
+                                    file_handles.into_iter(),
+                                    chunk_size,
+                                    check_utf8,
+                                    exec_strategy,
+                                    signal.clone(),
+                                );
+                                $body
+                            } else {
+                                let $inp = ByteReader::new(
+                                    file_handles.into_iter(),
+                                    field_sep[0],
+                                    record_sep[0],
+                                    chunk_size,
+                                    check_utf8,
+                                    exec_strategy,
+                                    signal.clone(),
+                                );
+                                $body
+                            }
+                        } else if record_sep.len() == 1
+                            && matches!(exec_strategy, ExecutionStrategy::ShardPerRecord)
+                        {
+                            let file_handles: Vec<_> = input_files
+                                .iter()
+                                .cloned()
+                                .map(move |file| (open_file_read(file.as_str()), file))
+                                .collect();
+                            let $inp = ByteReader::new_lazy_fields(
+                                file_handles.into_iter(),
+                                record_sep[0],
+                                chunk_size,
+                                check_utf8,
+                                exec_strategy,
+                                signal.clone(),
+                            );
+                            $body
+                        } else {
+                            let iter = input_files.iter().cloned().map(|file| {
+                                let reader: Box<dyn io::Read + Send> =
+                                    Box::new(open_file_read(file.as_str()));
+                                RegexSplitter::new(reader, chunk_size, file, check_utf8)
+                            });
+                            let $inp = ChainedReader::new(iter);
+                            $body
+                        }
+                    }
+                    cfg::SepAssign::Unsure => {
+                        let iter = input_files.iter().cloned().map(|file| {
+                            let reader: Box<dyn io::Read + Send> =
+                                Box::new(open_file_read(file.as_str()));
+                            RegexSplitter::new(reader, chunk_size, file, check_utf8)
+                        });
+                        let $inp = ChainedReader::new(iter);
+                        $body
+                    }
+                }
+            }
+        }};
+    }
+
+    timing.end_phase("startup");
+    let mut image_bytes = load_bytecode.map(|path| match std::fs::read(path) {
+        Ok(bs) => bs,
+        Err(e) => fail!("failed to read bytecode from {}: {}", path, e),
+    });
+    let cache_dir = matches
+        .is_present("cache")
+        .then(|| match matches.value_of("cache") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => cache::default_dir(),
+        });
+    if let Some(dir) = cache_dir {
+        if matches!(matches.value_of("backend"), Some(b) if b != "interp") {
+            fail!("--cache requires the bytecode interpreter");
+        }
+        // The input files only matter to the compiled program if it can see them.
+        let program = program_string.as_str();
+        if !program.contains("ARGV")
+            && !program.contains("ARGC")
+            && !raw.operands.iter().any(|op| is_assignment(op))
+        {
+            raw.argv.clear();
+            raw.operands.clear();
+        }
+        let key = cache_key(program, &raw);
+        let cached = dir.as_deref().and_then(|dir| cache::load(dir, &key));
+        image_bytes = Some(match cached {
+            Some(bs) => bs,
+            None => {
+                let bs = encoded_image(program, &raw);
+                timing.end_phase("compiling");
+                if let Some(dir) = dir {
+                    let _ = cache::store(&dir, &key, &bs);
+                }
+                bs
+            }
+        });
+    }
+    let image = image_bytes
+        .as_ref()
+        .map(|bs| match image::Image::decode(bs) {
+            Ok(image) => image,
+            Err(e) => fail!("failed to load bytecode: {}", e),
+        });
+    let analysis_result = match &image {
+        Some(image) => {
+            timing.end_phase("loading bytecode");
+            image.sep_assign.clone()
+        }
+        None => ctx
+            .get_or_insert_with(|| {
+                let prog = parse(program_string.as_str(), &a, get_prelude(&a, &raw));
+                timing.end_phase("parsing");
+                let prog = context(&prog, &a, &raw.scalars);
+                timing.end_phase("SSA construction");
+                prog
+            })
+            .analyze_sep_assignments(),
+    };
+    let timing = matches.is_present("timing").then_some(timing);
+    let out_file = matches.value_of("out-file");
+    let outline = if matches.is_present("coverage") {
+        coverage_outline(program_string.as_str())
+    } else {
+        Outline::default()
+    };
+    let profile = match (
+        matches.value_of("profile-bytecode"),
+        matches.value_of("profile"),
+        matches.value_of("coverage"),
+    ) {
+        (Some(path), _, _) => Some(ProfileOutput::Bytecode(path)),
+        (None, Some(path), _) => Some(ProfileOutput::Rules {
+            path,
+            source: program_string.as_str(),
+        }),
+        (None, None, Some(path)) => Some(ProfileOutput::Coverage {
+            path,
+            source: program_string.as_str(),
+            outline: &outline,
+        }),
+        (None, None, None) => None,
+    };
+    macro_rules! with_io {
+        (|$inp:ident, $out:ident| $body:expr) => {
+            match out_file {
+                Some(oup) => {
+                    let $out = runtime::writers::factory_from_file(oup, compression_level)
+                        .unwrap_or_else(|e| fail!("failed to open {}: {}", oup, e));
+                    with_inp!(analysis_result, $inp, $body);
+                }
+                None => {
+                    let $out = runtime::writers::default_factory(compression_level);
+                    with_inp!(analysis_result, $inp, $body);
+                }
+            }
+        };
+    }
+    if let Some(image) = image {
+        if let (Stage::Par { .. }, ExecutionStrategy::Serial) = (&image.stage, exec_strategy) {
+            fail!("bytecode was compiled for parallel execution; run it with -p");
+        }
+        with_io!(|inp, oup| run_interp_with_image(
+            image,
+            inp,
+            oup,
+            num_workers,
+            natives.clone(),
+            output_order,
+            ieee_division,
+            key_order,
+            profile,
+            timing
+        ));
+        return;
+    }
+    let ctx = ctx.unwrap();
+    let debug = matches.is_present("debug").then(|| {
+        let path = matches.value_of("debug").unwrap_or("/dev/tty");
+        match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => fail!("failed to open debugger commands from {}: {}", path, e),
+        }
+    });
+    let needs_interp =
+        profile.is_some() || timing.is_some() || debug.is_some() || !natives.is_empty();
+    let backend = match (matches.value_of("backend"), needs_interp) {
+        (None, true) => "interp",
+        (Some(b), true) if b != "interp" => {
+            fail!("--profile, --profile-bytecode, --coverage, --timing, --debug and --plugin require the bytecode interpreter")
+        }
+        (b, _) => b.unwrap_or(DEFAULT_BACKEND),
+    };
+    match backend {
+        "llvm" => {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "llvm_backend")] {
+                    with_io!(|inp, oup| run_llvm_with_context(
+                            ctx,
+                            inp,
+                            oup,
+                            codegen::Config {
+                                opt_level: opt_level as usize,
+                                num_workers,
+                                output_order,
+                                ieee_division,
+                                key_order,
+                            },
+                            signal,
+                    ));
+                } else {
+                    fail!("backend specified as LLVM, but compiled without LLVM support");
+                }
+            }
+        }
+        "interp" => match debug {
+            Some(commands) => with_io!(|inp, oup| run_interp_debug(
+                ctx,
+                inp,
+                oup,
+                natives.clone(),
+                ieee_division,
+                key_order,
+                commands,
+                program_string.as_str()
+            )),
+            None => with_io!(|inp, oup| run_interp_with_context(
+                ctx,
+                inp,
+                oup,
+                num_workers,
+                natives.clone(),
+                output_order,
+                ieee_division,
+                key_order,
+                profile,
+                timing
+            )),
+        },
+        "cranelift" => {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "cranelift_backend")] {
+                    with_io!(|inp, oup| run_cranelift_with_context(
+                        ctx,
+                        inp,
+                        oup,
+                        codegen::Config {
+                            opt_level: opt_level as usize,
+                            num_workers,
+                            output_order,
+                            ieee_division,
+                            key_order,
+                        },
+                        signal,
+                    ));
+                } else {
+                    fail!("backend specified as Cranelift, but compiled without Cranelift support");
+                }
+            }
+        }
+        b => {
+            fail!("invalid backend: {:?}", b);
+        }
+    }
+}
@@ -27,7 +27,7 @@ use smallvec::{smallvec, SmallVec};
 use crate::builtins;
 use crate::bytecode::Accum;
 use crate::codegen::{
-    intrinsics, Backend, CodeGenerator, Config, Handles, Jit, Op, Ref, Sig, StrReg,
+    intrinsics, Backend, CodeGenerator, Config, Divide, Handles, Jit, Op, Ref, Sig, StrReg,
 };
 use crate::common::{traverse, CompileError, Either, FileSpec, NodeIx, NumTy, Result, Stage};
 use crate::compile::{self, Typer};
@@ -993,8 +993,6 @@ impl<'a> View<'a> {
                 Mul => self.builder.ins().fmul(args[0], args[1]),
                 Minus => self.builder.ins().fsub(args[0], args[1]),
                 Add => self.builder.ins().fadd(args[0], args[1]),
-                // No floating-point modulo in cranelift?
-                Mod => self.call_external(external!(_frawk_fprem), args),
                 Neg => self.builder.ins().fneg(args[0]),
             }
         } else {
@@ -1002,7 +1000,6 @@ impl<'a> View<'a> {
                 Mul => self.builder.ins().imul(args[0], args[1]),
                 Minus => self.builder.ins().isub(args[0], args[1]),
                 Add => self.builder.ins().iadd(args[0], args[1]),
-                Mod => self.builder.ins().srem(args[0], args[1]),
                 Neg => self.builder.ins().ineg(args[0]),
             }
         }
@@ -1018,9 +1015,10 @@ impl<'a> View<'a> {
             Complement => self.builder.ins().bnot(args[0]),
             And => self.builder.ins().band(args[0], args[1]),
             Or => self.builder.ins().bor(args[0], args[1]),
-            LogicalRightShift => self.builder.ins().ushr(args[0], args[1]),
-            ArithmeticRightShift => self.builder.ins().sshr(args[0], args[1]),
-            LeftShift => self.builder.ins().ishl(args[0], args[1]),
+            // Cranelift's shifts only use the low bits of the shift count.
+            LogicalRightShift => self.call_external(external!(_frawk_rshiftl), args),
+            ArithmeticRightShift => self.call_external(external!(_frawk_rshift), args),
+            LeftShift => self.call_external(external!(_frawk_lshift), args),
             Xor => self.builder.ins().bxor(args[0], args[1]),
         }
     }
@@ -1218,7 +1216,6 @@ impl Backend for RegistrationState {
     type Ty = ();
     fn void_ptr_ty(&self) {}
     fn ptr_to(&self, (): ()) {}
-    fn usize_ty(&self) {}
     fn u32_ty(&self) {}
     fn get_ty(&self, _ty: compile::Ty) {}
 
@@ -1245,10 +1242,6 @@ impl<'a> Backend for View<'a> {
         // about "references", which cranelift uses to compute stack maps.
         self.void_ptr_ty()
     }
-    fn usize_ty(&self) -> Self::Ty {
-        // assume pointers are 64 bits
-        types::I64
-    }
     fn u32_ty(&self) -> Self::Ty {
         types::I32
     }
@@ -1356,7 +1349,6 @@ impl<'a> CodeGenerator for View<'a> {
             Arith { is_float, op } => Ok(self.arith(op, is_float, args)),
             Bitwise(bw) => Ok(self.bitwise(bw, args)),
             Math(ff) => Ok(self.floatfunc(ff, args)),
-            Div => Ok(self.builder.ins().fdiv(args[0], args[1])),
             Pow => Ok(self.call_external(external!(_frawk_pow), args)),
             FloatToInt => {
                 let ty = self.get_ty(compile::Ty::Int);
@@ -1370,6 +1362,53 @@ impl<'a> CodeGenerator for View<'a> {
         }
     }
 
+    fn store_loc(&mut self, loc: runtime::Int) -> Result<()> {
+        let rt = self.runtime_val();
+        let v = self.const_int(loc);
+        let offset = mem::offset_of!(intrinsics::Runtime, loc) as i32;
+        self.builder.ins().store(MemFlags::trusted(), v, rt, offset);
+        Ok(())
+    }
+
+    fn divide(&mut self, op: Divide, l: Value, r: Value) -> Result<Value> {
+        let slow_case = if op.is_float() {
+            let zero = self.builder.ins().f64const(0.0);
+            self.builder.ins().fcmp(FloatCC::Equal, r, zero)
+        } else {
+            // r is 0 or -1 iff r+1 is 0 or 1, as an unsigned integer.
+            let r_plus_one = self.builder.ins().iadd_imm(r, 1);
+            self.builder
+                .ins()
+                .icmp_imm(IntCC::UnsignedLessThanOrEqual, r_plus_one, 1)
+        };
+        let fast_block = self.builder.create_block();
+        let slow_block = self.builder.create_block();
+        let done_block = self.builder.create_block();
+        let res_ty = self.builder.func.dfg.value_type(r);
+        self.builder.append_block_param(done_block, res_ty);
+        self.builder.set_cold_block(slow_block);
+        self.builder
+            .ins()
+            .brif(slow_case, slow_block, &[], fast_block, &[]);
+
+        self.builder.switch_to_block(fast_block);
+        let fast_res = match op {
+            Divide::Div => self.builder.ins().fdiv(l, r),
+            // No floating-point modulo in cranelift?
+            Divide::ModFloat => self.call_external(external!(_frawk_fprem), &[l, r]),
+            Divide::ModInt => self.builder.ins().srem(l, r),
+        };
+        self.builder.ins().jump(done_block, &[fast_res]);
+
+        self.builder.switch_to_block(slow_block);
+        let rt = self.runtime_val();
+        let slow_res = self.call_external(op.slow_path(), &[rt, l, r]);
+        self.builder.ins().jump(done_block, &[slow_res]);
+
+        self.builder.switch_to_block(done_block);
+        Ok(self.builder.block_params(done_block)[0])
+    }
+
     // var-arg printing functions. The arguments here directly parallel the instruction
     // definitions.
 
@@ -1486,7 +1525,8 @@ impl<'a> CodeGenerator for View<'a> {
         let key_ty = self.get_ty(dst.1.iter()?);
         let map = self.get_val(map)?;
         let IterState { bytes, cur, base } = self.get_iter(dst)?;
-        let ptr = self.call_external(begin_fn, &[map]);
+        let rt = self.runtime_val();
+        let ptr = self.call_external(begin_fn, &[rt, map]);
         let map_len = self.call_external(len_fn, &[map]);
         let total_bytes = self.mul_by_type_size(key_ty, map_len)?;
         let zero = self.const_int(0);
@@ -10,27 +10,33 @@ use crate::runtime::{
     splitter::{
         batch::{ByteReader, CSVReader, WhitespaceOffsets},
         chunk::{ChunkProducer, OffsetChunk},
+        json::JsonReader,
         regex::RegexSplitter,
     },
     ChainedReader, FileRead, Float, Int, IntMap, Line, LineReader, RegexCache, Str, StrMap,
 };
 use crate::{
-    builtins::Variable,
+    builtins::{Bitwise, Variable},
+    bytecode::Cmp,
     common::{CancelSignal, Cleanup, FileSpec, Notification, Result},
     compile::Ty,
     pushdown::FieldSet,
+    source_map::SourceMap,
 };
 
+#[cfg(feature = "parquet_input")]
+use crate::runtime::splitter::parquet::ParquetReader;
+
 use libc::c_void;
 use paste::paste;
 use rand::{self, Rng};
-use regex::bytes::Regex;
-use smallvec;
+use regex::bytes::{Regex, RegexSet};
 
 use std::convert::TryFrom;
 use std::io;
 use std::mem;
 use std::slice;
+use std::sync::Arc;
 
 type SmallVec<T> = smallvec::SmallVec<[T; 4]>;
 
@@ -104,21 +110,29 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] str_to_float(str_ref_ty) -> float_ty;
         [ReadOnly] str_len(str_ref_ty) -> int_ty;
         starts_with_const(str_ref_ty, rt_ty, int_ty) -> int_ty;
+        [ReadOnly] contains_const(str_ref_ty, rt_ty, int_ty) -> int_ty;
         concat(str_ref_ty, str_ref_ty) -> str_ty;
         [ReadOnly] match_pat(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
         [ReadOnly] match_const_pat(str_ref_ty, rt_ty) -> int_ty;
+        [ReadOnly] match_set(str_ref_ty, rt_ty) -> int_ty;
         [ReadOnly] match_pat_loc(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        match_pat_loc_array(rt_ty, str_ref_ty, str_ref_ty, map_ty, str_ref_ty) -> int_ty;
         [ReadOnly] match_const_pat_loc(rt_ty, str_ref_ty, rt_ty) -> int_ty;
-        [ReadOnly] substr_index(str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] substr_index(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
         subst_first(rt_ty, str_ref_ty, str_ref_ty, str_ref_ty) -> int_ty;
         subst_all(rt_ty, str_ref_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        subst_first_const(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        subst_all_const(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
         gen_subst(rt_ty, str_ref_ty, str_ref_ty, str_ref_ty, str_ref_ty) -> str_ty;
         escape_csv(str_ref_ty) -> str_ty;
         escape_tsv(str_ref_ty) -> str_ty;
+        escape_json(str_ref_ty) -> str_ty;
+        float_to_json(float_ty) -> str_ty;
         substr(str_ref_ty, int_ty, int_ty) -> str_ty;
         [ReadOnly] get_col(rt_ty, int_ty) -> str_ty;
         [ReadOnly] join_csv(rt_ty, int_ty, int_ty) -> str_ty;
         [ReadOnly] join_tsv(rt_ty, int_ty, int_ty) -> str_ty;
+        [ReadOnly] join_json(rt_ty, int_ty, int_ty) -> str_ty;
         [ReadOnly] join_cols(rt_ty, int_ty, int_ty, str_ref_ty) -> str_ty;
         [ReadOnly] to_upper_ascii(str_ref_ty) -> str_ty;
         [ReadOnly] to_lower_ascii(str_ref_ty) -> str_ty;
@@ -126,17 +140,22 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         split_int(rt_ty, str_ref_ty, map_ty, str_ref_ty) -> int_ty;
         split_str(rt_ty, str_ref_ty, map_ty, str_ref_ty) -> int_ty;
         rand_float(rt_ty) -> float_ty;
+        [Cold] div(rt_ty, float_ty, float_ty) -> float_ty;
+        [Cold] mod_float(rt_ty, float_ty, float_ty) -> float_ty;
+        [Cold] mod_int(rt_ty, int_ty, int_ty) -> int_ty;
         seed_rng(rt_ty, int_ty) -> int_ty;
         reseed_rng(rt_ty) -> int_ty;
 
         exit(rt_ty, int_ty);
-        run_system(str_ref_ty) -> int_ty;
+        run_system(rt_ty, str_ref_ty) -> int_ty;
         print_all_stdout(rt_ty, pa_args_ty, int_ty);
         print_all_file(rt_ty, pa_args_ty, int_ty, str_ref_ty, int_ty);
         sprintf_impl(rt_ty, str_ref_ty, fmt_args_ty, fmt_tys_ty, int_ty) -> str_ty;
         printf_impl_file(rt_ty, str_ref_ty, fmt_args_ty, fmt_tys_ty, int_ty, str_ref_ty, int_ty);
         printf_impl_stdout(rt_ty, str_ref_ty, fmt_args_ty, fmt_tys_ty, int_ty);
-        close_file(rt_ty, str_ref_ty);
+        close_file(rt_ty, str_ref_ty) -> int_ty;
+        flush_file(rt_ty, str_ref_ty) -> int_ty;
+        flush_all(rt_ty) -> int_ty;
         read_err(rt_ty, str_ref_ty, int_ty) -> int_ty;
         read_err_stdin(rt_ty) -> int_ty;
         next_line(rt_ty, str_ref_ty, int_ty) -> str_ty;
@@ -163,6 +182,11 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly, ArgmemOnly] _frawk_exp(float_ty) -> float_ty;
         [ReadOnly, ArgmemOnly] _frawk_atan2(float_ty, float_ty) -> float_ty;
 
+        // Shifts, whose counts may be out of range for the corresponding instructions.
+        [ReadOnly, ArgmemOnly] _frawk_lshift(int_ty, int_ty) -> int_ty;
+        [ReadOnly, ArgmemOnly] _frawk_rshift(int_ty, int_ty) -> int_ty;
+        [ReadOnly, ArgmemOnly] _frawk_rshiftl(int_ty, int_ty) -> int_ty;
+
         load_var_str(rt_ty, int_ty) -> str_ty;
         store_var_str(rt_ty, int_ty, str_ref_ty);
         [ReadOnly] load_var_int(rt_ty, int_ty) -> int_ty;
@@ -172,84 +196,118 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] load_var_strmap(rt_ty, int_ty) -> map_ty;
         store_var_strmap(rt_ty, int_ty, map_ty);
 
-        [ReadOnly] str_lt(str_ref_ty, str_ref_ty) -> int_ty;
-        [ReadOnly] str_gt(str_ref_ty, str_ref_ty) -> int_ty;
-        [ReadOnly] str_lte(str_ref_ty, str_ref_ty) -> int_ty;
-        [ReadOnly] str_gte(str_ref_ty, str_ref_ty) -> int_ty;
-        [ReadOnly] str_eq(str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_lt(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_gt(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_lte(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_gte(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_eq(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_num_lt(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_num_gt(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_num_lte(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_num_gte(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_num_eq(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] str_num_neq(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
 
         drop_iter_int(iter_int_ty, int_ty);
         drop_iter_str(iter_str_ty, int_ty);
 
         alloc_intint() -> map_ty;
-        iter_intint(map_ty) -> iter_int_ty;
+        iter_intint(rt_ty, map_ty) -> iter_int_ty;
         [ReadOnly] len_intint(map_ty) -> int_ty;
-        [ReadOnly] lookup_intint(map_ty, int_ty) -> int_ty;
+        lookup_intint(map_ty, int_ty) -> int_ty;
+        lookup_str_intint(map_ty, int_ty) -> str_ty;
         [ReadOnly] contains_intint(map_ty, int_ty) -> int_ty;
         insert_intint(map_ty, int_ty, int_ty);
         delete_intint(map_ty, int_ty);
         clear_intint(map_ty);
         drop_intint(map_ty);
+        read_array_intint(str_ref_ty, map_ty) -> int_ty;
+        write_array_intint(str_ref_ty, map_ty) -> int_ty;
+        [ReadOnly] to_json_intint(map_ty) -> str_ty;
+        [ReadOnly] join_intint(rt_ty, map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_int_intint(map_ty, int_ty, int_ty) -> int_ty;
         inc_float_intint(map_ty, int_ty, float_ty) -> int_ty;
 
         alloc_intfloat() -> map_ty;
-        iter_intfloat(map_ty) -> iter_int_ty;
+        iter_intfloat(rt_ty, map_ty) -> iter_int_ty;
         [ReadOnly] len_intfloat(map_ty) -> int_ty;
-        [ReadOnly] lookup_intfloat(map_ty, int_ty) -> float_ty;
+        lookup_intfloat(map_ty, int_ty) -> float_ty;
+        lookup_str_intfloat(map_ty, int_ty) -> str_ty;
         [ReadOnly] contains_intfloat(map_ty, int_ty) -> int_ty;
         insert_intfloat(map_ty, int_ty, float_ty);
         delete_intfloat(map_ty, int_ty);
         clear_intfloat(map_ty);
         drop_intfloat(map_ty);
+        read_array_intfloat(str_ref_ty, map_ty) -> int_ty;
+        write_array_intfloat(str_ref_ty, map_ty) -> int_ty;
+        [ReadOnly] to_json_intfloat(map_ty) -> str_ty;
+        [ReadOnly] join_intfloat(rt_ty, map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_int_intfloat(map_ty, int_ty, int_ty) -> float_ty;
         inc_float_intfloat(map_ty, int_ty, float_ty) -> float_ty;
 
         alloc_intstr() -> map_ty;
-        iter_intstr(map_ty) -> iter_int_ty;
+        iter_intstr(rt_ty, map_ty) -> iter_int_ty;
         [ReadOnly] len_intstr(map_ty) -> int_ty;
-        [ReadOnly] lookup_intstr(map_ty, int_ty) -> str_ty;
+        lookup_intstr(map_ty, int_ty) -> str_ty;
         [ReadOnly] contains_intstr(map_ty, int_ty) -> int_ty;
         insert_intstr(map_ty, int_ty, str_ref_ty);
         delete_intstr(map_ty, int_ty);
         clear_intstr(map_ty);
         drop_intstr(map_ty);
+        read_array_intstr(str_ref_ty, map_ty) -> int_ty;
+        write_array_intstr(str_ref_ty, map_ty) -> int_ty;
+        [ReadOnly] to_json_intstr(map_ty) -> str_ty;
+        [ReadOnly] join_intstr(rt_ty, map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_int_intstr(map_ty, int_ty, int_ty) -> str_ty;
         inc_float_intstr(map_ty, int_ty, float_ty) -> str_ty;
 
         alloc_strint() -> map_ty;
-        iter_strint(map_ty) -> iter_str_ty;
+        iter_strint(rt_ty, map_ty) -> iter_str_ty;
         [ReadOnly] len_strint(map_ty) -> int_ty;
-        [ReadOnly] lookup_strint(map_ty, str_ref_ty) -> int_ty;
+        lookup_strint(map_ty, str_ref_ty) -> int_ty;
+        lookup_str_strint(map_ty, str_ref_ty) -> str_ty;
         [ReadOnly] contains_strint(map_ty, str_ref_ty) -> int_ty;
         insert_strint(map_ty, str_ref_ty, int_ty);
         delete_strint(map_ty, str_ref_ty);
         clear_strint(map_ty);
         drop_strint(map_ty);
+        read_array_strint(str_ref_ty, map_ty) -> int_ty;
+        write_array_strint(str_ref_ty, map_ty) -> int_ty;
+        [ReadOnly] to_json_strint(map_ty) -> str_ty;
+        [ReadOnly] join_strint(rt_ty, map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_int_strint(map_ty, str_ref_ty, int_ty) -> int_ty;
         inc_float_strint(map_ty, str_ref_ty, float_ty) -> int_ty;
 
         alloc_strfloat() -> map_ty;
-        iter_strfloat(map_ty) -> iter_str_ty;
+        iter_strfloat(rt_ty, map_ty) -> iter_str_ty;
         [ReadOnly] len_strfloat(map_ty) -> int_ty;
-        [ReadOnly] lookup_strfloat(map_ty, str_ref_ty) -> float_ty;
+        lookup_strfloat(map_ty, str_ref_ty) -> float_ty;
+        lookup_str_strfloat(map_ty, str_ref_ty) -> str_ty;
         [ReadOnly] contains_strfloat(map_ty, str_ref_ty) -> int_ty;
         insert_strfloat(map_ty, str_ref_ty, float_ty);
         delete_strfloat(map_ty, str_ref_ty);
         clear_strfloat(map_ty);
         drop_strfloat(map_ty);
+        read_array_strfloat(str_ref_ty, map_ty) -> int_ty;
+        write_array_strfloat(str_ref_ty, map_ty) -> int_ty;
+        [ReadOnly] to_json_strfloat(map_ty) -> str_ty;
+        [ReadOnly] join_strfloat(rt_ty, map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_int_strfloat(map_ty, str_ref_ty, int_ty) -> float_ty;
         inc_float_strfloat(map_ty, str_ref_ty, float_ty) -> float_ty;
 
         alloc_strstr() -> map_ty;
-        iter_strstr(map_ty) -> iter_str_ty;
+        iter_strstr(rt_ty, map_ty) -> iter_str_ty;
         [ReadOnly] len_strstr(map_ty) -> int_ty;
-        [ReadOnly] lookup_strstr(map_ty, str_ref_ty) -> str_ty;
+        lookup_strstr(map_ty, str_ref_ty) -> str_ty;
         [ReadOnly] contains_strstr(map_ty, str_ref_ty) -> int_ty;
         insert_strstr(map_ty, str_ref_ty, str_ref_ty);
         delete_strstr(map_ty, str_ref_ty);
         clear_strstr(map_ty);
         drop_strstr(map_ty);
+        read_array_strstr(str_ref_ty, map_ty) -> int_ty;
+        write_array_strstr(str_ref_ty, map_ty) -> int_ty;
+        [ReadOnly] to_json_strstr(map_ty) -> str_ty;
+        [ReadOnly] join_strstr(rt_ty, map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_int_strstr(map_ty, str_ref_ty, int_ty) -> str_ty;
         inc_float_strstr(map_ty, str_ref_ty, float_ty) -> str_ty;
 
@@ -278,15 +336,16 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
 
 macro_rules! fail {
     ($rt:expr, $($es:expr),+) => {{
+        let rt_raw = $rt as *mut Runtime;
+        let msg = (*rt_raw).source_map.annotate_encoded(format!($($es),*), (*rt_raw).loc);
+        eprintln_ignore!("failure in runtime {}. Halting execution", msg);
         #[cfg(test)]
         {
-            eprintln_ignore!("failure in runtime {}. Halting execution", format!($($es),*));
             panic!("failure in runtime")
         }
         #[cfg(not(test))]
         {
-            eprintln_ignore!("failure in runtime {}. Halting execution", format!($($es),*));
-            exit!($rt, 1)
+            exit!(rt_raw, 1)
         }
     }}
 }
@@ -331,7 +390,7 @@ macro_rules! exit {
             if pid == 1 {
                 // We are the main thread. Drop on `rt` should have waited for other threads to exit.
                 // All that's left is for us to abort.
-                std::process::exit(code)
+                std::process::exit(crate::runtime::exit_status(code))
             } else {
                 // Block forever. Let the main thread exit.
                 let n = Notification::default();
@@ -340,7 +399,7 @@ macro_rules! exit {
             }
         } else {
             std::ptr::drop_in_place(rt_raw);
-            std::process::exit(code)
+            std::process::exit(crate::runtime::exit_status(code))
         }
     }};
 }
@@ -352,16 +411,24 @@ macro_rules! with_input {
             $crate::codegen::intrinsics::InputData::V2($p) => $body,
             $crate::codegen::intrinsics::InputData::V3($p) => $body,
             $crate::codegen::intrinsics::InputData::V4($p) => $body,
+            $crate::codegen::intrinsics::InputData::V5($p) => $body,
+            #[cfg(feature = "parquet_input")]
+            $crate::codegen::intrinsics::InputData::V6($p) => $body,
         }
     };
 }
 
 pub(crate) type InputTuple<LR> = (<LR as LineReader>::Line, FileRead<LR>);
+pub(crate) type JsonLinesReader =
+    JsonReader<ChainedReader<RegexSplitter<Box<dyn io::Read + Send>>>>;
 pub(crate) enum InputData {
     V1(InputTuple<CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>>>),
     V2(InputTuple<ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk<WhitespaceOffsets>>>>>),
     V3(InputTuple<ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>>>),
     V4(InputTuple<ChainedReader<RegexSplitter<Box<dyn io::Read + Send>>>>),
+    V5(InputTuple<JsonLinesReader>),
+    #[cfg(feature = "parquet_input")]
+    V6(InputTuple<ParquetReader>),
 }
 
 pub(crate) trait IntoRuntime {
@@ -393,6 +460,8 @@ macro_rules! impl_into_runtime {
                     core: crate::interp::Core::new(ff),
                     cleanup: Cleanup::null(),
                     cancel_signal,
+                    loc: 0,
+                    source_map: Default::default(),
                 }
             }
         }
@@ -412,6 +481,9 @@ impl_into_runtime!(
 );
 impl_into_runtime!(ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>>, V3);
 impl_into_runtime!(ChainedReader<RegexSplitter<Box<dyn io::Read + Send>>>, V4);
+impl_into_runtime!(JsonLinesReader, V5);
+#[cfg(feature = "parquet_input")]
+impl_into_runtime!(ParquetReader, V6);
 
 pub(crate) struct Runtime<'a> {
     pub(crate) core: crate::interp::Core<'a>,
@@ -420,14 +492,24 @@ pub(crate) struct Runtime<'a> {
     pub(crate) concurrent: bool,
     pub(crate) cancel_signal: CancelSignal,
     pub(crate) cleanup: Cleanup<Self>,
+    // The location of the statement being run, as given by `source_map::encode`. Compiled code
+    // stores to this field directly; see `CodeGenerator::store_loc`.
+    pub(crate) loc: Int,
+    // Describes `loc` in error messages.
+    pub(crate) source_map: Arc<SourceMap<'a>>,
 }
 
 impl<'a> Runtime<'a> {
-    fn reset_file_vars(&mut self) {
+    fn reset_file_vars(&mut self) -> Result<()> {
         self.core.vars.fnr = 0;
-        self.core.vars.filename = with_input!(&mut self.input_data, |(_, read_files)| {
-            read_files.stdin_filename().upcast()
+        let (filename, index) = with_input!(&mut self.input_data, |(_, read_files)| {
+            (
+                read_files.stdin_filename().upcast(),
+                read_files.stdin_file_index(),
+            )
         });
+        self.core.vars.filename = filename;
+        self.core.write_files.start_input_file(index)
     }
 }
 
@@ -441,7 +523,9 @@ pub(crate) unsafe extern "C" fn exit(runtime: *mut c_void, code: Int) {
     exit!(runtime, code as i32);
 }
 
-pub(crate) unsafe extern "C" fn run_system(cmd: *mut U128) -> Int {
+pub(crate) unsafe extern "C" fn run_system(rt: *mut c_void, cmd: *mut U128) -> Int {
+    let rt = &mut *(rt as *mut Runtime);
+    try_silent_abort!(rt, rt.core.write_files.flush_all());
     let s: &Str = &*(cmd as *mut Str);
     s.with_bytes(runtime::run_command)
 }
@@ -451,6 +535,27 @@ pub(crate) unsafe extern "C" fn rand_float(runtime: *mut c_void) -> f64 {
     runtime.core.rng.gen_range(0.0..=1.0)
 }
 
+// The slow paths for division and modulo, called by generated code only when the divisor is zero
+// (or, for `mod_int`, -1); see `codegen::Divide`.
+
+pub(crate) unsafe extern "C" fn div(runtime: *mut c_void, l: Float, r: Float) -> Float {
+    let runtime = &mut *(runtime as *mut Runtime);
+    try_abort!(runtime, runtime::div(l, r, runtime.core.ieee_division))
+}
+
+pub(crate) unsafe extern "C" fn mod_float(runtime: *mut c_void, l: Float, r: Float) -> Float {
+    let runtime = &mut *(runtime as *mut Runtime);
+    try_abort!(
+        runtime,
+        runtime::mod_float(l, r, runtime.core.ieee_division)
+    )
+}
+
+pub(crate) unsafe extern "C" fn mod_int(runtime: *mut c_void, l: Int, r: Int) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    try_abort!(runtime, runtime::mod_int(l, r))
+}
+
 pub(crate) unsafe extern "C" fn seed_rng(runtime: *mut c_void, seed: Int) -> Int {
     let runtime = &mut *(runtime as *mut Runtime);
     runtime.core.reseed(seed as u64) as Int
@@ -495,12 +600,12 @@ pub(crate) unsafe extern "C" fn next_line_stdin_fused(runtime: *mut c_void) {
             runtime
                 .core
                 .regexes
-                .get_line_stdin_reuse(&runtime.core.vars.rs, read_files, line)
+                .get_line_stdin_reuse(&runtime.core.vars, read_files, line)
         }),
         "unexpected error when reading line from stdin:"
     );
     if changed {
-        runtime.reset_file_vars();
+        try_silent_abort!(runtime, runtime.reset_file_vars());
     }
 }
 
@@ -522,12 +627,12 @@ pub(crate) unsafe extern "C" fn next_line_stdin(runtime: *mut c_void) -> U128 {
             runtime
                 .core
                 .regexes
-                .get_line_stdin(&runtime.core.vars.rs, read_files)
+                .get_line_stdin(&runtime.core.vars, read_files)
         }),
         "unexpected error when reading line from stdin:"
     );
     if changed {
-        runtime.reset_file_vars();
+        try_silent_abort!(runtime, runtime.reset_file_vars());
     }
     mem::transmute::<Str, U128>(res)
 }
@@ -663,6 +768,24 @@ pub(crate) unsafe extern "C" fn join_tsv(runtime: *mut c_void, start: Int, end:
     mem::transmute::<Str, U128>(res)
 }
 
+pub(crate) unsafe extern "C" fn join_json(runtime: *mut c_void, start: Int, end: Int) -> U128 {
+    let sep: Str<'static> = ",".into();
+    let runtime = &mut *(runtime as *mut Runtime);
+    let res = try_abort!(
+        runtime,
+        with_input!(&mut runtime.input_data, |(line, _)| {
+            let nf = try_abort!(
+                runtime,
+                line.nf(&runtime.core.vars.fs, &mut runtime.core.regexes),
+                "nf:"
+            );
+            line.join_cols(start, end, &sep, nf, |s| runtime::escape_json(&s))
+        }),
+        "join_json:"
+    );
+    mem::transmute::<Str, U128>(runtime::json_array(&res))
+}
+
 pub(crate) unsafe extern "C" fn join_cols(
     runtime: *mut c_void,
     start: Int,
@@ -701,7 +824,7 @@ pub(crate) unsafe extern "C" fn set_col(runtime: *mut c_void, col: Int, s: *mut
     if let Err(e) = with_input!(&mut runtime.input_data, |(line, _)| line.set_col(
         col,
         s,
-        &runtime.core.vars.ofs,
+        &runtime.core.vars.fs,
         &mut runtime.core.regexes,
     )) {
         fail!(runtime, "set_col: {}", e);
@@ -725,6 +848,12 @@ pub(crate) unsafe extern "C" fn starts_with_const(
     ((s1_bytes.len() >= other.len()) && &s1_bytes[..other.len()] == other) as Int
 }
 
+pub(crate) unsafe extern "C" fn contains_const(s1: *mut c_void, base: *const u8, len: Int) -> Int {
+    debug_assert!(len >= 0);
+    let needle = slice::from_raw_parts(base, len as usize);
+    runtime::string_search::contains(needle, &*(s1 as *const Str)) as Int
+}
+
 pub(crate) unsafe extern "C" fn concat(s1: *mut c_void, s2: *mut c_void) -> U128 {
     let s1 = &*(s1 as *mut Str);
     let s2 = &*(s2 as *mut Str);
@@ -748,6 +877,12 @@ pub(crate) unsafe extern "C" fn match_pat(
     res as Int
 }
 
+pub(crate) unsafe extern "C" fn match_set(s: *mut c_void, set: *mut c_void) -> Int {
+    let s = &*(s as *mut Str);
+    let set = &*(set as *const RegexSet);
+    runtime::RegexCache::regex_set_matches(set, s)
+}
+
 pub(crate) unsafe extern "C" fn match_const_pat(s: *mut c_void, pat: *mut c_void) -> Int {
     let s = &*(s as *mut Str);
     let pat = &*(pat as *const Regex);
@@ -770,6 +905,27 @@ pub(crate) unsafe extern "C" fn match_pat_loc(
     res as Int
 }
 
+pub(crate) unsafe extern "C" fn match_pat_loc_array(
+    runtime: *mut c_void,
+    s: *mut c_void,
+    pat: *mut c_void,
+    into_arr: *mut c_void,
+    subsep: *mut c_void,
+) -> Int {
+    let runtime = runtime as *mut Runtime;
+    let s = &*(s as *mut Str);
+    let pat = &*(pat as *mut Str);
+    let subsep = &*(subsep as *mut Str);
+    let into_arr = mem::transmute::<*mut c_void, StrMap<Str>>(into_arr);
+    let res = try_abort!(
+        runtime,
+        (*runtime).core.match_regex_array(s, pat, &into_arr, subsep),
+        "match_pat_loc_array:"
+    );
+    mem::forget(into_arr);
+    res as Int
+}
+
 pub(crate) unsafe extern "C" fn match_const_pat_loc(
     runtime: *mut c_void,
     s: *mut c_void,
@@ -785,10 +941,19 @@ pub(crate) unsafe extern "C" fn match_const_pat_loc(
     )
 }
 
-pub(crate) unsafe extern "C" fn substr_index(s: *mut U128, t: *mut U128) -> Int {
+pub(crate) unsafe extern "C" fn substr_index(
+    runtime: *mut c_void,
+    s: *mut U128,
+    t: *mut U128,
+) -> Int {
+    let runtime = &*(runtime as *mut Runtime);
     let s = &*(s as *mut Str);
     let t = &*(t as *mut Str);
-    runtime::string_search::index_substr(/*needle*/ t, /*haystack*/ s)
+    if runtime.core.vars.ignore_case() {
+        runtime::string_search::index_substr_ignore_case(/*needle*/ t, /*haystack*/ s)
+    } else {
+        runtime::string_search::index_substr(/*needle*/ t, /*haystack*/ s)
+    }
 }
 
 pub(crate) unsafe extern "C" fn subst_first(
@@ -833,6 +998,32 @@ pub(crate) unsafe extern "C" fn subst_all(
     nsubs
 }
 
+pub(crate) unsafe extern "C" fn subst_first_const(
+    pat: *mut c_void,
+    s: *mut U128,
+    in_s: *mut U128,
+) -> Int {
+    let pat = &*(pat as *const Regex);
+    let s = &*(s as *mut Str);
+    let in_s = &mut *(in_s as *mut Str);
+    let (subbed, new) = in_s.subst_first(pat, s);
+    *in_s = subbed;
+    new as Int
+}
+
+pub(crate) unsafe extern "C" fn subst_all_const(
+    pat: *mut c_void,
+    s: *mut U128,
+    in_s: *mut U128,
+) -> Int {
+    let pat = &*(pat as *const Regex);
+    let s = &*(s as *mut Str);
+    let in_s = &mut *(in_s as *mut Str);
+    let (subbed, nsubs) = in_s.subst_all(pat, s);
+    *in_s = subbed;
+    nsubs
+}
+
 pub(crate) unsafe extern "C" fn gen_subst(
     runtime: *mut c_void,
     pat: *mut U128,
@@ -863,6 +1054,14 @@ pub(crate) unsafe extern "C" fn escape_tsv(s: *mut U128) -> U128 {
     mem::transmute::<Str, U128>(runtime::escape_tsv(&*(s as *mut Str)))
 }
 
+pub(crate) unsafe extern "C" fn escape_json(s: *mut U128) -> U128 {
+    mem::transmute::<Str, U128>(runtime::escape_json(&*(s as *mut Str)))
+}
+
+pub(crate) unsafe extern "C" fn float_to_json(f: Float) -> U128 {
+    mem::transmute::<Str, U128>(runtime::float_to_json(f))
+}
+
 pub(crate) unsafe extern "C" fn substr(base: *mut U128, l: Int, r: Int) -> U128 {
     use std::cmp::{max, min};
     let base = &*(base as *mut Str);
@@ -969,7 +1168,19 @@ pub(crate) unsafe extern "C" fn load_var_int(rt: *mut c_void, var: usize) -> Int
 pub(crate) unsafe extern "C" fn store_var_int(rt: *mut c_void, var: usize, i: Int) {
     let runtime = &mut *(rt as *mut Runtime);
     if let Ok(var) = Variable::try_from(var) {
+        if let Variable::NF = var {
+            if let Err(e) = with_input!(&mut runtime.input_data, |(line, _)| line.set_nf(
+                i,
+                &runtime.core.vars.fs,
+                &mut runtime.core.regexes,
+            )) {
+                fail!(runtime, "set_nf: {}", e);
+            }
+        }
         try_abort!(runtime, runtime.core.vars.store_int(var, i));
+        if let Variable::IGNORECASE = var {
+            runtime.core.regexes.set_ignore_case(i != 0);
+        }
     } else {
         fail!(runtime, "invalid variable code={}", var)
     }
@@ -1017,22 +1228,43 @@ pub(crate) unsafe extern "C" fn store_var_strmap(rt: *mut c_void, var: usize, ma
     }
 }
 
-macro_rules! str_compare_inner {
-    ($name:ident, $op:tt) => {
-        pub(crate) unsafe extern "C" fn $name(s1: *mut c_void, s2: *mut c_void) -> Int {
+macro_rules! str_compare {
+    ($($name:ident ($op:ident);)*) => { $(
+        pub(crate) unsafe extern "C" fn $name(
+            rt: *mut c_void,
+            s1: *mut c_void,
+            s2: *mut c_void,
+        ) -> Int {
+            let runtime = &*(rt as *mut Runtime);
             let s1 = &*(s1 as *mut Str);
             let s2 = &*(s2 as *mut Str);
-            let res = s1.with_bytes(|bs1| s2.with_bytes(|bs2| bs1 $op bs2)) as Int;
-            res
+            Cmp::$op.eval_str(s1, s2, runtime.core.vars.ignore_case()) as Int
         }
-    }
-}
-macro_rules! str_compare {
-    ($($name:ident ($op:tt);)*) => { $( str_compare_inner!($name, $op); )* };
+    )* };
 }
 
 str_compare! {
-    str_lt(<); str_gt(>); str_lte(<=); str_gte(>=); str_eq(==);
+    str_lt(Lt); str_gt(Gt); str_lte(Lte); str_gte(Gte); str_eq(Eq);
+}
+
+macro_rules! str_num_compare {
+    ($($name:ident ($op:ident);)*) => { $(
+        pub(crate) unsafe extern "C" fn $name(
+            rt: *mut c_void,
+            s1: *mut c_void,
+            s2: *mut c_void,
+        ) -> Int {
+            let runtime = &*(rt as *mut Runtime);
+            let s1 = &*(s1 as *mut Str);
+            let s2 = &*(s2 as *mut Str);
+            Cmp::$op.eval_str_num(s1, s2, runtime.core.vars.ignore_case()) as Int
+        }
+    )* };
+}
+
+str_num_compare! {
+    str_num_lt(Lt); str_num_gt(Gt); str_num_lte(Lte); str_num_gte(Gte); str_num_eq(Eq);
+    str_num_neq(Neq);
 }
 
 pub(crate) unsafe extern "C" fn drop_iter_int(iter: *mut Int, len: usize) {
@@ -1170,11 +1402,26 @@ pub(crate) unsafe extern "C" fn printf_impl_stdout(
     }
 }
 
-pub(crate) unsafe extern "C" fn close_file(rt: *mut c_void, file: *mut U128) {
+pub(crate) unsafe extern "C" fn close_file(rt: *mut c_void, file: *mut U128) -> Int {
     let rt = &mut *(rt as *mut Runtime);
     let file = &*(file as *mut Str);
-    with_input!(&mut rt.input_data, |(_, read_files)| read_files.close(file));
-    try_abort!(rt, rt.core.write_files.close(file));
+    let input = with_input!(&mut rt.input_data, |(_, read_files)| read_files.close(file));
+    let res = try_abort!(rt, rt.core.write_files.close(file));
+    match input {
+        Some(status) if res < 0 => status,
+        _ => res,
+    }
+}
+
+pub(crate) unsafe extern "C" fn flush_file(rt: *mut c_void, file: *mut U128) -> Int {
+    let rt = &mut *(rt as *mut Runtime);
+    try_silent_abort!(rt, rt.core.write_files.flush(&*(file as *mut Str)))
+}
+
+pub(crate) unsafe extern "C" fn flush_all(rt: *mut c_void) -> Int {
+    let rt = &mut *(rt as *mut Runtime);
+    try_silent_abort!(rt, rt.core.write_files.flush_all());
+    0
 }
 
 pub(crate) unsafe extern "C" fn _frawk_cos(f: Float) -> Float {
@@ -1209,6 +1456,18 @@ pub(crate) unsafe extern "C" fn _frawk_atan2(x: Float, y: Float) -> Float {
     x.atan2(y)
 }
 
+pub(crate) unsafe extern "C" fn _frawk_lshift(x: Int, y: Int) -> Int {
+    Bitwise::LeftShift.eval2(x, y)
+}
+
+pub(crate) unsafe extern "C" fn _frawk_rshift(x: Int, y: Int) -> Int {
+    Bitwise::ArithmeticRightShift.eval2(x, y)
+}
+
+pub(crate) unsafe extern "C" fn _frawk_rshiftl(x: Int, y: Int) -> Int {
+    Bitwise::LogicalRightShift.eval2(x, y)
+}
+
 pub(crate) unsafe extern "C" fn _frawk_pow(x: Float, y: Float) -> Float {
     Float::powf(x, y)
 }
@@ -1307,10 +1566,11 @@ macro_rules! map_impl {
                 mem::transmute::<runtime::SharedMap<$k, $v>, *mut c_void>(res)
             }
 
-            pub(crate) unsafe extern "C" fn [< iter_ $ty >](map: *mut c_void) -> iter_ty!($k) {
+            pub(crate) unsafe extern "C" fn [< iter_ $ty >](runtime: *mut c_void, map: *mut c_void) -> iter_ty!($k) {
                 debug_assert!(!map.is_null());
+                let runtime = &*(runtime as *mut Runtime);
                 let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
-                let iter: Vec<_> = map.to_vec();
+                let iter: Vec<_> = map.to_vec(runtime.core.key_order, runtime.core.vars.ignore_case());
                 mem::forget(map);
                 let b = iter.into_boxed_slice();
                 Box::into_raw(b) as _
@@ -1324,8 +1584,29 @@ macro_rules! map_impl {
                 res as Int
             }
 
+            pub(crate) unsafe extern "C" fn [<to_json_ $ty>](map: *mut c_void) -> U128 {
+                debug_assert!(!map.is_null());
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let res = map.to_json();
+                mem::forget(map);
+                mem::transmute::<Str, U128>(res)
+            }
+
+            pub(crate) unsafe extern "C" fn [<join_ $ty>](runtime: *mut c_void, map: *mut c_void, sep: *mut U128, all: Int) -> U128 {
+                debug_assert!(!map.is_null());
+                let runtime = &*(runtime as *mut Runtime);
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let res = map.join(
+                    &*(sep as *mut Str),
+                    all != 0,
+                    runtime.core.key_order,
+                    runtime.core.vars.ignore_case(),
+                );
+                mem::forget(map);
+                mem::transmute::<Str, U128>(res)
+            }
+
             pub(crate) unsafe extern "C" fn [<lookup_ $ty>](map: *mut c_void, k: in_ty!($k)) -> out_ty!($v) {
-                // TODO: this should probably insert the value as well!
                 debug_assert!(!map.is_null());
                 let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
                 let key = convert_in!($k, &k);
@@ -1400,6 +1681,58 @@ map_impl!(strint, Str, Int);
 map_impl!(strfloat, Str, Float);
 map_impl!(strstr, Str, Str);
 
+macro_rules! persist_impl {
+    ($ty:ident, $k:tt, $v:tt) => {
+        paste! {
+            pub(crate) unsafe extern "C" fn [<read_array_ $ty>](file: *mut U128, map: *mut c_void) -> Int {
+                debug_assert!(!map.is_null());
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let res = map.load(&*(file as *mut Str)).is_ok() as Int;
+                mem::forget(map);
+                res
+            }
+
+            pub(crate) unsafe extern "C" fn [<write_array_ $ty>](file: *mut U128, map: *mut c_void) -> Int {
+                debug_assert!(!map.is_null());
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let res = map.save(&*(file as *mut Str)).is_ok() as Int;
+                mem::forget(map);
+                res
+            }
+        }
+    };
+}
+
+persist_impl!(intint, Int, Int);
+persist_impl!(intfloat, Int, Float);
+persist_impl!(intstr, Int, Str);
+persist_impl!(strint, Str, Int);
+persist_impl!(strfloat, Str, Float);
+persist_impl!(strstr, Str, Str);
+
+macro_rules! lookup_str_impl {
+    ($ty:ident, $k:tt, $v:tt) => {
+        paste! {
+            pub(crate) unsafe extern "C" fn [<lookup_str_ $ty>](map: *mut c_void, k: in_ty!($k)) -> U128 {
+                debug_assert!(!map.is_null());
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let key = convert_in!($k, &k);
+                let res: Str = match map.get_init(key) {
+                    Some(v) => runtime::convert(v),
+                    None => Str::default(),
+                };
+                mem::forget(map);
+                convert_out!(Str, res)
+            }
+        }
+    };
+}
+
+lookup_str_impl!(intint, Int, Int);
+lookup_str_impl!(intfloat, Int, Float);
+lookup_str_impl!(strint, Str, Int);
+lookup_str_impl!(strfloat, Str, Float);
+
 macro_rules! slot_impl {
     ($name:ident, $ty:tt) => {
         paste! {
@@ -13,6 +13,7 @@ fn cstr(fa: &FunctionAttr) -> (*const libc::c_char, usize) {
     match fa {
         ReadOnly => cstr_len!("readonly"),
         ArgmemOnly => cstr_len!("argmemonly"),
+        Cold => cstr_len!("cold"),
     }
 }
 
@@ -5,6 +5,7 @@
 
 use super::TypeMap;
 
+use crate::codegen::Divide;
 use crate::compile::Ty;
 
 use lazy_static::lazy_static;
@@ -21,6 +22,7 @@ pub enum Function {
     Log2,
     Log10,
     Exp,
+    FloatToInt,
 }
 
 macro_rules! intrinsic_id {
@@ -41,6 +43,7 @@ lazy_static! {
     static ref LOG2_ID: c_uint = intrinsic_id!("llvm.log2");
     static ref LOG10_ID: c_uint = intrinsic_id!("llvm.log10");
     static ref EXP_ID: c_uint = intrinsic_id!("llvm.exp");
+    static ref FPTOSI_SAT_ID: c_uint = intrinsic_id!("llvm.fptosi.sat");
 }
 
 /// Dropping a string is one of the more common operations performed by a frawk program. Strings
@@ -100,6 +103,76 @@ pub(crate) unsafe fn gen_drop_str(
     decl
 }
 
+/// Division and modulo have to check for a zero divisor, which almost never occurs. As with
+/// `gen_drop_str`, we implement the check and the operation itself in LLVM so that both can be
+/// inlined, calling `slow` (the intrinsic given by `Divide::slow_path`) only when the check fails.
+pub(crate) unsafe fn gen_divide(
+    ctx: LLVMContextRef,
+    module: LLVMModuleRef,
+    tmap: &TypeMap,
+    op: Divide,
+    slow: LLVMValueRef,
+) -> LLVMValueRef {
+    let (ty, name) = match op {
+        Divide::Div => (tmap.get_ty(Ty::Float), c_str!("div_fast")),
+        Divide::ModFloat => (tmap.get_ty(Ty::Float), c_str!("mod_float_fast")),
+        Divide::ModInt => (tmap.get_ty(Ty::Int), c_str!("mod_int_fast")),
+    };
+    let mut args = [tmap.runtime_ty, ty, ty];
+    let fty = LLVMFunctionType(ty, args.as_mut_ptr(), 3, 0);
+    let decl = LLVMAddFunction(module, name, fty);
+    LLVMSetLinkage(decl, llvm_sys::LLVMLinkage::LLVMLinkerPrivateLinkage);
+    let builder = LLVMCreateBuilderInContext(ctx);
+    let entry = LLVMAppendBasicBlockInContext(ctx, decl, c_str!(""));
+    let fast = LLVMAppendBasicBlockInContext(ctx, decl, c_str!(""));
+    let slow_bb = LLVMAppendBasicBlockInContext(ctx, decl, c_str!(""));
+    LLVMPositionBuilderAtEnd(builder, entry);
+    let rt = LLVMGetParam(decl, 0);
+    let l = LLVMGetParam(decl, 1);
+    let r = LLVMGetParam(decl, 2);
+    let test = if op.is_float() {
+        LLVMBuildFCmp(
+            builder,
+            llvm_sys::LLVMRealPredicate::LLVMRealOEQ,
+            r,
+            LLVMConstReal(ty, 0.0),
+            c_str!(""),
+        )
+    } else {
+        // test = (r + 1) as u64 <= 1, i.e. r is 0 or -1; srem is undefined for both.
+        let r_plus_one = LLVMBuildAdd(
+            builder,
+            r,
+            LLVMConstInt(ty, 1, /*sign_extend=*/ 0),
+            c_str!(""),
+        );
+        LLVMBuildICmp(
+            builder,
+            llvm_sys::LLVMIntPredicate::LLVMIntULE,
+            r_plus_one,
+            LLVMConstInt(ty, 1, /*sign_extend=*/ 0),
+            c_str!(""),
+        )
+    };
+    LLVMBuildCondBr(builder, test, slow_bb, fast);
+
+    // Fast path, divide inline
+    LLVMPositionBuilderAtEnd(builder, fast);
+    let res = match op {
+        Divide::Div => LLVMBuildFDiv(builder, l, r, c_str!("")),
+        Divide::ModFloat => LLVMBuildFRem(builder, l, r, c_str!("")),
+        Divide::ModInt => LLVMBuildSRem(builder, l, r, c_str!("")),
+    };
+    LLVMBuildRet(builder, res);
+
+    // Slow path, call into the runtime, which reports the error
+    LLVMPositionBuilderAtEnd(builder, slow_bb);
+    let res = LLVMBuildCall(builder, slow, [rt, l, r].as_mut_ptr(), 3, c_str!(""));
+    LLVMBuildRet(builder, res);
+    LLVMDisposeBuilder(builder);
+    decl
+}
+
 impl Function {
     pub(crate) unsafe fn get_val(self, module: LLVMModuleRef, tmap: &TypeMap) -> LLVMValueRef {
         match self {
@@ -127,6 +200,12 @@ impl Function {
             Function::Exp => {
                 LLVMGetIntrinsicDeclaration(module, *EXP_ID, &mut tmap.get_ty(Ty::Float), 1)
             }
+            Function::FloatToInt => {
+                // Unlike fptosi, this saturates out-of-range values and maps NaN to 0, matching
+                // `as` in Rust.
+                let mut tys = [tmap.get_ty(Ty::Int), tmap.get_ty(Ty::Float)];
+                LLVMGetIntrinsicDeclaration(module, *FPTOSI_SAT_ID, tys.as_mut_ptr(), 2)
+            }
         }
     }
 }
@@ -14,7 +14,6 @@ use llvm_sys::{
     prelude::{LLVMContextRef, LLVMModuleRef, LLVMTypeRef, LLVMValueRef},
     support::LLVMAddSymbol,
 };
-use smallvec;
 use std::cell::RefCell;
 
 struct Intrinsic {
@@ -6,7 +6,7 @@ pub(crate) mod intrinsics;
 use crate::builtins;
 use crate::bytecode::Accum;
 use crate::codegen::{
-    self, intrinsics::register_all, Backend, CodeGenerator, Handles, Jit, Ref, Sig, StrReg,
+    self, intrinsics::register_all, Backend, CodeGenerator, Divide, Handles, Jit, Ref, Sig, StrReg,
 };
 use crate::common::{Either, FileSpec, NodeIx, NumTy, Result, Stage};
 use crate::compile::{self, Ty, Typer};
@@ -79,6 +79,7 @@ struct View<'a> {
     prints: &'a mut HashMap<(usize, /*stdout*/ bool), LLVMValueRef>,
     handles: &'a mut Handles,
     drop_str: LLVMValueRef,
+    divides: [LLVMValueRef; 3],
     // We keep an extra builder always pointed at the start of the function. This is because
     // binding new string values requires an `alloca`; and we do not want to call `alloca` where a
     // string variable is referenced: for example, we do not want to call alloca in a loop.
@@ -96,9 +97,6 @@ impl<'a> Backend for View<'a> {
     fn ptr_to(&self, ty: Self::Ty) -> Self::Ty {
         unsafe { LLVMPointerType(ty, 0) }
     }
-    fn usize_ty(&self) -> Self::Ty {
-        unsafe { LLVMIntTypeInContext(self.ctx, (mem::size_of::<*const u8>() * 8) as libc::c_uint) }
-    }
     fn get_ty(&self, ty: compile::Ty) -> Self::Ty {
         self.tmap.get_ty(ty)
     }
@@ -314,7 +312,6 @@ impl<'a> CodeGenerator for View<'a> {
                             Mul => LLVMBuildFMul(self.f.builder, args[0], args[1], c_str!("")),
                             Minus => LLVMBuildFSub(self.f.builder, args[0], args[1], c_str!("")),
                             Add => LLVMBuildFAdd(self.f.builder, args[0], args[1], c_str!("")),
-                            Mod => LLVMBuildFRem(self.f.builder, args[0], args[1], c_str!("")),
                             Neg => LLVMBuildFNeg(self.f.builder, args[0], c_str!("")),
                         }
                     } else {
@@ -322,7 +319,6 @@ impl<'a> CodeGenerator for View<'a> {
                             Mul => LLVMBuildMul(self.f.builder, args[0], args[1], c_str!("")),
                             Minus => LLVMBuildSub(self.f.builder, args[0], args[1], c_str!("")),
                             Add => LLVMBuildAdd(self.f.builder, args[0], args[1], c_str!("")),
-                            Mod => LLVMBuildSRem(self.f.builder, args[0], args[1], c_str!("")),
                             Neg => {
                                 let zero = self.const_int(0);
                                 LLVMBuildSub(self.f.builder, zero, args[0], c_str!(""))
@@ -342,13 +338,14 @@ impl<'a> CodeGenerator for View<'a> {
                         ),
                         And => LLVMBuildAnd(self.f.builder, args[0], args[1], c_str!("")),
                         Or => LLVMBuildOr(self.f.builder, args[0], args[1], c_str!("")),
+                        // LLVM's shifts are undefined for counts outside of [0, 64).
                         LogicalRightShift => {
-                            LLVMBuildLShr(self.f.builder, args[0], args[1], c_str!(""))
+                            self.call(codegen::intrinsics::_frawk_rshiftl as _, args)
                         }
                         ArithmeticRightShift => {
-                            LLVMBuildAShr(self.f.builder, args[0], args[1], c_str!(""))
+                            self.call(codegen::intrinsics::_frawk_rshift as _, args)
                         }
-                        LeftShift => LLVMBuildShl(self.f.builder, args[0], args[1], c_str!("")),
+                        LeftShift => self.call(codegen::intrinsics::_frawk_lshift as _, args),
                         Xor => LLVMBuildXor(self.f.builder, args[0], args[1], c_str!("")),
                     })
                 }
@@ -356,14 +353,8 @@ impl<'a> CodeGenerator for View<'a> {
                     Either::Left(fname) => self.call(fname, args),
                     Either::Right(builtin) => self.call_builtin(builtin, args),
                 }),
-                Div => Ok(LLVMBuildFDiv(self.f.builder, args[0], args[1], c_str!(""))),
                 Pow => Ok(self.call_builtin(BuiltinFunc::Pow, args)),
-                FloatToInt => Ok(LLVMBuildFPToSI(
-                    self.f.builder,
-                    args[0],
-                    self.get_ty(Ty::Int),
-                    c_str!(""),
-                )),
+                FloatToInt => Ok(self.call_builtin(BuiltinFunc::FloatToInt, args)),
                 IntToFloat => Ok(LLVMBuildSIToFP(
                     self.f.builder,
                     args[0],
@@ -374,6 +365,40 @@ impl<'a> CodeGenerator for View<'a> {
             }
         }
     }
+    fn store_loc(&mut self, loc: runtime::Int) -> Result<()> {
+        let rt = self.runtime_val();
+        let v = self.const_int(loc);
+        unsafe {
+            let i8_ptr = LLVMPointerType(LLVMIntTypeInContext(self.ctx, 8), 0);
+            let base = LLVMBuildBitCast(self.f.builder, rt, i8_ptr, c_str!(""));
+            let offset = mem::offset_of!(codegen::intrinsics::Runtime, loc) as u64;
+            let mut indices = [LLVMConstInt(self.tmap.get_ty(Ty::Int), offset, 0)];
+            let field = LLVMBuildGEP(
+                self.f.builder,
+                base,
+                indices.as_mut_ptr(),
+                indices.len() as libc::c_uint,
+                c_str!(""),
+            );
+            let int_ptr = LLVMPointerType(self.tmap.get_ty(Ty::Int), 0);
+            let field = LLVMBuildBitCast(self.f.builder, field, int_ptr, c_str!(""));
+            LLVMBuildStore(self.f.builder, v, field);
+        }
+        Ok(())
+    }
+    fn divide(&mut self, op: Divide, l: Self::Val, r: Self::Val) -> Result<Self::Val> {
+        let rt = self.runtime_val();
+        let mut args = [rt, l, r];
+        unsafe {
+            Ok(LLVMBuildCall(
+                self.f.builder,
+                self.divides[op as usize],
+                args.as_mut_ptr(),
+                args.len() as libc::c_uint,
+                c_str!(""),
+            ))
+        }
+    }
     fn printf(
         &mut self,
         output: &Option<(StrReg, FileSpec)>,
@@ -490,7 +515,8 @@ impl<'a> CodeGenerator for View<'a> {
                 _ => return err!("iterating over non-map type: {:?}", map.1),
             };
 
-            let iter_ptr = self.call(begin_fn, &mut [arrv]);
+            let rt = self.runtime_val();
+            let iter_ptr = self.call(begin_fn, &mut [rt, arrv]);
             let cur_index = self.alloca(Ty::Int)?;
 
             let ty = self.tmap.get_ty(Ty::Int);
@@ -626,6 +652,8 @@ pub(crate) struct Generator<'a, 'b> {
 
     // Specialized implementation of string destruction.
     drop_str: LLVMValueRef,
+    // Specialized implementations of division and modulo, indexed by `Divide`.
+    divides: [LLVMValueRef; 3],
 }
 
 impl<'a, 'b> Drop for Generator<'a, 'b> {
@@ -696,6 +724,7 @@ macro_rules! view_at {
             ctx: $slf.ctx,
             module: $slf.module,
             drop_str: $slf.drop_str,
+            divides: $slf.divides,
             entry_builder: $entry_builder,
         }
     };
@@ -792,6 +821,7 @@ impl<'a, 'b> Generator<'a, 'b> {
             handles: Default::default(),
             cfg,
             drop_str: ptr::null_mut(),
+            divides: [ptr::null_mut(); 3],
         };
         res.build_map();
         res.build_decls();
@@ -800,6 +830,11 @@ impl<'a, 'b> Generator<'a, 'b> {
         let drop_slow = res.intrinsics.get(intrinsic!(drop_str_slow));
         res.drop_str =
             builtin_functions::gen_drop_str(res.ctx, res.module, &res.type_map, drop_slow);
+        for op in [Divide::Div, Divide::ModFloat, Divide::ModInt] {
+            let slow = res.intrinsics.get(op.slow_path());
+            res.divides[op as usize] =
+                builtin_functions::gen_divide(res.ctx, res.module, &res.type_map, op, slow);
+        }
         for i in 0..nframes {
             res.gen_function(i)?;
         }
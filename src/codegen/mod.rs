@@ -2,16 +2,24 @@
 //! programs based on the output of the `compile` module.
 //!
 //! The module root contains code that is shared by the cranelift and LLVM backends.
+
+// Builds without either JIT backend still use the runtime glue in `intrinsics` to set up input for
+// the interpreter, but none of the rest of this module.
+#![cfg_attr(
+    not(any(feature = "llvm_backend", feature = "cranelift_backend")),
+    allow(dead_code)
+)]
 use crate::{
     builtins,
     bytecode::{self, Accum},
-    common::{CancelSignal, Cleanup, FileSpec, NumTy, Result, Stage},
+    common::{CancelSignal, Cleanup, FileSpec, NumTy, OutputOrder, Result, Stage},
     compile,
     pushdown::FieldSet,
-    runtime::{self, UniqueStr},
+    runtime::{self, KeyOrder, UniqueStr},
+    source_map::{self, SourceMap},
 };
 
-use regex::bytes::Regex;
+use regex::bytes::{Regex, RegexSet};
 
 use std::marker::PhantomData;
 use std::mem;
@@ -22,6 +30,11 @@ use std::sync::Arc;
 pub struct Config {
     pub opt_level: usize,
     pub num_workers: usize,
+    pub output_order: OutputOrder,
+    // Divide by zero as IEEE 754 does, rather than failing with an error.
+    pub ieee_division: bool,
+    // The order in which for-in loops and join visit the keys of an array.
+    pub key_order: KeyOrder,
 }
 
 macro_rules! external {
@@ -32,6 +45,7 @@ macro_rules! external {
 
 #[macro_use]
 pub(crate) mod intrinsics;
+#[cfg(feature = "cranelift_backend")]
 pub(crate) mod clif;
 #[cfg(feature = "llvm_backend")]
 pub(crate) mod llvm;
@@ -66,7 +80,6 @@ pub(crate) enum Arith {
     Mul,
     Minus,
     Add,
-    Mod,
     Neg,
 }
 
@@ -77,13 +90,35 @@ pub(crate) enum Op {
     Arith { is_float: bool, op: Arith },
     Bitwise(builtins::Bitwise),
     Math(builtins::FloatFunc),
-    Div,
     Pow,
     FloatToInt,
     IntToFloat,
     Intrinsic(*const u8),
 }
 
+/// Operations that fail when the divisor is zero. Backends perform these inline, and only call the
+/// intrinsic given by [`Divide::slow_path`] (which reports the error) when the divisor is zero or,
+/// for `ModInt`, -1: the remainder of the smallest integer by -1 overflows.
+#[derive(Copy, Clone)]
+pub(crate) enum Divide {
+    Div,
+    ModFloat,
+    ModInt,
+}
+
+impl Divide {
+    pub(crate) fn is_float(self) -> bool {
+        !matches!(self, Divide::ModInt)
+    }
+    pub(crate) fn slow_path(self) -> *const u8 {
+        match self {
+            Divide::Div => external!(div),
+            Divide::ModFloat => external!(mod_float),
+            Divide::ModInt => external!(mod_int),
+        }
+    }
+}
+
 fn op(op: Arith, is_float: bool) -> Op {
     Op::Arith { is_float, op }
 }
@@ -95,6 +130,7 @@ fn cmp(op: Cmp, is_float: bool) -> Op {
 pub enum FunctionAttr {
     ReadOnly,
     ArgmemOnly,
+    Cold,
 }
 
 /// A handle around a generated main function, potentially allocated dynamically with the given
@@ -137,13 +173,19 @@ pub(crate) trait Jit: Sized {
 
 /// Run the main function (or functions, for parallel scripts) given a [`Jit`] and the various
 /// other parameters required to construct a runtime.
+#[allow(clippy::too_many_arguments)]
 pub(crate) unsafe fn run_main<R, FF, J>(
     mut jit: J,
     stdin: R,
     ff: FF,
     used_fields: &FieldSet,
     named_columns: Option<Vec<&[u8]>>,
+    slot_aggs: crate::interp::SlotAggs,
     num_workers: usize,
+    output_order: OutputOrder,
+    ieee_division: bool,
+    key_order: KeyOrder,
+    source_map: SourceMap,
     cancel_signal: CancelSignal,
 ) -> Result<()>
 where
@@ -152,6 +194,11 @@ where
     J: Jit,
 {
     let mut rt = stdin.into_runtime(ff, used_fields, named_columns, cancel_signal.clone());
+    rt.core.slot_aggs = slot_aggs;
+    rt.core.write_files.set_output_order(output_order);
+    rt.core.ieee_division = ieee_division;
+    rt.core.key_order = key_order;
+    rt.source_map = Arc::new(source_map);
     let main = jit.main_functions()?;
     match main {
         Stage::Main(m) => {
@@ -191,10 +238,12 @@ where
                 if rt.core.write_files.flush_stdout().is_err() {
                     return Ok(());
                 }
+                rt.core.write_files.start_output_by_file();
 
                 rt.concurrent = true;
 
                 let (sender, receiver) = crossbeam_channel::bounded(reads.len());
+                rt.core.vars.set_workers(reads.len() as runtime::Int + 1);
                 let launch_data: Vec<_> = reads
                     .into_iter()
                     .enumerate()
@@ -203,6 +252,7 @@ where
                             reader,
                             sender.clone(),
                             rt.core.shuttle(i as runtime::Int + 2),
+                            rt.source_map.clone(),
                         )
                     })
                     .collect();
@@ -210,7 +260,7 @@ where
                     let old_read_files = mem::take(&mut read_files.inputs);
                     let main_loop_fn = main_loop.unwrap();
                     let scope_res = crossbeam::scope(|s| {
-                        for (reader, sender, shuttle) in launch_data.into_iter() {
+                        for (reader, sender, shuttle, source_map) in launch_data.into_iter() {
                             let cancel_signal = cancel_signal.clone();
                             s.spawn(move |_| {
                                 if let Some(reader) = reader() {
@@ -222,6 +272,8 @@ where
                                             sender.send(rt.core.extract_result(0)).unwrap();
                                         }),
                                         cancel_signal,
+                                        loc: 0,
+                                        source_map,
                                     };
                                     main_loop_fn.invoke(&mut runtime);
                                 }
@@ -229,19 +281,22 @@ where
                         }
                         mem::drop(sender);
                         {
-                            rt.core.vars.pid = 1;
+                            rt.core.vars.set_pid(1);
                             let r = receiver.clone();
                             rt.cleanup =
                                 Cleanup::<Runtime>::new(move |_| while r.recv().is_ok() {});
                             main_loop_fn.invoke(&mut rt);
                             rt.cleanup.cancel();
                         }
-                        rt.core.vars.pid = 0;
+                        rt.core.vars.set_pid(0);
 
                         with_input!(&mut rt.input_data, |(_, read_files)| {
                             while let Ok(res) = receiver.recv() {
                                 rt.core.combine(res);
                             }
+                            if rt.core.write_files.finish_output_by_file().is_err() {
+                                return;
+                            }
                             if let Some(rc) = cancel_signal.get_code() {
                                 mem::drop(rt);
                                 std::process::exit(rc);
@@ -267,6 +322,7 @@ where
 #[derive(Default)]
 pub(crate) struct Handles {
     res: Vec<Arc<Regex>>,
+    sets: Vec<Arc<RegexSet>>,
     slices: Vec<Arc<[u8]>>,
 }
 
@@ -275,7 +331,6 @@ pub(crate) trait Backend {
     // mappings from compile::Ty to Self::Ty
     fn void_ptr_ty(&self) -> Self::Ty;
     fn ptr_to(&self, ty: Self::Ty) -> Self::Ty;
-    fn usize_ty(&self) -> Self::Ty;
     fn u32_ty(&self) -> Self::Ty;
     fn get_ty(&self, ty: compile::Ty) -> Self::Ty;
 
@@ -320,6 +375,11 @@ pub(crate) trait CodeGenerator: Backend {
         self.handles().res.push(pat);
         res
     }
+    fn const_re_set(&mut self, set: Arc<RegexSet>) -> Self::Val {
+        let res = self.const_ptr(&*set);
+        self.handles().sets.push(set);
+        res
+    }
     fn const_slice(&mut self, bs: Arc<[u8]>) -> Self::Val {
         let res = self.const_ptr(bs.as_ptr());
         self.handles().slices.push(bs);
@@ -335,6 +395,13 @@ pub(crate) trait CodeGenerator: Backend {
     /// Call an intrinsic, given a pointer to the [`intrinsics`] module and a list of arguments.
     fn call_intrinsic(&mut self, func: Op, args: &mut [Self::Val]) -> Result<Self::Val>;
 
+    /// Record that the code that follows comes from `loc` (see [`source_map::encode`]), by
+    /// storing it to the `loc` field of the runtime.
+    fn store_loc(&mut self, loc: runtime::Int) -> Result<()>;
+
+    /// Divide `l` by `r`, or take the remainder, as given by `op`.
+    fn divide(&mut self, op: Divide, l: Self::Val, r: Self::Val) -> Result<Self::Val>;
+
     /// Call an external function that does not return a value.
     ///
     /// Some backends (LLVM) are fine with returning a "value" with no content; for that case we
@@ -528,6 +595,78 @@ pub(crate) trait CodeGenerator: Backend {
         Ok(())
     }
 
+    fn map_to_json(&mut self, map: Ref, dst: &impl Accum) -> Result<()> {
+        use compile::Ty::*;
+        let func = match map.1 {
+            MapIntInt => intrinsic!(to_json_intint),
+            MapIntFloat => intrinsic!(to_json_intfloat),
+            MapIntStr => intrinsic!(to_json_intstr),
+            MapStrInt => intrinsic!(to_json_strint),
+            MapStrFloat => intrinsic!(to_json_strfloat),
+            MapStrStr => intrinsic!(to_json_strstr),
+            ty => return err!("non-map type: {:?}", ty),
+        };
+        let mapv = self.get_val(map)?;
+        let resv = self.call_intrinsic(func, &mut [mapv])?;
+        self.bind_val(dst.reflect(), resv)
+    }
+
+    fn map_join(
+        &mut self,
+        map: Ref,
+        sep: &impl Accum,
+        all: &impl Accum,
+        dst: &impl Accum,
+    ) -> Result<()> {
+        use compile::Ty::*;
+        let func = match map.1 {
+            MapIntInt => intrinsic!(join_intint),
+            MapIntFloat => intrinsic!(join_intfloat),
+            MapIntStr => intrinsic!(join_intstr),
+            MapStrInt => intrinsic!(join_strint),
+            MapStrFloat => intrinsic!(join_strfloat),
+            MapStrStr => intrinsic!(join_strstr),
+            ty => return err!("non-map type: {:?}", ty),
+        };
+        let rt = self.runtime_val();
+        let mapv = self.get_val(map)?;
+        let sepv = self.get_val(sep.reflect())?;
+        let allv = self.get_val(all.reflect())?;
+        let resv = self.call_intrinsic(func, &mut [rt, mapv, sepv, allv])?;
+        self.bind_val(dst.reflect(), resv)
+    }
+
+    /// Loads `map` from (if `read`) or saves `map` to the file named by `file`, storing 1 in `dst`
+    /// on success and 0 on failure.
+    fn persist_map(
+        &mut self,
+        read: bool,
+        map: Ref,
+        file: &impl Accum,
+        dst: &impl Accum,
+    ) -> Result<()> {
+        use compile::Ty::*;
+        let func = match (read, map.1) {
+            (true, MapIntInt) => intrinsic!(read_array_intint),
+            (true, MapIntFloat) => intrinsic!(read_array_intfloat),
+            (true, MapIntStr) => intrinsic!(read_array_intstr),
+            (true, MapStrInt) => intrinsic!(read_array_strint),
+            (true, MapStrFloat) => intrinsic!(read_array_strfloat),
+            (true, MapStrStr) => intrinsic!(read_array_strstr),
+            (false, MapIntInt) => intrinsic!(write_array_intint),
+            (false, MapIntFloat) => intrinsic!(write_array_intfloat),
+            (false, MapIntStr) => intrinsic!(write_array_intstr),
+            (false, MapStrInt) => intrinsic!(write_array_strint),
+            (false, MapStrFloat) => intrinsic!(write_array_strfloat),
+            (false, MapStrStr) => intrinsic!(write_array_strstr),
+            (_, ty) => return err!("non-map type: {:?}", ty),
+        };
+        let filev = self.get_val(file.reflect())?;
+        let mapv = self.get_val(map)?;
+        let resv = self.call_intrinsic(func, &mut [filev, mapv])?;
+        self.bind_val(dst.reflect(), resv)
+    }
+
     /// Stores `val` into `map` at key `key`.
     ///
     /// Assumes that the types of the input registers match up.
@@ -585,6 +724,29 @@ pub(crate) trait CodeGenerator: Backend {
         self.bind_val(dst.reflect(), res)
     }
 
+    /// Like `binop`, but also passes the runtime to `op`, ahead of the operands.
+    fn binop_rt(&mut self, op: Op, dst: &impl Accum, l: &impl Accum, r: &impl Accum) -> Result<()> {
+        let rt = self.runtime_val();
+        let lv = self.get_val(l.reflect())?;
+        let rv = self.get_val(r.reflect())?;
+        let res = self.call_intrinsic(op, &mut [rt, lv, rv])?;
+        self.bind_val(dst.reflect(), res)
+    }
+
+    /// Wraps `divide` in the same way as `binop`.
+    fn divide_op(
+        &mut self,
+        op: Divide,
+        dst: &impl Accum,
+        l: &impl Accum,
+        r: &impl Accum,
+    ) -> Result<()> {
+        let lv = self.get_val(l.reflect())?;
+        let rv = self.get_val(r.reflect())?;
+        let res = self.divide(op, lv, rv)?;
+        self.bind_val(dst.reflect(), res)
+    }
+
     /// Wraps `call_intrinsic` for [`Op`]s that have one argument and return a value.
     fn unop(&mut self, op: Op, dst: &impl Accum, x: &impl Accum) -> Result<()> {
         let xv = self.get_val(x.reflect())?;
@@ -622,9 +784,9 @@ pub(crate) trait CodeGenerator: Backend {
             MinusFloat(res, l, r) => self.binop(op(Arith::Minus, true), res, l, r),
             MulInt(res, l, r) => self.binop(op(Arith::Mul, false), res, l, r),
             MulFloat(res, l, r) => self.binop(op(Arith::Mul, true), res, l, r),
-            ModInt(res, l, r) => self.binop(op(Arith::Mod, false), res, l, r),
-            ModFloat(res, l, r) => self.binop(op(Arith::Mod, true), res, l, r),
-            Div(res, l, r) => self.binop(Op::Div, res, l, r),
+            ModInt(res, l, r) => self.divide_op(Divide::ModInt, res, l, r),
+            ModFloat(res, l, r) => self.divide_op(Divide::ModFloat, res, l, r),
+            Div(res, l, r) => self.divide_op(Divide::Div, res, l, r),
             Pow(res, l, r) => self.binop(Op::Pow, res, l, r),
             Not(res, ir) => {
                 let iv = self.get_val(ir.reflect())?;
@@ -681,6 +843,13 @@ pub(crate) trait CodeGenerator: Backend {
                 let res = self.call_intrinsic(intrinsic!(starts_with_const), &mut [s, ptr, len])?;
                 self.bind_val(dst.reflect(), res)
             }
+            ContainsConst(dst, s, bs) => {
+                let s = self.get_val(s.reflect())?;
+                let ptr = self.const_slice(bs.clone());
+                let len = self.const_int(bs.len() as i64);
+                let res = self.call_intrinsic(intrinsic!(contains_const), &mut [s, ptr, len])?;
+                self.bind_val(dst.reflect(), res)
+            }
             Match(dst, l, r) => {
                 let lv = self.get_val(l.reflect())?;
                 let rv = self.get_val(r.reflect())?;
@@ -688,6 +857,18 @@ pub(crate) trait CodeGenerator: Backend {
                 let res = self.call_intrinsic(intrinsic!(match_pat_loc), &mut [rt, lv, rv])?;
                 self.bind_val(dst.reflect(), res)
             }
+            MatchArr(dst, l, r, arr, subsep) => {
+                let lv = self.get_val(l.reflect())?;
+                let rv = self.get_val(r.reflect())?;
+                let arrv = self.get_val(arr.reflect())?;
+                let subsepv = self.get_val(subsep.reflect())?;
+                let rt = self.runtime_val();
+                let res = self.call_intrinsic(
+                    intrinsic!(match_pat_loc_array),
+                    &mut [rt, lv, rv, arrv, subsepv],
+                )?;
+                self.bind_val(dst.reflect(), res)
+            }
             IsMatch(dst, l, r) => {
                 let lv = self.get_val(l.reflect())?;
                 let rv = self.get_val(r.reflect())?;
@@ -703,13 +884,19 @@ pub(crate) trait CodeGenerator: Backend {
                     self.call_intrinsic(intrinsic!(match_const_pat_loc), &mut [rt, srcv, patv])?;
                 self.bind_val(res.reflect(), resv)
             }
+            MatchSet(res, src, set) => {
+                let srcv = self.get_val(src.reflect())?;
+                let setv = self.const_re_set(set.clone());
+                let resv = self.call_intrinsic(intrinsic!(match_set), &mut [srcv, setv])?;
+                self.bind_val(res.reflect(), resv)
+            }
             IsMatchConst(res, src, pat) => {
                 let srcv = self.get_val(src.reflect())?;
                 let patv = self.const_re(pat.clone());
                 let resv = self.call_intrinsic(intrinsic!(match_const_pat), &mut [srcv, patv])?;
                 self.bind_val(res.reflect(), resv)
             }
-            SubstrIndex(dst, s, t) => self.binop(intrinsic!(substr_index), dst, s, t),
+            SubstrIndex(dst, s, t) => self.binop_rt(intrinsic!(substr_index), dst, s, t),
             LenStr(dst, x) => self.unop(intrinsic!(str_len), dst, x),
             Sub(res, pat, s, in_s) => {
                 let rt = self.runtime_val();
@@ -729,6 +916,22 @@ pub(crate) trait CodeGenerator: Backend {
                     self.call_intrinsic(intrinsic!(subst_all), &mut [rt, patv, sv, in_sv])?;
                 self.bind_val(res.reflect(), resv)
             }
+            SubConst(res, pat, s, in_s) => {
+                let patv = self.const_re(pat.clone());
+                let sv = self.get_val(s.reflect())?;
+                let in_sv = self.get_val(in_s.reflect())?;
+                let resv =
+                    self.call_intrinsic(intrinsic!(subst_first_const), &mut [patv, sv, in_sv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            GSubConst(res, pat, s, in_s) => {
+                let patv = self.const_re(pat.clone());
+                let sv = self.get_val(s.reflect())?;
+                let in_sv = self.get_val(in_s.reflect())?;
+                let resv =
+                    self.call_intrinsic(intrinsic!(subst_all_const), &mut [patv, sv, in_sv])?;
+                self.bind_val(res.reflect(), resv)
+            }
             GenSubDynamic(res, pat, s, how, in_s) => {
                 let rt = self.runtime_val();
                 let patv = self.get_val(pat.reflect())?;
@@ -741,6 +944,8 @@ pub(crate) trait CodeGenerator: Backend {
             }
             EscapeCSV(dst, s) => self.unop(intrinsic!(escape_csv), dst, s),
             EscapeTSV(dst, s) => self.unop(intrinsic!(escape_tsv), dst, s),
+            EscapeJSON(dst, s) => self.unop(intrinsic!(escape_json), dst, s),
+            FloatToJSON(dst, f) => self.unop(intrinsic!(float_to_json), dst, f),
             Substr(res, base, l, r) => {
                 let basev = self.get_val(base.reflect())?;
                 let lv = self.get_val(l.reflect())?;
@@ -758,11 +963,23 @@ pub(crate) trait CodeGenerator: Backend {
             LTEFloat(res, l, r) => self.binop(cmp(Cmp::Lte, true), res, l, r),
             GTEFloat(res, l, r) => self.binop(cmp(Cmp::Gte, true), res, l, r),
             EQFloat(res, l, r) => self.binop(cmp(Cmp::Eq, true), res, l, r),
-            LTStr(res, l, r) => self.binop(intrinsic!(str_lt), res, l, r),
-            GTStr(res, l, r) => self.binop(intrinsic!(str_gt), res, l, r),
-            LTEStr(res, l, r) => self.binop(intrinsic!(str_lte), res, l, r),
-            GTEStr(res, l, r) => self.binop(intrinsic!(str_gte), res, l, r),
-            EQStr(res, l, r) => self.binop(intrinsic!(str_eq), res, l, r),
+            LTStr(res, l, r) => self.binop_rt(intrinsic!(str_lt), res, l, r),
+            GTStr(res, l, r) => self.binop_rt(intrinsic!(str_gt), res, l, r),
+            LTEStr(res, l, r) => self.binop_rt(intrinsic!(str_lte), res, l, r),
+            GTEStr(res, l, r) => self.binop_rt(intrinsic!(str_gte), res, l, r),
+            EQStr(res, l, r) => self.binop_rt(intrinsic!(str_eq), res, l, r),
+            StrNumCmp(op, res, l, r) => {
+                use bytecode::Cmp::*;
+                let op = match op {
+                    Lt => intrinsic!(str_num_lt),
+                    Gt => intrinsic!(str_num_gt),
+                    Lte => intrinsic!(str_num_lte),
+                    Gte => intrinsic!(str_num_gte),
+                    Eq => intrinsic!(str_num_eq),
+                    Neq => intrinsic!(str_num_neq),
+                };
+                self.binop_rt(op, res, l, r)
+            }
             SetColumn(dst, src) => {
                 let rt = self.runtime_val();
                 let srcv = self.get_val(src.reflect())?;
@@ -790,6 +1007,13 @@ pub(crate) trait CodeGenerator: Backend {
                 let resv = self.call_intrinsic(intrinsic!(join_tsv), &mut [rt, startv, endv])?;
                 self.bind_val(dst.reflect(), resv)
             }
+            JoinJSON(dst, start, end) => {
+                let rt = self.runtime_val();
+                let startv = self.get_val(start.reflect())?;
+                let endv = self.get_val(end.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(join_json), &mut [rt, startv, endv])?;
+                self.bind_val(dst.reflect(), resv)
+            }
             JoinColumns(dst, start, end, sep) => {
                 let rt = self.runtime_val();
                 let startv = self.get_val(start.reflect())?;
@@ -817,16 +1041,34 @@ pub(crate) trait CodeGenerator: Backend {
                     self.call_intrinsic(intrinsic!(split_str), &mut [rt, tsv, arrv, patv])?;
                 self.bind_val(flds.reflect(), fldsv)
             }
-            Printf { output, fmt, args } => self.printf(output, fmt, &args[..]),
+            Printf { output, fmt, args } => {
+                self.printf(&output.as_deref().cloned(), fmt, &args[..])
+            }
             Sprintf { dst, fmt, args } => self.sprintf(dst, fmt, &args[..]),
-            PrintAll { output, args } => self.print_all(output, &args[..]),
-            Close(file) => {
+            PrintAll { output, args } => self.print_all(&output.as_deref().cloned(), &args[..]),
+            Close(dst, file) => {
                 let rt = self.runtime_val();
                 let filev = self.get_val(file.reflect())?;
-                self.call_void(external!(close_file), &mut [rt, filev])?;
-                Ok(())
+                let resv = self.call_intrinsic(intrinsic!(close_file), &mut [rt, filev])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            Flush(dst, file) => {
+                let rt = self.runtime_val();
+                let filev = self.get_val(file.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(flush_file), &mut [rt, filev])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            FlushAll(dst) => {
+                let rt = self.runtime_val();
+                let resv = self.call_intrinsic(intrinsic!(flush_all), &mut [rt])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            RunCmd(dst, cmd) => {
+                let rt = self.runtime_val();
+                let cmdv = self.get_val(cmd.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(run_system), &mut [rt, cmdv])?;
+                self.bind_val(dst.reflect(), resv)
             }
-            RunCmd(dst, cmd) => self.unop(intrinsic!(run_system), dst, cmd),
             Exit(code) => {
                 let rt = self.runtime_val();
                 let codev = self.get_val(code.reflect())?;
@@ -893,6 +1135,25 @@ pub(crate) trait CodeGenerator: Backend {
                 (*key, map_ty.key()?),
                 (*dst, map_ty.val()?),
             ),
+            LookupStr {
+                map_ty,
+                dst,
+                map,
+                key,
+            } => {
+                use compile::Ty::*;
+                let func = match map_ty {
+                    MapIntInt => intrinsic!(lookup_str_intint),
+                    MapIntFloat => intrinsic!(lookup_str_intfloat),
+                    MapStrInt => intrinsic!(lookup_str_strint),
+                    MapStrFloat => intrinsic!(lookup_str_strfloat),
+                    ty => return err!("string lookup for map of type {:?}", ty),
+                };
+                let mapv = self.get_val((*map, *map_ty))?;
+                let keyv = self.get_val((*key, map_ty.key()?))?;
+                let resv = self.call_intrinsic(func, &mut [mapv, keyv])?;
+                self.bind_val(dst.reflect(), resv)
+            }
             Contains {
                 map_ty,
                 dst,
@@ -906,6 +1167,26 @@ pub(crate) trait CodeGenerator: Backend {
             Delete { map_ty, map, key } => self.delete_map((*map, *map_ty), (*key, map_ty.key()?)),
             Clear { map_ty, map } => self.clear_map((*map, *map_ty)),
             Len { map_ty, map, dst } => self.len_map((*map, *map_ty), (*dst, compile::Ty::Int)),
+            ToJSON { map_ty, dst, map } => self.map_to_json((*map, *map_ty), dst),
+            JoinArray {
+                map_ty,
+                dst,
+                map,
+                sep,
+                all,
+            } => self.map_join((*map, *map_ty), sep, all, dst),
+            ReadArray {
+                map_ty,
+                dst,
+                file,
+                map,
+            } => self.persist_map(true, (*map, *map_ty), file, dst),
+            WriteArray {
+                map_ty,
+                dst,
+                file,
+                map,
+            } => self.persist_map(false, (*map, *map_ty), file, dst),
             Store {
                 map_ty,
                 map,
@@ -1011,9 +1292,24 @@ pub(crate) trait CodeGenerator: Backend {
             AllocMap(_, _) => {
                 err!("unexpected AllocMap (allocs are handled differently in LLVM)")
             }
+            GetColumnFloat(..)
+            | JmpIfInt(..)
+            | JmpIfFloat(..)
+            | JmpIfConstMatch(..)
+            | AddIntImm(..)
+            | EQStrConst(..)
+            | LookupConstKey { .. }
+            | StoreConstKey { .. }
+            | IncIntConstKey { .. }
+            | IncFloatConstKey { .. } => {
+                err!("unexpected interpreter superinstruction")
+            }
             Ret | Jmp(_) | JmpIf(_, _) | Call(_) => {
                 err!("unexpected bytecode-level control flow")
             }
+            ProfileRule(_) => err!("rule profiling requires the bytecode interpreter"),
+            Loc(loc) => self.store_loc(source_map::encode(*loc)),
+            CallNative { .. } => err!("native functions require the bytecode interpreter"),
         }
     }
 }
@@ -47,6 +47,19 @@ impl ExecutionStrategy {
     }
 }
 
+/// The order in which output to stdout is written when worker threads each process a file at a
+/// time.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputOrder {
+    /// Write output as it is produced; lines printed for different files may be interleaved.
+    #[default]
+    Any,
+    /// Write the output for each input file together, once the file has been processed.
+    File,
+    /// Write the output for each input file together, in the order the files were given.
+    Input,
+}
+
 #[derive(Debug, Clone)]
 pub enum Stage<T> {
     Main(T),
@@ -157,23 +170,6 @@ where
     }
 }
 
-pub(crate) struct IntoIter<L, R>(pub Either<L, R>);
-
-impl<L, R, T> IntoIterator for IntoIter<L, R>
-where
-    L: IntoIterator<Item = T>,
-    R: IntoIterator<Item = T>,
-{
-    type Item = T;
-    type IntoIter = Either<L::IntoIter, R::IntoIter>;
-    fn into_iter(self) -> Self::IntoIter {
-        match self.0 {
-            Either::Left(l) => Either::Left(l.into_iter()),
-            Either::Right(r) => Either::Right(r.into_iter()),
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct CompileError(pub String);
 
@@ -388,6 +384,16 @@ impl<Arg> Drop for Cleanup<Arg> {
     }
 }
 
+/// How the values that the workers of a parallel program compute for a variable are combined
+/// before END runs. Maps are combined entry by entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Aggregation {
+    Sum,
+    Min,
+    Max,
+    Concat,
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(i64)]
 pub enum FileSpec {
@@ -421,6 +427,7 @@ impl std::convert::TryFrom<i64> for FileSpec {
     }
 }
 
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
 pub(crate) fn traverse<T>(o: Option<Result<T>>) -> Result<Option<T>> {
     match o {
         Some(e) => Ok(Some(e?)),
@@ -1,21 +1,32 @@
-use crate::builtins;
-use crate::bytecode;
+use crate::ast;
+use crate::builtins::{self, Variable};
+use crate::bytecode::{self, Accum};
 use crate::cfg::{self, is_unused, Function, Ident, PrimExpr, PrimStmt, PrimVal, ProgramContext};
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
 use crate::codegen;
 #[cfg(feature = "llvm_backend")]
 use crate::codegen::llvm;
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
+use crate::common::CancelSignal;
 use crate::common::{
-    CancelSignal, CompileError, Either, Graph, NodeIx, NumTy, Result, Stage, WorkList,
+    Aggregation, CompileError, Either, Graph, NodeIx, NumTy, Result, Stage, WorkList,
 };
 use crate::cross_stage;
+use crate::image;
 use crate::input_taint::TaintedStringAnalysis;
+use crate::lexer::SourceLoc;
+use crate::peephole;
 use crate::pushdown::{FieldSet, UsedFieldAnalysis};
+use crate::regalloc;
 use crate::runtime::{self, Str};
+use crate::source_map::SourceMap;
 use crate::string_constants::{self, StringConstantAnalysis};
+use crate::strnum::StrNumAnalysis;
+use crate::timing::Timing;
 use crate::types;
 
 use hashbrown::{hash_map::Entry, HashMap, HashSet};
-use regex::bytes::Regex;
+use regex::bytes::{Regex, RegexSet};
 use smallvec::smallvec;
 
 use std::collections::VecDeque;
@@ -157,7 +168,59 @@ pub(crate) fn bytecode<'a, LR: runtime::LineReader>(
     ff: impl runtime::writers::FileFactory,
     num_workers: usize,
 ) -> Result<bytecode::Interp<'a, LR>> {
-    Typer::init_from_ctx(ctx)?.to_interp(reader, ff, num_workers)
+    image(ctx)?.into_interp(reader, ff, num_workers)
+}
+
+/// Compile the program to bytecode without starting an interpreter, e.g. to save it to disk.
+pub(crate) fn image<'a>(ctx: &mut cfg::ProgramContext<'a, &'a str>) -> Result<image::Image<'a>> {
+    image_timed(ctx, &mut Timing::default())
+}
+
+/// Like `image`, but charge the time spent in type inference and bytecode generation to phases of
+/// `timing`.
+pub(crate) fn image_timed<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+    timing: &mut Timing,
+) -> Result<image::Image<'a>> {
+    let sep_assign = ctx.analyze_sep_assignments();
+    let rules = ctx.rules.clone();
+    let type_info = types::get_types(ctx)?;
+    timing.end_phase("type inference");
+    let image = Typer::init_from_types(ctx, type_info)?.to_image(sep_assign, rules, &mut [])?;
+    timing.end_phase("bytecode generation");
+    Ok(image)
+}
+
+/// The name of a global variable, and the register holding it.
+pub(crate) type GlobalReg<'a> = (&'a str, NumTy, Ty);
+
+/// Like `image`, but keep the final value of each of the program's global variables in a register,
+/// returning the name, register and type of each of them. The program should be parsed with
+/// `export_globals` set; otherwise globals only used in the main function are renamed like locals
+/// and cannot be found.
+pub(crate) fn image_with_globals<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+) -> Result<(image::Image<'a>, Vec<GlobalReg<'a>>)> {
+    let sep_assign = ctx.analyze_sep_assignments();
+    let rules = ctx.rules.clone();
+    let type_info = types::get_types(ctx)?;
+    let names: Vec<_> = ctx.globals().map(|(name, id)| (*name, id)).collect();
+    let mut typer = Typer::init_from_types(ctx, type_info)?;
+    let (names, mut regs): (Vec<_>, Vec<_>) = names
+        .into_iter()
+        .filter_map(|(name, id)| {
+            let (reg, ty) = *typer.regs.globals.get(&id)?;
+            let exported = ty.is_array() || matches!(ty, Ty::Int | Ty::Float | Ty::Str);
+            exported.then_some((name, (reg, ty)))
+        })
+        .unzip();
+    let image = typer.to_image(sep_assign, rules, &mut regs)?;
+    let globals = names
+        .into_iter()
+        .zip(regs)
+        .map(|(name, (reg, ty))| (name, reg, ty))
+        .collect();
+    Ok((image, globals))
 }
 
 #[cfg(test)]
@@ -206,9 +269,14 @@ pub(crate) fn run_llvm<'a>(
     cancel_signal: CancelSignal,
 ) -> Result<()> {
     use llvm::Generator;
+    let rules = ctx.rules.clone();
     let mut typer = Typer::init_from_ctx(ctx)?;
     let used_fields = typer.used_fields.clone();
     let named_cols = typer.named_columns.take();
+    let named_cols = named_cols
+        .as_ref()
+        .map(|cs| cs.iter().map(|c| &c[..]).collect());
+    let slot_aggs = typer.slot_aggs.iter().cloned().collect();
     unsafe {
         let gen = Generator::init(&mut typer, cfg)?;
         codegen::run_main(
@@ -217,12 +285,18 @@ pub(crate) fn run_llvm<'a>(
             ff,
             &used_fields,
             named_cols,
+            slot_aggs,
             cfg.num_workers,
+            cfg.output_order,
+            cfg.ieee_division,
+            cfg.key_order,
+            SourceMap::from_rules(rules),
             cancel_signal,
         )
     }
 }
 
+#[cfg(feature = "cranelift_backend")]
 pub(crate) fn run_cranelift<'a>(
     ctx: &mut cfg::ProgramContext<'a, &'a str>,
     reader: impl codegen::intrinsics::IntoRuntime,
@@ -231,9 +305,14 @@ pub(crate) fn run_cranelift<'a>(
     cancel_signal: CancelSignal,
 ) -> Result<()> {
     use codegen::clif::Generator;
+    let rules = ctx.rules.clone();
     let mut typer = Typer::init_from_ctx(ctx)?;
     let used_fields = typer.used_fields.clone();
     let named_cols = typer.named_columns.take();
+    let named_cols = named_cols
+        .as_ref()
+        .map(|cs| cs.iter().map(|c| &c[..]).collect());
+    let slot_aggs = typer.slot_aggs.iter().cloned().collect();
     unsafe {
         let gen = Generator::init(&mut typer, cfg)?;
         codegen::run_main(
@@ -242,7 +321,12 @@ pub(crate) fn run_cranelift<'a>(
             ff,
             &used_fields,
             named_cols,
+            slot_aggs,
             cfg.num_workers,
+            cfg.output_order,
+            cfg.ieee_division,
+            cfg.key_order,
+            SourceMap::from_rules(rules),
             cancel_signal,
         )
     }
@@ -296,10 +380,6 @@ impl RegStatuses {
         res as NumTy
     }
 
-    fn count(&self, ty: Ty) -> NumTy {
-        self.0[ty as usize].len() as NumTy
-    }
-
     fn get_status(&self, reg: NumTy, ty: Ty) -> RegStatus {
         if ty == Ty::Null {
             return RegStatus::Local;
@@ -350,12 +430,15 @@ pub(crate) struct Typer<'a> {
     // For projection pushdown
     used_fields: FieldSet,
     // The fields referenced by name via the FI builtin variable
-    named_columns: Option<Vec<&'a [u8]>>,
+    named_columns: Option<Vec<Arc<[u8]>>>,
     // For rejecting suspcicious programs with commands.
     taint_analysis: Option<TaintedStringAnalysis>,
     // For analysis passes that introspect into the set of constant string values that will
     // dynamically be assigned to a register
     string_constants: Option<StringConstantAnalysis<'a>>,
+    // Set if the program assigns to IGNORECASE. Constant patterns are then left for the runtime
+    // to compile, as only it knows whether they should match without regard to case.
+    may_ignore_case: bool,
     // Not used for bytecode generation.
     callgraph: Graph<HashSet<(NumTy, Ty)>, ()>,
 
@@ -364,6 +447,11 @@ pub(crate) struct Typer<'a> {
     // variables in the LLVM backend. It is computed lazily because these are not needed for
     // serial, bytecode-only scripts.
     global_refs: Option<Vec<HashSet<(NumTy, Ty)>>>,
+
+    // How the values that the workers of a parallel program store in each slot are combined, for
+    // the slots of variables that the main loop only accumulates into. Sorted, so that images
+    // are deterministic.
+    pub(crate) slot_aggs: Vec<((Ty, usize), Aggregation)>,
 }
 
 #[derive(Default)]
@@ -402,6 +490,8 @@ pub(crate) struct Frame<'a> {
     pub arg_regs: SmallVec<NumTy>,
     pub cfg: Cfg<'a>,
     pub is_called: bool,
+    // The location of the statements being lowered, from the last `PrimStmt::Loc`.
+    loc: Option<SourceLoc>,
 }
 
 impl<'a> Frame<'a> {
@@ -443,6 +533,8 @@ struct View<'a, 'b> {
     local_globals: &'b HashSet<NumTy>,
     arity: &'b HashMap<NumTy, NumTy>,
     func_info: &'b Vec<FuncInfo>,
+    // See `Typer::may_ignore_case`.
+    may_ignore_case: bool,
     // The current basic block being filled; It'll be swapped into `frame.cfg` as we translate a
     // given function cfg.
     stream: &'b mut Node<'a>,
@@ -522,24 +614,28 @@ impl<'a> Typer<'a> {
     }
 
     #[allow(clippy::wrong_self_convention)]
-    fn to_interp<LR: runtime::LineReader>(
+    /// Generate and optimize bytecode for the program. The registers in `keep` are preserved, and
+    /// replaced with their new names.
+    fn to_image(
         &mut self,
-        reader: LR,
-        ff: impl runtime::writers::FileFactory,
-        num_workers: usize,
-    ) -> Result<bytecode::Interp<'a, LR>> {
-        let instrs = self.to_bytecode()?;
-        let cols = self.named_columns.take();
-        Ok(bytecode::Interp::new(
+        sep_assign: cfg::SepAssign<'a>,
+        rules: Vec<(SourceLoc, ast::Rule<&'a str>)>,
+        keep: &mut [(NumTy, Ty)],
+    ) -> Result<image::Image<'a>> {
+        let mut instrs = self.to_bytecode()?;
+        peephole::optimize(&mut instrs, keep);
+        let regs = regalloc::allocate(&mut instrs, keep);
+        let source_map = SourceMap::strip(&mut instrs, rules);
+        Ok(image::Image {
             instrs,
-            self.stage(),
-            num_workers,
-            |ty| self.regs.stats.count(ty) as usize,
-            reader,
-            ff,
-            &self.used_fields,
-            cols,
-        ))
+            source_map,
+            stage: self.stage(),
+            regs,
+            used_fields: self.used_fields.clone(),
+            named_columns: self.named_columns.take(),
+            sep_assign,
+            slot_aggs: mem::take(&mut self.slot_aggs),
+        })
     }
 
     // At initialization time, we generate Either<LL, HL>, this function lowers the HL into LL.
@@ -710,23 +806,38 @@ impl<'a> Typer<'a> {
         Ok(res)
     }
 
+    #[cfg(any(test, feature = "llvm_backend", feature = "cranelift_backend"))]
     fn init_from_ctx(pc: &mut ProgramContext<'a, &'a str>) -> Result<Typer<'a>> {
-        // Type-check the code, then initialize a Typer, assigning registers to local
-        // and global variables.
+        let type_info = types::get_types(pc)?;
+        Typer::init_from_types(pc, type_info)
+    }
+
+    fn init_from_types(
+        pc: &mut ProgramContext<'a, &'a str>,
+        types::TypeInfo { var_tys, func_tys }: types::TypeInfo,
+    ) -> Result<Typer<'a>> {
+        // Given the types of the program's variables and functions, initialize a Typer, assigning
+        // registers to local and global variables.
 
         let mut gen = Typer::default();
         if !pc.allow_arbitrary_commands {
             gen.taint_analysis = Some(Default::default());
         }
-        if pc.fold_regex_constants || pc.parse_header {
+        gen.may_ignore_case = pc.funcs.iter().any(|f| {
+            f.cfg.node_weights().any(|bb| {
+                bb.q.iter()
+                    .any(|s| matches!(s, PrimStmt::SetBuiltin(Variable::IGNORECASE, _)))
+            })
+        });
+        let fold_regexes = pc.fold_regex_constants && !gen.may_ignore_case;
+        if fold_regexes || pc.parse_header {
             gen.string_constants = Some(StringConstantAnalysis::from_config(
                 string_constants::Config {
-                    query_regex: pc.fold_regex_constants,
+                    query_regex: fold_regexes,
                     fi_refs: pc.parse_header,
                 },
             ));
         }
-        let types::TypeInfo { var_tys, func_tys } = types::get_types(pc)?;
         let local_globals = pc.local_globals();
         macro_rules! init_entry {
             ($v:expr, $func_id:expr, $args:expr) => {
@@ -797,32 +908,189 @@ impl<'a> Typer<'a> {
                 arity: &gen.arity,
                 local_globals: &gen.local_globals,
                 func_info: &gen.func_info,
+                may_ignore_case: gen.may_ignore_case,
                 stream: &mut stream,
             }
             .process_function(&pc.funcs[src_func])?;
         }
+        gen.fuse_string_lookups();
         // TODO: mark used frames first and then exclude them from the analyses?
         gen.run_analyses()?;
         gen.mark_used_frames();
-        gen.add_slots()?;
+        gen.add_slots(&pc.aggregations)?;
         Ok(gen)
     }
 
+    /// Replace lookups into maps with numeric values that are only ever converted to a string with
+    /// `LookupStr`, so that missing elements read as "" rather than "0".
+    fn fuse_string_lookups(&mut self) {
+        fn visit_regs(stmt: &Instr, mut f: impl FnMut(NumTy, Ty)) {
+            match stmt {
+                Either::Left(ll) => ll.accum(f),
+                Either::Right(HighLevel::Call {
+                    dst_reg,
+                    dst_ty,
+                    args,
+                    ..
+                }) => {
+                    f(*dst_reg, *dst_ty);
+                    for (reg, ty) in args.iter() {
+                        f(*reg, *ty)
+                    }
+                }
+                Either::Right(HighLevel::Phi(reg, ty, preds)) => {
+                    f(*reg, *ty);
+                    for (_, pred) in preds.iter() {
+                        f(*pred, *ty)
+                    }
+                }
+                Either::Right(HighLevel::Ret(reg, ty))
+                | Either::Right(HighLevel::DropIter(reg, ty)) => f(*reg, *ty),
+            }
+        }
+        let mut uses = HashMap::<(NumTy, Ty), usize>::new();
+        for frame in self.frames.iter() {
+            for bb in frame.cfg.raw_nodes() {
+                for stmt in bb.weight.insts.iter() {
+                    visit_regs(stmt, |reg, ty| *uses.entry((reg, ty)).or_insert(0) += 1);
+                }
+            }
+        }
+        for frame in self.frames.iter_mut() {
+            for bb in frame.cfg.node_weights_mut() {
+                let insts = &mut bb.insts;
+                let mut i = 0;
+                while i < insts.len() {
+                    let (map_ty, dst, map, key) = match &insts[i] {
+                        Either::Left(LL::Lookup {
+                            map_ty,
+                            dst,
+                            map,
+                            key,
+                        }) => (*map_ty, *dst, *map, *key),
+                        _ => {
+                            i += 1;
+                            continue;
+                        }
+                    };
+                    i += 1;
+                    let val = (dst, map_ty.val().unwrap());
+                    if !matches!(val.1, Ty::Int | Ty::Float) || uses.get(&val) != Some(&2) {
+                        continue;
+                    }
+                    // The other use of the value has to be a conversion later in the same block.
+                    let j = match (i..insts.len()).find(|j| {
+                        let mut found = false;
+                        visit_regs(&insts[*j], |reg, ty| found |= (reg, ty) == val);
+                        found
+                    }) {
+                        Some(j) => j,
+                        None => continue,
+                    };
+                    let s = match &insts[j] {
+                        Either::Left(LL::IntToStr(s, _)) | Either::Left(LL::FloatToStr(s, _)) => *s,
+                        _ => continue,
+                    };
+                    // Moving the conversion up is only sound if nothing in between touches its
+                    // destination.
+                    let mut touched = false;
+                    for stmt in insts.range(i..j) {
+                        visit_regs(stmt, |reg, ty| touched |= (reg, ty) == s.reflect());
+                    }
+                    if touched {
+                        continue;
+                    }
+                    insts[i - 1] = Either::Left(LL::LookupStr {
+                        map_ty,
+                        dst: s,
+                        map,
+                        key,
+                    });
+                    insts.remove(j);
+                }
+            }
+        }
+    }
+
     fn run_analyses(&mut self) -> Result<()> {
+        use bytecode::{Cmp, Reg};
+        use runtime::{Float, Int};
+        type FloatCmp = (Cmp, Reg<Int>, Reg<Float>, Reg<Float>);
+        fn float_cmp(inst: &Instr) -> Option<FloatCmp> {
+            match inst {
+                Either::Left(LL::LTFloat(dst, l, r)) => Some((Cmp::Lt, *dst, *l, *r)),
+                Either::Left(LL::GTFloat(dst, l, r)) => Some((Cmp::Gt, *dst, *l, *r)),
+                Either::Left(LL::LTEFloat(dst, l, r)) => Some((Cmp::Lte, *dst, *l, *r)),
+                Either::Left(LL::GTEFloat(dst, l, r)) => Some((Cmp::Gte, *dst, *l, *r)),
+                Either::Left(LL::EQFloat(dst, l, r)) => Some((Cmp::Eq, *dst, *l, *r)),
+                _ => None,
+            }
+        }
+        /// If `insts[stmt]` compares a string converted to a float with a number, the string and
+        /// whether it is on the left.
+        fn mixed_cmp<'a>(insts: &VecDeque<Instr<'a>>, stmt: usize) -> Option<(Reg<Str<'a>>, bool)> {
+            let (_, _, l, r) = float_cmp(&insts[stmt])?;
+            // Operands are converted just before the comparison that uses them.
+            let converted = |f: Reg<Float>| {
+                insts
+                    .range(stmt.saturating_sub(2)..stmt)
+                    .find_map(|inst| match inst {
+                        Either::Left(LL::StrToFloat(dst, s)) if *dst == f => Some(*s),
+                        _ => None,
+                    })
+            };
+            match (converted(l), converted(r)) {
+                (Some(s), None) => Some((s, true)),
+                (None, Some(s)) => Some((s, false)),
+                _ => None,
+            }
+        }
         let mut ufa = UsedFieldAnalysis::default();
+        let mut sna = StrNumAnalysis::default();
         let mut refs = SmallVec::new();
+        let mut cmps = Vec::new();
+        let mut mixed = Vec::new();
         for (fix, frame) in self.frames.iter().enumerate() {
+            for (reg, ty) in frame
+                .arg_regs
+                .iter()
+                .cloned()
+                .zip(self.func_info[fix].arg_tys.iter().cloned())
+            {
+                sna.add_arg(reg, ty);
+            }
             for (bbix, bb) in frame.cfg.raw_nodes().iter().enumerate() {
                 for (stmtix, stmt) in bb.weight.insts.iter().enumerate() {
                     // not tracking function calls
                     visit_used_fields(stmt, frame.cur_ident, &mut ufa);
+                    match stmt {
+                        Either::Left(ll) => {
+                            if let LL::LTStr(_, l, r)
+                            | LL::GTStr(_, l, r)
+                            | LL::LTEStr(_, l, r)
+                            | LL::GTEStr(_, l, r)
+                            | LL::EQStr(_, l, r) = ll
+                            {
+                                sna.add_cmp(l, r);
+                                cmps.push((fix, bbix, stmtix));
+                            }
+                            if let Some((s, _)) = mixed_cmp(&bb.weight.insts, stmtix) {
+                                sna.add_num_cmp(&s);
+                                mixed.push((fix, bbix, stmtix));
+                            }
+                            sna.visit_ll(ll)
+                        }
+                        Either::Right(hl) => sna.visit_hl(frame.cur_ident, hl),
+                    }
                     if let Some(tsa) = &mut self.taint_analysis {
                         visit_taint_analysis(stmt, frame.cur_ident, tsa)
                     }
                     if let Some(sca) = &mut self.string_constants {
                         if sca.cfg().query_regex {
                             if let Either::Left(LL::IsMatch(_, _, pat))
-                            | Either::Left(LL::Match(_, _, pat)) = stmt
+                            | Either::Left(LL::Match(_, _, pat))
+                            | Either::Left(LL::Sub(_, pat, _, _))
+                            | Either::Left(LL::GSub(_, pat, _, _)) = stmt
                             {
                                 refs.push((fix, bbix, stmtix, *pat));
                             }
@@ -833,6 +1101,50 @@ impl<'a> Typer<'a> {
             }
         }
         self.used_fields = ufa.solve();
+        for (frame, bb, stmt) in cmps.into_iter() {
+            let inst = self.frames[frame]
+                .cfg
+                .node_weight_mut(NodeIx::new(bb))
+                .unwrap()
+                .insts
+                .get_mut(stmt)
+                .unwrap();
+            let (op, dst, l, r) = match inst {
+                Either::Left(LL::LTStr(dst, l, r)) => (Cmp::Lt, dst, l, r),
+                Either::Left(LL::GTStr(dst, l, r)) => (Cmp::Gt, dst, l, r),
+                Either::Left(LL::LTEStr(dst, l, r)) => (Cmp::Lte, dst, l, r),
+                Either::Left(LL::GTEStr(dst, l, r)) => (Cmp::Gte, dst, l, r),
+                Either::Left(LL::EQStr(dst, l, r)) => (Cmp::Eq, dst, l, r),
+                _ => return err!("unexpected instruction during strnum analysis: {:?}", inst),
+            };
+            if sna.is_input(&*l) && sna.is_input(&*r) {
+                *inst = Either::Left(LL::StrNumCmp(op, *dst, *l, *r));
+            }
+        }
+        // A comparison between input and a number was lowered to a float comparison, with the
+        // input converted just before it. If the input does not look like a number, the number
+        // should be converted to a string instead, which StrNumCmp does. Instructions are inserted,
+        // so go from the back of each block to the front.
+        for (frame, bb, stmt) in mixed.into_iter().rev() {
+            let insts = &mut self.frames[frame]
+                .cfg
+                .node_weight_mut(NodeIx::new(bb))
+                .unwrap()
+                .insts;
+            let (s, input_left) = mixed_cmp(insts, stmt).unwrap();
+            if !sna.is_input(&s) {
+                continue;
+            }
+            let (op, dst, l, r) = float_cmp(&insts[stmt]).unwrap();
+            let num_str: Reg<Str> = self.regs.stats.reg_of_ty(Ty::Str).into();
+            let (num, l, r) = if input_left {
+                (r, s, num_str)
+            } else {
+                (l, num_str, s)
+            };
+            insts[stmt] = Either::Left(LL::StrNumCmp(op, dst, l, r));
+            insts.insert(stmt, Either::Left(LL::FloatToStr(num_str, num)));
+        }
         if let Some(tsa) = &mut self.taint_analysis {
             if !tsa.ok() {
                 return err!(concat!(
@@ -853,9 +1165,8 @@ impl<'a> Typer<'a> {
                     if strs.len() != 1 {
                         continue;
                     }
-                    let text = std::str::from_utf8(strs[0]).map_err(|e| {
-                        CompileError(format!("regex patterns must be valid UTF-8: {}", e))
-                    })?;
+                    let text = runtime::regex_source(strs[0]);
+                    let text = &*text;
                     let re = Arc::new(Regex::new(text).map_err(|err| {
                         CompileError(format!("regex parse error during compilation: {}", err))
                     })?);
@@ -871,6 +1182,8 @@ impl<'a> Typer<'a> {
                         Either::Left(LL::IsMatch(dst, s, _)) => {
                             if let Some(bs) = extract_anchored_literal(text) {
                                 Either::Left(LL::StartsWithConst(*dst, *s, bs))
+                            } else if let Some(bs) = extract_literal(text) {
+                                Either::Left(LL::ContainsConst(*dst, *s, bs))
                             } else {
                                 Either::Left(LL::IsMatchConst(*dst, *s, re))
                             }
@@ -878,6 +1191,12 @@ impl<'a> Typer<'a> {
                         Either::Left(LL::Match(dst, s, _)) => {
                             Either::Left(LL::MatchConst(*dst, *s, re))
                         }
+                        Either::Left(LL::Sub(dst, _, s, in_s)) => {
+                            Either::Left(LL::SubConst(*dst, re, *s, *in_s))
+                        }
+                        Either::Left(LL::GSub(dst, _, s, in_s)) => {
+                            Either::Left(LL::GSubConst(*dst, re, *s, *in_s))
+                        }
                         _ => {
                             return err!(
                                 "unexpected instruction during regex constant folding: {:?}",
@@ -891,7 +1210,9 @@ impl<'a> Typer<'a> {
             if sca.cfg().fi_refs {
                 strs.clear();
                 if sca.fi_info(&mut strs) {
-                    self.named_columns = Some(strs);
+                    // Copy the names: short strings are stored inline, so `strs` can point into
+                    // instructions that move once the bytecode is optimized.
+                    self.named_columns = Some(strs.iter().map(|s| Arc::from(*s)).collect());
                 }
             }
         }
@@ -908,7 +1229,7 @@ impl<'a> Typer<'a> {
         }
     }
 
-    fn add_slots(&mut self) -> Result<()> {
+    fn add_slots(&mut self, aggregations: &HashMap<cfg::Ident, Aggregation>) -> Result<()> {
         use cross_stage::compute_slots;
         let (begin, main_loop, end) = match self.main_offset {
             Stage::Main(_) => return Ok(()),
@@ -934,6 +1255,15 @@ impl<'a> Typer<'a> {
             self.frames[off].load_slots(slots.loop_stores.iter().cloned(), &mut ctr)?;
         }
 
+        for (id, agg) in aggregations.iter() {
+            if let Some(reg) = self.regs.globals.get(id) {
+                if slots.loop_stores.contains(reg) {
+                    self.slot_aggs.push(((reg.1, ctr.get_slot(*reg)), *agg));
+                }
+            }
+        }
+        self.slot_aggs
+            .sort_by_key(|((ty, slot), _)| (*ty as u32, *slot));
         Ok(())
     }
 
@@ -1271,6 +1601,16 @@ impl<'a, 'b> View<'a, 'b> {
 
         // Determine if we will need to convert the result when storing the variable.
         let arr_val_ty = arr_ty.val()?;
+        if dst_ty == Ty::Str && matches!(arr_val_ty, Ty::Int | Ty::Float) {
+            // Converting a missing numeric value would give "0" rather than "".
+            self.pushl(LL::LookupStr {
+                map_ty: arr_ty,
+                dst: dst_reg.into(),
+                map: arr_reg,
+                key: key_reg,
+            });
+            return Ok(());
+        }
         let load_reg = if dst_ty == arr_val_ty {
             dst_reg
         } else {
@@ -1305,6 +1645,52 @@ impl<'a, 'b> View<'a, 'b> {
         use crate::ast::{Binop::*, Unop::*};
         use builtins::Function::*;
 
+        if let MatchSet = bf {
+            // The patterns of a MatchSet are always literals: ast::desugar only emits it for runs of
+            // rules with constant regex patterns.
+            if dst_reg == UNUSED {
+                return Ok(());
+            }
+            let mut lits = Vec::with_capacity(args.len().saturating_sub(1));
+            for arg in args.iter().skip(1) {
+                match arg {
+                    PrimVal::StrLit(s) => lits.push(*s),
+                    _ => return err!("match_set called with a non-constant pattern"),
+                }
+            }
+            let (src_reg, src_ty) = self.get_reg(&args[0])?;
+            if src_ty != Ty::Str {
+                return err!("match_set called on a non-string value");
+            }
+            let res_reg = if dst_ty == Ty::Int {
+                dst_reg
+            } else {
+                self.regs.stats.reg_of_ty(Ty::Int)
+            };
+            if self.may_ignore_case {
+                // Whether the patterns ignore case is only known at runtime, so match them one at
+                // a time through the regex cache, setting the bit of each one that matches.
+                self.pushl(LL::StoreConstInt(res_reg.into(), 0));
+                for (i, lit) in lits.into_iter().enumerate() {
+                    let pat_reg = self.regs.stats.reg_of_ty(Ty::Str);
+                    let matched = self.regs.stats.reg_of_ty(Ty::Int);
+                    let bit = self.regs.stats.reg_of_ty(Ty::Int);
+                    self.pushl(LL::StoreConstStr(pat_reg.into(), Str::from(lit).into()));
+                    self.pushl(LL::IsMatch(matched.into(), src_reg.into(), pat_reg.into()));
+                    self.pushl(LL::StoreConstInt(bit.into(), 1 << i));
+                    self.pushl(LL::MulInt(matched.into(), matched.into(), bit.into()));
+                    self.pushl(LL::AddInt(res_reg.into(), res_reg.into(), matched.into()));
+                }
+            } else {
+                let pats: Vec<_> = lits.into_iter().map(runtime::regex_source).collect();
+                let set = Arc::new(RegexSet::new(pats).map_err(|err| {
+                    CompileError(format!("regex parse error during compilation: {}", err))
+                })?);
+                self.pushl(LL::MatchSet(res_reg.into(), src_reg.into(), set));
+            }
+            return self.convert(dst_reg, dst_ty, res_reg, Ty::Int);
+        }
+
         // Compile the argument values
         let mut args_regs = cfg::SmallVec::with_capacity(args.len());
         let mut args_tys = cfg::SmallVec::with_capacity(args.len());
@@ -1367,6 +1753,8 @@ impl<'a, 'b> View<'a, 'b> {
         //    registers" within a function), but for now we are keeping things simple.
 
         match bf {
+            // Handled above, before the patterns are compiled into registers.
+            MatchSet => unreachable!(),
             Unop(Column) => self.pushl(LL::GetColumn(res_reg.into(), conv_regs[0].into())),
             Unop(Not) => self.pushl(if conv_tys[0] == Ty::Str {
                 LL::NotStr(res_reg.into(), conv_regs[0].into())
@@ -1412,6 +1800,21 @@ impl<'a, 'b> View<'a, 'b> {
                 }
             }
             Match => gen_op!(Match, [Str, Match]),
+            MatchArray => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                if conv_tys[2] != Ty::MapStrStr {
+                    return err!("invalid input types to match: {:?}", &conv_tys[..]);
+                }
+                self.pushl(LL::MatchArr(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                    conv_regs[2].into(),
+                    conv_regs[3].into(),
+                ))
+            }
             SubstrIndex => gen_op!(SubstrIndex, [Str, SubstrIndex]),
             Contains => {
                 if res_reg != UNUSED {
@@ -1442,6 +1845,23 @@ impl<'a, 'b> View<'a, 'b> {
                 self.pushl(LL::RunCmd(res_reg.into(), conv_regs[0].into()))
             }
             Exit => self.pushl(LL::Exit(conv_regs[0].into())),
+            Native(native) => {
+                // Native functions may have side effects, so they are called even if their result
+                // is unused.
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::CallNative {
+                    dst: (res_reg, res_ty),
+                    func: native.id,
+                    args: conv_regs
+                        .iter()
+                        .cloned()
+                        .zip(conv_tys.iter().cloned())
+                        .collect(),
+                })
+            }
+            ProfileRule => self.pushl(LL::ProfileRule(conv_regs[0].into())),
             ReadErr => {
                 if res_reg != UNUSED {
                     self.pushl(LL::ReadErr(
@@ -1523,6 +1943,28 @@ impl<'a, 'b> View<'a, 'b> {
                     self.pushl(LL::EscapeTSV(res_reg.into(), conv_regs[0].into()))
                 }
             }
+            ToJSON => {
+                if res_reg != UNUSED {
+                    self.pushl(match conv_tys[0] {
+                        // An uninitialized variable is the empty string.
+                        Ty::Null => LL::StoreConstStr(res_reg.into(), Str::from("\"\"").into()),
+                        Ty::Int => LL::IntToStr(res_reg.into(), conv_regs[0].into()),
+                        Ty::Float => LL::FloatToJSON(res_reg.into(), conv_regs[0].into()),
+                        Ty::Str => LL::EscapeJSON(res_reg.into(), conv_regs[0].into()),
+                        Ty::MapIntInt
+                        | Ty::MapIntStr
+                        | Ty::MapIntFloat
+                        | Ty::MapStrInt
+                        | Ty::MapStrStr
+                        | Ty::MapStrFloat => LL::ToJSON {
+                            map_ty: conv_tys[0],
+                            dst: res_reg.into(),
+                            map: conv_regs[0],
+                        },
+                        _ => return err!("invalid input type for to_json: {:?}", &conv_tys[..]),
+                    })
+                }
+            }
             ToUpper => {
                 if res_reg != UNUSED {
                     self.pushl(LL::ToUpperAscii(res_reg.into(), conv_regs[0].into()))
@@ -1621,6 +2063,42 @@ impl<'a, 'b> View<'a, 'b> {
                 }),
                 _ => return err!("incorrect parameter types for Delete: {:?}", &conv_tys[..]),
             },
+            IncMap if conv_tys[0].is_array() && conv_tys[0].val()? == Ty::Str => {
+                // String-valued maps (e.g. those filled by split) have no increment instruction:
+                // load the value, add to it as a float, and store it back.
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                if res_ty != Ty::Float || conv_tys[0].key()? != conv_tys[1] {
+                    return err!(
+                        "IncMap called with malformed types: {:?} => {:?}",
+                        &conv_tys[..],
+                        dst_ty
+                    );
+                }
+                let cur_str = self.regs.stats.reg_of_ty(Ty::Str);
+                let cur = self.regs.stats.reg_of_ty(Ty::Float);
+                let new_str = self.regs.stats.reg_of_ty(Ty::Str);
+                self.pushl(LL::Lookup {
+                    map_ty: conv_tys[0],
+                    dst: cur_str,
+                    map: conv_regs[0],
+                    key: conv_regs[1],
+                });
+                self.convert(cur, Ty::Float, cur_str, Ty::Str)?;
+                self.pushl(LL::AddFloat(
+                    res_reg.into(),
+                    cur.into(),
+                    conv_regs[2].into(),
+                ));
+                self.convert(new_str, Ty::Str, res_reg, Ty::Float)?;
+                self.pushl(LL::Store {
+                    map_ty: conv_tys[0],
+                    map: conv_regs[0],
+                    key: conv_regs[1],
+                    val: new_str,
+                });
+            }
             IncMap => {
                 if res_reg == UNUSED {
                     res_reg = self.regs.stats.reg_of_ty(res_ty);
@@ -1668,12 +2146,52 @@ impl<'a, 'b> View<'a, 'b> {
                     return err!("incorrect parameter types for Clear: {:?}", &conv_tys[..]);
                 }
             }
+            ReadArray | WriteArray => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                if !conv_tys[1].is_array() {
+                    return err!("incorrect parameter types for {}: {:?}", bf, &conv_tys[..]);
+                }
+                let (map_ty, dst, file, map) = (
+                    conv_tys[1],
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1],
+                );
+                self.pushl(if let ReadArray = bf {
+                    LL::ReadArray {
+                        map_ty,
+                        dst,
+                        file,
+                        map,
+                    }
+                } else {
+                    LL::WriteArray {
+                        map_ty,
+                        dst,
+                        file,
+                        map,
+                    }
+                })
+            }
             Close => {
-                self.pushl(LL::Close(conv_regs[0].into()));
-                assert_eq!(res_ty, Ty::Str);
-                if res_reg != UNUSED {
-                    self.pushl(LL::StoreConstStr(res_reg.into(), Default::default()));
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Close(res_reg.into(), conv_regs[0].into()));
+            }
+            Flush => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
                 }
+                self.pushl(LL::Flush(res_reg.into(), conv_regs[0].into()));
+            }
+            FlushAll => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::FlushAll(res_reg.into()));
             }
             JoinCSV => {
                 if res_reg != UNUSED {
@@ -1693,6 +2211,29 @@ impl<'a, 'b> View<'a, 'b> {
                     ))
                 }
             }
+            JoinJSON => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::JoinJSON(
+                        res_reg.into(),
+                        conv_regs[0].into(),
+                        conv_regs[1].into(),
+                    ))
+                }
+            }
+            JoinArray => {
+                if !conv_tys[0].is_array() {
+                    return err!("incorrect parameter types for join: {:?}", &conv_tys[..]);
+                }
+                if res_reg != UNUSED {
+                    self.pushl(LL::JoinArray {
+                        map_ty: conv_tys[0],
+                        dst: res_reg.into(),
+                        map: conv_regs[0],
+                        sep: conv_regs[1].into(),
+                        all: conv_regs[2].into(),
+                    })
+                }
+            }
             JoinCols => {
                 if res_reg != UNUSED {
                     self.pushl(LL::JoinColumns(
@@ -1755,14 +2296,14 @@ impl<'a, 'b> View<'a, 'b> {
                     self.pushl(Sprintf {
                         dst: dst_reg.into(),
                         fmt: fmt_reg.into(),
-                        args: arg_regs,
+                        args: arg_regs.into(),
                     });
                 } else {
                     let reg = self.regs.stats.reg_of_ty(Ty::Str);
                     self.pushl(Sprintf {
                         dst: reg.into(),
                         fmt: fmt_reg.into(),
-                        args: arg_regs,
+                        args: arg_regs.into(),
                     });
                     self.convert(dst_reg, dst_ty, reg, Ty::Str)?;
                 }
@@ -1809,6 +2350,9 @@ impl<'a, 'b> View<'a, 'b> {
                         args,
                     });
                 }
+                // Compiled code records locations as it runs them, so the callee leaves its own
+                // behind; see source_map.
+                self.pushl(LL::Loc(self.frame.loc));
             }
             PrimExpr::Index(arr, k) => {
                 let (arr_reg, arr_ty) = if let PrimVal::Var(arr_id) = arr {
@@ -1959,13 +2503,13 @@ impl<'a, 'b> View<'a, 'b> {
                     // monad transformers.
                     let (mut out_reg, out_ty) = self.get_reg(out)?;
                     out_reg = self.ensure_ty(out_reg, out_ty, Ty::Str)?;
-                    Some((out_reg.into(), *append))
+                    Some(Box::new((out_reg.into(), *append)))
                 } else {
                     None
                 };
                 self.pushl(PrintAll {
                     output: out_reg,
-                    args: arg_regs,
+                    args: arg_regs.into(),
                 });
             }
             PrimStmt::Printf(fmt, args, out) => {
@@ -1980,20 +2524,24 @@ impl<'a, 'b> View<'a, 'b> {
                 let out_reg = if let Some((out, append)) = out {
                     let (mut out_reg, out_ty) = self.get_reg(out)?;
                     out_reg = self.ensure_ty(out_reg, out_ty, Ty::Str)?;
-                    Some((out_reg.into(), *append))
+                    Some(Box::new((out_reg.into(), *append)))
                 } else {
                     None
                 };
                 self.pushl(Printf {
                     output: out_reg,
                     fmt: fmt_reg.into(),
-                    args: arg_regs,
+                    args: arg_regs.into(),
                 });
             }
             PrimStmt::IterDrop(v) => {
                 let (reg, ty) = self.get_reg(v)?;
                 self.pushr(HighLevel::DropIter(reg, ty))
             }
+            PrimStmt::Loc(loc) => {
+                self.frame.loc = *loc;
+                self.pushl(LL::Loc(*loc))
+            }
         };
         Ok(())
     }
@@ -2019,17 +2567,7 @@ fn extract_anchored_literal(text: &str) -> Option<Arc<[u8]>> {
             )
         {
             for ast in &asts[1..] {
-                if let Ast::Literal(l) = ast {
-                    if let Some(b) = l.byte() {
-                        bs.push(b);
-                        continue;
-                    }
-                    let cur = bs.len();
-                    bs.resize(cur + l.c.len_utf8(), 0);
-                    l.c.encode_utf8(&mut bs[cur..]);
-                } else {
-                    return None;
-                }
+                push_literal(ast, &mut bs)?;
             }
         } else {
             return None;
@@ -2039,3 +2577,35 @@ fn extract_anchored_literal(text: &str) -> Option<Arc<[u8]>> {
     }
     Some(bs.into())
 }
+
+/// If `text` is a regex matching a literal string with no anchors or metacharacters, return the
+/// bytes of that string. Such regexes can be matched with a plain substring search.
+fn extract_literal(text: &str) -> Option<Arc<[u8]>> {
+    use regex_syntax::ast::{parse, Ast, Concat};
+    let re_ast = parse::Parser::new().parse(text).ok()?;
+    let asts = match &re_ast {
+        Ast::Literal(_) => std::slice::from_ref(&re_ast),
+        Ast::Concat(Concat { asts, .. }) => &asts[..],
+        _ => return None,
+    };
+    let mut bs = Vec::new();
+    for ast in asts {
+        push_literal(ast, &mut bs)?;
+    }
+    Some(bs.into())
+}
+
+fn push_literal(ast: &regex_syntax::ast::Ast, bs: &mut Vec<u8>) -> Option<()> {
+    if let regex_syntax::ast::Ast::Literal(l) = ast {
+        if let Some(b) = l.byte() {
+            bs.push(b);
+        } else {
+            let cur = bs.len();
+            bs.resize(cur + l.c.len_utf8(), 0);
+            l.c.encode_utf8(&mut bs[cur..]);
+        }
+        Some(())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,224 @@
+//! Coverage reports, for the `--coverage` flag.
+//!
+//! Programs run for --coverage are compiled and profiled as they are for --profile, so the
+//! interpreter counts how many times each instruction runs, as well as how many times the pattern
+//! and action of each rule run. The source map says which statement each run of instructions comes
+//! from, and control enters each run at its start, so a statement is counted as running as often as
+//! the first instruction of its busiest run. Statements that compile to no instructions of their
+//! own (like `next`) are not counted, and neither are functions that are never called, which are
+//! not compiled at all; all of their lines are reported as never running.
+//!
+//! The branches of each `if` statement are found in the program text, and each arm is counted by
+//! the first statement in it. An `if` without an `else` falls through as often as its condition
+//! is false.
+use crate::ast::{FunDec, Prog, Rule, Stmt};
+use crate::lexer::SourceLoc;
+use crate::profile::Profile;
+use crate::source_map::SourceMap;
+
+use hashbrown::HashMap;
+
+use std::io::{self, Write};
+
+/// An `if` statement, along with the start of the first statement in each of its arms.
+pub(crate) struct Branch {
+    cond: SourceLoc,
+    then: Option<SourceLoc>,
+    // None if there is no else.
+    els: Option<Option<SourceLoc>>,
+}
+
+/// The parts of a program that the report needs, besides its bytecode.
+#[derive(Default)]
+pub(crate) struct Outline {
+    // The `if` statements in the program, in no particular order.
+    branches: Vec<Branch>,
+    // The statements in each function.
+    functions: Vec<Vec<SourceLoc>>,
+}
+
+pub(crate) fn outline<'a, 'b, I>(prog: &Prog<'a, 'b, I>) -> Outline {
+    let mut res = Outline::default();
+    for FunDec { body, .. } in prog.decs.iter() {
+        let mut stmts = Vec::new();
+        walk(body, &mut res.branches, &mut stmts);
+        res.functions.push(stmts);
+    }
+    for s in prog
+        .begin
+        .iter()
+        .chain(prog.prepare.iter())
+        .chain(prog.end.iter())
+        .chain(prog.pats.iter().filter_map(|(_, body)| body.as_ref()))
+    {
+        walk(s, &mut res.branches, &mut Vec::new());
+    }
+    res
+}
+
+/// The start of the first statement in `s`.
+fn first_loc<'a, 'b, I>(s: &Stmt<'a, 'b, I>) -> Option<SourceLoc> {
+    match s {
+        Stmt::Loc(loc, _) => Some(*loc),
+        Stmt::Block(stmts) => stmts.first().and_then(|s| first_loc(s)),
+        _ => None,
+    }
+}
+
+/// Add the `if` statements in `s` to `branches`, and the starts of all of its statements to
+/// `stmts`.
+fn walk<'a, 'b, I>(s: &Stmt<'a, 'b, I>, branches: &mut Vec<Branch>, stmts: &mut Vec<SourceLoc>) {
+    use Stmt::*;
+    match s {
+        StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile | Expr(_)
+        | Print(..) | Printf(..) | Return(_) => {}
+        Block(block) => block.iter().for_each(|s| walk(s, branches, stmts)),
+        If(_, t, f) => {
+            walk(t, branches, stmts);
+            if let Some(f) = f {
+                walk(f, branches, stmts);
+            }
+        }
+        For(init, _, update, body) => {
+            for s in init.iter().chain(update.iter()).chain(Some(body)) {
+                walk(s, branches, stmts);
+            }
+        }
+        DoWhile(_, body) | While(_, _, body) | ForEach(_, _, body) => walk(body, branches, stmts),
+        Loc(loc, s) => {
+            stmts.push(*loc);
+            if let If(_, t, f) = s {
+                branches.push(Branch {
+                    cond: *loc,
+                    then: first_loc(t),
+                    els: f.map(first_loc),
+                });
+            }
+            walk(s, branches, stmts);
+        }
+    }
+}
+
+/// Format `n` as a percentage of `total`.
+fn pct(n: usize, total: usize) -> String {
+    if total == 0 {
+        return "-".into();
+    }
+    format!("{:.2}%", 100.0 * n as f64 / total as f64)
+}
+
+/// Write the program `source`, with the number of times each line ran (or `#####` for lines that
+/// never did), how many records the patterns on each line matched, and how often each arm of each
+/// `if` statement was taken. A summary follows.
+pub(crate) fn report(
+    profile: &Profile,
+    source_map: &SourceMap,
+    outline: &Outline,
+    source: &str,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    // How many times each statement ran.
+    let mut stmts = HashMap::<SourceLoc, u64>::new();
+    for (locs, counts) in source_map.locs.iter().zip(profile.counts().iter()) {
+        for (start, loc) in locs.iter() {
+            if let (Some(loc), Some(n)) = (loc, counts.get(*start)) {
+                let count = stmts.entry(*loc).or_default();
+                *count = (*count).max(*n);
+            }
+        }
+    }
+    for func in outline.functions.iter() {
+        if !func.iter().any(|loc| stmts.contains_key(loc)) {
+            stmts.extend(func.iter().map(|loc| (*loc, 0)));
+        }
+    }
+    let mut lines = HashMap::<usize, u64>::new();
+    for (loc, count) in stmts.iter() {
+        let line = lines.entry(loc.line as usize).or_default();
+        *line = (*line).max(*count);
+    }
+    // Rules are counted by the line they start on, so several rules on one line count as one. This
+    // maps each line to the number of rules with patterns starting on it.
+    let mut pattern_lines = HashMap::<usize, usize>::new();
+    for (loc, rule) in source_map.rules.iter() {
+        if let Rule::Pattern = rule {
+            *pattern_lines.entry(loc.line as usize).or_default() += 1;
+        }
+    }
+    let mut branches: Vec<&Branch> = outline.branches.iter().collect();
+    branches.sort_by_key(|b| b.cond);
+
+    let (mut lines_run, mut patterns_matched, mut arms_taken, mut arms) = (0, 0, 0, 0);
+    let mut branches = branches.into_iter().peekable();
+    let rule_count = |rule: usize| profile.rule_count(rule as i64).unwrap_or(0);
+    for (i, text) in source.trim_end_matches('\n').lines().enumerate() {
+        match lines.get(&i) {
+            Some(0) => writeln!(w, "{:>9}:{:>5}:{}", "#####", i + 1, text)?,
+            Some(n) => {
+                lines_run += 1;
+                writeln!(w, "{:>9}:{:>5}:{}", n, i + 1, text)?
+            }
+            None => writeln!(w, "{:>9}:{:>5}:{}", "-", i + 1, text)?,
+        }
+        if let Some(rules) = pattern_lines.get(&i) {
+            let (tested, matched) = (rule_count(2 * i), rule_count(2 * i + 1));
+            patterns_matched += (matched > 0) as usize;
+            match rules {
+                1 => writeln!(w, "pattern matched {} of {} records", matched, tested)?,
+                n => writeln!(w, "{} patterns matched {} of {} times", n, matched, tested)?,
+            }
+        }
+        while let Some(b) = branches.next_if(|b| b.cond.line as usize == i) {
+            // Arms without code of their own can't be counted.
+            let count = |loc: Option<SourceLoc>| loc.and_then(|loc| stmts.get(&loc)).cloned();
+            let (total, then) = match (stmts.get(&b.cond), count(b.then)) {
+                (Some(total), Some(then)) => (*total, then),
+                _ => continue,
+            };
+            let els = match b.els {
+                Some(els) => count(els),
+                None => Some(total.saturating_sub(then)),
+            };
+            let name = if b.els.is_some() { "else" } else { "not taken" };
+            arms += 1;
+            arms_taken += (then > 0) as usize;
+            match els {
+                Some(els) => {
+                    arms += 1;
+                    arms_taken += (els > 0) as usize;
+                    writeln!(
+                        w,
+                        "branch at column {}: then {}, {} {}",
+                        b.cond.col + 1,
+                        then,
+                        name,
+                        els
+                    )?;
+                }
+                None => writeln!(w, "branch at column {}: then {}", b.cond.col + 1, then)?,
+            }
+        }
+    }
+    let pattern_rules = pattern_lines.len();
+    writeln!(
+        w,
+        "\nlines executed: {} of {} ({})",
+        lines_run,
+        lines.len(),
+        pct(lines_run, lines.len())
+    )?;
+    writeln!(
+        w,
+        "patterns matched: {} of {} ({})",
+        patterns_matched,
+        pattern_rules,
+        pct(patterns_matched, pattern_rules)
+    )?;
+    writeln!(
+        w,
+        "branches taken: {} of {} ({})",
+        arms_taken,
+        arms,
+        pct(arms_taken, arms)
+    )
+}
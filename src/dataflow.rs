@@ -238,29 +238,48 @@ pub(crate) mod boilerplate {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
             }
-            StartsWithConst(dst, x, _) => f(dst.into(), Some(x.into())),
+            StartsWithConst(dst, x, _) | ContainsConst(dst, x, _) | EQStrConst(dst, x, _) => {
+                f(dst.into(), Some(x.into()))
+            }
+            AddIntImm(dst, x, _) => f(dst.into(), Some(x.into())),
 
             // NB: this assumes that regexes that have been constant-folded are not tainted by
             // user-input. That is certainly true today, but any kind of dynamic simplification or
             // inlining could change that.
-            MatchConst(dst, x, _) | IsMatchConst(dst, x, _) => f(dst.into(), Some(x.into())),
+            MatchConst(dst, x, _) | IsMatchConst(dst, x, _) | MatchSet(dst, x, _) => {
+                f(dst.into(), Some(x.into()))
+            }
             IsMatch(dst, x, y) | Match(dst, x, y) | SubstrIndex(dst, x, y) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
             }
+            MatchArr(dst1, src1, src2, dst2, subsep) => {
+                f(dst1.into(), Some(src1.into()));
+                f(dst1.into(), Some(src2.into()));
+                f(dst2.into(), Some(src1.into()));
+                f(dst2.into(), Some(src2.into()));
+                f(dst2.into(), Some(subsep.into()));
+            }
             GSub(dst, x, y, dstin) | Sub(dst, x, y, dstin) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
                 f(dstin.into(), Some(x.into()));
                 f(dstin.into(), Some(y.into()));
             }
+            GSubConst(dst, _, y, dstin) | SubConst(dst, _, y, dstin) => {
+                f(dst.into(), Some(y.into()));
+                f(dstin.into(), Some(y.into()));
+            }
             GenSubDynamic(dst, pat, s, how, in_s) => {
                 f(dst.into(), Some(pat.into()));
                 f(dst.into(), Some(s.into()));
                 f(dst.into(), Some(how.into()));
                 f(dst.into(), Some(in_s.into()));
             }
-            EscapeTSV(dst, src) | EscapeCSV(dst, src) => f(dst.into(), Some(src.into())),
+            EscapeTSV(dst, src) | EscapeCSV(dst, src) | EscapeJSON(dst, src) => {
+                f(dst.into(), Some(src.into()))
+            }
+            FloatToJSON(dst, src) => f(dst.into(), Some(src.into())),
             Substr(dst, x, y, z) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
@@ -286,12 +305,14 @@ pub(crate) mod boilerplate {
             | GTStr(dst, x, y)
             | LTEStr(dst, x, y)
             | GTEStr(dst, x, y)
-            | EQStr(dst, x, y) => {
+            | EQStr(dst, x, y)
+            | StrNumCmp(_, dst, x, y) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
             }
             GetColumn(dst, _) => f(dst.into(), None),
-            JoinTSV(dst, start, end) | JoinCSV(dst, start, end) => {
+            GetColumnFloat(dst, _) => f(dst.into(), None),
+            JoinTSV(dst, start, end) | JoinCSV(dst, start, end) | JoinJSON(dst, start, end) => {
                 f(dst.into(), Some(start.into()));
                 f(dst.into(), Some(end.into()));
             }
@@ -304,6 +325,8 @@ pub(crate) mod boilerplate {
                 f(dst.into(), Some(src.into()));
             }
             ReadErr(dst, _cmd, _) => f(dst.into(), None),
+            Close(dst, _) | Flush(dst, _) => f(dst.into(), None),
+            FlushAll(dst) => f(dst.into(), None),
             NextLine(dst, _cmd, _) => f(dst.into(), None),
             ReadErrStdin(dst) => f(dst.into(), None),
             NextLineStdin(dst) => f(dst.into(), None),
@@ -328,6 +351,7 @@ pub(crate) mod boilerplate {
                 }
             }
             RunCmd(dst, _) => f(dst.into(), None),
+            CallNative { dst, .. } => f(Key::Reg(dst.0, dst.1), None),
             Lookup {
                 map_ty,
                 dst,
@@ -340,7 +364,34 @@ pub(crate) mod boilerplate {
                 f(Key::MapVal(*map, *map_ty), None);
                 f(Key::Reg(*dst, map_ty.val().unwrap()), Some(Key::MapVal(*map, *map_ty)))
             },
+            LookupStr { map_ty, dst, map, key } => {
+                f(Key::MapKey(*map, *map_ty), Some(Key::Reg(*key, map_ty.key().unwrap())));
+                f(Key::MapVal(*map, *map_ty), None);
+                f(dst.into(), Some(Key::MapVal(*map, *map_ty)))
+            }
             Len { map_ty, dst, map } => f(Key::Reg(*dst, Ty::Int), Some(Key::Reg(*map, *map_ty))),
+            ToJSON { map_ty, dst, map } => {
+                f(dst.into(), Some(Key::MapKey(*map, *map_ty)));
+                f(dst.into(), Some(Key::MapVal(*map, *map_ty)));
+            }
+            JoinArray {
+                map_ty,
+                dst,
+                map,
+                sep,
+                all,
+            } => {
+                f(dst.into(), Some(Key::MapKey(*map, *map_ty)));
+                f(dst.into(), Some(Key::MapVal(*map, *map_ty)));
+                f(dst.into(), Some(sep.into()));
+                f(dst.into(), Some(all.into()));
+            }
+            ReadArray { map_ty, dst, map, .. } => {
+                f(Key::MapKey(*map, *map_ty), None);
+                f(Key::MapVal(*map, *map_ty), None);
+                f(dst.into(), None)
+            }
+            WriteArray { dst, .. } => f(dst.into(), None),
             Store { map_ty, map, key, val } => {
                 f(Key::MapKey(*map, *map_ty), Some(Key::Reg(*key, map_ty.key().unwrap())));
                 f(Key::MapVal(*map, *map_ty), Some(Key::Reg(*val, map_ty.val().unwrap())));
@@ -357,6 +408,27 @@ pub(crate) mod boilerplate {
                 f(Key::MapVal(*map, *map_ty), Some(Key::Reg(reg, ty)));
                 f(Key::Reg(*dst, map_ty.val().unwrap()), Some(Key::MapVal(*map, *map_ty)));
             }
+            LookupConstKey { map_ty, dst, map, .. } => {
+                f(Key::MapKey(*map, *map_ty), None);
+                f(Key::MapVal(*map, *map_ty), None);
+                f(Key::Reg(*dst, map_ty.val().unwrap()), Some(Key::MapVal(*map, *map_ty)))
+            }
+            StoreConstKey { map_ty, map, val, .. } => {
+                f(Key::MapKey(*map, *map_ty), None);
+                f(Key::MapVal(*map, *map_ty), Some(Key::Reg(*val, map_ty.val().unwrap())));
+            }
+            IncIntConstKey { map_ty, map, dst, by, .. } => {
+                let (reg, ty) = by.reflect();
+                f(Key::MapKey(*map, *map_ty), None);
+                f(Key::MapVal(*map, *map_ty), Some(Key::Reg(reg, ty)));
+                f(Key::Reg(*dst, map_ty.val().unwrap()), Some(Key::MapVal(*map, *map_ty)));
+            }
+            IncFloatConstKey { map_ty, map, dst, by, .. } => {
+                let (reg, ty) = by.reflect();
+                f(Key::MapKey(*map, *map_ty), None);
+                f(Key::MapVal(*map, *map_ty), Some(Key::Reg(reg, ty)));
+                f(Key::Reg(*dst, map_ty.val().unwrap()), Some(Key::MapVal(*map, *map_ty)));
+            }
             IterBegin { map_ty, dst, map } => {
                 f(Key::Reg(*dst, map_ty.key_iter().unwrap()), Some(Key::MapKey(*map, *map_ty)))
             }
@@ -397,6 +469,9 @@ pub(crate) mod boilerplate {
             | IterHasNext{..}
             | JmpIf(..)
             | Jmp(_)
+            | JmpIfInt(..)
+            | JmpIfFloat(..)
+            | JmpIfConstMatch(..)
             | Push(..)
             | Pop(..)
             // We consume high-level instructions, so calls and returns are handled by visit_hl
@@ -404,12 +479,13 @@ pub(crate) mod boilerplate {
             | Call(_)
             | Ret
             | Printf { .. }
-            | Close(_)
             | NextLineStdinFused()
             | NextFile()
             | SetColumn(_, _)
             | AllocMap(_, _)
-            | Exit(_) => {}
+            | Exit(_)
+            | ProfileRule(_)
+            | Loc(_) => {}
         }
     }
 }
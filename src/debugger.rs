@@ -0,0 +1,665 @@
+//! An interactive debugger for bytecode programs, for --debug.
+//!
+//! The interpreter hands control to the debugger before each instruction it runs. The debugger
+//! looks the instruction up in the program's `SourceMap` to notice when a new statement starts,
+//! and stops there if it is stepping through the program, if the statement is at a breakpoint, or
+//! if the value of a watched expression has changed since the last statement. While stopped it
+//! reads commands that print variables, fields and array elements, and that say where to stop
+//! next.
+//!
+//! Locations are tracked separately for each depth of the call stack, so that returning from a
+//! function to the middle of a statement does not count as starting a new one.
+//!
+//! Programs are compiled with `export_globals` set, which keeps each global variable in a register
+//! of its own that the debugger can read. Variables local to functions have no such register, and
+//! cannot be printed.
+use crate::builtins::Variable;
+use crate::common::{CompileError, NumTy, Result};
+use crate::compile::{GlobalReg, Ty};
+use crate::embed::Value;
+use crate::interp::Interp;
+use crate::lexer::{self, SourceLoc};
+use crate::runtime::{self, Float, Int, LineReader, Str};
+use crate::source_map::SourceMap;
+use crate::types::TVar;
+
+use hashbrown::HashMap;
+
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::mem;
+use std::sync::Arc;
+
+const HELP: &str = "\
+step, s               run until the next statement
+next, n               run until the next statement in this function or one that called it
+finish                run until the current function returns
+record, r             run until the next input record has been read
+continue, c           run until a breakpoint or watchpoint is reached
+break, b [where]      stop at a line, at BEGIN, END or PREPARE, or in a function (default: this line)
+watch, w expr         stop when the value of expr changes
+delete, d [n]         delete breakpoint or watchpoint n (default: all of them)
+info, i               list breakpoints and watchpoints
+print, p expr, ...    print global variables, builtin variables, fields ($n) and array elements
+list, l               show the program around the current line
+backtrace, bt         show the functions that have been called
+quit, q               end the program
+help, h               show this message";
+
+// Ignore errors writing to the debugger's output, as the program itself does for stderr.
+macro_rules! say {
+    ($d:expr, $($t:tt)*) => {{
+        let _ = writeln!($d.out, $($t)*);
+    }};
+}
+
+/// Where to stop next, other than at breakpoints and watchpoints.
+#[derive(Copy, Clone)]
+enum Resume {
+    // At the next statement.
+    Step,
+    // At the next statement at or above this depth of the call stack.
+    Next(usize),
+    // At the next statement above this depth of the call stack.
+    Finish(usize),
+    // At the next statement once NR differs from this.
+    Record(Int),
+    // Only at breakpoints and watchpoints.
+    Continue,
+}
+
+enum Target {
+    // Statements on a line, counting from 0.
+    Line(u32),
+    // Statements in any of these toplevel items, as indexes into `SourceMap::rules`.
+    Rules(Vec<usize>),
+}
+
+struct Breakpoint {
+    id: usize,
+    target: Target,
+    desc: String,
+}
+
+impl Breakpoint {
+    /// Whether starting a statement at `loc`, after one at `prev` in the same function call,
+    /// enters the breakpoint's line or rule.
+    fn hit(&self, loc: SourceLoc, prev: Option<SourceLoc>, map: &SourceMap) -> bool {
+        match &self.target {
+            Target::Line(line) => loc.line == *line && prev.map(|p| p.line) != Some(*line),
+            Target::Rules(rules) => match map.rule_index(loc) {
+                Some(i) => rules.contains(&i) && prev.and_then(|p| map.rule_index(p)) != Some(i),
+                None => false,
+            },
+        }
+    }
+}
+
+struct Watch {
+    id: usize,
+    // The expression as it was typed.
+    text: String,
+    expr: Expr,
+    value: String,
+}
+
+/// The expressions that can be printed and watched.
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Lit(Value),
+    Var(String),
+    Field(Box<Expr>),
+    Index(String, Box<Expr>),
+}
+
+enum Val {
+    Scalar(Value),
+    Array(Vec<(String, Value)>),
+}
+
+fn error<T>(msg: String) -> Result<T> {
+    Err(CompileError(msg))
+}
+
+/// Parse an expression from the start of `s`, returning it along with the rest of `s`.
+fn parse_expr(s: &str) -> Result<(Expr, &str)> {
+    let s = s.trim_start();
+    if let Some(rest) = s.strip_prefix('$') {
+        let (e, rest) = parse_expr(rest)?;
+        return Ok((Expr::Field(Box::new(e)), rest));
+    }
+    if let Some(rest) = s.strip_prefix('(') {
+        let (e, rest) = parse_expr(rest)?;
+        return match rest.trim_start().strip_prefix(')') {
+            Some(rest) => Ok((e, rest)),
+            None => error(format!("expected ) at {:?}", rest)),
+        };
+    }
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut lit = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Expr::Lit(Value::Str(lit)), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => lit.push('\n'),
+                    Some((_, 't')) => lit.push('\t'),
+                    Some((_, c)) => lit.push(c),
+                    None => break,
+                },
+                c => lit.push(c),
+            }
+        }
+        return error("unterminated string".into());
+    }
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    if word.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        let lit = match (word.parse::<Int>(), word.parse::<Float>()) {
+            (Ok(i), _) => Value::Int(i),
+            (_, Ok(f)) => Value::Float(f),
+            _ => return error(format!("invalid number {}", word)),
+        };
+        return Ok((Expr::Lit(lit), rest));
+    }
+    if !lexer::is_ident(word) {
+        return error(format!(
+            "expected a variable, a field or a constant at {:?}",
+            s
+        ));
+    }
+    match rest.trim_start().strip_prefix('[') {
+        Some(rest) => {
+            let (key, rest) = parse_expr(rest)?;
+            match rest.trim_start().strip_prefix(']') {
+                Some(rest) => Ok((Expr::Index(word.into(), Box::new(key)), rest)),
+                None => error(format!("expected ] at {:?}", rest)),
+            }
+        }
+        None => Ok((Expr::Var(word.into()), rest)),
+    }
+}
+
+/// Parse one expression, which must make up all of `s`.
+fn parse_one(s: &str) -> Result<Expr> {
+    let (e, rest) = parse_expr(s)?;
+    if !rest.trim().is_empty() {
+        return error(format!("unexpected {:?}", rest.trim()));
+    }
+    Ok(e)
+}
+
+/// Parse a comma-separated list of expressions.
+fn parse_list(mut s: &str) -> Result<Vec<Expr>> {
+    let mut res = Vec::new();
+    loop {
+        let (e, rest) = parse_expr(s)?;
+        res.push(e);
+        let rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(res);
+        }
+        match rest.strip_prefix(',') {
+            Some(rest) => s = rest,
+            None => return error(format!("unexpected {:?}", rest)),
+        }
+    }
+}
+
+fn to_str(s: Str) -> String {
+    s.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned())
+}
+
+/// `v` as awk would convert it to a string, e.g. for use as an array key.
+fn key_of(v: &Value) -> String {
+    match v {
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => to_str(runtime::convert::<Float, Str>(*f)),
+        Value::Str(s) => s.clone(),
+        Value::Bytes(bs) => String::from_utf8_lossy(bs).into_owned(),
+    }
+}
+
+/// `v` as it is shown to the user: strings are quoted to tell them apart from numbers.
+pub(crate) fn show(v: &Value) -> String {
+    match v {
+        Value::Str(_) | Value::Bytes(_) => format!("{:?}", key_of(v)),
+        v => key_of(v),
+    }
+}
+
+pub(crate) fn show_key(k: &str) -> String {
+    match k.parse::<Int>() {
+        Ok(_) => k.into(),
+        Err(_) => format!("{:?}", k),
+    }
+}
+
+/// Sort the entries of an array by key, numerically if they are all integers.
+pub(crate) fn sort_entries(entries: &mut [(String, Value)]) {
+    if entries.iter().all(|(k, _)| k.parse::<Int>().is_ok()) {
+        entries.sort_by_key(|(k, _)| k.parse::<Int>().unwrap());
+    } else {
+        entries.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+    }
+}
+
+pub(crate) struct Debugger<'a> {
+    commands: Box<dyn BufRead>,
+    out: Box<dyn Write>,
+    // The program text, by line.
+    source: Vec<String>,
+    globals: HashMap<&'a str, (NumTy, Ty)>,
+    source_map: Arc<SourceMap<'a>>,
+    breakpoints: Vec<Breakpoint>,
+    watches: Vec<Watch>,
+    // The id of the next breakpoint or watchpoint.
+    next_id: usize,
+    resume: Resume,
+    // The location of the statement running at each depth of the call stack.
+    frames: Vec<Option<SourceLoc>>,
+    // The last command entered, which an empty line repeats.
+    last_command: String,
+    attached: bool,
+}
+
+impl<'a> Debugger<'a> {
+    /// A debugger for the program with text `source`, reading commands from `commands` and writing
+    /// to `out`. It stops at the first statement of the program.
+    pub(crate) fn new(
+        commands: Box<dyn BufRead>,
+        out: Box<dyn Write>,
+        source: &str,
+        globals: Vec<GlobalReg<'a>>,
+    ) -> Debugger<'a> {
+        Debugger {
+            commands,
+            out,
+            source: source.lines().map(String::from).collect(),
+            globals: globals
+                .into_iter()
+                .map(|(name, reg, ty)| (name, (reg, ty)))
+                .collect(),
+            source_map: Default::default(),
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            next_id: 1,
+            resume: Resume::Step,
+            frames: Vec::new(),
+            last_command: String::new(),
+            attached: true,
+        }
+    }
+
+    pub(crate) fn set_source_map(&mut self, source_map: Arc<SourceMap<'a>>) {
+        self.source_map = source_map;
+    }
+
+    /// Whether the debugger should still be consulted. It detaches once its commands run out,
+    /// leaving the program to run to completion.
+    pub(crate) fn attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Called before the `offset`th instruction of function `func` runs. Returns the exit status
+    /// if the user ends the program.
+    pub(crate) fn visit<LR: LineReader>(
+        &mut self,
+        interp: &mut Interp<'a, LR>,
+        func: usize,
+        offset: usize,
+    ) -> Result<Option<i32>> {
+        let loc = self.source_map.locate(func, offset);
+        let depth = interp.call_stack().len();
+        self.frames.resize(depth + 1, None);
+        let prev = mem::replace(&mut self.frames[depth], loc);
+        let loc = match loc {
+            Some(loc) if Some(loc) != prev => loc,
+            _ => return Ok(None),
+        };
+        let mut stop = match self.resume {
+            Resume::Step => true,
+            Resume::Next(d) => depth <= d,
+            Resume::Finish(d) => depth < d,
+            Resume::Record(nr) => interp.nr() != nr,
+            Resume::Continue => false,
+        };
+        for bp in self.breakpoints.iter() {
+            if bp.hit(loc, prev, &self.source_map) {
+                say!(self, "Breakpoint {}, {}", bp.id, bp.desc);
+                stop = true;
+            }
+        }
+        let mut watches = mem::take(&mut self.watches);
+        for w in watches.iter_mut() {
+            let value = self.render(interp, &w.expr);
+            if value != w.value {
+                say!(self, "Watchpoint {}: {}", w.id, w.text);
+                say!(self, "  Old value: {}", w.value);
+                say!(self, "  New value: {}", value);
+                w.value = value;
+                stop = true;
+            }
+        }
+        self.watches = watches;
+        if !stop {
+            return Ok(None);
+        }
+        say!(self, "Stopped at {}", self.source_map.describe(loc));
+        self.show_line(loc.line, false);
+        self.prompt(interp, func, offset, loc)
+    }
+
+    /// Read and run commands until one of them resumes the program.
+    fn prompt<LR: LineReader>(
+        &mut self,
+        interp: &mut Interp<'a, LR>,
+        func: usize,
+        offset: usize,
+        loc: SourceLoc,
+    ) -> Result<Option<i32>> {
+        let depth = interp.call_stack().len();
+        loop {
+            // Show what the program has printed so far before asking for more commands.
+            interp.flush_stdout()?;
+            let _ = write!(self.out, "(frawk) ");
+            let _ = self.out.flush();
+            let mut line = String::new();
+            match self.commands.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    self.attached = false;
+                    return Ok(None);
+                }
+                Ok(_) => {}
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => {
+                    self.last_command = line.into();
+                    line.into()
+                }
+            };
+            let (cmd, arg) = match line.split_once(char::is_whitespace) {
+                Some((cmd, arg)) => (cmd, arg.trim()),
+                None => (line.as_str(), ""),
+            };
+            self.resume = match cmd {
+                "s" | "step" => Resume::Step,
+                "n" | "next" => Resume::Next(depth),
+                "finish" => Resume::Finish(depth),
+                "r" | "record" => Resume::Record(interp.nr()),
+                "c" | "continue" => Resume::Continue,
+                "q" | "quit" => {
+                    self.attached = false;
+                    return Ok(Some(0));
+                }
+                "b" | "break" => {
+                    self.add_breakpoint(arg, loc);
+                    continue;
+                }
+                "w" | "watch" => {
+                    self.add_watch(interp, arg);
+                    continue;
+                }
+                "d" | "delete" => {
+                    self.delete(arg);
+                    continue;
+                }
+                "i" | "info" => {
+                    self.info();
+                    continue;
+                }
+                "p" | "print" => {
+                    self.print(interp, arg);
+                    continue;
+                }
+                "l" | "list" => {
+                    let first = loc.line.saturating_sub(5);
+                    for line in first..=loc.line + 5 {
+                        self.show_line(line, line == loc.line);
+                    }
+                    continue;
+                }
+                "bt" | "backtrace" => {
+                    self.backtrace(interp, func, offset);
+                    continue;
+                }
+                "h" | "help" => {
+                    say!(self, "{}", HELP);
+                    continue;
+                }
+                _ => {
+                    say!(self, "unknown command {:?}; try \"help\"", cmd);
+                    continue;
+                }
+            };
+            return Ok(None);
+        }
+    }
+
+    /// Print `line` of the program (counting from 0), if there is one.
+    fn show_line(&mut self, line: u32, current: bool) {
+        if let Some(text) = self.source.get(line as usize) {
+            let marker = if current { "=>" } else { "" };
+            say!(self, "{:>2}{:>4}  {}", marker, line + 1, text);
+        }
+    }
+
+    fn add_breakpoint(&mut self, arg: &str, loc: SourceLoc) {
+        let (target, desc) = if arg.is_empty() {
+            (Target::Line(loc.line), format!("line {}", loc.line + 1))
+        } else if let Ok(line) = arg.parse::<u32>() {
+            if line == 0 || line as usize > self.source.len() {
+                say!(self, "the program has no line {}", line);
+                return;
+            }
+            (Target::Line(line - 1), format!("line {}", line))
+        } else {
+            let rules: Vec<usize> = (0..self.source_map.rules.len())
+                .filter(|i| {
+                    use crate::ast::Rule::*;
+                    match (&self.source_map.rules[*i].1, arg) {
+                        (Begin, "BEGIN") | (Prepare, "PREPARE") | (End, "END") => true,
+                        (Function(name), arg) => *name == arg,
+                        _ => false,
+                    }
+                })
+                .collect();
+            if rules.is_empty() {
+                say!(self, "no line, rule or function {}", arg);
+                return;
+            }
+            let desc = match arg {
+                "BEGIN" | "PREPARE" | "END" => arg.into(),
+                name => format!("function {}", name),
+            };
+            (Target::Rules(rules), desc)
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        say!(self, "Breakpoint {} at {}", id, desc);
+        self.breakpoints.push(Breakpoint { id, target, desc });
+    }
+
+    fn add_watch<LR: LineReader>(&mut self, interp: &mut Interp<'a, LR>, arg: &str) {
+        let expr = match parse_one(arg) {
+            Ok(expr) => expr,
+            Err(e) => {
+                say!(self, "{}", e);
+                return;
+            }
+        };
+        let value = self.render(interp, &expr);
+        let id = self.next_id;
+        self.next_id += 1;
+        say!(self, "Watchpoint {}: {} = {}", id, arg, value);
+        self.watches.push(Watch {
+            id,
+            text: arg.into(),
+            expr,
+            value,
+        });
+    }
+
+    fn delete(&mut self, arg: &str) {
+        if arg.is_empty() {
+            self.breakpoints.clear();
+            self.watches.clear();
+            return;
+        }
+        let before = self.breakpoints.len() + self.watches.len();
+        if let Ok(id) = arg.parse::<usize>() {
+            self.breakpoints.retain(|bp| bp.id != id);
+            self.watches.retain(|w| w.id != id);
+        }
+        if self.breakpoints.len() + self.watches.len() == before {
+            say!(self, "no breakpoint or watchpoint {}", arg);
+        }
+    }
+
+    fn info(&mut self) {
+        if self.breakpoints.is_empty() && self.watches.is_empty() {
+            say!(self, "no breakpoints or watchpoints");
+        }
+        for bp in self.breakpoints.iter() {
+            say!(self, "Breakpoint {} at {}", bp.id, bp.desc);
+        }
+        for w in self.watches.iter() {
+            say!(self, "Watchpoint {}: {} = {}", w.id, w.text, w.value);
+        }
+    }
+
+    fn print<LR: LineReader>(&mut self, interp: &mut Interp<'a, LR>, arg: &str) {
+        let exprs = match parse_list(arg) {
+            Ok(exprs) => exprs,
+            Err(e) => {
+                say!(self, "{}", e);
+                return;
+            }
+        };
+        for expr in exprs.iter() {
+            match (self.eval(interp, expr), expr) {
+                (Ok(Val::Scalar(v)), _) => say!(self, "{}", show(&v)),
+                (Ok(Val::Array(mut entries)), Expr::Var(name)) => {
+                    if entries.is_empty() {
+                        say!(self, "{} is empty", name);
+                    }
+                    sort_entries(&mut entries);
+                    for (k, v) in entries.iter() {
+                        say!(self, "{}[{}] = {}", name, show_key(k), show(v));
+                    }
+                }
+                (Ok(Val::Array(_)), _) => unreachable!("only variables are arrays"),
+                (Err(e), _) => say!(self, "{}", e),
+            }
+        }
+    }
+
+    fn backtrace<LR: LineReader>(&mut self, interp: &Interp<'a, LR>, func: usize, offset: usize) {
+        let mut frames: Vec<_> = interp.call_stack().collect();
+        frames.push((func, offset));
+        for (i, (func, offset)) in frames.into_iter().rev().enumerate() {
+            let desc = match self.source_map.locate(func, offset) {
+                Some(loc) => self.source_map.describe(loc),
+                None => "the main loop".into(),
+            };
+            say!(self, "#{} {}", i, desc);
+        }
+    }
+
+    /// The value of `expr`, or the reason it has none, as shown for watchpoints.
+    fn render<LR: LineReader>(&self, interp: &mut Interp<'a, LR>, expr: &Expr) -> String {
+        match self.eval(interp, expr) {
+            Ok(Val::Scalar(v)) => show(&v),
+            Ok(Val::Array(mut entries)) => {
+                sort_entries(&mut entries);
+                let entries: Vec<_> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", show_key(k), show(v)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Err(e) => format!("<{}>", e),
+        }
+    }
+
+    fn eval<LR: LineReader>(&self, interp: &mut Interp<'a, LR>, expr: &Expr) -> Result<Val> {
+        Ok(match expr {
+            Expr::Lit(v) => Val::Scalar(v.clone()),
+            Expr::Var(name) => {
+                if let Some((reg, ty)) = self.globals.get(name.as_str()) {
+                    if ty.is_array() {
+                        Val::Array(interp.array((*reg, *ty))?)
+                    } else {
+                        Val::Scalar(interp.scalar((*reg, *ty))?)
+                    }
+                } else if let Ok(var) = Variable::try_from(name.as_str()) {
+                    match var.ty() {
+                        TVar::Scalar(_) => Val::Scalar(interp.builtin_scalar(var)?),
+                        _ => Val::Array(interp.builtin_array(var)?),
+                    }
+                } else {
+                    return error(format!(
+                        "{} is not a global variable, or is never set",
+                        name
+                    ));
+                }
+            }
+            Expr::Field(e) => {
+                let i = match self.eval(interp, e)? {
+                    Val::Scalar(Value::Int(i)) => i,
+                    Val::Scalar(Value::Float(f)) => runtime::convert::<Float, Int>(f),
+                    Val::Scalar(Value::Str(s)) => runtime::convert::<&Str, Int>(&s.into()),
+                    Val::Scalar(Value::Bytes(bs)) => runtime::convert::<&Str, Int>(&bs[..].into()),
+                    Val::Array(_) => return error("arrays cannot be used as field numbers".into()),
+                };
+                if i < 0 {
+                    return error(format!("there is no field {}", i));
+                }
+                Val::Scalar(Value::Str(to_str(interp.field(i)?)))
+            }
+            Expr::Index(name, key) => {
+                let entries = match self.eval(interp, &Expr::Var(name.clone()))? {
+                    Val::Array(entries) => entries,
+                    Val::Scalar(_) => return error(format!("{} is not an array", name)),
+                };
+                let key = match self.eval(interp, key)? {
+                    Val::Scalar(v) => key_of(&v),
+                    Val::Array(_) => return error("arrays cannot be used as keys".into()),
+                };
+                match entries.into_iter().find(|(k, _)| *k == key) {
+                    Some((_, v)) => Val::Scalar(v),
+                    None => return error(format!("{}[{}] is not set", name, show_key(&key))),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_exprs() {
+        use Expr::*;
+        assert_eq!(
+            parse_list("x, $NF, a[\"k\\\"\"], $ (2), b[$1], 1.5").unwrap(),
+            vec![
+                Var("x".into()),
+                Field(Box::new(Var("NF".into()))),
+                Index("a".into(), Box::new(Lit(Value::Str("k\"".into())))),
+                Field(Box::new(Lit(Value::Int(2)))),
+                Index("b".into(), Box::new(Field(Box::new(Lit(Value::Int(1)))))),
+                Lit(Value::Float(1.5)),
+            ]
+        );
+        assert!(parse_one("x y").is_err());
+        assert!(parse_one("a[1").is_err());
+        assert!(parse_one("\"abc").is_err());
+        assert!(parse_one("x + 1").is_err());
+        assert!(parse_one("$(1").is_err());
+    }
+}
@@ -3,6 +3,7 @@ use crate::ast::{Binop, Unop};
 use crate::builtins::{Function, Variable};
 use crate::cfg::{BasicBlock, Ident, PrimExpr, PrimStmt, PrimVal, Transition};
 use crate::common::FileSpec;
+use crate::compile::Ty;
 use crate::lexer;
 use std::fmt::{self, Display, Formatter};
 use std::string::String;
@@ -83,6 +84,8 @@ impl<'a> Display for PrimStmt<'a> {
                 Ok(())
             }
             IterDrop(v) => write!(f, "drop_iter {}", v),
+            Loc(Some(loc)) => write!(f, "loc {}", loc),
+            Loc(None) => write!(f, "loc unknown"),
         }
     }
 }
@@ -167,7 +170,10 @@ impl Display for Function {
             Delete => write!(f, "delete"),
             Clear => write!(f, "clear"),
             Close => write!(f, "close"),
-            Match => write!(f, "match"),
+            Flush => write!(f, "fflush"),
+            FlushAll => write!(f, "fflush_all"),
+            Match | MatchArray => write!(f, "match"),
+            MatchSet => write!(f, "match_set"),
             SubstrIndex => write!(f, "index"),
             Sub => write!(f, "sub"),
             GSub => write!(f, "gsub"),
@@ -176,6 +182,9 @@ impl Display for Function {
             EscapeTSV => write!(f, "escape_tsv"),
             JoinCSV => write!(f, "join_csv"),
             JoinTSV => write!(f, "join_tsv"),
+            JoinJSON => write!(f, "join_json"),
+            JoinArray => write!(f, "join"),
+            ToJSON => write!(f, "to_json"),
             JoinCols => write!(f, "join_fields"),
             Substr => write!(f, "substr"),
             ToInt => write!(f, "int"),
@@ -189,7 +198,26 @@ impl Display for Function {
             ToLower => write!(f, "tolower"),
             ToUpper => write!(f, "toupper"),
             IncMap => write!(f, "inc_map"),
+            ReadArray => write!(f, "reada"),
+            WriteArray => write!(f, "writea"),
             Exit => write!(f, "exit"),
+            ProfileRule => write!(f, "profile_rule"),
+            Native(native) => write!(f, "native_{}", native.id),
+        }
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Ty::*;
+        match self {
+            Int => write!(f, "int"),
+            Float => write!(f, "float"),
+            Str => write!(f, "str"),
+            Null => write!(f, "null"),
+            IterInt => write!(f, "iter[int]"),
+            IterStr => write!(f, "iter[str]"),
+            map => write!(f, "map[{}]{}", map.key().unwrap(), map.val().unwrap()),
         }
     }
 }
@@ -215,6 +243,8 @@ impl Display for Variable {
                 RLENGTH => "RLENGTH",
                 PID => "PID",
                 FI => "FI",
+                PROCINFO => "PROCINFO",
+                IGNORECASE => "IGNORECASE",
             }
         )
     }
@@ -267,6 +297,12 @@ impl Display for lexer::Loc {
     }
 }
 
+impl Display for lexer::SourceLoc {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "line {}, column {}", self.line + 1, self.col + 1)
+    }
+}
+
 impl Display for lexer::Error {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         write!(fmt, "{}. {}", self.location, self.desc)
@@ -0,0 +1,812 @@
+//! An interface for running frawk programs from Rust.
+//!
+//! Programs are compiled to the same bytecode image that `--save-bytecode` writes to disk, and
+//! each call to `run` loads a fresh interpreter from it, so a `Program` holds no state between
+//! runs. Programs always run on the bytecode interpreter, serially.
+//!
+//! Keeping the image encoded is also what lets a `Program` be shared between threads: decoded
+//! instructions hold strings and maps that are reference-counted without synchronization, while
+//! the image is plain bytes, and native functions and file openers must be `Send` and `Sync`.
+//!
+//! Native functions registered with a `Builder` are called like builtins: the compiler resolves
+//! them to `builtins::Function::Native`, which records their signature for type inference, and
+//! calls to them become `CallNative` instructions that index into a table of callbacks held by the
+//! interpreter.
+//!
+//! Programs are compiled with `export_globals` set, which keeps each global variable in a register
+//! of its own rather than renaming those only used in the main function like locals, and has the
+//! bytecode optimizations preserve their final values. `Program` records the register holding each
+//! of them, so `run_with_globals` can read them from the interpreter once the program finishes.
+//!
+//! Cancellation, timeouts and memory limits are checked by the interpreter (see `interp::Cancel`)
+//! whenever it jumps backwards or calls a function, which every unbounded loop must do. Output
+//! limits are instead enforced by the writers handed to the interpreter, which share a count of
+//! the bytes written.
+//!
+//! Files that the program reads with `getline` or writes with `print >` come from the local file
+//! system, as they do for the `frawk` binary, unless the `Builder` was given functions to open
+//! them: `runtime::FileRead` calls the `InputOpener` in place of opening a file, and the
+//! `FileFactory` passed to the interpreter calls the output function.
+//!
+//! Output is written by background threads (see `runtime::writers`), which can only hold on to
+//! values that live forever. To let callers pass a borrowed writer, those threads send the
+//! program's standard output over a channel to a thread scoped to the call to `run`, which writes
+//! it out.
+//!
+//! A `Stream` instead runs the program on a thread of its own, reading from a channel of pushed
+//! chunks and sending its output back over another. Its input is split eagerly (see
+//! `RegexSplitter::set_eager`) and its output is line-buffered, so that each record is processed,
+//! and its output seen, without waiting for a full chunk of either.
+use crate::arena::Arena;
+use crate::builtins::{self, MAX_NATIVE_ARGS};
+use crate::cfg::{self, Escaper};
+use crate::common::{CancelSignal, CompileError, FileSpec, NumTy, Stage};
+use crate::interp::Cancel;
+use crate::runtime::{self, splitter::regex::RegexSplitter, InputOpener, KeyOrder, CHUNK_SIZE};
+use crate::{ast, compile, image, lexer, parsing};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of chunks of output that can be waiting to be written before the program blocks.
+const OUTPUT_CHUNKS: usize = 16;
+
+/// The ways in which compiling or running a [`Program`] can fail.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The program could not be parsed.
+    Syntax(String),
+    /// The program parsed, but could not be compiled; for example, because a variable is used both
+    /// as a scalar and as an array.
+    Compile(String),
+    /// The program failed while it was running.
+    Runtime(String),
+    /// Writing the program's output failed.
+    Io(io::Error),
+    /// The program was stopped with a [`CancelToken`].
+    Cancelled,
+    /// The program ran for longer than the timeout set with [`Builder::timeout`].
+    TimedOut,
+    /// The program's strings and arrays grew larger than the limit set with
+    /// [`Builder::max_memory`].
+    MemoryLimitExceeded,
+    /// The program wrote more than the limit set with [`Builder::max_output`].
+    OutputLimitExceeded,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(msg) => write!(f, "syntax error: {}", msg),
+            Error::Compile(msg) => write!(f, "failed to compile program: {}", msg),
+            Error::Runtime(msg) => write!(f, "error during execution: {}", msg),
+            Error::Io(e) => write!(f, "failed to write output: {}", e),
+            Error::Cancelled => write!(f, "execution cancelled"),
+            Error::TimedOut => write!(f, "execution timed out"),
+            Error::MemoryLimitExceeded => write!(f, "memory limit exceeded"),
+            Error::OutputLimitExceeded => write!(f, "output limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    fn compile(e: CompileError) -> Error {
+        Error::Compile(e.0)
+    }
+}
+
+/// The types of the arguments and results of native functions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Float,
+    Str,
+}
+
+impl From<Type> for compile::Ty {
+    fn from(ty: Type) -> compile::Ty {
+        match ty {
+            Type::Int => compile::Ty::Int,
+            Type::Float => compile::Ty::Float,
+            Type::Str => compile::Ty::Str,
+        }
+    }
+}
+
+/// A value passed to or returned from a native function.
+///
+/// Arguments always have the types given when the function was registered. A function may return
+/// a value of a different type than the one it was registered with, in which case it is converted
+/// the way awk converts values, so that returning `Value::Str("12abc")` from a function returning
+/// `Type::Int` yields 12. Strings are passed to functions as `Value::Bytes` if they are not valid
+/// UTF-8, and functions may return either kind of string.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// A string that is not valid UTF-8.
+    Bytes(Vec<u8>),
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Value {
+        Value::Float(f)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::Str(s.into())
+    }
+}
+
+/// A `Value::Str` if `bs` is valid UTF-8, and a `Value::Bytes` otherwise.
+impl From<Vec<u8>> for Value {
+    fn from(bs: Vec<u8>) -> Value {
+        match String::from_utf8(bs) {
+            Ok(s) => Value::Str(s),
+            Err(e) => Value::Bytes(e.into_bytes()),
+        }
+    }
+}
+
+pub(crate) type Callback = Arc<dyn Fn(&[Value]) -> Value + Send + Sync>;
+
+type OutputOpener = Arc<dyn Fn(&str, bool) -> io::Result<Box<dyn Write + Send>> + Send + Sync>;
+
+struct NativeFunction {
+    name: String,
+    args: Vec<Type>,
+    ret: Type,
+    callback: Callback,
+}
+
+/// Compiles a [`Program`] that can call functions implemented in Rust, or that reads and writes
+/// files somewhere other than the local file system.
+#[derive(Default)]
+pub struct Builder {
+    natives: Vec<NativeFunction>,
+    inputs: Option<InputOpener>,
+    outputs: Option<OutputOpener>,
+    cancel: Option<CancelSignal>,
+    timeout: Option<Duration>,
+    max_memory: Option<usize>,
+    max_output: Option<u64>,
+    ieee_division: bool,
+    key_order: KeyOrder,
+}
+
+impl Builder {
+    /// Make `f` callable from the program as the function `name`, taking arguments of the types in
+    /// `args` and returning a value of type `ret`. Arguments are converted to the types in `args`
+    /// before `f` is called, as they are for builtins like `sqrt`.
+    ///
+    /// Functions are checked when the program is compiled: their names must be identifiers that
+    /// are not already the names of builtin functions, and they can take at most 8 arguments.
+    pub fn function(
+        mut self,
+        name: &str,
+        args: &[Type],
+        ret: Type,
+        f: impl Fn(&[Value]) -> Value + Send + Sync + 'static,
+    ) -> Builder {
+        self.natives.push(NativeFunction {
+            name: name.into(),
+            args: args.to_vec(),
+            ret,
+            callback: Arc::new(f),
+        });
+        self
+    }
+
+    /// Open the files that the program reads with `getline` by calling `f` with their names,
+    /// rather than opening them on the local file system. Errors returned by `f` are treated like
+    /// a missing file, so `getline` returns -1.
+    pub fn input_files(
+        mut self,
+        f: impl Fn(&str) -> io::Result<Box<dyn Read + Send>> + Send + Sync + 'static,
+    ) -> Builder {
+        self.inputs = Some(Arc::new(f));
+        self
+    }
+
+    /// Open the files that the program writes with `print >` and `print >>` by calling `f` with
+    /// their names, rather than creating them on the local file system. The second argument to
+    /// `f` is true if the file is being appended to, and false if it should be truncated.
+    pub fn output_files(
+        mut self,
+        f: impl Fn(&str, bool) -> io::Result<Box<dyn Write + Send>> + Send + Sync + 'static,
+    ) -> Builder {
+        self.outputs = Some(Arc::new(f));
+        self
+    }
+
+    /// Stop runs of the program with [`Error::Cancelled`] once `token` is cancelled.
+    ///
+    /// Programs check for cancellation each time they go around a loop or call a function, so
+    /// they stop promptly unless they are waiting for input.
+    pub fn cancel_token(mut self, token: &CancelToken) -> Builder {
+        self.cancel = Some(token.0.clone());
+        self
+    }
+
+    /// Stop runs of the program that take longer than `timeout` with [`Error::TimedOut`]. As with
+    /// cancellation, the time is checked as the program loops and calls functions.
+    pub fn timeout(mut self, timeout: Duration) -> Builder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop runs of the program with [`Error::MemoryLimitExceeded`] once its strings and arrays
+    /// hold more than about `bytes` bytes.
+    ///
+    /// Memory use is estimated, not measured: strings are counted at their full length, and the
+    /// size of large arrays is extrapolated from a sample of their entries. It is checked
+    /// periodically as the program loops and calls functions, so a program can briefly go over
+    /// the limit before it is stopped.
+    pub fn max_memory(mut self, bytes: usize) -> Builder {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Stop runs of the program with [`Error::OutputLimitExceeded`] once they write more than
+    /// `bytes` bytes, counting both standard output and the files written with `print >`. Output
+    /// is cut off at the limit. Output to commands and to standard error is not counted.
+    pub fn max_output(mut self, bytes: u64) -> Builder {
+        self.max_output = Some(bytes);
+        self
+    }
+
+    /// Divide floating-point numbers by zero as IEEE 754 does, producing an infinity or NaN,
+    /// rather than stopping runs of the program with an error. Taking an integer modulo zero is an
+    /// error either way.
+    pub fn ieee_division(mut self, ieee: bool) -> Builder {
+        self.ieee_division = ieee;
+        self
+    }
+
+    /// Visit the keys of arrays in `order` in `for (k in m)` loops and `join`, as the
+    /// `--sorted-in` flag does for the `frawk` binary.
+    pub fn key_order(mut self, order: KeyOrder) -> Builder {
+        self.key_order = order;
+        self
+    }
+
+    /// Compile the awk program `src`.
+    pub fn compile(self, src: &str) -> Result<Program, Error> {
+        let a = Arena::default();
+        let src = a.alloc_str(src);
+        let mut buf = Vec::new();
+        let mut prog = ast::Prog::from_stage(&a, Stage::Main(()));
+        prog.export_globals = true;
+        let mut names = HashSet::new();
+        for (id, native) in self.natives.iter().enumerate() {
+            let name = a.alloc_str(native.name.as_str());
+            if !lexer::is_ident(name) {
+                return Err(Error::Compile(format!(
+                    "invalid name for native function: {:?}",
+                    name
+                )));
+            }
+            if builtins::Function::try_from(name).is_ok() {
+                return Err(Error::Compile(format!(
+                    "native function {} has the same name as a builtin function",
+                    name
+                )));
+            }
+            if !names.insert(name) {
+                return Err(Error::Compile(format!(
+                    "native function {} is defined more than once",
+                    name
+                )));
+            }
+            let args: Vec<compile::Ty> = native.args.iter().map(|ty| (*ty).into()).collect();
+            let sig = match builtins::Native::new(id as u32, &args[..], native.ret.into()) {
+                Some(sig) => sig,
+                None => {
+                    return Err(Error::Compile(format!(
+                        "native function {} takes {} arguments, but at most {} are supported",
+                        name,
+                        args.len(),
+                        MAX_NATIVE_ARGS
+                    )))
+                }
+            };
+            prog.native_functions.push((name, sig));
+        }
+        if let Err(e) = parsing::syntax::ProgParser::new().parse(
+            &a,
+            &mut buf,
+            &mut prog,
+            lexer::Tokenizer::new(src),
+        ) {
+            return Err(Error::Syntax(e.to_string()));
+        }
+        let prog = a.alloc(prog);
+        let mut ctx =
+            cfg::ProgramContext::from_prog(&a, prog, Escaper::Identity).map_err(Error::compile)?;
+        ctx.fold_regex_constants = true;
+        let (image, globals) = compile::image_with_globals(&mut ctx).map_err(Error::compile)?;
+        Ok(Program {
+            image: image.encode(),
+            natives: self.natives.into_iter().map(|n| n.callback).collect(),
+            inputs: self.inputs,
+            outputs: self.outputs,
+            cancel: self.cancel,
+            timeout: self.timeout,
+            max_memory: self.max_memory,
+            max_output: self.max_output,
+            ieee_division: self.ieee_division,
+            key_order: self.key_order,
+            globals: globals
+                .into_iter()
+                .map(|(name, reg, ty)| (name.into(), reg, ty))
+                .collect(),
+        })
+    }
+}
+
+/// A compiled awk program.
+///
+/// Compiling a program parses it, infers types and generates bytecode; none of this is repeated
+/// when the program is run, so a `Program` can be compiled once and then run over many inputs.
+///
+/// A `Program` is immutable, and each run has an interpreter of its own, so the same `Program` can
+/// be run on any number of threads at once; for example, by a server that runs a script for each
+/// request it receives.
+#[derive(Clone)]
+pub struct Program {
+    image: Vec<u8>,
+    // The native functions the program calls, indexed by their id.
+    natives: Vec<Callback>,
+    inputs: Option<InputOpener>,
+    outputs: Option<OutputOpener>,
+    cancel: Option<CancelSignal>,
+    timeout: Option<Duration>,
+    max_memory: Option<usize>,
+    max_output: Option<u64>,
+    ieee_division: bool,
+    key_order: KeyOrder,
+    // The name, register and type of each global variable.
+    globals: Vec<(String, NumTy, compile::Ty)>,
+}
+
+impl Program {
+    /// Compile the awk program `src`.
+    pub fn compile(src: &str) -> Result<Program, Error> {
+        Builder::default().compile(src)
+    }
+
+    /// Start compiling a program that calls native functions.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Run the program, reading records from `input` and writing its standard output to `output`.
+    ///
+    /// Returns the program's exit status: 0, unless it is ended by `exit` with another code.
+    /// Commands and standard error are written to as they are by the `frawk` binary, as are files
+    /// unless the program was compiled with [`Builder::output_files`].
+    pub fn run(&self, input: impl Read, output: &mut (impl Write + Send)) -> Result<i32, Error> {
+        self.run_inner(input, output, None)
+    }
+
+    /// Like [`Program::run`], but also return the values of the program's global variables once it
+    /// has finished.
+    pub fn run_with_globals(
+        &self,
+        input: impl Read,
+        output: &mut (impl Write + Send),
+    ) -> Result<(i32, Globals), Error> {
+        let mut globals = Globals::default();
+        let status = self.run_inner(input, output, Some(&mut globals))?;
+        Ok((status, globals))
+    }
+
+    fn run_inner(
+        &self,
+        input: impl Read,
+        output: &mut (impl Write + Send),
+        globals: Option<&mut Globals>,
+    ) -> Result<i32, Error> {
+        let reader = RegexSplitter::new(input, CHUNK_SIZE, "-", /*check_utf8=*/ false);
+        let (sender, receiver) = bounded(OUTPUT_CHUNKS);
+        thread::scope(|s| {
+            let writer = s.spawn(move || write_output(receiver, output));
+            let res = self.execute(
+                reader,
+                sender.clone(),
+                /*line_buffered=*/ false,
+                globals,
+            );
+            // Dropping the interpreter (in `execute`) flushes all of its output, so everything
+            // the program printed is already in the channel.
+            let _ = sender.send(None);
+            let written = match writer.join() {
+                Ok(written) => written,
+                Err(panic) => std::panic::resume_unwind(panic),
+            };
+            // A failure to write usually causes the program to fail as well, but the write error
+            // is the more useful one to report.
+            written.map_err(Error::Io)?;
+            res
+        })
+    }
+
+    /// Start running the program on a background thread, over input that is pushed to it with
+    /// [`Stream::push`].
+    pub fn stream(&self) -> Stream {
+        let (input, chunks) = unbounded();
+        let (stdout, output) = unbounded();
+        let prog = self.clone();
+        let run = thread::spawn(move || {
+            let mut reader = RegexSplitter::new(
+                ChannelReader {
+                    chunks,
+                    cur: Vec::new(),
+                    pos: 0,
+                },
+                CHUNK_SIZE,
+                "-",
+                /*check_utf8=*/ false,
+            );
+            reader.set_eager();
+            prog.execute(
+                reader, stdout, /*line_buffered=*/ true, /*globals=*/ None,
+            )
+        });
+        Stream { input, output, run }
+    }
+
+    fn execute(
+        &self,
+        reader: impl runtime::LineReader,
+        stdout: Sender<Option<Vec<u8>>>,
+        line_buffered: bool,
+        globals: Option<&mut Globals>,
+    ) -> Result<i32, Error> {
+        let cancelled = || matches!(&self.cancel, Some(signal) if signal.cancelled());
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let image = image::Image::decode(&self.image).map_err(Error::compile)?;
+        let limit = self.max_output.map(|max| {
+            Arc::new(OutputLimit {
+                max,
+                written: AtomicU64::new(0),
+            })
+        });
+        let ff = OutputFactory {
+            stdout,
+            files: self.outputs.clone(),
+            line_buffered,
+            limit: limit.clone(),
+        };
+        let mut interp = image
+            .into_interp(reader, ff, /*num_workers=*/ 1)
+            .map_err(Error::compile)?;
+        interp.set_natives(self.natives.clone());
+        interp.set_ieee_division(self.ieee_division);
+        interp.set_key_order(self.key_order);
+        if let Some(inputs) = &self.inputs {
+            interp.set_input_opener(inputs.clone());
+        }
+        if self.cancel.is_some() || deadline.is_some() || self.max_memory.is_some() {
+            interp.set_cancel(Cancel::new(self.cancel.clone(), deadline, self.max_memory));
+        }
+        let res = interp.run();
+        // The interpreter does not record why it stopped, but the state it stopped in does.
+        let memory_exceeded =
+            res.is_err() && matches!(self.max_memory, Some(max) if interp.memory_usage() > max);
+        if let (Ok(_), Some(globals)) = (&res, globals) {
+            for (name, reg, ty) in self.globals.iter() {
+                let (reg, ty) = (*reg, *ty);
+                if ty.is_array() {
+                    let entries = interp.array((reg, ty)).map_err(Error::compile)?;
+                    globals
+                        .arrays
+                        .insert(name.clone(), entries.into_iter().collect());
+                } else {
+                    let value = interp.scalar((reg, ty)).map_err(Error::compile)?;
+                    globals.scalars.insert(name.clone(), value);
+                }
+            }
+        }
+        // Dropping the interpreter flushes the rest of its output, which can take it over the
+        // output limit even if the program succeeded.
+        drop(interp);
+        if matches!(&limit, Some(limit) if limit.exceeded()) {
+            return Err(Error::OutputLimitExceeded);
+        }
+        res.map_err(|e| {
+            if cancelled() {
+                Error::Cancelled
+            } else if matches!(deadline, Some(d) if Instant::now() >= d) {
+                Error::TimedOut
+            } else if memory_exceeded {
+                Error::MemoryLimitExceeded
+            } else {
+                Error::Runtime(e.0)
+            }
+        })
+    }
+}
+
+/// A handle for stopping the programs it was passed to with [`Builder::cancel_token`] from another
+/// thread.
+///
+/// Cancelling a token stops all of the runs of those programs that are in progress, and makes any
+/// started afterwards fail immediately. It cannot be undone.
+#[derive(Clone, Default)]
+pub struct CancelToken(CancelSignal);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Stop the programs using this token.
+    pub fn cancel(&self) {
+        self.0.cancel(/*code=*/ 1);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled()
+    }
+}
+
+/// The values of a program's global variables after it has run, returned by
+/// [`Program::run_with_globals`].
+///
+/// Only variables that the program uses are included. The keys of arrays are converted to
+/// strings, as awk does when comparing them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Globals {
+    scalars: HashMap<String, Value>,
+    arrays: HashMap<String, HashMap<String, Value>>,
+}
+
+impl Globals {
+    /// The value of the scalar variable `name`.
+    pub fn scalar(&self, name: &str) -> Option<&Value> {
+        self.scalars.get(name)
+    }
+
+    /// The contents of the array `name`.
+    pub fn array(&self, name: &str) -> Option<&HashMap<String, Value>> {
+        self.arrays.get(name)
+    }
+
+    /// The scalar variables and the arrays, each by name.
+    pub(crate) fn into_parts(
+        self,
+    ) -> (
+        HashMap<String, Value>,
+        HashMap<String, HashMap<String, Value>>,
+    ) {
+        (self.scalars, self.arrays)
+    }
+}
+
+/// A run of a [`Program`] over input that arrives a piece at a time, started with
+/// [`Program::stream`].
+///
+/// The program runs on a background thread. Each record is processed as soon as all of it has
+/// been pushed (or, if `RS` is a regular expression, once the input after it has been pushed too),
+/// and each line the program prints can be read with [`Stream::output`] as soon as it is printed.
+/// Neither input nor output is bounded, so callers should read output as they go.
+///
+/// Dropping a `Stream` ends its input without waiting for the program to finish.
+pub struct Stream {
+    input: Sender<Vec<u8>>,
+    output: Receiver<Option<Vec<u8>>>,
+    run: thread::JoinHandle<Result<i32, Error>>,
+}
+
+impl Stream {
+    /// Push `data` to the end of the program's input. It need not end at a record boundary.
+    ///
+    /// Returns false if the program has stopped running, in which case `data` is discarded.
+    pub fn push(&self, data: impl AsRef<[u8]>) -> bool {
+        self.input.send(data.as_ref().to_vec()).is_ok()
+    }
+
+    /// Push `record` to the end of the program's input, followed by a newline. This is the same as
+    /// `push`ing the record and the newline, and is only useful if `RS` has its default value.
+    pub fn push_record(&self, record: impl AsRef<[u8]>) -> bool {
+        let record = record.as_ref();
+        let mut data = Vec::with_capacity(record.len() + 1);
+        data.extend_from_slice(record);
+        data.push(b'\n');
+        self.push(data)
+    }
+
+    /// Everything the program has written to its standard output since the last call to
+    /// `output`. This does not wait for the program to print anything, and may return nothing.
+    pub fn output(&self) -> Vec<u8> {
+        self.output.try_iter().flatten().flatten().collect()
+    }
+
+    /// End the program's input and wait for it to finish, returning its exit status along with
+    /// any output that has not been read with [`Stream::output`].
+    pub fn finish(self) -> Result<(i32, Vec<u8>), Error> {
+        let Stream { input, output, run } = self;
+        drop(input);
+        let status = match run.join() {
+            Ok(res) => res?,
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+        Ok((status, output.try_iter().flatten().flatten().collect()))
+    }
+}
+
+/// Reads the chunks of input pushed to a `Stream`, until the `Stream` is finished or dropped.
+struct ChannelReader {
+    chunks: Receiver<Vec<u8>>,
+    cur: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Returning 0 would end the input, so wait for the next chunk.
+        while self.pos == self.cur.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.cur = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = (&self.cur[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Write chunks of output from `receiver` to `w` until the end of the output is signaled with
+/// `None`. Returning early on an error drops `receiver`, which makes further writes to the channel
+/// fail.
+fn write_output(receiver: Receiver<Option<Vec<u8>>>, w: &mut impl Write) -> io::Result<()> {
+    while let Ok(Some(chunk)) = receiver.recv() {
+        w.write_all(&chunk)?;
+    }
+    w.flush()
+}
+
+/// A `FileFactory` that sends standard output to the thread running `write_output`, and opens
+/// other files with `files`, or on the local file system if it is not set.
+#[derive(Clone)]
+struct OutputFactory {
+    stdout: Sender<Option<Vec<u8>>>,
+    files: Option<OutputOpener>,
+    line_buffered: bool,
+    limit: Option<Arc<OutputLimit>>,
+}
+
+/// The number of bytes a run of a program may write, shared by all of its outputs.
+struct OutputLimit {
+    max: u64,
+    // The number of bytes written, plus those of any writes that were refused.
+    written: AtomicU64,
+}
+
+impl OutputLimit {
+    /// Claim space for up to `len` more bytes of output, returning how many of them can be written.
+    fn claim(&self, len: usize) -> io::Result<usize> {
+        if len == 0 {
+            return Ok(0);
+        }
+        let prev = self.written.fetch_add(len as u64, Ordering::Relaxed);
+        if prev >= self.max {
+            return Err(io::Error::other("output limit exceeded"));
+        }
+        Ok(cmp::min(len as u64, self.max - prev) as usize)
+    }
+
+    /// Return space claimed for `len` bytes that were not written.
+    fn unclaim(&self, len: usize) {
+        self.written.fetch_sub(len as u64, Ordering::Relaxed);
+    }
+
+    fn exceeded(&self) -> bool {
+        self.written.load(Ordering::Relaxed) > self.max
+    }
+}
+
+/// A writer that counts the bytes written to `inner` against `limit`.
+struct Limited<W> {
+    inner: W,
+    limit: Option<Arc<OutputLimit>>,
+}
+
+impl<W: Write> Write for Limited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return self.inner.write(buf),
+        };
+        let claimed = limit.claim(buf.len())?;
+        let res = self.inner.write(&buf[..claimed]);
+        limit.unclaim(claimed - *res.as_ref().unwrap_or(&0));
+        res
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ChannelWriter(Sender<Option<Vec<u8>>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.send(Some(buf.to_vec())) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "output is no longer being read",
+            )),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl runtime::writers::FileFactory for OutputFactory {
+    type Output = Limited<Box<dyn Write + Send>>;
+    type Stdout = Limited<ChannelWriter>;
+    fn build(&self, path: &str, spec: FileSpec) -> io::Result<Self::Output> {
+        let inner = match &self.files {
+            Some(files) => files(path, matches!(spec, FileSpec::Append))?,
+            None => runtime::writers::open_file(path, spec, /*level=*/ None)?,
+        };
+        Ok(Limited {
+            inner,
+            limit: self.limit.clone(),
+        })
+    }
+    fn stdout(&self) -> Limited<ChannelWriter> {
+        Limited {
+            inner: ChannelWriter(self.stdout.clone()),
+            limit: self.limit.clone(),
+        }
+    }
+    fn line_buffered(&self) -> bool {
+        self.line_buffered
+    }
+}
@@ -8,7 +8,7 @@ use crate::{
     arena::Arena,
     ast,
     cfg::{self, Escaper},
-    codegen::{self, intrinsics::IntoRuntime},
+    codegen::intrinsics::IntoRuntime,
     common::{ExecutionStrategy, Result},
     compile, lexer,
     parsing::syntax,
@@ -16,7 +16,7 @@ use crate::{
     runtime::{
         self,
         splitter::{
-            batch::{ByteReader, CSVReader, InputFormat},
+            batch::{CSVReader, InputFormat},
             regex::RegexSplitter,
         },
         writers::testing::FakeFs,
@@ -24,6 +24,11 @@ use crate::{
     },
     types::{self, get_types},
 };
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
+use crate::{
+    codegen,
+    runtime::splitter::batch::{byte_splittable, ByteReader},
+};
 
 use cfg_if::cfg_if;
 use hashbrown::HashMap;
@@ -47,6 +52,7 @@ fn split_stdin(
     )
     .into_iter()
 }
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
 macro_rules! with_reader {
     ($report:expr, $inp:expr, |$id:ident| $body:expr) => {
         match $report {
@@ -61,7 +67,7 @@ macro_rules! with_reader {
                 // TODO: unify this code with the code in main.
                 let field_sep = field_sep.unwrap_or(b" ");
                 let record_sep = record_sep.unwrap_or(b"\n");
-                if field_sep.len() == 1 && record_sep.len() == 1 {
+                if byte_splittable(field_sep, record_sep) {
                     if field_sep == b" " && record_sep == b"\n" {
                         let $id = simulate_stdin_whitespace($inp);
                         $body
@@ -78,6 +84,7 @@ macro_rules! with_reader {
     };
 }
 
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
 fn simulate_stdin_singlechar(
     field_sep: u8,
     record_sep: u8,
@@ -94,6 +101,7 @@ fn simulate_stdin_singlechar(
     )
 }
 
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
 fn simulate_stdin_whitespace(inp: impl Into<String>) -> impl IntoRuntime + runtime::LineReader {
     ByteReader::new_whitespace(
         split_stdin(inp.into()),
@@ -150,9 +158,13 @@ type ProgResult<'a> = Result<(
     HashMap<&'a str, compile::Ty>, /* type info */
 )>;
 
+#[cfg(any(feature = "llvm_backend", feature = "cranelift_backend"))]
 const CODEGEN_CONFIG: codegen::Config = codegen::Config {
     opt_level: 0,
     num_workers: 1,
+    output_order: crate::common::OutputOrder::Any,
+    ieee_division: false,
+    key_order: runtime::KeyOrder::Unordered,
 };
 
 pub(crate) fn run_program<'a>(
@@ -217,6 +229,9 @@ cfg_if! {
                     llvm::Config {
                         opt_level: CODEGEN_CONFIG.opt_level,
                         num_workers: strat.num_workers(),
+                        output_order: crate::common::OutputOrder::Any,
+                        ieee_division: false,
+    key_order: runtime::KeyOrder::Unordered,
                     },
                     Default::default(),
                 )?;
@@ -234,6 +249,7 @@ cfg_if! {
     }
 }
 
+#[cfg(feature = "cranelift_backend")]
 pub(crate) fn run_cranelift(
     prog: &str,
     stdin: impl Into<String>,
@@ -260,6 +276,9 @@ pub(crate) fn run_cranelift(
             codegen::Config {
                 opt_level: CODEGEN_CONFIG.opt_level,
                 num_workers: strat.num_workers(),
+                output_order: crate::common::OutputOrder::Any,
+                ieee_division: false,
+                key_order: runtime::KeyOrder::Unordered,
             },
             Default::default(),
         )?;
@@ -553,6 +572,7 @@ mod tests {
                     }
                 }
 
+                #[cfg(feature = "cranelift_backend")]
                 #[test]
                 fn cranelift() {
                     match run_cranelift($e, $inp, $esc, $csv, ExecutionStrategy::Serial) {
@@ -640,6 +660,15 @@ mod tests {
         assert_eq!(s2, used_fields(p3).unwrap());
     }
 
+    #[test]
+    fn used_fields_assignments() {
+        // Assigning a field or NF rebuilds $0 from every field, so none can be projected out.
+        let p1 = r#"{ $5 = $1; print }"#;
+        assert_eq!(FieldSet::all(), used_fields(p1).unwrap());
+        let p2 = r#"{ NF = 2; print $1 }"#;
+        assert_eq!(FieldSet::all(), used_fields(p2).unwrap());
+    }
+
     #[test]
     fn used_fields_functions() {
         // We handle functions pretty imprecisely at the moment, but basic stuff does work.
@@ -706,7 +735,7 @@ mod tests {
     test_program!(
         map_join_points_1,
         r#"BEGIN { if (1) { m1[1]=3; y=m1; } else { m2[2]=5; y=m2}; print y[1], y[2]; }"#,
-        "3 0\n"
+        "3 \n"
     );
 
     test_program!(
@@ -731,9 +760,7 @@ mod tests {
     test_program!(
         basic_csv_render,
         r#"BEGIN { print "hi", "there"; print "comma,\"in field","and a\ttab"; }"#,
-r#"hi,there
-"comma,""in field","and a\ttab"
-"#,
+        "hi,there\n\"comma,\"\"in field\",\"and a\ttab\"\n",
         @out_fmt Escaper::CSV
     );
 
@@ -839,6 +866,53 @@ it has one more line"#
         @input "1,a 2,b 3,c 4,d 5,e"
     );
 
+    test_program!(
+        json_join,
+        r#"BEGIN { FS = ";" } { print join_json(2, 4);}"#,
+        concat!(r#"["2,b","say \"hi\"","x\ty"]"#, "\n"),
+        @input "1,a;2,b;say \"hi\";x\ty"
+    );
+
+    test_program!(
+        json_render,
+        r#"{ split($0, fields, " "); r["first"] = $1; r["n"] = NF; c[$1] += 1.5;
+             print to_json(fields), to_json(r), to_json($2), to_json(NF) }
+        END { print to_json(c), to_json(missing), to_json(1/3) }"#,
+        concat!(
+            r#"["a","b","a"] {"first":"a","n":"3"} "b" 3"#,
+            "\n",
+            r#"["e"] {"first":"e","n":"1"} "" 1"#,
+            "\n",
+            r#"{"a":1.5,"e":1.5} "" 0.3333333333333333"#,
+            "\n"
+        ),
+        @input "a b a\ne"
+    );
+
+    test_program!(
+        join_array,
+        r#"{ n = split($0, fields, ","); print join(fields, "-"), join(fields); }
+        END {
+            OFS = ":"; print join(fields);
+            delete fields[2]; print join(fields, ",");
+            nums[1] = 1; nums[2] = nums[1] / 4; nums[3] = "x"; print join(nums, " ");
+            single["k"] = "v"; print join(single, ",", 1), "[" join(empty, ",") "]";
+        }"#,
+        "a-b-c a b c\nx-y x y\nx:y\nx\n1 0.25 x\nv:[]\n",
+        @input "a,b,c\nx,y"
+    );
+
+    test_program!(
+        join_user_defined,
+        r#"function join(a, start, end, sep,    res, i) {
+            res = a[start];
+            for (i = start + 1; i <= end; i++) res = res sep a[i];
+            return res;
+        }
+        BEGIN { n = split("x y z", parts); print join(parts, 1, n, "-"); }"#,
+        "x-y-z\n"
+    );
+
     test_program!(
         raw_getline,
         r#"{ print "even", $0; getline; print "odd", $0; }"#,
@@ -912,6 +986,68 @@ print w,z;
         @input "1 2 3 4 5 6"
     );
 
+    test_program!(
+        fields_split_lazily,
+        r#"BEGIN { FS = "::"; OFS = "-" }
+        { print $1; i = 4; print $i, NF, $2; $5 = "e"; print; $0 = "x::y"; print $2, NF }"#,
+        "a\nd-5-b\na-b-c-d-e\ny-2\n\n-0-\n----e\ny-2\n",
+        @input "a::b::c::d::\n\n"
+    );
+
+    test_program!(
+        assign_nf,
+        r#"BEGIN { OFS = "-" }
+        { NF = 2; print; print NF; NF = 4; print; $3 = "c"; NF = 3; print; $0 = "x y"; print NF }"#,
+        "a-b\n2\na-b--\na-b-c\n2\n",
+        @input "a b c d e\n"
+    );
+
+    test_program!(
+        assign_field_then_nf,
+        r#"BEGIN { OFS = "-" } { $3 = "Z"; NF = 2; print; NF = 0; print; print NF, $1 }"#,
+        "a-b\n\n0-\n",
+        @input "a b c d\n"
+    );
+
+    test_program!(
+        string_to_number_prefixes,
+        r#"BEGIN { FS = "," }
+        { print $1 + 1, $2 * 2, substr("abcdef", $3, 2), $4 + 0, $5 + 1, ($1 < 10) }"#,
+        "13 -70 cd 1 1.5 0\n",
+        @input " 12,\t-3.5e1x,3e0,1e,+.5.\n"
+    );
+
+    test_program!(
+        loop_invariants,
+        r#"function f(n,   i, t) { for (i = 0; i < n; i++) t = t f(n - 1); return t "x" }
+        BEGIN { x = "b+"; k = "2" }
+        $0 ~ ("a" x "c") { n++ }
+        { for (i = 1; i <= NF; i++) s += $i * k; }
+        NR == 2 { x = "z" }
+        END { print n, s, f(3) }"#,
+        "1 20 xxxxxxxxxxxxxxxx\n",
+        @input "abbc 1\nac 2\nabc 3 4\n"
+    );
+
+    test_program!(
+        division_in_untaken_loop,
+        r#"{ x = $1; while (n > 0) { y = 1 / x; z = 2 % x; n--; } print "ok" }"#,
+        "ok\n",
+        @input "0\n"
+    );
+
+    test_program!(
+        integer_overflow,
+        r#"BEGIN {
+        x = 9223372036854775807; x++; print x;
+        y = -9223372036854775807; y = y - 2; print y;
+        z = 3037000500; print z * z, -(x);
+        m["k"] = 9223372036854775807; m["k"] += 1; print m["k"];
+        print int(1e300), int(-1e300), int(log(-1));
+        }"#,
+        "-9223372036854775808\n9223372036854775807\n-9223372036709301616 -9223372036854775808\n-9223372036854775808\n9223372036854775807 -9223372036854775808 0\n"
+    );
+
     test_program!(
         map_ops_simple,
         r#"BEGIN {
@@ -1281,6 +1417,57 @@ this as well"#
         "5 5 2\n0 0 -1\n"
     );
 
+    test_program!(
+        ignorecase,
+        r#"{
+        print ($1 == $2), ($1 < "FOP"), index($0, "FOO")
+        IGNORECASE = 1
+        print ($1 == $2), ($1 < "FOP"), index($0, "FOO"), ("abc" == "ABC")
+        IGNORECASE = 0
+        }"#,
+        "0 0 0\n1 1 1 1\n",
+        @input "Foo foo"
+    );
+
+    test_program!(
+        ignorecase_regexes,
+        r#"{
+        x = $0
+        print ($0 ~ /hello/), match($0, "l+"), split($0, a, /o/), gsub(/l/, "x", x), x
+        IGNORECASE = 1
+        x = $0
+        print ($0 ~ /hello/), match($0, "l+"), split($0, a, /o/), gsub(/l/, "x", x), x
+        IGNORECASE = 0
+        print ($0 ~ /hello/), ($0 ~ "HEL+O")
+        }"#,
+        "0 0 1 0 HELLO\n1 3 2 2 HExxO\n0 1\n",
+        @input "HELLO"
+    );
+
+    test_program!(
+        ignorecase_regex_rules,
+        r#"BEGIN { IGNORECASE = 1 }
+        /a/ { print "a", $0 }
+        /b/ { print "b", $0 }
+        /c/ { print "c", $0 }
+        /d/ { print "d", $0 }"#,
+        "a A\nb xB\nc C\nd D\n",
+        @input "A\nxB\nC\nD"
+    );
+
+    test_program!(
+        match_capture_groups,
+        r#"BEGIN {
+        x=match("key: some value", /([a-z]+): ([a-z]+)( [0-9]+)?/, m)
+        print x, RSTART, RLENGTH, m[0]
+        print m[1], m[1, "start"], m[1, "length"], m[2], m[2, "start"], m[2, "length"]
+        print ((3 in m) ? "yes" : "no"), length(m)
+        y=match("no digits", /[0-9]+/, m)
+        print y, RSTART, RLENGTH, length(m)
+        }"#,
+        "1 1 9 key: some\nkey 1 3 some 6 4\nno 9\n0 0 -1 0\n"
+    );
+
     test_program!(degenerate_map, r#"BEGIN { print m[1]; }"#, "\n");
 
     test_program!(
@@ -1295,6 +1482,43 @@ this as well"#
         "b there 1 snow banana 2\n"
     );
 
+    test_program!(
+        posix_regex_syntax,
+        r#"/{b}/ { print "brace"; } /^*/ { print "star"; } /[[]y[]]/ { print "bracket"; }
+        /\/baz/ { print "slash"; } $0 ~ "\\ywor" { print "word"; }
+        { print gsub(/o+?/, "0"); }"#,
+        "brace\n5\nstar\n6\nbracket\n6\nslash\n10\nword\n10\n",
+        @input "a{b}\n*star\nx[y]z\nfoo.bar/baz\nhello world\n"
+    );
+
+    test_program!(
+        literal_regex_patterns,
+        r#"/a\.b/ { print "dot", $0; } /x/ { print "x", $0; } $0 ~ "o b" { print "space"; }
+        /é/ { print "utf8"; } /^fo/ { print "prefix"; } /o*b/ { print "regex"; }"#,
+        "dot a.b\nregex\nx axb\nregex\nspace\nprefix\nregex\nutf8\n",
+        @input "a.b\naxb\nfoo bar\ncafé\n"
+    );
+
+    test_program!(
+        regex_set_rules,
+        r#"/hello/ { print "h", $0; } /foo/ { gsub(/foo/, "hello"); print "f", $0; }
+        /baz/ { print "b"; } $0 ~ /qu+x/ { print "q"; } /hello/ { print "h2", $0; }
+        /^$/ { next; } /o/ { print "o"; } /z/ { print "z"; } /q/ { print "q2"; } /l/ { print "l"; }"#,
+        "h hello world\nh2 hello world\no\nl\nf hello bar\nh2 hello bar\no\nl\nb\nq\nz\nq2\n",
+        @input "hello world\nfoo bar\n\nbaz quux\n"
+    );
+
+    test_program!(
+        constant_regex_substitutions,
+        r#"{
+        n=gsub(/o/, "0"); m=sub("l+", "L");
+        p="o"; k=gsub(p, "0", $2);
+        print n, m, k, $0, match($0, /L/), RSTART, RLENGTH, ($0 ~ /w/);
+        }"#,
+        "2 1 0 heL0 w0rld 3 3 1 1\n2 0 0 f00 bar 0 0 -1 0\n",
+        @input "hello world\nfoo bar\n"
+    );
+
     test_program!(
         map_substitutions,
         r#"BEGIN {
@@ -1332,6 +1556,17 @@ this as well"#
         "2\n3\n1\n256\n1\n-1\n3\n"
     );
 
+    test_program!(
+        bitwise_operand_ranges,
+        r#"BEGIN {
+        print and(7.9, "14 apples"), xor(-1, 5);
+        print lshift(1, 63), lshift(1, 64), lshift(3, -1);
+        print rshift(-8, 70), rshift(8, 64), rshift(8, -2);
+        print rshiftl(-1, 63), rshiftl(-1, 64), rshiftl(1, -3);
+        }"#,
+        "6 -6\n-9223372036854775808 0 1\n-1 0 32\n1 0 8\n"
+    );
+
     test_program!(
         column_substitutions,
         r#"{
@@ -1470,6 +1705,119 @@ function do_something(v) {
         @input "hello\n"
     );
 
+    test_program!(
+        constant_folding,
+        r#"BEGIN {
+    x = 1 + 2 * 3; y = x ""; z = y + 1; w = 7 % 0.5;
+    if (2 > 1) print x, y, z, w;
+    if ("a" < "b") print "yes"; else print "no";
+}"#,
+        "7 7 8 0\nyes\n"
+    );
+
+    test_program!(
+        superinstructions,
+        r#"{ if ($2 < $3) lt++; else ge++; if (NR != 2) odd++; m[$1] = m[$1] + $2; k = $1; m[k] = m[k] + 1; }
+    END { print lt, ge, odd; print m["a"], m["b"]; }"#,
+        "1 2 2\n4 4\n",
+        @input "a 1 2\nb 3 2\na 1 1\n"
+    );
+
+    test_program!(
+        constant_map_keys,
+        r#"function add(arr, k) { arr[k] += 10; }
+    { m["a"] += $2; m[$1]++; s["x"] = $1; t["n"]++; }
+    END { add(m, "b"); m["b"] += 1; n = 0; for (k in m) n++; print n, m["a"], m["b"], s["x"], t["n"]; }"#,
+        "2 6 12 a 3\n",
+        @input "a 1\nb 1\na 2\n"
+    );
+
+    test_program!(
+        accumulate_string,
+        r#"BEGIN { for (i = 0; i < 500000; i++) s = s "x" i; t = s; s = s "!"; t = t "?";
+    print length(s), substr(s, 1, 12), substr(s, length(s) - 6), substr(t, length(t) - 6); }"#,
+        "3388891 x0x1x2x3x4x5 499999! 499999?\n"
+    );
+
+    test_program!(
+        non_utf8_patterns,
+        r#"BEGIN { s = "a\xfeb\xfec"; n = split(s, a, "\xfe"); p = "b\xfe";
+    if (s ~ p) m = "match"; if (s ~ "\xff") m = m " wrong"; gsub("\xfe", "-", s);
+    print n, a[2], m, s; }"#,
+        "3 b match a-b-c\n"
+    );
+
+    test_program!(
+        strnum_comparisons,
+        r#"{ split($0, a); x = $1; getline y;
+    print ($1 == $2), (a[1] == a[2]), (x > $3), ($3 > a[1]), ($2 == "10"), ($4 == $1), ($1 == y); }"#,
+        "1 1 1 0 0 0 1\n",
+        @input "10 10.0 9 x\n10.00\n"
+    );
+
+    test_program!(
+        strnum_number_comparisons,
+        r#"{ print ($1 == 0), ($1 < 1), ($1 > 1), (0.5 < $1), ($2 == 10), ($2 < 9.5); }"#,
+        "0 0 1 1 1 0\n",
+        @input "abc 1e1\n"
+    );
+
+    test_program!(
+        getline_eof_keeps_var,
+        r#"BEGIN { x = "old"; r1 = (getline x); r2 = (getline x); r3 = (getline y); print r1, r2, r3, x, "[" y "]"; }"#,
+        "1 0 0 a []\n",
+        @input "a\n"
+    );
+
+    test_program!(
+        strnum_assigned_columns,
+        r#"{ $2 = "10.0"; print ($1 == $2), ($1 < $2); }"#,
+        "0 1\n",
+        @input "10 10\n"
+    );
+
+    test_program!(
+        missing_numeric_elements,
+        r#"{ c[$1]++; f[$1] += 0.5; }
+    END { print "[" c["a"] "]", "[" c["zz"] "]", "[" f["zz"] "]", c["zz"] + 1, length(c);
+    print "[" f["a"] "]", ("zz" in c); }"#,
+        "[2] [] [] 1 3\n[1] 1\n",
+        @input "a\nb\na\n"
+    );
+
+    test_program!(
+        missing_numeric_element_read_twice,
+        r#"BEGIN { c["k"] = 1; print "[" c["q"] "]" "[" c["q"] "]", length(c), ("q" in c);
+    x = c["r"] + 1; print "[" c["r"] "]"; c["q"] += 0; print "[" c["q"] "]"; }"#,
+        "[][] 2 1\n[]\n[0]\n"
+    );
+
+    test_program!(
+        mutate_during_iteration,
+        r#"BEGIN { m[1]; m[2]; m[3];
+    for (k in m) { n++; s += k; if (k == 2) { delete m[1]; delete m[3]; } m[k + 10]; }
+    print n, s, length(m), (1 in m), (12 in m); }"#,
+        "3 6 4 0 1\n"
+    );
+
+    test_program!(
+        array_autovivification,
+        r#"BEGIN {
+    if ("x" in a) print "bad"; if (0) c["q"] = 1;
+    print length(a);
+    y = a["x"]; print "[" c["k"] "]", length(a), length(c), ("x" in a), ("k" in c);
+    if ("z" in a) print "bad"; print length(a);
+    a["w"]; print length(a), ("w" in a);
+}"#,
+        "0\n[] 1 1 1 1\n1\n2 1\n"
+    );
+
+    test_program!(
+        increment_string_array,
+        r#"BEGIN { split("1 x", a); a[1] += 2.5; a[2] += 1; a[3] += 1; print a[1], a[2], a[3] }"#,
+        "3.5 1 1\n"
+    );
+
     // TODO test more operators, consider more edge cases around functions
 }
 
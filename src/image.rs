@@ -0,0 +1,915 @@
+//! A stable on-disk format for compiled bytecode programs.
+//!
+//! An `Image` holds everything the interpreter needs to run a program: the (optimized) bytecode
+//! for each function, the entry points for each stage, register counts, and the small amount of
+//! static analysis that is consulted when setting up input. Writing an image to disk and reading
+//! it back skips parsing, type inference and the bytecode optimization passes entirely.
+//!
+//! The format is a magic number and version followed by a flat little-endian encoding of the
+//! program. Every instruction is written as a one-byte opcode followed by its operands. Opcodes
+//! are assigned explicitly in the table below rather than derived from the order of the `Instr`
+//! enum, so reordering variants does not change the format; adding, removing or changing the
+//! operands of an instruction does, and requires bumping `VERSION`.
+//!
+//! The image also carries a `SourceMap`, so that errors raised by a program loaded from disk still
+//! point back to its source.
+//!
+//! String constants in a decoded image borrow from the buffer it was read from. Regular
+//! expressions are stored as their source and recompiled on load.
+//!
+//! Decoding an image only checks that it is well-formed; whether the program is safe to run is
+//! checked by `verify` when the interpreter is constructed.
+use crate::ast::Rule;
+use crate::builtins::{Bitwise, FloatFunc, Variable};
+use crate::bytecode::{Cmp, ConstKey, Instr, Interp, Label, Reg};
+use crate::cfg::SepAssign;
+use crate::common::{Aggregation, FileSpec, Result, Stage};
+use crate::compile::{self, Ty, NUM_TYPES};
+use crate::lexer::SourceLoc;
+use crate::pushdown::FieldSet;
+use crate::runtime::{self, Float, Str, UniqueStr};
+use crate::source_map::SourceMap;
+
+use regex::bytes::{Regex, RegexSet};
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"FRAWKBC\0";
+pub(crate) const VERSION: u32 = 11;
+
+/// A compiled program, ready to be handed to the interpreter or written to disk.
+pub(crate) struct Image<'a> {
+    pub(crate) instrs: Vec<Vec<Instr<'a>>>,
+    pub(crate) stage: Stage<usize>,
+    pub(crate) regs: [usize; NUM_TYPES],
+    pub(crate) used_fields: FieldSet,
+    pub(crate) named_columns: Option<Vec<Arc<[u8]>>>,
+    pub(crate) sep_assign: SepAssign<'a>,
+    pub(crate) slot_aggs: Vec<((Ty, usize), Aggregation)>,
+    pub(crate) source_map: SourceMap<'a>,
+}
+
+impl<'a> Image<'a> {
+    pub(crate) fn into_interp<LR: runtime::LineReader>(
+        self,
+        reader: LR,
+        ff: impl runtime::writers::FileFactory,
+        num_workers: usize,
+    ) -> Result<Interp<'a, LR>> {
+        let regs = self.regs;
+        let mut interp = Interp::new(
+            self.instrs,
+            self.stage,
+            num_workers,
+            |ty| regs[ty as usize],
+            reader,
+            ff,
+            &self.used_fields,
+            self.named_columns
+                .as_ref()
+                .map(|cs| cs.iter().map(|c| &c[..]).collect()),
+        )?;
+        interp.set_slot_aggs(self.slot_aggs.into_iter().collect());
+        interp.set_source_map(self.source_map);
+        Ok(interp)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        w.extend_from_slice(MAGIC);
+        VERSION.encode(&mut w);
+        for count in self.regs.iter() {
+            count.encode(&mut w);
+        }
+        self.stage.encode(&mut w);
+        self.used_fields.bits().encode(&mut w);
+        self.named_columns.encode(&mut w);
+        self.sep_assign.encode(&mut w);
+        self.slot_aggs.encode(&mut w);
+        self.source_map.locs.encode(&mut w);
+        self.source_map.rules.encode(&mut w);
+        self.instrs.len().encode(&mut w);
+        for func in self.instrs.iter() {
+            func.len().encode(&mut w);
+            for inst in func.iter() {
+                encode_instr(inst, &mut w);
+            }
+        }
+        w
+    }
+
+    pub(crate) fn decode(bs: &'a [u8]) -> Result<Image<'a>> {
+        let mut r = Reader(bs);
+        if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return err!("not a frawk bytecode image");
+        }
+        let version = u32::decode(&mut r)?;
+        if version != VERSION {
+            return err!(
+                "unsupported bytecode image version {} (expected {})",
+                version,
+                VERSION
+            );
+        }
+        let mut regs = [0; NUM_TYPES];
+        for count in regs.iter_mut() {
+            *count = Decode::decode(&mut r)?;
+        }
+        let stage = Decode::decode(&mut r)?;
+        let used_fields = FieldSet::from_bits(Decode::decode(&mut r)?);
+        let named_columns = Decode::decode(&mut r)?;
+        let sep_assign = Decode::decode(&mut r)?;
+        let slot_aggs = Decode::decode(&mut r)?;
+        let source_map = SourceMap {
+            locs: Decode::decode(&mut r)?,
+            rules: Decode::decode(&mut r)?,
+        };
+        let nfuncs = r.len()?;
+        let mut instrs = Vec::with_capacity(nfuncs);
+        for _ in 0..nfuncs {
+            let ninstrs = r.len()?;
+            let mut func = Vec::with_capacity(ninstrs);
+            for _ in 0..ninstrs {
+                func.push(decode_instr(&mut r)?);
+            }
+            instrs.push(func);
+        }
+        if !r.0.is_empty() {
+            return err!("{} trailing bytes in bytecode image", r.0.len());
+        }
+        Ok(Image {
+            instrs,
+            stage,
+            regs,
+            used_fields,
+            named_columns,
+            sep_assign,
+            slot_aggs,
+            source_map,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.0.len() {
+            return err!("unexpected end of bytecode image");
+        }
+        let (res, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(res)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut res = [0; N];
+        res.copy_from_slice(self.bytes(N)?);
+        Ok(res)
+    }
+    // Length prefixes are bounded by the number of bytes remaining, so a corrupt length cannot
+    // trigger a huge allocation.
+    fn len(&mut self) -> Result<usize> {
+        let len = usize::decode(self)?;
+        if len > self.0.len() {
+            return err!("invalid length {} in bytecode image", len);
+        }
+        Ok(len)
+    }
+}
+
+trait Encode {
+    fn encode(&self, w: &mut Vec<u8>);
+}
+
+trait Decode<'a>: Sized {
+    fn decode(r: &mut Reader<'a>) -> Result<Self>;
+}
+
+macro_rules! encode_le {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, w: &mut Vec<u8>) {
+                w.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+        impl<'a> Decode<'a> for $t {
+            fn decode(r: &mut Reader<'a>) -> Result<$t> {
+                Ok(<$t>::from_le_bytes(r.array()?))
+            }
+        }
+    )*};
+}
+
+encode_le!(u8, u32, u64, i64);
+
+impl Encode for usize {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (*self as u64).encode(w)
+    }
+}
+impl<'a> Decode<'a> for usize {
+    fn decode(r: &mut Reader<'a>) -> Result<usize> {
+        match usize::try_from(u64::decode(r)?) {
+            Ok(u) => Ok(u),
+            Err(_) => err!("integer out of range in bytecode image"),
+        }
+    }
+}
+
+impl Encode for Float {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.to_bits().encode(w)
+    }
+}
+impl<'a> Decode<'a> for Float {
+    fn decode(r: &mut Reader<'a>) -> Result<Float> {
+        Ok(Float::from_bits(u64::decode(r)?))
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (*self as u8).encode(w)
+    }
+}
+impl<'a> Decode<'a> for bool {
+    fn decode(r: &mut Reader<'a>) -> Result<bool> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => err!("invalid boolean {} in bytecode image", b),
+        }
+    }
+}
+
+impl<T> Encode for Reg<T> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (self.index() as u32).encode(w)
+    }
+}
+impl<'a, T> Decode<'a> for Reg<T> {
+    fn decode(r: &mut Reader<'a>) -> Result<Reg<T>> {
+        let reg = u32::decode(r)?;
+        if reg == compile::UNUSED || reg == compile::NULL_REG {
+            return err!("invalid register {} in bytecode image", reg);
+        }
+        Ok(reg.into())
+    }
+}
+
+impl Encode for Label {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.0.encode(w)
+    }
+}
+impl<'a> Decode<'a> for Label {
+    fn decode(r: &mut Reader<'a>) -> Result<Label> {
+        Ok(Label(Decode::decode(r)?))
+    }
+}
+
+impl Encode for Ty {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (*self as u32).encode(w)
+    }
+}
+impl<'a> Decode<'a> for Ty {
+    fn decode(r: &mut Reader<'a>) -> Result<Ty> {
+        let ty = u32::decode(r)?;
+        match Ty::try_from(ty) {
+            Ok(ty) => Ok(ty),
+            Err(()) => err!("invalid type {} in bytecode image", ty),
+        }
+    }
+}
+
+impl Encode for Variable {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (*self as u8).encode(w)
+    }
+}
+impl<'a> Decode<'a> for Variable {
+    fn decode(r: &mut Reader<'a>) -> Result<Variable> {
+        let var = u8::decode(r)?;
+        match Variable::try_from(var as usize) {
+            Ok(var) => Ok(var),
+            Err(()) => err!("invalid variable {} in bytecode image", var),
+        }
+    }
+}
+
+impl Encode for FileSpec {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (*self as u8).encode(w)
+    }
+}
+impl<'a> Decode<'a> for FileSpec {
+    fn decode(r: &mut Reader<'a>) -> Result<FileSpec> {
+        let spec = u8::decode(r)?;
+        match FileSpec::try_from(spec as i64) {
+            Ok(spec) => Ok(spec),
+            Err(_) => err!("invalid output specifier {} in bytecode image", spec),
+        }
+    }
+}
+
+// Fieldless enums without a conversion from an integer elsewhere in the crate are encoded as
+// their index in a table of all variants.
+macro_rules! encode_table {
+    ($t:ident, [$($v:ident),*]) => {
+        impl Encode for $t {
+            fn encode(&self, w: &mut Vec<u8>) {
+                (*self as u8).encode(w)
+            }
+        }
+        impl<'a> Decode<'a> for $t {
+            fn decode(r: &mut Reader<'a>) -> Result<$t> {
+                const TABLE: &[$t] = &[$($t::$v),*];
+                let ix = u8::decode(r)?;
+                match TABLE.get(ix as usize) {
+                    Some(v) => Ok(*v),
+                    None => err!("invalid {} {} in bytecode image", stringify!($t), ix),
+                }
+            }
+        }
+    };
+}
+
+encode_table!(Cmp, [Lt, Gt, Lte, Gte, Eq, Neq]);
+encode_table!(Aggregation, [Sum, Min, Max, Concat]);
+encode_table!(
+    FloatFunc,
+    [Cos, Sin, Atan, Atan2, Log, Log2, Log10, Sqrt, Exp]
+);
+encode_table!(
+    Bitwise,
+    [
+        Complement,
+        And,
+        Or,
+        LogicalRightShift,
+        ArithmeticRightShift,
+        LeftShift,
+        Xor
+    ]
+);
+
+impl<'a> Decode<'a> for &'a [u8] {
+    fn decode(r: &mut Reader<'a>) -> Result<&'a [u8]> {
+        let len = r.len()?;
+        r.bytes(len)
+    }
+}
+
+impl<'b> Encode for UniqueStr<'b> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.clone_str().with_bytes(|bs| bs.encode(w))
+    }
+}
+impl<'a> Decode<'a> for UniqueStr<'a> {
+    fn decode(r: &mut Reader<'a>) -> Result<UniqueStr<'a>> {
+        let bs: &'a [u8] = Decode::decode(r)?;
+        Ok(Str::from(bs).into())
+    }
+}
+
+impl<'b> Encode for ConstKey<'b> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.key.encode(w)
+    }
+}
+impl<'a> Decode<'a> for ConstKey<'a> {
+    fn decode(r: &mut Reader<'a>) -> Result<ConstKey<'a>> {
+        // The hash depends on keys chosen at startup, so it is recomputed rather than stored.
+        Ok(ConstKey::new(Decode::decode(r)?))
+    }
+}
+
+impl Encode for Arc<[u8]> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (**self).encode(w)
+    }
+}
+impl<'a> Decode<'a> for Arc<[u8]> {
+    fn decode(r: &mut Reader<'a>) -> Result<Arc<[u8]>> {
+        let bs: &'a [u8] = Decode::decode(r)?;
+        Ok(bs.into())
+    }
+}
+
+impl Encode for Arc<Regex> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.as_str().as_bytes().encode(w)
+    }
+}
+impl<'a> Decode<'a> for Arc<Regex> {
+    fn decode(r: &mut Reader<'a>) -> Result<Arc<Regex>> {
+        let bs: &'a [u8] = Decode::decode(r)?;
+        let pat = match std::str::from_utf8(bs) {
+            Ok(pat) => pat,
+            Err(e) => return err!("invalid regex in bytecode image: {}", e),
+        };
+        match Regex::new(pat) {
+            Ok(re) => Ok(Arc::new(re)),
+            Err(e) => err!("invalid regex in bytecode image: {}", e),
+        }
+    }
+}
+
+impl Encode for Arc<RegexSet> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        let pats: Vec<&[u8]> = self.patterns().iter().map(|p| p.as_bytes()).collect();
+        pats.encode(w)
+    }
+}
+impl<'a> Decode<'a> for Arc<RegexSet> {
+    fn decode(r: &mut Reader<'a>) -> Result<Arc<RegexSet>> {
+        let pats: Vec<&'a [u8]> = Decode::decode(r)?;
+        let mut strs = Vec::with_capacity(pats.len());
+        for bs in pats {
+            match std::str::from_utf8(bs) {
+                Ok(pat) => strs.push(pat),
+                Err(e) => return err!("invalid regex in bytecode image: {}", e),
+            }
+        }
+        match RegexSet::new(strs) {
+            Ok(set) => Ok(Arc::new(set)),
+            Err(e) => err!("invalid regex in bytecode image: {}", e),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.is_some().encode(w);
+        if let Some(t) = self {
+            t.encode(w)
+        }
+    }
+}
+impl<'a, T: Decode<'a>> Decode<'a> for Option<T> {
+    fn decode(r: &mut Reader<'a>) -> Result<Option<T>> {
+        Ok(if bool::decode(r)? {
+            Some(T::decode(r)?)
+        } else {
+            None
+        })
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (**self).encode(w)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Box<T> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        (**self).encode(w)
+    }
+}
+impl<'a, T: Decode<'a>> Decode<'a> for Box<T> {
+    fn decode(r: &mut Reader<'a>) -> Result<Box<T>> {
+        Ok(Box::new(T::decode(r)?))
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.len().encode(w);
+        for t in self.iter() {
+            t.encode(w)
+        }
+    }
+}
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self[..].encode(w)
+    }
+}
+impl<'a, T: Decode<'a>> Decode<'a> for Vec<T> {
+    fn decode(r: &mut Reader<'a>) -> Result<Vec<T>> {
+        (0..r.len()?).map(|_| T::decode(r)).collect()
+    }
+}
+impl<'a, T: Decode<'a>> Decode<'a> for Box<[T]> {
+    fn decode(r: &mut Reader<'a>) -> Result<Box<[T]>> {
+        Ok(Vec::decode(r)?.into_boxed_slice())
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.0.encode(w);
+        self.1.encode(w);
+    }
+}
+impl<'a, A: Decode<'a>, B: Decode<'a>> Decode<'a> for (A, B) {
+    fn decode(r: &mut Reader<'a>) -> Result<(A, B)> {
+        Ok((A::decode(r)?, B::decode(r)?))
+    }
+}
+
+impl Encode for Stage<usize> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        match self {
+            Stage::Main(main) => {
+                0u8.encode(w);
+                main.encode(w);
+            }
+            Stage::Par {
+                begin,
+                main_loop,
+                end,
+            } => {
+                1u8.encode(w);
+                begin.encode(w);
+                main_loop.encode(w);
+                end.encode(w);
+            }
+        }
+    }
+}
+impl<'a> Decode<'a> for Stage<usize> {
+    fn decode(r: &mut Reader<'a>) -> Result<Stage<usize>> {
+        match u8::decode(r)? {
+            0 => Ok(Stage::Main(Decode::decode(r)?)),
+            1 => Ok(Stage::Par {
+                begin: Decode::decode(r)?,
+                main_loop: Decode::decode(r)?,
+                end: Decode::decode(r)?,
+            }),
+            tag => err!("invalid stage {} in bytecode image", tag),
+        }
+    }
+}
+
+impl<'b> Encode for SepAssign<'b> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        match self {
+            SepAssign::Unsure => 0u8.encode(w),
+            SepAssign::Potential {
+                field_sep,
+                record_sep,
+            } => {
+                1u8.encode(w);
+                field_sep.encode(w);
+                record_sep.encode(w);
+            }
+        }
+    }
+}
+impl<'a> Decode<'a> for SepAssign<'a> {
+    fn decode(r: &mut Reader<'a>) -> Result<SepAssign<'a>> {
+        match u8::decode(r)? {
+            0 => Ok(SepAssign::Unsure),
+            1 => Ok(SepAssign::Potential {
+                field_sep: Decode::decode(r)?,
+                record_sep: Decode::decode(r)?,
+            }),
+            tag => err!("invalid separator analysis {} in bytecode image", tag),
+        }
+    }
+}
+
+impl Encode for SourceLoc {
+    fn encode(&self, w: &mut Vec<u8>) {
+        self.line.encode(w);
+        self.col.encode(w);
+    }
+}
+impl<'a> Decode<'a> for SourceLoc {
+    fn decode(r: &mut Reader<'a>) -> Result<SourceLoc> {
+        Ok(SourceLoc {
+            line: Decode::decode(r)?,
+            col: Decode::decode(r)?,
+        })
+    }
+}
+
+impl Encode for Rule<&str> {
+    fn encode(&self, w: &mut Vec<u8>) {
+        match self {
+            Rule::Begin => 0u8.encode(w),
+            Rule::Prepare => 1u8.encode(w),
+            Rule::End => 2u8.encode(w),
+            Rule::Pattern => 3u8.encode(w),
+            Rule::Function(name) => {
+                4u8.encode(w);
+                name.as_bytes().encode(w);
+            }
+        }
+    }
+}
+impl<'a> Decode<'a> for Rule<&'a str> {
+    fn decode(r: &mut Reader<'a>) -> Result<Rule<&'a str>> {
+        match u8::decode(r)? {
+            0 => Ok(Rule::Begin),
+            1 => Ok(Rule::Prepare),
+            2 => Ok(Rule::End),
+            3 => Ok(Rule::Pattern),
+            4 => match std::str::from_utf8(Decode::decode(r)?) {
+                Ok(name) => Ok(Rule::Function(name)),
+                Err(e) => err!("invalid function name in bytecode image: {}", e),
+            },
+            tag => err!("invalid rule {} in bytecode image", tag),
+        }
+    }
+}
+
+macro_rules! fields {
+    (encode $w:ident ($($f:ident),*)) => { $($f.encode($w);)* };
+    (encode $w:ident {$($f:ident),*}) => { $($f.encode($w);)* };
+    (decode $r:ident $name:ident ($($f:ident),*)) => {
+        Instr::$name($(fields!(field $r $f)),*)
+    };
+    (decode $r:ident $name:ident {$($f:ident),*}) => {
+        Instr::$name { $($f: Decode::decode($r)?),* }
+    };
+    (field $r:ident $f:ident) => { Decode::decode($r)? };
+}
+
+macro_rules! opcodes {
+    ($($op:literal => $name:ident $fields:tt,)*) => {
+        fn encode_instr(inst: &Instr, w: &mut Vec<u8>) {
+            match inst {
+                $(Instr::$name $fields => {
+                    w.push($op);
+                    fields!(encode w $fields);
+                })*
+            }
+        }
+
+        fn decode_instr<'a>(r: &mut Reader<'a>) -> Result<Instr<'a>> {
+            Ok(match u8::decode(r)? {
+                $($op => fields!(decode r $name $fields),)*
+                op => return err!("unknown opcode {} in bytecode image", op),
+            })
+        }
+    };
+}
+
+opcodes! {
+    0 => StoreConstStr(a, b),
+    1 => StoreConstInt(a, b),
+    2 => StoreConstFloat(a, b),
+    3 => IntToStr(a, b),
+    4 => FloatToStr(a, b),
+    5 => StrToInt(a, b),
+    6 => HexStrToInt(a, b),
+    7 => FloatToInt(a, b),
+    8 => IntToFloat(a, b),
+    9 => StrToFloat(a, b),
+    10 => Mov(a, b, c),
+    11 => AllocMap(a, b),
+    12 => AddInt(a, b, c),
+    13 => AddFloat(a, b, c),
+    14 => MulFloat(a, b, c),
+    15 => MulInt(a, b, c),
+    16 => Div(a, b, c),
+    17 => Pow(a, b, c),
+    18 => MinusFloat(a, b, c),
+    19 => MinusInt(a, b, c),
+    20 => ModFloat(a, b, c),
+    21 => ModInt(a, b, c),
+    22 => Not(a, b),
+    23 => NotStr(a, b),
+    24 => NegInt(a, b),
+    25 => NegFloat(a, b),
+    26 => Float1(a, b, c),
+    27 => Float2(a, b, c, d),
+    28 => Int1(a, b, c),
+    29 => Int2(a, b, c, d),
+    30 => Rand(a),
+    31 => Srand(a, b),
+    32 => ReseedRng(a),
+    33 => Concat(a, b, c),
+    34 => StartsWithConst(a, b, c),
+    35 => IsMatch(a, b, c),
+    36 => IsMatchConst(a, b, c),
+    37 => Match(a, b, c),
+    38 => MatchConst(a, b, c),
+    39 => SubstrIndex(a, b, c),
+    40 => LenStr(a, b),
+    41 => Sub(a, b, c, d),
+    42 => GSub(a, b, c, d),
+    43 => GenSubDynamic(a, b, c, d, e),
+    44 => EscapeCSV(a, b),
+    45 => EscapeTSV(a, b),
+    46 => Substr(a, b, c, d),
+    47 => LTFloat(a, b, c),
+    48 => LTInt(a, b, c),
+    49 => LTStr(a, b, c),
+    50 => GTFloat(a, b, c),
+    51 => GTInt(a, b, c),
+    52 => GTStr(a, b, c),
+    53 => LTEFloat(a, b, c),
+    54 => LTEInt(a, b, c),
+    55 => LTEStr(a, b, c),
+    56 => GTEFloat(a, b, c),
+    57 => GTEInt(a, b, c),
+    58 => GTEStr(a, b, c),
+    59 => EQFloat(a, b, c),
+    60 => EQInt(a, b, c),
+    61 => EQStr(a, b, c),
+    62 => SetColumn(a, b),
+    63 => GetColumn(a, b),
+    64 => JoinCSV(a, b, c),
+    65 => JoinTSV(a, b, c),
+    66 => JoinColumns(a, b, c, d),
+    67 => ToUpperAscii(a, b),
+    68 => ToLowerAscii(a, b),
+    69 => ReadErr(a, b, c),
+    70 => NextLine(a, b, c),
+    71 => ReadErrStdin(a),
+    72 => NextLineStdin(a),
+    73 => NextLineStdinFused(),
+    74 => NextFile(),
+    75 => UpdateUsedFields(),
+    76 => SetFI(a, b),
+    77 => SplitInt(a, b, c, d),
+    78 => SplitStr(a, b, c, d),
+    79 => Sprintf { dst, fmt, args },
+    80 => Printf { output, fmt, args },
+    81 => PrintAll { output, args },
+    82 => Close(a, b),
+    83 => RunCmd(a, b),
+    84 => Exit(a),
+    85 => Lookup { map_ty, dst, map, key },
+    86 => Contains { map_ty, dst, map, key },
+    87 => Delete { map_ty, map, key },
+    88 => Clear { map_ty, map },
+    89 => Len { map_ty, dst, map },
+    90 => Store { map_ty, map, key, val },
+    91 => IncInt { map_ty, map, key, dst, by },
+    92 => IncFloat { map_ty, map, key, dst, by },
+    93 => IterBegin { map_ty, dst, map },
+    94 => IterHasNext { iter_ty, dst, iter },
+    95 => IterGetNext { iter_ty, dst, iter },
+    96 => LoadVarStr(a, b),
+    97 => StoreVarStr(a, b),
+    98 => LoadVarInt(a, b),
+    99 => StoreVarInt(a, b),
+    100 => LoadVarIntMap(a, b),
+    101 => StoreVarIntMap(a, b),
+    102 => LoadVarStrMap(a, b),
+    103 => StoreVarStrMap(a, b),
+    104 => LoadSlot { ty, slot, dst },
+    105 => StoreSlot { ty, slot, src },
+    106 => JmpIf(a, b),
+    107 => Jmp(a),
+    108 => GetColumnFloat(a, b),
+    109 => JmpIfInt(a, b, c, d),
+    110 => JmpIfFloat(a, b, c, d),
+    111 => Push(a, b),
+    112 => Pop(a, b),
+    113 => Call(a),
+    114 => Ret {},
+    115 => LookupConstKey { map_ty, dst, map, key },
+    116 => StoreConstKey { map_ty, map, val, key },
+    117 => IncIntConstKey { map_ty, map, dst, by, key },
+    118 => IncFloatConstKey { map_ty, map, dst, by, key },
+    119 => StrNumCmp(a, b, c, d),
+    120 => LookupStr { map_ty, dst, map, key },
+    121 => ReadArray { map_ty, dst, file, map },
+    122 => WriteArray { map_ty, dst, file, map },
+    123 => Flush(a, b),
+    124 => FlushAll(a),
+    125 => EscapeJSON(a, b),
+    126 => FloatToJSON(a, b),
+    127 => JoinJSON(a, b, c),
+    128 => ToJSON { map_ty, dst, map },
+    129 => MatchArr(a, b, c, d, e),
+    130 => SubConst(a, b, c, d),
+    131 => GSubConst(a, b, c, d),
+    132 => ContainsConst(a, b, c),
+    133 => MatchSet(a, b, c),
+    134 => ProfileRule(a),
+    135 => CallNative { dst, func, args },
+    136 => JmpIfConstMatch(a, b, c, d),
+    137 => AddIntImm(a, b, c),
+    138 => EQStrConst(a, b, c),
+    139 => Loc(a),
+    140 => JoinArray { map_ty, dst, map, sep, all },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instr::*;
+
+    fn image(instrs: Vec<Instr<'static>>) -> Image<'static> {
+        let mut regs = [0; NUM_TYPES];
+        for inst in instrs.iter() {
+            inst.accum(|reg, ty| {
+                let count = &mut regs[ty as usize];
+                *count = std::cmp::max(*count, reg as usize + 1);
+            });
+        }
+        Image {
+            instrs: vec![instrs],
+            stage: Stage::Main(0),
+            regs,
+            used_fields: FieldSet::singleton(2),
+            named_columns: Some(vec![Arc::from(&b"a"[..]), Arc::from(&b"b"[..])]),
+            sep_assign: SepAssign::Potential {
+                field_sep: Some(b","),
+                record_sep: None,
+            },
+            slot_aggs: vec![
+                ((Ty::Int, 0), Aggregation::Max),
+                ((Ty::Str, 1), Aggregation::Concat),
+            ],
+            source_map: SourceMap {
+                locs: vec![vec![(0, Some(SourceLoc { line: 2, col: 4 })), (3, None)]],
+                rules: vec![
+                    (SourceLoc { line: 0, col: 0 }, Rule::Begin),
+                    (SourceLoc { line: 1, col: 0 }, Rule::Function("f")),
+                ],
+            },
+        }
+    }
+
+    fn sample() -> Image<'static> {
+        image(vec![
+            StoreConstStr(0.into(), Str::from("hello").into()),
+            StoreConstFloat(0.into(), 1.5),
+            IsMatchConst(0.into(), 0.into(), Arc::new(Regex::new("^h+").unwrap())),
+            Float2(FloatFunc::Atan2, 1.into(), 0.into(), 0.into()),
+            Int1(Bitwise::Complement, 1.into(), 0.into()),
+            Printf {
+                output: Some(Box::new((0.into(), FileSpec::Append))),
+                fmt: 0.into(),
+                args: vec![(0, Ty::Str), (1, Ty::Float)].into_boxed_slice(),
+            },
+            Lookup {
+                map_ty: Ty::MapStrInt,
+                dst: 2,
+                map: 0,
+                key: 0,
+            },
+            IncFloatConstKey {
+                map_ty: Ty::MapStrFloat,
+                map: 1,
+                dst: 0,
+                by: 1.into(),
+                key: Box::new(ConstKey::new(Str::from("total").into())),
+            },
+            LoadVarStr(1.into(), Variable::FILENAME),
+            CallNative {
+                dst: (2, Ty::Int),
+                func: 3,
+                args: vec![(0, Ty::Str), (1, Ty::Int)].into_boxed_slice(),
+            },
+            JmpIfFloat(Cmp::Lte, 0.into(), 1.into(), Label(0)),
+            JmpIfConstMatch(
+                false,
+                1.into(),
+                Arc::new(Regex::new("x$").unwrap()),
+                Label(0),
+            ),
+            AddIntImm(1.into(), 1.into(), -3),
+            EQStrConst(0.into(), 0.into(), Arc::from(&b"hi"[..])),
+            Ret,
+        ])
+    }
+
+    #[test]
+    fn round_trip() {
+        let img = sample();
+        let bs = img.encode();
+        let decoded = Image::decode(&bs).unwrap();
+        assert_eq!(decoded.encode(), bs);
+        let render = |img: &Image| format!("{:?}", &img.instrs[0][1..]);
+        assert_eq!(render(&decoded), render(&img));
+        assert_eq!(decoded.regs, img.regs);
+        assert!(decoded.used_fields == img.used_fields);
+        assert_eq!(decoded.named_columns, img.named_columns);
+        assert_eq!(decoded.slot_aggs, img.slot_aggs);
+        assert_eq!(decoded.source_map.locs, img.source_map.locs);
+        assert_eq!(
+            format!("{:?}", decoded.source_map.rules),
+            format!("{:?}", img.source_map.rules)
+        );
+        assert_eq!(
+            format!("{:?}", decoded.sep_assign),
+            format!("{:?}", img.sep_assign)
+        );
+        match &decoded.instrs[0][0] {
+            StoreConstStr(_, s) => assert_eq!(s.literal_bytes(), b"hello"),
+            inst => panic!("unexpected instruction {:?}", inst),
+        }
+    }
+
+    #[test]
+    fn reject_corrupt_images() {
+        let bs = sample().encode();
+        for len in 0..bs.len() {
+            assert!(Image::decode(&bs[..len]).is_err());
+        }
+        let mut bad_version = bs.clone();
+        bad_version[MAGIC.len()] += 1;
+        assert!(Image::decode(&bad_version).is_err());
+    }
+}
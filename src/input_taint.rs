@@ -46,7 +46,7 @@
 use crate::bytecode::Instr;
 use crate::common::{FileSpec, NumTy};
 use crate::compile::HighLevel;
-use crate::dataflow::{self, JoinSemiLattice};
+use crate::dataflow::{self, JoinSemiLattice, Key};
 
 /// aka bool, with join = ||; making our own enum for explicitness.
 #[derive(Copy, Clone, Debug)]
@@ -120,17 +120,21 @@ impl TaintedStringAnalysis {
             StoreConstInt(dst, _) => self.dfa.add_src(dst, Taint::Okay),
             StoreConstFloat(dst, _) => self.dfa.add_src(dst, Taint::Okay),
             PrintAll {
-                output: Some((cmd, FileSpec::Cmd)),
-                ..
+                output: Some(out), ..
             }
             | Printf {
-                output: Some((cmd, FileSpec::Cmd)),
-                ..
-            } => self.dfa.add_query(cmd),
+                output: Some(out), ..
+            } if matches!(out.1, FileSpec::Cmd) => self.dfa.add_query(&out.0),
             RunCmd(dst, cmd) => {
                 self.dfa.add_query(cmd);
                 self.dfa.add_src(dst, Taint::Tainted);
             }
+            // Native functions can return anything, including data from outside the program.
+            CallNative { dst, .. } => self.dfa.add_src(Key::Reg(dst.0, dst.1), Taint::Tainted),
+            // Arrays read from a file hold whatever was written to it.
+            ReadArray { .. } => dataflow::boilerplate::visit_ll(inst, |dst, _| {
+                self.dfa.add_src(dst, Taint::Tainted)
+            }),
             _ => dataflow::boilerplate::visit_ll(inst, |dst, src| {
                 if let Some(src) = src {
                     self.dfa.add_dep(dst, src, ())
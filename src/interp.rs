@@ -1,18 +1,28 @@
 use crate::builtins::Variable;
-use crate::bytecode::{Get, Instr, Label, Reg};
-use crate::common::{NumTy, Result, Stage};
+use crate::bytecode::{Cmp, Get, Instr, Label, Reg};
+use crate::common::{Aggregation, CancelSignal, NumTy, OutputOrder, Result, Stage};
 use crate::compile::{self, Ty};
+use crate::coverage::{self, Outline};
+use crate::debugger::Debugger;
+use crate::embed::{Callback, Value};
+use crate::profile::Profile;
 use crate::pushdown::FieldSet;
-use crate::runtime::{self, Float, Int, Line, LineReader, Str, UniqueStr};
+use crate::runtime::{self, Float, Int, KeyOrder, Line, LineReader, Str, UniqueStr};
+use crate::source_map::SourceMap;
+use crate::verify;
 
 use crossbeam::scope;
 use crossbeam_channel::bounded;
-use hashbrown::HashMap;
+use hashbrown::{hash_map::Entry, HashMap, HashSet};
 use rand::{self, rngs::StdRng, Rng, SeedableRng};
 use regex::bytes::Regex;
 
 use std::cmp;
+use std::io;
 use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
 type ClassicReader = runtime::splitter::regex::RegexSplitter<Box<dyn std::io::Read>>;
 
@@ -31,12 +41,21 @@ pub(crate) struct Core<'a> {
     pub rng: StdRng,
     pub current_seed: u64,
     pub slots: Slots,
+    // How to combine the values in `slots` that come from different workers. Slots that are not
+    // listed are summed.
+    pub slot_aggs: SlotAggs,
+    // Whether floating-point division by zero produces an infinity or NaN rather than an error.
+    pub ieee_division: bool,
+    // The order in which `for (k in m)` loops visit keys.
+    pub key_order: KeyOrder,
 }
 
 impl<'a> Drop for Core<'a> {
     fn drop(&mut self) {
         if let Err(e) = self.write_files.shutdown() {
-            eprintln_ignore!("{}", e);
+            if !runtime::output_pipe_closed() {
+                eprintln_ignore!("{}", e);
+            }
         }
     }
 }
@@ -59,36 +78,59 @@ pub(crate) struct Slots {
     pub strstr: Vec<HashMap<UniqueStr<'static>, UniqueStr<'static>>>,
 }
 
+/// The aggregation used for each slot that the main loop only accumulates into, keyed by its type
+/// and index.
+pub(crate) type SlotAggs = HashMap<(Ty, usize), Aggregation>;
+
 /// A Simple helper trait for implement aggregations for slot values and variables.
 trait Agg {
-    fn agg(self, other: Self) -> Self;
+    fn agg(self, other: Self, how: Aggregation) -> Self;
 }
 impl Agg for Int {
-    fn agg(self, other: Int) -> Int {
-        self + other
+    fn agg(self, other: Int, how: Aggregation) -> Int {
+        match how {
+            Aggregation::Min => cmp::min(self, other),
+            Aggregation::Max => cmp::max(self, other),
+            Aggregation::Sum | Aggregation::Concat => self + other,
+        }
     }
 }
 impl Agg for Float {
-    fn agg(self, other: Float) -> Float {
-        self + other
+    fn agg(self, other: Float, how: Aggregation) -> Float {
+        match how {
+            Aggregation::Min => self.min(other),
+            Aggregation::Max => self.max(other),
+            Aggregation::Sum | Aggregation::Concat => self + other,
+        }
     }
 }
 impl<'a> Agg for UniqueStr<'a> {
-    fn agg(self, other: UniqueStr<'a>) -> UniqueStr<'a> {
-        // Strings are not aggregated explicitly.
-        if other.is_empty() {
-            self
-        } else {
-            other
+    fn agg(self, other: UniqueStr<'a>, how: Aggregation) -> UniqueStr<'a> {
+        let (l, r) = (self.into_str(), other.into_str());
+        match how {
+            // Strings are not summed: we pick a non-empty value.
+            Aggregation::Sum if r.is_empty() => l.into(),
+            Aggregation::Sum => r.into(),
+            Aggregation::Concat => Str::concat(l, r).into(),
+            // Compare the strings the way that the program would have if they came from input.
+            Aggregation::Min if Cmp::Lte.eval_str_num(&l, &r, false) => l.into(),
+            Aggregation::Max if Cmp::Gte.eval_str_num(&l, &r, false) => l.into(),
+            Aggregation::Min | Aggregation::Max => r.into(),
         }
     }
 }
 impl<K: std::hash::Hash + Eq, V: Agg + Default> Agg for HashMap<K, V> {
-    fn agg(mut self, other: HashMap<K, V>) -> HashMap<K, V> {
+    fn agg(mut self, other: HashMap<K, V>, how: Aggregation) -> HashMap<K, V> {
         for (k, v) in other {
-            let entry = self.entry(k).or_default();
-            let v2 = mem::take(entry);
-            *entry = v2.agg(v);
+            match self.entry(k) {
+                Entry::Occupied(mut o) => {
+                    let v2 = mem::take(o.get_mut());
+                    *o.get_mut() = v2.agg(v, how);
+                }
+                Entry::Vacant(vac) => {
+                    vac.insert(v);
+                }
+            }
         }
         self
     }
@@ -105,29 +147,53 @@ pub(crate) struct StageResult {
 }
 
 impl Slots {
-    fn combine(&mut self, mut other: Slots) {
+    fn combine(&mut self, mut other: Slots, aggs: &SlotAggs) {
         macro_rules! for_each_slot_pair {
-            ($s1:ident, $s2:ident, $body:expr) => {
+            ($s1:ident, $s2:ident, $ty:ident, $body:expr) => {
                 for_each_slot_pair!(
-                    $s1, $s2, $body, int, float, strs, intint, intfloat, intstr, strint, strfloat,
-                    strstr
+                    $s1, $s2, $ty, $body, (int, Int), (float, Float), (strs, Str),
+                    (intint, MapIntInt), (intfloat, MapIntFloat), (intstr, MapIntStr),
+                    (strint, MapStrInt), (strfloat, MapStrFloat), (strstr, MapStrStr)
                 );
             };
-            ($s1:ident, $s2:ident, $body:expr, $($fld:tt),*) => {$({
+            ($s1:ident, $s2:ident, $ty:ident, $body:expr, $(($fld:tt, $t:ident)),*) => {$({
                 let $s1 = &mut self.$fld;
                 let $s2 = &mut other.$fld;
+                let $ty = Ty::$t;
                 $body
             });*};
         }
 
-        for_each_slot_pair!(a, b, {
+        for_each_slot_pair!(a, b, ty, {
             a.resize_with(std::cmp::max(a.len(), b.len()), Default::default);
-            for (a_elt, b_elt_v) in a.iter_mut().zip(b.drain(..)) {
+            for (i, (a_elt, b_elt_v)) in a.iter_mut().zip(b.drain(..)).enumerate() {
+                let how = aggs.get(&(ty, i)).cloned().unwrap_or(Aggregation::Sum);
                 let a_elt_v = mem::take(a_elt);
-                *a_elt = a_elt_v.agg(b_elt_v);
+                *a_elt = a_elt_v.agg(b_elt_v, how);
             }
         });
     }
+
+    /// Reset slot `slot` of type `ty` to an empty value.
+    fn clear(&mut self, ty: Ty, slot: usize) {
+        fn clear<T: Default>(vec: &mut [T], slot: usize) {
+            if let Some(v) = vec.get_mut(slot) {
+                *v = Default::default();
+            }
+        }
+        match ty {
+            Ty::Int => clear(&mut self.int, slot),
+            Ty::Float => clear(&mut self.float, slot),
+            Ty::Str => clear(&mut self.strs, slot),
+            Ty::MapIntInt => clear(&mut self.intint, slot),
+            Ty::MapIntFloat => clear(&mut self.intfloat, slot),
+            Ty::MapIntStr => clear(&mut self.intstr, slot),
+            Ty::MapStrInt => clear(&mut self.strint, slot),
+            Ty::MapStrFloat => clear(&mut self.strfloat, slot),
+            Ty::MapStrStr => clear(&mut self.strstr, slot),
+            Ty::Null | Ty::IterInt | Ty::IterStr => {}
+        }
+    }
 }
 
 pub fn set_slot<T: Default>(vec: &mut Vec<T>, slot: usize, v: T) {
@@ -139,17 +205,6 @@ pub fn set_slot<T: Default>(vec: &mut Vec<T>, slot: usize, v: T) {
     vec.push(v)
 }
 
-pub fn combine_slot<T: Default>(vec: &mut Vec<T>, slot: usize, f: impl FnOnce(T) -> T) {
-    if slot < vec.len() {
-        let res = f(std::mem::take(&mut vec[slot]));
-        vec[slot] = res;
-        return;
-    }
-    vec.resize_with(slot, Default::default);
-    let res = f(Default::default());
-    vec.push(res)
-}
-
 impl<'a> Core<'a> {
     pub fn shuttle(&self, pid: Int) -> impl FnOnce() -> Core<'a> + Send {
         use crate::builtins::Variables;
@@ -162,9 +217,20 @@ impl<'a> Core<'a> {
         let filename: UniqueStr<'a> = self.vars.filename.clone().into();
         let argv = self.vars.argv.shuttle();
         let fi = self.vars.fi.shuttle();
-        let slots = self.slots.clone();
+        let procinfo = self.vars.procinfo.shuttle();
+        let ignorecase = self.vars.ignorecase;
+        let ieee_division = self.ieee_division;
+        let key_order = self.key_order;
+        let mut slots = self.slots.clone();
+        // Each worker starts its sums and concatenations from scratch, so that the values they
+        // had before the main loop are only counted once, in the main thread.
+        for ((ty, slot), agg) in self.slot_aggs.iter() {
+            if matches!(agg, Aggregation::Sum | Aggregation::Concat) {
+                slots.clear(*ty, *slot);
+            }
+        }
         move || {
-            let vars = Variables {
+            let mut vars = Variables {
                 fs: fs.into_str(),
                 ofs: ofs.into_str(),
                 ors: ors.into_str(),
@@ -179,14 +245,22 @@ impl<'a> Core<'a> {
                 argc: 0,
                 argv: argv.into(),
                 fi: fi.into(),
+                procinfo: procinfo.into(),
+                ignorecase,
             };
+            vars.set_pid(pid);
+            let mut regexes = runtime::RegexCache::default();
+            regexes.set_ignore_case(ignorecase != 0);
             Core {
                 vars,
-                regexes: Default::default(),
+                regexes,
                 write_files: fw,
                 rng: rand::rngs::StdRng::seed_from_u64(seed),
                 current_seed: seed,
                 slots,
+                slot_aggs: Default::default(),
+                ieee_division,
+                key_order,
             }
         }
     }
@@ -199,10 +273,16 @@ impl<'a> Core<'a> {
             rng: rand::rngs::StdRng::seed_from_u64(seed),
             current_seed: seed,
             slots: Default::default(),
+            slot_aggs: Default::default(),
+            ieee_division: false,
+            key_order: KeyOrder::Unordered,
         }
     }
 
     pub fn extract_result(&mut self, rc: i32) -> StageResult {
+        // Hand off the output for the last file this worker read. Errors writing it surface when
+        // stdout is flushed at exit.
+        let _ = self.write_files.end_input_file();
         StageResult {
             slots: mem::take(&mut self.slots),
             nr: self.vars.nr,
@@ -211,8 +291,8 @@ impl<'a> Core<'a> {
     }
 
     pub fn combine(&mut self, StageResult { slots, nr, rc: _ }: StageResult) {
-        self.slots.combine(slots);
-        self.vars.nr = self.vars.nr.agg(nr);
+        self.slots.combine(slots, &self.slot_aggs);
+        self.vars.nr = self.vars.nr.agg(nr, Aggregation::Sum);
     }
 
     pub fn reseed(&mut self, seed: u64) -> u64 /* old seed */ {
@@ -230,6 +310,17 @@ impl<'a> Core<'a> {
         self.regexes.regex_match_loc(&mut self.vars, pat, s)
     }
 
+    pub fn match_regex_array(
+        &mut self,
+        s: &Str<'a>,
+        pat: &Str<'a>,
+        m: &runtime::StrMap<'a, Str<'a>>,
+        subsep: &Str<'a>,
+    ) -> Result<Int> {
+        self.regexes
+            .regex_match_loc_array(&mut self.vars, pat, s, m, subsep)
+    }
+
     pub fn match_const_regex(&mut self, s: &Str<'a>, pat: &Regex) -> Result<Int> {
         runtime::RegexCache::regex_const_match_loc(&mut self.vars, pat, s)
     }
@@ -248,10 +339,14 @@ impl<'a> Core<'a> {
         mem::take(&mut self.slots.strs[slot]).into_str().upcast()
     }
     pub fn load_intint(&mut self, slot: usize) -> runtime::IntMap<Int> {
-        mem::take(&mut self.slots.intint[slot]).into()
+        mem::take(&mut self.slots.intint[slot])
+            .into_iter()
+            .collect()
     }
     pub fn load_intfloat(&mut self, slot: usize) -> runtime::IntMap<Float> {
-        mem::take(&mut self.slots.intfloat[slot]).into()
+        mem::take(&mut self.slots.intfloat[slot])
+            .into_iter()
+            .collect()
     }
     pub fn load_intstr(&mut self, slot: usize) -> runtime::IntMap<Str<'a>> {
         mem::take(&mut self.slots.intstr[slot])
@@ -291,35 +386,35 @@ impl<'a> Core<'a> {
         set_slot(
             &mut self.slots.intint,
             slot,
-            s.iter(|i| i.map(|(k, v)| (*k, *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k, *v)).collect()),
         )
     }
     pub fn store_intfloat(&mut self, slot: usize, s: runtime::IntMap<Float>) {
         set_slot(
             &mut self.slots.intfloat,
             slot,
-            s.iter(|i| i.map(|(k, v)| (*k, *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k, *v)).collect()),
         )
     }
     pub fn store_intstr(&mut self, slot: usize, s: runtime::IntMap<Str<'a>>) {
         set_slot(
             &mut self.slots.intstr,
             slot,
-            s.iter(|i| i.map(|(k, v)| (*k, v.clone().unmoor().into())).collect()),
+            s.iter(|i| i.map(|(k, v)| (k, v.clone().unmoor().into())).collect()),
         )
     }
     pub fn store_strint(&mut self, slot: usize, s: runtime::StrMap<'a, Int>) {
         set_slot(
             &mut self.slots.strint,
             slot,
-            s.iter(|i| i.map(|(k, v)| (k.clone().unmoor().into(), *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k.unmoor().into(), *v)).collect()),
         )
     }
     pub fn store_strfloat(&mut self, slot: usize, s: runtime::StrMap<'a, Float>) {
         set_slot(
             &mut self.slots.strfloat,
             slot,
-            s.iter(|i| i.map(|(k, v)| (k.clone().unmoor().into(), *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k.unmoor().into(), *v)).collect()),
         )
     }
     pub fn store_strstr(&mut self, slot: usize, s: runtime::StrMap<'a, Str<'a>>) {
@@ -327,7 +422,7 @@ impl<'a> Core<'a> {
             &mut self.slots.strstr,
             slot,
             s.iter(|i| {
-                i.map(|(k, v)| (k.clone().unmoor().into(), v.clone().unmoor().into()))
+                i.map(|(k, v)| (k.unmoor().into(), v.clone().unmoor().into()))
                     .collect()
             }),
         )
@@ -404,6 +499,34 @@ macro_rules! map_regs {
     }};
 }
 
+// Like `map_regs`, but for the maps with string keys accepted by the `*ConstKey` instructions.
+macro_rules! str_map_regs {
+    ($map_ty:expr, $map_reg:ident, $val_reg:ident, $body:expr) => {{
+        let map_ty = $map_ty;
+        match map_ty {
+            Ty::MapStrInt => {
+                let $map_reg: Reg<runtime::StrMap<'a, Int>> = $map_reg.into();
+                let $val_reg: Reg<Int> = $val_reg.into();
+                $body
+            }
+            Ty::MapStrFloat => {
+                let $map_reg: Reg<runtime::StrMap<'a, Float>> = $map_reg.into();
+                let $val_reg: Reg<Float> = $val_reg.into();
+                $body
+            }
+            Ty::MapStrStr => {
+                let $map_reg: Reg<runtime::StrMap<'a, Str<'a>>> = $map_reg.into();
+                let $val_reg: Reg<Str<'a>> = $val_reg.into();
+                $body
+            }
+            _ => panic!(
+                "attempting to perform string map operations on type: {:?}",
+                map_ty
+            ),
+        }
+    }};
+}
+
 pub(crate) struct Interp<'a, LR: LineReader = ClassicReader> {
     // index of `instrs` that contains "main"
     main_func: Stage<usize>,
@@ -416,6 +539,20 @@ pub(crate) struct Interp<'a, LR: LineReader = ClassicReader> {
 
     core: Core<'a>,
 
+    // Execution counts for each instruction, if profiling is enabled.
+    profile: Option<Box<Profile>>,
+
+    // The functions called by CallNative, indexed by their id.
+    natives: Vec<Callback>,
+
+    cancel: Option<Box<Cancel>>,
+
+    // Where the instructions come from in the program text, for error messages.
+    source_map: Arc<SourceMap<'a>>,
+
+    // Stops the program at breakpoints and steps through it, for --debug.
+    debugger: Option<Box<Debugger<'a>>>,
+
     // Core storage.
     // TODO: should these be smallvec<[T; 32]>? We never add registers, so could we allocate one
     // contiguous region ahead of time?
@@ -434,6 +571,103 @@ pub(crate) struct Interp<'a, LR: LineReader = ClassicReader> {
     pub(crate) iters_str: Storage<runtime::Iter<Str<'a>>>,
 }
 
+/// The conditions under which a running program is stopped early. They are checked at the
+/// back-edges of loops and at function calls, so that every unbounded loop checks them; a program
+/// blocked reading input is not stopped until the read completes.
+#[derive(Clone)]
+pub(crate) struct Cancel {
+    signal: Option<CancelSignal>,
+    deadline: Option<Instant>,
+    // A limit on the estimate returned by `Interp::memory_usage`.
+    max_memory: Option<usize>,
+    // Reading the clock and measuring memory are much slower than checking `signal`, so they are
+    // only done every PERIODIC_INTERVAL checks.
+    until_periodic: u32,
+}
+
+const PERIODIC_INTERVAL: u32 = 1 << 12;
+
+impl Cancel {
+    pub(crate) fn new(
+        signal: Option<CancelSignal>,
+        deadline: Option<Instant>,
+        max_memory: Option<usize>,
+    ) -> Cancel {
+        Cancel {
+            signal,
+            deadline,
+            max_memory,
+            until_periodic: 0,
+        }
+    }
+
+    /// Check for cancellation and, periodically, the deadline. Returns the memory limit when it is
+    /// also time to check memory usage.
+    fn check(&mut self) -> Result<Option<usize>> {
+        if matches!(&self.signal, Some(signal) if signal.cancelled()) {
+            return err!("execution cancelled");
+        }
+        if self.deadline.is_none() && self.max_memory.is_none() {
+            return Ok(None);
+        }
+        if self.until_periodic > 0 {
+            self.until_periodic -= 1;
+            return Ok(None);
+        }
+        self.until_periodic = PERIODIC_INTERVAL - 1;
+        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return err!("execution timed out");
+        }
+        Ok(self.max_memory)
+    }
+}
+
+/// The memory that a value points to, beyond the space for the value itself; see
+/// `Interp::memory_usage`.
+trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for Int {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for Float {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl<'a> HeapSize for Str<'a> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+fn strs_usage(strs: &Storage<Str>) -> usize {
+    strs.regs
+        .iter()
+        .chain(strs.stack.iter())
+        .map(HeapSize::heap_size)
+        .sum()
+}
+
+/// The approximate size of the maps in `maps`, skipping those already in `seen`: the same map can
+/// be held in several registers.
+fn maps_usage<K: runtime::MapKey + HeapSize, V: HeapSize>(
+    maps: &Storage<runtime::SharedMap<K, V>>,
+    seen: &mut HashSet<*const ()>,
+) -> usize {
+    maps.regs
+        .iter()
+        .chain(maps.stack.iter())
+        .filter(|m| seen.insert(Rc::as_ptr(&m.0) as *const ()))
+        .map(|m| m.approx_size(|k, v| k.heap_size() + v.heap_size()))
+        .sum()
+}
+
 fn default_of<T: Default>(n: usize) -> Storage<T> {
     let mut regs = Vec::new();
     regs.resize_with(n, Default::default);
@@ -453,9 +687,12 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         ff: impl runtime::writers::FileFactory,
         used_fields: &FieldSet,
         named_columns: Option<Vec<&[u8]>>,
-    ) -> Self {
+    ) -> Result<Self> {
         use compile::Ty::*;
-        Interp {
+        if let Err(e) = verify::verify(&instrs, &main_func, &regs) {
+            return err!("invalid bytecode: {}", e);
+        }
+        Ok(Interp {
             main_func,
             num_workers,
             instrs,
@@ -464,6 +701,11 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
             ints: default_of(regs(Int)),
             strs: default_of(regs(Str)),
             core: Core::new(ff),
+            profile: None,
+            natives: Vec::new(),
+            cancel: None,
+            source_map: Default::default(),
+            debugger: None,
 
             line: Default::default(),
             read_files: runtime::FileRead::new(stdin, used_fields.clone(), named_columns),
@@ -478,13 +720,119 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
 
             iters_int: default_of(regs(IterInt)),
             iters_str: default_of(regs(IterStr)),
-        }
+        })
     }
 
     pub(crate) fn instrs(&self) -> &Vec<Vec<Instr<'a>>> {
         &self.instrs
     }
 
+    /// The value of NR: the number of records read so far, across all workers once the main loop
+    /// has finished.
+    pub(crate) fn nr(&self) -> Int {
+        self.core.vars.nr
+    }
+
+    /// Count the executions of every instruction from here on, for use with `profile_report`.
+    pub(crate) fn enable_profiling(&mut self) {
+        self.profile = Some(Box::new(Profile::new(&self.instrs)));
+    }
+
+    /// Set the functions that the program calls with CallNative.
+    pub(crate) fn set_natives(&mut self, natives: Vec<Callback>) {
+        self.natives = natives;
+    }
+
+    /// Combine the values that parallel workers compute for the slots in `aggs` as given there.
+    pub(crate) fn set_slot_aggs(&mut self, aggs: SlotAggs) {
+        self.core.slot_aggs = aggs;
+    }
+
+    /// Report errors at the locations in `source_map`.
+    pub(crate) fn set_source_map(&mut self, source_map: SourceMap<'a>) {
+        self.source_map = Arc::new(source_map);
+    }
+
+    /// Run the program under `debugger`. Errors are reported at the locations in the source map,
+    /// so it should be set first.
+    pub(crate) fn set_debugger(&mut self, mut debugger: Debugger<'a>) {
+        debugger.set_source_map(self.source_map.clone());
+        self.debugger = Some(Box::new(debugger));
+    }
+
+    /// Group what parallel workers print by input file, in the order given by `order`.
+    pub(crate) fn set_output_order(&mut self, order: OutputOrder) {
+        self.core.write_files.set_output_order(order);
+    }
+
+    /// Divide floating-point numbers by zero as IEEE 754 does, producing an infinity or NaN, rather
+    /// than failing with an error. Taking an integer modulo zero is an error either way.
+    pub(crate) fn set_ieee_division(&mut self, ieee: bool) {
+        self.core.ieee_division = ieee;
+    }
+
+    /// Visit the keys of maps in `order` in `for (k in m)` loops.
+    pub(crate) fn set_key_order(&mut self, order: KeyOrder) {
+        self.core.key_order = order;
+    }
+
+    /// Stop the program early under the conditions in `cancel`.
+    pub(crate) fn set_cancel(&mut self, cancel: Cancel) {
+        self.cancel = Some(Box::new(cancel));
+    }
+
+    /// An estimate of the memory held by the program's strings and arrays, in bytes. Strings are
+    /// counted at their full length even if they share memory, and arrays are estimated from a
+    /// sample of their entries (see `Table::approx_size`), so this is only accurate to within a
+    /// small factor.
+    pub(crate) fn memory_usage(&self) -> usize {
+        let mut seen = HashSet::new();
+        strs_usage(&self.strs)
+            + maps_usage(&self.maps_int_float, &mut seen)
+            + maps_usage(&self.maps_int_int, &mut seen)
+            + maps_usage(&self.maps_int_str, &mut seen)
+            + maps_usage(&self.maps_str_float, &mut seen)
+            + maps_usage(&self.maps_str_int, &mut seen)
+            + maps_usage(&self.maps_str_str, &mut seen)
+    }
+
+    /// Open the files read with `getline` using `opener`.
+    pub(crate) fn set_input_opener(&mut self, opener: runtime::InputOpener) {
+        self.read_files.set_opener(opener);
+    }
+
+    pub(crate) fn profile_report(&self, w: &mut impl io::Write) -> io::Result<()> {
+        match &self.profile {
+            Some(profile) => profile.report(&self.instrs, w),
+            None => Ok(()),
+        }
+    }
+
+    /// Report the time spent in each rule of a program compiled for --profile, alongside its
+    /// `source`.
+    pub(crate) fn rule_report(&mut self, source: &str, w: &mut impl io::Write) -> io::Result<()> {
+        match &mut self.profile {
+            Some(profile) => {
+                profile.finish();
+                profile.rule_report(source, w)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Report how often each line and branch of `source` ran, for --coverage.
+    pub(crate) fn coverage_report(
+        &self,
+        source: &str,
+        outline: &Outline,
+        w: &mut impl io::Write,
+    ) -> io::Result<()> {
+        match &self.profile {
+            Some(profile) => coverage::report(profile, &self.source_map, outline, source, w),
+            None => Ok(()),
+        }
+    }
+
     fn format_arg(&self, (reg, ty): (NumTy, Ty)) -> Result<runtime::FormatArg<'a>> {
         Ok(match ty {
             Ty::Str => self.get(Reg::<Str<'a>>::from(reg)).clone().into(),
@@ -495,9 +843,139 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         })
     }
 
-    fn reset_file_vars(&mut self) {
+    /// The value of the scalar in `reg`, as passed to native functions.
+    pub(crate) fn scalar(&self, (reg, ty): (NumTy, Ty)) -> Result<Value> {
+        Ok(match ty {
+            Ty::Str => self
+                .get(Reg::<Str<'a>>::from(reg))
+                .with_bytes(|bs| bs.to_vec())
+                .into(),
+            Ty::Int => Value::Int(*self.get(Reg::<Int>::from(reg))),
+            Ty::Float => Value::Float(*self.get(Reg::<Float>::from(reg))),
+            _ => return err!("non-scalar native function argument type {:?}", ty),
+        })
+    }
+
+    /// The entries of the array in `reg`, with their keys converted to strings.
+    pub(crate) fn array(&self, (reg, ty): (NumTy, Ty)) -> Result<Vec<(String, Value)>> {
+        fn str_key(s: Str) -> String {
+            s.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned())
+        }
+        macro_rules! entries {
+            ($map:ty, $key:expr, $val:expr) => {
+                self.get(Reg::<$map>::from(reg))
+                    .iter(|es| es.map(|(k, v)| ($key(k), $val(v))).collect())
+            };
+        }
+        let int = |i: &Int| Value::Int(*i);
+        let float = |f: &Float| Value::Float(*f);
+        let str = |s: &Str| Value::from(s.with_bytes(|bs| bs.to_vec()));
+        Ok(match ty {
+            Ty::MapIntInt => entries!(runtime::IntMap<Int>, |k: Int| k.to_string(), int),
+            Ty::MapIntFloat => entries!(runtime::IntMap<Float>, |k: Int| k.to_string(), float),
+            Ty::MapIntStr => entries!(runtime::IntMap<Str<'a>>, |k: Int| k.to_string(), str),
+            Ty::MapStrInt => entries!(runtime::StrMap<'a, Int>, str_key, int),
+            Ty::MapStrFloat => entries!(runtime::StrMap<'a, Float>, str_key, float),
+            Ty::MapStrStr => entries!(runtime::StrMap<'a, Str<'a>>, str_key, str),
+            _ => return err!("non-array type {:?}", ty),
+        })
+    }
+
+    /// The value of the builtin scalar variable `var`.
+    pub(crate) fn builtin_scalar(&mut self, var: Variable) -> Result<Value> {
+        use crate::types::{BaseTy, TVar};
+        if let Variable::NF = var {
+            self.core.vars.nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)? as Int;
+        }
+        let vars = &self.core.vars;
+        Ok(match var.ty() {
+            TVar::Scalar(BaseTy::Int) => Value::Int(vars.load_int(var)?),
+            TVar::Scalar(BaseTy::Str) => Value::Str(
+                vars.load_str(var)?
+                    .with_bytes(|bs| String::from_utf8_lossy(bs).into_owned()),
+            ),
+            _ => return err!("{} is not a scalar", var),
+        })
+    }
+
+    /// The entries of the builtin array `var`, with their keys converted to strings.
+    pub(crate) fn builtin_array(&self, var: Variable) -> Result<Vec<(String, Value)>> {
+        fn str_val(s: &Str) -> String {
+            s.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned())
+        }
+        let vars = &self.core.vars;
+        Ok(match var {
+            Variable::ARGV => vars.load_intmap(var)?.iter(|es| {
+                es.map(|(k, v)| (k.to_string(), Value::Str(str_val(v))))
+                    .collect()
+            }),
+            _ => vars
+                .load_strmap(var)?
+                .iter(|es| es.map(|(k, v)| (str_val(&k), Value::Int(*v))).collect()),
+        })
+    }
+
+    /// Field `i` of the current record.
+    pub(crate) fn field(&mut self, i: Int) -> Result<Str<'a>> {
+        self.line.get_col(
+            i,
+            &self.core.vars.fs,
+            &self.core.vars.ofs,
+            &mut self.core.regexes,
+        )
+    }
+
+    /// The function and offset of each call that has not yet returned, from the outermost in.
+    pub(crate) fn call_stack(&self) -> impl ExactSizeIterator<Item = (usize, usize)> + '_ {
+        self.stack
+            .iter()
+            .map(|(func, Label(ret))| (*func, *ret - 1))
+    }
+
+    /// Write out anything the program has printed to standard output so far.
+    pub(crate) fn flush_stdout(&mut self) -> Result<()> {
+        self.core.write_files.flush_stdout()
+    }
+
+    /// Store `v` in `reg`, converting it to `ty` if the function returned a value of another type.
+    fn set_native_result(&mut self, (reg, ty): (NumTy, Ty), v: Value) -> Result<()> {
+        use runtime::convert;
+        match ty {
+            Ty::Str => {
+                *self.get_mut(Reg::<Str<'a>>::from(reg)) = match v {
+                    Value::Str(s) => s.into(),
+                    Value::Bytes(bs) => Str::copy_from_bytes(&bs),
+                    Value::Int(i) => convert::<Int, Str>(i),
+                    Value::Float(f) => convert::<Float, Str>(f),
+                }
+            }
+            Ty::Int => {
+                *self.get_mut(Reg::<Int>::from(reg)) = match v {
+                    Value::Str(s) => convert::<&Str, Int>(&s.into()),
+                    Value::Bytes(bs) => convert::<&Str, Int>(&bs[..].into()),
+                    Value::Int(i) => i,
+                    Value::Float(f) => convert::<Float, Int>(f),
+                }
+            }
+            Ty::Float => {
+                *self.get_mut(Reg::<Float>::from(reg)) = match v {
+                    Value::Str(s) => convert::<&Str, Float>(&s.into()),
+                    Value::Bytes(bs) => convert::<&Str, Float>(&bs[..].into()),
+                    Value::Int(i) => convert::<Int, Float>(i),
+                    Value::Float(f) => f,
+                }
+            }
+            _ => return err!("non-scalar native function result type {:?}", ty),
+        }
+        Ok(())
+    }
+
+    fn reset_file_vars(&mut self) -> Result<()> {
         self.core.vars.fnr = 0;
         self.core.vars.filename = self.read_files.stdin_filename().upcast();
+        self.core
+            .write_files
+            .start_input_file(self.read_files.stdin_file_index())
     }
 
     pub(crate) fn run_parallel(&mut self) -> Result<i32> {
@@ -532,6 +1010,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         if self.core.write_files.flush_stdout().is_err() {
             return Ok(1);
         }
+        self.core.write_files.start_output_by_file();
         // For handling the worker portion, we want to transfer the current stdin progress to a
         // worker thread, but to withhold any progress on other files open for read. We'll swap
         // these back in when we execute the `end` block, if there is one.
@@ -556,10 +1035,18 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
             let maps_str_str_size = self.maps_str_str.regs.len();
             let iters_int_size = self.iters_int.regs.len();
             let iters_str_size = self.iters_str.regs.len();
+            self.core.vars.set_workers(handles.len() as Int + 1);
             for (i, handle) in handles.into_iter().enumerate() {
                 let sender = sender.clone();
                 let core_shuttle = self.core.shuttle(i as Int + 2);
                 let instrs = self.instrs.clone();
+                let natives = self.natives.clone();
+                let source_map = self.source_map.clone();
+                let cancel = self.cancel.clone();
+                let profile = self
+                    .profile
+                    .as_ref()
+                    .map(|_| Box::new(Profile::new(&instrs)));
                 s.spawn(move |_| {
                     if let Some(read_files) = handle() {
                         let mut interp = Interp {
@@ -568,6 +1055,11 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             instrs,
                             stack: Default::default(),
                             core: core_shuttle(),
+                            profile,
+                            natives,
+                            cancel,
+                            source_map,
+                            debugger: None,
                             line: Default::default(),
                             read_files,
 
@@ -589,23 +1081,33 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         // exiting anyway.
                         let _ = match res {
                             Err(e) => sender.send(Err(e)),
-                            Ok(rc) => sender.send(Ok(interp.core.extract_result(rc))),
+                            Ok(rc) => {
+                                let profile = interp.profile.take().map(|mut profile| {
+                                    profile.finish();
+                                    profile
+                                });
+                                sender.send(Ok((interp.core.extract_result(rc), profile)))
+                            }
                         };
                     }
                 });
             }
             mem::drop(sender);
-            self.core.vars.pid = 1;
+            self.core.vars.set_pid(1);
             let mut rc = self.run_at(main_loop)?;
-            self.core.vars.pid = 0;
+            self.core.vars.set_pid(0);
             while let Ok(res) = receiver.recv() {
-                let res = res?;
+                let (res, profile) = res?;
+                if let (Some(mine), Some(theirs)) = (&mut self.profile, profile) {
+                    mine.merge(&theirs);
+                }
                 let sub_rc = res.rc;
                 self.core.combine(res);
                 if rc == 0 && sub_rc != 0 {
                     rc = sub_rc;
                 }
             }
+            self.core.write_files.finish_output_by_file()?;
             Ok(rc)
         });
         let rc = wrap_error(scope_res)?;
@@ -638,8 +1140,27 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         }
     }
 
+    pub(crate) fn run_at(&mut self, cur_fn: usize) -> Result<i32> {
+        let mut at = (cur_fn, 0);
+        let res = if self.profile.is_some() || self.debugger.is_some() {
+            self.run_from::<true>(cur_fn, &mut at)
+        } else {
+            self.run_from::<false>(cur_fn, &mut at)
+        };
+        res.map_err(|e| self.source_map.annotate(e, at.0, at.1))
+    }
+
+    /// Run function `cur_fn`, keeping the function and offset of the current instruction in `at`
+    /// so that errors can be reported at the right place in the program.
+    ///
+    /// Profiling and debugging are only checked for when `INSTRUMENTED` is set, so that the
+    /// instantiation that runs ordinary programs does no extra work per instruction.
     #[allow(clippy::never_loop)]
-    pub(crate) fn run_at(&mut self, mut cur_fn: usize) -> Result<i32> {
+    fn run_from<const INSTRUMENTED: bool>(
+        &mut self,
+        mut cur_fn: usize,
+        at: &mut (usize, usize),
+    ) -> Result<i32> {
         use Instr::*;
         let mut scratch: Vec<runtime::FormatArg> = Vec::new();
         // We are only accessing one vector at a time here, but it's hard to convince the borrow
@@ -648,10 +1169,23 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         let mut cur = 0;
 
         'outer: loop {
+            let prev = cur;
             // This somewhat ersatz structure is to allow 'cur' to be reassigned
             // in most but not all branches in the big match below.
             cur = loop {
                 debug_assert!(cur < unsafe { (*instrs).len() });
+                // The function only changes at calls and returns, which record it there.
+                at.1 = cur;
+                if INSTRUMENTED {
+                    if let Some(profile) = &mut self.profile {
+                        profile.record(cur_fn, cur);
+                    }
+                    if self.debugger.is_some() {
+                        if let Some(rc) = self.debug(cur_fn, cur)? {
+                            return Ok(rc);
+                        }
+                    }
+                }
                 use Variable::*;
                 match unsafe { (*instrs).get_unchecked(cur) } {
                     StoreConstStr(sr, s) => {
@@ -705,7 +1239,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l + r;
+                        *self.get_mut(res) = l.wrapping_add(r);
                     }
                     AddFloat(res, l, r) => {
                         let res = *res;
@@ -717,7 +1251,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l * r;
+                        *self.get_mut(res) = l.wrapping_mul(r);
                     }
                     MulFloat(res, l, r) => {
                         let res = *res;
@@ -729,7 +1263,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l - r;
+                        *self.get_mut(res) = l.wrapping_sub(r);
                     }
                     MinusFloat(res, l, r) => {
                         let res = *res;
@@ -741,19 +1275,19 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l % r;
+                        *self.get_mut(res) = runtime::mod_int(l, r)?;
                     }
                     ModFloat(res, l, r) => {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l % r;
+                        *self.get_mut(res) = runtime::mod_float(l, r, self.core.ieee_division)?;
                     }
                     Div(res, l, r) => {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l / r;
+                        *self.get_mut(res) = runtime::div(l, r, self.core.ieee_division)?;
                     }
                     Pow(res, l, r) => {
                         let res = *res;
@@ -775,7 +1309,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     NegInt(res, ir) => {
                         let res = *res;
                         let i = *self.get(*ir);
-                        *self.get_mut(res) = -i;
+                        *self.get_mut(res) = i.wrapping_neg();
                     }
                     NegFloat(res, fr) => {
                         let res = *res;
@@ -820,6 +1354,10 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         *index_mut(&mut self.ints, res) =
                             (bs.len() <= s_bytes.len() && s_bytes[..bs.len()] == **bs) as Int;
                     }
+                    ContainsConst(res, s, bs) => {
+                        *index_mut(&mut self.ints, res) =
+                            runtime::string_search::contains(bs, index(&self.strs, s)) as Int;
+                    }
                     Concat(res, l, r) => {
                         let res = *res;
                         let l = self.get(*l).clone();
@@ -831,6 +1369,14 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             .core
                             .match_regex(index(&self.strs, l), index(&self.strs, r))?;
                     }
+                    MatchArr(res, l, r, arr, subsep) => {
+                        *index_mut(&mut self.ints, res) = self.core.match_regex_array(
+                            index(&self.strs, l),
+                            index(&self.strs, r),
+                            index(&self.maps_str_str, arr),
+                            index(&self.strs, subsep),
+                        )?;
+                    }
                     IsMatch(res, l, r) => {
                         *index_mut(&mut self.ints, res) = self
                             .core
@@ -839,18 +1385,26 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     }
                     MatchConst(res, x, pat) => {
                         *index_mut(&mut self.ints, res) =
-                            runtime::RegexCache::regex_const_match(pat, index(&self.strs, x))
-                                as Int;
+                            self.core.match_const_regex(index(&self.strs, x), pat)?;
+                    }
+                    MatchSet(res, x, set) => {
+                        *index_mut(&mut self.ints, res) =
+                            runtime::RegexCache::regex_set_matches(set, index(&self.strs, x));
                     }
                     IsMatchConst(res, x, pat) => {
                         *index_mut(&mut self.ints, res) =
-                            self.core.match_const_regex(index(&self.strs, x), pat)?;
+                            runtime::RegexCache::regex_const_match(pat, index(&self.strs, x))
+                                as Int;
                     }
                     SubstrIndex(res, s, t) => {
                         let res = *res;
                         let s = index(&self.strs, s);
                         let t = index(&self.strs, t);
-                        *self.get_mut(res) = runtime::string_search::index_substr(t, s);
+                        *self.get_mut(res) = if self.core.vars.ignore_case() {
+                            runtime::string_search::index_substr_ignore_case(t, s)
+                        } else {
+                            runtime::string_search::index_substr(t, s)
+                        };
                     }
                     LenStr(res, s) => {
                         let res = *res;
@@ -884,6 +1438,24 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         *index_mut(&mut self.strs, in_s) = subbed;
                         *index_mut(&mut self.ints, res) = subs_made;
                     }
+                    SubConst(res, pat, s, in_s) => {
+                        let (subbed, new) = {
+                            let s = index(&self.strs, s);
+                            let in_s = index(&self.strs, in_s);
+                            in_s.subst_first(pat, s)
+                        };
+                        *index_mut(&mut self.strs, in_s) = subbed;
+                        *index_mut(&mut self.ints, res) = new as Int;
+                    }
+                    GSubConst(res, pat, s, in_s) => {
+                        let (subbed, subs_made) = {
+                            let s = index(&self.strs, s);
+                            let in_s = index(&self.strs, in_s);
+                            in_s.subst_all(pat, s)
+                        };
+                        *index_mut(&mut self.strs, in_s) = subbed;
+                        *index_mut(&mut self.ints, res) = subs_made;
+                    }
                     GenSubDynamic(res, pat, s, how, in_s) => {
                         let subbed = {
                             let pat = index(&self.strs, pat);
@@ -908,6 +1480,16 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             runtime::escape_tsv(s)
                         };
                     }
+                    EscapeJSON(res, s) => {
+                        *index_mut(&mut self.strs, res) = {
+                            let s = index(&self.strs, s);
+                            runtime::escape_json(s)
+                        };
+                    }
+                    FloatToJSON(res, f) => {
+                        *index_mut(&mut self.strs, res) =
+                            runtime::float_to_json(*index(&self.floats, f));
+                    }
                     Substr(res, base, l, r) => {
                         let base = index(&self.strs, base);
                         let len = base.len();
@@ -936,7 +1518,8 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l < r)) as Int;
+                        let ignore_case = self.core.vars.ignore_case();
+                        *self.get_mut(res) = Cmp::Lt.eval_str(l, r, ignore_case) as Int;
                     }
                     GTFloat(res, l, r) => {
                         let res = *res;
@@ -954,7 +1537,8 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l > r)) as Int;
+                        let ignore_case = self.core.vars.ignore_case();
+                        *self.get_mut(res) = Cmp::Gt.eval_str(l, r, ignore_case) as Int;
                     }
                     LTEFloat(res, l, r) => {
                         let res = *res;
@@ -972,7 +1556,8 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l <= r)) as Int;
+                        let ignore_case = self.core.vars.ignore_case();
+                        *self.get_mut(res) = Cmp::Lte.eval_str(l, r, ignore_case) as Int;
                     }
                     GTEFloat(res, l, r) => {
                         let res = *res;
@@ -990,7 +1575,8 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l >= r)) as Int;
+                        let ignore_case = self.core.vars.ignore_case();
+                        *self.get_mut(res) = Cmp::Gte.eval_str(l, r, ignore_case) as Int;
                     }
                     EQFloat(res, l, r) => {
                         let res = *res;
@@ -1008,13 +1594,21 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = (l == r) as Int;
+                        let ignore_case = self.core.vars.ignore_case();
+                        *self.get_mut(res) = Cmp::Eq.eval_str(l, r, ignore_case) as Int;
+                    }
+                    StrNumCmp(op, res, l, r) => {
+                        let res = *res;
+                        let l = self.get(*l);
+                        let r = self.get(*r);
+                        let ignore_case = self.core.vars.ignore_case();
+                        *self.get_mut(res) = op.eval_str_num(l, r, ignore_case) as Int;
                     }
                     SetColumn(dst, src) => {
                         let col = *self.get(*dst);
                         let v = index(&self.strs, src);
                         self.line
-                            .set_col(col, v, &self.core.vars.ofs, &mut self.core.regexes)?;
+                            .set_col(col, v, &self.core.vars.fs, &mut self.core.regexes)?;
                     }
                     GetColumn(dst, src) => {
                         let col = *self.get(*src);
@@ -1047,6 +1641,17 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             })?
                         };
                     }
+                    JoinJSON(dst, start, end) => {
+                        let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
+                        *index_mut(&mut self.strs, dst) = {
+                            let start = *index(&self.ints, start);
+                            let end = *index(&self.ints, end);
+                            let elts = self.line.join_cols(start, end, &",".into(), nf, |s| {
+                                runtime::escape_json(&s)
+                            })?;
+                            runtime::json_array(&elts)
+                        };
+                    }
                     JoinColumns(dst, start, end, sep) => {
                         let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
                         *index_mut(&mut self.strs, dst) = {
@@ -1105,17 +1710,14 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         for a in args {
                             scratch_strs.push(index(&self.strs, a));
                         }
-                        let res = if let Some((out_path_reg, fspec)) = output {
+                        if let Some((out_path_reg, fspec)) = output.as_deref() {
                             let out_path = index(&self.strs, out_path_reg);
                             self.core
                                 .write_files
-                                .write_all(&scratch_strs[..], Some((out_path, *fspec)))
+                                .write_all(&scratch_strs[..], Some((out_path, *fspec)))?
                         } else {
-                            self.core.write_files.write_all(&scratch_strs[..], None)
+                            self.core.write_files.write_all(&scratch_strs[..], None)?
                         };
-                        if res.is_err() {
-                            return Ok(0);
-                        }
                     }
                     Printf { output, fmt, args } => {
                         debug_assert_eq!(scratch.len(), 0);
@@ -1123,41 +1725,75 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             scratch.push(self.format_arg(*a)?);
                         }
                         let fmt_str = index(&self.strs, fmt);
-                        let res = if let Some((out_path_reg, fspec)) = output {
+                        if let Some((out_path_reg, fspec)) = output.as_deref() {
                             let out_path = index(&self.strs, out_path_reg);
                             self.core.write_files.printf(
                                 Some((out_path, *fspec)),
                                 fmt_str,
                                 &scratch[..],
-                            )
+                            )?
                         } else {
                             // print to stdout.
-                            self.core.write_files.printf(None, fmt_str, &scratch[..])
+                            self.core.write_files.printf(None, fmt_str, &scratch[..])?
                         };
-                        if res.is_err() {
-                            return Ok(0);
-                        }
                         scratch.clear();
                     }
-                    Close(file) => {
+                    Close(dst, file) => {
                         let file = index(&self.strs, file);
                         // NB this may create an unused entry in write_files. It would not be
                         // terribly difficult to optimize the close path to include an existence
                         // check first.
-                        self.core.write_files.close(file)?;
-                        self.read_files.close(file);
+                        let res = self.core.write_files.close(file)?;
+                        *index_mut(&mut self.ints, dst) = match self.read_files.close(file) {
+                            Some(status) if res < 0 => status,
+                            _ => res,
+                        };
+                    }
+                    Flush(dst, file) => {
+                        let res = self.core.write_files.flush(index(&self.strs, file))?;
+                        *index_mut(&mut self.ints, dst) = res;
+                    }
+                    FlushAll(dst) => {
+                        self.core.write_files.flush_all()?;
+                        *index_mut(&mut self.ints, dst) = 0;
                     }
                     RunCmd(dst, cmd) => {
+                        // Output printed before the command runs should appear before its output.
+                        self.core.write_files.flush_all()?;
                         *index_mut(&mut self.ints, dst) =
                             index(&self.strs, cmd).with_bytes(runtime::run_command);
                     }
                     Exit(code) => return Ok(*index(&self.ints, code) as i32),
+                    CallNative { dst, func, args } => {
+                        let args = args
+                            .iter()
+                            .map(|a| self.scalar(*a))
+                            .collect::<Result<Vec<_>>>()?;
+                        let res = match self.natives.get(*func as usize) {
+                            Some(f) => f(&args[..]),
+                            None => return err!("call to undefined native function {}", func),
+                        };
+                        self.set_native_result(*dst, res)?;
+                    }
+                    ProfileRule(rule) => {
+                        if INSTRUMENTED {
+                            if let Some(profile) = &mut self.profile {
+                                profile.mark_rule(*index(&self.ints, rule));
+                            }
+                        }
+                    }
                     Lookup {
                         map_ty,
                         dst,
                         map,
                         key,
                     } => self.lookup(*map_ty, *dst, *map, *key),
+                    LookupStr {
+                        map_ty,
+                        dst,
+                        map,
+                        key,
+                    } => self.lookup_str(*map_ty, *dst, *map, *key),
                     Contains {
                         map_ty,
                         dst,
@@ -1167,6 +1803,55 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     Delete { map_ty, map, key } => self.delete(*map_ty, *map, *key),
                     Clear { map_ty, map } => self.clear(*map_ty, *map),
                     Len { map_ty, map, dst } => self.len(*map_ty, *map, *dst),
+                    ToJSON { map_ty, dst, map } => {
+                        let (dst, map) = (*dst, *map);
+                        let res = map_regs!(*map_ty, map, self.get(map).to_json());
+                        *index_mut(&mut self.strs, &dst) = res;
+                    }
+                    JoinArray {
+                        map_ty,
+                        dst,
+                        map,
+                        sep,
+                        all,
+                    } => {
+                        let (dst, map) = (*dst, *map);
+                        let sep = index(&self.strs, sep);
+                        let all = *index(&self.ints, all) != 0;
+                        let res = map_regs!(
+                            *map_ty,
+                            map,
+                            self.get(map).join(
+                                sep,
+                                all,
+                                self.core.key_order,
+                                self.core.vars.ignore_case()
+                            )
+                        );
+                        *index_mut(&mut self.strs, &dst) = res;
+                    }
+                    ReadArray {
+                        map_ty,
+                        dst,
+                        file,
+                        map,
+                    } => {
+                        let (dst, map) = (*dst, *map);
+                        let file = index(&self.strs, file);
+                        let res = map_regs!(*map_ty, map, self.get(map).load(file).is_ok());
+                        *index_mut(&mut self.ints, &dst) = res as Int;
+                    }
+                    WriteArray {
+                        map_ty,
+                        dst,
+                        file,
+                        map,
+                    } => {
+                        let (dst, map) = (*dst, *map);
+                        let file = index(&self.strs, file);
+                        let res = map_regs!(*map_ty, map, self.get(map).save(file).is_ok());
+                        *index_mut(&mut self.ints, &dst) = res as Int;
+                    }
                     Store {
                         map_ty,
                         map,
@@ -1198,8 +1883,8 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         self.core.vars.store_str(*var, s)?;
                     }
                     LoadVarInt(dst, var) => {
-                        // If someone explicitly sets NF to a different value, this means we will
-                        // ignore it. I think that is fine.
+                        // Assignments to NF are applied to the line itself (see StoreVarInt), so
+                        // the line is always the source of truth here.
                         if let NF = *var {
                             self.core.vars.nf =
                                 self.line.nf(&self.core.vars.fs, &mut self.core.regexes)? as Int;
@@ -1211,7 +1896,14 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     StoreVarInt(var, src) => {
                         let src = *src;
                         let s = *self.get(src);
+                        if let NF = *var {
+                            self.line
+                                .set_nf(s, &self.core.vars.fs, &mut self.core.regexes)?;
+                        }
                         self.core.vars.store_int(*var, s)?;
+                        if let IGNORECASE = *var {
+                            self.core.regexes.set_ignore_case(s != 0);
+                        }
                     }
                     LoadVarIntMap(dst, var) => {
                         let arr = self.core.vars.load_intmap(*var)?;
@@ -1277,25 +1969,30 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let (changed, res) = self
                             .core
                             .regexes
-                            .get_line_stdin(&self.core.vars.rs, &mut self.read_files)?;
+                            .get_line_stdin(&self.core.vars, &mut self.read_files)?;
                         if changed {
-                            self.reset_file_vars();
+                            self.reset_file_vars()?;
                         }
                         *self.get_mut(dst) = res;
                     }
                     NextLineStdinFused() => {
+                        if INSTRUMENTED {
+                            if let Some(profile) = &mut self.profile {
+                                profile.mark_rule(crate::profile::READ_INPUT);
+                            }
+                        }
                         let changed = self.core.regexes.get_line_stdin_reuse(
-                            &self.core.vars.rs,
+                            &self.core.vars,
                             &mut self.read_files,
                             &mut self.line,
                         )?;
                         if changed {
-                            self.reset_file_vars()
+                            self.reset_file_vars()?;
                         }
                     }
                     NextFile() => {
                         self.read_files.next_file()?;
-                        self.reset_file_vars();
+                        self.reset_file_vars()?;
                     }
                     UpdateUsedFields() => {
                         let fi = &self.core.vars.fi;
@@ -1321,28 +2018,158 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     Jmp(lbl) => {
                         break lbl.0;
                     }
+                    GetColumnFloat(dst, src) => {
+                        let col = *self.get(*src);
+                        let dst = *dst;
+                        let res = runtime::convert::<_, Float>(&self.line.get_col(
+                            col,
+                            &self.core.vars.fs,
+                            &self.core.vars.ofs,
+                            &mut self.core.regexes,
+                        )?);
+                        *self.get_mut(dst) = res;
+                    }
+                    JmpIfInt(cmp, l, r, lbl) => {
+                        if cmp.eval(*self.get(*l), *self.get(*r)) {
+                            break lbl.0;
+                        }
+                    }
+                    JmpIfFloat(cmp, l, r, lbl) => {
+                        if cmp.eval(*self.get(*l), *self.get(*r)) {
+                            break lbl.0;
+                        }
+                    }
+                    JmpIfConstMatch(on, s, pat, lbl) => {
+                        let s = index(&self.strs, s);
+                        if runtime::RegexCache::regex_const_match(pat, s) == *on {
+                            break lbl.0;
+                        }
+                    }
+                    AddIntImm(res, l, r) => {
+                        let l = *self.get(*l);
+                        *self.get_mut(*res) = l.wrapping_add(*r);
+                    }
+                    EQStrConst(res, s, bs) => {
+                        let eq = index(&self.strs, s).with_bytes(|s| {
+                            if self.core.vars.ignore_case() {
+                                s.eq_ignore_ascii_case(bs)
+                            } else {
+                                s == &bs[..]
+                            }
+                        });
+                        *index_mut(&mut self.ints, res) = eq as Int;
+                    }
+                    LookupConstKey {
+                        map_ty,
+                        dst,
+                        map,
+                        key,
+                    } => {
+                        let (map_ty, dst, map) = (*map_ty, *dst, *map);
+                        let k = key.key.clone_str();
+                        let hash = key.hash;
+                        str_map_regs!(map_ty, map, dst, {
+                            let res = self.get(map).get_hashed(&k, hash);
+                            *self.get_mut(dst) = res;
+                        })
+                    }
+                    StoreConstKey {
+                        map_ty,
+                        map,
+                        val,
+                        key,
+                    } => {
+                        let (map_ty, map, val) = (*map_ty, *map, *val);
+                        let k = key.key.clone_str();
+                        let hash = key.hash;
+                        str_map_regs!(map_ty, map, val, {
+                            #[allow(clippy::clone_on_copy)]
+                            let v = self.get(val).clone();
+                            self.get(map).insert_hashed(&k, hash, v);
+                        })
+                    }
+                    IncIntConstKey {
+                        map_ty,
+                        map,
+                        dst,
+                        by,
+                        key,
+                    } => {
+                        let (map_ty, map, dst) = (*map_ty, *map, *dst);
+                        let by = *self.get(*by);
+                        let k = key.key.clone_str();
+                        let hash = key.hash;
+                        str_map_regs!(map_ty, map, dst, {
+                            let res = self.get(map).inc_int_hashed(&k, hash, by);
+                            *self.get_mut(dst) = res;
+                        })
+                    }
+                    IncFloatConstKey {
+                        map_ty,
+                        map,
+                        dst,
+                        by,
+                        key,
+                    } => {
+                        let (map_ty, map, dst) = (*map_ty, *map, *dst);
+                        let by = *self.get(*by);
+                        let k = key.key.clone_str();
+                        let hash = key.hash;
+                        str_map_regs!(map_ty, map, dst, {
+                            let res = self.get(map).inc_float_hashed(&k, hash, by);
+                            *self.get_mut(dst) = res;
+                        })
+                    }
                     Push(ty, reg) => self.push_reg(*ty, *reg),
                     Pop(ty, reg) => self.pop_reg(*ty, *reg),
                     Call(func) => {
                         self.stack.push((cur_fn, Label(cur + 1)));
                         cur_fn = *func;
+                        at.0 = cur_fn;
                         instrs = &mut self.instrs[*func];
                         break 0;
                     }
                     Ret => {
                         if let Some((func, Label(inst))) = self.stack.pop() {
                             cur_fn = func;
+                            at.0 = cur_fn;
                             instrs = &mut self.instrs[func];
                             break inst;
                         } else {
                             break 'outer Ok(0);
                         }
                     }
+                    // Markers are removed before the program runs; see source_map.
+                    Loc(_) => {}
                 };
                 break cur + 1;
             };
+            // Jumping backwards (or calling a function, which starts at 0) may start another
+            // iteration of an unbounded loop.
+            if cur <= prev {
+                if let Some(cancel) = &mut self.cancel {
+                    if let Some(max_memory) = cancel.check()? {
+                        if self.memory_usage() > max_memory {
+                            return err!("memory limit exceeded");
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// Hand control to the debugger before running the `offset`th instruction of `func`. Returns
+    /// the exit status if the debugger ends the program.
+    #[cold]
+    #[inline(never)]
+    fn debug(&mut self, func: usize, offset: usize) -> Result<Option<i32>> {
+        let mut debugger = self.debugger.take().unwrap();
+        let res = debugger.visit(self, func, offset);
+        if debugger.attached() {
+            self.debugger = Some(debugger);
         }
+        res
     }
+
     fn mov(&mut self, ty: Ty, dst: NumTy, src: NumTy) {
         match ty {
             Ty::Int => {
@@ -1395,6 +2222,26 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
             *self.get_mut(dst) = res;
         });
     }
+    fn lookup_str(&mut self, map_ty: Ty, dst: Reg<Str<'a>>, map: NumTy, key: NumTy) {
+        macro_rules! lookup_str {
+            ($map:ty, $key:ty) => {{
+                let map: Reg<$map> = map.into();
+                let key: Reg<$key> = key.into();
+                let res = match self.get(map).get_init(self.get(key)) {
+                    Some(v) => runtime::convert::<_, Str>(v),
+                    None => Str::default(),
+                };
+                *self.get_mut(dst) = res;
+            }};
+        }
+        match map_ty {
+            Ty::MapIntInt => lookup_str!(runtime::IntMap<Int>, Int),
+            Ty::MapIntFloat => lookup_str!(runtime::IntMap<Float>, Int),
+            Ty::MapStrInt => lookup_str!(runtime::StrMap<'a, Int>, Str<'a>),
+            Ty::MapStrFloat => lookup_str!(runtime::StrMap<'a, Float>, Str<'a>),
+            _ => unreachable!("string lookup for map of type {:?}", map_ty),
+        }
+    }
     fn contains(&mut self, map_ty: Ty, dst: NumTy, map: NumTy, key: NumTy) {
         let _v = 0u32;
         let dst: Reg<Int> = dst.into();
@@ -1448,7 +2295,9 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         let _k = 0u32;
         let _v = 0u32;
         map_regs!(map_ty, map, _k, _v, dst, {
-            let iter = self.get(map).to_iter();
+            let iter = self
+                .get(map)
+                .to_iter(self.core.key_order, self.core.vars.ignore_case());
             *self.get_mut(dst) = iter;
         })
     }
@@ -1567,8 +2416,8 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
     }
 }
 
-// TODO: Add a pass that does checking of indexes once.
-// That could justify no checking during interpretation.
+// Register indexes are checked once, by `verify::verify` in `Interp::new`, so release builds skip
+// the checks during interpretation.
 #[cfg(debug_assertions)]
 const CHECKED: bool = true;
 #[cfg(not(debug_assertions))]
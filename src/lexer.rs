@@ -16,9 +16,38 @@ pub struct Loc {
     offset: usize,
 }
 
+impl Loc {
+    /// The same location, with lines counted from `first_line` rather than from the start of the
+    /// program text.
+    pub fn relative_to(&self, first_line: usize) -> Loc {
+        Loc {
+            line: self.line.saturating_sub(first_line),
+            ..self.clone()
+        }
+    }
+}
+
+/// A compact form of `Loc`, for recording where statements start so that errors at runtime can
+/// point back to them.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug, Default)]
+pub struct SourceLoc {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl From<Loc> for SourceLoc {
+    fn from(l: Loc) -> SourceLoc {
+        SourceLoc {
+            line: l.line as u32,
+            col: l.col as u32,
+        }
+    }
+}
+
 pub type Spanned<T> = (Loc, T, Loc);
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Tok<'a> {
     Begin,
     Prepare,
@@ -600,12 +629,8 @@ impl<'a> Tokenizer<'a> {
                 col: ix,
                 offset,
             },
-            Ok(line) => Loc {
-                line: line - 1,
-                col: ix - self.lines[line - 1] - 1,
-                offset,
-            },
-            Err(line) => Loc {
+            // A newline is the last character on its line.
+            Ok(line) | Err(line) => Loc {
                 line,
                 col: ix - self.lines[line - 1] - 1,
                 offset,
@@ -0,0 +1,75 @@
+//! frawk is a small programming language for writing short programs processing textual data.
+//!
+//! Besides the `frawk` binary, this crate can be used to run awk programs from Rust. A
+//! [`Program`] is compiled once from source and can then be run over any number of inputs:
+//!
+//! ```
+//! let prog = frawk::Program::compile("{ sum += $2 } END { print sum }").unwrap();
+//! let mut out = Vec::new();
+//! let status = prog.run(&b"a 1\nb 2\nc 3\n"[..], &mut out).unwrap();
+//! assert_eq!(status, 0);
+//! assert_eq!(out, b"6\n");
+//! ```
+//!
+//! A [`Builder`] configures how a program is compiled and run: native functions it can call,
+//! where its files come from, limits on its resources, and the order of `for (k in m)` loops. A
+//! [`Stream`] runs a program over input pushed to it a piece at a time.
+//!
+//! The items exported at the top level of the crate make up the whole library interface. The
+//! `cli` module is exported only for the `frawk` binary and may change at any time.
+#![recursion_limit = "512"]
+#![cfg_attr(feature = "unstable", feature(core_intrinsics))]
+#![cfg_attr(feature = "unstable", feature(test))]
+#![cfg_attr(feature = "unstable", feature(write_all_vectored))]
+#[macro_use]
+mod common;
+
+mod arena;
+mod ast;
+mod builtins;
+mod bytecode;
+mod cache;
+mod cfg;
+#[doc(hidden)]
+pub mod cli;
+#[macro_use]
+mod codegen;
+mod compile;
+mod coverage;
+mod cross_stage;
+mod dataflow;
+mod debugger;
+mod display;
+mod dom;
+mod embed;
+#[cfg(test)]
+mod harness;
+mod image;
+mod input_taint;
+mod interp;
+mod lexer;
+mod lint;
+mod parallel_safety;
+#[allow(unused_parens)] // Warnings appear in generated code
+#[allow(clippy::all)]
+mod parsing;
+mod peephole;
+mod plugin;
+mod pretty;
+mod profile;
+mod pushdown;
+mod regalloc;
+mod repl;
+mod runtime;
+mod sandbox;
+mod source_map;
+mod string_constants;
+mod strnum;
+#[cfg(test)]
+mod test_string_constants;
+mod timing;
+mod types;
+mod verify;
+
+pub use embed::{Builder, CancelToken, Error, Globals, Program, Stream, Type, Value};
+pub use runtime::KeyOrder;
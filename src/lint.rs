@@ -0,0 +1,601 @@
+//! Checks for the `--lint` flag, which reports things in a program that are legal but are often
+//! mistakes, or that other awks do not support:
+//!
+//! * Variables that are read but never assigned to, and ones that BEGIN reads before it assigns to
+//!   them (including those only assigned by var=value operands, which are performed after BEGIN).
+//! * Functions that are never called.
+//! * Assignments used as conditions, which are usually a typo for `==`.
+//! * Builtin functions and variables that gawk or frawk add to POSIX awk, and PREPARE blocks.
+//! * Comparisons against string literals that look like numbers, which compare as strings.
+//!
+//! Like `--sandbox`, this only looks at the syntax of the program. BEGIN is followed in order,
+//! except that anything assigned to in a loop is treated as assigned throughout it. Variables
+//! passed to a user-defined function are assumed to be assigned by it, since they may be arrays.
+use crate::ast::{Binop, Expr, FunDec, Pattern, Prog, Rule, Stmt, Unop};
+use crate::builtins::{Function, Variable};
+use crate::common::Either;
+use crate::lexer::{self, SourceLoc};
+
+use hashbrown::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::mem;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Uninitialized,
+    UnusedFunction,
+    AssignInCondition,
+    NonPortable,
+    StringComparison,
+}
+
+impl Kind {
+    /// The name of the check, as it appears in the output of `--lint`.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Kind::Uninitialized => "uninitialized",
+            Kind::UnusedFunction => "unused-function",
+            Kind::AssignInCondition => "assign-in-condition",
+            Kind::NonPortable => "non-portable",
+            Kind::StringComparison => "string-comparison",
+        }
+    }
+}
+
+pub(crate) struct Warning {
+    // The start of the statement (or rule, for patterns) that the warning is about.
+    pub(crate) loc: SourceLoc,
+    pub(crate) kind: Kind,
+    pub(crate) message: String,
+}
+
+/// Return the warnings for `prog`, in the order they appear in the program.
+pub(crate) fn check<'a, 'b, I>(prog: &Prog<'a, 'b, I>) -> Vec<Warning>
+where
+    I: Clone + Eq + Hash + AsRef<str>,
+{
+    let rule_locs = |kind: fn(&Rule<I>) -> bool| -> Vec<SourceLoc> {
+        prog.rules
+            .iter()
+            .filter_map(|(loc, rule)| kind(rule).then_some(*loc))
+            .collect()
+    };
+    let fn_locs: HashMap<I, SourceLoc> = prog
+        .rules
+        .iter()
+        .filter_map(|(loc, rule)| match rule {
+            Rule::Function(name) => Some((name.clone(), *loc)),
+            _ => None,
+        })
+        .collect();
+
+    // The globals that each function assigns to, including through the functions it calls, and
+    // which of its parameters it may use as arrays.
+    let no_assigns = HashMap::new();
+    // Nothing is known about the parameters of each function yet, but they must still be known to
+    // exist, so that they take the place of fallback builtins.
+    let unknown_arrays: HashMap<I, Vec<bool>> = prog
+        .decs
+        .iter()
+        .map(|FunDec { name, args, .. }| (name.clone(), vec![true; args.len()]))
+        .collect();
+    let mut fn_assigns = HashMap::new();
+    let mut fn_calls = HashMap::new();
+    let mut array_params = HashMap::new();
+    for FunDec { name, args, body } in prog.decs.iter() {
+        let mut lint = Lint::new(&no_assigns, &unknown_arrays);
+        lint.enter_function(name, args);
+        lint.stmt(body);
+        let arrays = args.iter().map(|x| lint.arrays.contains(x)).collect();
+        array_params.insert(name.clone(), arrays);
+        fn_assigns.insert(name.clone(), lint.assigned);
+        fn_calls.insert(name.clone(), lint.calls);
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (f, calls) in fn_calls.iter() {
+            let added: Vec<I> = calls
+                .iter()
+                .filter_map(|g| fn_assigns.get(g))
+                .flat_map(|assigns| assigns.iter())
+                .filter(|x| !fn_assigns[f].contains(*x))
+                .cloned()
+                .collect();
+            changed |= !added.is_empty();
+            fn_assigns.get_mut(f).unwrap().extend(added);
+        }
+    }
+
+    let mut lint = Lint::new(&fn_assigns, &array_params);
+    lint.seq = Some(HashSet::new());
+    for (x, _) in prog.prelude_vardecs.iter() {
+        lint.assign(x);
+    }
+    for (loc, s) in rule_locs(|r| matches!(r, Rule::Begin))
+        .into_iter()
+        .zip(prog.begin.iter())
+    {
+        lint.loc = loc;
+        lint.stmt(s);
+    }
+    lint.seq = None;
+    for (_, x, _) in prog.operand_vardecs.iter() {
+        lint.assign(x);
+    }
+    for (loc, s) in rule_locs(|r| matches!(r, Rule::Prepare))
+        .into_iter()
+        .zip(prog.prepare.iter())
+    {
+        lint.loc = loc;
+        lint.warn(Kind::NonPortable, "PREPARE is a frawk extension".into());
+        lint.stmt(s);
+    }
+    for (loc, (pat, body)) in rule_locs(|r| matches!(r, Rule::Pattern))
+        .into_iter()
+        .zip(prog.pats.iter())
+    {
+        lint.loc = loc;
+        match pat {
+            Pattern::Null => {}
+            Pattern::Bool(e) => lint.cond(e),
+            Pattern::Comma(l, r) => {
+                lint.cond(l);
+                lint.cond(r);
+            }
+        }
+        if let Some(body) = body {
+            lint.stmt(body);
+        }
+    }
+    for (loc, s) in rule_locs(|r| matches!(r, Rule::End))
+        .into_iter()
+        .zip(prog.end.iter())
+    {
+        lint.loc = loc;
+        lint.stmt(s);
+    }
+    for FunDec { name, args, body } in prog.decs.iter() {
+        lint.loc = fn_locs.get(name).cloned().unwrap_or_default();
+        lint.enter_function(name, args);
+        lint.stmt(body);
+    }
+
+    let Lint {
+        reads,
+        assigned,
+        calls,
+        early,
+        mut warnings,
+        ..
+    } = lint;
+    for (x, loc) in reads.iter() {
+        if !assigned.contains(x) {
+            warnings.push(Warning {
+                loc: *loc,
+                kind: Kind::Uninitialized,
+                message: format!("{} is read but never assigned to", x.as_ref()),
+            });
+        }
+    }
+    for (x, loc) in early.iter() {
+        if assigned.contains(x) {
+            warnings.push(Warning {
+                loc: *loc,
+                kind: Kind::Uninitialized,
+                message: format!("BEGIN reads {} before assigning to it", x.as_ref()),
+            });
+        }
+    }
+    for FunDec { name, .. } in prog.decs.iter() {
+        if !calls.contains(name) {
+            warnings.push(Warning {
+                loc: fn_locs.get(name).cloned().unwrap_or_default(),
+                kind: Kind::UnusedFunction,
+                message: format!("function {} is never called", name.as_ref()),
+            });
+        }
+    }
+    warnings.sort_by(|w1, w2| (w1.loc, &w1.message).cmp(&(w2.loc, &w2.message)));
+    warnings.dedup_by(|w1, w2| w1.loc == w2.loc && w1.message == w2.message);
+    warnings
+}
+
+struct Lint<'c, I> {
+    // The globals assigned to by each function; see `check`.
+    fn_assigns: &'c HashMap<I, HashSet<I>>,
+    // For each function, whether each of its parameters may be an array.
+    array_params: &'c HashMap<I, Vec<bool>>,
+    // The function being checked, if any, and its parameters.
+    cur_fn: Option<&'c I>,
+    locals: &'c [I],
+    // The start of the statement being checked.
+    loc: SourceLoc,
+    // The variables used as arrays, including those passed to user-defined functions.
+    arrays: HashSet<I>,
+    // Where each global is first read.
+    reads: HashMap<I, SourceLoc>,
+    assigned: HashSet<I>,
+    // The functions called from outside of themselves.
+    calls: HashSet<I>,
+    // While checking BEGIN, the globals it has assigned to so far.
+    seq: Option<HashSet<I>>,
+    // Where BEGIN first reads each global that it has not yet assigned to.
+    early: HashMap<I, SourceLoc>,
+    warnings: Vec<Warning>,
+}
+
+/// Whether `s` is a string literal that reads as a number, like "10".
+fn looks_numeric(s: &[u8]) -> bool {
+    let s = match std::str::from_utf8(s) {
+        Ok(s) => s.trim(),
+        Err(_) => return false,
+    };
+    s.bytes().any(|b| b.is_ascii_digit())
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b))
+        && s.parse::<f64>().is_ok()
+}
+
+impl<'c, I: Clone + Eq + Hash + AsRef<str>> Lint<'c, I> {
+    fn new(
+        fn_assigns: &'c HashMap<I, HashSet<I>>,
+        array_params: &'c HashMap<I, Vec<bool>>,
+    ) -> Self {
+        Lint {
+            fn_assigns,
+            array_params,
+            arrays: HashSet::new(),
+            cur_fn: None,
+            locals: &[],
+            loc: SourceLoc::default(),
+            reads: HashMap::new(),
+            assigned: HashSet::new(),
+            calls: HashSet::new(),
+            seq: None,
+            early: HashMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn enter_function(&mut self, name: &'c I, args: &'c [I]) {
+        self.cur_fn = Some(name);
+        self.locals = args;
+    }
+
+    fn warn(&mut self, kind: Kind, message: String) {
+        self.warnings.push(Warning {
+            loc: self.loc,
+            kind,
+            message,
+        });
+    }
+
+    /// Whether `x` names a global variable of the program, rather than a local, a builtin variable
+    /// or a variable that the frontend introduces.
+    fn is_global(&mut self, x: &I) -> bool {
+        let name = x.as_ref();
+        if self.locals.contains(x) || name == "SUBSEP" || !lexer::is_ident(name) {
+            return false;
+        }
+        match Variable::try_from(name) {
+            Ok(Variable::PROCINFO | Variable::IGNORECASE) => {
+                self.warn(Kind::NonPortable, format!("{} is a gawk extension", name))
+            }
+            Ok(Variable::PID | Variable::FI) => {
+                self.warn(Kind::NonPortable, format!("{} is a frawk extension", name))
+            }
+            Ok(_) => {}
+            Err(_) => return true,
+        }
+        false
+    }
+
+    fn read(&mut self, x: &I) {
+        if !self.is_global(x) {
+            return;
+        }
+        self.reads.entry(x.clone()).or_insert(self.loc);
+        if let Some(seq) = &self.seq {
+            if !seq.contains(x) {
+                self.early.entry(x.clone()).or_insert(self.loc);
+            }
+        }
+    }
+
+    fn assign(&mut self, x: &I) {
+        if !self.is_global(x) {
+            return;
+        }
+        self.assigned.insert(x.clone());
+        if let Some(seq) = &mut self.seq {
+            seq.insert(x.clone());
+        }
+    }
+
+    /// Record that `f` has been called, along with what it assigns to.
+    fn call_user(&mut self, f: &I) {
+        if self.cur_fn != Some(f) {
+            self.calls.insert(f.clone());
+        }
+        if let (Some(seq), Some(assigns)) = (&mut self.seq, self.fn_assigns.get(f)) {
+            seq.extend(assigns.iter().cloned());
+        }
+    }
+
+    /// While checking BEGIN, treat everything that the loop `s` assigns to as assigned before it
+    /// starts, since a later iteration may read what an earlier one assigned.
+    fn enter_loop<'a, 'b>(&mut self, s: &Stmt<'a, 'b, I>) {
+        if self.seq.is_none() {
+            return;
+        }
+        let mut inner = Lint::new(self.fn_assigns, self.array_params);
+        inner.cur_fn = self.cur_fn;
+        inner.locals = self.locals;
+        inner.stmt(s);
+        let seq = self.seq.as_mut().unwrap();
+        seq.extend(inner.assigned);
+        for f in inner.calls.iter() {
+            if let Some(assigns) = self.fn_assigns.get(f) {
+                seq.extend(assigns.iter().cloned());
+            }
+        }
+    }
+
+    /// Check `e`, which is used as a condition.
+    fn cond<'a, 'b>(&mut self, e: &Expr<'a, 'b, I>) {
+        self.check_cond(e);
+        self.expr(e);
+    }
+
+    fn check_cond<'a, 'b>(&mut self, e: &Expr<'a, 'b, I>) {
+        use Expr::*;
+        match e {
+            Assign(..) => self.warn(
+                Kind::AssignInCondition,
+                "assignment used as a condition; did you mean ==?".into(),
+            ),
+            Unop(self::Unop::Not, e) => self.check_cond(e),
+            And(l, r) | Or(l, r) => {
+                self.check_cond(l);
+                self.check_cond(r);
+            }
+            _ => {}
+        }
+    }
+
+    fn compare<'a, 'b>(&mut self, l: &Expr<'a, 'b, I>, r: &Expr<'a, 'b, I>) {
+        use Expr::*;
+        let s = match (l, r) {
+            (StrLit(_), StrLit(_)) => return,
+            (StrLit(s), _) | (_, StrLit(s)) => s,
+            _ => return,
+        };
+        if looks_numeric(s) {
+            let s = String::from_utf8_lossy(s);
+            self.warn(
+                Kind::StringComparison,
+                format!(
+                    "comparison with the string \"{}\" compares strings, not numbers; write {} to compare numerically",
+                    s,
+                    s.trim()
+                ),
+            );
+        }
+    }
+
+    fn mark_array<'a, 'b>(&mut self, e: &Expr<'a, 'b, I>) {
+        if let Expr::Var(x) = e {
+            self.arrays.insert(x.clone());
+        }
+    }
+
+    /// Check `e`, which is assigned to.
+    fn lvalue<'a, 'b>(&mut self, e: &Expr<'a, 'b, I>) {
+        match e {
+            Expr::Var(x) => self.assign(x),
+            Expr::Index(arr, k) => {
+                self.expr(k);
+                self.mark_array(arr);
+                self.lvalue(arr);
+            }
+            _ => self.expr(e),
+        }
+    }
+
+    fn call<'a, 'b>(&mut self, f: &Either<I, Function>, args: &[&Expr<'a, 'b, I>]) {
+        let (name, f) = match f {
+            // Functions that the program defines take the place of fallback builtins like `join`.
+            Either::Left(name) => match Function::try_from(name.as_ref()).ok().or_else(|| {
+                Function::fallback(name.as_ref()).filter(|_| !self.array_params.contains_key(name))
+            }) {
+                Some(f) => (name.as_ref(), f),
+                None => {
+                    // Arrays are passed by reference, so the function may fill them in. Unless it
+                    // is known not to use it as an array, assume that a variable passed on its
+                    // own is one.
+                    let arrays = self.array_params.get(name);
+                    for (i, arg) in args.iter().enumerate() {
+                        match arg {
+                            Expr::Var(x) if arrays.is_none_or(|a| a.get(i) != Some(&false)) => {
+                                self.arrays.insert(x.clone());
+                                self.assign(x);
+                            }
+                            _ => self.expr(arg),
+                        }
+                    }
+                    self.call_user(name);
+                    return;
+                }
+            },
+            // Functions named by the parser rather than the program, like `delete` and `in`.
+            Either::Right(f) => ("", *f),
+        };
+        match name {
+            "gensub" | "and" | "or" | "xor" | "compl" | "lshift" | "rshift" => {
+                self.warn(Kind::NonPortable, format!("{} is a gawk extension", name))
+            }
+            "match" if args.len() == 3 => self.warn(
+                Kind::NonPortable,
+                "match with three arguments is a gawk extension".into(),
+            ),
+            "hex" | "atan" | "log2" | "log10" | "rshiftl" | "join" | "join_fields" | "join_csv"
+            | "join_tsv" | "join_json" | "to_json" | "escape_csv" | "escape_tsv" | "reada"
+            | "writea" => self.warn(Kind::NonPortable, format!("{} is a frawk extension", name)),
+            _ => {}
+        }
+        // The argument that the function assigns to, if any.
+        let out = match f {
+            Function::Split | Function::ReadArray => Some(1),
+            Function::Match => Some(2),
+            Function::Sub | Function::GSub => {
+                if let Some(arg) = args.get(2) {
+                    self.expr(arg);
+                }
+                Some(2)
+            }
+            Function::Delete | Function::Clear => Some(0),
+            _ => None,
+        };
+        if matches!(f, Function::Contains) {
+            self.mark_array(args[0]);
+        }
+        for (i, arg) in args.iter().enumerate() {
+            if Some(i) == out {
+                if !matches!(f, Function::Sub | Function::GSub) {
+                    self.mark_array(arg);
+                }
+                self.lvalue(arg);
+            } else {
+                self.expr(arg);
+            }
+        }
+    }
+
+    fn expr<'a, 'b>(&mut self, e: &Expr<'a, 'b, I>) {
+        use Expr::*;
+        match e {
+            ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | ReadStdin | Cond(_) => {}
+            Var(x) => self.read(x),
+            Unop(_, e) => self.expr(e),
+            Binop(op, l, r) => {
+                if matches!(
+                    op,
+                    self::Binop::LT
+                        | self::Binop::GT
+                        | self::Binop::LTE
+                        | self::Binop::GTE
+                        | self::Binop::EQ
+                ) {
+                    self.compare(l, r);
+                }
+                self.expr(l);
+                self.expr(r);
+            }
+            Index(arr, k) => {
+                self.mark_array(arr);
+                self.expr(arr);
+                self.expr(k);
+            }
+            And(l, r) | Or(l, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            ITE(c, t, f) => {
+                self.cond(c);
+                self.expr(t);
+                self.expr(f);
+            }
+            Assign(l, r) => {
+                self.expr(r);
+                self.lvalue(l);
+            }
+            // Updating a variable that has not been assigned to is the usual way to start a count
+            // or a sum, so it is not counted as a read.
+            AssignOp(l, _, r) => {
+                self.expr(r);
+                self.lvalue(l);
+            }
+            Inc { x, .. } => self.lvalue(x),
+            Getline { into, from, .. } => {
+                if let Some(from) = from {
+                    self.expr(from);
+                }
+                if let Some(into) = into {
+                    self.lvalue(into);
+                }
+            }
+            Call(f, args) => self.call(f, args),
+        }
+    }
+
+    fn stmt<'a, 'b>(&mut self, s: &Stmt<'a, 'b, I>) {
+        use Stmt::*;
+        if matches!(s, For(..) | DoWhile(..) | While(..) | ForEach(..)) {
+            self.enter_loop(s);
+        }
+        let out = |lint: &mut Self, o: &Option<(&self::Expr<'a, 'b, I>, _)>| {
+            if let Some((e, _)) = o {
+                lint.expr(e);
+            }
+        };
+        match s {
+            StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile => {}
+            Expr(e) => self.expr(e),
+            Return(e) => {
+                if let Some(e) = e {
+                    self.expr(e);
+                }
+            }
+            Block(stmts) => stmts.iter().for_each(|s| self.stmt(s)),
+            Print(args, o) => {
+                args.iter().for_each(|e| self.expr(e));
+                out(self, o);
+            }
+            Printf(fmt, args, o) => {
+                self.expr(fmt);
+                args.iter().for_each(|e| self.expr(e));
+                out(self, o);
+            }
+            If(c, t, f) => {
+                self.cond(c);
+                self.stmt(t);
+                if let Some(f) = f {
+                    self.stmt(f);
+                }
+            }
+            For(init, cond, update, body) => {
+                if let Some(init) = init {
+                    self.stmt(init);
+                }
+                if let Some(cond) = cond {
+                    self.cond(cond);
+                }
+                self.stmt(body);
+                if let Some(update) = update {
+                    self.stmt(update);
+                }
+            }
+            DoWhile(c, body) => {
+                self.stmt(body);
+                self.cond(c);
+            }
+            While(_, c, body) => {
+                self.cond(c);
+                self.stmt(body);
+            }
+            ForEach(x, arr, body) => {
+                self.mark_array(arr);
+                self.expr(arr);
+                self.assign(x);
+                self.stmt(body);
+            }
+            Loc(loc, s) => {
+                let prev = mem::replace(&mut self.loc, *loc);
+                self.stmt(s);
+                self.loc = prev;
+            }
+        }
+    }
+}
@@ -0,0 +1,666 @@
+//! This module decides whether a program can run its main loop on several worker threads without
+//! changing its meaning, for the `--parallel` flag.
+//!
+//! When a program runs in parallel (see info/parallelism.md), each worker runs the main loop over
+//! its share of the input with its own copy of the program's variables. Those that END uses are
+//! then aggregated across the workers. By default numbers are summed and maps are unioned, summing
+//! the values that they share, but `analyze` picks out globals that the main loop only
+//! accumulates into in some other way: keeping the largest or smallest value seen, as in
+//! `if ($1 > max) max = $1`, or appending to a map entry, as in `l[$2] = l[$2] $1 ","`. Those are
+//! combined the same way, and workers start accumulating sums and strings from scratch rather
+//! than from the values that BEGIN left, which are only counted once. A program gets the same
+//! result this way as when it runs serially, apart from the order in which its output (and
+//! concatenated strings) are written, if:
+//!
+//! * No record depends on what earlier records did. A global that the main loop changes may only
+//!   be read by a record after that record assigns to it itself, as in `{ x = $1 * 2; s += x }`.
+//! * END only sees globals (or map entries) that the main loop accumulates into in one of these
+//!   ways, and not ones that it assigns to some other way.
+//! * The main loop does not use features that depend on the position of a record in the input
+//!   (NR, FNR, range patterns), or that consume or end the input (getline, nextfile, exit).
+//! * The main loop does not close files or commands that other workers may be using.
+//!
+//! When there are several input files, workers can instead each read whole files at a time. FNR and
+//! nextfile then mean the same thing as they do serially, so only NR rules that out.
+//!
+//! This check is conservative: it is purely syntactic, it only follows assignments through
+//! straight-line code, and it treats any global that is passed to a function as modified by it.
+//! Programs with a PREPARE block are assumed to have been written with parallelism in mind, and
+//! are left alone.
+use crate::ast::{Binop, Expr, FunDec, Pattern, Prog, Stmt, Unop};
+use crate::builtins::{Function, Variable};
+use crate::common::{Aggregation, Either, ExecutionStrategy};
+
+use hashbrown::{hash_map::Entry, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::hash::Hash;
+
+/// Why running the main loop of a program in parallel might change its meaning, for each way of
+/// splitting up its input.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Hazards {
+    per_record: Option<String>,
+    per_file: Option<String>,
+}
+
+impl Hazards {
+    /// Explain why running the main loop in parallel with `strategy` might change its meaning, or
+    /// return None if it can't.
+    pub(crate) fn get(&self, strategy: ExecutionStrategy) -> Option<&str> {
+        match strategy {
+            ExecutionStrategy::Serial => None,
+            ExecutionStrategy::ShardPerRecord => self.per_record.as_deref(),
+            ExecutionStrategy::ShardPerFile => self.per_file.as_deref(),
+        }
+    }
+}
+
+/// Analyze the main loop of `prog`, returning the globals that it only accumulates into, along
+/// with how to combine the values that each worker ends up with, and the reasons (if any) that it
+/// can't run in parallel. Globals other than those returned are combined by the default rules.
+pub(crate) fn analyze<'a, 'b, I>(prog: &Prog<'a, 'b, I>) -> (HashMap<I, Aggregation>, Hazards)
+where
+    I: Clone + Eq + Hash + Display + From<&'a str>,
+    Function: TryFrom<I>,
+    Variable: TryFrom<I>,
+{
+    if !prog.prepare.is_empty() {
+        return Default::default();
+    }
+    let main = main_uses(prog);
+    let per_file = main.hazard.clone().or_else(|| {
+        if let Some(x) = main
+            .read
+            .iter()
+            .find(|x| main.assigned.contains(*x) || main.accumulated.contains_key(*x))
+        {
+            return Some(format!(
+                "the main loop reads {}, which earlier records may have changed",
+                x
+            ));
+        }
+        let mut end = Uses::new(&prog.decs[..]);
+        for stmt in prog.end.iter() {
+            end.stmt(stmt, &[], &mut HashSet::new());
+        }
+        main.assigned
+            .iter()
+            .find(|x| {
+                end.read.contains(*x)
+                    || end.assigned.contains(*x)
+                    || end.accumulated.contains_key(*x)
+            })
+            .map(|x| {
+                format!(
+                    "END uses {}, which the main loop assigns to without accumulating into it",
+                    x
+                )
+            })
+    });
+    let hazards = Hazards {
+        per_record: main
+            .hazard
+            .clone()
+            .or_else(|| main.record_hazard.clone())
+            .or_else(|| per_file.clone()),
+        per_file,
+    };
+    let Uses {
+        accumulated,
+        assigned,
+        read,
+        ..
+    } = main;
+    let aggregations = accumulated
+        .into_iter()
+        .filter(|(x, _)| !assigned.contains(x) && !read.contains(x))
+        .collect();
+    (aggregations, hazards)
+}
+
+fn main_uses<'p, 'a, 'b, I>(prog: &'p Prog<'a, 'b, I>) -> Uses<'p, 'a, 'b, I>
+where
+    I: Clone + Eq + Hash + Display + From<&'a str>,
+    Function: TryFrom<I>,
+    Variable: TryFrom<I>,
+{
+    let mut main = Uses::new(&prog.decs[..]);
+    for (pat, body) in prog.pats.iter() {
+        let mut defined = HashSet::new();
+        if let (Pattern::Bool(cond), Some(body)) = (pat, body) {
+            if main.min_max_if(cond, body, &[], &mut defined) {
+                continue;
+            }
+        }
+        match pat {
+            Pattern::Null => {}
+            Pattern::Bool(pat) => main.expr(pat, &[], &mut defined),
+            Pattern::Comma(..) => {
+                main.hazard("the program uses range patterns".into());
+            }
+        }
+        match (pat, body) {
+            (Pattern::Null, Some(body)) => main.stmt(body, &[], &mut defined),
+            (_, Some(body)) => main.stmt(body, &[], &mut defined.clone()),
+            (_, None) => main.output_seps(&[], &defined),
+        }
+    }
+    main
+}
+
+/// Whether `a` and `b` are the same expression, and evaluating it has no side effects.
+fn same<I: Eq>(a: &Expr<I>, b: &Expr<I>) -> bool {
+    use Expr::*;
+    match (a, b) {
+        (ILit(x), ILit(y)) => x == y,
+        (FLit(x), FLit(y)) => x.to_bits() == y.to_bits(),
+        (StrLit(x), StrLit(y)) => x == y,
+        (Var(x), Var(y)) => x == y,
+        (Unop(o1, x), Unop(o2, y)) => o1 == o2 && same(x, y),
+        (Binop(o1, l1, r1), Binop(o2, l2, r2)) => o1 == o2 && same(l1, l2) && same(r1, r2),
+        (Index(m1, k1), Index(m2, k2)) => same(m1, m2) && same(k1, k2),
+        _ => false,
+    }
+}
+
+/// Whether `e` refers to the variable `x`.
+fn mentions<I: Eq>(e: &Expr<I>, x: &I) -> bool {
+    use Expr::*;
+    match e {
+        ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | Cond(_) | ReadStdin => false,
+        Var(y) => y == x,
+        Unop(_, e) => mentions(e, x),
+        Binop(_, l, r) | Index(l, r) | Assign(l, r) | AssignOp(l, _, r) | And(l, r) | Or(l, r) => {
+            mentions(l, x) || mentions(r, x)
+        }
+        ITE(c, t, f) => mentions(c, x) || mentions(t, x) || mentions(f, x),
+        Inc { x: e, .. } => mentions(e, x),
+        Getline { into, from, .. } => into.iter().chain(from.iter()).any(|e| mentions(e, x)),
+        Call(_, args) => args.iter().any(|e| mentions(e, x)),
+    }
+}
+
+/// The variable that an assignment to `e` changes, if `e` is a variable or a map entry.
+fn target<'e, 'a, 'b, I>(e: &'e Expr<'a, 'b, I>) -> Option<&'e I> {
+    match e {
+        Expr::Var(x) | Expr::Index(Expr::Var(x), _) => Some(x),
+        _ => None,
+    }
+}
+
+/// The operands of a comparison `cond`, larger first: `(a, b)` if `cond` holds when `a` is greater
+/// than `b`.
+fn ordered<'e, 'a, 'b, I>(
+    cond: &'e Expr<'a, 'b, I>,
+) -> Option<(&'e Expr<'a, 'b, I>, &'e Expr<'a, 'b, I>)> {
+    match cond {
+        Expr::Binop(Binop::GT | Binop::GTE, l, r) => Some((l, r)),
+        Expr::Binop(Binop::LT | Binop::LTE, l, r) => Some((r, l)),
+        _ => None,
+    }
+}
+
+/// The lvalue and value of `stmt`, if it is a single assignment.
+fn single_assignment<'e, 'a, 'b, I>(
+    stmt: &'e Stmt<'a, 'b, I>,
+) -> Option<(&'e Expr<'a, 'b, I>, &'e Expr<'a, 'b, I>)> {
+    match stmt {
+        Stmt::Expr(Expr::Assign(x, v)) => Some((x, v)),
+        Stmt::Block(stmts) if stmts.len() == 1 => single_assignment(stmts[0]),
+        Stmt::Loc(_, s) => single_assignment(s),
+        _ => None,
+    }
+}
+
+/// Whether `e` is `x` followed by a sequence of concatenations; the values concatenated onto it
+/// are pushed to `rest`.
+fn appends_to<'e, 'a, 'b, I: Eq>(
+    x: &Expr<'a, 'b, I>,
+    e: &'e Expr<'a, 'b, I>,
+    rest: &mut Vec<&'e Expr<'a, 'b, I>>,
+) -> bool {
+    if same(x, e) {
+        return true;
+    }
+    match e {
+        Expr::Binop(Binop::Concat, l, r) if appends_to(x, l, rest) => {
+            rest.push(r);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The globals that a part of a program uses, and how.
+struct Uses<'p, 'a, 'b, I> {
+    decs: &'p [FunDec<'a, 'b, I>],
+    // The user-defined functions that we have already looked at.
+    visited: HashSet<I>,
+    // Globals read before they are assigned to in the same record.
+    read: HashSet<I>,
+    // Globals assigned to, or map entries assigned to, other than by adding to them.
+    assigned: HashSet<I>,
+    // Globals, or map entries, that are only accumulated into: added to with `+=`, `-=`, `++` or
+    // `--`, replaced by larger or smaller values, or appended to. Those accumulated into in more
+    // than one way are also in `assigned`.
+    accumulated: HashMap<I, Aggregation>,
+    // The first construct we found that rules out running in parallel.
+    hazard: Option<String>,
+    // The first construct we found that rules out handing records from the same file to different
+    // workers, but not having each worker read whole files, as FNR and nextfile then behave as
+    // they do serially.
+    record_hazard: Option<String>,
+}
+
+impl<'p, 'a, 'b, I> Uses<'p, 'a, 'b, I>
+where
+    I: Clone + Eq + Hash + Display + From<&'a str>,
+    Function: TryFrom<I>,
+    Variable: TryFrom<I>,
+{
+    fn new(decs: &'p [FunDec<'a, 'b, I>]) -> Self {
+        Uses {
+            decs,
+            visited: HashSet::new(),
+            read: HashSet::new(),
+            assigned: HashSet::new(),
+            accumulated: HashMap::new(),
+            hazard: None,
+            record_hazard: None,
+        }
+    }
+
+    fn hazard(&mut self, reason: String) {
+        self.hazard.get_or_insert(reason);
+    }
+
+    /// Whether `x` is a global whose value is carried from one record to the next. Function
+    /// parameters in `locals` are not, nor are variables that frawk sets for each record.
+    fn is_global(&mut self, x: &I, locals: &[I]) -> bool {
+        if locals.contains(x) {
+            return false;
+        }
+        match Variable::try_from(x.clone()) {
+            Ok(Variable::NR) => {
+                self.hazard("the main loop uses NR".into());
+                false
+            }
+            Ok(Variable::FNR) => {
+                self.record_hazard
+                    .get_or_insert_with(|| "the main loop uses FNR".into());
+                false
+            }
+            Ok(Variable::NF)
+            | Ok(Variable::FILENAME)
+            | Ok(Variable::RSTART)
+            | Ok(Variable::RLENGTH)
+            | Ok(Variable::PID)
+            | Ok(Variable::PROCINFO) => false,
+            _ => true,
+        }
+    }
+
+    fn read_var(&mut self, x: &I, locals: &[I], defined: &HashSet<I>) {
+        if self.is_global(x, locals) && !defined.contains(x) {
+            self.read.insert(x.clone());
+        }
+    }
+
+    /// Record an assignment to the lvalue `e`, other than by adding to it. Assigning to a whole
+    /// variable gives it a value for the rest of the record.
+    fn assign(&mut self, e: &Expr<'a, 'b, I>, locals: &[I], defined: &mut HashSet<I>) {
+        match e {
+            Expr::Var(x) => {
+                self.assign_var(x, locals);
+                defined.insert(x.clone());
+            }
+            Expr::Index(Expr::Var(m), k) => {
+                self.expr(k, locals, defined);
+                self.assign_var(m, locals);
+            }
+            Expr::Unop(Unop::Column, col) => self.expr(col, locals, defined),
+            e => self.expr(e, locals, defined),
+        }
+    }
+
+    fn assign_var(&mut self, x: &I, locals: &[I]) {
+        if matches!(
+            Variable::try_from(x.clone()),
+            Ok(Variable::FS) | Ok(Variable::RS)
+        ) {
+            self.hazard(format!(
+                "the main loop assigns to {}, which changes how later records are read",
+                x
+            ));
+        }
+        if self.is_global(x, locals) {
+            self.assigned.insert(x.clone());
+        }
+    }
+
+    /// Record a use of `e` as an operand of `+=`, `-=`, `++` or `--` whose result is discarded.
+    fn add_to(&mut self, e: &Expr<'a, 'b, I>, locals: &[I], defined: &mut HashSet<I>) {
+        if !self.accumulate(e, Aggregation::Sum, locals, defined) {
+            self.expr(e, locals, defined);
+            self.assign(e, locals, defined);
+        }
+    }
+
+    /// Record an update to the lvalue `e` that combines its old value with another using `agg`, if
+    /// `e` is a global or an entry in a global map. Returns false, recording nothing, otherwise.
+    fn accumulate(
+        &mut self,
+        e: &Expr<'a, 'b, I>,
+        agg: Aggregation,
+        locals: &[I],
+        defined: &mut HashSet<I>,
+    ) -> bool {
+        let x = match e {
+            Expr::Var(x) if !defined.contains(x) && self.is_global(x, locals) => x,
+            Expr::Index(Expr::Var(m), k) if self.is_global(m, locals) => {
+                self.expr(k, locals, defined);
+                m
+            }
+            _ => return false,
+        };
+        match self.accumulated.entry(x.clone()) {
+            Entry::Occupied(o) => {
+                if *o.get() != agg {
+                    self.assigned.insert(x.clone());
+                }
+            }
+            Entry::Vacant(v) => {
+                v.insert(agg);
+            }
+        }
+        true
+    }
+
+    /// Record `x = v`, where `v` is the larger (for `Max`) or smaller of `x` and some other value
+    /// `y`. Returns false, recording nothing, if `y` depends on `x`.
+    fn extremum(
+        &mut self,
+        x: &Expr<'a, 'b, I>,
+        y: &Expr<'a, 'b, I>,
+        agg: Aggregation,
+        locals: &[I],
+        defined: &mut HashSet<I>,
+    ) -> bool {
+        if matches!(target(x), Some(m) if mentions(y, m)) {
+            return false;
+        }
+        if !self.accumulate(x, agg, locals, defined) {
+            return false;
+        }
+        self.expr(y, locals, defined);
+        true
+    }
+
+    /// Record `if (cond) body` if it replaces a global with another value when that value is
+    /// larger or smaller, as in `if ($1 > max) max = $1`. Returns false if it does not.
+    fn min_max_if(
+        &mut self,
+        cond: &Expr<'a, 'b, I>,
+        body: &Stmt<'a, 'b, I>,
+        locals: &[I],
+        defined: &mut HashSet<I>,
+    ) -> bool {
+        let (x, v) = match single_assignment(body) {
+            Some(assign) => assign,
+            None => return false,
+        };
+        let agg = match ordered(cond) {
+            Some((hi, lo)) if same(hi, v) && same(lo, x) => Aggregation::Max,
+            Some((hi, lo)) if same(hi, x) && same(lo, v) => Aggregation::Min,
+            _ => return false,
+        };
+        self.extremum(x, v, agg, locals, defined)
+    }
+
+    /// Record `x = v` if it accumulates into `x`: as in `m[k] = m[k] $1`, which appends to a map
+    /// entry, or `x = $1 > x ? $1 : x`, which keeps the larger value. Returns false if it does not.
+    fn accumulate_assign(
+        &mut self,
+        x: &Expr<'a, 'b, I>,
+        v: &Expr<'a, 'b, I>,
+        locals: &[I],
+        defined: &mut HashSet<I>,
+    ) -> bool {
+        if let Expr::ITE(cond, t, f) = v {
+            let (hi, lo) = match ordered(cond) {
+                Some(ops) => ops,
+                None => return false,
+            };
+            let y = if same(hi, x) {
+                lo
+            } else if same(lo, x) {
+                hi
+            } else {
+                return false;
+            };
+            let agg = if same(t, hi) && same(f, lo) {
+                Aggregation::Max
+            } else if same(t, lo) && same(f, hi) {
+                Aggregation::Min
+            } else {
+                return false;
+            };
+            return self.extremum(x, y, agg, locals, defined);
+        }
+        // Workers' strings are concatenated in no particular order. That is fine for map entries,
+        // which usually collect values under distinct keys, but a single string would come out
+        // differently from run to run.
+        let m = match (x, target(x)) {
+            (Expr::Index(..), Some(m)) => m,
+            _ => return false,
+        };
+        let mut rest = Vec::new();
+        if same(x, v)
+            || !appends_to(x, v, &mut rest)
+            || rest.iter().any(|e| mentions(e, m))
+            || !self.accumulate(x, Aggregation::Concat, locals, defined)
+        {
+            return false;
+        }
+        for e in rest {
+            self.expr(e, locals, defined);
+        }
+        true
+    }
+
+    /// Record the use of the output separators by print.
+    fn output_seps(&mut self, locals: &[I], defined: &HashSet<I>) {
+        self.read_var(&"OFS".into(), locals, defined);
+        self.read_var(&"ORS".into(), locals, defined);
+    }
+
+    fn call(
+        &mut self,
+        name: &I,
+        args: &[&Expr<'a, 'b, I>],
+        locals: &[I],
+        defined: &mut HashSet<I>,
+    ) {
+        let decs = self.decs;
+        let dec = match decs.iter().find(|d| &d.name == name) {
+            Some(dec) => dec,
+            // sprintf, which is not in the builtin table.
+            None => {
+                for arg in args {
+                    self.expr(arg, locals, defined);
+                }
+                return;
+            }
+        };
+        for arg in args {
+            self.expr(arg, locals, defined);
+            // Maps are passed by reference, so the function may change them.
+            if let Expr::Var(x) = arg {
+                self.assign_var(x, locals);
+            }
+        }
+        if self.visited.insert(name.clone()) {
+            self.stmt(dec.body, &dec.args[..], &mut HashSet::new());
+        }
+    }
+
+    fn builtin(
+        &mut self,
+        f: Function,
+        args: &[&Expr<'a, 'b, I>],
+        locals: &[I],
+        defined: &mut HashSet<I>,
+    ) {
+        use Function::*;
+        match f {
+            Exit => self.hazard("the main loop calls exit".into()),
+            Close => self.hazard("the main loop calls close".into()),
+            _ => {}
+        }
+        // The maps that these functions fill in, replacing their contents.
+        let filled = match f {
+            Split | ReadArray => 1,
+            MatchArray => 2,
+            Clear => 0,
+            _ => args.len(),
+        };
+        for (i, arg) in args.iter().enumerate() {
+            match (f, i) {
+                (_, i) if i == filled => self.assign(arg, locals, defined),
+                (Delete, 0) => match arg {
+                    Expr::Var(m) => self.assign_var(m, locals),
+                    _ => self.expr(arg, locals, defined),
+                },
+                // sub and gsub change their third argument.
+                (Sub | GSub, 2) => {
+                    self.expr(arg, locals, defined);
+                    self.assign(arg, locals, defined);
+                }
+                _ => self.expr(arg, locals, defined),
+            }
+        }
+    }
+
+    fn expr(&mut self, e: &Expr<'a, 'b, I>, locals: &[I], defined: &mut HashSet<I>) {
+        use Expr::*;
+        match e {
+            ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | Cond(_) => {}
+            Var(x) => self.read_var(x, locals, defined),
+            Unop(_, e) => self.expr(e, locals, defined),
+            Binop(_, l, r) | Index(l, r) => {
+                self.expr(l, locals, defined);
+                self.expr(r, locals, defined);
+            }
+            // Assignments on the right-hand side of these only happen some of the time.
+            And(l, r) | Or(l, r) => {
+                self.expr(l, locals, defined);
+                self.expr(r, locals, &mut defined.clone());
+            }
+            ITE(c, t, f) => {
+                self.expr(c, locals, defined);
+                self.expr(t, locals, &mut defined.clone());
+                self.expr(f, locals, &mut defined.clone());
+            }
+            Assign(l, r) => {
+                self.expr(r, locals, defined);
+                self.assign(l, locals, defined);
+            }
+            AssignOp(l, _, r) => {
+                self.expr(r, locals, defined);
+                self.expr(l, locals, defined);
+                self.assign(l, locals, defined);
+            }
+            Inc { x, .. } => {
+                self.expr(x, locals, defined);
+                self.assign(x, locals, defined);
+            }
+            Getline { .. } | ReadStdin => self.hazard("the main loop calls getline".into()),
+            Call(Either::Right(f), args) => self.builtin(*f, args, locals, defined),
+            Call(Either::Left(name), args) => match Function::try_from(name.clone()) {
+                Ok(f) => self.builtin(f, args, locals, defined),
+                Err(_) => self.call(name, args, locals, defined),
+            },
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt<'a, 'b, I>, locals: &[I], defined: &mut HashSet<I>) {
+        use Stmt::*;
+        match stmt {
+            StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next => {}
+            NextFile => {
+                self.record_hazard
+                    .get_or_insert_with(|| "the main loop calls nextfile".into());
+            }
+            Expr(self::Expr::AssignOp(l, Binop::Plus | Binop::Minus, r)) => {
+                self.expr(r, locals, defined);
+                self.add_to(l, locals, defined);
+            }
+            Expr(self::Expr::Inc { x, .. }) => self.add_to(x, locals, defined),
+            Expr(self::Expr::Assign(x, v)) if self.accumulate_assign(x, v, locals, defined) => {}
+            If(c, t, None) if self.min_max_if(c, t, locals, defined) => {}
+            Expr(e) | Return(Some(e)) => self.expr(e, locals, defined),
+            Return(None) => {}
+            Block(stmts) => {
+                for s in stmts.iter() {
+                    self.stmt(s, locals, defined);
+                }
+            }
+            Print(args, out) => {
+                for arg in args.iter() {
+                    self.expr(arg, locals, defined);
+                }
+                if let Some((out, _)) = out {
+                    self.expr(out, locals, defined);
+                }
+                self.output_seps(locals, defined);
+            }
+            Printf(fmt, args, out) => {
+                self.expr(fmt, locals, defined);
+                for arg in args.iter() {
+                    self.expr(arg, locals, defined);
+                }
+                if let Some((out, _)) = out {
+                    self.expr(out, locals, defined);
+                }
+            }
+            If(c, t, f) => {
+                self.expr(c, locals, defined);
+                self.stmt(t, locals, &mut defined.clone());
+                if let Some(f) = f {
+                    self.stmt(f, locals, &mut defined.clone());
+                }
+            }
+            For(init, cond, update, body) => {
+                if let Some(init) = init {
+                    self.stmt(init, locals, defined);
+                }
+                if let Some(cond) = cond {
+                    self.expr(cond, locals, defined);
+                }
+                let mut inner = defined.clone();
+                self.stmt(body, locals, &mut inner);
+                if let Some(update) = update {
+                    self.stmt(update, locals, &mut inner);
+                }
+            }
+            While(_, c, body) => {
+                self.expr(c, locals, defined);
+                self.stmt(body, locals, &mut defined.clone());
+            }
+            DoWhile(c, body) => {
+                // The body always runs at least once.
+                self.stmt(body, locals, defined);
+                self.expr(c, locals, defined);
+            }
+            ForEach(v, arr, body) => {
+                self.expr(arr, locals, defined);
+                self.assign_var(v, locals);
+                let mut inner = defined.clone();
+                inner.insert(v.clone());
+                self.stmt(body, locals, &mut inner);
+            }
+            Loc(_, s) => self.stmt(s, locals, defined),
+        }
+    }
+}
@@ -0,0 +1,1573 @@
+//! A cleanup pass over the bytecode emitted by `compile::Typer::to_bytecode`.
+//!
+//! Lowering to bytecode happens one basic block at a time, and it leaves a fair amount of work
+//! that can be done ahead of time: constants are materialized into fresh registers before every
+//! use, conversions are emitted back-to-back, and empty blocks leave chains of jumps behind. This
+//! module performs a few cheap optimizations to clean those cases up:
+//!
+//! * Constant folding (and propagation within a basic block) for arithmetic, comparisons and
+//!   conversions whose operands are known constants. Conditional jumps on constants are replaced
+//!   with unconditional jumps, or removed. String comparisons depend on `IGNORECASE` as well, so
+//!   they are only folded in programs that never assign to it.
+//! * Collapsing conversions that undo one another, such as `IntToStr` followed by `StrToInt`.
+//! * Threading jumps that target another unconditional jump (or a `Ret`), and removing jumps to
+//!   the following instruction.
+//! * Removing unreachable instructions, and pure instructions whose destination is never read.
+//! * Fusing common instruction sequences into a single superinstruction: a column read followed
+//!   by a conversion to a float, a numeric comparison followed by a conditional jump on its
+//!   result, and a map lookup, addition and store back to the same key. Map operations with a
+//!   constant string key are fused with the constant, so the key can be hashed ahead of time.
+//! * Folding constant operands of integer additions and string equality tests into the
+//!   instruction, so they are not stored into a register on every iteration of a loop.
+//! * Hoisting instructions that compute the same value on every iteration of a loop (including
+//!   the main loop over input records) in front of the loop. Besides constants and conversions,
+//!   this covers dynamic regular expressions built from values that do not change: the pattern is
+//!   then the same string on every iteration, which `RegexCache` looks up without hashing it.
+//!
+//! All functions share the same register files in the interpreter, so "never read" is computed
+//! over the entire program rather than a single function.
+use crate::builtins::Variable;
+use crate::bytecode::{Accum, Cmp, ConstKey, Instr, Label, Reg};
+use crate::common::NumTy;
+use crate::compile::Ty;
+use crate::regalloc;
+use crate::runtime::{self, Float, Int, Str, UniqueStr};
+
+use hashbrown::{HashMap, HashSet};
+
+use std::sync::Arc;
+
+/// Optimize the instructions for all functions in a program. The registers in `live` are read
+/// once the program has finished, so every write to them is kept.
+pub(crate) fn optimize<'a>(funcs: &mut [Vec<Instr<'a>>], live: &[(NumTy, Ty)]) {
+    let mut removed: Vec<Vec<bool>> = funcs.iter().map(|f| vec![false; f.len()]).collect();
+    let may_ignore_case = funcs.iter().flatten().any(sets_ignorecase);
+    for (instrs, removed) in funcs.iter_mut().zip(removed.iter_mut()) {
+        fold_constants(instrs, removed, may_ignore_case);
+        thread_jumps(instrs, removed);
+        remove_unreachable(instrs, removed);
+    }
+    remove_dead_stores(funcs, &mut removed, live);
+    fuse(funcs, &mut removed, live);
+    fuse_const_keys(funcs, &mut removed, live);
+    fuse_immediates(funcs, &mut removed, live);
+    for (instrs, removed) in funcs.iter_mut().zip(removed.iter_mut()) {
+        invert_branches(instrs, removed);
+        remove_fallthrough_jumps(instrs, removed);
+        compact(instrs, removed);
+    }
+    hoist_invariants(funcs, live);
+}
+
+fn sets_ignorecase(inst: &Instr) -> bool {
+    matches!(inst, Instr::StoreVarInt(Variable::IGNORECASE, _))
+}
+
+/// Whether the result of `inst` depends on `IGNORECASE`, besides its operands.
+fn reads_ignorecase(inst: &Instr) -> bool {
+    use Instr::*;
+    matches!(
+        inst,
+        LTStr(..)
+            | GTStr(..)
+            | LTEStr(..)
+            | GTEStr(..)
+            | EQStr(..)
+            | StrNumCmp(..)
+            | EQStrConst(..)
+            | SubstrIndex(..)
+    )
+}
+
+/// If `inst` has no side-effects beyond writing to a single destination register, return that
+/// register. For all of these instructions, the destination is the first register passed to the
+/// `accum` callback.
+///
+/// Division and modulo are left out, as they fail when the divisor is zero.
+fn pure_dst(inst: &Instr) -> Option<(NumTy, Ty)> {
+    use Instr::*;
+    match inst {
+        StoreConstStr(..) | StoreConstInt(..) | StoreConstFloat(..) | IntToStr(..)
+        | FloatToStr(..) | StrToInt(..) | HexStrToInt(..) | FloatToInt(..) | IntToFloat(..)
+        | StrToFloat(..) | Mov(..) | AddInt(..) | AddFloat(..) | MulFloat(..) | MulInt(..)
+        | Pow(..) | MinusFloat(..) | MinusInt(..) | Not(..) | NotStr(..) | NegInt(..)
+        | NegFloat(..) | Float1(..) | Float2(..) | Concat(..) | StartsWithConst(..)
+        | ContainsConst(..) | IsMatchConst(..) | MatchSet(..) | SubstrIndex(..) | LenStr(..)
+        | EscapeCSV(..) | EscapeTSV(..) | EscapeJSON(..) | FloatToJSON(..) | Substr(..)
+        | LTFloat(..) | LTInt(..) | LTStr(..) | GTFloat(..) | GTInt(..) | GTStr(..)
+        | LTEFloat(..) | LTEInt(..) | LTEStr(..) | GTEFloat(..) | GTEInt(..) | GTEStr(..)
+        | EQFloat(..) | EQInt(..) | EQStr(..) | StrNumCmp(..) | ToUpperAscii(..)
+        | ToLowerAscii(..) | AddIntImm(..) | EQStrConst(..) => {
+            let mut res = None;
+            inst.accum(|reg, ty| {
+                if res.is_none() {
+                    res = Some((reg, ty))
+                }
+            });
+            res
+        }
+        _ => None,
+    }
+}
+
+/// Call `f` on every register read by `inst`. Registers written by most instructions with
+/// side-effects are included as well; that is conservative for the purposes of dead store
+/// elimination.
+fn for_each_read(inst: &Instr, mut f: impl FnMut((NumTy, Ty))) {
+    // Column reads and map lookups also pass their destination first.
+    let has_dst = pure_dst(inst).is_some()
+        || matches!(
+            inst,
+            Instr::GetColumn(..)
+                | Instr::Lookup { .. }
+                | Instr::LookupStr { .. }
+                | Instr::LookupConstKey { .. }
+        );
+    let skip = has_dst as usize;
+    let mut n = 0;
+    inst.accum(|reg, ty| {
+        if n >= skip {
+            f((reg, ty))
+        }
+        n += 1;
+    });
+}
+
+pub(crate) fn jump_target(inst: &Instr) -> Option<usize> {
+    match inst {
+        Instr::Jmp(Label(l))
+        | Instr::JmpIf(_, Label(l))
+        | Instr::JmpIfInt(_, _, _, Label(l))
+        | Instr::JmpIfFloat(_, _, _, Label(l))
+        | Instr::JmpIfConstMatch(_, _, _, Label(l)) => Some(*l),
+        _ => None,
+    }
+}
+
+fn jump_target_mut<'b>(inst: &'b mut Instr) -> Option<&'b mut Label> {
+    match inst {
+        Instr::Jmp(l)
+        | Instr::JmpIf(_, l)
+        | Instr::JmpIfInt(_, _, _, l)
+        | Instr::JmpIfFloat(_, _, _, l)
+        | Instr::JmpIfConstMatch(_, _, _, l) => Some(l),
+        _ => None,
+    }
+}
+
+enum Const<'a> {
+    Int(Int),
+    Float(Float),
+    Str(Str<'a>),
+}
+
+#[derive(Default)]
+struct Folder<'a> {
+    consts: HashMap<(NumTy, Ty), Const<'a>>,
+    // Str registers holding the output of an IntToStr, mapped to the Int register they came from.
+    int_strs: HashMap<NumTy, NumTy>,
+    // Set if the program assigns to IGNORECASE, in which case string comparisons are left alone.
+    may_ignore_case: bool,
+}
+
+impl<'a> Folder<'a> {
+    fn clear(&mut self) {
+        self.consts.clear();
+        self.int_strs.clear();
+    }
+
+    fn int(&self, r: &Reg<Int>) -> Option<Int> {
+        match self.consts.get(&r.reflect()) {
+            Some(Const::Int(i)) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn float(&self, r: &Reg<Float>) -> Option<Float> {
+        match self.consts.get(&r.reflect()) {
+            Some(Const::Float(f)) => Some(*f),
+            _ => None,
+        }
+    }
+
+    fn str(&self, r: &Reg<Str<'a>>) -> Option<&Str<'a>> {
+        match self.consts.get(&r.reflect()) {
+            Some(Const::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn clobber(&mut self, reg: NumTy, ty: Ty) {
+        self.consts.remove(&(reg, ty));
+        match ty {
+            Ty::Str => {
+                self.int_strs.remove(&reg);
+            }
+            Ty::Int => self.int_strs.retain(|_, i| *i != reg),
+            _ => {}
+        }
+    }
+
+    /// Compute a cheaper instruction equivalent to `inst` given what we know, if there is one.
+    fn simplify(&self, inst: &Instr<'a>) -> Option<Instr<'a>> {
+        use Instr::*;
+        macro_rules! fold {
+            ($get:ident, $store:ident, $res:expr, [$($reg:expr),*], |$($x:ident),*| $e:expr) => {
+                match ($(self.$get($reg),)*) {
+                    ($(Some($x),)*) => Some($store(*$res, $e)),
+                    _ => None,
+                }
+            };
+        }
+        macro_rules! fold_str {
+            ($res:expr, $s:expr) => {
+                StoreConstStr($res, UniqueStr::from($s))
+            };
+        }
+        match inst {
+            Mov(ty, dst, src) => match (ty, self.consts.get(&(*src, *ty))) {
+                (Ty::Int, Some(Const::Int(i))) => Some(StoreConstInt((*dst).into(), *i)),
+                (Ty::Float, Some(Const::Float(f))) => Some(StoreConstFloat((*dst).into(), *f)),
+                (Ty::Str, Some(Const::Str(s))) => Some(fold_str!(Reg::from(*dst), s.clone())),
+                _ => None,
+            },
+            IntToStr(sr, ir) => self
+                .int(ir)
+                .map(|i| fold_str!(*sr, runtime::convert::<_, Str>(i))),
+            FloatToStr(sr, fr) => self
+                .float(fr)
+                .map(|f| fold_str!(*sr, runtime::convert::<_, Str>(f))),
+            StrToInt(ir, sr) => match (self.str(sr), self.int_strs.get(&sr.reflect().0)) {
+                (Some(s), _) => Some(StoreConstInt(*ir, runtime::convert::<_, Int>(s))),
+                (None, Some(src)) => Some(Mov(Ty::Int, ir.reflect().0, *src)),
+                (None, None) => None,
+            },
+            StrToFloat(fr, sr) => match (self.str(sr), self.int_strs.get(&sr.reflect().0)) {
+                (Some(s), _) => Some(StoreConstFloat(*fr, runtime::convert::<_, Float>(s))),
+                (None, Some(src)) => Some(IntToFloat(*fr, Reg::from(*src))),
+                (None, None) => None,
+            },
+            HexStrToInt(ir, sr) => self
+                .str(sr)
+                .map(|s| StoreConstInt(*ir, s.with_bytes(runtime::hextoi))),
+            FloatToInt(ir, fr) => fold!(float, StoreConstInt, ir, [fr], |f| f as Int),
+            IntToFloat(fr, ir) => fold!(int, StoreConstFloat, fr, [ir], |i| i as Float),
+            AddInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| x.wrapping_add(y)),
+            MulInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| x.wrapping_mul(y)),
+            MinusInt(res, l, r) => {
+                fold!(int, StoreConstInt, res, [l, r], |x, y| x.wrapping_sub(y))
+            }
+            // Leave division by zero (and overflow) for runtime.
+            ModInt(res, l, r) => match (self.int(l), self.int(r)) {
+                (Some(x), Some(y)) => x.checked_rem(y).map(|i| StoreConstInt(*res, i)),
+                _ => None,
+            },
+            AddFloat(res, l, r) => fold!(float, StoreConstFloat, res, [l, r], |x, y| x + y),
+            MulFloat(res, l, r) => fold!(float, StoreConstFloat, res, [l, r], |x, y| x * y),
+            MinusFloat(res, l, r) => fold!(float, StoreConstFloat, res, [l, r], |x, y| x - y),
+            // Division by zero is an error unless IEEE division is turned on; either way, it
+            // happens at runtime.
+            ModFloat(res, l, r) => match (self.float(l), self.float(r)) {
+                (Some(x), Some(y)) if y != 0.0 => Some(StoreConstFloat(*res, x % y)),
+                _ => None,
+            },
+            Div(res, l, r) => match (self.float(l), self.float(r)) {
+                (Some(x), Some(y)) if y != 0.0 => Some(StoreConstFloat(*res, x / y)),
+                _ => None,
+            },
+            Pow(res, l, r) => fold!(float, StoreConstFloat, res, [l, r], |x, y| x.powf(y)),
+            Not(res, ir) => fold!(int, StoreConstInt, res, [ir], |i| (i == 0) as Int),
+            NotStr(res, sr) => self
+                .str(sr)
+                .map(|s| StoreConstInt(*res, s.with_bytes(|bs| bs.is_empty()) as Int)),
+            NegInt(res, ir) => fold!(int, StoreConstInt, res, [ir], |i| i.wrapping_neg()),
+            NegFloat(res, fr) => fold!(float, StoreConstFloat, res, [fr], |f| -f),
+            LTInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| (x < y) as Int),
+            GTInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| (x > y) as Int),
+            LTEInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| (x <= y) as Int),
+            GTEInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| (x >= y) as Int),
+            EQInt(res, l, r) => fold!(int, StoreConstInt, res, [l, r], |x, y| (x == y) as Int),
+            LTFloat(res, l, r) => fold!(float, StoreConstInt, res, [l, r], |x, y| (x < y) as Int),
+            GTFloat(res, l, r) => fold!(float, StoreConstInt, res, [l, r], |x, y| (x > y) as Int),
+            LTEFloat(res, l, r) => {
+                fold!(float, StoreConstInt, res, [l, r], |x, y| (x <= y) as Int)
+            }
+            GTEFloat(res, l, r) => {
+                fold!(float, StoreConstInt, res, [l, r], |x, y| (x >= y) as Int)
+            }
+            EQFloat(res, l, r) => {
+                fold!(float, StoreConstInt, res, [l, r], |x, y| (x == y) as Int)
+            }
+            LTStr(res, l, r) if !self.may_ignore_case => {
+                fold!(str, StoreConstInt, res, [l, r], |x, y| x
+                    .with_bytes(|x| y.with_bytes(|y| x < y))
+                    as Int)
+            }
+            GTStr(res, l, r) if !self.may_ignore_case => {
+                fold!(str, StoreConstInt, res, [l, r], |x, y| x
+                    .with_bytes(|x| y.with_bytes(|y| x > y))
+                    as Int)
+            }
+            LTEStr(res, l, r) if !self.may_ignore_case => {
+                fold!(str, StoreConstInt, res, [l, r], |x, y| x
+                    .with_bytes(|x| y.with_bytes(|y| x <= y))
+                    as Int)
+            }
+            GTEStr(res, l, r) if !self.may_ignore_case => {
+                fold!(str, StoreConstInt, res, [l, r], |x, y| x
+                    .with_bytes(|x| y.with_bytes(|y| x >= y))
+                    as Int)
+            }
+            EQStr(res, l, r) if !self.may_ignore_case => {
+                fold!(str, StoreConstInt, res, [l, r], |x, y| x
+                    .with_bytes(|x| y.with_bytes(|y| x == y))
+                    as Int)
+            }
+            _ => None,
+        }
+    }
+
+    /// Update our knowledge of register contents after `inst` executes.
+    fn step(&mut self, inst: &Instr<'a>) {
+        use Instr::*;
+        match inst {
+            Call(_) | Jmp(_) | Ret => return self.clear(),
+            _ => {}
+        }
+        if let Some((reg, ty)) = pure_dst(inst) {
+            self.clobber(reg, ty);
+        } else {
+            let mut touched = Vec::new();
+            inst.accum(|reg, ty| touched.push((reg, ty)));
+            for (reg, ty) in touched {
+                self.clobber(reg, ty);
+            }
+        }
+        match inst {
+            StoreConstInt(ir, i) => {
+                self.consts.insert(ir.reflect(), Const::Int(*i));
+            }
+            StoreConstFloat(fr, f) => {
+                self.consts.insert(fr.reflect(), Const::Float(*f));
+            }
+            StoreConstStr(sr, s) => {
+                self.consts.insert(sr.reflect(), Const::Str(s.clone_str()));
+            }
+            IntToStr(sr, ir) => {
+                self.int_strs.insert(sr.reflect().0, ir.reflect().0);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn jump_targets(instrs: &[Instr]) -> Vec<bool> {
+    let mut res = vec![false; instrs.len() + 1];
+    for l in instrs.iter().flat_map(jump_target) {
+        if l < res.len() {
+            res[l] = true;
+        }
+    }
+    res
+}
+
+fn fold_constants<'a>(instrs: &mut [Instr<'a>], removed: &mut [bool], may_ignore_case: bool) {
+    let targets = jump_targets(instrs);
+    let mut folder = Folder {
+        may_ignore_case,
+        ..Folder::default()
+    };
+    for (i, inst) in instrs.iter_mut().enumerate() {
+        if targets[i] {
+            folder.clear();
+        }
+        if let Instr::JmpIf(cond, lbl) = inst {
+            match folder.int(cond) {
+                Some(0) => {
+                    removed[i] = true;
+                    continue;
+                }
+                Some(_) => *inst = Instr::Jmp(*lbl),
+                None => {}
+            }
+        } else if let Some(new) = folder.simplify(inst) {
+            *inst = new;
+        }
+        folder.step(inst);
+    }
+}
+
+/// The first instruction at or after `i` that is not removed or a location marker. Markers do not
+/// run, so a block holding nothing else but a jump is empty too. Jumps should still land on the
+/// markers, though, so that the code they jump to is attributed to the right statement.
+fn skip_markers(instrs: &[Instr], removed: &[bool], mut i: usize) -> usize {
+    while i < removed.len() && (removed[i] || matches!(instrs[i], Instr::Loc(_))) {
+        i += 1;
+    }
+    i
+}
+
+fn resolve_jump(instrs: &[Instr], removed: &[bool], mut dst: usize) -> usize {
+    // Bound the number of jumps we follow to avoid looping on cycles of empty blocks.
+    for _ in 0..instrs.len() {
+        while dst < removed.len() && removed[dst] {
+            dst += 1;
+        }
+        match instrs.get(skip_markers(instrs, removed, dst)) {
+            Some(Instr::Jmp(Label(next))) => dst = *next,
+            _ => break,
+        }
+    }
+    dst
+}
+
+fn thread_jumps(instrs: &mut [Instr], removed: &[bool]) {
+    for i in 0..instrs.len() {
+        if removed[i] {
+            continue;
+        }
+        let dst = match jump_target(&instrs[i]) {
+            Some(l) => resolve_jump(instrs, removed, l),
+            None => continue,
+        };
+        // Returning cannot fail, so there is no need to keep the markers in front of a Ret.
+        let is_ret = matches!(
+            instrs.get(skip_markers(instrs, removed, dst)),
+            Some(Instr::Ret)
+        );
+        match &mut instrs[i] {
+            inst @ Instr::Jmp(_) if is_ret => *inst = Instr::Ret,
+            Instr::Jmp(l) | Instr::JmpIf(_, l) => *l = Label(dst),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn remove_unreachable(instrs: &[Instr], removed: &mut [bool]) {
+    let mut reachable = vec![false; instrs.len()];
+    let mut stack = vec![0];
+    while let Some(i) = stack.pop() {
+        if i >= instrs.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        if removed[i] {
+            stack.push(i + 1);
+            continue;
+        }
+        match &instrs[i] {
+            Instr::Jmp(Label(l)) => stack.push(*l),
+            Instr::JmpIf(_, Label(l)) => {
+                stack.push(*l);
+                stack.push(i + 1);
+            }
+            Instr::Ret => {}
+            _ => stack.push(i + 1),
+        }
+    }
+    for (r, reachable) in removed.iter_mut().zip(reachable) {
+        *r |= !reachable;
+    }
+}
+
+fn remove_dead_stores(funcs: &[Vec<Instr>], removed: &mut [Vec<bool>], live: &[(NumTy, Ty)]) {
+    let mut reads = HashSet::new();
+    loop {
+        reads.clear();
+        reads.extend(live.iter().cloned());
+        for (instrs, removed) in funcs.iter().zip(removed.iter()) {
+            for (inst, _) in instrs.iter().zip(removed.iter()).filter(|(_, r)| !**r) {
+                for_each_read(inst, |reg| {
+                    reads.insert(reg);
+                });
+            }
+        }
+        let mut changed = false;
+        for (instrs, removed) in funcs.iter().zip(removed.iter_mut()) {
+            for (inst, r) in instrs.iter().zip(removed.iter_mut()) {
+                if *r {
+                    continue;
+                }
+                if let Some(dst) = pure_dst(inst) {
+                    if !reads.contains(&dst) {
+                        *r = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// If `inst` compares two numbers, return the comparison and its result, along with the
+/// superinstruction that jumps to `lbl` when the comparison holds.
+fn fused_compare<'a>(inst: &Instr<'a>, lbl: Label) -> Option<(Reg<Int>, Instr<'a>)> {
+    use Instr::*;
+    macro_rules! cmp {
+        ($cmp:ident, $jmp:ident, $res:expr, $l:expr, $r:expr) => {
+            Some((*$res, $jmp(Cmp::$cmp, *$l, *$r, lbl)))
+        };
+    }
+    match inst {
+        LTInt(res, l, r) => cmp!(Lt, JmpIfInt, res, l, r),
+        GTInt(res, l, r) => cmp!(Gt, JmpIfInt, res, l, r),
+        LTEInt(res, l, r) => cmp!(Lte, JmpIfInt, res, l, r),
+        GTEInt(res, l, r) => cmp!(Gte, JmpIfInt, res, l, r),
+        EQInt(res, l, r) => cmp!(Eq, JmpIfInt, res, l, r),
+        LTFloat(res, l, r) => cmp!(Lt, JmpIfFloat, res, l, r),
+        GTFloat(res, l, r) => cmp!(Gt, JmpIfFloat, res, l, r),
+        LTEFloat(res, l, r) => cmp!(Lte, JmpIfFloat, res, l, r),
+        GTEFloat(res, l, r) => cmp!(Gte, JmpIfFloat, res, l, r),
+        EQFloat(res, l, r) => cmp!(Eq, JmpIfFloat, res, l, r),
+        IsMatchConst(res, s, pat) => Some((*res, JmpIfConstMatch(true, *s, pat.clone(), lbl))),
+        _ => None,
+    }
+}
+
+/// If `lookup`, `add` and `store` add a value to an entry in a map and store the sum back to the
+/// same key, return the equivalent `IncInt` or `IncFloat`.
+fn fused_increment<'a>(lookup: &Instr, add: &Instr, store: &Instr) -> Option<Instr<'a>> {
+    use Instr::*;
+    let (map_ty, dst, map, key) = match lookup {
+        Lookup {
+            map_ty,
+            dst,
+            map,
+            key,
+        } => (*map_ty, *dst, *map, *key),
+        _ => return None,
+    };
+    let val = match store {
+        Store {
+            map_ty: t,
+            map: m,
+            key: k,
+            val,
+        } if (*t, *m, *k) == (map_ty, map, key) => *val,
+        _ => return None,
+    };
+    fn increment_by<T>(
+        res: &Reg<T>,
+        l: &Reg<T>,
+        r: &Reg<T>,
+        dst: NumTy,
+        val: NumTy,
+    ) -> Option<Reg<T>> {
+        let num = |reg: &Reg<T>| reg.index() as NumTy;
+        if num(res) != val {
+            return None;
+        }
+        match (num(l) == dst, num(r) == dst) {
+            (true, false) => Some(*r),
+            (false, true) => Some(*l),
+            _ => None,
+        }
+    }
+    match (map_ty.val().ok()?, add) {
+        (Ty::Int, AddInt(res, l, r)) => Some(IncInt {
+            map_ty,
+            map,
+            key,
+            dst: val,
+            by: increment_by(res, l, r, dst, val)?,
+        }),
+        (Ty::Float, AddFloat(res, l, r)) => Some(IncFloat {
+            map_ty,
+            map,
+            key,
+            dst: val,
+            by: increment_by(res, l, r, dst, val)?,
+        }),
+        _ => None,
+    }
+}
+
+/// The maximum number of instructions a fused sequence can be moved past.
+const MAX_SKIPPED: usize = 8;
+
+/// Find the next instruction after `i` that could be fused with the instructions at the indexes
+/// in `moved`. Pure instructions that do not touch any registers used by `moved` are skipped:
+/// fusing moves those instructions past them.
+fn next_fusable(
+    instrs: &[Instr],
+    removed: &[bool],
+    targets: &[bool],
+    i: usize,
+    moved: &[usize],
+) -> Option<usize> {
+    let mut touched = HashSet::new();
+    for m in moved {
+        instrs[*m].accum(|reg, ty| {
+            touched.insert((reg, ty));
+        });
+    }
+    let mut skipped = 0;
+    for j in i + 1..instrs.len() {
+        if targets[j] || skipped > MAX_SKIPPED {
+            return None;
+        }
+        if removed[j] {
+            continue;
+        }
+        let inst = &instrs[j];
+        let mut disjoint = pure_dst(inst).is_some();
+        inst.accum(|reg, ty| disjoint &= !touched.contains(&(reg, ty)));
+        if !disjoint {
+            return Some(j);
+        }
+        skipped += 1;
+    }
+    None
+}
+
+/// Replace common sequences of instructions with superinstructions.
+///
+/// A sequence is only fused if the intermediate values it computes are not read anywhere else,
+/// and if no instruction in the sequence other than the first is a jump target. The fused
+/// instruction takes the place of the last instruction in the sequence.
+fn fuse(funcs: &mut [Vec<Instr>], removed: &mut [Vec<bool>], live: &[(NumTy, Ty)]) {
+    let reads = count_reads(funcs, removed, live);
+    let read_once = |reg: (NumTy, Ty)| reads.get(&reg).cloned() == Some(1);
+    for (instrs, removed) in funcs.iter_mut().zip(removed.iter_mut()) {
+        let targets = jump_targets(instrs);
+        for i in 0..instrs.len() {
+            if removed[i] {
+                continue;
+            }
+            let j = match next_fusable(instrs, removed, &targets, i, &[i]) {
+                Some(j) => j,
+                None => continue,
+            };
+            use Instr::*;
+            let fused = match (&instrs[i], &instrs[j]) {
+                (GetColumn(s, col), StrToFloat(dst, s2)) if s == s2 && read_once(s.reflect()) => {
+                    Some(GetColumnFloat(*dst, *col))
+                }
+                (cmp, JmpIf(cond, lbl)) => match fused_compare(cmp, *lbl) {
+                    Some((res, jmp)) if res == *cond && read_once(res.reflect()) => Some(jmp),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(inst) = fused {
+                instrs[j] = inst;
+                removed[i] = true;
+                continue;
+            }
+            if let Lookup { map_ty, dst, .. } = &instrs[i] {
+                if !read_once((*dst, map_ty.val().unwrap())) {
+                    continue;
+                }
+                let k = match next_fusable(instrs, removed, &targets, j, &[i, j]) {
+                    Some(k) => k,
+                    None => continue,
+                };
+                if let Some(inc) = fused_increment(&instrs[i], &instrs[j], &instrs[k]) {
+                    instrs[k] = inc;
+                    removed[i] = true;
+                    removed[j] = true;
+                }
+            }
+        }
+    }
+}
+
+/// Replace map operations on string-keyed maps whose key is a string constant with the `*ConstKey`
+/// variants, which hash the key ahead of time. This runs after `fuse`, at which point a fused
+/// increment is the only instruction reading its key.
+fn fuse_const_keys(funcs: &mut [Vec<Instr>], removed: &mut [Vec<bool>], live: &[(NumTy, Ty)]) {
+    let reads = count_reads(funcs, removed, live);
+    for (instrs, removed) in funcs.iter_mut().zip(removed.iter_mut()) {
+        let targets = jump_targets(instrs);
+        for i in 0..instrs.len() {
+            let (k, s) = match &instrs[i] {
+                Instr::StoreConstStr(k, s)
+                    if !removed[i] && reads.get(&k.reflect()) == Some(&1) =>
+                {
+                    (k.index() as NumTy, s)
+                }
+                _ => continue,
+            };
+            let j = match next_use(instrs, removed, &targets, i, (k, Ty::Str)) {
+                Some(j) => j,
+                None => continue,
+            };
+            let key = || Box::new(ConstKey::new(s.clone()));
+            use Instr::*;
+            let fused = match &instrs[j] {
+                Lookup {
+                    map_ty,
+                    dst,
+                    map,
+                    key: kr,
+                } if *kr == k && map_ty.key().ok() == Some(Ty::Str) => LookupConstKey {
+                    map_ty: *map_ty,
+                    dst: *dst,
+                    map: *map,
+                    key: key(),
+                },
+                Store {
+                    map_ty,
+                    map,
+                    key: kr,
+                    val,
+                } if *kr == k && map_ty.key().ok() == Some(Ty::Str) => StoreConstKey {
+                    map_ty: *map_ty,
+                    map: *map,
+                    val: *val,
+                    key: key(),
+                },
+                IncInt {
+                    map_ty,
+                    map,
+                    key: kr,
+                    dst,
+                    by,
+                } if *kr == k && map_ty.key().ok() == Some(Ty::Str) => IncIntConstKey {
+                    map_ty: *map_ty,
+                    map: *map,
+                    dst: *dst,
+                    by: *by,
+                    key: key(),
+                },
+                IncFloat {
+                    map_ty,
+                    map,
+                    key: kr,
+                    dst,
+                    by,
+                } if *kr == k && map_ty.key().ok() == Some(Ty::Str) => IncFloatConstKey {
+                    map_ty: *map_ty,
+                    map: *map,
+                    dst: *dst,
+                    by: *by,
+                    key: key(),
+                },
+                _ => continue,
+            };
+            instrs[j] = fused;
+            removed[i] = true;
+        }
+    }
+}
+
+/// Replace integer additions and string equality tests where one operand is a constant with the
+/// variants that take the constant as an immediate, so it is not stored into a register every time
+/// the instruction runs. This runs after `fuse`, so that adding a constant to a map entry still
+/// becomes an increment.
+fn fuse_immediates(funcs: &mut [Vec<Instr>], removed: &mut [Vec<bool>], live: &[(NumTy, Ty)]) {
+    let reads = count_reads(funcs, removed, live);
+    for (instrs, removed) in funcs.iter_mut().zip(removed.iter_mut()) {
+        let targets = jump_targets(instrs);
+        for i in 0..instrs.len() {
+            use Instr::*;
+            let k = match &instrs[i] {
+                StoreConstInt(k, _) => k.reflect(),
+                StoreConstStr(k, _) => k.reflect(),
+                _ => continue,
+            };
+            if removed[i] || reads.get(&k) != Some(&1) {
+                continue;
+            }
+            let j = match next_use(instrs, removed, &targets, i, k) {
+                Some(j) => j,
+                None => continue,
+            };
+            // The constant is read exactly once, so only one operand of the instruction using it
+            // can be the constant.
+            let fused = match (&instrs[i], &instrs[j]) {
+                (StoreConstInt(_, c), AddInt(dst, l, r)) => {
+                    let other = if r.reflect() == k { l } else { r };
+                    AddIntImm(*dst, *other, *c)
+                }
+                (StoreConstInt(_, c), MinusInt(dst, l, r))
+                    if r.reflect() == k && *c != Int::MIN =>
+                {
+                    AddIntImm(*dst, *l, c.wrapping_neg())
+                }
+                (StoreConstStr(_, c), EQStr(dst, l, r)) => {
+                    let other = if r.reflect() == k { l } else { r };
+                    let bs = c.clone().into_str().with_bytes(|bs| Arc::from(bs));
+                    EQStrConst(*dst, *other, bs)
+                }
+                _ => continue,
+            };
+            instrs[j] = fused;
+            removed[i] = true;
+        }
+    }
+}
+
+/// The index of the first instruction after `i` that uses `reg`, as long as it is at most
+/// `MAX_SKIPPED` instructions away and there are no jump targets up to and including it. A
+/// constant stored to `reg` at `i` has no side-effects and reads no registers, so it can be moved
+/// down to that instruction.
+fn next_use(
+    instrs: &[Instr],
+    removed: &[bool],
+    targets: &[bool],
+    i: usize,
+    reg: (NumTy, Ty),
+) -> Option<usize> {
+    let j = (i + 1..instrs.len()).take(MAX_SKIPPED).find(|j| {
+        let mut uses = targets[*j];
+        if !removed[*j] {
+            instrs[*j].accum(|r, ty| uses |= (r, ty) == reg);
+        }
+        uses
+    })?;
+    if targets[j] {
+        None
+    } else {
+        Some(j)
+    }
+}
+
+/// Count the number of times each register is read across all functions.
+/// Count the reads of each register, counting the registers in `live` as read once more.
+fn count_reads(
+    funcs: &[Vec<Instr>],
+    removed: &[Vec<bool>],
+    live: &[(NumTy, Ty)],
+) -> HashMap<(NumTy, Ty), usize> {
+    let mut reads: HashMap<_, _> = live.iter().map(|reg| (*reg, 1)).collect();
+    for (instrs, removed) in funcs.iter().zip(removed.iter()) {
+        for (inst, _) in instrs.iter().zip(removed.iter()).filter(|(_, r)| !**r) {
+            for_each_read(inst, |reg| *reads.entry(reg).or_insert(0) += 1);
+        }
+    }
+    reads
+}
+
+/// Conditional jumps over an unconditional jump are emitted for every `if` statement. Where the
+/// condition is an integer comparison or a regex match, negate it and jump to the target of the
+/// unconditional jump instead.
+fn invert_branches(instrs: &mut [Instr], removed: &mut [bool]) {
+    let targets = jump_targets(instrs);
+    for i in 0..instrs.len() {
+        if removed[i] {
+            continue;
+        }
+        let lbl = match &instrs[i] {
+            Instr::JmpIfInt(_, _, _, lbl) | Instr::JmpIfConstMatch(_, _, _, lbl) => lbl.0,
+            _ => continue,
+        };
+        let j = match (i + 1..instrs.len()).find(|j| !removed[*j]) {
+            Some(j) if !targets[j] && (j + 1..lbl).all(|k| removed[k]) && lbl > j => j,
+            _ => continue,
+        };
+        if let Instr::Jmp(dst) = instrs[j] {
+            match &mut instrs[i] {
+                Instr::JmpIfInt(c, _, _, l) => {
+                    *c = c.negate();
+                    *l = dst;
+                }
+                Instr::JmpIfConstMatch(on, _, _, l) => {
+                    *on = !*on;
+                    *l = dst;
+                }
+                _ => unreachable!(),
+            }
+            removed[j] = true;
+        }
+    }
+}
+
+fn remove_fallthrough_jumps(instrs: &[Instr], removed: &mut [bool]) {
+    // Going in reverse means that jumps over other fallthrough jumps are also removed.
+    for i in (0..instrs.len()).rev() {
+        if removed[i] {
+            continue;
+        }
+        if let Some(l) = jump_target(&instrs[i]) {
+            if l > i && removed[i + 1..l.min(instrs.len())].iter().all(|r| *r) {
+                removed[i] = true;
+            }
+        }
+    }
+}
+
+/// Move pure instructions whose operands are not written inside a loop in front of the loop.
+///
+/// Loops are found by looking for backward jumps: a jump at `j` to `h <= j` makes `h..=j` a loop.
+/// That range may also contain code that is not part of the cycle (such as the code run once the
+/// loop exits), which is fine: every instruction we hoist is pure and cannot fail, and its operands
+/// hold the same values anywhere in the range. The hoisted instructions are placed where they run
+/// every time the range is entered; we only hoist from ranges with a single way in.
+///
+/// Instructions are only hoisted if their destination is a temporary: a register written once, and
+/// only read later in the same basic block. The hoisted write still comes before every read.
+fn hoist_invariants(funcs: &mut [Vec<Instr>], live: &[(NumTy, Ty)]) {
+    let temps = block_local_temps(funcs, live);
+    for instrs in funcs.iter_mut() {
+        // Every round hoists at least one instruction. Bound the number of rounds in case an
+        // instruction could move back and forth between overlapping loops.
+        for _ in 0..instrs.len() {
+            if !hoist_from_a_loop(instrs, &temps) {
+                break;
+            }
+        }
+    }
+}
+
+/// Registers, other than those in `live`, that are written once by a pure instruction and only
+/// read after that write in the same basic block.
+fn block_local_temps(funcs: &[Vec<Instr>], live: &[(NumTy, Ty)]) -> HashSet<(NumTy, Ty)> {
+    let mut writes = HashMap::<(NumTy, Ty), usize>::new();
+    let mut escapes: HashSet<_> = live.iter().cloned().collect();
+    let mut block_defs = HashSet::new();
+    for instrs in funcs.iter() {
+        let targets = jump_targets(instrs);
+        block_defs.clear();
+        for (i, inst) in instrs.iter().enumerate() {
+            if targets[i] {
+                block_defs.clear();
+            }
+            let written = regalloc::writes(inst);
+            let mut pos = 0;
+            inst.accum(|reg, ty| {
+                let is_write = written.contains(&pos);
+                if (!is_write || regalloc::updates(inst, pos)) && !block_defs.contains(&(reg, ty)) {
+                    escapes.insert((reg, ty));
+                }
+                if is_write {
+                    *writes.entry((reg, ty)).or_insert(0) += 1;
+                }
+                pos += 1;
+            });
+            match pure_dst(inst) {
+                Some(dst) => {
+                    block_defs.insert(dst);
+                }
+                None if jump_target(inst).is_some()
+                    || matches!(inst, Instr::Call(_) | Instr::Ret) =>
+                {
+                    block_defs.clear()
+                }
+                None => {}
+            }
+        }
+    }
+    writes
+        .into_iter()
+        .filter(|(reg, n)| *n == 1 && !escapes.contains(reg))
+        .map(|(reg, _)| reg)
+        .collect()
+}
+
+/// Hoist instructions out of the first loop (innermost first) that has any to hoist, returning
+/// whether there was one.
+fn hoist_from_a_loop(instrs: &mut Vec<Instr>, temps: &HashSet<(NumTy, Ty)>) -> bool {
+    let mut loops: Vec<(usize, usize)> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(j, inst)| jump_target(inst).filter(|h| *h <= j).map(|h| (h, j)))
+        .collect();
+    loops.sort_by_key(|(h, j)| j - h);
+    for (h, j) in loops {
+        let (at, retarget) = match loop_entry(instrs, h, j) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let hoisted = loop_invariants(instrs, h, j, temps);
+        if hoisted.is_empty() {
+            continue;
+        }
+        let mut removed = vec![false; instrs.len()];
+        for i in hoisted.iter() {
+            removed[*i] = true;
+        }
+        let pre_header: Vec<_> = hoisted.iter().map(|i| instrs[*i].clone()).collect();
+        let mut new_pos = Vec::with_capacity(instrs.len() + 1);
+        let mut n = 0;
+        for (i, r) in removed.iter().enumerate() {
+            if i == at {
+                n += pre_header.len();
+            }
+            new_pos.push(n);
+            n += !*r as usize;
+        }
+        new_pos.push(n);
+        let start = new_pos[at] - pre_header.len();
+        let old = std::mem::take(instrs);
+        for (i, inst) in old.into_iter().enumerate() {
+            if i == at {
+                instrs.extend(pre_header.iter().cloned());
+            }
+            if !removed[i] {
+                instrs.push(inst);
+            }
+        }
+        for l in instrs.iter_mut().flat_map(jump_target_mut) {
+            *l = Label(if retarget && l.0 == at {
+                start
+            } else {
+                new_pos[l.0]
+            });
+        }
+        return true;
+    }
+    false
+}
+
+/// Where to put instructions hoisted out of the loop `h..=j`, and whether jumps to that position
+/// should run them. If the loop is only entered by falling through to `h`, they go right before
+/// `h`; jumps to `h` are back edges, which skip them. If it is only entered by a single `Jmp`,
+/// they go right before the jump.
+fn loop_entry(instrs: &[Instr], h: usize, j: usize) -> Option<(usize, bool)> {
+    let falls_through = h == 0 || !matches!(instrs[h - 1], Instr::Jmp(_) | Instr::Ret);
+    let mut entries = instrs
+        .iter()
+        .enumerate()
+        .filter(|(i, inst)| {
+            (*i < h || *i > j) && matches!(jump_target(inst), Some(l) if l >= h && l <= j)
+        })
+        .map(|(i, _)| i);
+    match (falls_through, entries.next(), entries.next()) {
+        (true, None, _) => Some((h, false)),
+        (false, Some(e), None) if matches!(instrs[e], Instr::Jmp(_)) => Some((e, true)),
+        _ => None,
+    }
+}
+
+/// The instructions in `h..=j` that can be hoisted out of the loop, in an order in which they can
+/// be executed.
+fn loop_invariants(
+    instrs: &[Instr],
+    h: usize,
+    j: usize,
+    temps: &HashSet<(NumTy, Ty)>,
+) -> Vec<usize> {
+    let body = &instrs[h..=j];
+    // A call could write to any register that is not a temporary, including ones the loop does not
+    // mention.
+    let has_call = body.iter().any(|inst| matches!(inst, Instr::Call(_)));
+    let sets_case = body.iter().any(sets_ignorecase);
+    let mut written = HashSet::new();
+    let mut outside_reads = HashSet::new();
+    for (i, inst) in instrs.iter().enumerate() {
+        let in_loop = i >= h && i <= j;
+        let w = regalloc::writes(inst);
+        let mut pos = 0;
+        inst.accum(|reg, ty| {
+            let is_write = w.contains(&pos);
+            if in_loop && is_write {
+                written.insert((reg, ty));
+            }
+            if !in_loop && (!is_write || regalloc::updates(inst, pos)) {
+                outside_reads.insert((reg, ty));
+            }
+            pos += 1;
+        });
+    }
+    let mut hoisted = Vec::new();
+    let mut is_hoisted = vec![false; body.len()];
+    loop {
+        let mut changed = false;
+        for (i, inst) in body.iter().enumerate() {
+            if is_hoisted[i] {
+                continue;
+            }
+            let dst = match pure_dst(inst) {
+                Some(dst) if temps.contains(&dst) && !outside_reads.contains(&dst) => dst,
+                _ => continue,
+            };
+            let mut invariant = !(sets_case && reads_ignorecase(inst));
+            for_each_read(inst, |reg| {
+                invariant &= !has_call && !written.contains(&reg)
+            });
+            if invariant {
+                is_hoisted[i] = true;
+                written.remove(&dst);
+                hoisted.push(h + i);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    hoisted
+}
+
+/// Remove the instructions marked in `removed`, updating jump targets to match.
+pub(crate) fn compact(instrs: &mut Vec<Instr>, removed: &[bool]) {
+    let mut new_pos = Vec::with_capacity(instrs.len() + 1);
+    let mut n = 0;
+    for r in removed.iter() {
+        new_pos.push(n);
+        n += !*r as usize;
+    }
+    new_pos.push(n);
+    let mut i = 0;
+    instrs.retain(|_| {
+        i += 1;
+        !removed[i - 1]
+    });
+    for l in instrs.iter_mut().flat_map(jump_target_mut) {
+        *l = Label(new_pos[l.0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::bytes::Regex;
+    use Instr::*;
+
+    fn run(instrs: Vec<Instr<'static>>) -> Vec<String> {
+        let mut funcs = vec![instrs];
+        optimize(&mut funcs, &[]);
+        funcs[0].iter().map(|i| format!("{:?}", i)).collect()
+    }
+
+    fn observe(r: u32) -> Instr<'static> {
+        StoreVarInt(Variable::NR, r.into())
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        let out = run(vec![
+            StoreConstInt(0.into(), 2),
+            StoreConstInt(1.into(), 3),
+            AddInt(2.into(), 0.into(), 1.into()),
+            MulInt(3.into(), 2.into(), 2.into()),
+            observe(3),
+            Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec!["StoreConstInt(<3>, 25)", "StoreVarInt(NR, <3>)", "Ret"]
+        );
+    }
+
+    #[test]
+    fn no_fold_mod_zero() {
+        let out = run(vec![
+            StoreConstInt(0.into(), 2),
+            StoreConstInt(1.into(), 0),
+            ModInt(2.into(), 0.into(), 1.into()),
+            observe(2),
+            Ret,
+        ]);
+        assert_eq!(out.len(), 5);
+        assert_eq!(out[2], "ModInt(<2>, <0>, <1>)");
+    }
+
+    #[test]
+    fn collapse_conversions() {
+        let out = run(vec![
+            LoadVarInt(0.into(), Variable::NF),
+            IntToStr(0.into(), 0.into()),
+            StrToInt(1.into(), 0.into()),
+            observe(1),
+            Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<0>, NF)",
+                "Mov(Int, 1, 0)",
+                "StoreVarInt(NR, <1>)",
+                "Ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn clobbered_conversion() {
+        let out = run(vec![
+            LoadVarInt(0.into(), Variable::NF),
+            IntToStr(0.into(), 0.into()),
+            LoadVarInt(0.into(), Variable::FNR),
+            StrToInt(1.into(), 0.into()),
+            observe(1),
+            Ret,
+        ]);
+        assert_eq!(out[3], "StrToInt(<1>, <0>)");
+    }
+
+    #[test]
+    fn constant_branches() {
+        let out = run(vec![
+            /*0*/ StoreConstInt(0.into(), 0),
+            /*1*/ JmpIf(0.into(), Label(4)),
+            /*2*/ StoreConstInt(1.into(), 1),
+            /*3*/ JmpIf(1.into(), Label(6)),
+            /*4*/ observe(0),
+            /*5*/ Ret,
+            /*6*/ Ret,
+        ]);
+        assert_eq!(out, vec!["Ret"]);
+    }
+
+    #[test]
+    fn thread_jumps_and_dead_stores() {
+        let out = run(vec![
+            /*0*/ LoadVarInt(0.into(), Variable::NF),
+            /*1*/ JmpIf(0.into(), Label(5)),
+            /*2*/ StoreConstInt(1.into(), 7),
+            /*3*/ observe(1),
+            /*4*/ Jmp(Label(8)),
+            /*5*/ Jmp(Label(6)),
+            /*6*/ Jmp(Label(3)),
+            /*7*/ StoreConstInt(2.into(), 1),
+            /*8*/ Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<0>, NF)",
+                "JmpIf(<0>, @3)",
+                "StoreConstInt(<1>, 7)",
+                "StoreVarInt(NR, <1>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn thread_jumps_to_markers() {
+        let loc = |line| Loc(Some(crate::lexer::SourceLoc { line, col: 0 }));
+        let out = run(vec![
+            /*0*/ LoadVarInt(0.into(), Variable::NF),
+            /*1*/ JmpIf(0.into(), Label(6)),
+            /*2*/ Jmp(Label(3)),
+            /*3*/ loc(1),
+            /*4*/ observe(0),
+            /*5*/ Ret,
+            /*6*/ loc(2),
+            /*7*/ Jmp(Label(3)),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<0>, NF)",
+                "Loc(Some(SourceLoc { line: 1, col: 0 }))",
+                "StoreVarInt(NR, <0>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn live_stores() {
+        let mut funcs = vec![vec![
+            LoadVarInt(1.into(), Variable::NF),
+            StoreConstInt(0.into(), 1),
+            LTInt(0.into(), 1.into(), 1.into()),
+            JmpIf(0.into(), Label(5)),
+            observe(1),
+            Ret,
+        ]];
+        optimize(&mut funcs, &[(0, Ty::Int)]);
+        let out: Vec<_> = funcs[0].iter().map(|i| format!("{:?}", i)).collect();
+        // Neither the overwritten store nor the comparison are removed, as the final value of the
+        // register is read afterwards.
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<1>, NF)",
+                "StoreConstInt(<0>, 1)",
+                "LTInt(<0>, <1>, <1>)",
+                "JmpIf(<0>, @5)",
+                "StoreVarInt(NR, <1>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn fuse_column_and_branch() {
+        let out = run(vec![
+            /*0*/ LoadVarInt(0.into(), Variable::NF),
+            /*1*/ GetColumn(0.into(), 0.into()),
+            /*2*/ StrToFloat(0.into(), 0.into()),
+            /*3*/ LoadVarInt(1.into(), Variable::NR),
+            /*4*/ LTFloat(2.into(), 0.into(), 0.into()),
+            /*5*/ JmpIf(2.into(), Label(7)),
+            /*6*/ observe(1),
+            /*7*/ Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<0>, NF)",
+                "GetColumnFloat(<0>, <0>)",
+                "LoadVarInt(<1>, NR)",
+                "JmpIfFloat(Lt, <0>, <0>, @5)",
+                "StoreVarInt(NR, <1>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn invert_int_branch() {
+        let out = run(vec![
+            /*0*/ LoadVarInt(0.into(), Variable::NF),
+            /*1*/ LoadVarInt(1.into(), Variable::NR),
+            /*2*/ EQInt(2.into(), 0.into(), 1.into()),
+            /*3*/ JmpIf(2.into(), Label(5)),
+            /*4*/ Jmp(Label(6)),
+            /*5*/ observe(0),
+            /*6*/ observe(1),
+            /*7*/ Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<0>, NF)",
+                "LoadVarInt(<1>, NR)",
+                "JmpIfInt(Neq, <0>, <1>, @4)",
+                "StoreVarInt(NR, <0>)",
+                "StoreVarInt(NR, <1>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_fuse_shared_result() {
+        let out = run(vec![
+            /*0*/ LoadVarInt(0.into(), Variable::NF),
+            /*1*/ LoadVarInt(1.into(), Variable::NR),
+            /*2*/ LTInt(2.into(), 0.into(), 1.into()),
+            /*3*/ JmpIf(2.into(), Label(5)),
+            /*4*/ observe(2),
+            /*5*/ Ret,
+        ]);
+        assert_eq!(out[2], "LTInt(<2>, <0>, <1>)");
+        assert_eq!(out[3], "JmpIf(<2>, @5)");
+    }
+
+    #[test]
+    fn fuse_map_increment() {
+        let map_ty = Ty::MapStrInt;
+        let out = run(vec![
+            LoadVarStr(0.into(), Variable::FS),
+            Lookup {
+                map_ty,
+                dst: 0,
+                map: 0,
+                key: 0,
+            },
+            StoreConstInt(1.into(), 2),
+            AddInt(2.into(), 0.into(), 1.into()),
+            Store {
+                map_ty,
+                map: 0,
+                key: 0,
+                val: 2,
+            },
+            Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarStr(<0>, FS)",
+                "StoreConstInt(<1>, 2)",
+                "IncInt { map_ty: MapStrInt, map: 0, key: 0, dst: 2, by: <1> }",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn fuse_const_keys() {
+        let map_ty = Ty::MapStrInt;
+        let key = |reg: u32, s: &'static str| StoreConstStr(reg.into(), Str::from(s).into());
+        let out = run(vec![
+            key(0, "x"),
+            LoadVarInt(4.into(), Variable::NF),
+            Lookup {
+                map_ty,
+                dst: 0,
+                map: 0,
+                key: 0,
+            },
+            StoreConstInt(1.into(), 2),
+            AddInt(2.into(), 0.into(), 1.into()),
+            Store {
+                map_ty,
+                map: 0,
+                key: 0,
+                val: 2,
+            },
+            key(1, "y"),
+            Store {
+                map_ty,
+                map: 0,
+                key: 1,
+                val: 2,
+            },
+            // Keys that are read more than once are left alone.
+            key(2, "z"),
+            Contains {
+                map_ty,
+                dst: 3,
+                map: 0,
+                key: 2,
+            },
+            observe(3),
+            Store {
+                map_ty,
+                map: 0,
+                key: 2,
+                val: 2,
+            },
+            Ret,
+        ]);
+        assert_eq!(out.len(), 9);
+        assert_eq!(out[0], "LoadVarInt(<4>, NF)");
+        assert_eq!(out[1], "StoreConstInt(<1>, 2)");
+        assert!(out[2].starts_with(
+            "IncIntConstKey { map_ty: MapStrInt, map: 0, dst: 2, by: <1>, key: ConstKey"
+        ));
+        assert!(out[2].contains("disp=<x>"));
+        assert!(out[3].starts_with("StoreConstKey { map_ty: MapStrInt, map: 0, val: 2"));
+        assert!(out[3].contains("disp=<y>"));
+        assert!(out[4].starts_with("StoreConstStr(<2>"));
+        assert_eq!(out[8], "Ret");
+    }
+
+    #[test]
+    fn fuse_immediates() {
+        let out = run(vec![
+            LoadVarInt(0.into(), Variable::NR),
+            StoreConstInt(1.into(), 1),
+            AddInt(2.into(), 0.into(), 1.into()),
+            observe(2),
+            StoreConstInt(3.into(), 4),
+            MinusInt(4.into(), 0.into(), 3.into()),
+            observe(4),
+            LoadVarStr(0.into(), Variable::FS),
+            StoreConstStr(1.into(), Str::from("foo").into()),
+            EQStr(5.into(), 0.into(), 1.into()),
+            observe(5),
+            Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarInt(<0>, NR)",
+                "AddIntImm(<2>, <0>, 1)",
+                "StoreVarInt(NR, <2>)",
+                "AddIntImm(<4>, <0>, -4)",
+                "StoreVarInt(NR, <4>)",
+                "LoadVarStr(<0>, FS)",
+                "EQStrConst(<5>, <0>, [102, 111, 111])",
+                "StoreVarInt(NR, <5>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_fuse_const_on_left_of_minus() {
+        let out = run(vec![
+            LoadVarInt(0.into(), Variable::NR),
+            StoreConstInt(1.into(), 4),
+            MinusInt(2.into(), 1.into(), 0.into()),
+            observe(2),
+            Ret,
+        ]);
+        assert_eq!(out[2], "MinusInt(<2>, <1>, <0>)");
+    }
+
+    #[test]
+    fn invert_regex_branch() {
+        let out = run(vec![
+            /*0*/ LoadVarStr(0.into(), Variable::FS),
+            /*1*/ IsMatchConst(1.into(), 0.into(), Arc::new(Regex::new("ab+c").unwrap())),
+            /*2*/ JmpIf(1.into(), Label(4)),
+            /*3*/ Jmp(Label(5)),
+            /*4*/ observe(2),
+            /*5*/ observe(3),
+            /*6*/ Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarStr(<0>, FS)",
+                "JmpIfConstMatch(false, <0>, Regex(\"ab+c\"), @3)",
+                "StoreVarInt(NR, <2>)",
+                "StoreVarInt(NR, <3>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn hoist_from_loop() {
+        let out = run(vec![
+            /*0*/ StoreConstInt(0.into(), 0),
+            /*1*/ LoadVarStr(1.into(), Variable::FS),
+            /*2*/ StoreConstInt(2.into(), 10),
+            /*3*/ GTEInt(4.into(), 0.into(), 2.into()),
+            /*4*/ JmpIf(4.into(), Label(8)),
+            /*5*/ StrToInt(3.into(), 1.into()),
+            /*6*/ AddInt(0.into(), 0.into(), 3.into()),
+            /*7*/ Jmp(Label(2)),
+            /*8*/ observe(0),
+            /*9*/ Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "StoreConstInt(<0>, 0)",
+                "LoadVarStr(<1>, FS)",
+                "StoreConstInt(<2>, 10)",
+                "StrToInt(<3>, <1>)",
+                "JmpIfInt(Gte, <0>, <2>, @7)",
+                "AddInt(<0>, <0>, <3>)",
+                "Jmp(@4)",
+                "StoreVarInt(NR, <0>)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn hoist_before_loop_entry() {
+        let out = run(vec![
+            /*0*/ LoadVarStr(0.into(), Variable::FS),
+            /*1*/ Jmp(Label(4)),
+            /*2*/ StrToFloat(1.into(), 0.into()),
+            /*3*/ AddFloat(2.into(), 2.into(), 1.into()),
+            /*4*/ LoadVarInt(3.into(), Variable::NR),
+            /*5*/ JmpIf(3.into(), Label(2)),
+            /*6*/ Ret,
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "LoadVarStr(<0>, FS)",
+                "StrToFloat(<1>, <0>)",
+                "Jmp(@4)",
+                "AddFloat(<2>, <2>, <1>)",
+                "LoadVarInt(<3>, NR)",
+                "JmpIf(<3>, @3)",
+                "Ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_hoist_variant() {
+        let out = run(vec![
+            /*0*/ StoreConstInt(0.into(), 0),
+            /*1*/ LoadVarStr(1.into(), Variable::FS),
+            /*2*/ StrToInt(2.into(), 1.into()),
+            /*3*/ AddInt(0.into(), 0.into(), 2.into()),
+            /*4*/ JmpIf(0.into(), Label(1)),
+            /*5*/ observe(0),
+            /*6*/ Ret,
+        ]);
+        assert_eq!(out[2], "StrToInt(<2>, <1>)");
+        assert_eq!(out[4], "JmpIf(<0>, @1)");
+    }
+}
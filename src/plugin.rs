@@ -0,0 +1,248 @@
+//! Builtin functions loaded from shared libraries, for --plugin.
+//!
+//! A plugin is a shared library exporting a C function `frawk_plugin_init`, which frawk calls once
+//! at startup with a `frawk_registrar` (see `include/frawk_plugin.h`). The plugin calls the
+//! registrar's `register_function` for each function it provides, giving its name and the types
+//! of its arguments and result, and returns 0 if it loaded successfully.
+//!
+//! Plugin functions become native functions, like those registered with `embed::Builder`: the
+//! compiler resolves calls to them to `builtins::Function::Native`, and the interpreter calls them
+//! through the callbacks built here, which convert values to and from the C representation.
+//! Strings keep their bytes both ways, going through `Value::Bytes` if they are not valid UTF-8.
+//! Strings passed to a function are only valid for the duration of the call, and the string a
+//! function returns must stay valid until it returns, after which frawk copies it; a buffer local
+//! to the calling thread is enough. Functions may be called from several threads at once when
+//! frawk runs in parallel.
+//!
+//! Libraries are never unloaded, as the callbacks may be called at any point until frawk exits.
+use crate::builtins::{self, Native, MAX_NATIVE_ARGS};
+use crate::compile::Ty;
+use crate::embed::{Callback, Value};
+use crate::lexer;
+
+use std::convert::TryFrom;
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
+use std::sync::Arc;
+
+/// The version of the interface in `include/frawk_plugin.h`, which is passed to plugins so that
+/// they can refuse to load if it has changed.
+const ABI_VERSION: u32 = 1;
+
+const TAG_INT: u32 = 0;
+const TAG_FLOAT: u32 = 1;
+const TAG_STR: u32 = 2;
+
+/// A `frawk_value`.
+#[repr(C)]
+struct RawValue {
+    tag: u32,
+    int: i64,
+    float: f64,
+    str: *const u8,
+    len: usize,
+}
+
+type RawFunction = unsafe extern "C" fn(args: *const RawValue, nargs: usize, ret: *mut RawValue);
+
+/// A `frawk_registrar`.
+#[repr(C)]
+struct Registrar {
+    abi_version: u32,
+    ctx: *mut c_void,
+    register_function: unsafe extern "C" fn(
+        ctx: *mut c_void,
+        name: *const c_char,
+        args: *const u32,
+        nargs: usize,
+        ret: u32,
+        f: RawFunction,
+    ) -> c_int,
+}
+
+type Init = unsafe extern "C" fn(registrar: *const Registrar) -> c_int;
+
+/// A function provided by a plugin.
+pub(crate) struct Function {
+    pub(crate) name: String,
+    pub(crate) native: Native,
+    pub(crate) callback: Callback,
+}
+
+/// The state behind a `Registrar` while a plugin is being initialized.
+struct Loader<'a> {
+    functions: &'a mut Vec<Function>,
+    error: Option<String>,
+}
+
+fn ty_of(tag: u32) -> Option<Ty> {
+    match tag {
+        TAG_INT => Some(Ty::Int),
+        TAG_FLOAT => Some(Ty::Float),
+        TAG_STR => Some(Ty::Str),
+        _ => None,
+    }
+}
+
+impl RawValue {
+    /// `v`, pointing into its string if it has one.
+    fn borrow(v: &Value) -> RawValue {
+        let mut res = RawValue {
+            tag: TAG_INT,
+            int: 0,
+            float: 0.0,
+            str: std::ptr::null(),
+            len: 0,
+        };
+        match v {
+            Value::Int(i) => res.int = *i,
+            Value::Float(f) => {
+                res.tag = TAG_FLOAT;
+                res.float = *f;
+            }
+            Value::Str(s) => {
+                res.tag = TAG_STR;
+                res.str = s.as_ptr();
+                res.len = s.len();
+            }
+            Value::Bytes(bs) => {
+                res.tag = TAG_STR;
+                res.str = bs.as_ptr();
+                res.len = bs.len();
+            }
+        }
+        res
+    }
+
+    /// # Safety
+    /// A string value must point to `len` readable bytes.
+    unsafe fn to_value(&self) -> Value {
+        match self.tag {
+            TAG_INT => Value::Int(self.int),
+            TAG_FLOAT => Value::Float(self.float),
+            _ if self.str.is_null() => Value::Str(String::new()),
+            _ => std::slice::from_raw_parts(self.str, self.len)
+                .to_vec()
+                .into(),
+        }
+    }
+}
+
+unsafe extern "C" fn register_function(
+    ctx: *mut c_void,
+    name: *const c_char,
+    args: *const u32,
+    nargs: usize,
+    ret: u32,
+    f: RawFunction,
+) -> c_int {
+    let loader = &mut *(ctx as *mut Loader);
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    let arg_tys = if nargs == 0 {
+        Some(Vec::new())
+    } else {
+        std::slice::from_raw_parts(args, nargs)
+            .iter()
+            .map(|tag| ty_of(*tag))
+            .collect()
+    };
+    let error = if !lexer::is_ident(&name) {
+        Some(format!("invalid name for a function: {:?}", name))
+    } else if builtins::Function::try_from(name.as_str()).is_ok() {
+        Some(format!("{} has the same name as a builtin function", name))
+    } else if loader.functions.iter().any(|func| func.name == name) {
+        Some(format!("{} is defined more than once", name))
+    } else if nargs > MAX_NATIVE_ARGS {
+        Some(format!(
+            "{} takes {} arguments, but at most {} are supported",
+            name, nargs, MAX_NATIVE_ARGS
+        ))
+    } else {
+        None
+    };
+    let native = match (error, arg_tys, ty_of(ret)) {
+        (None, Some(arg_tys), Some(ret)) => {
+            Native::new(loader.functions.len() as u32, &arg_tys[..], ret)
+        }
+        (Some(e), _, _) => {
+            loader.error.get_or_insert(e);
+            return 1;
+        }
+        _ => {
+            loader
+                .error
+                .get_or_insert(format!("invalid type in the signature of {}", name));
+            return 1;
+        }
+    };
+    let callback: Callback = Arc::new(move |args: &[Value]| {
+        let args: Vec<RawValue> = args.iter().map(RawValue::borrow).collect();
+        let mut ret = RawValue::borrow(&Value::Int(0));
+        unsafe {
+            f(args.as_ptr(), args.len(), &mut ret);
+            ret.to_value()
+        }
+    });
+    loader.functions.push(Function {
+        name,
+        native: native.expect("argument count was checked"),
+        callback,
+    });
+    0
+}
+
+/// Load the plugin at `path`, adding the functions it provides to `functions`.
+pub(crate) fn load(path: &str, functions: &mut Vec<Function>) -> Result<(), String> {
+    let init = open(path)?;
+    let mut loader = Loader {
+        functions,
+        error: None,
+    };
+    let registrar = Registrar {
+        abi_version: ABI_VERSION,
+        ctx: &mut loader as *mut Loader as *mut c_void,
+        register_function,
+    };
+    let status = unsafe { init(&registrar) };
+    match loader.error {
+        Some(e) => Err(e),
+        None if status != 0 => Err(format!("initialization failed with status {}", status)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn open(path: &str) -> Result<Init, String> {
+    use std::ffi::CString;
+    let dl_error = || unsafe {
+        let e = libc::dlerror();
+        if e.is_null() {
+            "unknown error".into()
+        } else {
+            CStr::from_ptr(e).to_string_lossy().into_owned()
+        }
+    };
+    let c_path = CString::new(path).map_err(|e| e.to_string())?;
+    // dlopen only searches the library path for names without a slash.
+    let c_path = if path.contains('/') {
+        c_path
+    } else {
+        CString::new(format!("./{}", path)).map_err(|e| e.to_string())?
+    };
+    unsafe {
+        let lib = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if lib.is_null() {
+            return Err(dl_error());
+        }
+        let init = libc::dlsym(lib, b"frawk_plugin_init\0".as_ptr() as *const c_char);
+        if init.is_null() {
+            return Err(dl_error());
+        }
+        Ok(std::mem::transmute::<*mut c_void, Init>(init))
+    }
+}
+
+#[cfg(not(unix))]
+fn open(_path: &str) -> Result<Init, String> {
+    Err("plugins are only supported on Unix".into())
+}
@@ -0,0 +1,517 @@
+//! Print a parsed program back out as frawk source, for `--dump-ast`.
+//!
+//! The output is formatted canonically: functions come first, followed by BEGIN blocks, rules,
+//! PREPARE blocks and END blocks, with every block braced and indented by four spaces and one
+//! statement per line. Parentheses are only added where the grammar needs them, so parenthesized
+//! subexpressions in the original program that were not needed are dropped. Parsing the output
+//! gives back the same program.
+//!
+//! A few constructs are represented the same way in the AST, so they print the same way:
+//! `!(a == b)` prints as `a != b`, `m[a SUBSEP b]` prints as `m[a, b]`, and `exit 0` prints as
+//! `exit`.
+use crate::ast::{Binop, Expr, FunDec, Pattern, Prog, Stmt, Unop};
+use crate::builtins::Function;
+use crate::common::{Either, FileSpec};
+
+use std::fmt::{self, Display, Formatter, Write};
+
+type E<'a> = Expr<'a, 'a, &'a str>;
+type S<'a> = Stmt<'a, 'a, &'a str>;
+
+/// Displays a program as source text.
+pub(crate) struct Source<'p, 'a>(pub &'p Prog<'a, 'a, &'a str>);
+
+impl<'p, 'a> Display for Source<'p, 'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let prog = self.0;
+        let mut first = true;
+        let mut sep = |f: &mut Formatter| {
+            if first {
+                first = false;
+                Ok(())
+            } else {
+                writeln!(f)
+            }
+        };
+        for FunDec { name, args, body } in prog.decs.iter() {
+            sep(f)?;
+            write!(f, "function {}({}) ", name, args.join(", "))?;
+            braced(f, body, 0)?;
+            writeln!(f)?;
+        }
+        for body in prog.begin.iter() {
+            sep(f)?;
+            write!(f, "BEGIN ")?;
+            braced(f, body, 0)?;
+            writeln!(f)?;
+        }
+        for (pat, body) in prog.pats.iter() {
+            sep(f)?;
+            match pat {
+                Pattern::Null => {}
+                Pattern::Bool(e) => expr(f, e, Prec::Expr)?,
+                Pattern::Comma(l, r) => {
+                    expr(f, l, Prec::Base)?;
+                    write!(f, ", ")?;
+                    expr(f, r, Prec::Base)?;
+                }
+            }
+            if let Some(body) = body {
+                if !matches!(pat, Pattern::Null) {
+                    write!(f, " ")?;
+                }
+                braced(f, body, 0)?;
+            }
+            writeln!(f)?;
+        }
+        for (name, blocks) in [("PREPARE", &prog.prepare), ("END", &prog.end)] {
+            for body in blocks.iter() {
+                sep(f)?;
+                write!(f, "{} ", name)?;
+                braced(f, body, 0)?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The precedence levels of the grammar, from loosest to tightest. An expression printed where
+/// the grammar expects a level tighter than its own has to be parenthesized.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Expr,
+    Assign,
+    Ternary,
+    Or,
+    And,
+    In,
+    Match,
+    Cmp,
+    Add,
+    Mul,
+    Pow,
+    Unary,
+    Inc,
+    Concat,
+    Field,
+    Base,
+}
+
+fn prec(e: &E) -> Prec {
+    use Expr::*;
+    match e {
+        Getline { .. } | ReadStdin => Prec::Expr,
+        Assign(..) | AssignOp(..) => Prec::Assign,
+        ITE(..) => Prec::Ternary,
+        Or(..) => Prec::Or,
+        And(..) => Prec::And,
+        Call(Either::Right(Function::Contains), _) => Prec::In,
+        Binop(self::Binop::IsMatch, ..)
+        | Unop(self::Unop::Not, Binop(self::Binop::IsMatch, ..)) => Prec::Match,
+        Binop(
+            self::Binop::LT
+            | self::Binop::GT
+            | self::Binop::LTE
+            | self::Binop::GTE
+            | self::Binop::EQ,
+            ..,
+        )
+        | Unop(self::Unop::Not, Binop(self::Binop::EQ, ..)) => Prec::Cmp,
+        Binop(self::Binop::Plus | self::Binop::Minus, ..) => Prec::Add,
+        Binop(self::Binop::Mult | self::Binop::Div | self::Binop::Mod, ..) => Prec::Mul,
+        Binop(self::Binop::Pow, ..) => Prec::Pow,
+        Unop(self::Unop::Column, _) => Prec::Field,
+        Unop(..) => Prec::Unary,
+        Inc { .. } => Prec::Inc,
+        Binop(self::Binop::Concat, ..) => Prec::Concat,
+        ILit(..) | FLit(..) | StrLit(..) | PatLit(..) | Var(..) | Index(..) | Call(..)
+        | Cond(..) => Prec::Base,
+    }
+}
+
+/// If `e` joins several expressions with SUBSEP, as `m[a, b]` does, return them.
+fn lookup_list<'a>(e: &'a E<'a>) -> Option<Vec<&'a E<'a>>> {
+    match e {
+        Expr::Binop(Binop::Concat, Expr::Binop(Binop::Concat, l, Expr::Var("SUBSEP")), r) => {
+            let mut res = lookup_list(l).unwrap_or_else(|| vec![*l]);
+            res.push(r);
+            Some(res)
+        }
+        _ => None,
+    }
+}
+
+fn list(f: &mut Formatter, es: &[&E], min: Prec) -> fmt::Result {
+    for (i, e) in es.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        expr(f, e, min)?;
+    }
+    Ok(())
+}
+
+/// Print a map key: either a single expression or a list of them.
+fn key(f: &mut Formatter, k: &E) -> fmt::Result {
+    match lookup_list(k) {
+        Some(ks) => list(f, &ks[..], Prec::Match),
+        None => expr(f, k, Prec::Expr),
+    }
+}
+
+fn str_lit(f: &mut Formatter, s: &[u8]) -> fmt::Result {
+    f.write_char('"')?;
+    let escape = |f: &mut Formatter, c: char| match c {
+        '"' => f.write_str("\\\""),
+        '\\' => f.write_str("\\\\"),
+        '\n' => f.write_str("\\n"),
+        '\t' => f.write_str("\\t"),
+        '\r' => f.write_str("\\r"),
+        c if (c as u32) < 0x20 || c as u32 == 0x7f => write!(f, "\\{:03o}", c as u32),
+        c => f.write_char(c),
+    };
+    match std::str::from_utf8(s) {
+        Ok(s) => s.chars().try_for_each(|c| escape(f, c))?,
+        Err(_) => s.iter().try_for_each(|b| {
+            if b.is_ascii() {
+                escape(f, *b as char)
+            } else {
+                write!(f, "\\{:03o}", b)
+            }
+        })?,
+    }
+    f.write_char('"')
+}
+
+fn pat_lit(f: &mut Formatter, re: &[u8]) -> fmt::Result {
+    f.write_char('/')?;
+    for c in String::from_utf8_lossy(re).chars() {
+        match c {
+            '/' => f.write_str("\\/")?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('/')
+}
+
+/// Print `e`, parenthesizing it if it binds more loosely than `min`.
+fn expr(f: &mut Formatter, e: &E, min: Prec) -> fmt::Result {
+    if prec(e) < min {
+        f.write_char('(')?;
+        expr(f, e, Prec::Expr)?;
+        return f.write_char(')');
+    }
+    use Expr::*;
+    match e {
+        ILit(n) if *n < 0 => write!(f, "({})", n),
+        ILit(n) => write!(f, "{}", n),
+        FLit(n) if n.is_infinite() => write!(f, "{}1e999", if *n < 0.0 { "-" } else { "" }),
+        FLit(n) if *n < 0.0 => write!(f, "({:?})", n),
+        FLit(n) => write!(f, "{:?}", n),
+        StrLit(s) => str_lit(f, s),
+        PatLit(re) => pat_lit(f, re),
+        Var(v) => f.write_str(v),
+        Index(m, k) => {
+            expr(f, m, Prec::Base)?;
+            f.write_char('[')?;
+            key(f, k)?;
+            f.write_char(']')
+        }
+        Unop(self::Unop::Column, e) => {
+            f.write_char('$')?;
+            expr(f, e, Prec::Base)
+        }
+        Unop(self::Unop::Not, Binop(op @ (self::Binop::EQ | self::Binop::IsMatch), l, r)) => {
+            let (op, lmin, rmin) = if *op == self::Binop::EQ {
+                ("!=", Prec::Add, Prec::Cmp)
+            } else {
+                ("!~", Prec::Match, Prec::Cmp)
+            };
+            expr(f, l, lmin)?;
+            write!(f, " {} ", op)?;
+            expr(f, r, rmin)
+        }
+        Unop(op, e) => {
+            write!(f, "{}", op)?;
+            // Keep `- --x` from turning into `---x`.
+            let min = match e {
+                Inc { is_post: false, .. } => Prec::Base,
+                _ => Prec::Inc,
+            };
+            expr(f, e, min)
+        }
+        Binop(self::Binop::Concat, l, r) => {
+            expr(f, l, Prec::Concat)?;
+            f.write_char(' ')?;
+            if let PatLit(_) = r {
+                // After an operand, a `/` is read as division rather than the start of a regex.
+                f.write_char('(')?;
+                expr(f, r, Prec::Expr)?;
+                f.write_char(')')
+            } else {
+                expr(f, r, Prec::Field)
+            }
+        }
+        Binop(op, l, r) => {
+            let (lmin, rmin) = match op {
+                self::Binop::IsMatch => (Prec::Match, Prec::Cmp),
+                self::Binop::LT
+                | self::Binop::GT
+                | self::Binop::LTE
+                | self::Binop::GTE
+                | self::Binop::EQ => (Prec::Add, Prec::Cmp),
+                self::Binop::Plus | self::Binop::Minus => (Prec::Add, Prec::Mul),
+                self::Binop::Mult | self::Binop::Div | self::Binop::Mod => (Prec::Mul, Prec::Pow),
+                self::Binop::Pow => (Prec::Unary, Prec::Pow),
+                self::Binop::Concat => unreachable!(),
+            };
+            expr(f, l, lmin)?;
+            write!(f, " {} ", op)?;
+            expr(f, r, rmin)
+        }
+        Assign(l, r) => {
+            expr(f, l, Prec::In)?;
+            write!(f, " = ")?;
+            expr(f, r, Prec::Assign)
+        }
+        AssignOp(l, op, r) => {
+            expr(f, l, Prec::In)?;
+            write!(f, " {}= ", op)?;
+            expr(f, r, Prec::Assign)
+        }
+        And(l, r) => {
+            expr(f, l, Prec::In)?;
+            write!(f, " && ")?;
+            expr(f, r, Prec::And)
+        }
+        Or(l, r) => {
+            expr(f, l, Prec::And)?;
+            write!(f, " || ")?;
+            expr(f, r, Prec::Or)
+        }
+        ITE(c, t, e) => {
+            expr(f, c, Prec::Or)?;
+            write!(f, " ? ")?;
+            expr(f, t, Prec::Ternary)?;
+            write!(f, " : ")?;
+            expr(f, e, Prec::Ternary)
+        }
+        Inc { is_inc, is_post, x } => {
+            let op = if *is_inc { "++" } else { "--" };
+            if !*is_post {
+                f.write_str(op)?;
+            }
+            expr(f, x, Prec::Concat)?;
+            if *is_post {
+                f.write_str(op)?;
+            }
+            Ok(())
+        }
+        Getline {
+            into,
+            from,
+            is_file,
+        } => {
+            if let (Some(from), false) = (from, is_file) {
+                expr(f, from, Prec::Field)?;
+                write!(f, " | ")?;
+            }
+            write!(f, "getline")?;
+            if let Some(into) = into {
+                f.write_char(' ')?;
+                expr(f, into, Prec::Base)?;
+            }
+            if let (Some(from), true) = (from, is_file) {
+                write!(f, " < ")?;
+                expr(f, from, Prec::Base)?;
+            }
+            Ok(())
+        }
+        ReadStdin => write!(f, "getline"),
+        Call(Either::Right(Function::Contains), [m, k]) => {
+            match lookup_list(k) {
+                Some(ks) => {
+                    f.write_char('(')?;
+                    list(f, &ks[..], Prec::Match)?;
+                    f.write_char(')')?;
+                }
+                None => expr(f, k, Prec::Match)?,
+            }
+            write!(f, " in ")?;
+            expr(f, m, Prec::Match)
+        }
+        Call(Either::Right(Function::MatchArray), args) => {
+            // The parser passes SUBSEP as a hidden last argument.
+            write!(f, "match(")?;
+            list(f, &args[..args.len().min(3)], Prec::Expr)?;
+            f.write_char(')')
+        }
+        Call(func, args) => {
+            match func {
+                Either::Left(name) => f.write_str(name)?,
+                Either::Right(func) => write!(f, "{}", func)?,
+            }
+            f.write_char('(')?;
+            list(f, args, Prec::Expr)?;
+            f.write_char(')')
+        }
+        // Only generated by desugaring.
+        Cond(n) => write!(f, "<cond {}>", n),
+    }
+}
+
+fn indent(f: &mut Formatter, level: usize) -> fmt::Result {
+    write!(f, "{:1$}", "", level * 4)
+}
+
+/// Print `s` as a block starting on the current line and ending with its closing brace.
+fn braced(f: &mut Formatter, s: &S, level: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+    match s {
+        Stmt::Block(stmts) => {
+            for s in stmts.iter() {
+                stmt(f, s, level + 1)?;
+            }
+        }
+        s => stmt(f, s, level + 1)?,
+    }
+    indent(f, level)?;
+    f.write_char('}')
+}
+
+fn output(f: &mut Formatter, out: &Option<(&E, FileSpec)>) -> fmt::Result {
+    if let Some((e, spec)) = out {
+        let op = match spec {
+            FileSpec::Trunc => ">",
+            FileSpec::Append => ">>",
+            FileSpec::Cmd => "|",
+        };
+        write!(f, " {} ", op)?;
+        expr(f, e, Prec::Concat)?;
+    }
+    Ok(())
+}
+
+/// Print the arguments to print or printf. These are parsed at the level of `+`, so that `>`
+/// starts a redirection.
+fn print_args(f: &mut Formatter, args: &[&E]) -> fmt::Result {
+    for (i, e) in args.iter().enumerate() {
+        f.write_str(if i == 0 { " " } else { ", " })?;
+        expr(f, e, Prec::Add)?;
+    }
+    Ok(())
+}
+
+/// Print `s` on its own line(s), indented to `level`.
+fn stmt(f: &mut Formatter, s: &S, level: usize) -> fmt::Result {
+    use Stmt::*;
+    let s = s.without_loc();
+    indent(f, level)?;
+    match s {
+        Expr(self::Expr::Call(Either::Right(Function::Delete), [m, k])) => {
+            write!(f, "delete ")?;
+            expr(f, m, Prec::Base)?;
+            f.write_char('[')?;
+            key(f, k)?;
+            f.write_char(']')?;
+        }
+        Expr(self::Expr::Call(Either::Right(Function::Clear), [m])) => {
+            write!(f, "delete ")?;
+            expr(f, m, Prec::Base)?;
+        }
+        Expr(self::Expr::Call(Either::Right(Function::Exit), [code])) => {
+            write!(f, "exit")?;
+            if !matches!(code, self::Expr::ILit(0)) {
+                f.write_char(' ')?;
+                expr(f, code, Prec::Add)?;
+            }
+        }
+        Expr(e) => expr(f, e, Prec::Expr)?,
+        Block(_) => braced(f, s, level)?,
+        Print(args, out) => {
+            write!(f, "print")?;
+            print_args(f, args)?;
+            output(f, out)?;
+        }
+        Printf(fmt, args, out) => {
+            write!(f, "printf")?;
+            print_args(f, &[fmt])?;
+            if !args.is_empty() {
+                f.write_char(',')?;
+                print_args(f, args)?;
+            }
+            output(f, out)?;
+        }
+        If(..) => {
+            let mut s = s;
+            // Print else-if chains without nesting them.
+            while let If(c, t, e) = s {
+                write!(f, "if (")?;
+                expr(f, c, Prec::Expr)?;
+                write!(f, ") ")?;
+                braced(f, t, level)?;
+                match e {
+                    Some(e) => {
+                        write!(f, " else ")?;
+                        s = e.without_loc();
+                    }
+                    None => break,
+                }
+            }
+            if !matches!(s, If(..)) {
+                braced(f, s, level)?;
+            }
+        }
+        For(init, cond, update, body) => {
+            let part = |f: &mut Formatter, s: &Option<&S>| match s {
+                Some(Expr(e)) => expr(f, e, Prec::Expr),
+                _ => Ok(()),
+            };
+            write!(f, "for (")?;
+            part(f, init)?;
+            write!(f, "; ")?;
+            if let Some(cond) = cond {
+                expr(f, cond, Prec::Expr)?;
+            }
+            write!(f, "; ")?;
+            part(f, update)?;
+            write!(f, ") ")?;
+            braced(f, body, level)?;
+        }
+        ForEach(v, arr, body) => {
+            write!(f, "for ({} in ", v)?;
+            expr(f, arr, Prec::Expr)?;
+            write!(f, ") ")?;
+            braced(f, body, level)?;
+        }
+        While(_, c, body) => {
+            write!(f, "while (")?;
+            expr(f, c, Prec::Expr)?;
+            write!(f, ") ")?;
+            braced(f, body, level)?;
+        }
+        DoWhile(c, body) => {
+            write!(f, "do ")?;
+            braced(f, body, level)?;
+            write!(f, " while (")?;
+            expr(f, c, Prec::Expr)?;
+            f.write_char(')')?;
+        }
+        Break => write!(f, "break")?,
+        Continue => write!(f, "continue")?,
+        Next => write!(f, "next")?,
+        NextFile => write!(f, "nextfile")?,
+        Return(e) => {
+            write!(f, "return")?;
+            if let Some(e) = e {
+                f.write_char(' ')?;
+                expr(f, e, Prec::Expr)?;
+            }
+        }
+        // Only generated by desugaring.
+        StartCond(n) | EndCond(n) | LastCond(n) => write!(f, "<cond {}>", n)?,
+        Loc(..) => unreachable!("locations are removed above"),
+    }
+    writeln!(f)
+}
@@ -0,0 +1,284 @@
+//! Execution counts for bytecode programs.
+//!
+//! When profiling is enabled, the interpreter counts how many times each instruction executes.
+//! Counts for basic blocks are derived from these when the report is written: control only enters
+//! a block through its first instruction, so the block executes exactly as often as its leader.
+//! The report is meant for finding hot spots in the interpreter (and candidates for new
+//! superinstructions), so it lists the busiest blocks and opcodes before the full program.
+//!
+//! Programs compiled for --profile also mark the start of each pattern and action with a
+//! ProfileRule instruction. Every marker is identified by the source line of its rule: pattern
+//! markers are `2*line` and action markers are `2*line+1`. The time between one marker and the
+//! next is charged to the first, which gives the time spent in each rule (including any functions
+//! it calls). `rule_report` prints these alongside the program source.
+use crate::bytecode::Instr;
+use crate::peephole;
+use crate::runtime::Int;
+
+use hashbrown::HashMap;
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// The number of blocks listed in the summary at the top of a report.
+const TOP_BLOCKS: usize = 20;
+
+/// The marker for reading the next record in the main loop.
+pub(crate) const READ_INPUT: Int = -1;
+
+#[derive(Default, Copy, Clone)]
+struct RuleStats {
+    count: u64,
+    time: Duration,
+}
+
+pub(crate) struct Profile {
+    counts: Vec<Vec<u64>>,
+    rules: HashMap<Int, RuleStats>,
+    // The last marker reached, and when.
+    cur_rule: Option<(Int, Instant)>,
+}
+
+impl Profile {
+    pub(crate) fn new(instrs: &[Vec<Instr>]) -> Profile {
+        Profile {
+            counts: instrs.iter().map(|f| vec![0; f.len()]).collect(),
+            rules: Default::default(),
+            cur_rule: None,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn record(&mut self, func: usize, inst: usize) {
+        self.counts[func][inst] += 1;
+    }
+
+    pub(crate) fn mark_rule(&mut self, rule: Int) {
+        let now = Instant::now();
+        self.stop_rule(now);
+        self.cur_rule = Some((rule, now));
+        self.rules.entry(rule).or_default().count += 1;
+    }
+
+    /// Charge the time since the last marker to it.
+    fn stop_rule(&mut self, now: Instant) {
+        if let Some((rule, start)) = self.cur_rule.take() {
+            self.rules.entry(rule).or_default().time += now - start;
+        }
+    }
+
+    /// Add the counts from a profile of the same program, e.g. one collected by a worker thread.
+    pub(crate) fn merge(&mut self, other: &Profile) {
+        for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+            for (m, t) in mine.iter_mut().zip(theirs.iter()) {
+                *m += *t;
+            }
+        }
+        for (rule, theirs) in other.rules.iter() {
+            let mine = self.rules.entry(*rule).or_default();
+            mine.count += theirs.count;
+            mine.time += theirs.time;
+        }
+    }
+
+    /// The number of times each instruction of each function ran.
+    pub(crate) fn counts(&self) -> &[Vec<u64>] {
+        &self.counts
+    }
+
+    /// The number of times the marker `rule` was reached, if it ever was.
+    pub(crate) fn rule_count(&self, rule: Int) -> Option<u64> {
+        self.rules.get(&rule).map(|r| r.count)
+    }
+
+    /// Stop timing, once the program has finished running.
+    pub(crate) fn finish(&mut self) {
+        self.stop_rule(Instant::now());
+    }
+
+    /// Write the program `source`, with the number of times the patterns and actions starting on
+    /// each line were run, and the time spent in them.
+    pub(crate) fn rule_report(&self, source: &str, w: &mut impl Write) -> io::Result<()> {
+        let total: Duration = self.rules.values().map(|r| r.time).sum();
+        let pct = |time: Duration| {
+            if total.is_zero() {
+                0.0
+            } else {
+                100.0 * time.as_secs_f64() / total.as_secs_f64()
+            }
+        };
+        let count = |rule: Int| match self.rules.get(&rule) {
+            Some(r) => r.count.to_string(),
+            None => String::new(),
+        };
+        writeln!(
+            w,
+            "{:>12} {:>12} {:>12} {:>7}  source",
+            "patterns", "actions", "time (ms)", "time"
+        )?;
+        for (line, text) in source.trim_end_matches('\n').lines().enumerate() {
+            let (pat, action) = (2 * line as Int, 2 * line as Int + 1);
+            let time: Duration = [pat, action]
+                .iter()
+                .filter_map(|r| self.rules.get(r))
+                .map(|r| r.time)
+                .sum();
+            if self.rules.contains_key(&pat) || self.rules.contains_key(&action) {
+                writeln!(
+                    w,
+                    "{:>12} {:>12} {:>12.3} {:>6.2}%  {}",
+                    count(pat),
+                    count(action),
+                    time.as_secs_f64() * 1e3,
+                    pct(time),
+                    text
+                )?;
+            } else {
+                writeln!(w, "{:>46}  {}", "", text)?;
+            }
+        }
+        if let Some(input) = self.rules.get(&READ_INPUT) {
+            writeln!(
+                w,
+                "\n{:>12} {:>12} {:>12.3} {:>6.2}%  (reading input)",
+                input.count,
+                "",
+                input.time.as_secs_f64() * 1e3,
+                pct(input.time)
+            )?;
+        }
+        writeln!(w, "\ntotal time: {:.3}ms", total.as_secs_f64() * 1e3)
+    }
+
+    pub(crate) fn report(&self, instrs: &[Vec<Instr>], w: &mut impl Write) -> io::Result<()> {
+        let total: u64 = self.counts.iter().flatten().sum();
+        writeln!(w, "{} instructions executed", total)?;
+
+        let mut blocks = Vec::new();
+        for (f, func) in instrs.iter().enumerate() {
+            let leaders = leaders(func);
+            for (start, is_leader) in leaders.iter().enumerate() {
+                if *is_leader {
+                    let len = leaders[start + 1..]
+                        .iter()
+                        .position(|l| *l)
+                        .unwrap_or(func.len() - start - 1)
+                        + 1;
+                    blocks.push((self.counts[f][start], f, start, len));
+                }
+            }
+        }
+        blocks.sort_by(|x, y| y.cmp(x));
+        writeln!(w, "\nhottest blocks:")?;
+        for (count, f, start, len) in blocks.iter().take(TOP_BLOCKS) {
+            if *count == 0 {
+                break;
+            }
+            writeln!(
+                w,
+                "{:>12}  function {} @{} ({} instructions)",
+                count, f, start, len
+            )?;
+        }
+
+        let mut opcodes = HashMap::<String, u64>::new();
+        for (func, counts) in instrs.iter().zip(self.counts.iter()) {
+            for (inst, count) in func.iter().zip(counts.iter()) {
+                *opcodes.entry(opcode(inst)).or_insert(0) += count;
+            }
+        }
+        let mut opcodes: Vec<_> = opcodes.into_iter().filter(|(_, c)| *c > 0).collect();
+        opcodes.sort_by(|(o1, c1), (o2, c2)| c2.cmp(c1).then_with(|| o1.cmp(o2)));
+        writeln!(w, "\nexecutions by opcode:")?;
+        for (op, count) in opcodes.iter() {
+            let pct = 100.0 * *count as f64 / total as f64;
+            writeln!(w, "{:>12} {:>6.2}%  {}", count, pct, op)?;
+        }
+
+        writeln!(w)?;
+        for (f, (func, counts)) in instrs.iter().zip(self.counts.iter()).enumerate() {
+            if func.is_empty() {
+                continue;
+            }
+            writeln!(w, "function {} {{", f)?;
+            for (i, (is_leader, (inst, count))) in leaders(func)
+                .into_iter()
+                .zip(func.iter().zip(counts.iter()))
+                .enumerate()
+            {
+                if is_leader && i != 0 {
+                    writeln!(w)?;
+                }
+                writeln!(w, "{:>12}\t[{:2}] {:?}", count, i, inst)?;
+            }
+            writeln!(w, "}}\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Mark the first instruction of each basic block in `instrs`.
+fn leaders(instrs: &[Instr]) -> Vec<bool> {
+    let mut res = vec![false; instrs.len()];
+    if let Some(first) = res.first_mut() {
+        *first = true;
+    }
+    for (i, inst) in instrs.iter().enumerate() {
+        if let Some(dst) = peephole::jump_target(inst) {
+            res[dst] = true;
+        } else if !matches!(inst, Instr::Ret) {
+            continue;
+        }
+        if let Some(next) = res.get_mut(i + 1) {
+            *next = true;
+        }
+    }
+    res
+}
+
+/// The name of the instruction, without its operands.
+fn opcode(inst: &Instr) -> String {
+    let s = format!("{:?}", inst);
+    match s.find(|c: char| !c.is_alphanumeric()) {
+        Some(end) => s[..end].to_string(),
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Label;
+    use Instr::*;
+
+    #[test]
+    fn block_counts() {
+        let instrs = vec![vec![
+            StoreConstInt(0.into(), 0),
+            StoreConstInt(1.into(), 1),
+            AddInt(0.into(), 0.into(), 1.into()),
+            LTInt(2.into(), 0.into(), 1.into()),
+            JmpIf(2.into(), Label(2)),
+            Ret,
+        ]];
+        let mut profile = Profile::new(&instrs);
+        for i in [0, 1, 2, 3, 4, 2, 3, 4, 5].iter() {
+            profile.record(0, *i);
+        }
+        let mut other = Profile::new(&instrs);
+        other.record(0, 0);
+        profile.merge(&other);
+        assert_eq!(
+            leaders(&instrs[0]),
+            vec![true, false, true, false, false, true]
+        );
+
+        let mut out = Vec::new();
+        profile.report(&instrs, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("10 instructions executed\n"));
+        assert!(out.contains("           2  function 0 @2 (3 instructions)\n"));
+        assert!(out.contains("           2  function 0 @0 (2 instructions)\n"));
+        assert!(out.contains("           2  20.00%  AddInt\n"));
+    }
+}
@@ -94,15 +94,18 @@ impl FieldSet {
     pub fn has_fi(&self) -> bool {
         (self.0 != FieldSet::all().0) && ((1 << FI_INDEX) & self.0) != 0
     }
-    pub fn is_empty(&self) -> bool {
-        self.0 == 0
-    }
     pub fn all() -> FieldSet {
         FieldSet(!0)
     }
     pub fn empty() -> FieldSet {
         FieldSet(0)
     }
+    pub(crate) fn bits(&self) -> u64 {
+        self.0
+    }
+    pub(crate) fn from_bits(bits: u64) -> FieldSet {
+        FieldSet(bits)
+    }
     pub fn union(&mut self, other: &FieldSet) {
         self.0 |= other.0;
     }
@@ -246,6 +249,9 @@ pub struct UsedFieldAnalysis {
     // the variables in question.  We can always add it in the future, but since join nodes are
     // always "leaves" we will just add the missing columns as a postprocessing step.
     joins: Vec<(Key /*lhs*/, Key /*rhs*/)>,
+    // Assigning a field or NF rebuilds $0 from every field in the record, so nothing can be
+    // projected out.
+    assigns_fields: bool,
 }
 
 impl Default for UsedFieldAnalysis {
@@ -253,6 +259,7 @@ impl Default for UsedFieldAnalysis {
         let mut res = UsedFieldAnalysis {
             dfa: Default::default(),
             joins: Default::default(),
+            assigns_fields: false,
         };
         res.dfa.add_src(Key::Rng, FieldSet::all());
         res.dfa.add_src(Key::VarVal(Variable::FI), FieldSet::fi());
@@ -269,6 +276,9 @@ impl UsedFieldAnalysis {
     }
     pub(crate) fn visit_ll(&mut self, inst: &Instr) {
         use Instr::*;
+        if let SetColumn(..) | StoreVarInt(Variable::NF, _) = inst {
+            self.assigns_fields = true;
+        }
         match inst {
             StoreConstInt(dst, i) if *i >= 0 => {
                 self.dfa.add_src(dst, FieldSet::singleton(*i as usize))
@@ -293,6 +303,7 @@ impl UsedFieldAnalysis {
             }
             JoinCSV(dst, start, end)
             | JoinTSV(dst, start, end)
+            | JoinJSON(dst, start, end)
             | JoinColumns(dst, start, end, _) => {
                 self.dfa.add_query(start);
                 self.dfa.add_query(end);
@@ -307,6 +318,9 @@ impl UsedFieldAnalysis {
 
     /// Return the set of all fields mentioned by column nodes.
     pub fn solve(mut self) -> FieldSet {
+        if self.assigns_fields {
+            return FieldSet::all();
+        }
         let mut res = self.dfa.root().clone();
         for (l, r) in self.joins.iter().cloned() {
             let mut l_flds = self.dfa.query(l).clone();
@@ -0,0 +1,538 @@
+//! Register allocation for the bytecode interpreter.
+//!
+//! The compiler allocates a fresh register for every temporary it produces, and every function in
+//! a program shares the same register files. Left alone, that gives `Interp` register files with
+//! an entry for every temporary in the program, even though most of them are only live for a few
+//! instructions. This module computes the live ranges of each register and renames registers with
+//! disjoint live ranges so that they share the same slot.
+//!
+//! Registers are only shared among other registers in the same function, and some registers are
+//! never shared at all:
+//!
+//! * Registers referenced by more than one function, such as globals and return values.
+//! * Registers that may be read before they are written in a function. Those registers observe
+//!   their default value, or the value left behind by a previous call.
+//! * Registers that are live across a call. The callee could be the current function, in which
+//!   case it could clobber any register shared with the live one.
+use crate::bytecode::Instr;
+use crate::common::NumTy;
+use crate::compile::{Ty, NULL_REG, NUM_TYPES};
+use crate::peephole;
+
+use hashbrown::{HashMap, HashSet};
+use smallvec::SmallVec;
+
+/// Rename the registers in `funcs` to reuse registers with disjoint live ranges. Returns the new
+/// number of registers of each type, indexed by `Ty as usize`.
+///
+/// The registers in `keep` are never shared, even if they are not used at all, and are replaced
+/// with their new names.
+pub(crate) fn allocate(funcs: &mut [Vec<Instr>], keep: &mut [(NumTy, Ty)]) -> [usize; NUM_TYPES] {
+    let infos: Vec<FuncInfo> = funcs.iter().map(|f| FuncInfo::new(f)).collect();
+
+    // Pinned registers are numbered first, in the same order they were originally.
+    let mut funcs_of = HashMap::<(NumTy, Ty), usize>::new();
+    let mut pinned: HashSet<_> = keep.iter().cloned().collect();
+    for (i, info) in infos.iter().enumerate() {
+        for (j, reg) in info.regs.iter().enumerate() {
+            if info.pinned[j] || matches!(funcs_of.get(reg), Some(f) if *f != i) {
+                pinned.insert(*reg);
+            }
+            funcs_of.insert(*reg, i);
+        }
+    }
+    let mut pinned: Vec<_> = pinned.into_iter().collect();
+    pinned.sort_by_key(|(reg, ty)| (*ty as usize, *reg));
+    let mut counts = [0usize; NUM_TYPES];
+    let mut rename = HashMap::new();
+    for reg in pinned {
+        let count = &mut counts[reg.1 as usize];
+        rename.insert(reg, *count as NumTy);
+        *count += 1;
+    }
+
+    for info in infos.iter() {
+        for (reg, new) in info.color(&rename, &mut counts) {
+            rename.insert(reg, new);
+        }
+    }
+
+    for instrs in funcs.iter_mut() {
+        let mut removed = vec![false; instrs.len()];
+        for (inst, removed) in instrs.iter_mut().zip(removed.iter_mut()) {
+            inst.accum_mut(|reg, ty| {
+                if let Some(new) = rename.get(&(*reg, ty)) {
+                    *reg = *new;
+                }
+            });
+            // Moves between registers that now share a slot are no-ops.
+            if let Instr::Mov(_, dst, src) = inst {
+                *removed = dst == src;
+            }
+        }
+        peephole::compact(instrs, &removed);
+    }
+    for (reg, ty) in keep.iter_mut() {
+        *reg = rename[&(*reg, *ty)];
+    }
+    counts
+}
+
+/// The positions (in the order passed to the `Instr::accum` callback) of the registers written by
+/// `inst`. Registers at every other position are read.
+pub(crate) fn writes(inst: &Instr) -> &'static [usize] {
+    use Instr::*;
+    match inst {
+        StoreConstStr(..)
+        | StoreConstInt(..)
+        | StoreConstFloat(..)
+        | IntToStr(..)
+        | FloatToStr(..)
+        | StrToInt(..)
+        | HexStrToInt(..)
+        | FloatToInt(..)
+        | IntToFloat(..)
+        | StrToFloat(..)
+        | Mov(..)
+        | AllocMap(..)
+        | AddInt(..)
+        | AddFloat(..)
+        | MulFloat(..)
+        | MulInt(..)
+        | Div(..)
+        | Pow(..)
+        | MinusFloat(..)
+        | MinusInt(..)
+        | ModFloat(..)
+        | ModInt(..)
+        | Not(..)
+        | NotStr(..)
+        | NegInt(..)
+        | NegFloat(..)
+        | Float1(..)
+        | Float2(..)
+        | Int1(..)
+        | Int2(..)
+        | Rand(..)
+        | Srand(..)
+        | ReseedRng(..)
+        | Concat(..)
+        | StartsWithConst(..)
+        | ContainsConst(..)
+        | IsMatch(..)
+        | IsMatchConst(..)
+        | Match(..)
+        | MatchConst(..)
+        | MatchSet(..)
+        | SubstrIndex(..)
+        | LenStr(..)
+        | GenSubDynamic(..)
+        | EscapeCSV(..)
+        | EscapeTSV(..)
+        | EscapeJSON(..)
+        | FloatToJSON(..)
+        | Substr(..)
+        | LTFloat(..)
+        | LTInt(..)
+        | LTStr(..)
+        | GTFloat(..)
+        | GTInt(..)
+        | GTStr(..)
+        | LTEFloat(..)
+        | LTEInt(..)
+        | LTEStr(..)
+        | GTEFloat(..)
+        | GTEInt(..)
+        | GTEStr(..)
+        | EQFloat(..)
+        | EQInt(..)
+        | EQStr(..)
+        | StrNumCmp(..)
+        | GetColumn(..)
+        | JoinCSV(..)
+        | JoinTSV(..)
+        | JoinJSON(..)
+        | JoinColumns(..)
+        | ToUpperAscii(..)
+        | ToLowerAscii(..)
+        | ReadErr(..)
+        | NextLine(..)
+        | ReadErrStdin(..)
+        | NextLineStdin(..)
+        | SplitInt(..)
+        | SplitStr(..)
+        | MatchArr(..)
+        | Sprintf { .. }
+        | CallNative { .. }
+        | RunCmd(..)
+        | Close(..)
+        | Flush(..)
+        | FlushAll(..)
+        | Lookup { .. }
+        | LookupStr { .. }
+        | Contains { .. }
+        | Len { .. }
+        | ToJSON { .. }
+        | JoinArray { .. }
+        | ReadArray { .. }
+        | WriteArray { .. }
+        | IterBegin { .. }
+        | IterHasNext { .. }
+        | IterGetNext { .. }
+        | LoadVarStr(..)
+        | LoadVarInt(..)
+        | LoadVarIntMap(..)
+        | LoadVarStrMap(..)
+        | LoadSlot { .. }
+        | GetColumnFloat(..)
+        | AddIntImm(..)
+        | EQStrConst(..)
+        | LookupConstKey { .. }
+        | Pop(..) => &[0],
+        // The result, and the string the substitution is performed on.
+        Sub(..) | GSub(..) => &[0, 3],
+        SubConst(..) | GSubConst(..) => &[0, 2],
+        IncInt { .. } | IncFloat { .. } => &[2],
+        IncIntConstKey { .. } | IncFloatConstKey { .. } => &[1],
+        SetColumn(..)
+        | NextLineStdinFused()
+        | NextFile()
+        | UpdateUsedFields()
+        | SetFI(..)
+        | Printf { .. }
+        | PrintAll { .. }
+        | Exit(..)
+        | ProfileRule(..)
+        | Delete { .. }
+        | Clear { .. }
+        | Store { .. }
+        | StoreConstKey { .. }
+        | StoreVarStr(..)
+        | StoreVarInt(..)
+        | StoreVarIntMap(..)
+        | StoreVarStrMap(..)
+        | StoreSlot { .. }
+        | JmpIf(..)
+        | Jmp(..)
+        | JmpIfInt(..)
+        | JmpIfFloat(..)
+        | JmpIfConstMatch(..)
+        | Push(..)
+        | Call(..)
+        | Ret
+        | Loc(..) => &[],
+    }
+}
+
+/// `sub` and `gsub` modify their input in place; it is the only register that is both read and
+/// written by an instruction.
+pub(crate) fn updates(inst: &Instr, pos: usize) -> bool {
+    match inst {
+        Instr::Sub(..) | Instr::GSub(..) => pos == 3,
+        Instr::SubConst(..) | Instr::GSubConst(..) => pos == 2,
+        _ => false,
+    }
+}
+
+fn successors(instrs: &[Instr], i: usize) -> SmallVec<[usize; 2]> {
+    use Instr::*;
+    let mut res = SmallVec::new();
+    match &instrs[i] {
+        Jmp(l) => res.push(l.0),
+        JmpIf(_, l)
+        | JmpIfInt(_, _, _, l)
+        | JmpIfFloat(_, _, _, l)
+        | JmpIfConstMatch(_, _, _, l) => {
+            res.push(l.0);
+            res.push(i + 1);
+        }
+        Ret => {}
+        _ => res.push(i + 1),
+    }
+    res.retain(|s| *s < instrs.len());
+    res
+}
+
+/// Liveness information for the registers referenced in a single function. Registers are
+/// referred to by their index in `regs`.
+struct FuncInfo {
+    regs: Vec<(NumTy, Ty)>,
+    pinned: Vec<bool>,
+    // For each register, the registers it cannot share a slot with.
+    interferes: Vec<HashSet<usize>>,
+    // For each register, the registers it is moved to or from. These are good candidates to share
+    // a slot with, as it allows us to remove the move.
+    moves: Vec<SmallVec<[usize; 2]>>,
+}
+
+impl FuncInfo {
+    fn new(instrs: &[Instr]) -> FuncInfo {
+        let mut ids = HashMap::new();
+        let mut regs = Vec::new();
+        let mut defs = Vec::with_capacity(instrs.len());
+        let mut uses = Vec::with_capacity(instrs.len());
+        for inst in instrs {
+            let written = writes(inst);
+            let mut pos = 0;
+            let mut d = SmallVec::<[usize; 2]>::new();
+            let mut u = SmallVec::<[usize; 4]>::new();
+            inst.accum(|reg, ty| {
+                if ty == Ty::Null || reg >= NULL_REG {
+                    pos += 1;
+                    return;
+                }
+                let id = *ids.entry((reg, ty)).or_insert_with(|| {
+                    regs.push((reg, ty));
+                    regs.len() - 1
+                });
+                if written.contains(&pos) {
+                    d.push(id);
+                }
+                if !written.contains(&pos) || updates(inst, pos) {
+                    u.push(id);
+                }
+                pos += 1;
+            });
+            defs.push(d);
+            uses.push(u);
+        }
+
+        let mut preds = vec![SmallVec::<[usize; 2]>::new(); instrs.len()];
+        for i in 0..instrs.len() {
+            for s in successors(instrs, i) {
+                preds[s].push(i);
+            }
+        }
+
+        // Compute the instructions after which each register is live, one register at a time.
+        // Live ranges are usually short, so walking each one is cheaper than a dataflow analysis
+        // over sets of registers.
+        let mut live_out = vec![Vec::<usize>::new(); instrs.len()];
+        let mut pinned = vec![false; regs.len()];
+        let mut reg_uses = vec![Vec::new(); regs.len()];
+        for (i, u) in uses.iter().enumerate() {
+            for r in u.iter() {
+                reg_uses[*r].push(i);
+            }
+        }
+        // Stamps, to avoid visiting an instruction more than once for the same register.
+        let mut seen_in = vec![usize::MAX; instrs.len()];
+        let mut seen_out = vec![usize::MAX; instrs.len()];
+        let mut stack = Vec::new();
+        for (r, r_uses) in reg_uses.iter().enumerate() {
+            stack.extend(r_uses.iter().cloned());
+            while let Some(i) = stack.pop() {
+                // `r` is live before instruction `i`.
+                if seen_in[i] == r {
+                    continue;
+                }
+                seen_in[i] = r;
+                if i == 0 {
+                    pinned[r] = true;
+                }
+                for p in preds[i].iter().cloned() {
+                    if seen_out[p] != r {
+                        seen_out[p] = r;
+                        live_out[p].push(r);
+                    }
+                    if !defs[p].contains(&r) {
+                        stack.push(p);
+                    }
+                }
+            }
+        }
+
+        let mut interferes = vec![HashSet::new(); regs.len()];
+        let mut moves = vec![SmallVec::new(); regs.len()];
+        let mut add_edge = |x: usize, y: usize| {
+            if x != y {
+                interferes[x].insert(y);
+                interferes[y].insert(x);
+            }
+        };
+        for (i, inst) in instrs.iter().enumerate() {
+            if let Instr::Call(_) = inst {
+                for r in live_out[i].iter() {
+                    pinned[*r] = true;
+                }
+            }
+            let mov_src = match (inst, defs[i].first(), uses[i].first()) {
+                (Instr::Mov(..), Some(d), Some(s)) => {
+                    moves[*d].push(*s);
+                    moves[*s].push(*d);
+                    Some(*s)
+                }
+                _ => None,
+            };
+            for d in defs[i].iter().cloned() {
+                for r in live_out[i].iter().cloned() {
+                    if Some(r) != mov_src {
+                        add_edge(d, r);
+                    }
+                }
+                // Be conservative about instructions whose destination aliases one of their
+                // operands.
+                for r in uses[i].iter().chain(defs[i].iter()).cloned() {
+                    if Some(r) != mov_src {
+                        add_edge(d, r);
+                    }
+                }
+            }
+        }
+        FuncInfo {
+            regs,
+            pinned,
+            interferes,
+            moves,
+        }
+    }
+
+    /// Assign slots to the registers in this function that are not pinned. `rename` holds the
+    /// slots assigned to pinned registers, and `counts` the number of slots used so far for each
+    /// type. Slots are not shared with other functions.
+    fn color(
+        &self,
+        rename: &HashMap<(NumTy, Ty), NumTy>,
+        counts: &mut [usize; NUM_TYPES],
+    ) -> Vec<((NumTy, Ty), NumTy)> {
+        let base = *counts;
+        let mut slots: Vec<Option<NumTy>> = self
+            .regs
+            .iter()
+            .map(|reg| rename.get(reg).cloned())
+            .collect();
+        let mut res = Vec::new();
+        let mut taken = HashSet::new();
+        for (r, reg) in self.regs.iter().enumerate() {
+            if slots[r].is_some() {
+                continue;
+            }
+            let ty = reg.1 as usize;
+            taken.clear();
+            taken.extend(self.interferes[r].iter().flat_map(|n| slots[*n]));
+            let hint = self.moves[r]
+                .iter()
+                .flat_map(|m| slots[*m])
+                .find(|s| *s as usize >= base[ty] && !taken.contains(s));
+            let slot =
+                hint.unwrap_or_else(|| (base[ty] as NumTy..).find(|s| !taken.contains(s)).unwrap());
+            counts[ty] = counts[ty].max(slot as usize + 1);
+            slots[r] = Some(slot);
+            res.push((*reg, slot));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtins::Variable;
+    use crate::bytecode::Label;
+    use Instr::*;
+
+    fn run(instrs: Vec<Instr<'static>>) -> (Vec<String>, [usize; NUM_TYPES]) {
+        let mut funcs = vec![instrs];
+        let counts = allocate(&mut funcs, &mut []);
+        (
+            funcs[0].iter().map(|i| format!("{:?}", i)).collect(),
+            counts,
+        )
+    }
+
+    #[test]
+    fn reuse_temporaries() {
+        let (out, counts) = run(vec![
+            StoreConstInt(10.into(), 1),
+            StoreVarInt(Variable::NR, 10.into()),
+            StoreConstInt(11.into(), 2),
+            StoreVarInt(Variable::NR, 11.into()),
+            StoreConstInt(12.into(), 3),
+            StoreVarInt(Variable::NR, 12.into()),
+            Ret,
+        ]);
+        assert_eq!(counts[Ty::Int as usize], 1);
+        assert_eq!(out[4], "StoreConstInt(<0>, 3)");
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let (out, counts) = run(vec![
+            StoreConstInt(10.into(), 1),
+            StoreConstInt(11.into(), 2),
+            AddInt(12.into(), 10.into(), 11.into()),
+            StoreVarInt(Variable::NR, 12.into()),
+            Ret,
+        ]);
+        assert_eq!(counts[Ty::Int as usize], 3);
+        assert_eq!(out[2], "AddInt(<2>, <0>, <1>)");
+    }
+
+    #[test]
+    fn kept_registers() {
+        let mut funcs = vec![vec![
+            StoreConstInt(10.into(), 1),
+            StoreVarInt(Variable::NR, 10.into()),
+            StoreConstInt(11.into(), 2),
+            StoreVarInt(Variable::NR, 11.into()),
+            Ret,
+        ]];
+        // Kept registers get slots of their own, even if they are never used.
+        let mut keep = [(11, Ty::Int), (20, Ty::Float)];
+        let counts = allocate(&mut funcs, &mut keep);
+        assert_eq!(counts[Ty::Int as usize], 2);
+        assert_eq!(counts[Ty::Float as usize], 1);
+        assert_eq!(keep, [(0, Ty::Int), (0, Ty::Float)]);
+        assert_eq!(format!("{:?}", funcs[0][0]), "StoreConstInt(<1>, 1)");
+    }
+
+    #[test]
+    fn coalesce_moves() {
+        let (out, counts) = run(vec![
+            LoadVarInt(10.into(), Variable::NF),
+            Mov(Ty::Int, 11, 10),
+            StoreVarInt(Variable::NR, 11.into()),
+            Ret,
+        ]);
+        assert_eq!(counts[Ty::Int as usize], 1);
+        assert_eq!(
+            out,
+            vec!["LoadVarInt(<0>, NF)", "StoreVarInt(NR, <0>)", "Ret"]
+        );
+    }
+
+    #[test]
+    fn pin_loop_carried_and_uninitialized() {
+        // Register 10 is read before it is written on the first iteration of the loop, so it must
+        // keep its own slot; the temporaries in the loop body can still share one.
+        let (out, counts) = run(vec![
+            /*0*/ LoadVarInt(11.into(), Variable::NF),
+            /*1*/ JmpIf(11.into(), Label(6)),
+            /*2*/ StoreConstInt(12.into(), 1),
+            /*3*/ AddInt(10.into(), 10.into(), 12.into()),
+            /*4*/ StoreVarInt(Variable::NR, 10.into()),
+            /*5*/ Jmp(Label(0)),
+            /*6*/ Ret,
+        ]);
+        assert_eq!(counts[Ty::Int as usize], 2);
+        assert_eq!(out[3], "AddInt(<0>, <0>, <1>)");
+    }
+
+    #[test]
+    fn pin_shared_registers() {
+        let mut funcs = vec![
+            vec![
+                StoreConstInt(5.into(), 1),
+                StoreConstInt(7.into(), 1),
+                StoreVarInt(Variable::NR, 7.into()),
+                Call(1),
+                Ret,
+            ],
+            vec![StoreVarInt(Variable::NR, 5.into()), Ret],
+        ];
+        let counts = allocate(&mut funcs, &mut []);
+        assert_eq!(counts[Ty::Int as usize], 2);
+        assert_eq!(format!("{:?}", funcs[1][0]), "StoreVarInt(NR, <0>)");
+        assert_eq!(format!("{:?}", funcs[0][1]), "StoreConstInt(<1>, 1)");
+    }
+}
@@ -0,0 +1,440 @@
+//! An interactive session, for --repl.
+//!
+//! A session holds the functions and rules entered so far, along with the values of the global
+//! variables. Compiled programs can't be extended once type inference has run over them, so each
+//! line entered is run as a program of its own: the session's functions and rules, a BEGIN block
+//! that assigns each variable the value it had after the last line, and the new statements. The
+//! program is compiled with `export_globals` set, as programs run through the embedding interface
+//! are (see `embed`), and the values its globals end up with become the session's new state. A
+//! line that fails to compile or run leaves the session as it was.
+//!
+//! Records fed to the session are run through the same program, with the record as its only input.
+//! Builtin variables that a program may set (FS, NR and so on) and the last record are copied to
+//! hidden globals before the program exits, so that the next program can start with them.
+//!
+//! Values are carried between programs as literals, so strings read from input that look like
+//! numbers lose that status once the line that read them has run. FNR is not carried over, as
+//! each program's input is a file of its own.
+use crate::arena::Arena;
+use crate::ast::{self, Expr, Stmt};
+use crate::common::Stage;
+use crate::debugger::{show, show_key, sort_entries};
+use crate::embed::{Program, Value};
+use crate::{lexer, parsing};
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+const HELP: &str = "\
+statement             run a statement; expressions have their values printed
+function f(...) {...} define a function, replacing any other definition of f
+pattern { action }    add a rule, which runs on records fed with :record and :file
+BEGIN { ... }         run a program once, with the session's functions and variables
+:record, :r [text]    run the rules on a record
+:file, :f path        run the rules on each record in a file
+:vars, :v             show the values of all variables
+:list, :l             show the functions and rules, numbered
+:delete, :d n         delete function or rule n
+:unset, :u name       forget the value of a variable
+:reset                delete all functions, rules and variables
+:help, :h             show this message
+:quit, :q             end the session";
+
+// The names of the globals that builtin variables are copied to between programs.
+const HIDDEN: &str = "frawk_repl_";
+
+// The builtin variables carried from one program to the next, in the order they are restored. FS
+// comes before the record is restored, so that it is split the same way it was last time.
+const BUILTINS: &[&str] = &["FS", "OFS", "ORS", "RS", "SUBSEP", "CONVFMT", "OFMT", "NR"];
+
+// Ignore errors writing messages, as the debugger does.
+macro_rules! say {
+    ($s:expr, $($t:tt)*) => {{
+        let _ = writeln!($s.msgs, $($t)*);
+    }};
+}
+
+/// Whether `name` is a variable the user set, rather than a builtin or a hidden copy of one.
+fn user_var(name: &str) -> bool {
+    !name.starts_with(HIDDEN) && !BUILTINS.contains(&name)
+}
+
+/// What a line entered at the prompt does.
+enum Entry {
+    // Define functions with these names.
+    Functions(Vec<String>),
+    // Add rules.
+    Rules,
+    // Run a program with BEGIN or END blocks of its own once.
+    Program,
+    // Run statements, printing the value of the expression if there is one.
+    Statement { echo: bool },
+}
+
+/// A function definition or a group of rules, as entered.
+struct Item {
+    // The functions it defines, if any.
+    functions: Vec<String>,
+    text: String,
+}
+
+pub(crate) struct Session<W> {
+    items: Vec<Item>,
+    scalars: BTreeMap<String, Value>,
+    arrays: BTreeMap<String, BTreeMap<String, Value>>,
+    // Where prompts, errors and other messages go.
+    msgs: W,
+}
+
+/// Parse `src` as a program.
+fn parse<'a>(a: &'a Arena, src: &str) -> Result<ast::Prog<'a, 'a, &'a str>, String> {
+    let src = a.alloc_str(src);
+    let mut buf = Vec::new();
+    let mut prog = ast::Prog::from_stage(a, Stage::Main(()));
+    parsing::syntax::ProgParser::new()
+        .parse(a, &mut buf, &mut prog, lexer::Tokenizer::new(src))
+        .map_err(|e| e.to_string())?;
+    Ok(prog)
+}
+
+/// The expression making up all of `s`, if it is one.
+fn single_expr<'a, 'b, I>(s: &Stmt<'a, 'b, I>) -> Option<&'a Expr<'a, 'b, I>> {
+    match s {
+        Stmt::Loc(_, s) => single_expr(s),
+        Stmt::Block(stmts) if stmts.len() == 1 => single_expr(stmts[0]),
+        Stmt::Expr(e) => Some(e),
+        _ => None,
+    }
+}
+
+fn classify(text: &str) -> Result<Entry, String> {
+    let a = Arena::default();
+    if let Ok(prog) = parse(&a, text) {
+        let blocks = !prog.begin.is_empty() || !prog.prepare.is_empty() || !prog.end.is_empty();
+        let rules = !prog.pats.is_empty();
+        // A pattern without an action is more likely meant as an expression.
+        if !prog.pats.iter().any(|(_, body)| body.is_none()) {
+            if blocks {
+                return Ok(Entry::Program);
+            }
+            match (prog.decs.is_empty(), rules) {
+                (false, false) => {
+                    let names = prog.decs.iter().map(|d| d.name.to_string()).collect();
+                    return Ok(Entry::Functions(names));
+                }
+                (true, true) => return Ok(Entry::Rules),
+                (false, true) => return Err("enter functions and rules separately".into()),
+                (true, false) => {}
+            }
+        }
+    }
+    let prog = parse(&a, &format!("BEGIN {{\n{}\n}}", text))?;
+    // Assignments are not echoed, and neither is the result of getline.
+    let echo = match prog.begin.first().and_then(|s| single_expr(s)) {
+        Some(Expr::Assign(..) | Expr::AssignOp(..) | Expr::Inc { .. } | Expr::Getline { .. }) => {
+            false
+        }
+        Some(_) => true,
+        None => false,
+    };
+    Ok(Entry::Statement { echo })
+}
+
+/// Whether `text` needs more lines: it has unclosed braces or parentheses, or ends with a
+/// backslash.
+fn incomplete(text: &str) -> bool {
+    let mut depth = 0i64;
+    for line in text.lines() {
+        let (mut in_str, mut escaped) = (false, false);
+        for c in line.chars() {
+            if in_str {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_str = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => in_str = true,
+                '#' => break,
+                '{' | '(' => depth += 1,
+                '}' | ')' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    depth > 0 || text.trim_end().ends_with('\\')
+}
+
+/// `s` as an awk string literal.
+fn quote(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            c if c.is_ascii_control() => {
+                let _ = write!(res, "\\{:03o}", c as u32);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// `v` as an awk expression of the same type.
+fn literal(v: &Value) -> String {
+    match v {
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) if f.is_finite() => format!("{:?}", f),
+        Value::Float(f) if f.is_nan() => "(\"nan\" + 0)".into(),
+        Value::Float(f) if *f > 0.0 => "(\"+inf\" + 0)".into(),
+        Value::Float(_) => "(\"-inf\" + 0)".into(),
+        Value::Str(s) => quote(s),
+        Value::Bytes(bs) => quote(&String::from_utf8_lossy(bs)),
+    }
+}
+
+impl<W: Write> Session<W> {
+    pub(crate) fn new(msgs: W) -> Session<W> {
+        Session {
+            items: Vec::new(),
+            scalars: BTreeMap::new(),
+            arrays: BTreeMap::new(),
+            msgs,
+        }
+    }
+
+    /// Read lines from `input` until it ends or `:quit` is entered, writing the output of the
+    /// programs they run to `out`.
+    pub(crate) fn run(&mut self, mut input: impl BufRead, out: &mut (impl Write + Send)) {
+        let mut text = String::new();
+        loop {
+            let _ = write!(
+                self.msgs,
+                "{}",
+                if text.is_empty() {
+                    "frawk> "
+                } else {
+                    "...... "
+                }
+            );
+            let _ = self.msgs.flush();
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    say!(self, "");
+                    return;
+                }
+                Ok(_) => {}
+            }
+            text.push_str(&line);
+            if incomplete(&text) {
+                continue;
+            }
+            let entry = std::mem::take(&mut text);
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if let Some(command) = entry.strip_prefix(':') {
+                if !self.command(command, out) {
+                    return;
+                }
+            } else {
+                self.enter(entry, out);
+            }
+            let _ = out.flush();
+        }
+    }
+
+    /// Run `command`, returning false if it ends the session.
+    fn command(&mut self, command: &str, out: &mut (impl Write + Send)) -> bool {
+        let (cmd, arg) = match command.split_once(char::is_whitespace) {
+            Some((cmd, arg)) => (cmd, arg.trim()),
+            None => (command, ""),
+        };
+        match cmd {
+            "r" | "record" => {
+                let record = format!("{}\n", arg);
+                self.execute("", record.as_bytes(), out);
+            }
+            "f" | "file" => match std::fs::read(arg) {
+                Ok(contents) => self.execute("", &contents[..], out),
+                Err(e) => say!(self, "failed to read {}: {}", arg, e),
+            },
+            "v" | "vars" => {
+                let mut names: Vec<String> = self
+                    .scalars
+                    .keys()
+                    .chain(self.arrays.keys())
+                    .filter(|name| user_var(name))
+                    .cloned()
+                    .collect();
+                names.sort();
+                for name in names.iter() {
+                    self.show_var(name, out);
+                }
+            }
+            "l" | "list" => {
+                for (i, item) in self.items.iter().enumerate() {
+                    let _ = writeln!(out, "{}: {}", i + 1, item.text);
+                }
+            }
+            "d" | "delete" => match arg.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= self.items.len() => {
+                    self.items.remove(n - 1);
+                }
+                _ => say!(self, "no function or rule numbered {:?}", arg),
+            },
+            "u" | "unset" => {
+                if self.scalars.remove(arg).is_none() && self.arrays.remove(arg).is_none() {
+                    say!(self, "no variable named {:?}", arg);
+                }
+            }
+            "reset" => {
+                self.items.clear();
+                self.scalars.clear();
+                self.arrays.clear();
+            }
+            "h" | "help" => say!(self, "{}", HELP),
+            "q" | "quit" => return false,
+            _ => say!(self, "unknown command :{}; type :help for a list", cmd),
+        }
+        true
+    }
+
+    /// Write the value of the variable `name` to `out`.
+    fn show_var(&self, name: &str, out: &mut impl Write) {
+        if let Some(v) = self.scalars.get(name) {
+            let _ = writeln!(out, "{} = {}", name, show(v));
+        } else if let Some(entries) = self.arrays.get(name) {
+            if entries.is_empty() {
+                let _ = writeln!(out, "{} is empty", name);
+            }
+            let mut entries: Vec<_> = entries
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            sort_entries(&mut entries);
+            for (k, v) in entries.iter() {
+                let _ = writeln!(out, "{}[{}] = {}", name, show_key(k), show(v));
+            }
+        }
+    }
+
+    /// Add or run the functions, rules or statements in `text`.
+    fn enter(&mut self, text: &str, out: &mut (impl Write + Send)) {
+        // Arrays can't be printed by awk code, so show them the way :vars does.
+        if self.arrays.contains_key(text) {
+            self.show_var(text, out);
+            return;
+        }
+        let entry = match classify(text) {
+            Ok(entry) => entry,
+            Err(e) => {
+                say!(self, "{}", e);
+                return;
+            }
+        };
+        match entry {
+            Entry::Functions(names) => {
+                let old = std::mem::take(&mut self.items);
+                let (replaced, kept): (Vec<Item>, Vec<Item>) = old
+                    .into_iter()
+                    .partition(|item| item.functions.iter().any(|f| names.contains(f)));
+                self.items = kept;
+                self.items.push(Item {
+                    functions: names,
+                    text: text.into(),
+                });
+                if let Err(e) = Program::compile(&self.source("")) {
+                    say!(self, "{}", e);
+                    self.items.pop();
+                    self.items.extend(replaced);
+                }
+            }
+            Entry::Rules => {
+                self.items.push(Item {
+                    functions: Vec::new(),
+                    text: text.into(),
+                });
+                if let Err(e) = Program::compile(&self.source("")) {
+                    say!(self, "{}", e);
+                    self.items.pop();
+                }
+            }
+            Entry::Program => self.execute(text, &[][..], out),
+            Entry::Statement { echo: true } => {
+                self.execute(&format!("BEGIN {{\nprint ({})\n}}", text), &[][..], out)
+            }
+            Entry::Statement { echo: false } => {
+                self.execute(&format!("BEGIN {{\n{}\n}}", text), &[][..], out)
+            }
+        }
+    }
+
+    /// The program for the session, running `extra` after the variables are restored.
+    fn source(&self, extra: &str) -> String {
+        let mut src = String::from("BEGIN {\n");
+        for name in BUILTINS.iter() {
+            if let Some(v) = self.scalars.get(&format!("{}{}", HIDDEN, name)) {
+                let _ = writeln!(src, "{} = {}", name, literal(v));
+            }
+        }
+        if let Some(v) = self.scalars.get(&format!("{}0", HIDDEN)) {
+            let _ = writeln!(src, "$0 = {}", literal(v));
+        }
+        for (name, v) in self.scalars.iter() {
+            if user_var(name) {
+                let _ = writeln!(src, "{} = {}", name, literal(v));
+            }
+        }
+        for (name, entries) in self.arrays.iter() {
+            let _ = writeln!(src, "delete {}", name);
+            for (k, v) in entries.iter() {
+                let _ = writeln!(src, "{}[{}] = {}", name, quote(k), literal(v));
+            }
+        }
+        src.push_str("}\n");
+        src.push_str(extra);
+        let _ = writeln!(src, "\nBEGIN {{ {}0 = $0 }}", HIDDEN);
+        let _ = writeln!(src, "{{ {}0 = $0 }}", HIDDEN);
+        for item in self.items.iter() {
+            src.push_str(&item.text);
+            src.push('\n');
+        }
+        src.push_str("END {\n");
+        for name in BUILTINS.iter() {
+            let _ = writeln!(src, "{}{} = {}", HIDDEN, name, name);
+        }
+        src.push_str("}\n");
+        src
+    }
+
+    /// Run the session's program with `extra` added, over `input`, and keep the values its
+    /// variables end up with.
+    fn execute(&mut self, extra: &str, input: &[u8], out: &mut (impl Write + Send)) {
+        let res = Program::compile(&self.source(extra))
+            .and_then(|prog| prog.run_with_globals(input, out));
+        match res {
+            Ok((_, globals)) => {
+                let (scalars, arrays) = globals.into_parts();
+                self.scalars = scalars.into_iter().collect();
+                self.arrays = arrays
+                    .into_iter()
+                    .map(|(name, entries)| (name, entries.into_iter().collect()))
+                    .collect();
+            }
+            Err(e) => say!(self, "{}", e),
+        }
+    }
+}
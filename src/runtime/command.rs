@@ -1,7 +1,9 @@
-use std::io;
-use std::process::{ChildStdin, Command, Stdio};
-
-use grep_cli::{CommandError, CommandReader};
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
 
 use crate::runtime::Int;
 
@@ -21,27 +23,97 @@ fn prepare_command(bs: &[u8]) -> io::Result<Command> {
     }
 }
 
+fn wrap_err(e: Option<i32>) -> Int {
+    e.map(Int::from).unwrap_or(1)
+}
+
+fn exit_code(status: io::Result<ExitStatus>) -> Int {
+    match status {
+        Ok(status) => wrap_err(status.code()),
+        Err(e) => wrap_err(e.raw_os_error()),
+    }
+}
+
 pub fn run_command(bs: &[u8]) -> Int {
-    fn wrap_err(e: Option<i32>) -> Int {
-        e.map(Int::from).unwrap_or(1)
+    exit_code(prepare_command(bs).and_then(|mut cmd| cmd.status()))
+}
+
+/// The input of a command that frawk is printing to.
+///
+/// Dropping a CommandWriter closes the command's standard input and waits for it to exit, storing
+/// its exit code (computed the same way as for `system`) in `status`.
+pub struct CommandWriter {
+    stdin: Option<ChildStdin>,
+    child: Child,
+    status: Arc<AtomicI64>,
+}
+
+impl Write for CommandWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write(buf)
     }
-    fn run_command_inner(bs: &[u8]) -> io::Result<Int> {
-        let status = prepare_command(bs)?.status()?;
-        Ok(wrap_err(status.code()))
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write_vectored(bufs)
     }
-    match run_command_inner(bs) {
-        Ok(i) => i,
-        Err(e) => wrap_err(e.raw_os_error()),
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for CommandWriter {
+    fn drop(&mut self) {
+        self.stdin = None;
+        self.status
+            .store(exit_code(self.child.wait()), Ordering::Release);
     }
 }
 
-pub fn command_for_write(bs: &[u8]) -> io::Result<ChildStdin> {
+pub fn command_for_write(bs: &[u8], status: Arc<AtomicI64>) -> io::Result<CommandWriter> {
     let mut cmd = prepare_command(bs)?;
     let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::inherit()).spawn()?;
-    Ok(child.stdin.take().unwrap())
+    Ok(CommandWriter {
+        stdin: child.stdin.take(),
+        child,
+        status,
+    })
+}
+
+/// The output of a command that frawk is reading from.
+///
+/// Unlike a CommandWriter, a CommandReader does not record the command's exit code when it is
+/// dropped; `close` returns it instead. Dropping a CommandReader still closes the command's
+/// standard output and waits for it to exit.
+pub struct CommandReader {
+    stdout: Option<ChildStdout>,
+    child: Child,
+}
+
+impl Read for CommandReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.as_mut().unwrap().read(buf)
+    }
+}
+
+impl CommandReader {
+    /// Close the command's standard output and wait for it to exit, returning its exit code.
+    pub fn close(mut self) -> Int {
+        self.stdout = None;
+        exit_code(self.child.wait())
+    }
+}
+
+impl Drop for CommandReader {
+    fn drop(&mut self) {
+        self.stdout = None;
+        let _ = self.child.wait();
+    }
 }
 
-pub fn command_for_read(bs: &[u8]) -> Result<CommandReader, CommandError> {
+pub fn command_for_read(bs: &[u8]) -> io::Result<CommandReader> {
     let mut cmd = prepare_command(bs)?;
-    CommandReader::new(&mut cmd)
+    let mut child = cmd.stdin(Stdio::inherit()).stdout(Stdio::piped()).spawn()?;
+    Ok(CommandReader {
+        stdout: child.stdout.take(),
+        child,
+    })
 }
@@ -0,0 +1,213 @@
+//! Transparent compression of input and output files.
+//!
+//! Compressed input files are recognized by their first few bytes, falling back to their
+//! extension, so that `frawk '...' access.log.gz` works without an external `zcat`. Output files
+//! are compressed based on their extension alone, so `print > "out.gz"` writes gzip data. The
+//! encoders and decoders themselves are only built with the `compression` feature; without it,
+//! compressed files are read and written as-is.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Format {
+    /// Guess the format of a file from the start of its contents.
+    fn sniff(bs: &[u8]) -> Option<Format> {
+        // bzip2 files start with "BZh", a block size, and then either the magic number of the
+        // first block or of the end of the stream (for empty files). The extra bytes keep us from
+        // mistaking ordinary text starting with "BZh" for a compressed file.
+        const BZ_BLOCK: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+        const BZ_EOS: &[u8] = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+        match bs {
+            [0x1f, 0x8b, ..] => Some(Format::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Format::Zstd),
+            [b'B', b'Z', b'h', b'1'..=b'9', rest @ ..]
+                if rest.starts_with(BZ_BLOCK) || rest.starts_with(BZ_EOS) =>
+            {
+                Some(Format::Bzip2)
+            }
+            _ => None,
+        }
+    }
+
+    /// Guess the format of a file from its extension.
+    pub fn from_path(path: &str) -> Option<Format> {
+        let (_, ext) = path.rsplit_once('.')?;
+        match ext {
+            "gz" => Some(Format::Gzip),
+            "zst" => Some(Format::Zstd),
+            "bz2" => Some(Format::Bzip2),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    fn decoder(self, r: BufReader<impl Read + Send + 'static>) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            // The "multi" decoders keep reading past the end of the first stream, which is what
+            // tools like zcat do with concatenated files.
+            Format::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(r)),
+            Format::Zstd => Box::new(zstd::Decoder::with_buffer(r)?),
+            Format::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(r)),
+        })
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn decoder(self, r: BufReader<impl Read + Send + 'static>) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(r))
+    }
+
+    /// Wrap `w` in an encoder for this format. Levels outside of the range a format supports are
+    /// clamped to it; `None` uses the format's default level.
+    ///
+    /// The encoders write out the end of the compressed stream when they are dropped, so output
+    /// files have to be closed (rather than just flushed) to be complete.
+    #[cfg(feature = "compression")]
+    fn encoder(self, w: File, level: Option<u32>) -> io::Result<Box<dyn Write + Send>> {
+        Ok(match self {
+            Format::Gzip => {
+                let level = level.map_or(flate2::Compression::default(), |l| {
+                    flate2::Compression::new(l.min(9))
+                });
+                Box::new(flate2::write::GzEncoder::new(w, level))
+            }
+            Format::Zstd => {
+                // Level 0 asks zstd for its default level.
+                let level = level.map_or(0, |l| l.min(22) as i32);
+                Box::new(zstd::Encoder::new(w, level)?.auto_finish())
+            }
+            Format::Bzip2 => {
+                let level = level.map_or(bzip2::Compression::default(), |l| {
+                    bzip2::Compression::new(l.clamp(1, 9))
+                });
+                Box::new(bzip2::write::BzEncoder::new(w, level))
+            }
+        })
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn encoder(self, w: File, _level: Option<u32>) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(w))
+    }
+}
+
+/// Wrap `r`, the contents of the file at `path`, in a decoder if the file is compressed.
+pub fn decompress(path: &str, r: impl Read + Send + 'static) -> io::Result<Box<dyn Read + Send>> {
+    let mut r = BufReader::new(r);
+    match Format::sniff(r.fill_buf()?).or_else(|| Format::from_path(path)) {
+        Some(format) => format.decoder(r),
+        None => Ok(Box::new(r)),
+    }
+}
+
+/// Open the file at `path` for reading, skipping the first `skip` bytes of its (decompressed)
+/// contents. Uncompressed files are seeked to `skip`; compressed ones are decoded from the start.
+pub fn open_at(path: &str, skip: u64) -> io::Result<Box<dyn Read + Send>> {
+    let mut r = BufReader::new(File::open(path)?);
+    match Format::sniff(r.fill_buf()?).or_else(|| Format::from_path(path)) {
+        Some(format) => {
+            let mut r = format.decoder(r)?;
+            io::copy(&mut r.by_ref().take(skip), &mut io::sink())?;
+            Ok(r)
+        }
+        None => {
+            r.seek(SeekFrom::Start(skip))?;
+            Ok(Box::new(r))
+        }
+    }
+}
+
+/// Wrap `w`, opened for writing at `path`, in an encoder if `path` names a compressed file.
+///
+/// Appending to a compressed file adds a new stream to the end of it, which `decompress` (like
+/// `zcat`) reads as a continuation of the earlier contents.
+pub fn compress(path: &str, w: File, level: Option<u32>) -> io::Result<Box<dyn Write + Send>> {
+    match Format::from_path(path) {
+        Some(format) => format.encoder(w, level),
+        None => Ok(Box::new(w)),
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"one two\nthree four\n";
+
+    fn read_all(path: &str, bs: Vec<u8>) -> Vec<u8> {
+        let mut res = Vec::new();
+        decompress(path, io::Cursor::new(bs))
+            .unwrap()
+            .read_to_end(&mut res)
+            .unwrap();
+        res
+    }
+
+    #[test]
+    fn decompress_by_contents() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(TEXT).unwrap();
+        let gz = gz.finish().unwrap();
+        let zst = zstd::encode_all(TEXT, 0).unwrap();
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(TEXT).unwrap();
+        let bz = bz.finish().unwrap();
+
+        // Extensions do not matter when the contents are recognized.
+        assert_eq!(read_all("input", gz.clone()), TEXT);
+        assert_eq!(read_all("input.txt", zst), TEXT);
+        assert_eq!(read_all("input.gz", bz), TEXT);
+
+        // Concatenated gzip files are read in full.
+        let mut two = gz.clone();
+        two.extend_from_slice(&gz);
+        assert_eq!(read_all("input", two), [TEXT, TEXT].concat());
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, level) in [
+            ("out.gz", Some(1)),
+            ("out.zst", Some(100)),
+            ("out.bz2", None),
+        ] {
+            let path = dir.path().join(name);
+            let path = path.to_str().unwrap();
+            for _ in 0..2 {
+                let f = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .unwrap();
+                let mut w = compress(path, f, level).unwrap();
+                w.write_all(TEXT).unwrap();
+            }
+            let bs = std::fs::read(path).unwrap();
+            assert_ne!(bs, [TEXT, TEXT].concat());
+            assert_eq!(read_all(path, bs), [TEXT, TEXT].concat(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn plain_text_passes_through() {
+        assert_eq!(read_all("input", TEXT.to_vec()), TEXT);
+        assert_eq!(
+            read_all("input", b"BZh9 is not bzip2\n".to_vec()),
+            b"BZh9 is not bzip2\n"
+        );
+        assert_eq!(read_all("input", Vec::new()), b"");
+        // A file that is named like a compressed file but isn't one is an error.
+        let mut buf = Vec::new();
+        assert!(decompress("input.gz", io::Cursor::new(TEXT))
+            .unwrap()
+            .read_to_end(&mut buf)
+            .is_err());
+    }
+}
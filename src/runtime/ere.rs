@@ -0,0 +1,433 @@
+//! Translation of Awk's POSIX extended regular expressions into the syntax of the `regex` crate.
+//!
+//! The two are close, but a pattern written for gawk or mawk can fail to compile, or mean
+//! something different, when it is passed straight to `Regex::new`:
+//!
+//! * `*`, `+`, `?` and `{` are literals when there is nothing before them to repeat, as in `/*x/`
+//!   or `/({)/`, and so is a `{` that does not start an interval like `{2}` or `{1,3}`.
+//! * Repetitions can be stacked, as in `a*?`, which is greedy rather than lazy.
+//! * Inside a bracket expression, `[` is a literal unless it starts a character class such as
+//!   `[:alpha:]`, a `]` right after the opening `[` or `[^` is a literal, and so are doubled
+//!   `&`, `-` and `~`. Collating symbols and equivalence classes (`[.x.]` and `[=x=]`) match `x`.
+//! * Escaping a character with no special meaning gives that character, as in `\/` or `\"`. Octal
+//!   and hex escapes like `\033` or `\x1b` give the corresponding byte, and `\b` in a bracket
+//!   expression is a backspace.
+//! * gawk's `\y` matches a word boundary, as do `\<` and `\>`, which cannot check which side of
+//!   the boundary the word is on. `` \` `` and `\'` match the start and end of the text.
+//!
+//! Syntax that the `regex` crate supports and Awk does not, like `\d` or `\pL`, passes through
+//! unchanged.
+use regex_syntax::is_meta_character;
+
+/// Translate the ERE `pat` into a pattern for `regex::bytes::Regex`. Patterns that are not valid
+/// UTF-8, or that use escapes for bytes outside of ASCII, are matched byte-by-byte, as are all
+/// patterns if `bytes` is set.
+pub(crate) fn translate(pat: &[u8], bytes: bool) -> String {
+    let mut t = Translator {
+        pat,
+        i: 0,
+        out: Vec::with_capacity(pat.len() + 8),
+        utf8: !bytes && std::str::from_utf8(pat).is_ok(),
+        bytes,
+    };
+    t.translate();
+    let mut res = String::with_capacity(t.out.len() + 5);
+    if t.bytes || !t.utf8 {
+        res.push_str("(?-u)");
+    }
+    // Non-ASCII bytes are only copied to the output if `pat` is valid UTF-8, and then a whole
+    // character at a time.
+    res.push_str(std::str::from_utf8(&t.out).expect("translated regex is valid UTF-8"));
+    res
+}
+
+struct Translator<'a> {
+    pat: &'a [u8],
+    i: usize,
+    out: Vec<u8>,
+    utf8: bool,
+    // Set when the pattern must be matched as bytes rather than as UTF-8.
+    bytes: bool,
+}
+
+impl<'a> Translator<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.pat.get(self.i).copied()
+    }
+
+    fn translate(&mut self) {
+        // The offsets in `out` of the groups that are open, and of the last expression that a
+        // repetition would apply to, if there is one.
+        let mut groups = Vec::new();
+        let mut atom: Option<usize> = None;
+        let mut repeated = false;
+        while let Some(b) = self.peek() {
+            let start = self.out.len();
+            self.i += 1;
+            let is_atom = match b {
+                b'*' | b'+' | b'?' | b'{' => {
+                    let interval = if b == b'{' { self.interval() } else { None };
+                    match atom {
+                        Some(atom) if b != b'{' || interval.is_some() => {
+                            if repeated {
+                                // a*? and friends are greedy in Awk, so group the repetition
+                                // rather than letting it turn into a lazy one.
+                                self.out.splice(atom..atom, b"(?:".iter().copied());
+                                self.out.push(b')');
+                            }
+                            match interval {
+                                Some(interval) => self.out.extend_from_slice(interval.as_bytes()),
+                                None => self.out.push(b),
+                            }
+                            repeated = true;
+                            continue;
+                        }
+                        _ => {
+                            self.literal(b);
+                            true
+                        }
+                    }
+                }
+                b'\\' => match self.peek() {
+                    // A backslash before a non-ASCII character is ignored.
+                    Some(c) if !c.is_ascii() => continue,
+                    _ => self.escape(false),
+                },
+                b'[' => {
+                    self.bracket();
+                    true
+                }
+                b'(' => {
+                    groups.push(start);
+                    self.out.push(b);
+                    false
+                }
+                b')' => {
+                    if let Some(group) = groups.pop() {
+                        self.out.push(b);
+                        atom = Some(group);
+                        repeated = false;
+                        continue;
+                    }
+                    self.literal(b);
+                    true
+                }
+                b'|' | b'^' | b'$' => {
+                    self.out.push(b);
+                    false
+                }
+                b'}' => {
+                    self.literal(b);
+                    true
+                }
+                b if b.is_ascii() => {
+                    self.out.push(b);
+                    true
+                }
+                b if self.utf8 => {
+                    self.out.push(b);
+                    // Continuation bytes of a UTF-8 character belong to the atom that its first
+                    // byte started.
+                    if b < 0xC0 {
+                        continue;
+                    }
+                    true
+                }
+                b => {
+                    self.non_ascii(b);
+                    true
+                }
+            };
+            atom = if is_atom { Some(start) } else { None };
+            repeated = false;
+        }
+    }
+
+    /// Parse the interval that starts after a `{`, returning it in the regex crate's syntax.
+    fn interval(&mut self) -> Option<String> {
+        let rest = &self.pat[self.i..];
+        let close = rest.iter().position(|b| *b == b'}')?;
+        let body = std::str::from_utf8(&rest[..close]).ok()?;
+        let (min, max) = match body.split_once(',') {
+            Some((min, max)) => (min, Some(max)),
+            None => (body, None),
+        };
+        let is_num = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        let res = match max {
+            None if is_num(min) => format!("{{{}}}", min),
+            Some(max) if (min.is_empty() || is_num(min)) && (max.is_empty() || is_num(max)) => {
+                if min.is_empty() && max.is_empty() {
+                    return None;
+                }
+                let min = if min.is_empty() { "0" } else { min };
+                format!("{{{},{}}}", min, max)
+            }
+            _ => return None,
+        };
+        self.i += close + 1;
+        Some(res)
+    }
+
+    /// Translate the escape after a `\`, returning whether it matches a character (rather than
+    /// an assertion like a word boundary).
+    fn escape(&mut self, in_bracket: bool) -> bool {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => {
+                self.out.extend_from_slice(b"\\\\");
+                return true;
+            }
+        };
+        self.i += 1;
+        match c {
+            b'0'..=b'7' => {
+                let mut val = u32::from(c - b'0');
+                for _ in 0..2 {
+                    match self.peek() {
+                        Some(d @ b'0'..=b'7') => {
+                            val = val * 8 + u32::from(d - b'0');
+                            self.i += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                self.byte(val as u8);
+            }
+            b'x' if self.peek() == Some(b'{') => self.copy_braced(b'x'),
+            b'x' => {
+                let mut val = 0u32;
+                let mut digits = 0;
+                while let Some(d) = self.peek().and_then(|d| (d as char).to_digit(16)) {
+                    if digits == 2 {
+                        break;
+                    }
+                    val = val * 16 + d;
+                    digits += 1;
+                    self.i += 1;
+                }
+                if digits == 0 {
+                    self.literal(b'x');
+                } else {
+                    self.byte(val as u8);
+                }
+            }
+            b'p' | b'P' if self.peek() == Some(b'{') => self.copy_braced(c),
+            b'p' | b'P' if self.peek().is_some_and(|d| d.is_ascii_alphabetic()) => {
+                self.out.extend_from_slice(&[b'\\', c, self.pat[self.i]]);
+                self.i += 1;
+            }
+            b'b' if in_bracket => self.byte(8),
+            b'a' | b'f' | b'n' | b'r' | b't' | b'v' | b'd' | b'D' | b's' | b'S' | b'w' | b'W' => {
+                self.out.extend_from_slice(&[b'\\', c])
+            }
+            _ if in_bracket => self.literal(c),
+            b'b' | b'B' | b'A' | b'z' => {
+                self.out.extend_from_slice(&[b'\\', c]);
+                return false;
+            }
+            b'y' | b'<' | b'>' => {
+                self.out.extend_from_slice(b"\\b");
+                return false;
+            }
+            b'`' => {
+                self.out.extend_from_slice(b"\\A");
+                return false;
+            }
+            b'\'' => {
+                self.out.extend_from_slice(b"\\z");
+                return false;
+            }
+            c => self.literal(c),
+        }
+        true
+    }
+
+    /// Translate a bracket expression, after its opening `[`.
+    fn bracket(&mut self) {
+        self.out.push(b'[');
+        if self.peek() == Some(b'^') {
+            self.out.push(b'^');
+            self.i += 1;
+        }
+        if self.peek() == Some(b']') {
+            self.out.extend_from_slice(b"\\]");
+            self.i += 1;
+        }
+        while let Some(c) = self.peek() {
+            self.i += 1;
+            match c {
+                b']' => {
+                    self.out.push(c);
+                    return;
+                }
+                b'[' => match self.peek() {
+                    Some(delim @ (b':' | b'.' | b'=')) => {
+                        let rest = &self.pat[self.i + 1..];
+                        let end = match rest.windows(2).position(|w| w == [delim, b']']) {
+                            Some(end) => end,
+                            None => {
+                                self.literal(c);
+                                continue;
+                            }
+                        };
+                        let inner = self.i + 1..self.i + 1 + end;
+                        self.i = inner.end + 2;
+                        if delim == b':' {
+                            self.out.extend_from_slice(b"[:");
+                            self.out.extend_from_slice(&self.pat[inner]);
+                            self.out.extend_from_slice(b":]");
+                        } else {
+                            for &b in &self.pat[inner] {
+                                self.class_char(b);
+                            }
+                        }
+                    }
+                    _ => self.literal(c),
+                },
+                b'\\' => match self.peek() {
+                    Some(c) if !c.is_ascii() => {}
+                    _ => {
+                        self.escape(true);
+                    }
+                },
+                b'&' | b'-' | b'~' if self.peek() == Some(c) => {
+                    self.out.extend_from_slice(&[c, b'\\', c]);
+                    self.i += 1;
+                }
+                c => self.class_char(c),
+            }
+        }
+    }
+
+    fn class_char(&mut self, c: u8) {
+        match c {
+            b'[' | b']' | b'\\' => self.literal(c),
+            c if c.is_ascii() || self.utf8 => self.out.push(c),
+            c => self.non_ascii(c),
+        }
+    }
+
+    fn copy_braced(&mut self, c: u8) {
+        let len = self.pat[self.i..]
+            .iter()
+            .position(|b| *b == b'}')
+            .map_or(self.pat.len() - self.i, |i| i + 1);
+        self.out.extend_from_slice(&[b'\\', c]);
+        self.out.extend_from_slice(&self.pat[self.i..self.i + len]);
+        self.i += len;
+    }
+
+    fn literal(&mut self, c: u8) {
+        if is_meta_character(c as char) {
+            self.out.push(b'\\');
+        }
+        self.out.push(c);
+    }
+
+    fn byte(&mut self, b: u8) {
+        if b.is_ascii_graphic() || b == b' ' {
+            self.literal(b);
+        } else {
+            if !b.is_ascii() {
+                self.bytes = true;
+            }
+            self.out
+                .extend_from_slice(format!("\\x{:02X}", b).as_bytes());
+        }
+    }
+
+    fn non_ascii(&mut self, b: u8) {
+        self.bytes = true;
+        self.out
+            .extend_from_slice(format!("\\x{:02X}", b).as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tr(pat: &str) -> String {
+        translate(pat.as_bytes(), false)
+    }
+
+    #[test]
+    fn passthrough() {
+        for pat in [
+            "abc",
+            "^a(b|c)*d$",
+            "[[:alpha:]_][[:alnum:]_]*",
+            "x{2,3}y{4}z{5,}",
+            "[^a-z]+",
+            r"\d+\.\d*",
+            r"\bword\B",
+            "é+",
+        ] {
+            assert_eq!(tr(pat), pat);
+        }
+    }
+
+    #[test]
+    fn literal_repetitions() {
+        assert_eq!(tr("*x"), r"\*x");
+        assert_eq!(tr("(+|?)"), r"(\+|\?)");
+        assert_eq!(tr("^*"), r"^\*");
+        assert_eq!(tr("{}"), r"\{\}");
+        assert_eq!(tr("a{b}"), r"a\{b\}");
+        assert_eq!(tr("a{,}"), r"a\{,\}");
+        assert_eq!(tr("a{,2}"), "a{0,2}");
+        assert_eq!(tr("a)"), r"a\)");
+    }
+
+    #[test]
+    fn stacked_repetitions() {
+        assert_eq!(tr("a*?"), "(?:a*)?");
+        assert_eq!(tr("x(ab)+*"), "x(?:(ab)+)*");
+        assert_eq!(tr("[ab]{2}+?"), "(?:(?:[ab]{2})+)?");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(tr(r#"\/\"\,"#), r#"/","#);
+        assert_eq!(tr(r"\.\*\\"), r"\.\*\\");
+        assert_eq!(tr(r"\101\x42\033"), r"AB\x1B");
+        assert_eq!(tr(r"\351"), r"(?-u)\xE9");
+        assert_eq!(tr(r"\ya\<b\>\`\'"), r"\ba\bb\b\A\z");
+        assert_eq!(tr(r"\q"), "q");
+        assert_eq!(tr("a\\"), r"a\\");
+        assert_eq!(tr(r"\x{263A}\pL\p{Greek}"), r"\x{263A}\pL\p{Greek}");
+    }
+
+    #[test]
+    fn brackets() {
+        assert_eq!(tr("[]a]"), r"[\]a]");
+        assert_eq!(tr("[^]a]"), r"[^\]a]");
+        assert_eq!(tr("[[]"), r"[\[]");
+        assert_eq!(tr("[a[b]"), r"[a\[b]");
+        assert_eq!(tr("[a&&b]"), r"[a&\&b]");
+        assert_eq!(tr("[!--]"), r"[!-\-]");
+        assert_eq!(tr("[[.-.][=a=]]"), "[-a]");
+        assert_eq!(tr(r"[\b\]\/]"), r"[\x08\]/]");
+        assert_eq!(tr("[*+?{]*"), "[*+?{]*");
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_eq!(translate(b"a\xff+[\xfe]", false), r"(?-u)a\xFF+[\xFE]");
+    }
+
+    #[test]
+    fn byte_mode() {
+        assert_eq!(translate("é+.".as_bytes(), true), r"(?-u)\xC3\xA9+.");
+        assert_eq!(translate("[^é]".as_bytes(), true), r"(?-u)[^\xC3\xA9]");
+    }
+
+    #[test]
+    fn translations_compile() {
+        use regex::bytes::Regex;
+        let re = Regex::new(&tr("^*[]x[]{2}a*?$")).unwrap();
+        assert!(re.is_match(b"*]xaa"));
+        let re = Regex::new(&tr(r"[[:digit:][.-.]]+\.\/")).unwrap();
+        assert!(re.is_match(b"12-3./"));
+    }
+}
@@ -5,24 +5,73 @@ fn is_integer(c: u8) -> bool {
     c.is_ascii_digit()
 }
 
+// The blanks that strtod(3) skips before a number.
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0b' | b'\x0c')
+}
+
+fn skip_space(mut bs: &[u8]) -> &[u8] {
+    while let [b, rest @ ..] = bs {
+        if !is_space(*b) {
+            break;
+        }
+        bs = rest;
+    }
+    bs
+}
+
+fn split_sign(bs: &[u8]) -> (bool, &[u8]) {
+    match bs {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, bs),
+    }
+}
+
+/// The value of the run of at most `max` digits at the start of `bs`, along with its length.
+fn leading_digits(bs: &[u8], max: usize) -> (u64, usize) {
+    let mut n = 0u64;
+    let mut len = 0;
+    while len < max && len < bs.len() && is_integer(bs[len]) {
+        n = n * 10 + (bs[len] - b'0') as u64;
+        len += 1;
+    }
+    (n, len)
+}
+
+// Does the number continue past a prefix of plain digits, i.e. does it have a fractional part, an
+// exponent, or more digits than we parsed?
+fn continues(bs: &[u8]) -> bool {
+    matches!(
+        bs.first(),
+        Some(b'0'..=b'9') | Some(b'.') | Some(b'e') | Some(b'E')
+    )
+}
+
+/// Parse the integer at the start of `bs`, returning 0 if there isn't one. Like `strtod`, leading
+/// blanks are skipped; numbers with a fractional part or an exponent are truncated toward zero, and
+/// those out of range saturate.
+///
 /// The simdjson repo has more optimizations to add for int parsing, but this is a big win over libc
 /// for the time being, if only because we do not have to copy `s` into a NUL-terminated
 /// representation.
 pub fn strtoi(bs: &[u8]) -> i64 {
-    if bs.is_empty() {
-        return 0;
-    }
-    let neg = bs[0] == b'-';
-    let off = if neg || bs[0] == b'+' { 1 } else { 0 };
+    let bs = skip_space(bs);
+    let (neg, digits) = split_sign(bs);
     let mut i = 0i64;
-    for b in bs[off..].iter().cloned().take_while(|b| is_integer(*b)) {
+    let mut len = 0;
+    for b in digits.iter().cloned().take_while(|b| is_integer(*b)) {
         let digit = (b - b'0') as i64;
         i = if let Some(i) = i.checked_mul(10).and_then(|i| i.checked_add(digit)) {
             i
         } else {
             // overflow
-            return 0;
-        }
+            return strtod(bs) as i64;
+        };
+        len += 1;
+    }
+    if len == 0 || continues(&digits[len..]) {
+        return strtod(bs) as i64;
     }
     if neg {
         -i
@@ -66,8 +115,40 @@ pub fn hextoi(mut bs: &[u8]) -> i64 {
     }
 }
 
-/// Parse a floating-poing number from `bs`, returning 0 if one isn't there.
+// Powers of ten that are exactly representable as floats.
+const POW10: [f64; 16] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15,
+];
+
+/// Most numbers in input data are short integers or decimals like "12.50", which are cheaper to
+/// parse by hand than to hand to fast_float. If there are at most 15 digits and no exponent, the
+/// digits form an integer that is exactly representable as a float, and dividing it by an exact
+/// power of ten is correctly rounded. Returns None for anything else.
+fn strtod_short(bs: &[u8]) -> Option<f64> {
+    let (neg, digits) = split_sign(bs);
+    let (mut n, int_len) = leading_digits(digits, 15);
+    let mut rest = &digits[int_len..];
+    let mut frac_len = 0;
+    if let [b'.', frac @ ..] = rest {
+        let (frac_n, len) = leading_digits(frac, 15 - int_len);
+        n = n * 10u64.pow(len as u32) + frac_n;
+        frac_len = len;
+        rest = &frac[len..];
+    }
+    if int_len + frac_len == 0 || continues(rest) {
+        return None;
+    }
+    let f = n as f64 / POW10[frac_len];
+    Some(if neg { -f } else { f })
+}
+
+/// Parse a floating-point number from the start of `bs` (after any blanks), returning 0 if one
+/// isn't there.
 pub fn strtod(bs: &[u8]) -> f64 {
+    let bs = skip_space(bs);
+    if let Some(f) = strtod_short(bs) {
+        return f;
+    }
     if let Ok((f, _)) = fast_float::parse_partial(bs) {
         f
     } else {
@@ -75,9 +156,47 @@ pub fn strtod(bs: &[u8]) -> f64 {
     }
 }
 
+/// Parse `bs` as a floating-point number if all of it, ignoring leading and trailing blanks, is
+/// one. This is the test awk uses to decide whether a string from the input "looks numeric".
+pub fn strtod_exact(bs: &[u8]) -> Option<f64> {
+    let is_blank = |b: &u8| matches!(b, b' ' | b'\t' | b'\n');
+    let start = bs.iter().position(|b| !is_blank(b))?;
+    let end = bs.iter().rposition(|b| !is_blank(b))? + 1;
+    let bs = &bs[start..end];
+    // fast_float also accepts "inf" and "nan"; awk does not consider those numbers.
+    let digits = match bs {
+        [b'+', rest @ ..] | [b'-', rest @ ..] => rest,
+        _ => bs,
+    };
+    if !matches!(digits.first(), Some(b'0'..=b'9') | Some(b'.')) {
+        return None;
+    }
+    match fast_float::parse_partial(bs) {
+        Ok((f, len)) if len == bs.len() => Some(f),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn exact() {
+        assert_eq!(strtod_exact(b"1.5"), Some(1.5));
+        assert_eq!(strtod_exact(b"  -3e2\t"), Some(-300.0));
+        assert_eq!(strtod_exact(b".5"), Some(0.5));
+        assert_eq!(strtod_exact(b"+10"), Some(10.0));
+        assert_eq!(strtod_exact(b"1.5x"), None);
+        assert_eq!(strtod_exact(b"x1.5"), None);
+        assert_eq!(strtod_exact(b"1 2"), None);
+        assert_eq!(strtod_exact(b"inf"), None);
+        assert_eq!(strtod_exact(b"-nan"), None);
+        assert_eq!(strtod_exact(b"0x10"), None);
+        assert_eq!(strtod_exact(b""), None);
+        assert_eq!(strtod_exact(b"  "), None);
+        assert_eq!(strtod_exact(b"-"), None);
+    }
+
     #[test]
     fn basic_behavior() {
         assert_eq!(strtod(b"1.234"), 1.234);
@@ -90,5 +209,32 @@ mod tests {
         let imin = format!("{}", i64::min_value());
         assert_eq!(strtod(imax.as_bytes()), i64::max_value() as f64);
         assert_eq!(strtod(imin.as_bytes()), i64::min_value() as f64);
+        assert_eq!(strtod(b" \t12abc"), 12.0);
+        assert_eq!(strtod(b"-0"), -0.0);
+        assert_eq!(strtod(b"+7."), 7.0);
+        assert_eq!(strtod(b"1e"), 1.0);
+        assert_eq!(strtod(b"-"), 0.0);
+        assert_eq!(strtod(b"x12"), 0.0);
+        assert_eq!(strtod(b"12.50 "), 12.5);
+        assert_eq!(strtod(b"-.25"), -0.25);
+        assert_eq!(strtod(b"."), 0.0);
+        assert_eq!(strtod(b"1.2.3"), 1.2);
+        assert_eq!(strtod(b"0.1"), 0.1);
+        assert_eq!(strtod(b"123456789.123456789"), 123456789.12345679);
+    }
+
+    #[test]
+    fn int_behavior() {
+        assert_eq!(strtoi(b"123"), 123);
+        assert_eq!(strtoi(b"  -45xyz"), -45);
+        assert_eq!(strtoi(b"+8"), 8);
+        assert_eq!(strtoi(b"1.9e1"), 19);
+        assert_eq!(strtoi(b"-2.7"), -2);
+        assert_eq!(strtoi(b".5"), 0);
+        assert_eq!(strtoi(b""), 0);
+        assert_eq!(strtoi(b"abc"), 0);
+        assert_eq!(strtoi(b"99999999999999999999"), i64::MAX);
+        assert_eq!(strtoi(b"-99999999999999999999"), i64::MIN);
+        assert_eq!(strtoi(b"9223372036854775806"), i64::MAX - 1);
     }
 }
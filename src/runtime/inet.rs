@@ -0,0 +1,305 @@
+//! Network special files, as in gawk.
+//!
+//! Printing to or reading from a file named `/inet/PROTOCOL/LOCAL-PORT/HOST/REMOTE-PORT` opens a
+//! socket rather than a file. PROTOCOL is `tcp` or `udp`, and `/inet4` or `/inet6` in place of
+//! `/inet` restrict HOST to addresses of that family. A TCP file with a remote port of 0 listens
+//! on LOCAL-PORT and accepts a single connection; otherwise frawk connects to HOST.
+//!
+//! Output and input files with the same name share a connection, so a script can print a request
+//! to a server and then read its response with `getline`. Closing the output side of a TCP
+//! connection shuts it down for writing, which tells the server that the request is complete.
+//! Input is read until the other end closes the connection, or for UDP, until the end of the
+//! first datagram; closing the input file and reading from it again reads the next one.
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    UdpSocket,
+};
+use std::sync::{Arc, Mutex};
+
+use hashbrown::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Spec<'a> {
+    protocol: Protocol,
+    // None if either address family will do, otherwise whether the address must be IPv6.
+    ipv6: Option<bool>,
+    local_port: u16,
+    host: &'a str,
+    remote_port: u16,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Parse `path` as a network special file, returning None if it does not start with one of the
+/// `/inet` prefixes.
+fn parse(path: &str) -> Option<io::Result<Spec<'_>>> {
+    let mut parts = path.strip_prefix('/')?.split('/');
+    let ipv6 = match parts.next()? {
+        "inet" => None,
+        "inet4" => Some(false),
+        "inet6" => Some(true),
+        _ => return None,
+    };
+    let bad_path = || {
+        invalid(format!(
+            "invalid network file '{}', expected /inet/tcp|udp/local-port/host/remote-port",
+            path
+        ))
+    };
+    let spec = (|| {
+        let protocol = match parts.next()? {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return None,
+        };
+        let local_port = parts.next()?.parse().ok()?;
+        let host = parts.next()?;
+        let remote_port = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Spec {
+            protocol,
+            ipv6,
+            local_port,
+            host,
+            remote_port,
+        })
+    })();
+    Some(spec.ok_or_else(bad_path))
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Spec<'_> {
+    fn remote_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<_> = (self.host, self.remote_port)
+            .to_socket_addrs()?
+            .filter(|a| self.ipv6.is_none() || self.ipv6 == Some(a.is_ipv6()))
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no suitable address for host '{}'", self.host),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    fn local_addr(&self, ipv6: bool) -> SocketAddr {
+        let ip = if ipv6 {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+        SocketAddr::new(ip, self.local_port)
+    }
+
+    fn connect(&self) -> io::Result<Socket> {
+        match self.protocol {
+            Protocol::Tcp if self.remote_port == 0 => {
+                let listener = TcpListener::bind(self.local_addr(self.ipv6 == Some(true)))?;
+                Ok(Socket::Tcp(listener.accept()?.0))
+            }
+            Protocol::Tcp if self.local_port != 0 => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TCP connections cannot choose their local port; use 0 instead",
+            )),
+            Protocol::Tcp => Ok(Socket::Tcp(TcpStream::connect(&self.remote_addrs()?[..])?)),
+            Protocol::Udp if self.remote_port == 0 => {
+                // Reply to whoever sends us the first datagram.
+                let sock = UdpSocket::bind(self.local_addr(self.ipv6 == Some(true)))?;
+                let (_, peer) = sock.peek_from(&mut [0])?;
+                sock.connect(peer)?;
+                Ok(Socket::Udp(sock))
+            }
+            Protocol::Udp => {
+                let remote = self.remote_addrs()?;
+                let sock = UdpSocket::bind(self.local_addr(remote[0].is_ipv6()))?;
+                sock.connect(remote[0])?;
+                Ok(Socket::Udp(sock))
+            }
+        }
+    }
+}
+
+struct Entry {
+    sock: Arc<Socket>,
+    // Cleared once the output side of a TCP connection has been closed.
+    writable: bool,
+}
+
+lazy_static::lazy_static! {
+    // The connections that are open, by file name. A TCP connection stays here after its output
+    // side is closed, so that the response can still be read; it is removed once its input side
+    // is closed. UDP sockets stay open until frawk exits.
+    static ref OPEN: Mutex<HashMap<String, Entry>> = Default::default();
+}
+
+/// A connection opened for a network special file.
+pub struct Connection {
+    sock: Arc<Socket>,
+    path: String,
+    write: bool,
+    // Set once a UDP datagram has been read, after which reads report the end of the file.
+    received: bool,
+}
+
+fn open(path: &str, write: bool) -> Option<io::Result<Connection>> {
+    let spec = match parse(path)? {
+        Ok(spec) => spec,
+        Err(e) => return Some(Err(e)),
+    };
+    let mut open = OPEN.lock().unwrap();
+    let sock = match open.get(path) {
+        Some(entry) if entry.writable || !write => entry.sock.clone(),
+        _ => match spec.connect() {
+            Ok(sock) => {
+                let sock = Arc::new(sock);
+                let entry = Entry {
+                    sock: sock.clone(),
+                    writable: true,
+                };
+                open.insert(path.into(), entry);
+                sock
+            }
+            Err(e) => return Some(Err(e)),
+        },
+    };
+    Some(Ok(Connection {
+        sock,
+        path: path.into(),
+        write,
+        received: false,
+    }))
+}
+
+/// Open `path` for reading if it is a network special file.
+pub fn open_read(path: &str) -> Option<io::Result<Connection>> {
+    open(path, false)
+}
+
+/// Open `path` for writing if it is a network special file.
+pub fn open_write(path: &str) -> Option<io::Result<Connection>> {
+    open(path, true)
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &*self.sock {
+            Socket::Tcp(s) => (&*s).read(buf),
+            Socket::Udp(_) if self.received => Ok(0),
+            Socket::Udp(s) => {
+                self.received = true;
+                s.recv(buf)
+            }
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &*self.sock {
+            Socket::Tcp(s) => (&*s).write(buf),
+            Socket::Udp(s) => s.send(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let s = match &*self.sock {
+            Socket::Tcp(s) => s,
+            Socket::Udp(_) => return,
+        };
+        let mut open = OPEN.lock().unwrap();
+        let entry = match open.get_mut(&self.path) {
+            Some(entry) if Arc::ptr_eq(&entry.sock, &self.sock) => entry,
+            _ => return,
+        };
+        if self.write {
+            let _ = s.shutdown(Shutdown::Write);
+            entry.writable = false;
+        } else {
+            open.remove(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_paths() {
+        assert_eq!(parse("/dev/stdout").map(|r| r.is_ok()), None);
+        assert_eq!(parse("inet/tcp/0/localhost/80").map(|r| r.is_ok()), None);
+        assert_eq!(
+            parse("/inet/tcp/0/localhost/80").unwrap().unwrap(),
+            Spec {
+                protocol: Protocol::Tcp,
+                ipv6: None,
+                local_port: 0,
+                host: "localhost",
+                remote_port: 80,
+            }
+        );
+        assert_eq!(
+            parse("/inet6/udp/514/::1/0").unwrap().unwrap(),
+            Spec {
+                protocol: Protocol::Udp,
+                ipv6: Some(true),
+                local_port: 514,
+                host: "::1",
+                remote_port: 0,
+            }
+        );
+        for bad in [
+            "/inet/sctp/0/localhost/80",
+            "/inet/tcp/0/localhost",
+            "/inet/tcp/0/localhost/http",
+            "/inet/tcp/0/localhost/80/x",
+        ] {
+            assert!(parse(bad).unwrap().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn shared_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!(
+            "/inet/tcp/0/127.0.0.1/{}",
+            listener.local_addr().unwrap().port()
+        );
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut req = String::new();
+            conn.read_to_string(&mut req).unwrap();
+            conn.write_all(req.to_uppercase().as_bytes()).unwrap();
+        });
+        let mut w = open_write(&path).unwrap().unwrap();
+        w.write_all(b"hello\n").unwrap();
+        drop(w);
+        // The output side is closed before the input side is opened, as with `close` in a script.
+        let mut r = open_read(&path).unwrap().unwrap();
+        let mut resp = String::new();
+        r.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "HELLO\n");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Joining the values of an array into one string, for the `join` builtin.
+//!
+//! By default `join` is the inverse of `split`: it joins the values at keys 1 through the length
+//! of the array, in order. Keys that are missing in that range contribute an empty string, and are
+//! not inserted. With its third argument set, `join` instead joins every value in the order that a
+//! `for (k in m)` loop visits their keys.
+//!
+//! The values are gathered first and then copied into a buffer of exactly the right size, so
+//! joining an array takes time linear in the length of the result, where building the same string
+//! with repeated concatenation is quadratic.
+use super::{convert, Float, Int, IterKey, KeyOrder, MapKey, SharedMap, Str};
+
+/// Map keys that can name a position in an array, like those `split` assigns to.
+pub(crate) trait JoinKey {
+    fn from_position(i: Int) -> Self;
+}
+
+impl JoinKey for Int {
+    fn from_position(i: Int) -> Int {
+        i
+    }
+}
+
+impl<'a> JoinKey for Str<'a> {
+    fn from_position(i: Int) -> Str<'a> {
+        convert(i)
+    }
+}
+
+/// Map values, converted to strings as they are when read as strings elsewhere.
+pub(crate) trait JoinValue<'a> {
+    fn join_str(&self) -> Str<'a>;
+}
+
+impl<'a> JoinValue<'a> for Int {
+    fn join_str(&self) -> Str<'a> {
+        convert(*self)
+    }
+}
+
+impl<'a> JoinValue<'a> for Float {
+    fn join_str(&self) -> Str<'a> {
+        convert(*self)
+    }
+}
+
+impl<'a> JoinValue<'a> for Str<'a> {
+    fn join_str(&self) -> Str<'a> {
+        self.clone()
+    }
+}
+
+impl<'a, K: MapKey + IterKey + JoinKey, V: JoinValue<'a>> SharedMap<K, V> {
+    /// Join the values at keys 1 through `len()`, or all values in the key order `order` if `all`
+    /// is set (see `to_vec`), separated by `sep`.
+    pub(crate) fn join(
+        &self,
+        sep: &Str<'a>,
+        all: bool,
+        order: KeyOrder,
+        ignore_case: bool,
+    ) -> Str<'a> {
+        let keys: Vec<K> = if all {
+            self.to_vec(order, ignore_case)
+        } else {
+            (1..=self.len() as Int).map(K::from_position).collect()
+        };
+        let table = self.0.borrow();
+        let vals: Vec<Str<'a>> = keys
+            .iter()
+            .map(|k| table.get(k).map_or_else(Str::default, V::join_str))
+            .collect();
+        sep.join_slice(&vals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join<'a, K: MapKey + IterKey + JoinKey, V: JoinValue<'a>>(
+        map: &SharedMap<K, V>,
+        sep: &'a str,
+        all: bool,
+    ) -> String {
+        map.join(&Str::from(sep), all, KeyOrder::Unordered, false)
+            .to_string()
+    }
+
+    #[test]
+    fn positions() {
+        let map: SharedMap<Int, Str> = vec![(1, "a".into()), (2, "b".into()), (3, "c".into())]
+            .into_iter()
+            .collect();
+        assert_eq!(join(&map, ", ", false), "a, b, c");
+        let empty: SharedMap<Int, Str> = Default::default();
+        assert_eq!(join(&empty, ", ", false), "");
+    }
+
+    #[test]
+    fn gaps_are_empty() {
+        let map: SharedMap<Int, Int> = vec![(1, 1), (3, 3), (7, 7)].into_iter().collect();
+        assert_eq!(join(&map, "-", false), "1--3");
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn string_keys() {
+        let map: SharedMap<Str, Float> = vec![("2".into(), 2.5), ("1".into(), 1.0)]
+            .into_iter()
+            .collect();
+        assert_eq!(join(&map, " ", false), "1 2.5");
+    }
+
+    #[test]
+    fn all_values() {
+        let map: SharedMap<Int, Int> = vec![(5, 5), (-1, -1)].into_iter().collect();
+        let mut vals: Vec<String> = join(&map, ",", true).split(',').map(String::from).collect();
+        vals.sort();
+        assert_eq!(vals, vec!["-1", "5"]);
+        let sep = Str::from(",");
+        assert_eq!(
+            map.join(&sep, true, KeyOrder::NumAsc, false).to_string(),
+            "-1,5"
+        );
+        assert_eq!(
+            map.join(&sep, true, KeyOrder::StrDesc, false).to_string(),
+            "5,-1"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Formatting values as JSON, for the `to_json` and `join_json` builtins.
+//!
+//! Strings become JSON strings, and numbers become JSON numbers (or `null`, for infinities and
+//! NaN). Arrays with integer keys running from 1 up to their length, like those produced by
+//! `split`, become JSON arrays (as do empty arrays with integer keys); all other arrays become
+//! objects. Object keys are written in sorted order, so the same array always produces the same
+//! output.
+use super::{str_impl::Buf, Float, Int, MapKey, SharedMap, Str};
+
+pub(crate) trait ToJson {
+    fn write_json(&self, buf: &mut Vec<u8>);
+    /// Write this value as the key of a JSON object, which must be a string.
+    fn write_key(&self, buf: &mut Vec<u8>);
+    /// Whether arrays with keys of this type can be written as JSON arrays.
+    const INDEXED: bool = false;
+    /// The position this key would have in a JSON array, if it is an integer.
+    fn array_index(&self) -> Option<Int> {
+        None
+    }
+}
+
+impl ToJson for Int {
+    fn write_json(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(itoa::Buffer::new().format(*self).as_bytes())
+    }
+    fn write_key(&self, buf: &mut Vec<u8>) {
+        buf.push(b'"');
+        self.write_json(buf);
+        buf.push(b'"');
+    }
+    const INDEXED: bool = true;
+    fn array_index(&self) -> Option<Int> {
+        Some(*self)
+    }
+}
+
+impl ToJson for Float {
+    fn write_json(&self, buf: &mut Vec<u8>) {
+        if self.is_finite() {
+            Str::from(*self).with_bytes(|bs| buf.extend_from_slice(bs))
+        } else {
+            buf.extend_from_slice(b"null")
+        }
+    }
+    fn write_key(&self, buf: &mut Vec<u8>) {
+        buf.push(b'"');
+        Str::from(*self).with_bytes(|bs| buf.extend_from_slice(bs));
+        buf.push(b'"');
+    }
+}
+
+impl<'a> ToJson for Str<'a> {
+    fn write_json(&self, buf: &mut Vec<u8>) {
+        self.with_bytes(|bs| write_string(bs, buf))
+    }
+    fn write_key(&self, buf: &mut Vec<u8>) {
+        self.write_json(buf)
+    }
+}
+
+/// Write `bs` as a quoted JSON string. JSON text has to be valid UTF-8, so any invalid sequences
+/// in `bs` are replaced with U+FFFD.
+fn write_string(bs: &[u8], buf: &mut Vec<u8>) {
+    buf.push(b'"');
+    for b in String::from_utf8_lossy(bs).bytes() {
+        match b {
+            b'"' => buf.extend_from_slice(b"\\\""),
+            b'\\' => buf.extend_from_slice(b"\\\\"),
+            b'\n' => buf.extend_from_slice(b"\\n"),
+            b'\r' => buf.extend_from_slice(b"\\r"),
+            b'\t' => buf.extend_from_slice(b"\\t"),
+            0..=0x1f | 0x7f => {
+                const HEX: &[u8; 16] = b"0123456789abcdef";
+                buf.extend_from_slice(b"\\u00");
+                buf.push(HEX[(b >> 4) as usize]);
+                buf.push(HEX[(b & 0xf) as usize]);
+            }
+            b => buf.push(b),
+        }
+    }
+    buf.push(b'"');
+}
+
+fn into_str<'a>(buf: Vec<u8>) -> Str<'a> {
+    Buf::read_from_bytes(&buf).into_str()
+}
+
+/// Escape `s` as a JSON string, surrounding quotes included.
+pub fn escape_json<'a>(s: &Str<'a>) -> Str<'a> {
+    let mut buf = Vec::with_capacity(s.len() + 2);
+    s.write_json(&mut buf);
+    into_str(buf)
+}
+
+/// Format `f` as a JSON number.
+pub fn float_to_json<'a>(f: Float) -> Str<'a> {
+    let mut buf = Vec::new();
+    f.write_json(&mut buf);
+    into_str(buf)
+}
+
+/// Surround `elts`, a comma-separated list of JSON values, with brackets.
+pub fn json_array<'a>(elts: &Str<'a>) -> Str<'a> {
+    let mut buf = Vec::with_capacity(elts.len() + 2);
+    buf.push(b'[');
+    elts.with_bytes(|bs| buf.extend_from_slice(bs));
+    buf.push(b']');
+    into_str(buf)
+}
+
+impl<K: MapKey + ToJson + Ord, V: ToJson + Clone> SharedMap<K, V> {
+    pub(crate) fn to_json<'a>(&self) -> Str<'a> {
+        let mut entries: Vec<(K, V)> =
+            self.iter(|entries| entries.map(|(k, v)| (k, v.clone())).collect());
+        entries.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        let is_array = K::INDEXED
+            && (entries.iter().enumerate())
+                .all(|(i, (k, _))| k.array_index() == Some(i as Int + 1));
+        let mut buf = vec![if is_array { b'[' } else { b'{' }];
+        for (i, (k, v)) in entries.iter().enumerate() {
+            if i > 0 {
+                buf.push(b',');
+            }
+            if !is_array {
+                k.write_key(&mut buf);
+                buf.push(b':');
+            }
+            v.write_json(&mut buf);
+        }
+        buf.push(if is_array { b']' } else { b'}' });
+        into_str(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_strings() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"plain", r#""plain""#),
+            (b"", r#""""#),
+            (b"say \"hi\"\\", r#""say \"hi\"\\""#),
+            (b"a\tb\nc\x01\x7f", r#""a\tb\nc\u0001\u007f""#),
+            ("é😀".as_bytes(), "\"é😀\""),
+            (b"bad \xff byte", "\"bad \u{fffd} byte\""),
+        ];
+        for (input, expected) in cases {
+            let s: Str = Buf::read_from_bytes(input).into_str();
+            assert_eq!(escape_json(&s).to_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn arrays() {
+        let m = SharedMap::<Int, Str>::default();
+        assert_eq!(m.to_json().to_string(), "[]");
+        m.insert(2, "b".into());
+        m.insert(1, "a".into());
+        assert_eq!(m.to_json().to_string(), r#"["a","b"]"#);
+        m.insert(10, "c".into());
+        assert_eq!(m.to_json().to_string(), r#"{"1":"a","2":"b","10":"c"}"#);
+
+        let m = SharedMap::<Str, Float>::default();
+        assert_eq!(m.to_json().to_string(), "{}");
+        m.insert("y".into(), 0.5);
+        m.insert("x".into(), 3.0);
+        m.insert("z".into(), Float::NAN);
+        assert_eq!(m.to_json().to_string(), r#"{"x":3,"y":0.5,"z":null}"#);
+    }
+}
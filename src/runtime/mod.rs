@@ -1,22 +1,33 @@
-use crate::common::{FileSpec, Result};
-use grep_cli::CommandReader;
-use hashbrown::HashMap;
-use regex::bytes::Regex;
+use crate::common::{FileSpec, OutputOrder, Result};
+use hashbrown::{
+    hash_map::{DefaultHashBuilder, RawEntryMut},
+    HashMap,
+};
+use regex::bytes::{Regex, RegexBuilder, RegexSet};
 use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::fs::File;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash};
 use std::io;
 use std::iter::FromIterator;
-use std::mem;
 use std::rc::Rc;
 use std::str;
+use std::sync::atomic::{self, AtomicBool, AtomicI32, AtomicUsize};
+use std::sync::Arc;
 
 mod command;
+pub mod compress;
+mod ere;
 pub mod float_parse;
+mod inet;
+mod join;
+mod json;
+mod persist;
 pub mod printf;
 pub mod splitter;
 pub mod str_impl;
 pub mod string_search;
+mod table;
 pub mod utf8;
 pub mod writers;
 
@@ -26,25 +37,164 @@ use splitter::regex::RegexSplitter;
 // TODO: remove the pub use for Variables here.
 pub(crate) use crate::builtins::Variables;
 pub use command::run_command;
-pub(crate) use float_parse::{hextoi, strtod, strtoi};
+use command::CommandReader;
+pub(crate) use float_parse::{hextoi, strtod, strtod_exact, strtoi};
+pub use json::{escape_json, float_to_json, json_array};
 pub(crate) use printf::FormatArg;
 pub use splitter::{
     batch::{escape_csv, escape_tsv},
     ChainedReader, Line, LineReader,
 };
+use str_impl::{FieldSep, SplitPos};
 pub use str_impl::{Str, UniqueStr};
+pub(crate) use table::MapKey;
+use table::Table;
+
+/// Translate the awk pattern `pat` into a source string for the regex crate.
+///
+/// See the [`ere`] module for the differences between the two syntaxes. Patterns that are not
+/// valid UTF-8 (e.g. ones built from binary input) are matched byte by byte: Unicode support is
+/// turned off and each non-ASCII byte is escaped so that it matches only itself. The same goes
+/// for every pattern after a call to `set_byte_regexes`.
+pub(crate) fn regex_source(pat: &[u8]) -> String {
+    ere::translate(pat, BYTE_REGEXES.load(atomic::Ordering::Relaxed))
+}
 
-#[derive(Default)]
-pub struct RegexCache(Registry<Regex>);
+static BYTE_REGEXES: AtomicBool = AtomicBool::new(false);
+
+/// Match regular expressions compiled from now on against bytes rather than UTF-8 characters, so
+/// that `.` matches any single byte, even one that is not part of valid UTF-8.
+pub fn set_byte_regexes(bytes: bool) {
+    BYTE_REGEXES.store(bytes, atomic::Ordering::Relaxed)
+}
+
+/// Divide `l` by `r`. Dividing by zero is an error, unless `ieee` is set, in which case it produces
+/// an infinity or NaN as IEEE 754 does.
+pub(crate) fn div(l: Float, r: Float, ieee: bool) -> Result<Float> {
+    if r == 0.0 && !ieee {
+        return err!("division by zero attempted");
+    }
+    Ok(l / r)
+}
+
+/// The remainder of `l` divided by `r`, which is an error for a zero `r` as it is in `div`.
+pub(crate) fn mod_float(l: Float, r: Float, ieee: bool) -> Result<Float> {
+    if r == 0.0 && !ieee {
+        return err!("division by zero attempted in `%'");
+    }
+    Ok(l % r)
+}
+
+/// The remainder of `l` divided by `r`, which is an error for a zero `r` regardless of IEEE
+/// division.
+pub(crate) fn mod_int(l: Int, r: Int) -> Result<Int> {
+    if r == 0 {
+        return err!("division by zero attempted in `%'");
+    }
+    // Int::MIN % -1 overflows, though the remainder is 0.
+    Ok(l.wrapping_rem(r))
+}
+
+/// The status that frawk exits with after writing to a closed pipe: the status that shells report
+/// for a process killed by SIGPIPE.
+pub const BROKEN_PIPE_STATUS: i32 = 141;
+
+/// The status that frawk exits with after an input file could not be read or output could not be
+/// written.
+pub const IO_ERROR_STATUS: i32 = 2;
+
+static IO_ERROR: AtomicI32 = AtomicI32::new(0);
+
+/// Record an I/O error that the program carries on from (by skipping an input file) or that is
+/// reported later (when output is flushed), so that frawk still exits with a nonzero status. Only
+/// the first error is kept.
+pub(crate) fn record_io_error(kind: io::ErrorKind) {
+    let status = if kind == io::ErrorKind::BrokenPipe {
+        BROKEN_PIPE_STATUS
+    } else {
+        IO_ERROR_STATUS
+    };
+    let _ = IO_ERROR.compare_exchange(
+        0,
+        status,
+        atomic::Ordering::AcqRel,
+        atomic::Ordering::Acquire,
+    );
+}
+
+/// Whether output failed because it went to a closed pipe. This is not reported as an error, as it
+/// is what happens when frawk's output is piped to a command like `head` that exits early.
+pub(crate) fn output_pipe_closed() -> bool {
+    IO_ERROR.load(atomic::Ordering::Acquire) == BROKEN_PIPE_STATUS
+}
+
+/// The status frawk should exit with after the program exits with status `code`: the status for
+/// the first I/O error recorded with `record_io_error`, if there was one, and `code` otherwise.
+pub fn exit_status(code: i32) -> i32 {
+    match IO_ERROR.load(atomic::Ordering::Acquire) {
+        0 => code,
+        status => status,
+    }
+}
+
+/// If `pat` is a regex matching a single byte (say, "," or "\t"), return that byte. These are by far
+/// the most common field separators, and are much cheaper to split on with memchr.
+pub(crate) fn literal_byte(pat: &[u8]) -> Option<u8> {
+    match pat {
+        [b] if b.is_ascii() && !br".\+*?()|[]{}^$#&-~ ".contains(b) => Some(*b),
+        _ => None,
+    }
+}
+
+// The most dynamic regexes we keep compiled at once. Scripts that build patterns from their input
+// may use an unbounded number of them, while the patterns used on every record stay cached.
+const MAX_CACHED_REGEXES: usize = 1 << 10;
+
+pub struct RegexCache {
+    regexes: Registry<Regex>,
+    // The same patterns, compiled to match without regard to case while IGNORECASE is set.
+    folded: Registry<Regex>,
+    ignore_case: bool,
+}
+
+impl Default for RegexCache {
+    fn default() -> Self {
+        RegexCache {
+            regexes: Registry::with_capacity(MAX_CACHED_REGEXES),
+            folded: Registry::with_capacity(MAX_CACHED_REGEXES),
+            ignore_case: false,
+        }
+    }
+}
+
+fn compile_regex(pat: &[u8], ignore_case: bool) -> Result<Regex> {
+    match RegexBuilder::new(&regex_source(pat))
+        .case_insensitive(ignore_case)
+        .build()
+    {
+        Ok(r) => Ok(r),
+        Err(e) => err!("{}", e),
+    }
+}
 
 impl RegexCache {
+    /// Compile patterns to match without regard to case from now on, as they should while
+    /// `IGNORECASE` is nonzero.
+    pub(crate) fn set_ignore_case(&mut self, ignore_case: bool) {
+        self.ignore_case = ignore_case;
+    }
+    fn cache(&mut self) -> &mut Registry<Regex> {
+        if self.ignore_case {
+            &mut self.folded
+        } else {
+            &mut self.regexes
+        }
+    }
     pub(crate) fn with_regex<T>(&mut self, pat: &Str, mut f: impl FnMut(&Regex) -> T) -> Result<T> {
-        self.0.get(
+        let ignore_case = self.ignore_case;
+        self.cache().get(
             pat,
-            |s| match Regex::new(s) {
-                Ok(r) => Ok(r),
-                Err(e) => err!("{}", e),
-            },
+            |s| compile_regex(s, ignore_case),
             // eta-expansion required to get this compiling..
             |x| f(x),
         )
@@ -54,12 +204,10 @@ impl RegexCache {
         pat: &Str,
         mut f: impl FnMut(&Regex) -> Result<T>,
     ) -> Result<T> {
-        self.0.get_fallible(
+        let ignore_case = self.ignore_case;
+        self.cache().get_fallible(
             pat,
-            |s| match Regex::new(s) {
-                Ok(r) => Ok(r),
-                Err(e) => err!("{}", e),
-            },
+            |s| compile_regex(s, ignore_case),
             // eta-expansion required to get this compiling..
             |x| f(x),
         )
@@ -73,9 +221,11 @@ impl RegexCache {
         is_file: bool,
     ) -> Result<Str<'a>> {
         Ok(if is_file {
+            // Files that cannot be opened read as empty; read_err reports the failure.
             reg.with_file(file, |reader| {
                 self.with_regex(pat, |re| reader.read_line_regex(re))
             })?
+            .unwrap_or_default()
         } else {
             reg.with_cmd(file, |reader| {
                 self.with_regex(pat, |re| reader.read_line_regex(re))
@@ -88,48 +238,76 @@ impl RegexCache {
     // This only gets used if getline is invoked explicitly without an input file argument.
     pub(crate) fn get_line_stdin<'a, LR: LineReader>(
         &mut self,
-        pat: &Str<'a>,
+        vars: &Variables<'a>,
         reg: &mut FileRead<LR>,
     ) -> Result<(/* file changed */ bool, Str<'a>)> {
-        let (changed, mut line) = reg.stdin.read_line(pat, self)?;
-        // NB both of these `pat`s are "wrong" but we are fine because they are only used
-        // when the column is nonzero, or someone has overwritten a nonzero column.
-        Ok((changed, line.get_col(0, pat, pat, self)?.clone().upcast()))
+        let (changed, mut line) = reg.stdin.read_line(&vars.rs, self)?;
+        if changed {
+            reg.skip_headers(&mut line, vars, self)?;
+        }
+        reg.name_columns(vars);
+        Ok((
+            changed,
+            line.get_col(0, &vars.fs, &vars.ofs, self)?.clone().upcast(),
+        ))
     }
-    pub(crate) fn get_line_stdin_reuse<LR: LineReader>(
+    pub(crate) fn get_line_stdin_reuse<'a, LR: LineReader>(
         &mut self,
-        pat: &Str,
+        vars: &Variables<'a>,
         reg: &mut FileRead<LR>,
         old_line: &mut LR::Line,
     ) -> Result</*file changed */ bool> {
-        reg.stdin.read_line_reuse(pat, self, old_line)
+        let changed = reg.stdin.read_line_reuse(&vars.rs, self, old_line)?;
+        if changed {
+            reg.skip_headers(old_line, vars, self)?;
+        }
+        reg.name_columns(vars);
+        Ok(changed)
     }
     fn split_internal<'a>(
         &mut self,
         pat: &Str,
         s: &Str<'a>,
         used_fields: &FieldSet,
+        start: SplitPos,
+        limit: usize,
         mut push: impl FnMut(Str<'a>),
-    ) -> Result<()> {
+    ) -> Result<Option<SplitPos>> {
         if pat == &Str::from(" ") {
-            self.with_regex(&Str::from(r#"[ \t]+"#), |re| {
-                s.split(
-                    re,
-                    |s, is_empty| {
-                        if !is_empty {
-                            push(s);
-                            1
-                        } else {
-                            0
-                        }
-                    },
-                    used_fields,
-                )
-            })
+            Ok(s.split_prefix(
+                FieldSep::Blanks,
+                start,
+                limit,
+                |s, is_empty| {
+                    if !is_empty {
+                        push(s);
+                        1
+                    } else {
+                        0
+                    }
+                },
+                used_fields,
+            ))
+        } else if let Some(sep) = pat
+            .with_bytes(literal_byte)
+            .filter(|b| !(self.ignore_case && b.is_ascii_alphabetic()))
+        {
+            Ok(s.split_prefix(
+                FieldSep::Byte(sep),
+                start,
+                limit,
+                |s, _| {
+                    push(s);
+                    1
+                },
+                used_fields,
+            ))
         } else {
             self.with_regex(pat, |re| {
-                s.split(
-                    re,
+                s.split_prefix(
+                    FieldSep::Regex(re),
+                    start,
+                    limit,
                     |s, _| {
                         push(s);
                         1
@@ -139,14 +317,18 @@ impl RegexCache {
             })
         }
     }
-    pub(crate) fn split_regex<'a>(
+    /// Split `s` from `start` until `v` holds at least `limit` fields, returning where to resume
+    /// splitting if there are more fields left.
+    pub(crate) fn split_regex_prefix<'a>(
         &mut self,
         pat: &Str,
         s: &Str<'a>,
         used_fields: &FieldSet,
+        start: SplitPos,
+        limit: usize,
         v: &mut Vec<Str<'a>>,
-    ) -> Result<()> {
-        self.split_internal(pat, s, used_fields, |s| v.push(s))
+    ) -> Result<Option<SplitPos>> {
+        self.split_internal(pat, s, used_fields, start, limit, |s| v.push(s))
     }
 
     pub(crate) fn split_regex_intmap<'a>(
@@ -158,10 +340,18 @@ impl RegexCache {
         let mut i = 0i64;
         let mut m_b = m.0.borrow_mut();
         m_b.clear();
-        self.split_internal(pat, s, &FieldSet::all(), |s| {
-            i += 1;
-            m_b.insert(i, s);
-        })
+        self.split_internal(
+            pat,
+            s,
+            &FieldSet::all(),
+            SplitPos::default(),
+            usize::MAX,
+            |s| {
+                i += 1;
+                m_b.insert(i, s);
+            },
+        )?;
+        Ok(())
     }
 
     pub(crate) fn split_regex_strmap<'a>(
@@ -173,10 +363,18 @@ impl RegexCache {
         let mut i = 0i64;
         let mut m_b = m.0.borrow_mut();
         m_b.clear();
-        self.split_internal(pat, s, &FieldSet::all(), |s| {
-            i += 1;
-            m_b.insert(convert::<i64, Str<'_>>(i), s);
-        })
+        self.split_internal(
+            pat,
+            s,
+            &FieldSet::all(),
+            SplitPos::default(),
+            usize::MAX,
+            |s| {
+                i += 1;
+                m_b.insert(convert::<i64, Str<'_>>(i), s);
+            },
+        )?;
+        Ok(())
     }
 
     pub(crate) fn regex_const_match_loc(vars: &mut Variables, re: &Regex, s: &Str) -> Result<Int> {
@@ -202,10 +400,61 @@ impl RegexCache {
         self.with_regex_fallible(pat, |re| Self::regex_const_match_loc(vars, re, s))
     }
 
+    /// Like `regex_match_loc`, but also fills `m` with the text of the match (under "0") and of each
+    /// capture group that participated in it, along with their start and length under keys like
+    /// `1 SUBSEP "start"`.
+    pub(crate) fn regex_match_loc_array<'a>(
+        &mut self,
+        vars: &mut Variables,
+        pat: &Str,
+        s: &Str<'a>,
+        m: &StrMap<'a, Str<'a>>,
+        subsep: &Str<'a>,
+    ) -> Result<Int> {
+        use crate::builtins::Variable;
+        let groups: Vec<Option<(usize, usize)>> = self.with_regex(pat, |re| {
+            s.with_bytes(|bs| match re.captures(bs) {
+                Some(caps) => caps
+                    .iter()
+                    .map(|m| m.map(|m| (m.start(), m.end())))
+                    .collect(),
+                None => Vec::new(),
+            })
+        })?;
+        let mut m_b = m.0.borrow_mut();
+        m_b.clear();
+        for (i, group) in groups.iter().enumerate() {
+            if let Some((start, end)) = *group {
+                let key = convert::<i64, Str<'_>>(i as Int);
+                let sub_key = |name: &'static str| {
+                    Str::concat(key.clone(), Str::concat(subsep.clone(), Str::from(name)))
+                };
+                m_b.insert(sub_key("start"), convert::<i64, Str<'_>>(start as Int + 1));
+                m_b.insert(
+                    sub_key("length"),
+                    convert::<i64, Str<'_>>((end - start) as Int),
+                );
+                m_b.insert(key, s.slice(start, end));
+            }
+        }
+        let (start, len) = match groups.first() {
+            Some(Some((start, end))) => (*start as Int + 1, (end - start) as Int),
+            _ => (0, -1),
+        };
+        vars.store_int(Variable::RSTART, start)?;
+        vars.store_int(Variable::RLENGTH, len)?;
+        Ok(start)
+    }
+
     pub(crate) fn regex_const_match(pat: &Regex, s: &Str) -> bool {
         s.with_bytes(|bs| pat.is_match(bs))
     }
 
+    /// Match `s` against every pattern in `set`, setting bit i of the result if pattern i matches.
+    pub(crate) fn regex_set_matches(set: &RegexSet, s: &Str) -> Int {
+        s.with_bytes(|bs| set.matches(bs).iter().fold(0, |acc, i| acc | (1 << i)))
+    }
+
     pub(crate) fn is_regex_match(&mut self, pat: &Str, s: &Str) -> Result<bool> {
         self.with_regex(pat, |re| Self::regex_const_match(re, s))
     }
@@ -216,7 +465,7 @@ pub(crate) struct FileWrite(writers::Registry);
 
 impl Default for FileWrite {
     fn default() -> FileWrite {
-        FileWrite::new(writers::default_factory())
+        FileWrite::new(writers::default_factory(None))
     }
 }
 
@@ -224,12 +473,33 @@ impl FileWrite {
     pub(crate) fn flush_stdout(&mut self) -> Result<()> {
         self.0.get_file(None)?.flush()
     }
-    pub(crate) fn close(&mut self, path: &Str) -> Result<()> {
+    pub(crate) fn close(&mut self, path: &Str) -> Result<Int> {
         self.0.close(path)
     }
+    pub(crate) fn flush(&mut self, path: &Str) -> Result<Int> {
+        self.0.flush(path)
+    }
+    pub(crate) fn flush_all(&mut self) -> Result<()> {
+        self.0.flush_all()
+    }
     pub(crate) fn new(ff: impl writers::FileFactory) -> FileWrite {
         FileWrite(writers::Registry::from_factory(ff))
     }
+    pub(crate) fn set_output_order(&mut self, order: OutputOrder) {
+        self.0.set_output_order(order)
+    }
+    pub(crate) fn start_output_by_file(&mut self) {
+        self.0.start_output_by_file()
+    }
+    pub(crate) fn start_input_file(&mut self, index: u32) -> Result<()> {
+        self.0.start_input_file(index)
+    }
+    pub(crate) fn end_input_file(&mut self) -> Result<()> {
+        self.0.end_input_file()
+    }
+    pub(crate) fn finish_output_by_file(&mut self) -> Result<()> {
+        self.0.finish_output_by_file()
+    }
 
     pub(crate) fn shutdown(&mut self) -> Result<()> {
         self.0.destroy_and_flush_all_files()
@@ -273,16 +543,79 @@ pub const CHUNK_SIZE: usize = 8 << 10;
 
 #[derive(Default)]
 pub(crate) struct Inputs {
-    files: Registry<RegexSplitter<File>>,
+    files: Registry<RegexSplitter<InputFile>>,
     commands: Registry<RegexSplitter<CommandReader>>,
+    // The number of entries in `files` with an open file, and a counter used to find the least
+    // recently used of them.
+    open_files: usize,
+    clock: u64,
+}
+
+/// A file read with `getline`.
+///
+/// Input files can be closed while they are not being read, to stay under the limit on open
+/// files, after which they are reopened where they left off.
+pub(crate) struct InputFile {
+    path: String,
+    inner: Option<Box<dyn io::Read + Send>>,
+    // The number of bytes read so far.
+    pos: u64,
+    last_used: u64,
+    // Whether the file can be closed and reopened; false for pipes, devices and network files.
+    reopenable: bool,
+}
+
+/// Opens the files read with `getline` in place of the local file system.
+pub(crate) type InputOpener =
+    Arc<dyn Fn(&str) -> io::Result<Box<dyn io::Read + Send>> + Send + Sync>;
+
+impl InputFile {
+    fn open(path: &str, opener: Option<&InputOpener>) -> io::Result<InputFile> {
+        let (inner, reopenable) = if let Some(opener) = opener {
+            // We cannot tell where a file from `opener` would pick up if it were opened again.
+            (opener(path)?, false)
+        } else if let Some(conn) = inet::open_read(path) {
+            (Box::new(conn?) as Box<dyn io::Read + Send>, false)
+        } else {
+            let file = File::open(path)?;
+            let regular = file.metadata().is_ok_and(|m| m.is_file());
+            (compress::decompress(path, file)?, regular)
+        };
+        Ok(InputFile {
+            path: path.into(),
+            inner: Some(inner),
+            pos: 0,
+            last_used: 0,
+            reopenable,
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        self.inner.is_some()
+    }
+}
+
+impl io::Read for InputFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.inner.is_none() {
+            self.inner = Some(compress::open_at(&self.path, self.pos)?);
+        }
+        let n = self.inner.as_mut().unwrap().read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
 }
 
 pub(crate) struct FileRead<LR = RegexSplitter<Box<dyn io::Read + Send>>> {
     pub(crate) inputs: Inputs,
+    opener: Option<InputOpener>,
     stdin: LR,
     named_columns: Option<Vec<Str<'static>>>,
     used_fields: FieldSet,
     backup_used_fields: FieldSet,
+    // Set once the header of the first input file has been read with -H, after which the first
+    // line of every other input file is a header as well.
+    headers: bool,
 }
 
 impl<LR: LineReader> FileRead<LR> {
@@ -292,14 +625,17 @@ impl<LR: LineReader> FileRead<LR> {
             .into_iter()
             .map(|x| {
                 let fields = self.used_fields.clone();
+                let opener = self.opener.clone();
                 move || {
                     let stdin = x();
                     if stdin.wait() {
                         Some(FileRead {
                             inputs: Default::default(),
+                            opener,
                             named_columns: None,
                             used_fields: fields.clone(),
                             backup_used_fields: fields,
+                            headers: false,
                             stdin,
                         })
                     } else {
@@ -310,9 +646,15 @@ impl<LR: LineReader> FileRead<LR> {
             .collect()
     }
 
-    pub(crate) fn close(&mut self, path: &Str) {
-        self.inputs.files.remove(path);
-        self.inputs.commands.remove(path);
+    /// Stop reading from `path`, returning None if it was not open for reading. Otherwise returns
+    /// 0 for an input file, or the exit code of a command.
+    pub(crate) fn close(&mut self, path: &Str) -> Option<Int> {
+        let file = self.inputs.files.remove(path).map(|mut r| {
+            self.inputs.open_files -= r.get_mut().is_open() as usize;
+            0
+        });
+        let cmd = self.inputs.commands.remove(path);
+        cmd.map(|c| c.into_inner().close()).or(file)
     }
 
     pub(crate) fn new(
@@ -331,17 +673,28 @@ impl<LR: LineReader> FileRead<LR> {
         };
         let mut res = FileRead {
             inputs: Default::default(),
+            opener: None,
             stdin,
             used_fields,
             backup_used_fields,
             named_columns: named_columns
                 .map(|cs| cs.into_iter().map(|s| Str::from(s).unmoor()).collect()),
+            headers: false,
         };
         res.stdin.set_used_fields(&res.used_fields);
         res
     }
 
+    /// Open the files read with `getline` using `opener`.
+    pub(crate) fn set_opener(&mut self, opener: InputOpener) {
+        self.opener = Some(opener);
+    }
+
     pub(crate) fn update_named_columns<'a>(&mut self, fi: &StrMap<'a, Int>) {
+        if !self.headers {
+            self.headers = true;
+            self.stdin.split_headers();
+        }
         let referenced_fi = self.backup_used_fields.has_fi();
         let have_columns = self.named_columns.is_some();
 
@@ -352,7 +705,7 @@ impl<LR: LineReader> FileRead<LR> {
         }
 
         // Switch back to the original used-field set.
-        mem::swap(&mut self.used_fields, &mut self.backup_used_fields);
+        self.used_fields = self.backup_used_fields.clone();
 
         // We didn't use FI to reference columns, perhaps just using -H to trim the header.
         //
@@ -373,16 +726,51 @@ impl<LR: LineReader> FileRead<LR> {
         self.stdin.set_used_fields(&self.used_fields)
     }
 
+    /// Called with `line`, the first line of a new input file. If that line is a header, rebuild
+    /// FI from its columns and replace it with the line after it.
+    fn skip_headers<'a>(
+        &mut self,
+        line: &mut LR::Line,
+        vars: &Variables<'a>,
+        rc: &mut RegexCache,
+    ) -> Result<()> {
+        // The loop skips past files containing nothing but a header.
+        let mut changed = true;
+        while changed && self.headers {
+            let fi = &vars.fi;
+            fi.clear();
+            let nf = line.nf(&vars.fs, rc)?;
+            for i in 1..=nf as Int {
+                fi.insert(line.get_col(i, &vars.fs, &vars.ofs, rc)?, i);
+            }
+            self.update_named_columns(fi);
+            changed = self.stdin.read_line_reuse(&vars.rs, rc, line)?;
+        }
+        Ok(())
+    }
+
+    /// Add any columns the input names itself (e.g. the keys of JSON records) to FI.
+    fn name_columns<'a>(&mut self, vars: &Variables<'a>) {
+        self.stdin
+            .new_columns(|name, col| vars.fi.insert(name.upcast(), col));
+    }
+
     pub(crate) fn stdin_filename(&self) -> Str<'static> {
         self.stdin.filename()
     }
 
+    pub(crate) fn stdin_file_index(&self) -> u32 {
+        self.stdin.file_index()
+    }
+
     pub(crate) fn read_err_stdin(&mut self) -> Int {
         self.stdin.read_state()
     }
 
     pub(crate) fn read_err(&mut self, path: &Str) -> Result<Int> {
-        self.with_file(path, |reader| Ok(reader.read_state()))
+        Ok(self
+            .with_file(path, |reader| Ok(reader.read_state()))?
+            .unwrap_or(splitter::ReaderState::Error as Int))
     }
     pub(crate) fn read_err_cmd(&mut self, cmd: &Str) -> Result<Int> {
         self.with_cmd(cmd, |reader| Ok(reader.read_state()))
@@ -401,7 +789,7 @@ impl<LR: LineReader> FileRead<LR> {
         let check_utf8 = self.stdin.check_utf8();
         self.inputs.commands.get_fallible(
             cmd,
-            |s| match command::command_for_read(s.as_bytes()) {
+            |s| match command::command_for_read(s) {
                 Ok(r) => Ok(RegexSplitter::new(
                     r,
                     CHUNK_SIZE,
@@ -414,53 +802,126 @@ impl<LR: LineReader> FileRead<LR> {
         )
     }
 
+    /// Call `f` on the reader for `path`, opening it if need be. Returns None if the file cannot
+    /// be opened.
+    ///
+    /// If this leaves too many files open, the least recently used ones are closed.
     fn with_file<R>(
         &mut self,
         path: &Str,
-        f: impl FnMut(&mut RegexSplitter<File>) -> Result<R>,
-    ) -> Result<R> {
+        mut f: impl FnMut(&mut RegexSplitter<InputFile>) -> Result<R>,
+    ) -> Result<Option<R>> {
         let check_utf8 = self.stdin.check_utf8();
-        self.inputs.files.get_fallible(
+        let mut open_failed = false;
+        let opener = self.opener.as_ref();
+        let inputs = &mut self.inputs;
+        inputs.clock += 1;
+        let now = inputs.clock;
+        let opened = Cell::new(0);
+        let res = inputs.files.get_fallible(
             path,
-            |s| match File::open(s) {
-                Ok(f) => Ok(RegexSplitter::new(
-                    f,
-                    CHUNK_SIZE,
-                    path.clone().unmoor(),
-                    check_utf8,
-                )),
-                Err(e) => err!("failed to open file '{}': {}", s, e),
+            |s| {
+                let s = match str::from_utf8(s) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        open_failed = true;
+                        return err!("invalid UTF-8 for file name: {}", e);
+                    }
+                };
+                match InputFile::open(s, opener) {
+                    Ok(f) => {
+                        opened.set(1);
+                        Ok(RegexSplitter::new(
+                            f,
+                            CHUNK_SIZE,
+                            path.clone().unmoor(),
+                            check_utf8,
+                        ))
+                    }
+                    Err(e) => {
+                        open_failed = true;
+                        err!("failed to open file '{}': {}", s, e)
+                    }
+                }
             },
-            f,
-        )
+            |reader| {
+                let was_open = reader.get_mut().is_open();
+                reader.get_mut().last_used = now;
+                let res = f(reader);
+                if !was_open && reader.get_mut().is_open() {
+                    opened.set(1);
+                }
+                res
+            },
+        );
+        inputs.open_files += opened.get();
+        while inputs.open_files > max_open_files() {
+            let lru = inputs
+                .files
+                .values_mut()
+                .map(RegexSplitter::get_mut)
+                .filter(|f| f.is_open() && f.reopenable && f.last_used != now)
+                .min_by_key(|f| f.last_used);
+            match lru {
+                Some(file) => {
+                    file.inner = None;
+                    inputs.open_files -= 1;
+                }
+                None => break,
+            }
+        }
+        match res {
+            Err(_) if open_failed => Ok(None),
+            res => res.map(Some),
+        }
     }
 }
 
 pub(crate) struct Registry<T> {
-    // TODO(ezr): use the raw bucket interface so we can avoid calls to `unmoor` here.
-    // TODO(ezr): we could potentially increase speed here if we did pointer equality (and
-    // length) for lookups.
-    // We could be fine having duplicates for Regex. We could also also intern strings
-    // as we go by swapping out one Rc for another as we encounter them. That would keep the
-    // fast path fast, but we would have to make sure we weren't keeping any Refs alive.
-    cached: HashMap<Str<'static>, T>,
+    // TODO(ezr): we could also intern strings as we go by swapping out one Rc for another as we
+    // encounter them. That would make the fast path below apply to equal strings with different
+    // buffers, but we would have to make sure we weren't keeping any Refs alive.
+    cached: Map<Str<'static>, Entry<T>>,
+    // The most recently requested key and its hash. Scripts tend to ask for the same regex or file
+    // over and over (e.g. `$0 ~ pat` in the main loop); if the next key has the same
+    // representation, we can reuse the hash and skip cloning and unmooring the key. Holding on to
+    // the key keeps its buffer alive, so the representation cannot be reused for other contents.
+    last: Option<(Str<'static>, u64)>,
+    // Once there are this many entries, adding another evicts the least recently used one.
+    capacity: usize,
+    clock: u64,
 }
+
+struct Entry<T> {
+    val: T,
+    last_used: u64,
+}
+
 impl<T> Default for Registry<T> {
     fn default() -> Self {
-        Registry {
-            cached: Default::default(),
-        }
+        Registry::with_capacity(usize::MAX)
     }
 }
 
 impl<T> Registry<T> {
-    fn remove(&mut self, s: &Str) {
-        self.cached.remove(&s.clone().unmoor());
+    fn with_capacity(capacity: usize) -> Self {
+        Registry {
+            cached: Default::default(),
+            last: None,
+            capacity,
+            clock: 0,
+        }
+    }
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.cached.values_mut().map(|e| &mut e.val)
+    }
+    fn remove(&mut self, s: &Str) -> Option<T> {
+        self.cached.remove(&s.clone().unmoor()).map(|e| e.val)
     }
     fn get<R>(
         &mut self,
         s: &Str,
-        new: impl FnMut(&str) -> Result<T>,
+        new: impl FnMut(&[u8]) -> Result<T>,
         getter: impl FnOnce(&mut T) -> R,
     ) -> Result<R> {
         self.get_fallible(s, new, |t| Ok(getter(t)))
@@ -468,24 +929,51 @@ impl<T> Registry<T> {
     fn get_fallible<R>(
         &mut self,
         s: &Str,
-        mut new: impl FnMut(&str) -> Result<T>,
+        mut new: impl FnMut(&[u8]) -> Result<T>,
         getter: impl FnOnce(&mut T) -> Result<R>,
     ) -> Result<R> {
-        use hashbrown::hash_map::Entry;
-        let k_str = s.clone().unmoor();
-        match self.cached.entry(k_str) {
-            Entry::Occupied(mut o) => getter(o.get_mut()),
-            Entry::Vacant(v) => {
-                let (val, res) = v.key().with_bytes(|raw_str| {
-                    let s = match str::from_utf8(raw_str) {
-                        Ok(s) => s,
-                        Err(e) => return err!("invalid UTF-8 for file or regex: {}", e),
-                    };
-                    let mut val = new(s)?;
+        let (k_str, hash) = match &self.last {
+            Some((last, hash)) if last.same_rep(s) => (last, *hash),
+            _ => {
+                let k_str = s.clone().unmoor();
+                let hash = hash_key(&k_str);
+                let (k_str, hash) = self.last.insert((k_str, hash));
+                (&*k_str, *hash)
+            }
+        };
+        self.clock += 1;
+        let last_used = self.clock;
+        let full = self.cached.len() >= self.capacity;
+        match self
+            .cached
+            .raw_entry_mut()
+            .from_key_hashed_nocheck(hash, k_str)
+        {
+            RawEntryMut::Occupied(o) => {
+                let entry = o.into_mut();
+                entry.last_used = last_used;
+                getter(&mut entry.val)
+            }
+            RawEntryMut::Vacant(v) => {
+                let (val, res) = k_str.with_bytes(|raw_str| {
+                    let mut val = new(raw_str)?;
                     let res = getter(&mut val);
                     Ok((val, res))
                 })?;
-                v.insert(val);
+                let entry = Entry { val, last_used };
+                if full {
+                    let lru = self
+                        .cached
+                        .iter()
+                        .min_by_key(|(_, e)| e.last_used)
+                        .map(|(k, _)| k.clone());
+                    if let Some(lru) = lru {
+                        self.cached.remove(&lru);
+                    }
+                    self.cached.insert(k_str.clone(), entry);
+                } else {
+                    v.insert_hashed_nocheck(hash, k_str.clone(), entry);
+                }
                 res
             }
         }
@@ -548,10 +1036,10 @@ pub(crate) trait Inc {
 
 impl Inc for Int {
     fn inc_int(&mut self, by: Int) {
-        *self += by;
+        *self = self.wrapping_add(by);
     }
     fn inc_float(&mut self, by: Float) {
-        *self += by as Int;
+        *self = self.wrapping_add(by as Int);
     }
 }
 
@@ -566,7 +1054,7 @@ impl Inc for Float {
 
 impl<'a> Inc for Str<'a> {
     fn inc_int(&mut self, by: Int) {
-        *self = convert::<_, Self>(convert::<_, Int>(self as &_) + by);
+        *self = convert::<_, Self>(convert::<_, Int>(self as &_).wrapping_add(by));
     }
     fn inc_float(&mut self, by: Float) {
         *self = convert::<_, Self>(convert::<_, Float>(self as &_) + by);
@@ -580,11 +1068,41 @@ where
     _Carrier::convert(s)
 }
 
+lazy_static::lazy_static! {
+    static ref MAP_HASHER: DefaultHashBuilder = DefaultHashBuilder::new();
+}
+
+/// The hasher used by every AWK array. All arrays share the same (randomly chosen) keys, which
+/// means that a key hashes to the same value in every array; the interpreter relies on this to
+/// hash constant keys once, ahead of time. See `hash_key`.
+#[derive(Clone)]
+pub(crate) struct MapHasher(DefaultHashBuilder);
+
+impl Default for MapHasher {
+    fn default() -> MapHasher {
+        MapHasher(MAP_HASHER.clone())
+    }
+}
+
+impl BuildHasher for MapHasher {
+    type Hasher = <DefaultHashBuilder as BuildHasher>::Hasher;
+    fn build_hasher(&self) -> Self::Hasher {
+        self.0.build_hasher()
+    }
+}
+
+/// Compute the hash of `k` in any `SharedMap`, for use with the `*_hashed` methods.
+pub(crate) fn hash_key<K: Hash + ?Sized>(k: &K) -> u64 {
+    MAP_HASHER.hash_one(k)
+}
+
+pub(crate) type Map<K, V> = HashMap<K, V, MapHasher>;
+
 // AWK arrays are inherently shared and mutable, so we have to do this, even if it is a code smell.
 // NB These are repr(transparent) because we pass them around as void* when compiling with LLVM.
 #[repr(transparent)]
 #[derive(Debug)]
-pub(crate) struct SharedMap<K, V>(pub(crate) Rc<RefCell<HashMap<K, V>>>);
+pub(crate) struct SharedMap<K, V>(pub(crate) Rc<RefCell<Table<K, V>>>);
 
 impl<K, V> Default for SharedMap<K, V> {
     fn default() -> SharedMap<K, V> {
@@ -598,7 +1116,7 @@ impl<K, V> Clone for SharedMap<K, V> {
     }
 }
 
-impl<K: Hash + Eq, V> SharedMap<K, V> {
+impl<K: MapKey, V> SharedMap<K, V> {
     pub(crate) fn len(&self) -> usize {
         self.0.borrow().len()
     }
@@ -610,16 +1128,19 @@ impl<K: Hash + Eq, V> SharedMap<K, V> {
     }
     pub(crate) fn iter<F, R>(&self, f: F) -> R
     where
-        F: FnOnce(hashbrown::hash_map::Iter<K, V>) -> R,
+        F: FnOnce(table::Entries<K, V>) -> R,
     {
         f(self.0.borrow().iter())
     }
     pub(crate) fn clear(&self) {
         self.borrow_mut().clear();
     }
+    pub(crate) fn approx_size(&self, contents: impl Fn(&K, &V) -> usize) -> usize {
+        self.0.borrow().approx_size(contents)
+    }
 }
 
-impl<K: Hash + Eq + Clone, V: Inc + Default + Clone> SharedMap<K, V> {
+impl<K: MapKey, V: Inc + Default + Clone> SharedMap<K, V> {
     pub(crate) fn inc_int(&self, k: &K, by: Int) -> V {
         self.with_inserted(k, |kref| {
             kref.inc_int(by);
@@ -635,12 +1156,7 @@ impl<K: Hash + Eq + Clone, V: Inc + Default + Clone> SharedMap<K, V> {
     }
 
     fn with_inserted<R>(&self, k: &K, f: impl FnOnce(&mut V) -> R) -> R {
-        let mut slf = self.0.borrow_mut();
-        if let Some(k) = slf.get_mut(k) {
-            f(k)
-        } else {
-            f(slf.entry(k.clone()).or_insert(Default::default()))
-        }
+        f(self.borrow_mut().entry(k))
     }
 }
 
@@ -664,7 +1180,7 @@ impl<'a> From<Shuttle<HashMap<UniqueStr<'a>, Int>>> for StrMap<'a, Int> {
 }
 
 impl<K, V> SharedMap<K, V> {
-    fn borrow_mut(&self) -> impl std::ops::DerefMut<Target = HashMap<K, V>> + '_ {
+    fn borrow_mut(&self) -> impl std::ops::DerefMut<Target = Table<K, V>> + '_ {
         // Unlike the full std::collections APIs, we are careful not to hand out any references
         // internal to a SharedMap from a public function. That means that functions which mutate
         // the map are "Cell"-like, in that they swap out values or drop them in, but never hold
@@ -683,27 +1199,68 @@ impl<K, V> SharedMap<K, V> {
     }
 }
 
-impl<K: Hash + Eq, V: Clone> SharedMap<K, V> {
+impl<K: MapKey, V: Clone> SharedMap<K, V> {
+    /// Whether `k` is present. Unlike the lookup methods, this never inserts `k`.
     pub(crate) fn contains(&self, k: &K) -> bool {
         #[cfg(debug_assertions)]
         {
-            self.0.borrow().get(k).is_some()
+            self.0.borrow().contains_key(k)
         }
         #[cfg(not(debug_assertions))]
         {
-            unsafe { &mut *self.0.as_ptr() }.get(k).is_some()
+            unsafe { &mut *self.0.as_ptr() }.contains_key(k)
         }
     }
 }
 
-impl<K: Hash + Eq + Clone, V: Clone + Default> SharedMap<K, V> {
+impl<K: MapKey, V: Clone + Default> SharedMap<K, V> {
     pub(crate) fn get(&self, k: &K) -> V {
-        self.borrow_mut()
-            .raw_entry_mut()
-            .from_key(k)
-            .or_insert_with(|| (k.clone(), V::default()))
-            .1
-            .clone()
+        self.borrow_mut().vivify(k).clone()
+    }
+
+    /// The value at `k`, or None if it is uninitialized. Like `get`, a missing key is inserted,
+    /// and stays uninitialized until it is assigned.
+    pub(crate) fn get_init(&self, k: &K) -> Option<V> {
+        let mut map = self.borrow_mut();
+        let v = map.vivify(k).clone();
+        if map.is_uninit(k) {
+            None
+        } else {
+            Some(v)
+        }
+    }
+
+    // The `*_hashed` variants of map operations take the hash of `k`, as computed by `hash_key`,
+    // rather than recomputing it. Missing keys are inserted with a default value first.
+
+    pub(crate) fn get_hashed(&self, k: &K, hash: u64) -> V {
+        debug_assert_eq!(hash, hash_key(k));
+        self.borrow_mut().vivify_hashed(k, hash).clone()
+    }
+
+    pub(crate) fn insert_hashed(&self, k: &K, hash: u64, v: V) {
+        self.with_entry_hashed(k, hash, |slot| *slot = v)
+    }
+
+    fn with_entry_hashed<R>(&self, k: &K, hash: u64, f: impl FnOnce(&mut V) -> R) -> R {
+        debug_assert_eq!(hash, hash_key(k));
+        f(self.borrow_mut().entry_hashed(k, hash))
+    }
+}
+
+impl<K: MapKey, V: Inc + Default + Clone> SharedMap<K, V> {
+    pub(crate) fn inc_int_hashed(&self, k: &K, hash: u64, by: Int) -> V {
+        self.with_entry_hashed(k, hash, |v| {
+            v.inc_int(by);
+            v.clone()
+        })
+    }
+
+    pub(crate) fn inc_float_hashed(&self, k: &K, hash: u64, by: Float) -> V {
+        self.with_entry_hashed(k, hash, |v| {
+            v.inc_float(by);
+            v.clone()
+        })
     }
 }
 
@@ -713,7 +1270,7 @@ impl<'a> IntMap<Str<'a>> {
             self.0
                 .borrow()
                 .iter()
-                .map(|(x, y)| (*x, UniqueStr::from(y.clone())))
+                .map(|(x, y)| (x, UniqueStr::from(y.clone())))
                 .collect(),
         )
     }
@@ -725,35 +1282,37 @@ impl<'a> StrMap<'a, Int> {
             self.0
                 .borrow()
                 .iter()
-                .map(|(x, y)| (UniqueStr::from(x.clone()), *y))
+                .map(|(x, y)| (UniqueStr::from(x), *y))
                 .collect(),
         )
     }
 }
 
-impl<K: Hash + Eq + Clone, V> SharedMap<K, V> {
-    pub(crate) fn to_iter(&self) -> Iter<K> {
-        self.0.borrow().keys().cloned().collect()
+impl<K: MapKey + IterKey, V> SharedMap<K, V> {
+    pub(crate) fn to_iter(&self, order: KeyOrder, ignore_case: bool) -> Iter<K> {
+        self.to_vec(order, ignore_case).into_iter().collect()
     }
-    pub(crate) fn to_vec(&self) -> Vec<K> {
-        self.0.borrow().keys().cloned().collect()
+    /// The keys of the map, sorted by `order`. If `ignore_case` is set, keys are compared as
+    /// strings without regard to the case of ASCII letters, as they are with `IGNORECASE`.
+    pub(crate) fn to_vec(&self, order: KeyOrder, ignore_case: bool) -> Vec<K> {
+        let mut keys: Vec<K> = self.0.borrow().keys().collect();
+        order.sort(&mut keys, ignore_case);
+        keys
     }
 }
 
-impl<K: Hash + Eq, V> From<HashMap<K, V>> for SharedMap<K, V> {
-    fn from(m: HashMap<K, V>) -> SharedMap<K, V> {
-        SharedMap(Rc::new(RefCell::new(m)))
+impl<K: MapKey, V> From<Map<K, V>> for SharedMap<K, V> {
+    fn from(m: Map<K, V>) -> SharedMap<K, V> {
+        m.into_iter().collect()
     }
 }
 
-impl<K: Hash + Eq, V> FromIterator<(K, V)> for SharedMap<K, V> {
+impl<K: MapKey, V> FromIterator<(K, V)> for SharedMap<K, V> {
     fn from_iter<T>(iter: T) -> Self
     where
         T: IntoIterator<Item = (K, V)>,
     {
-        SharedMap(Rc::new(RefCell::new(
-            iter.into_iter().collect::<HashMap<K, V>>(),
-        )))
+        SharedMap(Rc::new(RefCell::new(iter.into_iter().collect())))
     }
 }
 
@@ -762,6 +1321,110 @@ pub(crate) type Float = f64;
 pub(crate) type IntMap<V> = SharedMap<Int, V>;
 pub(crate) type StrMap<'a, V> = SharedMap<Str<'a>, V>;
 
+/// The order in which `for (k in m)` loops visit the keys of a map.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeyOrder {
+    /// Whatever order the keys are stored in.
+    #[default]
+    Unordered,
+    StrAsc,
+    StrDesc,
+    /// Keys are compared by their numeric value, with ties (such as between keys that are not
+    /// numbers) broken by comparing them as strings.
+    NumAsc,
+    NumDesc,
+}
+
+// Set at most once, from the command line; 0 means the limit has not been set.
+static MAX_OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the number of files that can be open for reading with `getline`, and separately for
+/// writing, at once. Beyond this, the least recently used files are closed, and reopened where they
+/// left off when they are used again.
+pub fn set_max_open_files(n: usize) {
+    MAX_OPEN_FILES.store(n, atomic::Ordering::Relaxed)
+}
+
+/// The limit set with `set_max_open_files`, defaulting to a quarter of the process's limit on open
+/// file descriptors.
+pub(crate) fn max_open_files() -> usize {
+    match MAX_OPEN_FILES.load(atomic::Ordering::Relaxed) {
+        0 => default_max_open_files(),
+        n => n,
+    }
+}
+
+#[cfg(unix)]
+fn default_max_open_files() -> usize {
+    lazy_static::lazy_static! {
+        static ref DEFAULT: usize = {
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+                (limit.rlim_cur.min(1 << 20) as usize / 4).max(16)
+            } else {
+                256
+            }
+        };
+    }
+    *DEFAULT
+}
+
+#[cfg(not(unix))]
+fn default_max_open_files() -> usize {
+    256
+}
+
+impl KeyOrder {
+    fn sort<K: IterKey>(self, keys: &mut [K], ignore_case: bool) {
+        use KeyOrder::*;
+        match self {
+            Unordered => {}
+            StrAsc => keys.sort_by(|x, y| x.cmp_str(y, ignore_case)),
+            StrDesc => keys.sort_by(|x, y| y.cmp_str(x, ignore_case)),
+            NumAsc => keys.sort_by(|x, y| x.cmp_num(y, ignore_case)),
+            NumDesc => keys.sort_by(|x, y| y.cmp_num(x, ignore_case)),
+        }
+    }
+}
+
+/// Map keys that can be sorted according to a [`KeyOrder`].
+pub(crate) trait IterKey {
+    fn cmp_str(&self, other: &Self, ignore_case: bool) -> cmp::Ordering;
+    fn cmp_num(&self, other: &Self, ignore_case: bool) -> cmp::Ordering;
+}
+
+impl IterKey for Int {
+    fn cmp_str(&self, other: &Int, _ignore_case: bool) -> cmp::Ordering {
+        let mut l = itoa::Buffer::new();
+        let mut r = itoa::Buffer::new();
+        l.format(*self).cmp(r.format(*other))
+    }
+    fn cmp_num(&self, other: &Int, _ignore_case: bool) -> cmp::Ordering {
+        self.cmp(other)
+    }
+}
+
+impl<'a> IterKey for Str<'a> {
+    fn cmp_str(&self, other: &Str<'a>, ignore_case: bool) -> cmp::Ordering {
+        if ignore_case {
+            self.cmp_ignore_ascii_case(other)
+        } else {
+            self.cmp(other)
+        }
+    }
+    fn cmp_num(&self, other: &Str<'a>, ignore_case: bool) -> cmp::Ordering {
+        let l: Float = convert(self);
+        let r: Float = convert(other);
+        l.total_cmp(&r)
+            .then_with(|| self.cmp_str(other, ignore_case))
+    }
+}
+
+/// The state of a `for (k in m)` loop. The keys of the map are copied when the loop starts, so
+/// the loop visits exactly the keys present at that point, whatever the body inserts or deletes.
 pub(crate) struct Iter<S> {
     cur: Cell<usize>,
     items: Vec<S>,
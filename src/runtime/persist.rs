@@ -0,0 +1,237 @@
+//! Saving arrays to files and loading them back, for the `writea` and `reada` builtins.
+//!
+//! A saved array is a short header, followed by the number of elements and then each key and
+//! value in turn. Keys and values are all stored as strings, prefixed by their length; lengths
+//! and counts are LEB128-encoded. Floats are formatted with the shortest representation that
+//! parses back to the same value, so numbers round-trip exactly, and an array can be loaded into
+//! an array with different key or value types using the usual conversions.
+use super::{str_impl::Buf, Float, Int, MapKey, SharedMap, Str};
+use crate::runtime::float_parse::{strtod, strtoi};
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const MAGIC: &[u8] = b"frawk-array\x01";
+
+/// Distinguishes the temporary files of concurrent saves from the same process, such as those of
+/// different workers under `-pr`.
+static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) trait Persist: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(bs: &[u8]) -> Self;
+}
+
+impl Persist for Int {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(itoa::Buffer::new().format(*self).as_bytes())
+    }
+    fn decode(bs: &[u8]) -> Int {
+        strtoi(bs)
+    }
+}
+
+impl Persist for Float {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        Str::from(*self).with_bytes(|bs| buf.extend_from_slice(bs))
+    }
+    fn decode(bs: &[u8]) -> Float {
+        strtod(bs)
+    }
+}
+
+impl<'a> Persist for Str<'a> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.with_bytes(|bs| buf.extend_from_slice(bs))
+    }
+    fn decode(bs: &[u8]) -> Str<'a> {
+        if bs.is_empty() {
+            Str::default()
+        } else {
+            Buf::read_from_bytes(bs).into_str()
+        }
+    }
+}
+
+fn put_len(mut n: u64, buf: &mut Vec<u8>) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn put_scalar(x: &impl Persist, buf: &mut Vec<u8>, scratch: &mut Vec<u8>) {
+    scratch.clear();
+    x.encode(scratch);
+    put_len(scratch.len() as u64, buf);
+    buf.extend_from_slice(scratch);
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed array file")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn len(&mut self) -> io::Result<usize> {
+        let mut res = 0u64;
+        for (i, b) in self.0.iter().enumerate().take(10) {
+            res |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                self.0 = &self.0[i + 1..];
+                return usize::try_from(res).map_err(|_| invalid());
+            }
+        }
+        Err(invalid())
+    }
+    fn scalar<T: Persist>(&mut self) -> io::Result<T> {
+        let len = self.len()?;
+        if len > self.0.len() {
+            return Err(invalid());
+        }
+        let (bs, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(T::decode(bs))
+    }
+}
+
+fn with_path<R>(path: &Str, f: impl FnOnce(&str) -> io::Result<R>) -> io::Result<R> {
+    path.with_bytes(|bs| match std::str::from_utf8(bs) {
+        Ok(s) => f(s),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path is not valid UTF-8",
+        )),
+    })
+}
+
+impl<K: MapKey + Persist, V: Persist> SharedMap<K, V> {
+    /// Write the contents of the map to `path`, replacing the file if it already exists. The new
+    /// contents are written to a temporary file that is then renamed, so a failed write never
+    /// leaves a partially-written array behind.
+    pub(crate) fn save(&self, path: &Str) -> io::Result<()> {
+        let mut buf = Vec::from(MAGIC);
+        let mut scratch = Vec::new();
+        put_len(self.len() as u64, &mut buf);
+        self.iter(|entries| {
+            for (k, v) in entries {
+                put_scalar(&k, &mut buf, &mut scratch);
+                put_scalar(v, &mut buf, &mut scratch);
+            }
+        });
+        with_path(path, |path| {
+            let tmp = format!(
+                "{}.tmp.{}.{}",
+                path,
+                std::process::id(),
+                SAVES.fetch_add(1, Ordering::Relaxed)
+            );
+            let res = std::fs::write(&tmp, &buf).and_then(|()| std::fs::rename(&tmp, path));
+            if res.is_err() {
+                let _ = std::fs::remove_file(&tmp);
+            }
+            res
+        })
+    }
+
+    /// Replace the contents of the map with an array saved by `save`. The map is left unchanged
+    /// if the file cannot be read.
+    pub(crate) fn load(&self, path: &Str) -> io::Result<()> {
+        let bytes = with_path(path, |path| std::fs::read(path))?;
+        let mut r = Reader(bytes.strip_prefix(MAGIC).ok_or_else(invalid)?);
+        let n = r.len()?;
+        let mut elts = Vec::with_capacity(n.min(r.0.len() / 2));
+        for _ in 0..n {
+            let k = r.scalar::<K>()?;
+            let v = r.scalar::<V>()?;
+            elts.push((k, v));
+        }
+        if !r.0.is_empty() {
+            return Err(invalid());
+        }
+        self.clear();
+        for (k, v) in elts {
+            self.insert(k, v);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_path(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("frawk-persist-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().into()
+    }
+
+    #[test]
+    fn round_trip_and_convert() {
+        let path = tmp_path("round_trip");
+        let path_str = Str::from(path.as_str());
+        let m = SharedMap::<Str, Float>::default();
+        m.insert(Str::from("3.9"), 0.1);
+        m.insert(Str::from("12"), -2.5e300);
+        m.insert(Str::default(), 7.0);
+        m.save(&path_str).unwrap();
+
+        let same = SharedMap::<Str, Float>::default();
+        same.insert(Str::from("stale"), 1.0);
+        same.load(&path_str).unwrap();
+        assert_eq!(same.len(), 3);
+        assert_eq!(same.get(&Str::from("3.9")), 0.1);
+        assert_eq!(same.get(&Str::from("12")), -2.5e300);
+        assert!(!same.contains(&Str::from("stale")));
+
+        let converted = SharedMap::<Int, Str>::default();
+        converted.load(&path_str).unwrap();
+        assert_eq!(converted.get(&3), Str::from("0.1"));
+        assert_eq!(converted.get(&12), Str::from("-2.5e300"));
+        assert_eq!(converted.get(&0), Str::from("7"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_files_leave_map_unchanged() {
+        let path = tmp_path("bad_file");
+        let path_str = Str::from(path.as_str());
+        let m = SharedMap::<Int, Int>::default();
+        m.insert(1, 2);
+        assert!(m.load(&path_str).is_err());
+        std::fs::write(&path, b"frawk-array\x01\x05\x01").unwrap();
+        assert!(m.load(&path_str).is_err());
+        assert_eq!(m.len(), 1);
+        assert_eq!(m.get(&1), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_saves() {
+        let path = tmp_path("concurrent");
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let path = path.as_str();
+                s.spawn(move || {
+                    let m = SharedMap::<Int, Int>::default();
+                    for j in 0..10_000 {
+                        m.insert(j, i);
+                    }
+                    for _ in 0..50 {
+                        m.save(&Str::from(path)).unwrap();
+                    }
+                });
+            }
+        });
+        let m = SharedMap::<Int, Int>::default();
+        m.load(&Str::from(path.as_str())).unwrap();
+        assert_eq!(m.len(), 10_000);
+        let i = m.get(&0);
+        assert!((0..10_000).all(|j| m.get(&j) == i));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
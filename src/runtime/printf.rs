@@ -3,12 +3,9 @@
 //! We lean heavily on ryu and the std::fmt machinery; as such, most of the work is parsing
 //! awk-style format strings and translating them to individual calls to write!.
 //!
-//! TODO: Originally, frawk enforced that all Strs contained valid UTF-8. We have since allowed
-//! strings to contain arbitrary byte sequences, but this module will eagerly replace invalid UTF8
-//! byte sequences with REPLACEMENT CHARACTER using String's from_utf8_lossy function. This means
-//! that users hoping to output raw bytes using `printf` (as may be necessary, given that print
-//! appends a newline) may find some bytes replaced inadvertently. We could solve this by adding a
-//! new print function that does not append a newline.
+//! Strings can contain arbitrary bytes, so `%s` is formatted by hand rather than with std::fmt,
+//! which only handles valid UTF-8. Widths and precisions count characters, with each byte that is
+//! not part of a valid UTF-8 sequence counting as a character of its own.
 use crate::common::Result;
 use crate::runtime::{convert, strtoi, Float, Int, Str};
 
@@ -38,11 +35,46 @@ impl Write for StackWriter {
     }
 }
 
-struct DisplayBytes<'a>(&'a [u8]);
-impl<'a> fmt::Display for DisplayBytes<'a> {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(&*std::string::String::from_utf8_lossy(self.0), fmt)
+/// The length of the character starting at `bs[0]`, or 1 if `bs` does not start with valid UTF-8.
+fn char_len(bs: &[u8]) -> usize {
+    let len = match bs[0] {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return 1,
+    };
+    match bs.get(..len) {
+        Some(c) if str::from_utf8(c).is_ok() => len,
+        _ => 1,
+    }
+}
+
+fn write_padding(mut w: impl Write, fill: u8, mut pad: usize) -> std::io::Result<()> {
+    let fill = [fill; 32];
+    while pad > 0 {
+        let n = pad.min(fill.len());
+        w.write_all(&fill[..n])?;
+        pad -= n;
+    }
+    Ok(())
+}
+
+fn write_str_spec(mut w: impl Write, fspec: &FormatSpec, bs: &[u8]) -> std::io::Result<()> {
+    let (mut end, mut chars) = (0, 0);
+    while end < bs.len() && chars < fspec.rnum {
+        end += char_len(&bs[end..]);
+        chars += 1;
+    }
+    let pad = fspec.lnum.saturating_sub(chars);
+    let fill = if fspec.leading_zeros { b'0' } else { b' ' };
+    if !fspec.minus {
+        write_padding(&mut w, fill, pad)?;
     }
+    w.write_all(&bs[..end])?;
+    if fspec.minus {
+        write_padding(&mut w, fill, pad)?;
+    }
+    Ok(())
 }
 
 #[derive(Clone, Debug)]
@@ -231,7 +263,7 @@ fn process_spec(mut w: impl Write, fspec: &mut FormatSpec, arg: &FormatArg) -> R
                 _ => match_for_spec!("", "?"),
             }
         }
-        b's' => arg.with_bytes(|bs| match_for_spec!("", DisplayBytes(bs))),
+        b's' => arg.with_bytes(|bs| write_str_spec(&mut w, fspec, bs)),
         x => return err!("unsupported format specifier: {}", x),
     };
     wrap_result(res)
@@ -433,6 +465,18 @@ mod tests {
         assert_eq!(s2.as_str(), "1.2535e4 3 ~~ hi");
     }
 
+    #[test]
+    fn invalid_utf8() {
+        let mut v = Vec::new();
+        let args: &[FormatArg] = &[
+            b"a\xffb"[..].into(),
+            b"\xc3\xa9\xc3"[..].into(),
+            b"x\xffyz"[..].into(),
+        ];
+        printf(&mut v, b"%s|%-4s|%3.2s|", args).unwrap();
+        assert_eq!(&v[..], b"a\xffb|\xc3\xa9\xc3  | x\xff|");
+    }
+
     #[test]
     fn truncation_padding() {
         let s1 = sprintf!(b"%06o |%-10.3s|", 98, "February");
@@ -30,6 +30,7 @@ use regex::{bytes, bytes::Regex};
 use crate::common::{CancelSignal, ExecutionStrategy, Result};
 use crate::pushdown::FieldSet;
 use crate::runtime::{
+    literal_byte,
     str_impl::{Buf, Str, UniqueBuf},
     Int, RegexCache,
 };
@@ -52,6 +53,7 @@ pub struct CSVReader<P> {
     // Used to trigger updating FILENAME on the first read.
     ifmt: InputFormat,
     field_set: FieldSet,
+    split_headers: bool,
 
     empty_buf: Buf,
     check_utf8: bool,
@@ -62,6 +64,9 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
     fn filename(&self) -> Str<'static> {
         Str::from(self.cur_chunk.get_name()).unmoor()
     }
+    fn file_index(&self) -> u32 {
+        self.cur_chunk.version
+    }
     fn wait(&self) -> bool {
         self.prod.wait()
     }
@@ -74,6 +79,7 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
         let ifmt = self.ifmt;
         for p_factory in producers.into_iter() {
             let field_set = self.field_set.clone();
+            let split_headers = self.split_headers;
             let check_utf8 = self.check_utf8;
             res.push(Box::new(move || {
                 let empty_buf = UniqueBuf::new(0).into_buf();
@@ -88,6 +94,7 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
                     last_len: 0,
                     ifmt,
                     field_set,
+                    split_headers,
                     check_utf8,
                 }
             }) as _)
@@ -95,9 +102,13 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
         res
     }
     fn read_line(&mut self, _pat: &Str, _rc: &mut RegexCache) -> Result<(bool, Line)> {
+        // This line is read into a variable, not $0, so the program needs all of it whichever
+        // fields it uses.
+        let field_set = mem::take(&mut self.field_set);
         let mut line = Line::default();
-        let changed = self.read_line_reuse(_pat, _rc, &mut line)?;
-        Ok((changed, line))
+        let res = self.read_line_reuse(_pat, _rc, &mut line);
+        self.field_set = field_set;
+        Ok((res?, line))
     }
     fn read_line_reuse<'a, 'b: 'a>(
         &'b mut self,
@@ -124,6 +135,9 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
     fn set_used_fields(&mut self, field_set: &FieldSet) {
         self.field_set = field_set.clone();
     }
+    fn split_headers(&mut self) {
+        self.split_headers = true;
+    }
 }
 
 impl CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
@@ -185,6 +199,7 @@ impl CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
             prev_ix: 0,
             last_len: 0,
             field_set: FieldSet::all(),
+            split_headers: false,
             ifmt,
             empty_buf,
             check_utf8,
@@ -245,8 +260,12 @@ impl<P: ChunkProducer<Chunk = OffsetChunk>> CSVReader<P> {
             }
         }
 
+        let full_split = changed && self.split_headers;
         let (prev_ix, st) = {
             let mut stepper = self.stepper(State::Init, line);
+            if full_split {
+                stepper.field_set = FieldSet::all();
+            }
             (unsafe { stepper.step() }, stepper.st)
         };
         let consumed = prev_ix - self.prev_ix;
@@ -302,15 +321,9 @@ pub struct Line {
     len: usize,
     fields: Vec<Str<'static>>,
     partial: Str<'static>,
-}
-
-impl Line {
-    pub fn is_empty(&self) -> bool {
-        self.len == 0
-    }
-    pub fn len(&self) -> usize {
-        self.len
-    }
+    // Has a field or NF been assigned since raw was last computed? If so, $0 is rebuilt from
+    // `fields` the next time it is read.
+    diverged: bool,
 }
 
 impl<'a> super::Line<'a> for Line {
@@ -340,10 +353,14 @@ impl<'a> super::Line<'a> for Line {
         &mut self,
         col: super::Int,
         _pat: &Str,
-        _ofs: &Str,
+        ofs: &Str,
         _rc: &mut super::RegexCache,
     ) -> Result<Str<'a>> {
         if col == 0 {
+            if self.diverged {
+                self.raw = ofs.join_slice(&self.fields[..]);
+                self.diverged = false;
+            }
             return Ok(self.raw.clone().upcast());
         }
         if col < 0 {
@@ -357,14 +374,38 @@ impl<'a> super::Line<'a> for Line {
             .upcast())
     }
 
-    // Setting columns for CSV doesn't work. We refuse it outright.
     fn set_col(
         &mut self,
-        _col: super::Int,
-        _s: &Str<'a>,
+        col: super::Int,
+        s: &Str<'a>,
         _pat: &Str,
         _rc: &mut super::RegexCache,
     ) -> Result<()> {
+        if col == 0 {
+            // There is no way to split a string as CSV or TSV outside of the reader, so the fields
+            // keep their values from the original record.
+            self.raw = s.clone().unmoor();
+            self.diverged = false;
+            return Ok(());
+        }
+        if col < 0 {
+            return err!("attempt to access field {}; field must be nonnegative", col);
+        }
+        let col = col as usize - 1;
+        if col >= self.fields.len() {
+            self.fields.resize_with(col + 1, Str::default);
+        }
+        self.fields[col] = s.clone().unmoor();
+        self.diverged = true;
+        Ok(())
+    }
+
+    fn set_nf(&mut self, nf: super::Int, _pat: &Str, _rc: &mut super::RegexCache) -> Result<()> {
+        if nf < 0 {
+            return err!("attempt to set NF to {}; NF must be nonnegative", nf);
+        }
+        self.fields.resize_with(nf as usize, Str::default);
+        self.diverged = true;
         Ok(())
     }
 }
@@ -383,6 +424,7 @@ impl Line {
         self.partial = Str::default();
         self.raw = Str::default();
         self.len = 0;
+        self.diverged = false;
     }
 }
 
@@ -602,6 +644,7 @@ impl<'a> Stepper<'a> {
 }
 
 #[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum InputFormat {
     CSV,
     TSV,
@@ -786,9 +829,12 @@ lazy_static! {
     static ref NEEDS_ESCAPE_TSV: bytes::RegexSet =
         bytes::RegexSet::new([r#"\t"#, r#"\n"#]).unwrap();
     static ref NEEDS_ESCAPE_CSV: bytes::RegexSet =
-        bytes::RegexSet::new([r#"""#, r#"\t"#, r#"\n"#, ","]).unwrap();
+        bytes::RegexSet::new([r#"""#, r#"[\t\r\n,]"#]).unwrap();
 }
 
+/// Escape `s` as a CSV field according to RFC 4180: fields containing separators, quotes or line
+/// breaks are quoted, with any quotes inside doubled. Line breaks are written as-is within the
+/// quotes; the CSV reader accepts them there, as do other CSV tools.
 pub fn escape_csv<'a>(s: &Str<'a>) -> Str<'a> {
     let bs = unsafe { &*s.get_bytes() };
     let matches = NEEDS_ESCAPE_CSV.matches(bs);
@@ -796,16 +842,8 @@ pub fn escape_csv<'a>(s: &Str<'a>) -> Str<'a> {
         return s.clone();
     }
     let mut cur = s.clone();
-    for m in matches.into_iter() {
-        let (pat, subst_for) = match m {
-            0 => (&*QUOTE, r#""""#),
-            1 => (&*TAB, r#"\t"#),
-            2 => (&*NEWLINE, r#"\n"#),
-            // This just necessitates the ""s
-            3 => continue,
-            _ => unreachable!(),
-        };
-        cur = cur.subst_all(pat, &Str::from(subst_for).upcast()).0;
+    if matches.matched(0) {
+        cur = cur.subst_all(&QUOTE, &Str::from(r#""""#).upcast()).0;
     }
     let quote = Str::from("\"");
     Str::concat(Str::concat(quote.clone(), cur), quote)
@@ -840,8 +878,10 @@ mod escape_tests {
         assert_eq!(escape_csv(&s1), s1);
         assert_eq!(
             escape_csv(&s2),
-            Str::from(r#""This ought to be escaped, for two\treasons""#)
+            Str::from("\"This ought to be escaped, for two\treasons\"")
         );
+        let s3 = Str::from("say \"hi\"\r\nand bye");
+        assert_eq!(escape_csv(&s3), Str::from("\"say \"\"hi\"\"\r\nand bye\""));
     }
 
     #[test]
@@ -868,7 +908,6 @@ mod generic {
         // Precondition: bptr points to at least INPUT_SIZE bytes.
         unsafe fn fill_input(btr: *const u8) -> Self;
         unsafe fn or(self, rhs: Self) -> Self;
-        unsafe fn and(self, rhs: Self) -> Self;
         unsafe fn mask(self) -> u64;
         // Compute a mask of which bits in input match (bytewise) `m`.
         unsafe fn cmp_against_input(self, m: u8) -> Self;
@@ -980,10 +1019,6 @@ mod generic {
             foreach_impl!(ix, self.0[ix] | rhs.0[ix])
         }
 
-        unsafe fn and(self, rhs: Self) -> Self {
-            foreach_impl!(ix, self.0[ix] & rhs.0[ix])
-        }
-
         unsafe fn mask(self) -> u64 {
             let mut res = 0u64;
             for i in 0..Self::VEC_BYTES {
@@ -1316,13 +1351,6 @@ mod sse2 {
             Impl { lo, hi }
         }
 
-        #[inline(always)]
-        unsafe fn and(self, rhs: Self) -> Self {
-            let lo = _mm_and_si128(self.lo, rhs.lo);
-            let hi = _mm_and_si128(self.hi, rhs.hi);
-            Impl { lo, hi }
-        }
-
         #[inline(always)]
         unsafe fn cmp_against_input(self, m: u8) -> Self {
             // Load the mask into all lanes.
@@ -1378,13 +1406,6 @@ mod avx2 {
             Impl { lo, hi }
         }
 
-        #[inline(always)]
-        unsafe fn and(self, rhs: Self) -> Self {
-            let lo = _mm256_and_si256(self.lo, rhs.lo);
-            let hi = _mm256_and_si256(self.hi, rhs.hi);
-            Impl { lo, hi }
-        }
-
         #[inline(always)]
         unsafe fn cmp_against_input(self, m: u8) -> Self {
             // Load the mask into all lanes.
@@ -1404,15 +1425,29 @@ mod avx2 {
     }
 }
 
+/// Whether input with the given field and record separators should be split by a `ByteReader`. A
+/// field separator of " " splits on runs of whitespace, which `ByteReader` only implements for
+/// newline-separated records; other records (say, with `-z`) are split with a regex, so long as
+/// the record separator means the same thing as a regex.
+pub fn byte_splittable(field_sep: &[u8], record_sep: &[u8]) -> bool {
+    field_sep.len() == 1
+        && record_sep.len() == 1
+        && (field_sep != b" " || record_sep == b"\n" || literal_byte(record_sep).is_none())
+}
+
 pub struct ByteReader<P: ChunkProducer> {
     prod: P,
     cur_chunk: P::Chunk,
     cur_buf: Buf,
     buf_len: usize,
     used_fields: FieldSet,
+    split_headers: bool,
     // Progress in the current buffer.
     progress: usize,
     record_sep: u8,
+    // Leave fields to be split from the line with FS when they are first used, rather than using
+    // the offsets of field separators in the chunk.
+    lazy_fields: bool,
 
     last_len: usize,
     check_utf8: bool,
@@ -1444,6 +1479,36 @@ impl ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
         )
     }
 
+    /// Read records separated by `record_sep`, splitting their fields with the current value of
+    /// FS when they are used. This allows records to be read in parallel when FS is a regex or
+    /// more than one byte long.
+    pub fn new_lazy_fields<I, S>(
+        rs: I,
+        record_sep: u8,
+        chunk_size: usize,
+        check_utf8: bool,
+        exec_strategy: ExecutionStrategy,
+        cancel_signal: CancelSignal,
+    ) -> Self
+    where
+        I: Iterator<Item = (S, String)> + 'static + Send,
+        S: Read + Send + 'static,
+    {
+        // Only record separators need to be found, so they double as field separators here.
+        let mut res = Self::new_internal(
+            rs,
+            record_sep,
+            record_sep,
+            chunk_size,
+            check_utf8,
+            exec_strategy,
+            get_find_indexes_bytes(),
+            cancel_signal,
+        );
+        res.lazy_fields = true;
+        res
+    }
+
     // Not great, but grouping into a separate type is a bit awkward given the
     // different permutations used between these modules.
     //
@@ -1508,7 +1573,9 @@ impl ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
             buf_len: 0,
             progress: 0,
             record_sep,
+            lazy_fields: false,
             used_fields: FieldSet::all(),
+            split_headers: false,
             last_len: usize::max_value(),
             check_utf8,
         }
@@ -1600,7 +1667,9 @@ impl ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk<WhitespaceOffsets>>>>
             buf_len: 0,
             progress: 0,
             record_sep: 0, // unused
+            lazy_fields: false,
             used_fields: FieldSet::all(),
+            split_headers: false,
             last_len: usize::max_value(),
             check_utf8,
         }
@@ -1615,6 +1684,9 @@ where
     fn filename(&self) -> Str<'static> {
         Str::from(self.cur_chunk.get_name()).unmoor()
     }
+    fn file_index(&self) -> u32 {
+        self.cur_chunk_version()
+    }
     fn check_utf8(&self) -> bool {
         self.check_utf8
     }
@@ -1626,7 +1698,9 @@ where
         let mut res = Vec::with_capacity(producers.len());
         for p_factory in producers.into_iter() {
             let used_fields = self.used_fields.clone();
+            let split_headers = self.split_headers;
             let record_sep = self.record_sep;
+            let lazy_fields = self.lazy_fields;
             let check_utf8 = self.check_utf8;
             res.push(Box::new(move || ByteReader {
                 prod: p_factory(),
@@ -1635,17 +1709,22 @@ where
                 buf_len: 0,
                 progress: 0,
                 record_sep,
+                lazy_fields,
                 last_len: usize::max_value(),
                 used_fields,
+                split_headers,
                 check_utf8,
             }) as _)
         }
         res
     }
     fn read_line(&mut self, _pat: &Str, _rc: &mut RegexCache) -> Result<(bool, DefaultLine)> {
+        // As in CSVReader, the whole line is needed whichever fields the program uses.
+        let used_fields = mem::take(&mut self.used_fields);
         let mut line = DefaultLine::default();
-        let changed = self.read_line_reuse(_pat, _rc, &mut line)?;
-        Ok((changed, line))
+        let res = self.read_line_reuse(_pat, _rc, &mut line);
+        self.used_fields = used_fields;
+        Ok((res?, line))
     }
     fn read_line_reuse<'a, 'b: 'a>(
         &'b mut self,
@@ -1661,8 +1740,21 @@ where
         } else if old.used_fields != self.used_fields {
             self.used_fields = old.used_fields.clone()
         }
-        old.fields.clear();
-        let changed = self.read_line_inner(&mut old.line, &mut old.fields)?;
+        old.clear_fields();
+        if !self.lazy_fields {
+            return self.read_line_inner(&mut old.line, &mut old.fields);
+        }
+        // Fields are split from the line itself, so we need all of it regardless of which fields
+        // are used.
+        let used_fields = mem::take(&mut self.used_fields);
+        let res = self.read_line_inner(&mut old.line, &mut old.fields);
+        self.used_fields = used_fields;
+        let changed = res?;
+        old.clear_fields();
+        // As in RegexSplitter, a header split in full means the rest of the file is too.
+        if changed && self.split_headers {
+            old.used_fields = FieldSet::all();
+        }
         Ok(changed)
     }
     fn read_state(&self) -> i64 {
@@ -1684,6 +1776,9 @@ where
     fn set_used_fields(&mut self, field_set: &FieldSet) {
         self.used_fields = field_set.clone();
     }
+    fn split_headers(&mut self) {
+        self.split_headers = true;
+    }
 }
 
 // Most of the implementation for splitting by whitespace and splitting by a single byte is
@@ -1751,7 +1846,14 @@ where
             return Ok(false);
         }
     }
-    let (next_line, consumed) = unsafe { br.consume_line(fields) };
+    let (next_line, consumed) = if changed && br.split_headers {
+        let used_fields = mem::take(&mut br.used_fields);
+        let res = unsafe { br.consume_line(fields) };
+        br.used_fields = used_fields;
+        res
+    } else {
+        unsafe { br.consume_line(fields) }
+    };
     *line = next_line;
     br.last_len = consumed;
     Ok(changed)
@@ -1822,11 +1924,22 @@ impl<P: ChunkProducer<Chunk = OffsetChunk>> ByteReaderBase for ByteReader<P> {
                 // character in the line.
                 fields.push(get_field!(index));
             }
-            if fields.len() == max {
+            if fields.len() >= max {
+                // The remaining fields are not used: count them without slicing them out.
                 let start_inc = gallop(&offs.rel.fields[offs.rel.start..], |ix| ix as usize <= end);
                 let len_inc = fields.len() + start_inc;
                 fields.resize_with(len_inc, Str::default);
                 offs.rel.start += start_inc;
+                if !is_record_sep && end == self.buf_len {
+                    // The last record in the input need not end in a record separator, in which
+                    // case the field after the last separator is added below.
+                    self.progress = if start_inc == 0 {
+                        index + 1
+                    } else {
+                        offs.rel.fields[offs.rel.start - 1] as usize + 1
+                    };
+                    break;
+                }
                 index = end;
                 is_record_sep = true;
             }
@@ -1915,12 +2028,27 @@ impl ByteReaderBase for ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk<Whi
         //    `fields` vector if they're present in used_fields.
         // 2. We are at the end of the input, in which case we take from the start offset to the
         //    end of the buffer.
+        let max = self.used_fields.max_value() as usize;
         let mut iter = self.cur_chunk.off.0.rel.fields[self.cur_chunk.off.0.rel.start..]
             .iter()
             .cloned()
             .map(|x| x as usize)
             .take_while(|x| x <= &record_end);
         while let Some(field_start) = iter.next() {
+            if fields.len() >= max {
+                // As when splitting on a single byte, count the unused fields at the end of the
+                // record rather than slicing them out. Each field has a start and end offset,
+                // except for a last field ending at the end of the input.
+                let rest = &self.cur_chunk.off.0.rel.fields[self.cur_chunk.off.0.rel.start..];
+                let n_offsets = gallop(rest, |ix| ix as usize <= record_end);
+                let mut n_fields = n_offsets / 2;
+                if n_offsets % 2 == 1 && rest[n_offsets - 1] as usize != record_end {
+                    n_fields += 1;
+                }
+                fields.resize_with(fields.len() + n_fields, Str::default);
+                self.cur_chunk.off.0.rel.start += n_offsets;
+                break;
+            }
             self.progress = field_start;
             self.cur_chunk.off.0.rel.start += 1;
             if let Some(field_end) = iter.next() {
@@ -2081,6 +2209,41 @@ unquoted,commas,"as well, including some long ones", and there we have it.""#;
         tsv_split(crate::test_string_constants::PRIDE_PREJUDICE_CH2);
     }
 
+    // The fields and lines we expect to read when only the fields in `used` are sliced out of the
+    // input: the others are empty, but there are just as many of them.
+    fn project(
+        fields: &[Vec<Str<'static>>],
+        lines: &[Str<'static>],
+        used: &FieldSet,
+    ) -> (Vec<Vec<Str<'static>>>, Vec<Str<'static>>) {
+        let fields = fields
+            .iter()
+            .map(|fs| {
+                fs.iter()
+                    .enumerate()
+                    .map(|(i, f)| {
+                        if used.get(i + 1) {
+                            f.clone()
+                        } else {
+                            Str::default()
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let lines = lines
+            .iter()
+            .map(|l| {
+                if used.get(0) {
+                    l.clone()
+                } else {
+                    Str::default()
+                }
+            })
+            .collect();
+        (fields, lines)
+    }
+
     fn bytes_split(kernel: BytesIndexKernel, fs: u8, rs: u8, corpus: &'static str) {
         let mut _cache = RegexCache::default();
         let _pat = Str::default();
@@ -2106,6 +2269,23 @@ unquoted,commas,"as well, including some long ones", and there we have it.""#;
             let _ = expected.pop();
         }
 
+        for used in [FieldSet::all(), FieldSet::singleton(2), FieldSet::empty()] {
+            bytes_split_projected(kernel, fs, rs, corpus, &used, &expected, &expected_lines);
+        }
+    }
+
+    fn bytes_split_projected(
+        kernel: BytesIndexKernel,
+        fs: u8,
+        rs: u8,
+        corpus: &'static str,
+        used: &FieldSet,
+        expected: &[Vec<Str<'static>>],
+        expected_lines: &[Str<'static>],
+    ) {
+        let mut _cache = RegexCache::default();
+        let _pat = Str::default();
+        let (expected, expected_lines) = project(expected, expected_lines, used);
         let reader = std::io::Cursor::new(corpus);
         let mut reader = ByteReader::new_internal(
             iter::once((reader, String::from("fake-stdin"))),
@@ -2117,11 +2297,13 @@ unquoted,commas,"as well, including some long ones", and there we have it.""#;
             kernel,
             Default::default(),
         );
+        reader.set_used_fields(used);
         let mut got_lines = Vec::new();
         let mut got = Vec::new();
+        let mut line = DefaultLine::default();
         loop {
-            let (_, line) = reader
-                .read_line(&_pat, &mut _cache)
+            reader
+                .read_line_reuse(&_pat, &mut _cache, &mut line)
                 .expect("failed to read line");
             if reader.read_state() != 1 {
                 break;
@@ -2326,6 +2508,21 @@ unquoted,commas,"as well, including some long ones", and there we have it.""#;
             let _ = expected_lines.pop();
             let _ = expected.pop();
         }
+        for used in [FieldSet::all(), FieldSet::singleton(2), FieldSet::empty()] {
+            whitespace_split_projected(kernel, corpus, &used, &expected, &expected_lines);
+        }
+    }
+
+    fn whitespace_split_projected(
+        kernel: WhitespaceIndexKernel,
+        corpus: &'static str,
+        used: &FieldSet,
+        expected: &[Vec<Str<'static>>],
+        expected_lines: &[Str<'static>],
+    ) {
+        let mut _cache = RegexCache::default();
+        let _pat = Str::default();
+        let (expected, expected_lines) = project(expected, expected_lines, used);
         let reader = std::io::Cursor::new(corpus);
         let mut reader = ByteReader::new_whitespace_internal(
             std::iter::once((reader, String::from("fake-stdin"))),
@@ -2335,11 +2532,13 @@ unquoted,commas,"as well, including some long ones", and there we have it.""#;
             kernel,
             Default::default(),
         );
+        reader.set_used_fields(used);
         let mut got_lines = Vec::new();
         let mut got = Vec::new();
+        let mut line = DefaultLine::default();
         loop {
-            let (_, line) = reader
-                .read_line(&_pat, &mut _cache)
+            reader
+                .read_line_reuse(&_pat, &mut _cache, &mut line)
                 .expect("failed to read line");
             if reader.read_state() != 1 {
                 break;
@@ -331,6 +331,13 @@ impl<R: Read, F: FnMut(&[u8], &mut Offsets)> ChunkProducer for OffsetChunkProduc
                             chunk.len = target.unwrap();
                             Ok(false)
                         }
+                        // There was no data left at all (e.g. the file was empty), so there is
+                        // nothing to yield.
+                        (true, true) if chunk.len == 0 => {
+                            self.inner.clear_buf();
+                            self.state = ChunkState::Done;
+                            continue;
+                        }
                         (false, true) | (true, true) => {
                             // Yield the entire buffer, this was the last piece of data.
                             self.inner.clear_buf();
@@ -410,6 +417,11 @@ impl<R: Read, F: FnMut(&[u8], &mut WhitespaceOffsets, u64) -> u64> ChunkProducer
                             chunk.len = len_if_not_last;
                             Ok(false)
                         }
+                        (true, true) if chunk.len == 0 => {
+                            self.0.inner.clear_buf();
+                            self.0.state = ChunkState::Done;
+                            continue;
+                        }
                         (false, true) | (true, true) => {
                             // Yield the entire buffer, this was the last piece of data.
                             self.0.inner.clear_buf();
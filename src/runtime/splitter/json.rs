@@ -0,0 +1,380 @@
+//! Reading JSON Lines input, for `-i jsonl`.
+//!
+//! Each record is a JSON value. Its scalars are flattened into columns named by their path within
+//! the value: `{"a": {"b": 1}, "c": [true, null]}` has the columns `a.b`, `c.0` and `c.1`. Columns
+//! are numbered in the order their names first appear in the input, so a name refers to the same
+//! column in every record, and `FI` maps each name to its column. Booleans become 1 and 0, null
+//! becomes the empty string, and numbers are kept as they are written.
+use hashbrown::HashMap;
+
+use crate::common::Result;
+use crate::pushdown::FieldSet;
+use crate::runtime::{
+    str_impl::{Buf, Str},
+    Int, RegexCache,
+};
+
+use super::{DefaultLine, LineReader};
+
+/// Values nested more deeply than this are treated as malformed, rather than risking overflowing
+/// the stack.
+const MAX_DEPTH: usize = 128;
+
+pub struct JsonReader<R> {
+    inner: R,
+    columns: HashMap<Box<[u8]>, usize>,
+    // Columns that were added since the last call to `new_columns`.
+    new_columns: Vec<(Str<'static>, Int)>,
+}
+
+impl<R> JsonReader<R> {
+    pub fn new(inner: R) -> JsonReader<R> {
+        JsonReader {
+            inner,
+            columns: Default::default(),
+            new_columns: Default::default(),
+        }
+    }
+
+    fn flatten(&mut self, line: &mut DefaultLine) {
+        // Assigning to columns works as it does for other input, so we need every column.
+        line.used_fields = FieldSet::all();
+        line.clear_fields();
+        let raw = line.line.clone();
+        let valid = raw.with_bytes(|bs| {
+            Flattener {
+                raw: &raw,
+                bs,
+                pos: 0,
+                path: Vec::new(),
+                columns: &mut self.columns,
+                new_columns: &mut self.new_columns,
+                fields: &mut line.fields,
+            }
+            .record()
+        });
+        // Records that are not valid JSON have empty fields. (Before any columns have been seen,
+        // there are no fields to fill, and they are split like any other line.)
+        if !valid {
+            line.clear_fields();
+        }
+        line.fields.resize_with(self.columns.len(), Str::default);
+    }
+}
+
+impl<R: LineReader<Line = DefaultLine>> LineReader for JsonReader<R> {
+    type Line = DefaultLine;
+    fn filename(&self) -> Str<'static> {
+        self.inner.filename()
+    }
+    fn check_utf8(&self) -> bool {
+        self.inner.check_utf8()
+    }
+    fn read_line(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<(bool, DefaultLine)> {
+        let mut line = DefaultLine::default();
+        let changed = self.read_line_reuse(pat, rc, &mut line)?;
+        Ok((changed, line))
+    }
+    fn read_line_reuse<'a, 'b: 'a>(
+        &'b mut self,
+        pat: &Str,
+        rc: &mut RegexCache,
+        old: &'a mut DefaultLine,
+    ) -> Result<bool> {
+        let changed = self.inner.read_line_reuse(pat, rc, old)?;
+        self.flatten(old);
+        Ok(changed)
+    }
+    fn read_state(&self) -> i64 {
+        self.inner.read_state()
+    }
+    fn next_file(&mut self) -> Result<bool> {
+        self.inner.next_file()
+    }
+    // Records are always parsed in full.
+    fn set_used_fields(&mut self, _used_fields: &FieldSet) {}
+    fn split_headers(&mut self) {}
+    fn new_columns(&mut self, mut f: impl FnMut(Str<'static>, Int)) {
+        for (name, col) in self.new_columns.drain(..) {
+            f(name, col)
+        }
+    }
+}
+
+fn owned(bs: &[u8]) -> Str<'static> {
+    if bs.is_empty() {
+        Str::default()
+    } else {
+        Buf::read_from_bytes(bs).into_str()
+    }
+}
+
+struct Flattener<'a> {
+    raw: &'a Str<'static>,
+    bs: &'a [u8],
+    pos: usize,
+    // The name of the column for the value currently being parsed.
+    path: Vec<u8>,
+    columns: &'a mut HashMap<Box<[u8]>, usize>,
+    new_columns: &'a mut Vec<(Str<'static>, Int)>,
+    fields: &'a mut Vec<Str<'static>>,
+}
+
+impl<'a> Flattener<'a> {
+    fn record(&mut self) -> bool {
+        self.skip_ws();
+        // Blank lines are empty records.
+        if self.pos == self.bs.len() {
+            return true;
+        }
+        if !self.value(0) {
+            return false;
+        }
+        self.skip_ws();
+        self.pos == self.bs.len()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.bs.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        self.skip_ws();
+        if self.bs.get(self.pos) == Some(&b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> bool {
+        if depth > MAX_DEPTH {
+            return false;
+        }
+        self.skip_ws();
+        match self.bs.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                if self.eat(b'}') {
+                    return true;
+                }
+                loop {
+                    self.skip_ws();
+                    let key = match self.string() {
+                        Some(key) => key,
+                        None => return false,
+                    };
+                    if !self.eat(b':') {
+                        return false;
+                    }
+                    let ok =
+                        self.nested(|path| key.with_bytes(|k| path.extend_from_slice(k)), depth);
+                    if !ok {
+                        return false;
+                    }
+                    if !self.eat(b',') {
+                        return self.eat(b'}');
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                if self.eat(b']') {
+                    return true;
+                }
+                let mut ix = itoa::Buffer::new();
+                for i in 0usize.. {
+                    let i = ix.format(i).as_bytes();
+                    if !self.nested(|path| path.extend_from_slice(i), depth) {
+                        return false;
+                    }
+                    if !self.eat(b',') {
+                        break;
+                    }
+                }
+                self.eat(b']')
+            }
+            Some(b'"') => match self.string() {
+                Some(s) => {
+                    self.push(s);
+                    true
+                }
+                None => false,
+            },
+            Some(_) => {
+                let start = self.pos;
+                while let Some(b) = self.bs.get(self.pos) {
+                    if matches!(b, b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n') {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let v = match &self.bs[start..self.pos] {
+                    b"true" => Str::from("1"),
+                    b"false" => Str::from("0"),
+                    b"null" => Str::default(),
+                    [b'-' | b'0'..=b'9', ..] => self.raw.slice(start, self.pos),
+                    _ => return false,
+                };
+                self.push(v);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Parse a value nested in an object or array, with `extend` appending its key to the path.
+    fn nested(&mut self, extend: impl FnOnce(&mut Vec<u8>), depth: usize) -> bool {
+        let len = self.path.len();
+        if len > 0 {
+            self.path.push(b'.');
+        }
+        extend(&mut self.path);
+        let ok = self.value(depth + 1);
+        self.path.truncate(len);
+        ok
+    }
+
+    fn string(&mut self) -> Option<Str<'static>> {
+        if self.bs.get(self.pos) != Some(&b'"') {
+            return None;
+        }
+        let start = self.pos + 1;
+        // Most strings contain no escapes, and can be sliced out of the record directly.
+        let end = start + memchr::memchr2(b'"', b'\\', &self.bs[start..])?;
+        if self.bs[end] == b'"' {
+            self.pos = end + 1;
+            return Some(self.raw.slice(start, end));
+        }
+        let mut buf = self.bs[start..end].to_vec();
+        let mut i = end;
+        loop {
+            match *self.bs.get(i)? {
+                b'"' => break,
+                b'\\' => {
+                    i += 1;
+                    let c = match *self.bs.get(i)? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let (c, len) = self.unicode_escape(i + 1)?;
+                            i += len;
+                            c
+                        }
+                        _ => return None,
+                    };
+                    buf.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    i += 1;
+                }
+                b => {
+                    buf.push(b);
+                    i += 1;
+                }
+            }
+        }
+        self.pos = i + 1;
+        Some(owned(&buf))
+    }
+
+    /// Decode the `\u` escape whose hex digits start at `i`, along with the low half of a
+    /// surrogate pair if one follows. Returns the character and the number of bytes consumed, not
+    /// counting the first `\u`.
+    fn unicode_escape(&self, i: usize) -> Option<(char, usize)> {
+        let hex = |i: usize| {
+            let digits = std::str::from_utf8(self.bs.get(i..i + 4)?).ok()?;
+            u32::from_str_radix(digits, 16).ok()
+        };
+        let hi = hex(i)?;
+        if (0xD800..0xDC00).contains(&hi) && self.bs.get(i + 4..i + 6) == Some(b"\\u") {
+            if let Some(lo @ 0xDC00..=0xDFFF) = hex(i + 6) {
+                let c = 0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00);
+                return Some((char::from_u32(c)?, 10));
+            }
+        }
+        // Unpaired surrogates are replaced, as they cannot be represented in UTF-8.
+        Some((char::from_u32(hi).unwrap_or(char::REPLACEMENT_CHARACTER), 4))
+    }
+
+    fn push(&mut self, v: Str<'static>) {
+        let col = match self.columns.get(&self.path[..]) {
+            Some(col) => *col,
+            None => {
+                let col = self.columns.len() + 1;
+                self.columns
+                    .insert(self.path.clone().into_boxed_slice(), col);
+                self.new_columns.push((owned(&self.path), col as Int));
+                col
+            }
+        };
+        if self.fields.len() < col {
+            self.fields.resize_with(col, Str::default);
+        }
+        self.fields[col - 1] = v;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flatten(reader: &mut JsonReader<()>, record: &str) -> Vec<String> {
+        let mut line = DefaultLine {
+            line: Str::from(record).unmoor(),
+            ..Default::default()
+        };
+        reader.flatten(&mut line);
+        line.fields.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn flatten_records() {
+        let mut reader = JsonReader::new(());
+        assert_eq!(
+            flatten(
+                &mut reader,
+                r#"{"a": {"b": 1.5e3, "c": "x\"y"}, "d": [true, null, "é😀"]}"#
+            ),
+            vec!["1.5e3", "x\"y", "1", "", "é😀"]
+        );
+        // Columns keep their numbers from earlier records; new ones are added at the end.
+        assert_eq!(
+            flatten(&mut reader, r#"{"e": false, "a": {"c": "z"}, "d": []}"#),
+            vec!["", "z", "", "", "", "0"]
+        );
+        let names: Vec<_> = (reader.new_columns.drain(..))
+            .map(|(name, col)| format!("{}={}", name, col))
+            .collect();
+        assert_eq!(
+            names,
+            vec!["a.b=1", "a.c=2", "d.0=3", "d.1=4", "d.2=5", "e=6"]
+        );
+    }
+
+    #[test]
+    fn invalid_records() {
+        let mut reader = JsonReader::new(());
+        assert_eq!(flatten(&mut reader, r#"{"a": 1}"#), vec!["1"]);
+        for bad in [
+            r#"{"a": 1"#,
+            r#"{"a" 1}"#,
+            r#"{"a": 1} x"#,
+            r#"{"a": tru}"#,
+            r#"{"a": "\q"}"#,
+            "not json",
+        ] {
+            assert_eq!(flatten(&mut reader, bad), vec![""], "{}", bad);
+        }
+        assert_eq!(flatten(&mut reader, "  "), vec![""]);
+        let deep = format!("{}{}", "[".repeat(MAX_DEPTH + 2), "]".repeat(MAX_DEPTH + 2));
+        assert_eq!(flatten(&mut reader, &deep), vec![""]);
+    }
+}
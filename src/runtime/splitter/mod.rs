@@ -7,9 +7,12 @@
 // TODO: add padding to the linereader trait
 pub mod batch;
 pub mod chunk;
+pub mod json;
+#[cfg(feature = "parquet_input")]
+pub mod parquet;
 pub mod regex;
 
-use super::str_impl::{Buf, Str, UniqueBuf};
+use super::str_impl::{Buf, SplitPos, Str, UniqueBuf};
 use super::utf8::{is_utf8, validate_utf8_clipped};
 use super::{Int, RegexCache};
 use crate::common::Result;
@@ -34,11 +37,18 @@ pub trait Line<'a>: Default {
     fn nf(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<usize>;
     fn get_col(&mut self, col: Int, pat: &Str, ofs: &Str, rc: &mut RegexCache) -> Result<Str<'a>>;
     fn set_col(&mut self, col: Int, s: &Str<'a>, pat: &Str, rc: &mut RegexCache) -> Result<()>;
+    // Truncate or extend the current record to `nf` fields, as an assignment to NF does.
+    fn set_nf(&mut self, nf: Int, pat: &Str, rc: &mut RegexCache) -> Result<()>;
 }
 
 pub trait LineReader: Sized {
     type Line: for<'a> Line<'a>;
     fn filename(&self) -> Str<'static>;
+    // The position of the current input file among those being read, counting from 1. Readers
+    // that cannot hand whole files to different worker threads may return 0.
+    fn file_index(&self) -> u32 {
+        0
+    }
     fn request_handles(&self, _size: usize) -> Vec<Box<dyn FnOnce() -> Self + Send>> {
         vec![]
     }
@@ -64,6 +74,13 @@ pub trait LineReader: Sized {
     fn read_state(&self) -> i64;
     fn next_file(&mut self) -> Result<bool>;
     fn set_used_fields(&mut self, used_fields: &FieldSet);
+    // Split the first line of each subsequent input file in full, regardless of the used field
+    // set. This lets us read the header of every input file with -H, after the used fields have
+    // been narrowed down to the columns named in the first header.
+    fn split_headers(&mut self);
+    // Pass the name and index of each column that has appeared since the last call to `f`. Only
+    // readers for self-describing formats like JSON name their columns; we add these to FI.
+    fn new_columns(&mut self, _f: impl FnMut(Str<'static>, Int)) {}
     // Whether or not this LineReader is configured to check for valid UTF-8. This is used to
     // propagate consistent options across multiple LineReader instances.
     fn check_utf8(&self) -> bool;
@@ -89,6 +106,10 @@ pub struct DefaultLine {
     line: Str<'static>,
     used_fields: FieldSet,
     fields: Vec<Str<'static>>,
+    // Where to resume splitting `line` if only its first few fields have been split. Fields are
+    // split when they are first used, and only as far as the one requested or the highest one the
+    // program is known to use, so that filters reading `$1` need not split the rest of the line.
+    rest: Option<SplitPos>,
     // Has someone assigned into `fields` without us regenerating `line`?
     // AWK lets you do
     //  $1 = "turnip"
@@ -106,16 +127,57 @@ impl Default for DefaultLine {
             line: Str::default(),
             used_fields: FieldSet::all(),
             fields: Vec::new(),
+            rest: None,
             diverged: false,
         }
     }
 }
 
 impl DefaultLine {
+    /// Forget the fields of the current line, so they are split again when they are next used.
+    pub(crate) fn clear_fields(&mut self) {
+        self.fields.clear();
+        self.rest = None;
+    }
+
     fn split_if_needed(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<()> {
-        if self.fields.is_empty() {
-            rc.split_regex(pat, &self.line, &self.used_fields, &mut self.fields)?;
+        self.split_to(usize::MAX, pat, rc)
+    }
+
+    // Split every field of the line, including any that were projected out. This is needed before
+    // modifying `fields`, as $0 is then rebuilt from `fields` alone.
+    //
+    // This is strictly more work than just reading all of the fields in the first place; so once
+    // we hit this condition we overwrite the used fields with all() so this doesn't happen again
+    // for a while.
+    fn split_all(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<()> {
+        if !self.diverged && self.used_fields != FieldSet::all() {
+            self.used_fields = FieldSet::all();
+            self.clear_fields();
         }
+        self.split_if_needed(pat, rc)
+    }
+
+    // Make sure fields up to `col` have been split (if the line has that many).
+    fn split_to(&mut self, col: usize, pat: &Str, rc: &mut RegexCache) -> Result<()> {
+        let start = match self.rest {
+            Some(pos) if col >= pos.field => pos,
+            None if self.fields.is_empty() && !self.diverged => SplitPos::default(),
+            _ => return Ok(()),
+        };
+        let limit = if self.used_fields == FieldSet::all() {
+            col
+        } else {
+            col.max(self.used_fields.max_value() as usize)
+        };
+        self.rest = rc.split_regex_prefix(
+            pat,
+            &self.line,
+            &self.used_fields,
+            start,
+            limit,
+            &mut self.fields,
+        )?;
         Ok(())
     }
 }
@@ -153,34 +215,12 @@ impl<'a> Line<'a> for DefaultLine {
         let res = if col == 0 && !self.diverged {
             self.line.clone()
         } else if col == 0 && self.diverged {
-            if self.used_fields != FieldSet::all() {
-                // We projected out fields, but now we have set one of the interior fields and need
-                // to print out $0. That means we have to split $0 in its entirety and then copy
-                // over the fields that were already set.
-                //
-                // This is strictly more work than just reading all of the fields in the first
-                // place; so once we hit this condition we overwrite the used fields with all() so
-                // this doesn't happen again for a while.
-                let old_set = std::mem::replace(&mut self.used_fields, FieldSet::all());
-                let mut new_vec = Vec::with_capacity(self.fields.len());
-                rc.split_regex(pat, &self.line, &self.used_fields, &mut new_vec)?;
-
-                for (i, field) in self.fields.iter().enumerate().rev() {
-                    if i >= new_vec.len() {
-                        new_vec.resize_with(i + 1, Str::default);
-                    }
-                    if old_set.get(i + 1) {
-                        new_vec[i] = field.clone()
-                    }
-                }
-                self.fields = new_vec;
-            }
             let res = ofs.join_slice(&self.fields[..]);
             self.line = res.clone();
             self.diverged = false;
             res
         } else {
-            self.split_if_needed(pat, rc)?;
+            self.split_to(col as usize, pat, rc)?;
             self.fields
                 .get((col - 1) as usize)
                 .cloned()
@@ -191,13 +231,14 @@ impl<'a> Line<'a> for DefaultLine {
     fn set_col(&mut self, col: Int, s: &Str<'a>, pat: &Str, rc: &mut RegexCache) -> Result<()> {
         if col == 0 {
             self.line = s.clone().unmoor();
-            self.fields.clear();
+            self.clear_fields();
+            self.diverged = false;
             return Ok(());
         }
         if col < 0 {
             return err!("attempt to access field {}; field must be nonnegative", col);
         }
-        self.split_if_needed(pat, rc)?;
+        self.split_all(pat, rc)?;
         let col = col as usize - 1;
         if col >= self.fields.len() {
             self.fields.resize_with(col + 1, Str::default);
@@ -206,6 +247,15 @@ impl<'a> Line<'a> for DefaultLine {
         self.diverged = true;
         Ok(())
     }
+    fn set_nf(&mut self, nf: Int, pat: &Str, rc: &mut RegexCache) -> Result<()> {
+        if nf < 0 {
+            return err!("attempt to set NF to {}; NF must be nonnegative", nf);
+        }
+        self.split_all(pat, rc)?;
+        self.fields.resize_with(nf as usize, Str::default);
+        self.diverged = true;
+        Ok(())
+    }
 }
 
 pub struct ChainedReader<R>(Vec<R>, /*check_utf8=*/ bool);
@@ -285,6 +335,16 @@ where
             i.set_used_fields(used_fields);
         }
     }
+    fn split_headers(&mut self) {
+        for i in self.0.iter_mut() {
+            i.split_headers();
+        }
+    }
+    fn new_columns(&mut self, mut f: impl FnMut(Str<'static>, Int)) {
+        for i in self.0.iter_mut() {
+            i.new_columns(&mut f);
+        }
+    }
 }
 
 // Buffer management and io
@@ -323,6 +383,11 @@ struct Reader<R> {
 
     // Validate input as UTF-8
     check_utf8: bool,
+
+    // Hand data to the splitter as soon as it is read, rather than once a full chunk has been
+    // read. Input then only ends with a read of zero bytes, rather than a short read. This is for
+    // input that arrives a little at a time, where waiting for a full chunk could block forever.
+    eager: bool,
 }
 
 fn read_to_slice(r: &mut impl Read, mut buf: &mut [u8]) -> Result<usize> {
@@ -348,6 +413,19 @@ fn read_to_slice(r: &mut impl Read, mut buf: &mut [u8]) -> Result<usize> {
     Ok(read)
 }
 
+fn read_once(r: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    loop {
+        match r.read(buf) {
+            Ok(n) => return Ok(n),
+            Err(e) => match e.kind() {
+                ErrorKind::Interrupted => continue,
+                ErrorKind::UnexpectedEof => return Ok(0),
+                _ => return err!("read error {}", e),
+            },
+        }
+    }
+}
+
 impl<R: Read> Reader<R> {
     pub(crate) fn new(r: R, chunk_size: usize, padding: usize, check_utf8: bool) -> Self {
         Reader {
@@ -361,6 +439,7 @@ impl<R: Read> Reader<R> {
             state: ReaderState::OK,
             last_len: 0,
             check_utf8,
+            eager: false,
         }
     }
 
@@ -417,11 +496,11 @@ impl<R: Read> Reader<R> {
         &mut self,
         consume: usize,
     ) -> Result<(UniqueBuf, /*end*/ usize, /*input_end*/ usize)> {
-        let mut done = false;
+        let done;
         let plen = self.input_end.saturating_sub(consume);
         // Double the chunk size if it is too small to read a sufficient batch given the prefix
-        // size.
-        if plen > self.chunk_size / 2 {
+        // size. (The prefix can be larger than a chunk if invalid UTF-8 was replaced in it.)
+        while plen > self.chunk_size / 2 {
             self.chunk_size = std::cmp::max(self.chunk_size * 2, 1024);
         }
         // NB: UniqueBuf fills the allocation with zeros.
@@ -432,33 +511,35 @@ impl<R: Read> Reader<R> {
             std::ptr::copy_nonoverlapping(self.buf.as_ptr().add(consume), data.as_mut_ptr(), plen);
         }
         let mut bytes = &mut data.as_mut_bytes()[..self.chunk_size];
-        let bytes_read = plen + read_to_slice(&mut self.inner, &mut bytes[plen..])?;
+        let bytes_read = if self.eager {
+            let n = read_once(&mut self.inner, &mut bytes[plen..])?;
+            done = n == 0;
+            plen + n
+        } else {
+            let n = plen + read_to_slice(&mut self.inner, &mut bytes[plen..])?;
+            done = n != self.chunk_size;
+            n
+        };
         if bytes_read != self.chunk_size {
-            done = true;
             bytes = &mut bytes[..bytes_read];
         }
         let mut ulen = bytes.len();
         if self.check_utf8 {
-            ulen = {
-                let opt = if done {
-                    if is_utf8(bytes) {
-                        Some(bytes.len())
-                    } else {
-                        None
+            let valid = if done {
+                is_utf8(bytes).then_some(bytes.len())
+            } else {
+                validate_utf8_clipped(bytes)
+            };
+            match valid {
+                Some(u) => ulen = u,
+                None => {
+                    let (data, ulen, input_end) = self.replace_invalid_utf8(bytes, done);
+                    if done {
+                        self.state = ReaderState::Eof;
                     }
-                } else {
-                    validate_utf8_clipped(bytes)
-                };
-                if let Some(u) = opt {
-                    u
-                } else {
-                    // Invalid utf8. Get the error.
-                    return match std::str::from_utf8(bytes) {
-                        Ok(_) => err!("bug in UTF8 validation!"),
-                        Err(e) => err!("invalid utf8: {}", e),
-                    };
+                    return Ok((data, ulen, input_end));
                 }
-            };
+            }
         }
 
         if done {
@@ -466,4 +547,45 @@ impl<R: Read> Reader<R> {
         }
         Ok((data, ulen, bytes_read))
     }
+
+    /// Copy `bytes` into a new buffer, replacing invalid UTF-8 sequences with U+FFFD. An
+    /// incomplete sequence at the end of `bytes` is left after the end of the valid data unless
+    /// this is the last chunk of input, to be completed by the next one.
+    fn replace_invalid_utf8(
+        &self,
+        bytes: &[u8],
+        done: bool,
+    ) -> (UniqueBuf, /*end*/ usize, /*input_end*/ usize) {
+        const REPLACEMENT: &[u8] = "\u{FFFD}".as_bytes();
+        let mut valid = Vec::with_capacity(bytes.len() + REPLACEMENT.len());
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            match std::str::from_utf8(rest) {
+                Ok(s) => {
+                    valid.extend_from_slice(s.as_bytes());
+                    rest = &[];
+                }
+                Err(e) => {
+                    let (ok, bad) = rest.split_at(e.valid_up_to());
+                    valid.extend_from_slice(ok);
+                    match e.error_len() {
+                        None if !done => {
+                            rest = bad;
+                            break;
+                        }
+                        len => {
+                            valid.extend_from_slice(REPLACEMENT);
+                            rest = &bad[len.unwrap_or(bad.len())..];
+                        }
+                    }
+                }
+            }
+        }
+        let (ulen, input_end) = (valid.len(), valid.len() + rest.len());
+        let mut data = UniqueBuf::new(input_end + self.padding);
+        let dst = data.as_mut_bytes();
+        dst[..ulen].copy_from_slice(&valid);
+        dst[ulen..input_end].copy_from_slice(rest);
+        (data, ulen, input_end)
+    }
 }
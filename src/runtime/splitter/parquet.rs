@@ -0,0 +1,437 @@
+//! Reading Parquet files, for `-i parquet`.
+//!
+//! Each row of a file is a record, and each column is a field. Columns are numbered in the order
+//! they appear in the file's schema, and `FI` maps their names to those numbers; the fields of
+//! struct columns become columns of their own, named by their path (`a.b`), as they are for
+//! `-i jsonl`. When several files are read, a column name keeps the number it was first given,
+//! and columns that only appear in later files are numbered after the rest.
+//!
+//! Integers and floating-point numbers are formatted the way frawk prints them, so converting a
+//! field back to a number gives the value stored in the file. Booleans become 1 and 0, nulls
+//! become the empty string, and values of other types (dates, decimals, lists, ...) are written
+//! out in Arrow's display format. Columns that the program never refers to are not decoded at
+//! all.
+use std::fs::File;
+use std::io::{self, Read};
+
+use arrow_array::{cast::AsArray, types::*, Array, ArrayRef, GenericByteArray, RecordBatch};
+use arrow_buffer::ArrowNativeType;
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use arrow_schema::{DataType, Field};
+use hashbrown::HashMap;
+use parquet::{
+    arrow::{
+        arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder},
+        ProjectionMask,
+    },
+    file::reader::ChunkReader,
+};
+
+use crate::common::Result;
+use crate::pushdown::FieldSet;
+use crate::runtime::{
+    record_io_error,
+    str_impl::{Buf, Str},
+    Float, Int, RegexCache,
+};
+
+use super::{DefaultLine, LineReader, ReaderState};
+
+/// The number of rows decoded at a time.
+const BATCH_SIZE: usize = 1024;
+
+pub struct ParquetReader {
+    // Files yet to be read, in reverse order.
+    files: Vec<String>,
+    name: Str<'static>,
+    file: Option<ParquetRecordBatchReader>,
+    // The path to each column of the current file that we read (a list of indexes into the
+    // columns of a batch and then into the fields of nested structs), and the column it fills.
+    leaves: Vec<(Vec<usize>, usize)>,
+    // The columns of the current batch, and the row within it that is read next.
+    batch: Vec<(usize, Vec<Str<'static>>)>,
+    row: usize,
+    rows: usize,
+    columns: HashMap<String, usize>,
+    // Columns that were added since the last call to `new_columns`.
+    new_columns: Vec<(Str<'static>, Int)>,
+    used_fields: FieldSet,
+    state: ReaderState,
+    // Set when a file is opened, so that the first row read from it updates FILENAME and FNR.
+    changed: bool,
+    check_utf8: bool,
+}
+
+impl ParquetReader {
+    pub fn new(files: impl Iterator<Item = String>, check_utf8: bool) -> ParquetReader {
+        let mut files: Vec<_> = files.collect();
+        files.reverse();
+        ParquetReader {
+            files,
+            name: Str::default(),
+            file: None,
+            leaves: Vec::new(),
+            batch: Vec::new(),
+            row: 0,
+            rows: 0,
+            columns: Default::default(),
+            new_columns: Vec::new(),
+            used_fields: FieldSet::all(),
+            state: ReaderState::OK,
+            changed: false,
+            check_utf8,
+        }
+    }
+
+    /// Open the next input file, returning false if there are none left. As with other input,
+    /// files that cannot be read are skipped with a warning.
+    fn next_input(&mut self) -> bool {
+        while let Some(name) = self.files.pop() {
+            let res = if name == "-" {
+                let mut buf = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut buf)
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| self.open(bytes::Bytes::from(buf)))
+            } else {
+                File::open(name.as_str())
+                    .map_err(|e| e.to_string())
+                    .and_then(|file| self.open(file))
+            };
+            match res {
+                Ok(reader) => {
+                    self.name = Str::from(name.as_str()).unmoor();
+                    self.file = Some(reader);
+                    self.changed = true;
+                    return true;
+                }
+                Err(e) => {
+                    record_io_error(io::ErrorKind::Other);
+                    eprintln_ignore!("warning: skipping input file {}: {}", name, e)
+                }
+            }
+        }
+        false
+    }
+
+    fn open<T: ChunkReader + 'static>(
+        &mut self,
+        input: T,
+    ) -> std::result::Result<ParquetRecordBatchReader, String> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(input).map_err(|e| e.to_string())?;
+        let schema = builder.schema().clone();
+        // $0 is made up of every column, and we cannot tell which columns are named in FI.
+        let used = &self.used_fields;
+        let read_all = used == &FieldSet::all() || used.get(0) || used.has_fi();
+        self.leaves.clear();
+        let mut roots = Vec::new();
+        let mut path = Vec::new();
+        for (i, field) in schema.fields().iter().enumerate() {
+            let start = self.leaves.len();
+            // Batches only hold the columns we project, so the path starts from the column's
+            // position among those.
+            path.push(roots.len());
+            self.add_leaves(field, &mut String::new(), &mut path);
+            path.clear();
+            if read_all || (self.leaves[start..].iter()).any(|(_, col)| self.used_fields.get(*col))
+            {
+                roots.push(i);
+            } else {
+                self.leaves.truncate(start);
+            }
+        }
+        let mask = ProjectionMask::roots(builder.parquet_schema(), roots);
+        (builder.with_projection(mask).with_batch_size(BATCH_SIZE))
+            .build()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Number the columns for `field` and any fields nested within it, appending them to
+    /// `self.leaves`.
+    fn add_leaves(&mut self, field: &Field, name: &mut String, path: &mut Vec<usize>) {
+        let len = name.len();
+        if len > 0 {
+            name.push('.');
+        }
+        name.push_str(field.name());
+        if let DataType::Struct(fields) = field.data_type() {
+            for (i, f) in fields.iter().enumerate() {
+                path.push(i);
+                self.add_leaves(f, name, path);
+                path.pop();
+            }
+        } else {
+            let col = match self.columns.get(name.as_str()) {
+                Some(col) => *col,
+                None => {
+                    let col = self.columns.len() + 1;
+                    self.columns.insert(name.clone(), col);
+                    self.new_columns
+                        .push((Str::from(name.as_str()).unmoor(), col as Int));
+                    col
+                }
+            };
+            self.leaves.push((path.clone(), col));
+        }
+        name.truncate(len);
+    }
+
+    fn load_batch(&mut self, batch: &RecordBatch) {
+        self.batch.clear();
+        for (path, col) in self.leaves.iter() {
+            let mut array: &ArrayRef = batch.column(path[0]);
+            for i in &path[1..] {
+                array = array.as_struct().column(*i);
+            }
+            let mut values = Vec::with_capacity(array.len());
+            to_strs(array, &mut values);
+            self.batch.push((*col, values));
+        }
+        self.row = 0;
+        self.rows = batch.num_rows();
+    }
+}
+
+impl LineReader for ParquetReader {
+    type Line = DefaultLine;
+    fn filename(&self) -> Str<'static> {
+        self.name.clone()
+    }
+    fn check_utf8(&self) -> bool {
+        self.check_utf8
+    }
+    fn read_line(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<(bool, DefaultLine)> {
+        let mut line = DefaultLine::default();
+        let changed = self.read_line_reuse(pat, rc, &mut line)?;
+        Ok((changed, line))
+    }
+    fn read_line_reuse<'a, 'b: 'a>(
+        &'b mut self,
+        _pat: &Str,
+        _rc: &mut RegexCache,
+        old: &'a mut DefaultLine,
+    ) -> Result<bool> {
+        old.line = Str::default();
+        old.used_fields = FieldSet::all();
+        old.clear_fields();
+        loop {
+            if self.row < self.rows {
+                old.fields.resize_with(self.columns.len(), Str::default);
+                for (col, values) in self.batch.iter() {
+                    old.fields[col - 1] = values[self.row].clone();
+                }
+                self.row += 1;
+                // There is no text for the record as a whole; $0 is built from the fields when
+                // it is asked for.
+                old.diverged = true;
+                self.state = ReaderState::OK;
+                return Ok(std::mem::replace(&mut self.changed, false));
+            }
+            let next = match self.file.as_mut() {
+                Some(file) => file.next(),
+                None => {
+                    if self.next_input() {
+                        continue;
+                    }
+                    old.diverged = false;
+                    self.state = ReaderState::Eof;
+                    return Ok(false);
+                }
+            };
+            match next {
+                Some(Ok(batch)) => self.load_batch(&batch),
+                Some(Err(e)) => {
+                    self.state = ReaderState::Error;
+                    return err!("failed to read {}: {}", self.name, e);
+                }
+                None => self.file = None,
+            }
+        }
+    }
+    fn read_state(&self) -> i64 {
+        self.state as i64
+    }
+    fn next_file(&mut self) -> Result<bool> {
+        self.file = None;
+        self.rows = 0;
+        Ok(!self.files.is_empty())
+    }
+    fn set_used_fields(&mut self, used_fields: &FieldSet) {
+        self.used_fields = used_fields.clone();
+    }
+    fn split_headers(&mut self) {}
+    fn new_columns(&mut self, mut f: impl FnMut(Str<'static>, Int)) {
+        for (name, col) in self.new_columns.drain(..) {
+            f(name, col)
+        }
+    }
+}
+
+/// Format each value in `array`, appending it to `out`.
+fn to_strs(array: &ArrayRef, out: &mut Vec<Str<'static>>) {
+    macro_rules! convert {
+        ($arr:expr, |$v:ident| $e:expr) => {{
+            let arr = $arr;
+            out.extend((0..arr.len()).map(|i| {
+                if arr.is_null(i) {
+                    Str::default()
+                } else {
+                    let $v = arr.value(i);
+                    $e
+                }
+            }))
+        }};
+    }
+    match array.data_type() {
+        DataType::Null => out.resize_with(array.len(), Str::default),
+        DataType::Boolean => convert!(array.as_boolean(), |v| Str::from(if v { "1" } else { "0" })),
+        DataType::Int8 => convert!(array.as_primitive::<Int8Type>(), |v| Str::from(v as Int)),
+        DataType::Int16 => convert!(array.as_primitive::<Int16Type>(), |v| Str::from(v as Int)),
+        DataType::Int32 => convert!(array.as_primitive::<Int32Type>(), |v| Str::from(v as Int)),
+        DataType::Int64 => convert!(array.as_primitive::<Int64Type>(), |v| Str::from(v as Int)),
+        DataType::UInt8 => convert!(array.as_primitive::<UInt8Type>(), |v| Str::from(v as Int)),
+        DataType::UInt16 => convert!(array.as_primitive::<UInt16Type>(), |v| Str::from(v as Int)),
+        DataType::UInt32 => convert!(array.as_primitive::<UInt32Type>(), |v| Str::from(v as Int)),
+        DataType::UInt64 => convert!(array.as_primitive::<UInt64Type>(), |v| {
+            // Values too large for an Int are kept exactly, rather than wrapping around.
+            Str::copy_from_bytes(itoa::Buffer::new().format(v).as_bytes())
+        }),
+        DataType::Float16 => convert!(array.as_primitive::<Float16Type>(), |v| Str::from(
+            v.to_f64() as Float
+        )),
+        DataType::Float32 => convert!(array.as_primitive::<Float32Type>(), |v| Str::from(
+            v as Float
+        )),
+        DataType::Float64 => convert!(array.as_primitive::<Float64Type>(), |v| Str::from(
+            v as Float
+        )),
+        DataType::Utf8 => bytes_to_strs(array.as_string::<i32>(), out),
+        DataType::LargeUtf8 => bytes_to_strs(array.as_string::<i64>(), out),
+        DataType::Binary => bytes_to_strs(array.as_binary::<i32>(), out),
+        DataType::LargeBinary => bytes_to_strs(array.as_binary::<i64>(), out),
+        _ => match ArrayFormatter::try_new(array.as_ref(), &FormatOptions::default()) {
+            Ok(fmt) => out.extend((0..array.len()).map(|i| {
+                if array.is_null(i) {
+                    Str::default()
+                } else {
+                    Buf::read_from_bytes(fmt.value(i).to_string().as_bytes()).into_str()
+                }
+            })),
+            // Types Arrow cannot display have no sensible text, so their fields are empty.
+            Err(_) => out.resize_with(array.len(), Str::default),
+        },
+    }
+}
+
+/// Copy the values of a string or binary column into a single buffer, with each field a slice of
+/// it.
+fn bytes_to_strs<T: ByteArrayType>(arr: &GenericByteArray<T>, out: &mut Vec<Str<'static>>) {
+    let offsets = arr.value_offsets();
+    let (start, end) = (offsets[0].as_usize(), offsets[arr.len()].as_usize());
+    let buf = Buf::read_from_bytes(&arr.value_data()[start..end]);
+    out.extend((0..arr.len()).map(|i| {
+        if arr.is_null(i) {
+            Str::default()
+        } else {
+            buf.slice_to_str(
+                offsets[i].as_usize() - start,
+                offsets[i + 1].as_usize() - start,
+            )
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{
+        BooleanArray, Float64Array, Int32Array, Int64Array, StringArray, StructArray, UInt64Array,
+    };
+    use std::sync::Arc;
+
+    fn write_file(path: &std::path::Path, cols: Vec<(&str, ArrayRef)>) {
+        let batch = RecordBatch::try_from_iter(cols).unwrap();
+        let file = File::create(path).unwrap();
+        let mut w = parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None).unwrap();
+        w.write(&batch).unwrap();
+        w.close().unwrap();
+    }
+
+    fn read_all(reader: &mut ParquetReader) -> Vec<(bool, Vec<String>)> {
+        let mut rc = RegexCache::default();
+        let mut line = DefaultLine::default();
+        let mut res = Vec::new();
+        loop {
+            let changed = reader
+                .read_line_reuse(&Str::from(" "), &mut rc, &mut line)
+                .unwrap();
+            if reader.read_state() == 0 {
+                return res;
+            }
+            let fields = line.fields.iter().map(|s| s.to_string()).collect();
+            res.push((changed, fields));
+        }
+    }
+
+    #[test]
+    fn read_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.parquet"), dir.path().join("b.parquet"));
+        let user = StructArray::from(vec![(
+            Arc::new(Field::new("name", DataType::Utf8, false)),
+            Arc::new(StringArray::from(vec!["ann", "bob"])) as ArrayRef,
+        )]);
+        write_file(
+            &a,
+            vec![
+                ("id", Arc::new(Int64Array::from(vec![Some(1), None]))),
+                ("price", Arc::new(Float64Array::from(vec![1.5, 2.0]))),
+                ("user", Arc::new(user)),
+                ("ok", Arc::new(BooleanArray::from(vec![true, false]))),
+                ("big", Arc::new(UInt64Array::from(vec![u64::MAX, 0]))),
+            ],
+        );
+        write_file(
+            &b,
+            vec![
+                ("extra", Arc::new(Int32Array::from(vec![-7]))),
+                ("price", Arc::new(Float64Array::from(vec![0.25]))),
+            ],
+        );
+        let paths = [&a, &b].map(|p| p.to_str().unwrap().to_string());
+        let files = || paths.iter().cloned();
+        let row =
+            |changed, fields: &[&str]| (changed, fields.iter().map(|s| s.to_string()).collect());
+
+        let mut reader = ParquetReader::new(files(), false);
+        assert_eq!(
+            read_all(&mut reader),
+            vec![
+                row(true, &["1", "1.5", "ann", "1", "18446744073709551615"]),
+                row(false, &["", "2", "bob", "0", "0"]),
+                // Columns keep their numbers from earlier files; new ones are added at the end.
+                row(true, &["", "0.25", "", "", "", "-7"]),
+            ]
+        );
+        let mut names = Vec::new();
+        reader.new_columns(|name, col| names.push(format!("{}={}", name, col)));
+        assert_eq!(
+            names,
+            vec!["id=1", "price=2", "user.name=3", "ok=4", "big=5", "extra=6"]
+        );
+
+        // Columns that are not used are not read.
+        let mut reader = ParquetReader::new(files(), false);
+        let mut used = FieldSet::singleton(2);
+        used.set(6);
+        reader.set_used_fields(&used);
+        assert_eq!(
+            read_all(&mut reader),
+            vec![
+                row(true, &["", "1.5", "", "", ""]),
+                row(false, &["", "2", "", "", ""]),
+                row(true, &["", "0.25", "", "", "", "-7"]),
+            ]
+        );
+    }
+}
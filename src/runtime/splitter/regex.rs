@@ -15,6 +15,7 @@ pub struct RegexSplitter<R> {
     used_fields: FieldSet,
     // Used to trigger updating FILENAME on the first read.
     start: bool,
+    split_headers: bool,
 }
 
 impl<R: Read> LineReader for RegexSplitter<R> {
@@ -37,11 +38,17 @@ impl<R: Read> LineReader for RegexSplitter<R> {
     ) -> Result<bool> {
         let start = self.start;
         if start {
-            old.used_fields = self.used_fields.clone();
+            // Lines are split lazily using their own field set, so a header split in full means
+            // the rest of the file is too.
+            old.used_fields = if self.split_headers {
+                FieldSet::all()
+            } else {
+                self.used_fields.clone()
+            };
         }
         self.start = false;
         old.diverged = false;
-        old.fields.clear();
+        old.clear_fields();
         rc.with_regex(pat, |re| {
             old.line = self.read_line_regex(re);
         })?;
@@ -51,10 +58,16 @@ impl<R: Read> LineReader for RegexSplitter<R> {
     fn read_line(&mut self, pat: &Str, rc: &mut super::RegexCache) -> Result<(bool, Self::Line)> {
         let start = self.start;
         self.start = false;
+        let used_fields = if start && self.split_headers {
+            FieldSet::all()
+        } else {
+            self.used_fields.clone()
+        };
         let line = rc.with_regex(pat, |re| DefaultLine {
             line: self.read_line_regex(re),
             fields: Default::default(),
-            used_fields: self.used_fields.clone(),
+            used_fields: used_fields.clone(),
+            rest: None,
             diverged: false,
         })?;
         Ok((/* file changed */ start, line))
@@ -70,6 +83,9 @@ impl<R: Read> LineReader for RegexSplitter<R> {
     fn set_used_fields(&mut self, used_fields: &FieldSet) {
         self.used_fields = used_fields.clone();
     }
+    fn split_headers(&mut self) {
+        self.split_headers = true;
+    }
 }
 
 impl<R: Read> RegexSplitter<R> {
@@ -79,9 +95,25 @@ impl<R: Read> RegexSplitter<R> {
             name: name.into(),
             used_fields: FieldSet::all(),
             start: true,
+            split_headers: false,
         }
     }
 
+    /// Split records as soon as their input has been read, rather than once a full chunk of input
+    /// is available.
+    pub(crate) fn set_eager(&mut self) {
+        self.reader.eager = true;
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader.inner
+    }
+
+    /// Stop splitting, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader.inner
+    }
+
     pub fn read_line_regex(&mut self, pat: &Regex) -> Str<'static> {
         // We keep this as a separate method because it helps in writing tests.
         let (res, consumed) = self.read_line_inner(pat);
@@ -99,8 +131,13 @@ impl<R: Read> RegexSplitter<R> {
             // makes it harder for us to call mutable methods like advance in the body, so just get
             // the start and end pointers.
             match pat.find(s).map(|m| (m.start(), m.end())) {
-                // We need this check in case the regex matches across a chunk boundary.
-                Some((start, end)) if end + self.reader.start < self.reader.end => {
+                // We need this check in case the regex matches across a chunk boundary. When
+                // reading eagerly, the next chunk may not arrive for a while, so we do not wait for
+                // it if the separator is a literal string, which cannot match any more bytes.
+                Some((start, end))
+                    if end + self.reader.start < self.reader.end
+                        || (self.reader.eager && is_literal(pat)) =>
+                {
                     // Valid offsets guaranteed by correctness of regex `find`.
                     let res = self
                         .reader
@@ -173,6 +210,10 @@ impl<R: Read> RegexSplitter<R> {
     }
 }
 
+fn is_literal(pat: &Regex) -> bool {
+    regex::escape(pat.as_str()) == pat.as_str()
+}
+
 #[cfg(test)]
 mod tests {
     // need to benchmark batched splitting vs. regular splitting to get a feel for things.
@@ -287,6 +328,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_replace_invalid_utf8() {
+        let chunk_size = 1 << 9;
+        let mut bs = Vec::new();
+        bs.extend(crate::test_string_constants::PRIDE_PREJUDICE_CH2.bytes());
+        // A stray byte, a truncated character straddling the first chunk boundary, and a run of
+        // invalid bytes long enough to make the replaced text larger than a chunk.
+        bs[10] = 0xff;
+        bs[chunk_size - 1] = "學".as_bytes()[0];
+        bs[chunk_size] = "學".as_bytes()[1];
+        bs.splice(2000..2000, std::iter::repeat_n(0xfe, chunk_size));
+        bs.extend(&"學".as_bytes()[..2]);
+
+        let s = String::from_utf8_lossy(&bs);
+        let c = Cursor::new(bs.clone());
+        let mut rdr = RegexSplitter::new(c, chunk_size, "", /*check_utf8=*/ true);
+        let mut lines = Vec::new();
+        while !rdr.reader.is_eof() {
+            let line = rdr.read_line_regex(&LINE).upcast();
+            assert!(rdr.read_state() != -1);
+            lines.push(line);
+        }
+        let expected: Vec<_> = LINE.split(s.as_bytes()).map(ref_str).collect();
+        assert_eq!(lines, expected);
+    }
+
     #[test]
     fn test_clipped_chunk_split_random() {
         const N_RUNS: usize = 5;
@@ -329,6 +396,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_eager_split() {
+        // Hands out one piece per read.
+        struct Pieces(Vec<&'static [u8]>);
+        impl Read for Pieces {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                let piece = self.0.remove(0);
+                buf[..piece.len()].copy_from_slice(piece);
+                Ok(piece.len())
+            }
+        }
+        let pieces = Pieces(vec![b"a\nb", b"c\n", b"d"]);
+        let mut rdr = RegexSplitter::new(pieces, 512, "", /*check_utf8=*/ false);
+        rdr.set_eager();
+        let read_line = |rdr: &mut RegexSplitter<Pieces>| {
+            let line: Str = rdr.read_line_regex(&LINE).upcast();
+            (line, rdr.get_mut().0.len())
+        };
+        // Each line is returned without reading past the piece that ends it.
+        assert_eq!(read_line(&mut rdr), (ref_str(b"a"), 2));
+        assert_eq!(read_line(&mut rdr), (ref_str(b"bc"), 1));
+        assert_eq!(read_line(&mut rdr), (ref_str(b"d"), 0));
+        assert!(rdr.reader.is_eof());
+    }
+
     fn bytes(n: usize, line_pct: f64, space_pct: f64) -> Vec<u8> {
         let mut res = Vec::with_capacity(n);
         use rand::distributions::{Distribution, Uniform};
@@ -14,6 +14,7 @@ use smallvec::SmallVec;
 
 use std::alloc::{alloc_zeroed, dealloc, realloc, Layout};
 use std::cell::{Cell, UnsafeCell};
+use std::cmp::{self, Ordering};
 use std::hash::{Hash, Hasher};
 use std::io::{self, Write};
 use std::marker::PhantomData;
@@ -31,6 +32,32 @@ use std::rc::Rc;
 use std::slice;
 use std::str;
 
+/// The separator between fields passed to `Str::split_prefix`.
+#[derive(Copy, Clone)]
+pub enum FieldSep<'a> {
+    /// Runs of spaces and tabs.
+    Blanks,
+    Byte(u8),
+    Regex(&'a Regex),
+}
+
+/// How far a string has been split into fields: `offset` is the byte just past the last
+/// separator consumed, and `field` is the number of the next field.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SplitPos {
+    pub offset: usize,
+    pub field: usize,
+}
+
+impl Default for SplitPos {
+    fn default() -> SplitPos {
+        SplitPos {
+            offset: 0,
+            field: 1,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[repr(usize)]
 enum StrTag {
@@ -58,6 +85,8 @@ impl StrTag {
 #[repr(transparent)]
 struct Inline(u128);
 const MAX_INLINE_SIZE: usize = 15;
+// See `Str::try_append`.
+const MIN_APPEND_LEN: usize = 256;
 
 impl Default for Inline {
     fn default() -> Inline {
@@ -210,7 +239,7 @@ impl<'a> From<Literal<'a>> for StrRep<'a> {
 }
 
 impl<'a> StrRep<'a> {
-    fn len(&mut self) -> usize {
+    fn len(&self) -> usize {
         match self.get_tag() {
             StrTag::Boxed | StrTag::Literal | StrTag::Concat => self.low as usize,
             StrTag::Shared => unsafe {
@@ -222,12 +251,16 @@ impl<'a> StrRep<'a> {
     unsafe fn view_as_inline<R>(&self, f: impl FnOnce(&Inline) -> R) -> R {
         f(mem::transmute::<&StrRep<'a>, &Inline>(self))
     }
-    unsafe fn view_as<T, R>(&mut self, f: impl FnOnce(&T) -> R) -> R {
-        let old = self.hi;
-        self.hi = old & !0x7;
-        let res = f(mem::transmute::<&mut StrRep<'a>, &T>(self));
-        self.hi = old;
-        res
+    // View `self` as the variant `T` named by its tag. The tag is cleared in a copy of the
+    // representation rather than in place, so reading a string never writes to it; `f` must not
+    // hold on to references into the copy (pointers into the buffer it refers to are fine).
+    unsafe fn view_as<T, R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let untagged = mem::ManuallyDrop::new(StrRep::<'a> {
+            hi: self.hi & !0x7,
+            low: self.low,
+            _marker: PhantomData,
+        });
+        f(mem::transmute::<&StrRep<'a>, &T>(&untagged))
     }
     unsafe fn drop_as<T>(&mut self) {
         let old = self.hi;
@@ -304,9 +337,6 @@ impl<'a> UniqueStr<'a> {
     pub fn into_str(self) -> Str<'a> {
         self.0
     }
-    pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
-    }
 
     // TODO: is this safe for INLINE values?
     // Seems like we aren't guaranteed that inlines are valid for all of <'a>
@@ -316,7 +346,7 @@ impl<'a> UniqueStr<'a> {
         unsafe { &*self.0.get_bytes() }
     }
     pub fn clone_str(&self) -> Str<'a> {
-        let rep = unsafe { self.0.rep_mut() };
+        let rep = unsafe { self.0.rep() };
         match rep.get_tag() {
             StrTag::Inline | StrTag::Literal => self.0.clone(),
             StrTag::Boxed => unsafe {
@@ -339,12 +369,12 @@ impl<'a> UniqueStr<'a> {
 impl<'a> From<Str<'a>> for UniqueStr<'a> {
     fn from(s: Str<'a>) -> UniqueStr<'a> {
         unsafe {
-            let rep = s.rep_mut();
-            match rep.get_tag() {
+            match s.rep().get_tag() {
                 StrTag::Inline | StrTag::Literal => return UniqueStr(s),
                 StrTag::Shared | StrTag::Concat => s.force(),
                 StrTag::Boxed => {}
             };
+            let rep = s.rep();
             debug_assert_eq!(StrTag::Boxed, rep.get_tag());
             // We have a box in place, check its refcount
             if let Some(boxed) = rep.view_as(|b: &Boxed| {
@@ -388,7 +418,8 @@ impl<'a> Str<'a> {
     //
     // Callers _must_ ensure they are not aliasing mutable references this way.
     // In practice this is a murky question given the fact that all strings are
-    // "logically" immutable and reference counted.
+    // "logically" immutable and reference counted. Only `force`, `unmoor` and the
+    // drop paths write through it; code that only reads a string uses `rep`.
     #[allow(clippy::mut_from_ref)]
     unsafe fn rep_mut(&self) -> &mut StrRep<'a> {
         &mut *self.0.get()
@@ -406,11 +437,19 @@ impl<'a> Str<'a> {
         }
     }
 
+    /// Whether `self` and `other` have the same representation: the same inline bytes, or the
+    /// same range of the same buffer. This never reads the underlying bytes, so it can be true
+    /// only if the strings are equal, but not every pair of equal strings passes.
+    pub(crate) fn same_rep(&self, other: &Str) -> bool {
+        unsafe { self.rep().hi == other.rep().hi && self.rep().low == other.rep().low }
+    }
+
     // leaks `self` unless you transmute it back. This is used in LLVM codegen
     pub fn into_bits(self) -> u128 {
         unsafe { mem::transmute::<Str<'a>, u128>(self) }
     }
 
+    #[cfg(test)]
     pub fn split(
         &self,
         pat: &Regex,
@@ -418,31 +457,148 @@ impl<'a> Str<'a> {
         // splitting. As a result, we pass down the field, and whether or not it was empty (emptiness
         // checks for the string itself are insufficient if used_fields projects some fields away),
         // the pattern returns the number of fields added to the output.
-        mut push: impl FnMut(Str<'a>, bool /*is_empty*/) -> usize,
+        push: impl FnMut(Str<'a>, bool /*is_empty*/) -> usize,
         used_fields: &FieldSet,
     ) {
-        if self.is_empty() {
-            return;
-        }
-        self.with_bytes(|s| {
-            let mut prev = 0;
-            let mut cur_field = 1;
-            for m in pat.find_iter(s) {
-                let is_empty = prev == m.start();
-                cur_field += if used_fields.get(cur_field) {
-                    push(self.slice(prev, m.start()), is_empty)
+        self.split_prefix(
+            FieldSep::Regex(pat),
+            SplitPos::default(),
+            usize::MAX,
+            push,
+            used_fields,
+        );
+    }
+
+    /// Like `split`, but with a single-byte separator, which we can find with memchr rather than
+    /// running a regex.
+    #[cfg(test)]
+    pub fn split_byte(
+        &self,
+        sep: u8,
+        push: impl FnMut(Str<'a>, bool /*is_empty*/) -> usize,
+        used_fields: &FieldSet,
+    ) {
+        self.split_prefix(
+            FieldSep::Byte(sep),
+            SplitPos::default(),
+            usize::MAX,
+            push,
+            used_fields,
+        );
+    }
+
+    /// Like `split`, but splitting on runs of spaces and tabs, as with the pattern `[ \t]+`.
+    #[cfg(test)]
+    pub fn split_blanks(
+        &self,
+        push: impl FnMut(Str<'a>, bool /*is_empty*/) -> usize,
+        used_fields: &FieldSet,
+    ) {
+        self.split_prefix(
+            FieldSep::Blanks,
+            SplitPos::default(),
+            usize::MAX,
+            push,
+            used_fields,
+        );
+    }
+
+    /// Push the fields of `self` from `start` onwards, stopping once field number `limit` has
+    /// been pushed. Returns where to resume splitting if we stopped before the end of the string.
+    pub fn split_prefix(
+        &self,
+        sep: FieldSep,
+        start: SplitPos,
+        limit: usize,
+        push: impl FnMut(Str<'a>, bool /*is_empty*/) -> usize,
+        used_fields: &FieldSet,
+    ) -> Option<SplitPos> {
+        self.with_bytes(|s| match sep {
+            FieldSep::Regex(pat) => {
+                // Continue the search the way `find_iter` would have: an empty match where the
+                // previous separator ended does not count.
+                let mut at = start.offset;
+                let mut last_end = if start.field > 1 {
+                    Some(start.offset)
                 } else {
-                    push(Str::default(), is_empty)
+                    None
                 };
-                prev = m.end();
+                let seps = std::iter::from_fn(|| loop {
+                    if at > s.len() {
+                        return None;
+                    }
+                    let m = pat.find_at(s, at)?;
+                    if m.start() == m.end() {
+                        at = m.end() + 1;
+                        if Some(m.end()) == last_end {
+                            continue;
+                        }
+                    } else {
+                        at = m.end();
+                    }
+                    last_end = Some(m.end());
+                    return Some((m.start(), m.end()));
+                });
+                self.split_at(s, seps, start, limit, push, used_fields)
+            }
+            FieldSep::Byte(sep) => {
+                let seps = memchr::memchr_iter(sep, &s[start.offset..])
+                    .map(|i| (start.offset + i, start.offset + i + 1));
+                self.split_at(s, seps, start, limit, push, used_fields)
             }
-            let is_empty = prev == s.len();
-            if used_fields.get(cur_field) {
-                push(self.slice(prev, s.len()), is_empty);
+            FieldSep::Blanks => {
+                let is_blank = |b: &u8| *b == b' ' || *b == b'\t';
+                let mut pos = start.offset;
+                let seps = std::iter::from_fn(|| {
+                    let start = pos + memchr::memchr2(b' ', b'\t', &s[pos..])?;
+                    pos = s[start..]
+                        .iter()
+                        .position(|b| !is_blank(b))
+                        .map_or(s.len(), |n| start + n);
+                    Some((start, pos))
+                });
+                self.split_at(s, seps, start, limit, push, used_fields)
+            }
+        })
+    }
+
+    // Push the fields of `s` (the contents of `self`) delimited by the separator spans in `seps`.
+    fn split_at(
+        &self,
+        s: &[u8],
+        seps: impl Iterator<Item = (usize, usize)>,
+        start: SplitPos,
+        limit: usize,
+        mut push: impl FnMut(Str<'a>, bool /*is_empty*/) -> usize,
+        used_fields: &FieldSet,
+    ) -> Option<SplitPos> {
+        if s.is_empty() {
+            return None;
+        }
+        let mut prev = start.offset;
+        let mut cur_field = start.field;
+        for (start, end) in seps {
+            let is_empty = prev == start;
+            cur_field += if used_fields.get(cur_field) {
+                push(self.slice(prev, start), is_empty)
             } else {
-                push(Str::default(), is_empty);
+                push(Str::default(), is_empty)
+            };
+            prev = end;
+            if cur_field > limit {
+                return Some(SplitPos {
+                    offset: prev,
+                    field: cur_field,
+                });
             }
-        });
+        }
+        let is_empty = prev == s.len();
+        if used_fields.get(cur_field) {
+            push(self.slice(prev, s.len()), is_empty);
+        } else {
+            push(Str::default(), is_empty);
+        }
+        None
     }
 
     pub fn join_slice<'b>(&self, inps: &[Str]) -> Str<'b> {
@@ -634,7 +790,56 @@ impl<'a> Str<'a> {
     }
 
     pub fn len(&self) -> usize {
-        unsafe { self.rep_mut() }.len()
+        unsafe { self.rep() }.len()
+    }
+
+    /// Compare the bytes of `self` and `other` lexicographically. Concatenated strings are
+    /// compared piece by piece rather than being forced, so comparing two large ropes does not
+    /// allocate.
+    pub fn cmp_bytes(&self, other: &Str) -> Ordering {
+        self.cmp_with(other, |l, r| l.cmp(r))
+    }
+
+    /// Like `cmp_bytes`, but ASCII letters compare equal regardless of case. Neither side is
+    /// copied or lowered.
+    pub fn cmp_ignore_ascii_case(&self, other: &Str) -> Ordering {
+        self.cmp_with(other, |l, r| {
+            let lower = |b: &u8| b.to_ascii_lowercase();
+            l.iter().map(lower).cmp(r.iter().map(lower))
+        })
+    }
+
+    pub fn eq_ignore_ascii_case(&self, other: &Str) -> bool {
+        self.len() == other.len() && self.cmp_ignore_ascii_case(other) == Ordering::Equal
+    }
+
+    /// Compare `self` and `other` using `order`, which must be a lexicographic ordering on byte
+    /// slices. `order` is applied to the full strings if neither is a concatenation, and to
+    /// aligned, equal-length pieces of them otherwise.
+    fn cmp_with(&self, other: &Str, order: impl Fn(&[u8], &[u8]) -> Ordering) -> Ordering {
+        let is_concat = |s: &Str| unsafe { s.rep() }.get_tag() == StrTag::Concat;
+        if !is_concat(self) && !is_concat(other) {
+            return self.with_bytes(|l| other.with_bytes(|r| order(l, r)));
+        }
+        let (mut ls, mut rs) = (Leaves::new(self.clone()), Leaves::new(other.clone()));
+        let (mut l, mut r) = (Str::default(), Str::default());
+        let (mut lb, mut rb): (&[u8], &[u8]) = (&[], &[]);
+        loop {
+            match (ls.refill(&mut l, &mut lb), rs.refill(&mut r, &mut rb)) {
+                (false, false) => return Ordering::Equal,
+                (false, true) => return Ordering::Less,
+                (true, false) => return Ordering::Greater,
+                (true, true) => {}
+            }
+            let n = cmp::min(lb.len(), rb.len());
+            match order(&lb[..n], &rb[..n]) {
+                Ordering::Equal => {
+                    lb = &lb[n..];
+                    rb = &rb[n..];
+                }
+                o => return o,
+            }
+        }
     }
 
     pub fn concat(left: Str<'a>, right: Str<'a>) -> Str<'a> {
@@ -656,27 +861,101 @@ impl<'a> Str<'a> {
                 b.write_all(&*right.get_bytes()).unwrap();
                 b.into_str()
             }
+        } else if let Some(s) = unsafe { left.try_append(&right, new_len) } {
+            s
         } else {
-            // TODO: we can add another case here. If `left` is boxed and has a refcount of 1, we
-            // can move it into a dynamicbuf and push `right` onto it, avoiding the heap
-            // allocation. We _only_ want to do this if we reevaluate the `realloc` that DynamicBuf
-            // does when you convert it back into a string, though. We would have to keep a
-            // capacity around as well as a length.
             let concat = unsafe { Concat::new(new_len as u64, left, right) };
             Str::from_rep(concat.into())
         }
     }
 
+    /// Build `self` followed by `right` in a buffer that later concatenations can extend in place.
+    ///
+    /// Accumulating a string with `s = s x` would otherwise build a tree of concatenations with a
+    /// node per iteration, which then has to be flattened when `s` is used. Instead, once the left
+    /// side of a concatenation is itself a concatenation, we copy both sides into a buffer with
+    /// room to spare (provided it is at least `MIN_APPEND_LEN` bytes long, so that one-off
+    /// concatenations of a few small strings keep their cheaper `Concat` nodes). A shared string that ends at the buffer's `used` mark can then be extended
+    /// by writing past the mark: no other string refers to those bytes, so nothing observable
+    /// changes. When the buffer fills up we fall back to a `Concat` node, and the next
+    /// concatenation copies everything into a buffer twice the size.
+    unsafe fn try_append(&self, right: &Str<'a>, new_len: usize) -> Option<Str<'a>> {
+        let llen = self.len();
+        let rlen = new_len - llen;
+        let rep = self.rep();
+        match rep.get_tag() {
+            StrTag::Shared => rep
+                .view_as(|s: &Shared| {
+                    let end = s.end as usize;
+                    if s.buf.used() != end
+                        || s.buf.len() - end < rlen
+                        || end + rlen > u32::MAX as usize
+                    {
+                        return None;
+                    }
+                    right.copy_to(s.buf.as_ptr().add(end) as *mut u8);
+                    s.buf.set_used(end + rlen);
+                    Some(Shared {
+                        buf: s.buf.clone(),
+                        start: s.start,
+                        end: (end + rlen) as u32,
+                    })
+                })
+                .map(|s| Str::from_rep(s.into())),
+            StrTag::Concat if llen >= MIN_APPEND_LEN => {
+                let cap = new_len.checked_mul(2)?;
+                if cap > u32::MAX as usize {
+                    return None;
+                }
+                let mut buf = UniqueBuf::new(cap);
+                self.copy_to(buf.as_mut_ptr());
+                right.copy_to(buf.as_mut_ptr().add(llen));
+                let buf = buf.into_buf();
+                buf.set_used(new_len);
+                Some(Str::from_rep(
+                    Shared {
+                        buf,
+                        start: 0,
+                        end: new_len as u32,
+                    }
+                    .into(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Copy the contents of `self` to `dst` without forcing it. `dst` must have room for
+    /// `self.len()` bytes.
+    unsafe fn copy_to(&self, mut dst: *mut u8) {
+        for leaf in Leaves::new(self.clone()) {
+            let bs = &*leaf.get_bytes();
+            ptr::copy_nonoverlapping(bs.as_ptr(), dst, bs.len());
+            dst = dst.add(bs.len());
+        }
+    }
+
     fn from_rep(rep: StrRep<'a>) -> Str<'a> {
         Str(UnsafeCell::new(rep))
     }
 
+    /// Copy `bs` into a new string: inline if it is short enough, so that no allocation is needed,
+    /// and in a single buffer otherwise. Numbers are formatted into stack buffers and then copied
+    /// in with this function, so most conversions to strings do not allocate.
+    pub(crate) fn copy_from_bytes(bs: &[u8]) -> Str<'a> {
+        if bs.len() <= MAX_INLINE_SIZE {
+            Str::from_rep(unsafe { Inline::from_raw(bs.as_ptr(), bs.len()).into() })
+        } else {
+            Buf::read_from_bytes(bs).into_str()
+        }
+    }
+
     // This helper method assumes:
     // * that from and to cannot overflow when moved to u32s/shared/etc.
     // * that any CONCATs have been forced away.
     // * to - from > MAX_INLINE_SIZE
     unsafe fn slice_nooverflow(&self, from: usize, to: usize) -> Str<'a> {
-        let rep = self.rep_mut();
+        let rep = self.rep();
         let tag = rep.get_tag();
         let new_rep = match tag {
             StrTag::Shared => rep.view_as(|s: &Shared| {
@@ -735,7 +1014,7 @@ impl<'a> Str<'a> {
         if !may_overflow && tag == StrTag::Shared {
             // If we are taking a slice of an existing slice, then we can overflow by adding the
             // starts and ends together.
-            may_overflow = self.rep_mut().view_as(|s: &Shared| {
+            may_overflow = self.rep().view_as(|s: &Shared| {
                 (s.start as usize + from) > u32_max || (s.start as usize + to) > u32_max
             });
         }
@@ -748,7 +1027,7 @@ impl<'a> Str<'a> {
             // by creating new ones with offset pointers. This doesn't seem worth optimizing right
             // now, but we may want to in the future.
             self.force();
-            let rep = self.rep_mut();
+            let rep = self.rep();
             let tag = rep.get_tag();
             // All other variants ruled out by how large `self` is and the fact that we
             // just called `force`
@@ -803,7 +1082,7 @@ impl<'a> Str<'a> {
     #[allow(clippy::never_loop)]
     unsafe fn force(&self) {
         let (tag, len) = {
-            let rep = self.rep_mut();
+            let rep = self.rep();
             (rep.get_tag(), rep.len())
         };
         if tag.forced() {
@@ -828,7 +1107,7 @@ impl<'a> Str<'a> {
         let mut todos = SmallVec::<[Str<'a>; 16]>::new();
         let mut cur: Str<'a> = self.clone();
         let new_rep: StrRep<'a> = 'outer: loop {
-            let rep = cur.rep_mut();
+            let rep = cur.rep();
             let tag = rep.get_tag();
             cur = loop {
                 match tag {
@@ -866,7 +1145,7 @@ impl<'a> Str<'a> {
 
     // Avoid using this function; subsequent immutable calls to &self can invalidate the pointer.
     pub fn get_bytes(&self) -> *const [u8] {
-        let rep = unsafe { self.rep_mut() };
+        let rep = unsafe { self.rep() };
         let tag = rep.get_tag();
         unsafe {
             match tag {
@@ -909,7 +1188,7 @@ impl<'a> Str<'a> {
 
 impl<'a> Clone for Str<'a> {
     fn clone(&self) -> Str<'a> {
-        let rep = unsafe { self.rep_mut() };
+        let rep = unsafe { self.rep() };
         let tag = rep.get_tag();
         let cloned_rep: StrRep<'a> = unsafe {
             match tag {
@@ -929,13 +1208,79 @@ impl<'a> PartialEq for Str<'a> {
         if unsafe { self.rep() == other.rep() } {
             return true;
         }
+        // Lengths are cached for every variant, including concatenations, so this check does not
+        // force anything.
+        if self.len() != other.len() {
+            return false;
+        }
         // TODO: we could intern these strings if they wind up equal.
-        self.with_bytes(|bs1| other.with_bytes(|bs2| bs1 == bs2))
+        self.cmp_bytes(other) == Ordering::Equal
     }
 }
 
 impl<'a> Eq for Str<'a> {}
 
+impl<'a> PartialOrd for Str<'a> {
+    fn partial_cmp(&self, other: &Str<'a>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for Str<'a> {
+    fn cmp(&self, other: &Str<'a>) -> Ordering {
+        self.cmp_bytes(other)
+    }
+}
+
+/// The pieces of a string that are not themselves concatenations, from left to right.
+struct Leaves<'a> {
+    todos: SmallVec<[Str<'a>; 16]>,
+}
+
+impl<'a> Leaves<'a> {
+    fn new(s: Str<'a>) -> Leaves<'a> {
+        let mut todos = SmallVec::new();
+        todos.push(s);
+        Leaves { todos }
+    }
+
+    /// Advance `bytes` to the next nonempty leaf, storing the leaf in `cur` to keep the bytes
+    /// alive. Returns false once there are no leaves left.
+    fn refill<'b>(&mut self, cur: &mut Str<'a>, bytes: &mut &'b [u8]) -> bool {
+        while bytes.is_empty() {
+            match self.next() {
+                Some(leaf) => {
+                    *cur = leaf;
+                    // Leaves are never concatenations, so get_bytes will not force (and
+                    // thereby free) anything. `cur` is not touched again until `bytes` is empty.
+                    *bytes = unsafe { &*cur.get_bytes() };
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = Str<'a>;
+    fn next(&mut self) -> Option<Str<'a>> {
+        let mut cur = self.todos.pop()?;
+        loop {
+            let rep = unsafe { cur.rep() };
+            if rep.get_tag() != StrTag::Concat {
+                return Some(cur);
+            }
+            cur = unsafe {
+                rep.view_as(|c: &Concat<'a>| {
+                    self.todos.push(c.right());
+                    c.left()
+                })
+            };
+        }
+    }
+}
+
 impl<'a> Hash for Str<'a> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.with_bytes(|bs| bs.hash(state))
@@ -975,15 +1320,7 @@ impl<'a> From<&'a [u8]> for Str<'a> {
 
 impl<'a> From<String> for Str<'a> {
     fn from(s: String) -> Str<'a> {
-        if s.is_empty() {
-            return Default::default();
-        }
-        let buf = Buf::read_from_bytes(s.as_bytes());
-        let boxed = Boxed {
-            len: s.len() as u64,
-            buf,
-        };
-        Str::from_rep(boxed.into())
+        Str::copy_from_bytes(s.as_bytes())
     }
 }
 
@@ -995,7 +1332,7 @@ impl<'a> From<Int> for Str<'a> {
     fn from(i: Int) -> Str<'a> {
         let mut itoabuf = itoa::Buffer::new();
         let s = itoabuf.format(i);
-        Buf::read_from_bytes(s.as_bytes()).into_str()
+        Str::copy_from_bytes(s.as_bytes())
     }
 }
 
@@ -1010,7 +1347,7 @@ impl<'a> From<Float> for Str<'a> {
         } else {
             slen
         };
-        Buf::read_from_bytes(&s.as_bytes()[..slen]).into_str()
+        Str::copy_from_bytes(&s.as_bytes()[..slen])
     }
 }
 
@@ -1030,6 +1367,9 @@ struct BufHeader {
     size: usize,
     // We only have "strong counts"
     count: Cell<usize>,
+    // Strings only refer to bytes before `used`. This is `size` except for buffers built by
+    // `Str::try_append`, which fill up as strings are appended to them.
+    used: Cell<usize>,
 }
 
 #[repr(transparent)]
@@ -1051,16 +1391,6 @@ impl DynamicBufHeap {
     fn size(&self) -> usize {
         unsafe { (*self.data.0).size }
     }
-    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
-        self.data.as_mut_bytes()
-    }
-    pub fn write_head(&self) -> usize {
-        self.write_head
-    }
-    pub fn into_buf(self) -> Buf {
-        self.data.into_buf()
-    }
-
     pub(crate) fn into_str<'a>(mut self) -> Str<'a> {
         // Shrink the buffer to fit.
         unsafe { self.realloc(self.write_head) };
@@ -1077,6 +1407,7 @@ impl DynamicBufHeap {
             UniqueBuf::layout(new_cap).size(),
         ) as *mut BufHeader;
         (*new_buf).size = new_cap;
+        (*new_buf).used.set(new_cap);
         self.data.0 = new_buf;
     }
 
@@ -1245,6 +1576,7 @@ impl UniqueBuf {
                 BufHeader {
                     size,
                     count: Cell::new(1),
+                    used: Cell::new(size),
                 },
             );
             UniqueBuf(alloced)
@@ -1291,6 +1623,15 @@ impl Buf {
         unsafe { self.0.offset(1) as *const u8 }
     }
 
+    fn used(&self) -> usize {
+        unsafe { &(*self.0) }.used.get()
+    }
+
+    fn set_used(&self, used: usize) {
+        debug_assert!(used <= self.len());
+        unsafe { &(*self.0) }.used.set(used)
+    }
+
     fn refcount(&self) -> usize {
         let header: &BufHeader = unsafe { &(*self.0) };
         header.count.get()
@@ -1462,6 +1803,125 @@ mod tests {
         s1.with_bytes(|bs1| assert_eq!(bs1, b"h"));
     }
 
+    #[test]
+    fn numbers_inline() {
+        let tag = |s: &Str| unsafe { s.rep().get_tag() };
+        let cases: &[(Str, &str)] = &[
+            (Str::from(-1234 as Int), "-1234"),
+            (Str::from(2.5 as Float), "2.5"),
+            (Str::from(3.0 as Float), "3"),
+            (Str::from(999999999999999 as Int), "999999999999999"),
+            (Str::from(String::from("short")), "short"),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(tag(s), StrTag::Inline);
+            s.with_bytes(|bs| assert_eq!(bs, expected.as_bytes()));
+        }
+        let long = Str::from(Int::MIN);
+        assert_eq!(tag(&long), StrTag::Boxed);
+        long.with_bytes(|bs| assert_eq!(bs, b"-9223372036854775808"));
+    }
+
+    #[test]
+    fn same_rep() {
+        let long = "a string that is too long to be inlined";
+        let s1: Str = String::from(long).into();
+        let s2: Str = String::from(long).into();
+        assert_eq!(s1, s2);
+        assert!(!s1.same_rep(&s2));
+        assert!(s1.same_rep(&s1.clone()));
+        assert!(s1.same_rep(&s1.clone().unmoor()));
+        assert!(!s1.same_rep(&s1.slice(1, 30)));
+        let i1: Str = "short".into();
+        let i2 = Str::from(String::from("a short string")).slice(2, 7);
+        assert!(i1.same_rep(&i2));
+    }
+
+    #[test]
+    fn compare_concats() {
+        fn rope<'a>(pieces: &[&'a str]) -> Str<'a> {
+            pieces
+                .iter()
+                .fold(Str::default(), |acc, p| Str::concat(acc, (*p).into()))
+        }
+        let cases: &[(&[&str], &[&str])] = &[
+            (
+                &["hello there ", "my good friend"],
+                &["hello", " there my good friend"],
+            ),
+            (
+                &["hello there ", "my good friend"],
+                &["hello there my good fiend"],
+            ),
+            (
+                &["abc", "defghijklmnopqrstuvwxyz"],
+                &["abcdefghijklmnopqrstuvwxy"],
+            ),
+            (
+                &["zzz", "defghijklmnopqrstuvwxyz"],
+                &["abcdefghijklmnopqrstuvwxyz!"],
+            ),
+            (
+                &["", "a fairly long string here"],
+                &["a fairly ", "", "long ", "string here"],
+            ),
+        ];
+        for (l, r) in cases {
+            let (ls, rs) = (rope(l), rope(r));
+            let expected = l.concat().cmp(&r.concat());
+            assert_eq!(ls.cmp(&rs), expected, "{:?} vs {:?}", l, r);
+            assert_eq!(rs.cmp(&ls), expected.reverse(), "{:?} vs {:?}", r, l);
+            assert_eq!(ls == rs, expected == Ordering::Equal);
+            assert_eq!(ls.cmp(&ls.clone()), Ordering::Equal);
+            // Comparisons should not force either side.
+            if l.iter().filter(|p| !p.is_empty()).count() > 1 {
+                assert_eq!(unsafe { ls.rep().get_tag() }, StrTag::Concat);
+            }
+        }
+    }
+
+    #[test]
+    fn append_in_place() {
+        let mut s = Str::default();
+        let mut expected = String::new();
+        let mut snapshots = Vec::new();
+        for i in 0..1000 {
+            let piece = format!("{},", i);
+            expected.push_str(&piece);
+            s = Str::concat(s, piece.into());
+            if i % 97 == 0 {
+                snapshots.push((s.clone(), expected.clone()));
+            }
+        }
+        assert_eq!(unsafe { s.rep().get_tag() }, StrTag::Shared);
+        // Appending to an older version of the string must not clobber the newer one, or vice
+        // versa.
+        let (old, old_expected) = snapshots[5].clone();
+        let branch = Str::concat(old, "branch".into());
+        let s = Str::concat(s, "trunk".into());
+        branch.with_bytes(|bs| assert_eq!(bs, format!("{}branch", old_expected).as_bytes()));
+        s.with_bytes(|bs| assert_eq!(bs, format!("{}trunk", expected).as_bytes()));
+        for (snap, expected) in snapshots {
+            snap.with_bytes(|bs| assert_eq!(bs, expected.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn compare_ignore_case() {
+        let rope = |l: &'static str, r: &'static str| Str::concat(l.into(), r.into());
+        let hello = rope("Hello There, ", "General Kenobi");
+        assert!(hello.eq_ignore_ascii_case(&rope("hello there", ", general kenobi")));
+        assert!(hello.eq_ignore_ascii_case(&"HELLO THERE, GENERAL KENOBI".into()));
+        assert!(!hello.eq_ignore_ascii_case(&"HELLO THERE, GENERAL KENOBI!".into()));
+        assert_eq!(
+            hello.cmp_ignore_ascii_case(&"hello there, general grievous".into()),
+            Ordering::Greater
+        );
+        let (a, b): (Str, Str) = ("apple".into(), "BANANA".into());
+        assert_eq!(a.cmp(&b), Ordering::Greater);
+        assert_eq!(a.cmp_ignore_ascii_case(&b), Ordering::Less);
+    }
+
     #[test]
     fn basic_behavior() {
         let base_1 = b"hi there fellow";
@@ -1538,6 +1998,97 @@ mod tests {
         );
     }
 
+    #[test]
+    fn memchr_splitting() {
+        fn fields(
+            f: impl FnOnce(&mut dyn FnMut(Str<'static>, bool) -> usize),
+        ) -> Vec<Str<'static>> {
+            let mut got = Vec::new();
+            f(&mut |sub, is_empty| {
+                if is_empty {
+                    0
+                } else {
+                    got.push(sub);
+                    1
+                }
+            });
+            got
+        }
+        let used = FieldSet::all();
+        let blanks = Regex::new(r#"[ \t]+"#).unwrap();
+        let comma = Regex::new(",").unwrap();
+        for base in [
+            &b"  what is \t up  "[..],
+            b"a,,b, c\t,",
+            b"\t",
+            crate::test_string_constants::PRIDE_PREJUDICE_CH2.as_bytes(),
+        ] {
+            let s = Str::from(base);
+            assert_eq!(
+                fields(|push| s.split_blanks(push, &used)),
+                fields(|push| s.split(&blanks, push, &used)),
+            );
+            assert_eq!(
+                fields(|push| s.split_byte(b',', push, &used)),
+                fields(|push| s.split(&comma, push, &used)),
+            );
+        }
+    }
+
+    #[test]
+    fn split_in_steps() {
+        fn push_to<'b>(
+            v: &'b mut Vec<Str<'static>>,
+            blanks: bool,
+        ) -> impl FnMut(Str<'static>, bool) -> usize + 'b {
+            move |sub, is_empty| {
+                if is_empty && blanks {
+                    0
+                } else {
+                    v.push(sub);
+                    1
+                }
+            }
+        }
+        let used = FieldSet::all();
+        let comma = Regex::new(",").unwrap();
+        let anchored = Regex::new("^a|,").unwrap();
+        let empty = Regex::new("x*").unwrap();
+        for base in [
+            &b"  what is \t up  "[..],
+            b"a,,b, c\t,",
+            b"ab,xx,",
+            b"\t",
+            crate::test_string_constants::VIRGIL.as_bytes(),
+        ] {
+            let s = Str::from(base);
+            for sep in [
+                FieldSep::Blanks,
+                FieldSep::Byte(b','),
+                FieldSep::Regex(&comma),
+                FieldSep::Regex(&anchored),
+                FieldSep::Regex(&empty),
+            ] {
+                let blanks = matches!(sep, FieldSep::Blanks);
+                let mut want = Vec::new();
+                let pos = SplitPos::default();
+                assert!(s
+                    .split_prefix(sep, pos, usize::MAX, push_to(&mut want, blanks), &used)
+                    .is_none());
+                for step in 1..4 {
+                    let mut got = Vec::new();
+                    let mut pos = Some(SplitPos::default());
+                    while let Some(start) = pos {
+                        let limit = got.len() + step;
+                        pos = s.split_prefix(sep, start, limit, push_to(&mut got, blanks), &used);
+                        assert!(pos.is_none() || got.len() == limit);
+                    }
+                    assert_eq!(got, want, "step={} base={:?}", step, s);
+                }
+            }
+        }
+    }
+
     #[test]
     fn dynamic_string() {
         let mut d = DynamicBuf::new(0);
@@ -1741,7 +2292,7 @@ mod formatting {
     impl<'a> Debug for Str<'a> {
         fn fmt(&self, f: &mut Formatter) -> fmt::Result {
             unsafe {
-                let rep = self.rep_mut();
+                let rep = self.rep();
                 match rep.get_tag() {
                     StrTag::Inline => {
                         rep.view_as_inline(|i| write!(f, "Str(Inline({:?}))", i.bytes()))
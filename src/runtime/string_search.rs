@@ -11,3 +11,52 @@ pub fn index_substr<'a>(needle: &Str<'a>, haystack: &Str<'a>) -> Int {
         .map(|x| x as Int + 1)
         .unwrap_or(0)
 }
+
+// Whether `needle` appears in `haystack`; used for regexes that are just a literal string.
+pub fn contains(needle: &[u8], haystack: &Str) -> bool {
+    haystack.with_bytes(|h| memmem::find(h, needle).is_some())
+}
+
+// Like `index_substr`, but ASCII letters match regardless of case.
+pub fn index_substr_ignore_case<'a>(needle: &Str<'a>, haystack: &Str<'a>) -> Int {
+    needle
+        .with_bytes(|n| haystack.with_bytes(|h| find_ignore_ascii_case(h, n)))
+        .map(|x| x as Int + 1)
+        .unwrap_or(0)
+}
+
+fn find_ignore_ascii_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let (first, rest) = match needle.split_first() {
+        Some(x) => x,
+        None => return Some(0),
+    };
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    // Find candidates by searching for either case of the first byte, then check the rest of the
+    // needle in place.
+    let candidates = &haystack[..haystack.len() - rest.len()];
+    memchr::memchr2_iter(
+        first.to_ascii_lowercase(),
+        first.to_ascii_uppercase(),
+        candidates,
+    )
+    .find(|i| haystack[i + 1..i + needle.len()].eq_ignore_ascii_case(rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_case() {
+        let index = |n: &str, h: &str| index_substr_ignore_case(&n.into(), &h.into());
+        assert_eq!(index("", "abc"), 1);
+        assert_eq!(index("ABC", "xxabcxx"), 3);
+        assert_eq!(index("aBc", "xxAbCxx"), 3);
+        assert_eq!(index("bcx", "xxabc"), 0);
+        assert_eq!(index("1-2", "a1-2"), 2);
+        assert_eq!(index("xxabcxx", "abc"), 0);
+        assert_eq!(index("a", "bA"), 2);
+    }
+}
@@ -0,0 +1,411 @@
+//! The storage backing AWK arrays.
+//!
+//! Arrays with integer keys are very often used like vectors: `split` fills in the keys 1 through
+//! n, and histograms count into small, contiguous bucket numbers. A [`Table`] stores small
+//! non-negative keys in a vector as long as it stays reasonably full, and every other key in a hash
+//! table. Keys move back to the hash table if the vector becomes too sparse.
+use super::{Int, Map, Str};
+use hashbrown::hash_map::{self, RawEntryMut};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::iter::{Enumerate, FromIterator};
+use std::mem;
+use std::slice;
+
+/// Keys below this index can always be stored in the vector, however sparse it is.
+const MIN_DENSE: usize = 16;
+
+/// The number of entries that `approx_size` measures the contents of.
+const SIZE_SAMPLE: usize = 16;
+
+/// Keys of a [`Table`].
+pub(crate) trait MapKey: Hash + Eq + Clone {
+    /// The index at which this key would be stored in the vector, if it can be stored there.
+    fn dense_index(&self) -> Option<usize>;
+    /// The key stored at index `i` of the vector.
+    fn from_dense_index(i: usize) -> Self;
+}
+
+impl MapKey for Int {
+    fn dense_index(&self) -> Option<usize> {
+        usize::try_from(*self).ok()
+    }
+    fn from_dense_index(i: usize) -> Int {
+        i as Int
+    }
+}
+
+impl<'a> MapKey for Str<'a> {
+    fn dense_index(&self) -> Option<usize> {
+        None
+    }
+    fn from_dense_index(_: usize) -> Str<'a> {
+        unreachable!("string keys are never stored in the vector")
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Table<K, V> {
+    // dense[i] holds the value (if any) for K::from_dense_index(i). Keys with an index below
+    // dense.len() are never in `sparse`.
+    dense: Vec<Option<V>>,
+    // The number of values in `dense`.
+    dense_len: usize,
+    sparse: Map<K, V>,
+    // Keys that were inserted by reading them, and have not been assigned since. They hold the
+    // default value, but are uninitialized: read as a string, they give "".
+    uninit: Map<K, ()>,
+}
+
+impl<K, V> Default for Table<K, V> {
+    fn default() -> Table<K, V> {
+        Table {
+            dense: Vec::new(),
+            dense_len: 0,
+            sparse: Default::default(),
+            uninit: Default::default(),
+        }
+    }
+}
+
+impl<K: MapKey, V> Table<K, V> {
+    pub(crate) fn len(&self) -> usize {
+        self.dense_len + self.sparse.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.dense.clear();
+        self.dense_len = 0;
+        self.sparse.clear();
+        self.uninit.clear();
+    }
+
+    pub(crate) fn get(&self, k: &K) -> Option<&V> {
+        match self.dense_slot(k) {
+            Some(i) => self.dense[i].as_ref(),
+            None => self.sparse.get(k),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, k: &K) -> bool {
+        self.get(k).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, k: K, v: V) {
+        self.assigned(&k);
+        match self.dense_slot_or_grow(&k) {
+            Some(i) => {
+                let slot = &mut self.dense[i];
+                if slot.is_none() {
+                    self.dense_len += 1;
+                }
+                *slot = Some(v);
+            }
+            None => {
+                self.sparse.insert(k, v);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, k: &K) {
+        self.assigned(k);
+        match self.dense_slot(k) {
+            Some(i) => {
+                if self.dense[i].take().is_some() {
+                    self.dense_len -= 1;
+                    self.shrink();
+                }
+            }
+            None => {
+                self.sparse.remove(k);
+            }
+        }
+    }
+
+    /// Whether `k` was inserted by `vivify` and has not been assigned since.
+    pub(crate) fn is_uninit(&self, k: &K) -> bool {
+        !self.uninit.is_empty() && self.uninit.contains_key(k)
+    }
+
+    /// The value at `k`, for reading. A missing key is inserted with a default value, which is
+    /// marked as uninitialized.
+    pub(crate) fn vivify(&mut self, k: &K) -> &V
+    where
+        V: Default,
+    {
+        if !self.contains_key(k) {
+            self.uninit.insert(k.clone(), ());
+        }
+        self.entry_inner(k)
+    }
+
+    /// Like `vivify`, but with `hash` (as computed by `hash_key`) as the hash of `k`.
+    pub(crate) fn vivify_hashed(&mut self, k: &K, hash: u64) -> &V
+    where
+        V: Default,
+    {
+        if !self.contains_key(k) {
+            self.uninit.insert(k.clone(), ());
+        }
+        self.entry_hashed_inner(k, hash)
+    }
+
+    /// The value at `k`, for writing, inserting a default value first if it is missing.
+    pub(crate) fn entry(&mut self, k: &K) -> &mut V
+    where
+        V: Default,
+    {
+        self.assigned(k);
+        self.entry_inner(k)
+    }
+
+    /// Like `entry`, but with `hash` (as computed by `hash_key`) as the hash of `k`.
+    pub(crate) fn entry_hashed(&mut self, k: &K, hash: u64) -> &mut V
+    where
+        V: Default,
+    {
+        self.assigned(k);
+        self.entry_hashed_inner(k, hash)
+    }
+
+    fn assigned(&mut self, k: &K) {
+        if !self.uninit.is_empty() {
+            self.uninit.remove(k);
+        }
+    }
+
+    fn entry_inner(&mut self, k: &K) -> &mut V
+    where
+        V: Default,
+    {
+        match self.dense_slot_or_grow(k) {
+            Some(i) => self.dense_entry(i),
+            None => {
+                self.sparse
+                    .raw_entry_mut()
+                    .from_key(k)
+                    .or_insert_with(|| (k.clone(), V::default()))
+                    .1
+            }
+        }
+    }
+
+    fn entry_hashed_inner(&mut self, k: &K, hash: u64) -> &mut V
+    where
+        V: Default,
+    {
+        match self.dense_slot_or_grow(k) {
+            Some(i) => self.dense_entry(i),
+            None => match self.sparse.raw_entry_mut().from_key_hashed_nocheck(hash, k) {
+                RawEntryMut::Occupied(o) => o.into_mut(),
+                RawEntryMut::Vacant(v) => v.insert_hashed_nocheck(hash, k.clone(), V::default()).1,
+            },
+        }
+    }
+
+    /// Iterate over the entries of the table: keys stored in the vector come first, in order.
+    pub(crate) fn iter(&self) -> Entries<'_, K, V> {
+        Entries {
+            dense: self.dense.iter().enumerate(),
+            sparse: self.sparse.iter(),
+        }
+    }
+
+    /// An estimate of the memory used by the table: the space allocated for its entries, plus
+    /// `contents` (the memory that a key and value point to) for a sample of the entries, scaled
+    /// up to all of them. Measuring a sample keeps this cheap for large tables; hash table
+    /// iteration order is effectively random, so the sample is not biased towards old entries.
+    pub(crate) fn approx_size(&self, contents: impl Fn(&K, &V) -> usize) -> usize {
+        let slots = self.dense.capacity() * mem::size_of::<Option<V>>()
+            + self.sparse.capacity() * (mem::size_of::<(K, V)>() + 1);
+        let (sampled, total) = self
+            .iter()
+            .take(SIZE_SAMPLE)
+            .fold((0, 0), |(n, total), (k, v)| {
+                (n + 1, total + contents(&k, v))
+            });
+        if sampled == 0 {
+            return slots;
+        }
+        slots + total * self.len() / sampled
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
+
+    fn dense_slot(&self, k: &K) -> Option<usize> {
+        k.dense_index().filter(|i| *i < self.dense.len())
+    }
+
+    fn dense_entry(&mut self, i: usize) -> &mut V
+    where
+        V: Default,
+    {
+        let slot = &mut self.dense[i];
+        if slot.is_none() {
+            self.dense_len += 1;
+        }
+        slot.get_or_insert_with(V::default)
+    }
+
+    /// The index of `k` in the vector, growing the vector to hold it if it would stay at least
+    /// half full.
+    fn dense_slot_or_grow(&mut self, k: &K) -> Option<usize> {
+        let i = k.dense_index()?;
+        if i < self.dense.len() {
+            return Some(i);
+        }
+        if i >= MIN_DENSE.max(2 * (self.dense_len + 1)) {
+            return None;
+        }
+        let old_len = self.dense.len();
+        self.dense.resize_with(i + 1, || None);
+        if !self.sparse.is_empty() {
+            for j in old_len..=i {
+                if let Some(v) = self.sparse.remove(&K::from_dense_index(j)) {
+                    self.dense[j] = Some(v);
+                    self.dense_len += 1;
+                }
+            }
+        }
+        Some(i)
+    }
+
+    fn shrink(&mut self) {
+        while let Some(None) = self.dense.last() {
+            self.dense.pop();
+        }
+        if self.dense.len() > MIN_DENSE && self.dense_len * 4 < self.dense.len() {
+            for (i, v) in self.dense.drain(..).enumerate() {
+                if let Some(v) = v {
+                    self.sparse.insert(K::from_dense_index(i), v);
+                }
+            }
+            self.dense_len = 0;
+        }
+    }
+}
+
+impl<K: MapKey, V> FromIterator<(K, V)> for Table<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut res = Table::default();
+        for (k, v) in iter {
+            res.insert(k, v);
+        }
+        res
+    }
+}
+
+pub(crate) struct Entries<'a, K, V> {
+    dense: Enumerate<slice::Iter<'a, Option<V>>>,
+    sparse: hash_map::Iter<'a, K, V>,
+}
+
+impl<'a, K: MapKey, V> Iterator for Entries<'a, K, V> {
+    type Item = (K, &'a V);
+    fn next(&mut self) -> Option<(K, &'a V)> {
+        for (i, v) in &mut self.dense {
+            if let Some(v) = v {
+                return Some((K::from_dense_index(i), v));
+            }
+        }
+        self.sparse.next().map(|(k, v)| (k.clone(), v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(t: &Table<Int, Int>) -> Vec<(Int, Int)> {
+        let mut res: Vec<_> = t.iter().map(|(k, v)| (k, *v)).collect();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn contiguous_keys_are_dense() {
+        let t: Table<Int, Int> = (1..=100).map(|i| (i, i * 2)).collect();
+        assert_eq!(t.len(), 100);
+        assert_eq!(t.dense.len(), 101);
+        assert!(t.sparse.is_empty());
+        assert_eq!(t.get(&50), Some(&100));
+        assert_eq!(t.get(&0), None);
+        assert_eq!(t.get(&101), None);
+        let keys: Vec<Int> = t.keys().collect();
+        assert_eq!(keys, (1..=100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sparse_keys_are_hashed() {
+        let mut t = Table::<Int, Int>::default();
+        for k in [-1, 1000, 1 << 40, 3] {
+            t.insert(k, k);
+        }
+        assert_eq!(t.dense_len, 1);
+        assert_eq!(t.sparse.len(), 3);
+        assert_eq!(
+            sorted(&t),
+            vec![(-1, -1), (3, 3), (1000, 1000), (1 << 40, 1 << 40)]
+        );
+    }
+
+    #[test]
+    fn grow_moves_hashed_keys() {
+        let mut t = Table::<Int, Int>::default();
+        t.insert(20, 1);
+        assert_eq!(t.sparse.len(), 1);
+        for k in 0..22 {
+            *t.entry(&k) += 1;
+        }
+        assert!(t.sparse.is_empty());
+        assert_eq!(t.len(), 22);
+        assert_eq!(t.get(&20), Some(&2));
+        assert_eq!(t.keys().collect::<Vec<_>>(), (0..22).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn deletes_fall_back_to_hashing() {
+        let mut t: Table<Int, Int> = (0..100).map(|i| (i, i)).collect();
+        for k in (0..99).filter(|k| k % 8 != 0) {
+            t.remove(&k);
+        }
+        assert_eq!(t.dense.len(), 0);
+        let expected: Vec<_> = (0..100)
+            .filter(|k| k % 8 == 0 || *k == 99)
+            .map(|k| (k, k))
+            .collect();
+        assert_eq!(sorted(&t), expected);
+        t.remove(&99);
+        t.insert(3, 3);
+        assert!(t.contains_key(&0) && t.contains_key(&3) && !t.contains_key(&99));
+        assert_eq!(t.len(), expected.len());
+    }
+
+    #[test]
+    fn vivified_keys_are_uninit() {
+        let mut t = Table::<Int, Int>::default();
+        assert_eq!(*t.vivify(&3), 0);
+        assert_eq!(*t.vivify(&1000), 0);
+        assert!(t.is_uninit(&3) && t.is_uninit(&1000) && t.contains_key(&3));
+        *t.entry(&3) += 1;
+        t.insert(1000, 2);
+        assert!(!t.is_uninit(&3) && !t.is_uninit(&1000));
+        t.vivify(&5);
+        t.remove(&5);
+        t.vivify(&5);
+        t.clear();
+        assert!(!t.is_uninit(&5));
+    }
+
+    #[test]
+    fn approx_size() {
+        let empty = Table::<Int, Int>::default();
+        assert_eq!(empty.approx_size(|_, _| 1), 0);
+        let t: Table<Int, Int> = (0..1000).map(|i| (i * 7, i)).collect();
+        let slots = t.approx_size(|_, _| 0);
+        assert!(slots >= 1000 * mem::size_of::<(Int, Int)>());
+        assert_eq!(t.approx_size(|_, _| 10), slots + 10 * 1000);
+    }
+}
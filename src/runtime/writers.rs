@@ -29,26 +29,24 @@
 //! protocol (as opposed to one that transfers ownership of the buffer to the thread performing the
 //! writes) allows each client thread to avoid allocating new buffers continuously. It also
 //! mitigates a "producer-consumer" allocation and freeing pattern, which can put a lot of strain
-//! on some allocators.
+//! on some allocators. Writes at least as large as a batch skip the copy altogether: the client
+//! sends the receiving thread the strings themselves and waits for the write to complete.
+//!
+//! Writer threads close files they have not written to recently once too many are open, and
+//! reopen them for appending the next time they are written to. This keeps scripts that write to
+//! thousands of files under the operating system's limit on open files.
 //!
 //! To facilitate easier testing, the functionality of the file system that we use is abstracted in
 //! the `FileFactory` trait. The `testing` module contains an implementation of this trait that
 //! writes all data in memory.
 
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::{self, Write};
-use std::process::ChildStdin;
 use std::sync::{
-    atomic::{AtomicBool, AtomicUsize, Ordering},
+    atomic::{AtomicI64, AtomicUsize, Ordering},
     Arc, Mutex,
 };
 
-#[cfg(not(feature = "unstable"))]
-use itertools::Itertools;
-
-#[cfg(not(feature = "unstable"))]
-use std::ops::Deref;
-
 // TODO: get_handle() should return an error on failure to parse UTF8
 
 // NB we only require mpsc semantics, but at time of writing there are a few open bugs on
@@ -56,8 +54,11 @@ use std::ops::Deref;
 use crossbeam_channel::{bounded, Receiver, Sender};
 use hashbrown::HashMap;
 
-use crate::common::{CompileError, FileSpec, Notification, Result};
-use crate::runtime::{command::command_for_write, Str};
+use crate::common::{CompileError, FileSpec, Notification, OutputOrder, Result};
+use crate::runtime::{
+    command::{command_for_write, CommandWriter},
+    compress, inet, Int, Str,
+};
 
 /// The maximum number of pending requests in the per-file channels.
 const IO_CHAN_SIZE: usize = 8;
@@ -74,13 +75,17 @@ const BUFFER_SIZE: usize = 64 << 10;
 pub trait FileFactory: Clone + 'static + Send + Sync {
     type Output: io::Write;
     type Stdout: io::Write;
-    // TODO: make ChildStdin an associated type, to permit better testing
-    fn cmd(&self, cmd: &[u8]) -> io::Result<ChildStdin> {
-        command_for_write(cmd)
+    // TODO: make CommandWriter an associated type, to permit better testing
+    fn cmd(&self, cmd: &[u8], status: Arc<AtomicI64>) -> io::Result<CommandWriter> {
+        command_for_write(cmd, status)
     }
     fn build(&self, path: &str, spec: FileSpec) -> io::Result<Self::Output>;
     // TODO maybe we should support this returning an error.
     fn stdout(&self) -> Self::Stdout;
+    /// Whether standard output is written out after every line, rather than in large batches.
+    fn line_buffered(&self) -> bool {
+        grep_cli::is_tty_stdout()
+    }
 }
 
 impl<W: io::Write, T: Fn(&str, FileSpec) -> io::Result<W> + Clone + 'static + Send + Sync>
@@ -96,52 +101,67 @@ impl<W: io::Write, T: Fn(&str, FileSpec) -> io::Result<W> + Clone + 'static + Se
     }
 }
 
-type FileWriter = std::fs::File;
+type FileWriter = Box<dyn io::Write + Send>;
 
-fn open_file(path: &str, spec: FileSpec) -> io::Result<FileWriter> {
-    let file = std::fs::OpenOptions::new()
+fn create_file(path: &str, spec: FileSpec) -> io::Result<std::fs::File> {
+    // Device files like /dev/stderr can refer to the same file as our stdout; always append to
+    // them so that we do not truncate or overwrite output that is already there.
+    let device = path.starts_with("/dev/");
+    std::fs::OpenOptions::new()
         .write(true)
         .create(true)
-        .append(matches!(spec, FileSpec::Append))
-        .open(path)?;
-    Ok(file)
+        .append(device || matches!(spec, FileSpec::Append))
+        .truncate(!device && matches!(spec, FileSpec::Trunc))
+        .open(path)
+}
+
+pub(crate) fn open_file(path: &str, spec: FileSpec, level: Option<u32>) -> io::Result<FileWriter> {
+    if let Some(conn) = inet::open_write(path) {
+        return Ok(Box::new(conn?));
+    }
+    compress::compress(path, create_file(path, spec)?, level)
 }
 
-pub fn default_factory() -> impl FileFactory {
-    open_file
+/// The factory for writing to the local file system. Files with names ending in ".gz", ".zst" or
+/// ".bz2" are compressed, using `level` if it is set.
+pub fn default_factory(level: Option<u32>) -> impl FileFactory {
+    move |path: &str, spec| open_file(path, spec, level)
 }
 
-pub fn factory_from_file(fname: &str) -> io::Result<impl FileFactory> {
+pub fn factory_from_file(fname: &str, level: Option<u32>) -> io::Result<impl FileFactory> {
     // Do a test open+truncate of the file.
-    let _file = open_file(fname, FileSpec::Trunc)?;
+    let _file = create_file(fname, FileSpec::Trunc)?;
 
     #[derive(Clone)]
-    struct FileStdout(String);
+    struct FileStdout(String, Option<u32>);
     impl FileFactory for FileStdout {
         type Output = FileWriter;
         type Stdout = FileWriter;
         fn build(&self, path: &str, spec: FileSpec) -> io::Result<Self::Output> {
-            open_file(path, spec)
+            open_file(path, spec, self.1)
         }
         fn stdout(&self) -> Self::Stdout {
-            open_file(self.0.as_str(), FileSpec::Append).expect("failed to open stdout")
+            open_file(self.0.as_str(), FileSpec::Append, self.1).expect("failed to open stdout")
         }
     }
-    Ok(FileStdout(fname.into()))
+    Ok(FileStdout(fname.into(), level))
 }
 
 fn build_handle<W: io::Write, F: Fn(FileSpec) -> io::Result<W> + Send + 'static>(
     f: F,
-    is_stdout: bool,
+    line_buffer: bool,
+    open_files: Option<&Arc<OpenFiles>>,
 ) -> RawHandle {
     let (sender, receiver) = bounded(IO_CHAN_SIZE);
     let error = Arc::new(Mutex::new(None));
     let receiver_error = error.clone();
-    std::thread::spawn(move || receive_thread(receiver, receiver_error, f));
+    let open_files = open_files.map(|o| (o.clone(), o.register(sender.clone())));
+    std::thread::spawn(move || receive_thread(receiver, receiver_error, f, open_files));
     RawHandle {
         error,
         sender,
-        line_buffer: is_stdout && grep_cli::is_tty_stdout(),
+        line_buffer,
+        exit_status: Default::default(),
     }
 }
 
@@ -150,24 +170,100 @@ fn build_handle<W: io::Write, F: Fn(FileSpec) -> io::Result<W> + Send + 'static>
 /// Note that handles are never removed, even after a file is closed. The single thread continues
 /// to run and listen for new requests that might trigger a reopen.
 pub struct Registry {
-    global: Arc<dyn Root>,
     files: HashMap<Str<'static>, FileHandle>,
     cmds: HashMap<Str<'static>, FileHandle>,
     stdout: FileHandle,
+    // How output to stdout is grouped by input file while the main loop runs in parallel, and
+    // whether it is currently being grouped; see `start_output_by_file`.
+    output_order: OutputOrder,
+    by_file: bool,
+    // The input file whose output `stdout` is collecting, if any.
+    cur_input: Option<u32>,
+    // Output for input files that has been collected but not yet written, shared with clones of
+    // this registry when output is written in the order of the input files.
+    pending: Arc<Mutex<PendingOutput>>,
+    // NB: `global` must come after the handles. Handles flush their pending output when they are
+    // dropped, and the last reference to `global` closes every file and command; closing first
+    // would make those final writes reopen (and possibly truncate) the file.
+    global: Arc<dyn Root>,
 }
 
 impl Registry {
     pub fn from_factory(f: impl FileFactory) -> Registry {
-        let root_impl = RootImpl::from_factory(f);
+        Registry::with_max_open_files(f, super::max_open_files())
+    }
+
+    /// Create a registry that keeps at most `max_open` of the files written to open at once.
+    pub fn with_max_open_files(f: impl FileFactory, max_open: usize) -> Registry {
+        let root_impl = RootImpl::from_factory(f, max_open);
         let stdout = root_impl.get_stdout().into_handle();
         Registry {
-            global: Arc::new(root_impl),
             files: Default::default(),
             cmds: Default::default(),
             stdout,
+            output_order: OutputOrder::Any,
+            by_file: false,
+            cur_input: None,
+            pending: Default::default(),
+            global: Arc::new(root_impl),
         }
     }
 
+    pub fn set_output_order(&mut self, order: OutputOrder) {
+        self.output_order = order;
+    }
+
+    /// Start grouping output to stdout by input file, unless the output order is `Any`. This
+    /// registry, and clones made of it afterwards, hold on to what they print for each input file
+    /// until it is finished (see `start_input_file`), and then write it out in one piece.
+    pub fn start_output_by_file(&mut self) {
+        self.by_file = self.output_order != OutputOrder::Any;
+    }
+
+    /// Note that subsequent output to stdout is for the input file at position `index`.
+    pub fn start_input_file(&mut self, index: u32) -> Result<()> {
+        if !self.by_file || self.cur_input == Some(index) {
+            return Ok(());
+        }
+        self.end_input_file()?;
+        self.cur_input = Some(index);
+        self.stdout.capture = Some(Vec::new());
+        Ok(())
+    }
+
+    /// Write out, or queue up, the output collected for the current input file.
+    pub fn end_input_file(&mut self) -> Result<()> {
+        let (index, output) = match (self.cur_input.take(), self.stdout.capture.take()) {
+            (Some(index), Some(output)) => (index, output),
+            _ => return Ok(()),
+        };
+        if self.output_order != OutputOrder::Input {
+            return self.stdout.write_block(output);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let pending = &mut *pending;
+        pending.ready.insert(index, output);
+        // Files are numbered from 1.
+        while let Some(output) = pending.ready.remove(&(pending.written + 1)) {
+            pending.written += 1;
+            self.stdout.write_block(output)?;
+        }
+        Ok(())
+    }
+
+    /// Stop grouping output by input file, writing out whatever is left in order. This is called
+    /// once every worker has finished; files that produced no records never report that they are
+    /// finished, so output for the files after them is only written here.
+    pub fn finish_output_by_file(&mut self) -> Result<()> {
+        self.end_input_file()?;
+        self.by_file = false;
+        let mut pending = self.pending.lock().unwrap();
+        for (_, output) in std::mem::take(&mut pending.ready) {
+            self.stdout.write_block(output)?;
+        }
+        Ok(())
+    }
+
     pub fn get_handle(&mut self, name: Option<&Str>, fspec: FileSpec) -> Result<&mut FileHandle> {
         let name = if let Some(s) = name {
             s
@@ -180,21 +276,43 @@ impl Registry {
         }
     }
 
-    pub fn close(&mut self, path_or_cmd: &Str) -> Result<()> {
+    /// Close the file or command `path_or_cmd`, returning the exit status of a command, 0 for a
+    /// file, and -1 if nothing by that name was ever opened.
+    pub fn close(&mut self, path_or_cmd: &Str) -> Result<Int> {
         // TODO: implement a newtype for heterogeneous lookup. We shouldn't have to do the clone or
         // the unmoor here, but we need to because we cannot implement Borrow<Str<'a>> for
         // Borrow<Str<'static>> (conflicts with the blanket impl for Borrow).
         if let Some(fh) = self.files.get_mut(&path_or_cmd.clone().unmoor()) {
-            fh.close()?;
-            return Ok(());
+            return fh.close();
         }
         if let Some(ch) = self.cmds.get_mut(&path_or_cmd.clone().unmoor()) {
-            ch.close()?;
-            return Ok(());
+            return ch.close();
         }
         path_or_cmd.with_bytes(|bs| self.global.close(bs))
     }
 
+    /// Flush pending output to the file or command `path_or_cmd`, returning -1 if nothing by that
+    /// name was ever opened in this registry and 0 otherwise.
+    pub fn flush(&mut self, path_or_cmd: &Str) -> Result<Int> {
+        let key = path_or_cmd.clone().unmoor();
+        let (files, cmds) = (&mut self.files, &mut self.cmds);
+        if let Some(fh) = files.get_mut(&key).or_else(|| cmds.get_mut(&key)) {
+            fh.flush()?;
+            Ok(0)
+        } else {
+            Ok(-1)
+        }
+    }
+
+    /// Flush pending output to stdout and to every file and command open in this registry.
+    pub fn flush_all(&mut self) -> Result<()> {
+        self.stdout.flush()?;
+        for fh in self.files.values_mut().chain(self.cmds.values_mut()) {
+            fh.flush()?;
+        }
+        Ok(())
+    }
+
     pub fn get_cmd(&mut self, cmd: &Str) -> Result<&mut FileHandle> {
         use hashbrown::hash_map::Entry;
         // borrowed by with_bytes closure.
@@ -229,7 +347,9 @@ impl Registry {
     }
 
     pub fn destroy_and_flush_all_files(&mut self) -> Result<()> {
-        let mut last_error = Ok(());
+        // Flush stdout first: commands still open are waited for once the last registry is
+        // dropped, and anything we printed before then should appear before their output.
+        let mut last_error = self.stdout.flush();
         for (_, mut fh) in self.files.drain().chain(self.cmds.drain()) {
             let res = fh.flush();
             if res.is_err() {
@@ -247,45 +367,138 @@ impl Clone for Registry {
             files: Default::default(),
             cmds: Default::default(),
             stdout: self.stdout.raw().into_handle(),
+            output_order: self.output_order,
+            by_file: self.by_file,
+            cur_input: None,
+            pending: self.pending.clone(),
         }
     }
 }
 
+/// Output for input files that have been processed, waiting for the files before them.
+#[derive(Default)]
+struct PendingOutput {
+    // The number of files whose output has been written.
+    written: u32,
+    ready: BTreeMap<u32, Vec<u8>>,
+}
+
 // We place Root behind a trait so that we can maintain static dispatch at the level of the
 // receiver threads, while still avoiding an extra type parameter all the way up the stack.
 trait Root: 'static + Send + Sync {
     fn get_command(&self, cmd: &[u8]) -> RawHandle;
     fn get_handle(&self, fname: &str) -> RawHandle;
     fn get_stdout(&self) -> RawHandle;
-    // closes a file or command with name `fname`.
-    fn close(&self, fname: &[u8]) -> Result<()>;
+    // closes a file or command with name `fname`, with the same return value as Registry::close.
+    fn close(&self, fname: &[u8]) -> Result<Int>;
 }
 
 struct RootImpl<F> {
     handles: Mutex<HashMap<String, RawHandle>>,
     commands: Mutex<HashMap<Box<[u8]>, RawHandle>>,
     stdout_raw: RawHandle,
+    open_files: Arc<OpenFiles>,
     file_factory: F,
 }
 
 impl<F: FileFactory> RootImpl<F> {
-    fn from_factory(file_factory: F) -> RootImpl<F> {
+    fn from_factory(file_factory: F, max_open: usize) -> RootImpl<F> {
         let local_factory = file_factory.clone();
         let stdout_raw = build_handle(
             move |_append| Ok(local_factory.stdout()),
-            /*is_stdout*/ true,
+            /*line_buffer=*/ file_factory.line_buffered(),
+            /*open_files=*/ None,
         );
         RootImpl {
             handles: Default::default(),
             commands: Default::default(),
             stdout_raw,
+            open_files: Arc::new(OpenFiles::new(max_open)),
             file_factory,
         }
     }
 }
 
+/// The output files that writer threads currently have open, shared between all of them.
+///
+/// Commands and stdout are not tracked: unlike files, they cannot be closed and reopened without
+/// the script noticing.
+struct OpenFiles {
+    max: usize,
+    state: Mutex<OpenFilesState>,
+}
+
+#[derive(Default)]
+struct OpenFilesState {
+    // The channel for every file, indexed by its id. These are used to ask writer threads to close
+    // their files.
+    senders: Vec<Sender<Request>>,
+    clock: u64,
+    // When each open file was last written to, and the open files ordered by that time.
+    last_used: HashMap<usize, u64>,
+    by_use: BTreeMap<u64, usize>,
+}
+
+impl OpenFiles {
+    fn new(max: usize) -> OpenFiles {
+        OpenFiles {
+            max: max.max(1),
+            state: Default::default(),
+        }
+    }
+
+    /// Start tracking the file written to via `sender`, returning its id.
+    fn register(&self, sender: Sender<Request>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.senders.push(sender);
+        state.senders.len() - 1
+    }
+
+    /// Record a write to file `id`. If the file was not already open and there are now too many
+    /// open files, ask the writers for the least recently used ones to close them.
+    fn touch(&self, id: usize) {
+        let mut to_close = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let now = state.clock;
+            let opened = match state.last_used.insert(id, now) {
+                Some(prev) => {
+                    state.by_use.remove(&prev);
+                    false
+                }
+                None => true,
+            };
+            state.by_use.insert(now, id);
+            while opened && state.last_used.len() > self.max {
+                let (_, lru) = state.by_use.pop_first().unwrap();
+                state.last_used.remove(&lru);
+                to_close.push(state.senders[lru].clone());
+            }
+        }
+        for sender in to_close {
+            // A writer with a full channel is busy; it will get added back the next time it
+            // writes, and may be closed then instead.
+            let _ = sender.try_send(Request::Suspend);
+        }
+    }
+
+    /// Record that file `id` was closed.
+    fn closed(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(last) = state.last_used.remove(&id) {
+            state.by_use.remove(&last);
+        }
+    }
+
+    /// Drop the channels for all files, so their writer threads can exit.
+    fn clear(&self) {
+        self.state.lock().unwrap().senders.clear();
+    }
+}
+
 impl<F: FileFactory> Root for RootImpl<F> {
-    fn close(&self, fname: &[u8]) -> Result<()> {
+    fn close(&self, fname: &[u8]) -> Result<Int> {
         let mut handle = None;
         {
             let cmds = self.commands.lock().unwrap();
@@ -295,8 +508,7 @@ impl<F: FileFactory> Root for RootImpl<F> {
             }
         }
         if let Some(h) = handle.take() {
-            h.into_handle().close()?;
-            return Ok(());
+            return h.into_handle().close();
         }
         {
             let fname = if let Ok(s) = std::str::from_utf8(fname) {
@@ -304,7 +516,7 @@ impl<F: FileFactory> Root for RootImpl<F> {
             } else {
                 // If this file name is invalid UTF8, we haven't opened it; no need to return an
                 // error.
-                return Ok(());
+                return Ok(-1);
             };
             let files = self.handles.lock().unwrap();
             if let Some(h) = files.get(fname) {
@@ -312,10 +524,9 @@ impl<F: FileFactory> Root for RootImpl<F> {
             }
         }
         if let Some(h) = handle.take() {
-            h.into_handle().close()?;
-            return Ok(());
+            return h.into_handle().close();
         }
-        Ok(())
+        Ok(-1)
     }
     fn get_command(&self, cmd: &[u8]) -> RawHandle {
         let mut cmds = self.commands.lock().unwrap();
@@ -325,10 +536,14 @@ impl<F: FileFactory> Root for RootImpl<F> {
         let local_factory = self.file_factory.clone();
         let local_name = Box::<[u8]>::from(cmd);
         let global_name = local_name.clone();
-        let handle = build_handle(
-            move |_| local_factory.cmd(&local_name),
-            /*is_stdout=*/ false,
+        let exit_status = Arc::<AtomicI64>::default();
+        let local_status = exit_status.clone();
+        let mut handle = build_handle(
+            move |_| local_factory.cmd(&local_name, local_status.clone()),
+            /*line_buffer=*/ false,
+            /*open_files=*/ None,
         );
+        handle.exit_status = exit_status;
         let _old = cmds.insert(global_name, handle.clone());
         debug_assert!(
             _old.is_none(),
@@ -347,7 +562,8 @@ impl<F: FileFactory> Root for RootImpl<F> {
         let global_name = local_name.clone();
         let handle = build_handle(
             move |append| local_factory.build(local_name.as_str(), append),
-            /*is_stdout=*/ false,
+            /*line_buffer=*/ false,
+            Some(&self.open_files),
         );
         handles.insert(global_name, handle.clone());
         handle
@@ -357,6 +573,26 @@ impl<F: FileFactory> Root for RootImpl<F> {
     }
 }
 
+impl<F> Drop for RootImpl<F> {
+    fn drop(&mut self) {
+        // The last registry is going away at the end of the program: wait for any commands we
+        // are still writing to, so their output isn't cut off or interleaved with what follows.
+        if let Ok(cmds) = self.commands.get_mut() {
+            for (_, h) in cmds.drain() {
+                let _ = h.into_handle().close();
+            }
+        }
+        // Close files as well, so that compressed files are finished before we exit.
+        if let Ok(files) = self.handles.get_mut() {
+            for (_, h) in files.drain() {
+                let _ = h.into_handle().close();
+            }
+        }
+        let _ = self.stdout_raw.clone().into_handle().close();
+        self.open_files.clear();
+    }
+}
+
 /// FileHandle contains thread-local state around writing to and closing an output file.
 pub struct FileHandle {
     raw: RawHandle,
@@ -371,6 +607,8 @@ pub struct FileHandle {
     old_guards: Vec<Box<WriteGuard>>,
     guards: VecDeque<Box<WriteGuard>>,
     cur_batch: Box<WriteGuard>,
+    // If set, writes are appended here rather than sent to the file.
+    capture: Option<Vec<u8>>,
 }
 
 impl FileHandle {
@@ -441,7 +679,46 @@ impl FileHandle {
         Ok(())
     }
 
+    /// Write `data` in a single request, so that writes from other handles to the same file are
+    /// not interleaved with it.
+    fn write_block(&mut self, mut data: Vec<u8>) -> Result<()> {
+        self.clear_batch(None)?;
+        std::mem::swap(&mut self.cur_batch.data, &mut data);
+        self.cur_batch.spec = FileSpec::Append;
+        self.clear_batch(None)
+    }
+
+    /// Write `ss` without copying it into a batch first: the writer thread issues the writes
+    /// straight from the bytes of each string, and we wait for it to finish before returning.
+    fn write_direct(&mut self, ss: &[&Str], spec: FileSpec) -> Result<()> {
+        self.clear_batch(None)?;
+        let slices: Vec<io::IoSlice<'static>> = ss
+            .iter()
+            .map(|s| io::IoSlice::new(unsafe { &*s.get_bytes() }))
+            .collect();
+        let status = ErrorCode::default();
+        self.raw
+            .sender
+            .send(Request::WriteVectored {
+                data: &slices[..],
+                status: &status,
+                spec,
+            })
+            .unwrap();
+        // `flush` waits for the request above to complete, so `ss` outlives it.
+        self.flush()
+    }
+
     pub fn write_all(&mut self, ss: &[&Str], spec: FileSpec) -> Result<()> {
+        if let Some(capture) = &mut self.capture {
+            for s in ss.iter() {
+                capture.extend_from_slice(unsafe { &*s.get_bytes() });
+            }
+            return Ok(());
+        }
+        if !self.raw.line_buffer && ss.iter().map(|s| s.len()).sum::<usize>() >= BUFFER_SIZE {
+            return self.write_direct(ss, spec);
+        }
         let cur_len = self.cur_batch.data.len();
         let mut added_bytes = 0;
         let mut last_line = None;
@@ -478,10 +755,15 @@ impl FileHandle {
         }
     }
 
-    pub fn close(&mut self) -> Result<()> {
+    /// Flush and close the file, returning the exit status of the command being written to (0
+    /// for files).
+    pub fn close(&mut self) -> Result<Int> {
         self.clear_batch(None)?;
         self.raw.sender.send(Request::Close).unwrap();
-        self.flush()
+        self.flush()?;
+        // Commands are waited for when their writer is dropped, which happens before the flush
+        // above completes.
+        Ok(self.raw.exit_status.load(Ordering::Acquire))
     }
 }
 
@@ -541,8 +823,18 @@ enum Request {
         spec: FileSpec,
         flush: bool,
     },
+    // A write of several buffers that the client waits on, used to write large strings without
+    // copying them.
+    WriteVectored {
+        data: *const [io::IoSlice<'static>],
+        status: *const ErrorCode,
+        spec: FileSpec,
+    },
     Flush(Arc<(ErrorCode, Notification)>),
     Close,
+    // Close the file to stay under the limit on open files. Unlike Close, the file is reopened for
+    // appending the next time it is written to.
+    Suspend,
 }
 
 // This isn't implemented automatically because of the raw pointers in Write. Those pointers are
@@ -562,17 +854,22 @@ impl Request {
             // length here should _always_ be safe. This is tracked by the {const_}slice_ptr_len
             // feature.
             Request::Write { data, .. } => unsafe { &**data }.len(),
-            Request::Flush(_) | Request::Close => 0,
+            Request::WriteVectored { data, .. } => {
+                unsafe { &**data }.iter().map(|slice| slice.len()).sum()
+            }
+            Request::Flush(_) | Request::Close | Request::Suspend => 0,
         }
     }
     fn set_code(&self, mut f: impl FnMut(&ErrorCode)) {
         match self {
-            Request::Write { status, .. } => f(unsafe { &**status }),
+            Request::Write { status, .. } | Request::WriteVectored { status, .. } => {
+                f(unsafe { &**status })
+            }
             Request::Flush(n) => {
                 f(&n.0);
                 n.1.notify();
             }
-            Request::Close => {}
+            Request::Close | Request::Suspend => {}
         }
     }
 }
@@ -580,7 +877,7 @@ impl Request {
 impl Drop for Request {
     fn drop(&mut self) {
         match self {
-            Request::Write { status, .. } => {
+            Request::Write { status, .. } | Request::WriteVectored { status, .. } => {
                 // We have to have set this as either ok, or an error.
                 let status = unsafe { &**status }.read();
                 assert!(!matches!(status, RequestStatus::Ongoing));
@@ -588,7 +885,7 @@ impl Drop for Request {
             Request::Flush(n) => {
                 assert!(n.1.has_been_notified());
             }
-            Request::Close => {}
+            Request::Close | Request::Suspend => {}
         }
     }
 }
@@ -646,6 +943,7 @@ struct RawHandle {
     error: Arc<Mutex<Option<CompileError>>>,
     sender: Sender<Request>,
     line_buffer: bool,
+    exit_status: Arc<AtomicI64>,
 }
 
 impl RawHandle {
@@ -655,6 +953,7 @@ impl RawHandle {
             raw: self,
             guards: Default::default(),
             old_guards: Default::default(),
+            capture: None,
         }
     }
 }
@@ -669,6 +968,7 @@ struct WriteBatch {
     write_bytes: usize,
     flush: bool,
     close: bool,
+    suspend: bool,
 }
 
 #[cfg(feature = "unstable")]
@@ -678,14 +978,38 @@ fn write_all(batch: &mut WriteBatch, w: &mut impl Write) -> io::Result</*close=*
 
 #[cfg(not(feature = "unstable"))]
 fn write_all(batch: &mut WriteBatch, w: &mut impl Write) -> io::Result</*close=*/ bool> {
-    batch
-        .io_vec
-        .iter()
-        .map(|slice| {
-            let buf: &[u8] = slice.deref();
-            w.write_all(buf)
-        })
-        .fold_results(true, |a, _| a)
+    // A stable version of write_all_vectored. Rather than splitting a partially-written slice in
+    // place, we write out its remainder on its own and carry on with the slices after it.
+    let mut bufs = &batch.io_vec[..];
+    while !bufs.is_empty() {
+        if bufs[0].is_empty() {
+            bufs = &bufs[1..];
+            continue;
+        }
+        let mut written = match w.write_vectored(bufs) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(slice) = bufs.first() {
+            if written < slice.len() {
+                break;
+            }
+            written -= slice.len();
+            bufs = &bufs[1..];
+        }
+        if written > 0 {
+            w.write_all(&bufs[0][written..])?;
+            bufs = &bufs[1..];
+        }
+    }
+    Ok(true)
 }
 
 impl WriteBatch {
@@ -707,7 +1031,7 @@ impl WriteBatch {
 
     fn get_spec(&self) -> FileSpec {
         for req in self.requests.iter() {
-            if let Request::Write { spec, .. } = req {
+            if let Request::Write { spec, .. } | Request::WriteVectored { spec, .. } = req {
                 return *spec;
             }
         }
@@ -724,8 +1048,19 @@ impl WriteBatch {
                 self.n_writes += 1;
                 self.flush |= *flush;
             }
+            Request::WriteVectored { data, .. } => {
+                for slice in unsafe { &**data }.iter() {
+                    self.write_bytes += slice.len();
+                    self.io_vec.push(io::IoSlice::new(slice));
+                }
+                self.n_writes += 1;
+            }
             Request::Flush(_) => self.flush = true,
             Request::Close => self.close = true,
+            Request::Suspend => {
+                self.close = true;
+                self.suspend = true;
+            }
         };
         self.requests.push(req);
         self.flush || self.close
@@ -736,6 +1071,7 @@ impl WriteBatch {
             req.set_code(&mut f)
         }
         self.close = false;
+        self.suspend = false;
         self.flush = false;
         self.n_writes = 0;
         self.write_bytes = 0;
@@ -752,9 +1088,11 @@ fn receive_thread<W: io::Write>(
     receiver: Receiver<Request>,
     error: Arc<Mutex<Option<CompileError>>>,
     f: impl Fn(FileSpec) -> io::Result<W>,
+    open_files: Option<(Arc<OpenFiles>, usize)>,
 ) {
     let mut batch = WriteBatch::default();
-    if let Err(e) = receive_loop(&receiver, &mut batch, f) {
+    if let Err(e) = receive_loop(&receiver, &mut batch, f, open_files.as_ref()) {
+        super::record_io_error(e.kind());
         // We got an error! install it in the `error` mutex.
         {
             let mut err = error.lock().unwrap();
@@ -773,12 +1111,15 @@ fn receive_loop<W: io::Write>(
     receiver: &Receiver<Request>,
     batch: &mut WriteBatch,
     f: impl Fn(FileSpec) -> io::Result<W>,
+    open_files: Option<&(Arc<OpenFiles>, usize)>,
 ) -> io::Result<()> {
     const MAX_BATCH_BYTES: usize = 1 << 20;
     const MAX_BATCH_SIZE: usize = 1 << 10;
 
     // Writer starts off closed. We use `f` to open it if a write appears.
     let mut writer = None;
+    // Set when the writer was closed to stay under the limit on open files.
+    let mut suspended = false;
 
     while let Ok(req) = receiver.recv() {
         // We build up a reasonably-sized batch of writes in the channel if it contains pending
@@ -805,23 +1146,41 @@ fn receive_loop<W: io::Write>(
         if writer.is_none() {
             if batch.n_writes() == 0 {
                 // check for a "flush/close-only batch", which we treat as a noop if the file is
-                // closed.
+                // closed. Closing a suspended file means it is truncated when it is reopened.
+                if batch.close && !batch.suspend {
+                    suspended = false;
+                }
                 batch.clear();
                 continue;
             }
             // We need to (re)open the file, the first write request will tell us whether or not
-            // this is an append request.
-            writer = Some(f(batch.get_spec())?);
+            // this is an append request. Suspended files pick up where they left off.
+            let spec = if suspended {
+                FileSpec::Append
+            } else {
+                batch.get_spec()
+            };
+            writer = Some(f(spec)?);
         }
+        if let Some((open_files, id)) = open_files {
+            open_files.touch(*id);
+        }
+        let suspend = batch.suspend;
         if batch.issue(writer.as_mut().unwrap())? {
             writer = None;
+            suspended = suspend;
+            if let Some((open_files, id)) = open_files {
+                open_files.closed(*id);
+            }
         }
     }
     Ok(())
 }
 
+#[cfg(test)]
 pub mod testing {
     use super::*;
+    use std::sync::atomic::AtomicBool;
 
     /// A file factory that writes all data in memory; used for unit testing.
     #[derive(Clone, Default)]
@@ -944,6 +1303,59 @@ mod tests {
         assert_eq!(&data[..], "hello therehello there".as_bytes());
     }
 
+    #[test]
+    fn large_writes() {
+        let big = Str::from("x".repeat(BUFFER_SIZE));
+        let sep = Str::from(" ");
+        let fs = FakeFs::default();
+        let mut reg = Registry::from_factory(fs.clone());
+        {
+            let handle = reg
+                .get_handle(/*stdout*/ None, FileSpec::default())
+                .unwrap();
+            handle.write(&sep, FileSpec::Append).unwrap();
+            handle
+                .write_all(&[&big, &sep, &big], FileSpec::Append)
+                .unwrap();
+            handle.write(&sep, FileSpec::Append).unwrap();
+            handle.flush().unwrap();
+        }
+        let expected = format!(" {} {} ", "x".repeat(BUFFER_SIZE), "x".repeat(BUFFER_SIZE));
+        assert_eq!(fs.stdout.read_data(), expected.as_bytes());
+    }
+
+    #[test]
+    fn partial_vectored_writes() {
+        // Accepts at most 3 bytes per call.
+        struct Trickle(Vec<u8>);
+        impl Write for Trickle {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                let n = bytes.len().min(3);
+                self.0.extend_from_slice(&bytes[..n]);
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+            fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+                let mut written = 0;
+                for b in bufs {
+                    let n = b.len().min(3 - written);
+                    self.0.extend_from_slice(&b[..n]);
+                    written += n;
+                }
+                Ok(written)
+            }
+        }
+        let mut batch = WriteBatch::default();
+        for s in ["ab", "", "cdefg", "h", "ijkl"].iter() {
+            batch.io_vec.push(io::IoSlice::new(s.as_bytes()));
+        }
+        let mut w = Trickle(Vec::new());
+        write_all(&mut batch, &mut w).unwrap();
+        assert_eq!(w.0, b"abcdefghijkl");
+    }
+
     #[test]
     fn reopen_named_file() {
         let fname_str = "/fake";
@@ -971,6 +1383,66 @@ mod tests {
         assert_eq!(&data[..], "hello there".as_bytes());
     }
 
+    #[test]
+    fn close_least_recently_used() {
+        let fs = FakeFs::default();
+        let mut reg = Registry::with_max_open_files(fs.clone(), 2);
+        let names: Vec<_> = (0..5).map(|i| format!("/fake/{}", i)).collect();
+        for round in 0..3 {
+            for name in names.iter() {
+                let handle = reg
+                    .get_handle(Some(&name.as_str().into()), FileSpec::Trunc)
+                    .unwrap();
+                handle
+                    .write(&Str::from(format!("{}", round)), FileSpec::Trunc)
+                    .unwrap();
+                handle.flush().unwrap();
+            }
+        }
+        // Files that were closed to make room for others are appended to when they are reopened.
+        for name in names.iter() {
+            assert_eq!(fs.get_handle(name).unwrap().read_data(), b"012");
+        }
+        // Closing a file explicitly still truncates it the next time it is written to.
+        let name = Str::from("/fake/0");
+        reg.close(&name).unwrap();
+        let handle = reg.get_handle(Some(&name), FileSpec::Trunc).unwrap();
+        handle.write(&Str::from("3"), FileSpec::Trunc).unwrap();
+        handle.flush().unwrap();
+        assert_eq!(fs.get_handle("/fake/0").unwrap().read_data(), b"3");
+    }
+
+    #[test]
+    fn output_in_input_order() {
+        let fs = FakeFs::default();
+        let mut reg = Registry::from_factory(fs.clone());
+        reg.set_output_order(OutputOrder::Input);
+        reg.start_output_by_file();
+        let mut worker = reg.clone();
+        let print = |reg: &mut Registry, index: u32, s: &str| {
+            reg.start_input_file(index).unwrap();
+            reg.get_handle(None, FileSpec::default())
+                .unwrap()
+                .write(&Str::from(s), FileSpec::Append)
+                .unwrap();
+        };
+        print(&mut worker, 3, "3a ");
+        print(&mut reg, 1, "1a ");
+        print(&mut worker, 3, "3b ");
+        print(&mut reg, 1, "1b ");
+        // Nothing for file 4 can be written until files 2 and 3 are finished.
+        print(&mut reg, 4, "4 ");
+        print(&mut worker, 5, "5 ");
+        reg.flush_all().unwrap();
+        assert_eq!(fs.stdout.read_data(), b"1a 1b ");
+        // File 2 is empty, so whatever follows it is written once the workers are done.
+        worker.end_input_file().unwrap();
+        drop(worker);
+        reg.finish_output_by_file().unwrap();
+        reg.flush_all().unwrap();
+        assert_eq!(fs.stdout.read_data(), b"1a 1b 3a 3b 4 5 ");
+    }
+
     #[test]
     fn multithreaded_write() {
         const N_THREADS: usize = 100;
@@ -0,0 +1,149 @@
+//! Checks for the `--sandbox` flag, which rules out programs that could touch the system outside
+//! of reading their input and writing to standard output and standard error.
+//!
+//! A sandboxed program may not call `system`, pipe output to or read input from a command, redirect
+//! output to a file, read from a file with `getline`, or save and restore arrays with `writea` and
+//! `reada`. The only redirections it may use are to the special files `/dev/stdout` and
+//! `/dev/stderr` (or, for `getline`, `/dev/stdin` and `-`), and only if they are written as string
+//! literals. This also rules out the network files in `/inet`.
+//!
+//! Since frawk has no `eval`, and a program's input files come from its command line, checking the
+//! syntax of the program is enough.
+use crate::ast::{Expr, FunDec, Pattern, Prog, Stmt};
+use crate::builtins::Function;
+use crate::common::{Either, FileSpec};
+
+use std::convert::TryFrom;
+
+type Result = std::result::Result<(), String>;
+
+/// Return an error describing the first thing in `prog` that `--sandbox` rules out.
+pub(crate) fn check<'a, 'b, I: Clone>(prog: &Prog<'a, 'b, I>) -> Result
+where
+    Function: TryFrom<I>,
+{
+    for FunDec { body, .. } in prog.decs.iter() {
+        stmt(body)?;
+    }
+    for s in prog
+        .begin
+        .iter()
+        .chain(prog.prepare.iter())
+        .chain(prog.end.iter())
+    {
+        stmt(s)?;
+    }
+    for (pat, body) in prog.pats.iter() {
+        match pat {
+            Pattern::Null => {}
+            Pattern::Bool(e) => expr(e)?,
+            Pattern::Comma(l, r) => {
+                expr(l)?;
+                expr(r)?;
+            }
+        }
+        if let Some(body) = body {
+            stmt(body)?;
+        }
+    }
+    Ok(())
+}
+
+fn disallowed(what: &str) -> Result {
+    Err(format!("{} is not allowed with --sandbox", what))
+}
+
+/// Whether `e` is a string literal naming one of `files`.
+fn is_special_file<I>(e: &Expr<I>, files: &[&str]) -> bool {
+    matches!(e, Expr::StrLit(s) if files.iter().any(|f| f.as_bytes() == *s))
+}
+
+fn output<'a, 'b, I>(out: &Option<(&'a Expr<'a, 'b, I>, FileSpec)>) -> Result {
+    match out {
+        None => Ok(()),
+        Some((_, FileSpec::Cmd)) => disallowed("piping output to a command"),
+        Some((e, _)) if is_special_file(e, &["/dev/stdout", "/dev/stderr"]) => Ok(()),
+        Some(_) => disallowed("redirecting output to a file"),
+    }
+}
+
+fn expr<'a, 'b, I: Clone>(e: &Expr<'a, 'b, I>) -> Result
+where
+    Function: TryFrom<I>,
+{
+    use Expr::*;
+    match e {
+        ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | Var(_) | ReadStdin | Cond(_) => Ok(()),
+        Unop(_, e) => expr(e),
+        Binop(_, l, r) | Index(l, r) | Assign(l, r) | AssignOp(l, _, r) | And(l, r) | Or(l, r) => {
+            expr(l)?;
+            expr(r)
+        }
+        ITE(c, t, f) => {
+            expr(c)?;
+            expr(t)?;
+            expr(f)
+        }
+        Inc { x, .. } => expr(x),
+        Getline {
+            from: Some(_),
+            is_file: false,
+            ..
+        } => disallowed("getline from a command"),
+        Getline {
+            from: Some(from), ..
+        } if !is_special_file(from, &["/dev/stdin", "-"]) => disallowed("getline from a file"),
+        Getline { into, .. } => into.map_or(Ok(()), expr),
+        Call(f, args) => {
+            let f = match f {
+                Either::Left(name) => Function::try_from(name.clone()).ok(),
+                Either::Right(f) => Some(*f),
+            };
+            match f {
+                Some(Function::System) => return disallowed("system()"),
+                Some(Function::ReadArray) => return disallowed("reada()"),
+                Some(Function::WriteArray) => return disallowed("writea()"),
+                _ => {}
+            }
+            args.iter().try_for_each(|e| expr(e))
+        }
+    }
+}
+
+fn stmt<'a, 'b, I: Clone>(s: &Stmt<'a, 'b, I>) -> Result
+where
+    Function: TryFrom<I>,
+{
+    use Stmt::*;
+    match s {
+        StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile => Ok(()),
+        Expr(e) => expr(e),
+        Return(e) => e.map_or(Ok(()), expr),
+        Block(stmts) => stmts.iter().try_for_each(|s| stmt(s)),
+        Print(args, out) => {
+            output(out)?;
+            args.iter().try_for_each(|e| expr(e))
+        }
+        Printf(fmt, args, out) => {
+            output(out)?;
+            expr(fmt)?;
+            args.iter().try_for_each(|e| expr(e))
+        }
+        If(c, t, f) => {
+            expr(c)?;
+            stmt(t)?;
+            f.map_or(Ok(()), stmt)
+        }
+        For(init, cond, update, body) => {
+            init.map_or(Ok(()), stmt)?;
+            cond.map_or(Ok(()), expr)?;
+            update.map_or(Ok(()), stmt)?;
+            stmt(body)
+        }
+        DoWhile(c, body) | While(_, c, body) | ForEach(_, c, body) => {
+            expr(c)?;
+            stmt(body)
+        }
+        Loc(_, s) => stmt(s),
+    }
+}
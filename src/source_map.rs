@@ -0,0 +1,189 @@
+//! Mapping bytecode back to the program text, for reporting errors at runtime.
+//!
+//! The parser wraps statements in a `Stmt::Loc` recording where they start. When a basic block in
+//! the CFG gets a statement from a different location than the one before it, it is marked with a
+//! `PrimStmt::Loc`, which is lowered to an `Instr::Loc` in the bytecode. Once the bytecode has been
+//! optimized, `SourceMap::strip` removes these markers and records where they were, so the
+//! interpreter does not spend any time on them. When an instruction fails, the interpreter looks
+//! up the last marker before it to say where in the program the error occurred, along with the
+//! rule (or function) that contains it.
+//!
+//! Compiled code has no instruction offsets to look up. Instead, the JIT backends lower each
+//! marker to a store of the location into `Runtime::loc` (see `encode`), and calls to functions are
+//! followed by another marker restoring the caller's location. Runtime failures then report the
+//! location last stored. Only the `rules` of the map are used there.
+use crate::ast::Rule;
+use crate::bytecode::Instr;
+use crate::common::CompileError;
+use crate::lexer::SourceLoc;
+use crate::peephole;
+use crate::runtime::Int;
+
+/// Pack `loc` into a single integer, for compiled code to store: the line plus one in the high
+/// 32 bits and the column in the low 32, with 0 standing for no location.
+pub(crate) fn encode(loc: Option<SourceLoc>) -> Int {
+    match loc {
+        Some(SourceLoc { line, col }) => ((line as Int + 1) << 32) | col as Int,
+        None => 0,
+    }
+}
+
+/// The inverse of `encode`.
+pub(crate) fn decode(code: Int) -> Option<SourceLoc> {
+    if code == 0 {
+        return None;
+    }
+    Some(SourceLoc {
+        line: ((code >> 32) - 1) as u32,
+        col: code as u32,
+    })
+}
+
+#[derive(Default)]
+pub(crate) struct SourceMap<'a> {
+    // For each function, the offsets of the instructions at which the location changes, in
+    // increasing order.
+    pub(crate) locs: Vec<Vec<(usize, Option<SourceLoc>)>>,
+    // Where each toplevel item starts, in the order they appear in the program.
+    pub(crate) rules: Vec<(SourceLoc, Rule<&'a str>)>,
+}
+
+impl<'a> SourceMap<'a> {
+    /// Remove the `Loc` markers from `funcs`, recording the positions of the instructions that
+    /// follow them.
+    pub(crate) fn strip(
+        funcs: &mut [Vec<Instr>],
+        rules: Vec<(SourceLoc, Rule<&'a str>)>,
+    ) -> SourceMap<'a> {
+        let mut locs = Vec::with_capacity(funcs.len());
+        for instrs in funcs.iter_mut() {
+            let mut func_locs: Vec<(usize, Option<SourceLoc>)> = Vec::new();
+            let mut removed = Vec::with_capacity(instrs.len());
+            let mut kept = 0;
+            for inst in instrs.iter() {
+                let loc = match inst {
+                    Instr::Loc(loc) => *loc,
+                    _ => {
+                        removed.push(false);
+                        kept += 1;
+                        continue;
+                    }
+                };
+                removed.push(true);
+                match func_locs.last_mut() {
+                    // The previous marker has no instructions after it.
+                    Some((offset, prev)) if *offset == kept => *prev = loc,
+                    Some((_, prev)) if *prev == loc => {}
+                    _ => func_locs.push((kept, loc)),
+                }
+            }
+            peephole::compact(instrs, &removed);
+            locs.push(func_locs);
+        }
+        SourceMap { locs, rules }
+    }
+
+    /// A map that can only describe locations, for compiled code.
+    pub(crate) fn from_rules(rules: Vec<(SourceLoc, Rule<&'a str>)>) -> SourceMap<'a> {
+        SourceMap {
+            locs: Vec::new(),
+            rules,
+        }
+    }
+
+    /// The location of the `offset`th instruction in function `func`, if it is known.
+    pub(crate) fn locate(&self, func: usize, offset: usize) -> Option<SourceLoc> {
+        let locs = self.locs.get(func)?;
+        let i = locs.partition_point(|(start, _)| *start <= offset);
+        locs.get(i.checked_sub(1)?)?.1
+    }
+
+    /// The index in `rules` of the toplevel item containing `loc`.
+    pub(crate) fn rule_index(&self, loc: SourceLoc) -> Option<usize> {
+        let i = self.rules.partition_point(|(start, _)| *start <= loc);
+        i.checked_sub(1)
+    }
+
+    /// A description of the `i`th toplevel item, e.g. "BEGIN" or "function f".
+    pub(crate) fn rule_name(&self, i: usize) -> String {
+        match &self.rules[i] {
+            (_, Rule::Begin) => "BEGIN".into(),
+            (_, Rule::Prepare) => "PREPARE".into(),
+            (_, Rule::End) => "END".into(),
+            (_, Rule::Function(name)) => format!("function {}", name),
+            (start, Rule::Pattern) => format!("the rule at {}", start),
+        }
+    }
+
+    /// `loc`, along with the rule (or function) that contains it.
+    pub(crate) fn describe(&self, loc: SourceLoc) -> String {
+        match self.rule_index(loc) {
+            Some(i) => format!("{} (in {})", loc, self.rule_name(i)),
+            None => loc.to_string(),
+        }
+    }
+
+    /// Add the location of the `offset`th instruction in function `func` to an error that it
+    /// raised.
+    pub(crate) fn annotate(&self, e: CompileError, func: usize, offset: usize) -> CompileError {
+        match self.locate(func, offset) {
+            Some(loc) => CompileError(format!("{}: {}", self.describe(loc), e)),
+            None => e,
+        }
+    }
+
+    /// Add `loc`, as given by `encode`, to the message `msg` of an error raised by compiled code.
+    pub(crate) fn annotate_encoded(&self, msg: String, loc: Int) -> String {
+        match decode(loc) {
+            Some(loc) => format!("{}: {}", self.describe(loc), msg),
+            None => msg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Label;
+    use Instr::*;
+
+    fn loc(line: u32, col: u32) -> Option<SourceLoc> {
+        Some(SourceLoc { line, col })
+    }
+
+    #[test]
+    fn strip_markers() {
+        let mut funcs = vec![vec![
+            Loc(loc(0, 8)),
+            Jmp(Label(3)),
+            Loc(None),
+            Loc(loc(1, 4)),
+            Ret,
+            Loc(loc(1, 4)),
+            Ret,
+        ]];
+        let rules = vec![
+            (SourceLoc { line: 0, col: 0 }, Rule::Begin),
+            (SourceLoc { line: 1, col: 0 }, Rule::Pattern),
+        ];
+        let map = SourceMap::strip(&mut funcs, rules);
+        assert_eq!(
+            format!("{:?}", funcs[0]),
+            format!("{:?}", vec![Jmp(Label(1)), Ret, Ret])
+        );
+        assert_eq!(map.locs[0], vec![(0, loc(0, 8)), (1, loc(1, 4))]);
+        let annotate = |offset| map.annotate(CompileError("oops".into()), 0, offset).0;
+        assert_eq!(annotate(0), "line 1, column 9 (in BEGIN): oops");
+        assert_eq!(
+            annotate(2),
+            "line 2, column 5 (in the rule at line 2, column 1): oops"
+        );
+        assert_eq!(map.annotate(CompileError("oops".into()), 1, 0).0, "oops");
+        assert_eq!(
+            map.annotate_encoded("oops".into(), encode(loc(0, 8))),
+            "line 1, column 9 (in BEGIN): oops"
+        );
+        assert_eq!(map.annotate_encoded("oops".into(), encode(None)), "oops");
+        assert_eq!(decode(encode(loc(7, 0))), loc(7, 0));
+    }
+}
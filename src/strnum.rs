@@ -0,0 +1,180 @@
+//! This module tracks which strings in a (typed) frawk program come straight from input.
+//!
+//! POSIX awk distinguishes two kinds of string. String constants, and strings computed by the
+//! program, are plain strings: comparing two of them always compares their bytes. Strings read
+//! from input (fields, variables assigned by getline, and the elements of an array filled in by
+//! split) are "strnums": when two of them are compared and both look like numbers, they are
+//! compared as numbers. So with the input "10 10.0":
+//!
+//! > $1 == $2
+//!
+//! is true, while
+//!
+//! > "10" == "10.0"
+//!
+//! is false.
+//!
+//! frawk's types do not record where a string came from, so we recover it statically here. A
+//! comparison between two string registers that are only ever assigned input is rewritten to
+//! a dedicated [`StrNumCmp`](crate::bytecode::Instr::StrNumCmp) instruction. Like the taint
+//! analysis, this is flow-insensitive and conservative: if a register might hold a computed
+//! string, it compares as a string.
+//!
+//! * Assigning anything but input to a field makes all fields plain strings, as we do not track
+//!   individual columns.
+//! * Array keys are never strnums, and array values lose the property if the array is passed to a
+//!   function.
+//! * A comparison between input and a number is also rewritten, with the number converted to a
+//!   string first: input that does not look like a number then compares as a string, so `$1 < 1`
+//!   is false for "abc". Numbers print exactly (see `From<Float> for Str`), so numeric input
+//!   still compares as before.
+use crate::bytecode::{Accum, Instr, Reg};
+use crate::common::NumTy;
+use crate::compile::{HighLevel, Ty};
+use crate::dataflow::{self, JoinSemiLattice, Key};
+use crate::runtime::Int;
+
+/// Where a string came from. The ordering is also the lattice order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Provenance {
+    /// Nothing has been assigned (yet).
+    Unknown,
+    Input,
+    Other,
+}
+
+impl JoinSemiLattice for Provenance {
+    type Func = ();
+    fn bottom() -> Provenance {
+        Provenance::Unknown
+    }
+    fn invoke(&mut self, other: &Self, (): &()) -> bool /* changed */ {
+        if *other > *self {
+            *self = *other;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct StrNumAnalysis {
+    dfa: dataflow::Analysis<Provenance>,
+    // One of the registers that reads a column, which all other column reads depend on.
+    column: Option<Key>,
+    // The values of column assignments.
+    set_columns: Vec<Key>,
+}
+
+impl StrNumAnalysis {
+    /// Function arguments are not tracked precisely (see dataflow::boilerplate), so they are
+    /// never treated as input.
+    pub(crate) fn add_arg(&mut self, reg: NumTy, ty: Ty) {
+        self.dfa.add_src(Key::Reg(reg, ty), Provenance::Other);
+        if ty.is_array() {
+            self.dfa.add_src(Key::MapVal(reg, ty), Provenance::Other);
+        }
+    }
+
+    pub(crate) fn visit_hl(&mut self, cur_fn_id: NumTy, inst: &HighLevel) {
+        if let HighLevel::Call { args, .. } = inst {
+            // Arrays are passed by reference, and the callee may store anything in them.
+            for (reg, ty) in args.iter().cloned().filter(|(_, ty)| ty.is_array()) {
+                self.dfa.add_src(Key::MapVal(reg, ty), Provenance::Other);
+            }
+        }
+        dataflow::boilerplate::visit_hl(inst, cur_fn_id, |dst, src| {
+            self.dfa.add_dep(dst, src.unwrap(), ())
+        })
+    }
+
+    pub(crate) fn visit_ll(&mut self, inst: &Instr) {
+        use Instr::*;
+        match inst {
+            // Uninitialized variables start out empty, and the empty string never looks like a
+            // number, so it compares the same way whether or not it is treated as input. Leaving
+            // it out keeps variables that getline may not assign to (at the end of the input)
+            // strnums.
+            StoreConstStr(_, s) if s.literal_bytes().is_empty() => {}
+            GetColumn(dst, _) => {
+                let dst = Key::from(dst);
+                self.dfa.add_src(dst, Provenance::Input);
+                match self.column {
+                    Some(col) => self.dfa.add_dep(dst, col, ()),
+                    None => self.column = Some(dst),
+                }
+            }
+            SetColumn(_, src) => self.set_columns.push(src.into()),
+            NextLine(dst, _, _) | NextLineStdin(dst) => self.dfa.add_src(dst, Provenance::Input),
+            SplitInt(dst1, _, dst2, _) => self.split(dst1, dst2),
+            SplitStr(dst1, _, dst2, _) => self.split(dst1, dst2),
+            Lookup {
+                map_ty, dst, map, ..
+            }
+            | LookupConstKey {
+                map_ty, dst, map, ..
+            } => {
+                // Lookups of missing keys insert an empty value; uninitialized values are strnums
+                // too, so there is no need to record that.
+                let dst = Key::Reg(*dst, map_ty.val().unwrap());
+                self.dfa.add_dep(dst, Key::MapVal(*map, *map_ty), ())
+            }
+            Store {
+                map_ty, map, val, ..
+            }
+            | StoreConstKey {
+                map_ty, map, val, ..
+            } => {
+                let val = Key::Reg(*val, map_ty.val().unwrap());
+                self.dfa.add_dep(Key::MapVal(*map, *map_ty), val, ())
+            }
+            Mov(..) | LoadSlot { .. } | StoreSlot { .. } => {
+                dataflow::boilerplate::visit_ll(inst, |dst, src| {
+                    self.dfa.add_dep(dst, src.unwrap(), ())
+                })
+            }
+            LoadVarIntMap(_, v)
+            | StoreVarIntMap(v, _)
+            | LoadVarStrMap(_, v)
+            | StoreVarStrMap(v, _) => {
+                self.dfa.add_src(Key::VarVal(*v), Provenance::Other);
+                dataflow::boilerplate::visit_ll(inst, |dst, src| {
+                    self.dfa.add_dep(dst, src.unwrap(), ())
+                })
+            }
+            // Everything else computes a new value.
+            _ => dataflow::boilerplate::visit_ll(inst, |dst, _| {
+                self.dfa.add_src(dst, Provenance::Other)
+            }),
+        }
+    }
+
+    fn split(&mut self, len: &Reg<Int>, arr: &impl Accum) {
+        self.dfa.add_src(len, Provenance::Other);
+        let (reg, ty) = arr.reflect();
+        self.dfa.add_src(Key::MapVal(reg, ty), Provenance::Input);
+    }
+
+    /// Record a string comparison of `l` and `r`, to be rewritten if both are input.
+    pub(crate) fn add_cmp(&mut self, l: impl Into<Key>, r: impl Into<Key>) {
+        self.dfa.add_query(l);
+        self.dfa.add_query(r);
+    }
+
+    /// Record a comparison of `s` with a number, to be rewritten if `s` is input.
+    pub(crate) fn add_num_cmp(&mut self, s: impl Into<Key>) {
+        self.dfa.add_query(s);
+    }
+
+    /// Whether `k` (previously passed to `add_cmp` or `add_num_cmp`) only ever holds strings read from input.
+    pub(crate) fn is_input(&mut self, k: impl Into<Key>) -> bool {
+        // Column assignments are only known once the whole program has been visited.
+        if let Some(col) = self.column {
+            for src in self.set_columns.drain(..) {
+                self.dfa.add_dep(col, src, ());
+            }
+        }
+        *self.dfa.query(k) == Provenance::Input
+    }
+}
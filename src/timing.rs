@@ -0,0 +1,94 @@
+//! Wall-clock time spent in each phase of running a program, for `--timing`.
+//!
+//! Phases are recorded in order: each one is charged the time since the previous one ended. The
+//! report goes to standard error once the program has finished, and includes how quickly the
+//! input was processed, so that the time spent compiling a program can be weighed against the time
+//! spent running it.
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The number of bytes of input read so far.
+static INPUT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// A reader that counts the bytes read through it towards the total in the timing report.
+pub(crate) struct Counted<R>(pub R);
+
+impl<R: io::Read> io::Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        INPUT_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+pub(crate) struct Timing {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        let now = Instant::now();
+        Timing {
+            start: now,
+            last: now,
+            phases: Vec::new(),
+        }
+    }
+}
+
+impl Timing {
+    /// Charge the time since the last phase ended to `phase`.
+    pub(crate) fn end_phase(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, time)) => *time += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Write the time spent in each phase, followed by the rate at which the `records` records
+    /// of input were processed during the "execution" phase.
+    pub(crate) fn report(&self, records: i64, w: &mut impl Write) -> io::Result<()> {
+        let width = self.phases.iter().map(|(name, _)| name.len()).max();
+        let width = width.unwrap_or(0).max("total".len());
+        for (name, time) in self.phases.iter() {
+            writeln!(
+                w,
+                "{:width$}  {:>10.6}s",
+                name,
+                time.as_secs_f64(),
+                width = width
+            )?;
+        }
+        let total = self.last - self.start;
+        writeln!(
+            w,
+            "{:width$}  {:>10.6}s",
+            "total",
+            total.as_secs_f64(),
+            width = width
+        )?;
+        let bytes = INPUT_BYTES.load(Ordering::Relaxed);
+        write!(w, "{} records, {} bytes of input", records, bytes)?;
+        let secs = self
+            .phases
+            .iter()
+            .find(|(name, _)| *name == "execution")
+            .map(|(_, time)| time.as_secs_f64())
+            .unwrap_or(0.0);
+        if secs > 0.0 && (records > 0 || bytes > 0) {
+            write!(
+                w,
+                " ({:.0} records/s, {:.2} MB/s)",
+                records as f64 / secs,
+                bytes as f64 / secs / 1e6
+            )?;
+        }
+        writeln!(w)
+    }
+}
@@ -0,0 +1,185 @@
+//! Checks that a bytecode program is safe to interpret.
+//!
+//! The interpreter fetches instructions and reads registers without bounds checks. That is only
+//! sound if every register index is in bounds for its type, every jump lands on an instruction in
+//! the same function, and control can never run off the end of a function. The compiler should
+//! uphold all of these, but bytecode can also be loaded from disk, so `Interp::new` checks them
+//! once up front with `verify`.
+use crate::bytecode::Instr;
+use crate::common::{Result, Stage};
+use crate::compile::Ty;
+use crate::peephole;
+
+/// Verify the functions in `instrs`, given the entry points in `stage` and the number of
+/// registers of each type.
+pub(crate) fn verify(
+    instrs: &[Vec<Instr>],
+    stage: &Stage<usize>,
+    regs: impl Fn(Ty) -> usize,
+) -> Result<()> {
+    // Functions for stages that do not exist are left empty, and must never be entered.
+    let callable = |f: usize| matches!(instrs.get(f), Some(func) if !func.is_empty());
+    if let Some(entry) = stage.iter().find(|e| !callable(**e)) {
+        return err!("invalid entry point {}", entry);
+    }
+    for (i, func) in instrs.iter().enumerate() {
+        // Call returns to the next instruction, and everything else but a jump falls through to
+        // it; the last instruction has to do neither.
+        match func.last() {
+            None | Some(Instr::Ret) | Some(Instr::Jmp(_)) => {}
+            Some(inst) => {
+                return err!(
+                    "function {} ends with {:?}, which does not return or jump",
+                    i,
+                    inst
+                )
+            }
+        }
+        for (j, inst) in func.iter().enumerate() {
+            if let Err(e) = verify_inst(inst, func.len(), callable, &regs) {
+                return err!("instruction {} of function {} ({:?}): {}", j, i, inst, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify_inst(
+    inst: &Instr,
+    len: usize,
+    callable: impl Fn(usize) -> bool,
+    regs: impl Fn(Ty) -> usize,
+) -> Result<()> {
+    use Instr::*;
+    if let Some(dst) = peephole::jump_target(inst) {
+        if dst >= len {
+            return err!("jump target @{} out of bounds", dst);
+        }
+    }
+    match inst {
+        Call(f) if !callable(*f) => return err!("call to invalid function {}", f),
+        // `accum` derives the types of the keys and values of these from the map type, and panics
+        // if it is not a map.
+        Lookup { map_ty, .. }
+        | Contains { map_ty, .. }
+        | Delete { map_ty, .. }
+        | Clear { map_ty, .. }
+        | Len { map_ty, .. }
+        | ToJSON { map_ty, .. }
+        | JoinArray { map_ty, .. }
+        | ReadArray { map_ty, .. }
+        | WriteArray { map_ty, .. }
+        | Store { map_ty, .. }
+        | IncInt { map_ty, .. }
+        | IncFloat { map_ty, .. } => {
+            map_ty.key()?;
+            map_ty.val()?;
+        }
+        // These are only implemented for maps with string keys.
+        LookupConstKey { map_ty, .. }
+        | StoreConstKey { map_ty, .. }
+        | IncIntConstKey { map_ty, .. }
+        | IncFloatConstKey { map_ty, .. } => {
+            if map_ty.key()? != Ty::Str {
+                return err!("constant key for map of type {:?}", map_ty);
+            }
+            map_ty.val()?;
+        }
+        LookupStr { map_ty, .. } => {
+            map_ty.key()?;
+            if !matches!(map_ty.val()?, Ty::Int | Ty::Float) {
+                return err!("string lookup for map of type {:?}", map_ty);
+            }
+        }
+        IterBegin { map_ty, .. } => {
+            map_ty.key_iter()?;
+        }
+        IterHasNext { iter_ty, .. } | IterGetNext { iter_ty, .. } => {
+            iter_ty.iter()?;
+        }
+        _ => {}
+    }
+    let mut bad_reg = None;
+    inst.accum(|reg, ty| {
+        if ty != Ty::Null && reg as usize >= regs(ty) {
+            bad_reg = Some((reg, ty));
+        }
+    });
+    if let Some((reg, ty)) = bad_reg {
+        return err!("register {} of type {:?} out of bounds", reg, ty);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtins::Variable;
+    use crate::bytecode::{ConstKey, Label};
+    use crate::runtime::Str;
+    use Instr::*;
+
+    fn check(instrs: Vec<Instr<'static>>) -> Result<()> {
+        // Two registers of each type.
+        verify(&[instrs, vec![]], &Stage::Main(0), |_| 2)
+    }
+
+    #[test]
+    fn valid_program() {
+        check(vec![
+            StoreConstInt(1.into(), 1),
+            JmpIf(1.into(), Label(3)),
+            Call(0),
+            Lookup {
+                map_ty: Ty::MapStrInt,
+                dst: 1,
+                map: 0,
+                key: 1,
+            },
+            Mov(Ty::Null, 5, 5),
+            Ret,
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn invalid_programs() {
+        assert!(check(vec![StoreConstInt(2.into(), 1), Ret]).is_err());
+        assert!(check(vec![Mov(Ty::Float, 0, 2), Ret]).is_err());
+        assert!(check(vec![Jmp(Label(2)), Ret]).is_err());
+        assert!(check(vec![LoadVarInt(0.into(), Variable::NR)]).is_err());
+        assert!(check(vec![Call(0)]).is_err());
+        assert!(check(vec![Call(1), Ret]).is_err());
+        assert!(check(vec![Call(2), Ret]).is_err());
+        assert!(check(vec![
+            Len {
+                map_ty: Ty::Int,
+                dst: 0,
+                map: 0
+            },
+            Ret
+        ])
+        .is_err());
+        assert!(check(vec![
+            LookupConstKey {
+                map_ty: Ty::MapIntInt,
+                dst: 0,
+                map: 0,
+                key: Box::new(ConstKey::new(Str::from("x").into())),
+            },
+            Ret
+        ])
+        .is_err());
+        assert!(check(vec![
+            LookupStr {
+                map_ty: Ty::MapStrStr,
+                dst: 0.into(),
+                map: 0,
+                key: 0,
+            },
+            Ret
+        ])
+        .is_err());
+        assert!(verify(&[vec![Ret]], &Stage::Main(1), |_| 2).is_err());
+    }
+}
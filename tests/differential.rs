@@ -0,0 +1,386 @@
+//! Differential tests: run a corpus of programs and inputs through frawk and through whichever
+//! other Awk implementations are installed, and report every case where their outputs differ.
+//!
+//! The reference implementations are looked up on the `PATH` by the names in `REFERENCE_AWKS`;
+//! set `FRAWK_DIFF_AWKS` to a colon-separated list of names or paths to compare against others.
+//! Names that resolve to the same binary (e.g. `nawk` as an alias for `mawk`) are only run once.
+//! With no reference implementations available, the test passes without checking anything.
+//!
+//! Cases in the corpus should have one POSIX answer; programs that hit the known differences in
+//! frawk's semantics (see `info/overview.md`), or that print floating point values without a
+//! format, do not belong here.
+
+use assert_cmd::Command;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(all(feature = "llvm_backend", feature = "cranelift_backend"))]
+const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm", "-Bcranelift"];
+#[cfg(all(feature = "llvm_backend", not(feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm"];
+#[cfg(all(not(feature = "llvm_backend"), feature = "cranelift_backend"))]
+const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bcranelift"];
+#[cfg(not(any(feature = "llvm_backend", feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp"];
+
+const REFERENCE_AWKS: &[&str] = &["gawk", "mawk", "original-awk", "nawk", "bwk-awk"];
+
+// Cases where frawk is known to disagree with the reference implementations, and why. These are
+// still run, and their divergences are printed, but they do not fail the test. If every reference
+// implementation agrees with frawk on one of these, the test fails so that it can be removed.
+const KNOWN_DIVERGENCES: &[(&str, &str)] = &[
+    (
+        "printf_formats",
+        "%c prints a NUL byte rather than the first character of a string",
+    ),
+    (
+        "control_flow",
+        "continue in a for loop skips the update expression",
+    ),
+    (
+        "next_and_exit",
+        "exit in a main rule does not run the END rules",
+    ),
+    ("getline", "plain getline does not increment NR"),
+    (
+        "record_separator_paragraphs",
+        "an empty RS does not split paragraphs",
+    ),
+];
+
+const COUNTRIES: &str = "Russia\t8650\t262\tAsia
+Canada\t3852\t24\tNorth America
+China\t3692\t866\tAsia
+USA\t3615\t219\tNorth America
+Brazil\t3286\t116\tSouth America
+Australia\t2968\t14\tAustralia
+India\t1269\t637\tAsia
+Argentina\t1072\t26\tSouth America
+Sudan\t968\t19\tAfrica
+Algeria\t920\t18\tAfrica
+";
+
+const WORDS: &str = "the quick  brown fox
+ jumps over\tthe lazy dog
+
+a b c d e f g
+";
+
+struct Case {
+    name: &'static str,
+    args: &'static [&'static str],
+    prog: &'static str,
+    input: &'static str,
+}
+
+const CORPUS: &[Case] = &[
+    Case {
+        name: "print_fields",
+        args: &[],
+        prog: r#"{ print NR, NF, $1, $NF }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "field_separator",
+        args: &["-F\t"],
+        prog: r#"{ print $4 ": " $1 }"#,
+        input: COUNTRIES,
+    },
+    Case {
+        name: "output_separators",
+        args: &["-vOFS=-", "-vORS=|\n"],
+        prog: r#"{ print $1, $2 }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "assign_field",
+        args: &["-vOFS=:"],
+        prog: r#"{ $2 = "X"; print; print NF }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "assign_field_beyond_nf",
+        args: &["-vOFS=:"],
+        prog: r#"{ $6 = "Y"; print; print NF }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "assign_nf",
+        args: &["-vOFS=,"],
+        prog: r#"{ NF = 2; print; NF = 4; print }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "assign_record",
+        args: &[],
+        prog: r#"{ $0 = "x " $0 " y"; print NF, $2 }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "patterns_and_ranges",
+        args: &["-F\t"],
+        prog: r#"$4 == "Asia" { print "asia", $1 } /^B/, /^Au/ { print "range", $1 }"#,
+        input: COUNTRIES,
+    },
+    Case {
+        name: "aggregate",
+        args: &["-F\t"],
+        prog: r#"{ pop[$4] += $3; n++ }
+END { printf "%d %d %d\n", pop["Asia"], pop["Africa"], n }"#,
+        input: COUNTRIES,
+    },
+    Case {
+        name: "printf_formats",
+        args: &["-F\t"],
+        prog: r#"{ printf "[%-10s|%6d|%06.2f|%x|%c]\n", $1, $2, $3 / 7, $2, $1 }"#,
+        input: COUNTRIES,
+    },
+    Case {
+        name: "sprintf_and_length",
+        args: &["-F\t"],
+        prog: r#"{ s = sprintf("%s-%s", $1, $4); print length(s), s }"#,
+        input: COUNTRIES,
+    },
+    Case {
+        name: "substr_index",
+        args: &[],
+        prog: r#"BEGIN {
+    s = "hello, world";
+    print substr(s, 1, 5), substr(s, 8), substr(s, 0, 3), substr(s, -1), substr(s, 20);
+    print index(s, "o"), index(s, "xyz"), index(s, "");
+}"#,
+        input: "",
+    },
+    Case {
+        name: "sub_gsub",
+        args: &[],
+        prog: r#"{ n = gsub(/o/, "0"); sub(/e/, "[&]"); gsub(/a/, "\\&"); print n, $0 }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "match_rstart",
+        args: &[],
+        prog: r#"{ if (match($0, /[aeiou]+/)) print RSTART, RLENGTH; else print "none", RSTART, RLENGTH; }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "split",
+        args: &[],
+        prog: r#"BEGIN {
+    n = split("a:b::c", parts, ":");
+    print n, parts[1], parts[3], parts[4];
+    n = split("  x  y ", ws);
+    print n, ws[1], ws[2];
+    n = split("a1b22c", re, /[0-9]+/);
+    print n, re[1], re[2], re[3];
+}"#,
+        input: "",
+    },
+    Case {
+        name: "arrays",
+        args: &[],
+        prog: r#"{ for (i = 1; i <= NF; i++) seen[$i]++; }
+END {
+    print ("the" in seen), ("cat" in seen), seen["the"];
+    delete seen["the"];
+    print ("the" in seen);
+    m[1, 2] = "x";
+    for (k in m) { split(k, ks, SUBSEP); print ks[1], ks[2], m[k]; }
+}"#,
+        input: WORDS,
+    },
+    Case {
+        name: "uninitialized",
+        args: &[],
+        prog: r#"BEGIN { print x + 0, "[" x "]", length(x), (x == 0), (x == "") }"#,
+        input: "",
+    },
+    Case {
+        name: "string_functions",
+        args: &[],
+        prog: r#"{ print toupper($1), tolower("MiXeD"), length($0) }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "integer_arithmetic",
+        args: &[],
+        prog: r#"BEGIN {
+    print 7 % 3, -7 % 3, 2 ^ 10, int(-3.7), int("42abc"), 1e3, 1000 * 1000;
+    x = 5; x += 2; x *= 3; x -= 1; x %= 7; print x;
+    y = x++; print x, y; y = ++x; print x, y; y = x--; print x, y;
+}"#,
+        input: "",
+    },
+    Case {
+        name: "numeric_strings",
+        args: &[],
+        prog: r#"{ print ($1 < $2), ($1 == $2), $1 + $2 }"#,
+        input: "10 9\n2 10\n1e2 100\n+5 5.0\n abc abd\n",
+    },
+    Case {
+        name: "control_flow",
+        args: &[],
+        prog: r#"BEGIN {
+    for (i = 0; i < 10; i++) {
+        if (i == 2) continue;
+        if (i == 6) break;
+        j = 0;
+        while (j < i) j += 2;
+        do { k++ } while (k < 3);
+        printf "%d:%d ", i, j;
+    }
+    print k;
+}"#,
+        input: "",
+    },
+    Case {
+        name: "functions",
+        args: &[],
+        prog: r#"function fib(n) { return n < 2 ? n : fib(n - 1) + fib(n - 2) }
+function fill(arr, n,   i) { for (i = 1; i <= n; i++) arr[i] = i * i; return n }
+BEGIN { print fib(15); fill(sq, 4); print sq[3], length(sq) }"#,
+        input: "",
+    },
+    Case {
+        name: "next_and_exit",
+        args: &[],
+        prog: r#"NR == 2 { next } { print NR ": " $1 } NR == 3 { exit } END { print "end", NR }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "getline",
+        args: &[],
+        prog: r#"NR == 1 { getline; print "after getline:", $1, NR; getline line; print line, NR }"#,
+        input: WORDS,
+    },
+    Case {
+        name: "record_separator_paragraphs",
+        args: &["-vRS="],
+        prog: r#"{ print NR ": " $1 " (" NF ")" }"#,
+        input: "a b\nc\n\n\n\nd e f\n",
+    },
+    Case {
+        name: "regex_field_separator",
+        args: &["-F[:,]+"],
+        prog: r#"{ print NF, $2 }"#,
+        input: "a:b,,c\n:x:\n",
+    },
+    Case {
+        name: "concatenation_and_comparison",
+        args: &[],
+        prog: r#"BEGIN { x = 1 2; y = x + 1; print x, y, (x "" < "2"), ("abc" < "abd"), -x }"#,
+        input: "",
+    },
+];
+
+// Resolve `name` to a path, searching the PATH if it is not one already.
+fn find_program(name: &str) -> Option<PathBuf> {
+    if name.contains(std::path::MAIN_SEPARATOR) {
+        let path = PathBuf::from(name);
+        return path.is_file().then_some(path);
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn reference_awks() -> Vec<(String, PathBuf)> {
+    let names: Vec<String> = match env::var("FRAWK_DIFF_AWKS") {
+        Ok(names) => names
+            .split(':')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => REFERENCE_AWKS.iter().map(|s| String::from(*s)).collect(),
+    };
+    let mut res: Vec<(String, PathBuf)> = Vec::new();
+    for name in names {
+        let path = match find_program(&name) {
+            Some(path) => path,
+            None => continue,
+        };
+        let canonical = path.canonicalize().unwrap_or(path);
+        if res.iter().all(|(_, other)| *other != canonical) {
+            res.push((name, canonical));
+        }
+    }
+    res
+}
+
+fn run(mut cmd: Command, case: &Case) -> String {
+    let output = cmd
+        .args(case.args)
+        .arg(case.prog)
+        .write_stdin(case.input)
+        .timeout(Duration::from_secs(10))
+        .output()
+        .unwrap();
+    let mut res = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() {
+        res.push_str(&format!(
+            "<exited with {}: {}>",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    res
+}
+
+#[test]
+fn differential_corpus() {
+    let awks = reference_awks();
+    if awks.is_empty() {
+        eprintln!("no reference awk implementations found; skipping differential tests");
+        return;
+    }
+    let mut divergences = Vec::new();
+    let mut fixed = Vec::new();
+    for case in CORPUS {
+        let expected: Vec<(&str, String)> = awks
+            .iter()
+            .map(|(name, path)| (name.as_str(), run(Command::new(path), case)))
+            .collect();
+        let mut case_divergences = Vec::new();
+        for backend_arg in BACKEND_ARGS {
+            let mut cmd = Command::cargo_bin("frawk").unwrap();
+            cmd.arg(backend_arg);
+            let got = run(cmd, case);
+            for (awk, want) in expected.iter() {
+                if got != *want {
+                    case_divergences.push(format!(
+                        "{} (frawk {} vs {}):\n  frawk: {:?}\n  {}: {:?}",
+                        case.name, backend_arg, awk, got, awk, want
+                    ));
+                }
+            }
+        }
+        match KNOWN_DIVERGENCES
+            .iter()
+            .find(|(name, _)| *name == case.name)
+        {
+            Some((_, reason)) if case_divergences.is_empty() => {
+                fixed.push(format!("{} ({})", case.name, reason))
+            }
+            Some((_, reason)) => {
+                eprintln!("known divergence: {}", reason);
+                for d in case_divergences {
+                    eprintln!("{}", d);
+                }
+            }
+            None => divergences.extend(case_divergences),
+        }
+    }
+    assert!(
+        divergences.is_empty(),
+        "{} divergences from {:?}:\n{}",
+        divergences.len(),
+        awks.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+        divergences.join("\n")
+    );
+    assert!(
+        fixed.is_empty(),
+        "known divergences no longer diverge; remove them from KNOWN_DIVERGENCES: {:?}",
+        fixed
+    );
+}
@@ -0,0 +1,479 @@
+use frawk::{CancelToken, Error, KeyOrder, Program, Type, Value};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn run(prog: &str, input: &str) -> (i32, String) {
+    let prog = Program::compile(prog).unwrap();
+    let mut out = Vec::new();
+    let status = prog.run(input.as_bytes(), &mut out).unwrap();
+    (status, String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn aggregate() {
+    assert_eq!(
+        run(
+            "{ total[$1] += $2 } END { print total[\"a\"], total[\"b\"], NR }",
+            "a 1\nb 2\na 3\n"
+        ),
+        (0, String::from("4 2 3\n"))
+    );
+}
+
+#[test]
+fn exit_status() {
+    assert_eq!(
+        run("NR == 2 { exit 3 } { print }", "x\ny\nz\n"),
+        (3, String::from("x\n"))
+    );
+}
+
+#[test]
+fn reuse() {
+    let prog = Program::compile("{ n++ } END { print n }").unwrap();
+    for (input, expected) in [("a\n", "1\n"), ("a\nb\nc\n", "3\n"), ("", "0\n")] {
+        let mut out = Vec::new();
+        assert_eq!(prog.run(input.as_bytes(), &mut out).unwrap(), 0);
+        assert_eq!(out, expected.as_bytes());
+    }
+}
+
+#[test]
+fn large_output() {
+    let (status, out) = run("BEGIN { for (i = 0; i < 100000; i++) print i; }", "");
+    assert_eq!(status, 0);
+    assert_eq!(out.lines().count(), 100000);
+    assert_eq!(out.lines().last(), Some("99999"));
+}
+
+#[test]
+fn compile_errors() {
+    assert!(matches!(
+        Program::compile("{ print ( }"),
+        Err(Error::Syntax(_))
+    ));
+    assert!(matches!(
+        Program::compile("{ x[1] = 1; x = 2 }"),
+        Err(Error::Compile(_))
+    ));
+}
+
+#[test]
+fn write_error() {
+    struct Failing;
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let prog = Program::compile("BEGIN { for (i = 0; i < 100000; i++) print i; }").unwrap();
+    match prog.run(io::empty(), &mut Failing) {
+        Err(Error::Io(e)) => assert_eq!(e.to_string(), "disk full"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(status) => panic!("unexpected success: {}", status),
+    }
+}
+
+#[test]
+fn native_functions() {
+    let prog = Program::builder()
+        .function("reverse", &[Type::Str], Type::Str, |args| match &args[0] {
+            Value::Str(s) => s.chars().rev().collect::<String>().into(),
+            v => panic!("unexpected argument {:?}", v),
+        })
+        .function("add", &[Type::Int, Type::Int], Type::Int, |args| {
+            match (&args[0], &args[1]) {
+                (Value::Int(x), Value::Int(y)) => (x + y).into(),
+                v => panic!("unexpected arguments {:?}", v),
+            }
+        })
+        // Results are converted to the declared type.
+        .function("half", &[Type::Float], Type::Int, |args| match &args[0] {
+            Value::Float(f) => Value::Float(f / 2.0),
+            v => panic!("unexpected argument {:?}", v),
+        })
+        .compile(r#"{ print reverse($1), add($2, "3"), half($2) * 2 }"#)
+        .unwrap();
+    let mut out = Vec::new();
+    assert_eq!(prog.run(&b"abc 5\nxy 8\n"[..], &mut out).unwrap(), 0);
+    assert_eq!(String::from_utf8(out).unwrap(), "cba 8 4\nyx 11 8\n");
+}
+
+#[test]
+fn native_functions_bytes() {
+    let prog = Program::builder()
+        .function("flip", &[Type::Str], Type::Str, |args| match &args[0] {
+            Value::Bytes(bs) => Value::Bytes(bs.iter().rev().cloned().collect()),
+            v => panic!("unexpected argument {:?}", v),
+        })
+        .compile("{ print flip($1) }")
+        .unwrap();
+    let mut out = Vec::new();
+    assert_eq!(prog.run(&b"a\xffb\n"[..], &mut out).unwrap(), 0);
+    assert_eq!(out, b"b\xffa\n");
+}
+
+#[test]
+fn native_side_effects() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let prog = Program::builder()
+        .function("count", &[], Type::Int, move |_| {
+            Value::Int(counter.fetch_add(1, Ordering::Relaxed) as i64)
+        })
+        .compile("{ count(); }")
+        .unwrap();
+    prog.run(&b"a\nb\nc\n"[..], &mut io::sink()).unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn native_errors() {
+    fn compile_native(name: &str, args: &[Type], src: &str) -> Result<Program, Error> {
+        Program::builder()
+            .function(name, args, Type::Int, |_| Value::Int(0))
+            .compile(src)
+    }
+    for (name, args, src) in [
+        ("f", &[Type::Int][..], "BEGIN { print f(1, 2) }"),
+        (
+            "f",
+            &[Type::Int][..],
+            "function f(x) { return x; } BEGIN { print f(1) }",
+        ),
+        ("length", &[Type::Str][..], "BEGIN { print length(1) }"),
+        ("not a name", &[][..], "BEGIN { }"),
+        ("f", &[Type::Int; 9][..], "BEGIN { }"),
+    ] {
+        match compile_native(name, args, src) {
+            Err(Error::Compile(_)) => {}
+            Err(e) => panic!("unexpected error for {:?}: {}", src, e),
+            Ok(_) => panic!("{} should not compile", src),
+        }
+    }
+}
+
+#[test]
+fn input_files() {
+    let prog = Program::builder()
+        .input_files(|path| match path {
+            "fixture" => Ok(Box::new(&b"x\ny\n"[..]) as Box<dyn Read + Send>),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such fixture")),
+        })
+        .compile(
+            r#"BEGIN {
+                while ((getline line < "fixture") > 0) print "got", line;
+                print (getline line < "missing");
+            }"#,
+        )
+        .unwrap();
+    let mut out = Vec::new();
+    assert_eq!(prog.run(io::empty(), &mut out).unwrap(), 0);
+    assert_eq!(String::from_utf8(out).unwrap(), "got x\ngot y\n-1\n");
+}
+
+#[test]
+fn output_files() {
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let files: Arc<Mutex<HashMap<String, (bool, Shared)>>> = Default::default();
+    let opened = files.clone();
+    let prog = Program::builder()
+        .output_files(move |path, append| {
+            let file = Shared::default();
+            opened
+                .lock()
+                .unwrap()
+                .insert(path.into(), (append, file.clone()));
+            Ok(Box::new(file))
+        })
+        .compile(r#"{ print $2 > $1 } END { print NR >> "count" }"#)
+        .unwrap();
+    let mut out = Vec::new();
+    assert_eq!(prog.run(&b"a 1\nb 2\na 3\n"[..], &mut out).unwrap(), 0);
+    assert!(out.is_empty());
+    let files = files.lock().unwrap();
+    let contents = |path: &str| {
+        let (append, file) = &files[path];
+        (
+            *append,
+            String::from_utf8(file.0.lock().unwrap().clone()).unwrap(),
+        )
+    };
+    assert_eq!(files.len(), 3);
+    assert_eq!(contents("a"), (false, String::from("1\n3\n")));
+    assert_eq!(contents("b"), (false, String::from("2\n")));
+    assert_eq!(contents("count"), (true, String::from("3\n")));
+}
+
+#[test]
+fn stream() {
+    let prog = Program::compile(r#"{ n += $2; print $1, n } END { print "total", n }"#).unwrap();
+    let stream = prog.stream();
+    // Output for each record arrives without waiting for the rest of the input.
+    let wait_for = |expected: &str| {
+        let mut out = Vec::new();
+        while out.len() < expected.len() {
+            out.extend(stream.output());
+            thread::yield_now();
+        }
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    };
+    assert!(stream.push_record("a 1"));
+    wait_for("a 1\n");
+    assert!(stream.push("b 2\nc"));
+    wait_for("b 3\n");
+    assert!(stream.push(" 3\n"));
+    wait_for("c 6\n");
+    assert_eq!(stream.finish().unwrap(), (0, b"total 6\n".to_vec()));
+}
+
+#[test]
+fn stream_exit() {
+    let prog = Program::compile("{ print; exit 2 }").unwrap();
+    let stream = prog.stream();
+    assert!(stream.push_record("x"));
+    // Wait for the program to stop reading input.
+    while stream.push("") {
+        thread::yield_now();
+    }
+    assert!(!stream.push_record("y"));
+    assert_eq!(stream.finish().unwrap(), (2, b"x\n".to_vec()));
+}
+
+#[test]
+fn globals() {
+    let prog = Program::compile(
+        r#"BEGIN { unused_later = "x" }
+        { count[$1]++; total += $2; last = $1 }
+        END { avg = total / NR; ids[1] = "one" }"#,
+    )
+    .unwrap();
+    let (status, globals) = prog
+        .run_with_globals(&b"a 1\nb 2\na 3\n"[..], &mut io::sink())
+        .unwrap();
+    assert_eq!(status, 0);
+    // Fields are strings, so sums of them are floats.
+    assert_eq!(globals.scalar("total"), Some(&Value::Float(6.0)));
+    assert_eq!(globals.scalar("avg"), Some(&Value::Float(2.0)));
+    assert_eq!(globals.scalar("last"), Some(&Value::Str("a".into())));
+    assert_eq!(
+        globals.scalar("unused_later"),
+        Some(&Value::Str("x".into()))
+    );
+    let count = globals.array("count").unwrap();
+    assert_eq!(count.len(), 2);
+    assert_eq!(count["a"], Value::Int(2));
+    assert_eq!(count["b"], Value::Int(1));
+    let ids = globals.array("ids").unwrap();
+    assert_eq!(ids["1"], Value::Str("one".into()));
+    assert_eq!(globals.scalar("count"), None);
+    assert_eq!(globals.scalar("missing"), None);
+}
+
+#[test]
+fn shared_between_threads() {
+    fn assert_send_sync<T: Send + Sync>(t: T) -> T {
+        t
+    }
+    let prog = assert_send_sync(
+        Program::compile(r#"$1 ~ /^[0-9]+$/ { sum += $1 } END { print sum }"#).unwrap(),
+    );
+    thread::scope(|s| {
+        let handles: Vec<_> = (1..=8)
+            .map(|n| {
+                let prog = &prog;
+                s.spawn(move || {
+                    let input: String = (1..=n * 1000).map(|i| format!("{}\nx\n", i)).collect();
+                    let mut out = Vec::new();
+                    assert_eq!(prog.run(input.as_bytes(), &mut out).unwrap(), 0);
+                    (n, String::from_utf8(out).unwrap())
+                })
+            })
+            .collect();
+        for h in handles {
+            let (n, out) = h.join().unwrap();
+            let n = n * 1000;
+            assert_eq!(out, format!("{}\n", n * (n + 1) / 2));
+        }
+    });
+}
+
+#[test]
+fn cancel() {
+    let token = CancelToken::new();
+    let prog = Program::builder()
+        .cancel_token(&token)
+        .compile("BEGIN { while (1) n++; }")
+        .unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        assert!(matches!(
+            prog.run(io::empty(), &mut io::sink()),
+            Err(Error::Cancelled)
+        ));
+    });
+    assert!(token.is_cancelled());
+    // Later runs stop before they start.
+    assert!(matches!(
+        prog.run(io::empty(), &mut io::sink()),
+        Err(Error::Cancelled)
+    ));
+}
+
+#[test]
+fn timeout() {
+    let compile = |src| {
+        Program::builder()
+            .timeout(Duration::from_millis(50))
+            .compile(src)
+            .unwrap()
+    };
+    for src in [
+        "BEGIN { for (;;) n++; }",
+        "function f(x) { return f(x + 1); } BEGIN { f(0) }",
+    ] {
+        assert!(
+            matches!(
+                compile(src).run(io::empty(), &mut io::sink()),
+                Err(Error::TimedOut)
+            ),
+            "{} did not time out",
+            src
+        );
+    }
+    let prog = compile("{ n++ } END { print n }");
+    let mut out = Vec::new();
+    assert_eq!(prog.run(&b"a\nb\n"[..], &mut out).unwrap(), 0);
+    assert_eq!(out, b"2\n");
+}
+
+#[test]
+fn memory_limit() {
+    let compile = |src| Program::builder().max_memory(1 << 20).compile(src).unwrap();
+    for src in [
+        r#"BEGIN { for (i = 0; ; i++) a[i] = "a string of some length " i; }"#,
+        r#"{ seen[$0 NR]++ }"#,
+        r#"BEGIN { for (;;) s = s "xxxxxxxxxxxxxxxxxxxxxxxx"; }"#,
+    ] {
+        let input = "a line of input that is repeated\n".repeat(100_000);
+        match compile(src).run(input.as_bytes(), &mut io::sink()) {
+            Err(Error::MemoryLimitExceeded) => {}
+            res => panic!("{} did not exceed the memory limit: {:?}", src, res),
+        }
+    }
+    let prog = compile(r#"{ seen[$1]++ } END { for (k in seen) print k, seen[k]; }"#);
+    let mut out = Vec::new();
+    let input = "a\nb\n".repeat(100_000);
+    assert_eq!(prog.run(input.as_bytes(), &mut out).unwrap(), 0);
+    assert_eq!(out.len(), "a 100000\nb 100000\n".len());
+}
+
+#[test]
+fn output_limit() {
+    let prog = Program::builder()
+        .max_output(1000)
+        .compile("BEGIN { for (i = 0; ; i++) print i; }")
+        .unwrap();
+    let mut out = Vec::new();
+    match prog.run(io::empty(), &mut out) {
+        Err(Error::OutputLimitExceeded) => {}
+        res => panic!("output limit not exceeded: {:?}", res),
+    }
+    let expected: String = (0..).map(|i| format!("{}\n", i)).take(400).collect();
+    assert_eq!(out, &expected.as_bytes()[..1000]);
+
+    // Output that fits is written in full, and files count towards the same limit.
+    let files = Arc::new(AtomicUsize::new(0));
+    let written = files.clone();
+    let compile = |src| {
+        let written = written.clone();
+        Program::builder()
+            .max_output(10)
+            .output_files(move |_, _| {
+                struct Counter(Arc<AtomicUsize>);
+                impl Write for Counter {
+                    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                        self.0.fetch_add(buf.len(), Ordering::Relaxed);
+                        Ok(buf.len())
+                    }
+                    fn flush(&mut self) -> io::Result<()> {
+                        Ok(())
+                    }
+                }
+                Ok(Box::new(Counter(written.clone())))
+            })
+            .compile(src)
+            .unwrap()
+    };
+    let mut out = Vec::new();
+    let prog = compile(r#"{ print > "file" } END { print NR }"#);
+    assert_eq!(prog.run(&b"abcd\n"[..], &mut out).unwrap(), 0);
+    assert_eq!(out, b"1\n");
+    assert_eq!(files.load(Ordering::Relaxed), 5);
+    let prog = compile(r#"{ print > "file"; print }"#);
+    assert!(matches!(
+        prog.run(&b"abcd\nefgh\n"[..], &mut io::sink()),
+        Err(Error::OutputLimitExceeded)
+    ));
+}
+
+#[test]
+fn ieee_division() {
+    let src = "{ print 1 / $1 }";
+    let mut out = Vec::new();
+    assert!(matches!(
+        Program::compile(src).unwrap().run(&b"0\n"[..], &mut out),
+        Err(Error::Runtime(_))
+    ));
+    // The setting belongs to the program it was compiled with, not to the process.
+    let prog = Program::builder().ieee_division(true).compile(src).unwrap();
+    let mut out = Vec::new();
+    assert_eq!(prog.run(&b"0\n-0\n"[..], &mut out).unwrap(), 0);
+    assert_eq!(out, b"inf\n-inf\n");
+    assert!(Program::compile(src)
+        .unwrap()
+        .run(&b"0\n"[..], &mut Vec::new())
+        .is_err());
+}
+
+#[test]
+fn key_order() {
+    let src = r#"BEGIN { m[10] = 10; m[9] = 9; m[100] = 100; for (k in m) printf "%s ", k; print ""; print join(m, ",", 1) }"#;
+    let run = |prog: Program| {
+        let mut out = Vec::new();
+        prog.run(&b""[..], &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let num_asc = Program::builder()
+        .key_order(KeyOrder::NumAsc)
+        .compile(src)
+        .unwrap();
+    let str_desc = Program::builder()
+        .key_order(KeyOrder::StrDesc)
+        .compile(src)
+        .unwrap();
+    // Each program keeps its own order, however their runs are interleaved.
+    assert_eq!(run(num_asc.clone()), "9 10 100 \n9,10,100\n");
+    assert_eq!(run(str_desc), "9 100 10 \n9,100,10\n");
+    assert_eq!(run(num_asc), "9 10 100 \n9,10,100\n");
+}
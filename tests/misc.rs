@@ -3,10 +3,14 @@ use std::fs::{read_to_string, File};
 use std::io::Write;
 use tempfile::tempdir;
 
-#[cfg(feature = "llvm_backend")]
+#[cfg(all(feature = "llvm_backend", feature = "cranelift_backend"))]
 const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm", "-Bcranelift"];
-#[cfg(not(feature = "llvm_backend"))]
+#[cfg(all(feature = "llvm_backend", not(feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm"];
+#[cfg(all(not(feature = "llvm_backend"), feature = "cranelift_backend"))]
 const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bcranelift"];
+#[cfg(not(any(feature = "llvm_backend", feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp"];
 
 // A simple function that looks for the "constant folded" regex instructions in the generated
 // output. This is a function that is possible to fool: test cases should be mindful of how it is
@@ -27,7 +31,11 @@ fn assert_folded(p: &str) {
             .stdout,
     )
     .unwrap();
-    assert!(out.contains("MatchConst") || out.contains("StartsWithConst"))
+    assert!(
+        out.contains("MatchConst")
+            || out.contains("StartsWithConst")
+            || out.contains("ContainsConst")
+    )
 }
 
 // Compare two byte slices, up to reordering the lines of each.
@@ -100,6 +108,490 @@ fn simple_fi() {
     }
 }
 
+#[test]
+fn fi_per_file() {
+    let tmpdir = tempdir().unwrap();
+    let files = [
+        ("a", "Item,Count,Notes\ncarrots,2,x\n"),
+        ("b", "Count,Notes,Item\n3,y,potato chips\n1,z,custard\n"),
+        ("c", "Item,Count\n"),
+        ("d", "Notes,Count\nw,4\n"),
+    ]
+    .map(|(name, contents)| {
+        let path = tmpdir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        fname_to_string(&path)
+    });
+    // Columns missing from a file's header are missing from FI, and so refer to $0.
+    let expected = "1 1 carrots 2\n2 1 potato chips 3\n3 2 custard 1\n4 1 w,4 4\n10 4\n";
+    let prog =
+        r#"{ print NR, FNR, $FI["Item"], $FI["Count"]; n += $FI["Count"] } END { print n, NR }"#;
+    for backend_arg in BACKEND_ARGS {
+        for fs in ["-icsv", "-F,"] {
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .args([fs, "-H", prog])
+                .args(&files)
+                .assert()
+                .success()
+                .stdout(expected);
+        }
+    }
+}
+
+#[test]
+fn json_lines_input() {
+    let input = r#"{"user": {"name": "ann", "age": 31}, "tags": ["x", "y"], "admin": true}
+{"user": {"name": "bob\tby", "age": 27}, "admin": false, "note": null}
+not json
+{"user": {"name": "cat", "age": 40}, "tags": []}
+"#;
+    // Columns keep the indexes they were first seen at, and are empty in records without them.
+    let expected = "ann 31 y 1 5\nbob\tby 27  0 6\n    6\ncat 40   6\nann cat 71\n";
+    let prog = r#"{ print $FI["user.name"], $FI["user.age"], $FI["tags.1"], $FI["admin"], NF }
+$FI["user.age"] > 30 { names = names (names ? " " : "") $FI["user.name"]; ages += $FI["user.age"] }
+END { print names, ages }"#;
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-ijsonl", prog])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+}
+
+#[cfg(feature = "parquet_input")]
+#[test]
+fn parquet_input() {
+    use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+    use std::sync::Arc;
+    let tmpdir = tempdir().unwrap();
+    let path = tmpdir.path().join("sales.parquet");
+    let batch = RecordBatch::try_from_iter(vec![
+        (
+            "item",
+            Arc::new(StringArray::from(vec!["pen", "ink", "pad"])) as ArrayRef,
+        ),
+        (
+            "qty",
+            Arc::new(Int64Array::from(vec![Some(3), None, Some(2)])),
+        ),
+        ("price", Arc::new(Float64Array::from(vec![1.5, 20.0, 0.25]))),
+    ])
+    .unwrap();
+    let mut w =
+        parquet::arrow::ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None)
+            .unwrap();
+    w.write(&batch).unwrap();
+    w.close().unwrap();
+    let prog = r#"{ print NR, $1, $FI["qty"] * $FI["price"], NF; t += $3 } END { print t }"#;
+    let expected = "1 pen 4.5 3\n2 ink 0 3\n3 pad 0.5 3\n21.75\n";
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-iparquet", prog, path.to_str().unwrap()])
+            .assert()
+            .success()
+            .stdout(expected);
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-iparquet", "{ print $0 }"])
+            .write_stdin(std::fs::read(&path).unwrap())
+            .assert()
+            .success()
+            .stdout("pen 3 1.5\nink  20\npad 2 0.25\n");
+    }
+}
+
+#[test]
+fn null_data() {
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-z", "{ print NF, $2 }"])
+            .write_stdin("a  b\0c\td e\n f\0")
+            .assert()
+            .success()
+            .stdout("2 b\x004 d\0");
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-z", "-vORS=\n", "-F/", "{ print $NF }"])
+            .write_stdin("./a/b.txt\0./c d.txt\0")
+            .assert()
+            .success()
+            .stdout("b.txt\nc d.txt\n");
+    }
+}
+
+#[test]
+fn assign_fields_and_nf() {
+    // The batch readers only split the fields a program uses; assigning a field or NF has to split
+    // the rest of the record (with FS, not OFS) before $0 is rebuilt.
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-vOFS=-", "{ $3 = \"Z\"; NF = 4; print; NF = 2; print }"])
+            .write_stdin("a-b c d e\nf\n")
+            .assert()
+            .success()
+            .stdout("a-b-c-Z-e\na-b-c\nf--Z-\nf-\n");
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-F,", "-vOFS=;", "{ NF = 2; print $0, NF }"])
+            .write_stdin("a,b;c,d\n")
+            .assert()
+            .success()
+            .stdout("a;b;c;2\n");
+    }
+}
+
+#[test]
+fn assign_fields_and_nf_csv() {
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args([
+                "-icsv",
+                "-vOFS=:",
+                "{ $5 = \"x\"; print; print NF; NF = 2; print }",
+            ])
+            .write_stdin("a,\"b,c\",d\n")
+            .assert()
+            .success()
+            .stdout("a:b,c:d::x\n5\na:b,c\n");
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-itsv", "-vOFS=-", "{ NF = 4; print; print $2 }"])
+            .write_stdin("a\tb\n")
+            .assert()
+            .success()
+            .stdout("a-b--\nb\n");
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-itsv", "-vOFS=,", "{ $2 = \"y\"; print $0, $3 }"])
+            .write_stdin("a\tb\tc\n")
+            .assert()
+            .success()
+            .stdout("a,y,c,c\n");
+    }
+}
+
+#[test]
+fn division_by_zero() {
+    for backend_arg in BACKEND_ARGS {
+        for prog in [
+            "BEGIN { x = 0; print 1 / x }",
+            "BEGIN { x = 0; print 1.5 % x }",
+        ] {
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg(prog)
+                .assert()
+                .failure();
+        }
+        // Integer modulo zero has no IEEE result, so it stays an error.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["--ieee-div", "BEGIN { x = 0; print 5 % x }"])
+            .assert()
+            .failure();
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["--ieee-div", "BEGIN { x = 0; print 1 / x, -1 / x, 0 / x }"])
+            .assert()
+            .success()
+            .stdout("inf -inf NaN\n");
+        // Nonzero divisors take the inline path.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg("BEGIN { x = 4; y = -1; print 10 / x, 10 % x, -7 % y, 7.5 % x, -7 % 2 }")
+            .assert()
+            .success()
+            .stdout("2.5 2 0 3.5 -1\n");
+    }
+}
+
+#[test]
+fn runtime_error_locations() {
+    // Errors raised while the program runs say which statement, and which rule, they came from.
+    for (prog, expected) in [
+        (
+            "BEGIN { x = 0 }\n{ print 1 % x }",
+            "line 2, column 3 (in the rule at line 2, column 1): ",
+        ),
+        (
+            "function f(y) {\n  return 1 % y\n}\nBEGIN { print f(0) }",
+            "line 2, column 3 (in function f): ",
+        ),
+        (
+            "END { r = \"(\" $0 }\nEND { if (\"a\" ~ r) print; }",
+            "line 2, column 7 (in END): ",
+        ),
+        (
+            "BEGIN { x = 0 }\n{ n++ }\nEND { print n % x }",
+            "line 3, column 7 (in END): ",
+        ),
+        (
+            // The location of the caller is restored once a function returns.
+            "function f(y) {\n  return y\n}\nBEGIN { x = f(1)\n  print x % 0 }",
+            "line 5, column 3 (in BEGIN): ",
+        ),
+    ] {
+        for backend_arg in BACKEND_ARGS {
+            let out = Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(backend_arg)
+                .arg(prog)
+                .write_stdin("a\n")
+                .output()
+                .unwrap();
+            assert!(!out.status.success());
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            assert!(stderr.contains(expected), "{}: {}", backend_arg, stderr);
+        }
+    }
+}
+
+#[test]
+fn debugger() {
+    let prog = "function add(a, b) {\n  return a + b\n}\n$1 > 1 {\n  total = add(total, $1)\n  seen[$2]++\n}\nEND { print total }";
+    let (_d, commands) = file_from_string(
+        "commands",
+        "break add\nwatch total\ncontinue\nbacktrace\nprint total, $0, NR\nfinish\n\
+         print seen, $(NF)\ndelete\ncontinue\n",
+    );
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .arg(format!("--debug={}", fname_to_string(&commands)))
+        .arg(prog)
+        .write_stdin("1 x\n2 y\n3 y\n")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "5\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    for expected in [
+        "Stopped at line 4, column 1 (in the rule at line 4, column 1)",
+        "Breakpoint 1 at function add",
+        "Watchpoint 2: total = 0",
+        "Breakpoint 1, function add\nStopped at line 2, column 3 (in function add)\n",
+        "#0 line 2, column 3 (in function add)\n#1 line 5, column 3 (in the rule at line 4, column 1)\n",
+        "(frawk) 0\n\"2 y\"\n2\n",
+        "Watchpoint 2: total\n  Old value: 0\n  New value: 2\n",
+        "Stopped at line 6, column 3",
+        "seen is empty\n\"y\"\n",
+    ] {
+        assert!(stderr.contains(expected), "{:?} not in:\n{}", expected, stderr);
+    }
+    // Once the commands run out, the program runs to completion.
+    let (_d, commands) = file_from_string("commands", "print $1\n");
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg(format!("--debug={}", fname_to_string(&commands)))
+        .arg("{ n += $1 } END { print n }")
+        .write_stdin("1\n2\n")
+        .assert()
+        .success()
+        .stdout("3\n");
+}
+
+#[test]
+fn plugins() {
+    let src = r#"
+#include "frawk_plugin.h"
+#include <string.h>
+
+static __thread char buf[256];
+
+static void repeat(const frawk_value *args, size_t nargs, frawk_value *ret) {
+    size_t n = 0;
+    for (int64_t i = 0; i < args[1].i && n + args[0].len < sizeof(buf); i++) {
+        memcpy(buf + n, args[0].s, args[0].len);
+        n += args[0].len;
+    }
+    ret->tag = FRAWK_STR;
+    ret->s = buf;
+    ret->len = n;
+}
+
+static void half(const frawk_value *args, size_t nargs, frawk_value *ret) {
+    ret->tag = FRAWK_FLOAT;
+    ret->f = args[0].f / 2;
+}
+
+int frawk_plugin_init(const frawk_registrar *r) {
+    if (r->abi_version != FRAWK_ABI_VERSION) return 1;
+    uint32_t repeat_args[] = {FRAWK_STR, FRAWK_INT};
+    uint32_t half_args[] = {FRAWK_FLOAT};
+    return r->register_function(r->ctx, "repeat", repeat_args, 2, FRAWK_STR, repeat)
+        || r->register_function(r->ctx, "half", half_args, 1, FRAWK_FLOAT, half);
+}
+"#;
+    let (dir, c_file) = file_from_string("plugin.c", src);
+    let lib = dir.path().join("plugin.so");
+    let built = std::process::Command::new("cc")
+        .args(["-shared", "-fPIC", "-I"])
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/include"))
+        .arg("-o")
+        .arg(&lib)
+        .arg(&c_file)
+        .status();
+    if !matches!(built, Ok(status) if status.success()) {
+        eprintln!("skipping plugin test: no C compiler");
+        return;
+    }
+    let plugin_arg = format!("--plugin={}", fname_to_string(&lib));
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg(&plugin_arg)
+        .arg(r#"{ print repeat($2, $1), half($1) } END { print half("7x") }"#)
+        .write_stdin("4 ab\n3 c\n")
+        .assert()
+        .success()
+        .stdout("abababab 2\nccc 1.5\n3.5\n");
+    // Strings are passed through as bytes, whether or not they are valid UTF-8.
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg(&plugin_arg)
+        .arg("{ print repeat($2, $1) }")
+        .write_stdin(&b"2 \xff\xfe\n"[..])
+        .assert()
+        .success()
+        .stdout(&b"\xff\xfe\xff\xfe\n"[..]);
+    // Functions may only be defined once.
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .arg(&plugin_arg)
+        .arg(&plugin_arg)
+        .arg("BEGIN { }")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("repeat is defined more than once"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn repl() {
+    let session = "x = 3\nx * 2\nfunction sq(n) { return n*n }\nsq(x)\n\
+                   { total += $2\n  seen[$1]++ }\n:record a 10\n:record b 5.5\n\
+                   print total, NR\nseen\n$2\ns = \"a\\\"b\"\nx[1] = 2\nx\n\
+                   FS = \",\"\n:record c,1\n$1\n:list\n:delete 2\n:record d,2\n\
+                   :unset x\n:vars\n:quit\nprint \"not run\"\n";
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("--repl")
+        .write_stdin(session)
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "6\n9\n15.5 2\nseen[\"a\"] = 1\nseen[\"b\"] = 1\n5.5\n3\nc\n\
+         1: function sq(n) { return n*n }\n2: { total += $2\n  seen[$1]++ }\n\
+         s = \"a\\\"b\"\nseen[\"a\"] = 1\nseen[\"b\"] = 1\nseen[\"c\"] = 1\ntotal = 16.5\n"
+    );
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("frawk> ...... frawk> "), "{}", stderr);
+    assert!(stderr.contains("failed to compile program"), "{}", stderr);
+}
+
+#[test]
+fn invalid_utf8_input() {
+    let input: &[u8] = b"a\xffb c\n\xfe d\n";
+    for backend_arg in BACKEND_ARGS {
+        // By default bytes pass through untouched, even via printf.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(r#"{ printf "%s|%3s|\n", $1, $2 }"#)
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(&b"a\xffb|  c|\n\xfe|  d|\n"[..]);
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["--utf8", "{ print NF, $1 }"])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout("2 a\u{FFFD}b\n2 \u{FFFD}\n");
+    }
+}
+
+#[test]
+fn byte_regexes() {
+    let input: &[u8] = b"a\xffb\na\xc3\xa9b\n";
+    let prog = r#"{ print ($0 ~ /^a.b$/), ($0 ~ /^a..b$/), gsub(/[^ab]/, "-"), $0 }"#;
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(prog)
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(&b"0 0 0 a\xffb\n1 0 1 a-b\n"[..]);
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["--bytes", prog])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout("1 0 1 a-b\n0 1 2 a--b\n");
+    }
+}
+
+#[test]
+fn csv_output_round_trip() {
+    let input = "a,\"b,c\",\"say \"\"hi\"\"\",\"two\nlines\"\r\n";
+    for backend_arg in BACKEND_ARGS {
+        // Output fields are quoted as in the input, with line breaks kept as-is.
+        let out = Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-icsv", "-ocsv", "{ print $1, $2, $3, $4 }"])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(input)
+            .get_output()
+            .stdout
+            .clone();
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["-icsv", "{ print NF; print $4 }"])
+            .write_stdin(out)
+            .assert()
+            .success()
+            .stdout("4\ntwo\nlines\n");
+    }
+}
+
 #[test]
 fn file_and_data_arg() {
     let input = r#"Hi"#;
@@ -127,6 +619,42 @@ fn file_and_data_arg() {
     }
 }
 
+#[test]
+fn multiple_program_files() {
+    let (_d1, defs) = file_from_string("defs.awk", "function twice(x) { return 2*x; }\n");
+    let (_d2, main) = file_from_string("main.awk", "BEGIN { x = 2 }\n{ print twice($1) + x }");
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("-f")
+        .arg(&defs)
+        .arg("-f")
+        .arg(&main)
+        .write_stdin("1\n5\n")
+        .assert()
+        .success()
+        .stdout("4\n12\n");
+
+    // Syntax errors name the file they appear in, with line numbers counted from its start.
+    let (_d3, bad) = file_from_string("bad.awk", "\n{ print x +* 2 }\n");
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("-f")
+        .arg(&defs)
+        .arg("-f")
+        .arg(&bad)
+        .arg("-f")
+        .arg(&main)
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let expected = format!(
+        "{}: Unrecognized token `*` found at line 2",
+        fname_to_string(&bad)
+    );
+    assert!(stderr.contains(&expected), "{}", stderr);
+}
+
 #[test]
 fn multiple_files() {
     let input = r#"Item,Count
@@ -171,6 +699,105 @@ file 2 3
     }
 }
 
+#[test]
+fn input_files_and_stdin() {
+    let tmpdir = tempdir().unwrap();
+    let [f1, empty, missing, f2] = ["f1", "empty", "missing", "f2"].map(|f| tmpdir.path().join(f));
+    std::fs::write(&f1, "a b\nc d\n").unwrap();
+    std::fs::write(&empty, "").unwrap();
+    std::fs::write(&f2, "e f\n").unwrap();
+    let files = [&f1, &empty, &missing, tmpdir.path(), &f2].map(fname_to_string);
+    let expected = format!(
+        "{f1} 1 1 a b\n{f1} 2 2 c d\n- 3 1 g:h\n{f2} 4 1 e f\n",
+        f1 = files[0],
+        f2 = files[4]
+    );
+    for backend_arg in BACKEND_ARGS {
+        for fs in [" ", ":", "[:]+"] {
+            // "-" reads standard input, and files that cannot be read are skipped with a warning
+            // (and a nonzero exit status).
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg(format!("-F{}", fs))
+                .arg("{ print FILENAME, NR, FNR, $0 }")
+                .args(&files[..2])
+                .arg("-")
+                .args(&files[2..])
+                .write_stdin("g:h\n")
+                .assert()
+                .code(2)
+                .stdout(expected.clone());
+        }
+    }
+}
+
+#[test]
+fn assignment_operands() {
+    let tmpdir = tempdir().unwrap();
+    let [f1, empty, f2] = ["f1", "empty", "f2"].map(|f| tmpdir.path().join(f));
+    std::fs::write(&f1, "a:b\n").unwrap();
+    std::fs::write(&empty, "").unwrap();
+    std::fs::write(&f2, "c:d\n").unwrap();
+    let [f1, empty, f2] = [&f1, &empty, &f2].map(|f| fname_to_string(f));
+    let prog = r#"BEGIN { print "begin", x; } { print x, $1; } END { print "end", x, ARGC; }"#;
+    for backend_arg in BACKEND_ARGS {
+        // Assignments are performed when the input reaches them, including those before files
+        // that have no records, and those after the last file are performed before END.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(backend_arg)
+            .arg(prog)
+            .args(["x=1", &f1, "FS=:", "x=2", &empty, "x=3", &f2, "x=4"])
+            .assert()
+            .success()
+            .stdout("begin \n1 a:b\n3 c\nend 4 9\n");
+        // With no input files, standard input is read after performing all of the assignments.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(backend_arg)
+            .arg(prog)
+            .arg("x=a\\tb")
+            .write_stdin("e\n")
+            .assert()
+            .success()
+            .stdout("begin \na\tb e\nend a\tb 2\n");
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_output_round_trip() {
+    let tmpdir = tempdir().unwrap();
+    for ext in ["gz", "zst", "bz2"] {
+        let out = fname_to_string(&tmpdir.path().join(format!("out.{}", ext)));
+        for backend_arg in BACKEND_ARGS {
+            // The second stream appended after `close` is read back as part of the same file,
+            // and the file is finished at exit without an explicit `close`.
+            let prog = format!(
+                r#"BEGIN {{ print "a b" > "{out}"; close("{out}"); print "c d" >> "{out}"; exit 0 }}"#,
+                out = out
+            );
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg("--compression-level=3")
+                .arg(prog)
+                .assert()
+                .success();
+            assert_ne!(std::fs::read(&out).unwrap(), b"a b\nc d\n");
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg("{ print NR, $2 }")
+                .arg(&out)
+                .assert()
+                .success()
+                .stdout("1 b\n2 d\n");
+        }
+    }
+}
+
 mod v_args {
     //! Tests for v args.
     use super::*;
@@ -352,12 +979,80 @@ fn multi_rc() {
 }
 
 #[test]
-fn nested_loops() {
-    let expected = "0 0\n0 1\n0 2\n1 0\n1 1\n1 2\n2 0\n2 1\n2 2\n";
-    let prog: String =
-        "BEGIN { m[0]=0; m[1]=1; m[2]=2; for (i in m) for (j in m) print i,j; }".into();
+fn broken_pipe_rc() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
     for backend_arg in BACKEND_ARGS {
-        let output = Command::cargo_bin("frawk")
+        // Writing to a closed pipe ends the program quietly, with the status of a process killed
+        // by SIGPIPE.
+        let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("frawk"))
+            .arg(backend_arg)
+            .arg(r#"BEGIN { while (1) print "y"; }"#)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "y\n");
+        let out = child.wait_with_output().unwrap();
+        assert_eq!(out.status.code(), Some(141));
+        assert_eq!(String::from_utf8_lossy(&out.stderr), "");
+    }
+}
+
+#[test]
+fn parallel_regex_field_sep() {
+    let mut text = String::default();
+    for i in 0..50_000 {
+        text.push_str(&format!("{},{};x{}\n", i, i % 7, i % 3));
+    }
+    let (_dir, data) = file_from_string("inputs", &text);
+    let prog = r#"{ s += $2; n += NF; c[$3]++; } END { print s, n, c["x0"], c["x2"]; }"#;
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(backend_arg)
+            .arg("-pr")
+            .arg("-j3")
+            .arg("-F[,;]")
+            .arg(prog)
+            .arg(fname_to_string(&data))
+            .assert()
+            .stdout("149997 150000 16667 16666\n");
+    }
+}
+
+#[test]
+fn many_dynamic_regexes() {
+    // More distinct patterns than the regex cache holds, interleaved with one that is used for
+    // every match.
+    let prog = r#"BEGIN {
+    for (i = 0; i < 3000; i++) {
+        if (("x" i "y") ~ ("^x" i "y$")) n++;
+        if (("x" i "y") ~ "x[0-9]+y") m++;
+    }
+    print n, m;
+}"#;
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(backend_arg)
+            .arg(prog)
+            .assert()
+            .stdout("3000 3000\n");
+    }
+}
+
+#[test]
+fn nested_loops() {
+    let expected = "0 0\n0 1\n0 2\n1 0\n1 1\n1 2\n2 0\n2 1\n2 2\n";
+    let prog: String =
+        "BEGIN { m[0]=0; m[1]=1; m[2]=2; for (i in m) for (j in m) print i,j; }".into();
+    for backend_arg in BACKEND_ARGS {
+        let output = Command::cargo_bin("frawk")
             .unwrap()
             .arg(String::from(*backend_arg))
             .arg(prog.clone())
@@ -368,6 +1063,33 @@ fn nested_loops() {
     }
 }
 
+#[test]
+fn sorted_in() {
+    let prog: String = r#"BEGIN {
+    m[10] = m[9] = m["x"] = m[-1.5] = m["b"] = 1;
+    for (k in m) printf "%s ", k;
+    split("3 1 2", a); for (i in a) printf "%s ", i;
+    print "";
+}"#
+    .into();
+    for (order, expected) in [
+        ("str-asc", "-1.5 10 9 b x 1 2 3 \n"),
+        ("str-desc", "x b 9 10 -1.5 3 2 1 \n"),
+        ("num-asc", "-1.5 b x 9 10 1 2 3 \n"),
+        ("num-desc", "10 9 x b -1.5 3 2 1 \n"),
+    ] {
+        for backend_arg in BACKEND_ARGS {
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg(format!("--sorted-in={}", order))
+                .arg(prog.clone())
+                .assert()
+                .stdout(String::from(expected));
+        }
+    }
+}
+
 #[test]
 fn dont_reorder_files_with_f() {
     let expected = "1 1\n2 2\n3 3\n";
@@ -402,6 +1124,819 @@ fn dont_reorder_files_with_f() {
     }
 }
 
+#[test]
+fn saved_bytecode() {
+    let input = "a,1\nb,2\na,3\n";
+    let prog =
+        r#"BEGIN { print "start", x; } { s[$1] += $2; } END { for (k in s) print k, s[k]; }"#;
+    let expected = "start 7\na 4\nb 2\n";
+
+    let tmpdir = tempdir().unwrap();
+    let data_fname = tmpdir.path().join("data");
+    let image_fname = tmpdir.path().join("prog.fbc");
+    {
+        let mut data_file = File::create(data_fname.clone()).unwrap();
+        data_file.write_all(input.as_bytes()).unwrap();
+    }
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("-F,")
+        .arg("-vx=7")
+        .arg("--save-bytecode")
+        .arg(image_fname.clone())
+        .arg(prog)
+        .assert()
+        .success()
+        .stdout("");
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("--load-bytecode")
+        .arg(image_fname.clone())
+        .arg(data_fname.clone())
+        .output()
+        .unwrap();
+    unordered_output_equals(expected.as_bytes(), &out.stdout[..]);
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("--load-bytecode")
+        .arg(data_fname)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn compile_cache() {
+    let tmpdir = tempdir().unwrap();
+    let cache = tmpdir.path().join("cache");
+    let cache_arg = format!("--cache={}", fname_to_string(&cache));
+    let entries = || std::fs::read_dir(&cache).unwrap().count();
+    let prog = "{ s += $1 } END { print s }";
+    // Runs over different input files share an entry.
+    for (input, expected) in [("1\n2\n", "3\n"), ("5\n", "5\n")] {
+        let (_d, data) = file_from_string("data", input);
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(&cache_arg)
+            .arg(prog)
+            .arg(data)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+    assert_eq!(entries(), 1);
+    // Changing the options compiles the program again.
+    for x in ["3", "4"] {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(&cache_arg)
+            .arg(format!("-vx={}", x))
+            .arg("BEGIN { print x }")
+            .assert()
+            .success()
+            .stdout(format!("{}\n", x));
+    }
+    assert_eq!(entries(), 3);
+    // Entries that can't be used are replaced.
+    for entry in std::fs::read_dir(&cache).unwrap() {
+        std::fs::write(entry.unwrap().path(), "garbage").unwrap();
+    }
+    let (_d, data) = file_from_string("data", "7\n");
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg(&cache_arg)
+        .arg(prog)
+        .arg(data)
+        .assert()
+        .success()
+        .stdout("7\n");
+    assert_eq!(entries(), 3);
+}
+
+#[test]
+fn profile_bytecode() {
+    let tmpdir = tempdir().unwrap();
+    let profile_fname = tmpdir.path().join("profile");
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("--profile-bytecode")
+        .arg(profile_fname.clone())
+        .arg("BEGIN { for (i = 0; i < 10; i++) s += i; print s; }")
+        .assert()
+        .stdout("45\n");
+    let profile = read_to_string(profile_fname).unwrap();
+    assert!(profile.contains(" instructions executed\n"));
+    assert!(profile.contains("hottest blocks:"));
+    assert!(profile.contains("%  AddInt\n"));
+}
+
+#[test]
+fn profile_rules() {
+    let tmpdir = tempdir().unwrap();
+    let profile_fname = tmpdir.path().join("profile");
+    let prog = "BEGIN { n = 0 }\n/a/ { n++ }\n{ m++ } NR > 1\nEND { print n, m }";
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("--profile")
+        .arg(profile_fname.clone())
+        .arg(prog)
+        .write_stdin("a\nb\nab\n")
+        .assert()
+        .stdout("b\nab\n2 3\n");
+    let profile = read_to_string(profile_fname).unwrap();
+    let lines: Vec<_> = profile.lines().collect();
+    // Each line of the program is listed with the number of times the patterns and actions
+    // starting on it ran.
+    assert_eq!(lines.len(), 9, "{}", profile);
+    let counts = |line: &str| -> Vec<String> {
+        line.split_whitespace()
+            .take_while(|w| !w.ends_with('%'))
+            .map(String::from)
+            .collect()
+    };
+    assert!(lines[1].ends_with("%  BEGIN { n = 0 }"));
+    assert_eq!(counts(lines[1])[0], "1");
+    assert!(lines[2].ends_with("%  /a/ { n++ }"));
+    assert_eq!(&counts(lines[2])[..2], &["3", "2"]);
+    assert!(lines[3].ends_with("%  { m++ } NR > 1"));
+    assert_eq!(&counts(lines[3])[..2], &["6", "5"]);
+    assert!(lines[6].ends_with("%  (reading input)"));
+    assert!(lines[8].starts_with("total time: "));
+}
+
+#[test]
+fn coverage() {
+    let tmpdir = tempdir().unwrap();
+    let report_fname = tmpdir.path().join("coverage");
+    let prog = r#"function half(x) {
+    if (x % 2)
+        return "odd"
+    else
+        return "even"
+}
+function unused() { print "never" }
+/a/ { n++; print half($2) }
+/z/ { print "no" }
+{ if ($2 > 1) m++; } NR > 2
+END { print n, m }"#;
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .arg("--coverage")
+        .arg(report_fname.clone())
+        .arg(prog)
+        .write_stdin("a 1\nb 2\nab 3\n")
+        .assert()
+        .stdout("odd\nodd\nab 3\n2 2\n");
+    let expected = r#"        -:    1:function half(x) {
+        2:    2:    if (x % 2)
+branch at column 5: then 2, else 0
+        2:    3:        return "odd"
+        -:    4:    else
+    #####:    5:        return "even"
+        -:    6:}
+    #####:    7:function unused() { print "never" }
+        3:    8:/a/ { n++; print half($2) }
+pattern matched 2 of 3 records
+        3:    9:/z/ { print "no" }
+pattern matched 0 of 3 records
+        3:   10:{ if ($2 > 1) m++; } NR > 2
+2 patterns matched 4 of 6 times
+branch at column 3: then 2, not taken 1
+        1:   11:END { print n, m }
+
+lines executed: 6 of 8 (75.00%)
+patterns matched: 2 of 3 (66.67%)
+branches taken: 3 of 4 (75.00%)
+"#;
+    assert_eq!(read_to_string(report_fname).unwrap(), expected);
+}
+
+#[test]
+fn parallel_flag() {
+    let input: String = (1..=10000).map(|i| format!("{} {}\n", i, i % 3)).collect();
+    let run = |prog: &str| {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg("--parallel")
+            .arg("4")
+            .arg(prog)
+            .write_stdin(input.clone())
+            .output()
+            .unwrap()
+    };
+
+    // Sums and counts are aggregated across the workers.
+    let out = run("{ x = $1 * 2; s += x; c[$2]++ } END { print s, c[0], c[1], c[2] }");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "100010000 3333 3334 3333\n"
+    );
+    assert!(
+        out.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    // Minimums, maximums and values seeded in BEGIN are merged when the workers finish.
+    for (prog, expected) in [
+        ("$1 > max { max = $1 } END { print max }", "10000\n"),
+        ("{ mx = $1 > mx ? $1 : mx } END { print mx }", "10000\n"),
+        (
+            "BEGIN { mn = 1e9 } $1 < mn { mn = $1 } END { print mn }",
+            "1\n",
+        ),
+        (
+            "{ if ($1 > m[$2]) m[$2] = $1; } END { print m[0], m[1], m[2] }",
+            "9999 10000 9998\n",
+        ),
+        ("BEGIN { s = 10 } { s += $1 } END { print s }", "50005010\n"),
+    ] {
+        let out = run(prog);
+        assert!(out.status.success(), "{}", prog);
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected, "{}", prog);
+        assert!(
+            out.stderr.is_empty(),
+            "{}: {}",
+            prog,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    // Concatenations are merged too, though the workers' pieces may arrive in any order.
+    let out = run(r#"{ l[$2] = l[$2] $1 "," } END { print l[1] }"#);
+    assert!(out.status.success());
+    assert!(out.stderr.is_empty());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut got: Vec<i64> = stdout
+        .trim_end()
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().unwrap())
+        .collect();
+    got.sort_unstable();
+    assert_eq!(got, (1..=10000).filter(|i| i % 3 == 1).collect::<Vec<_>>());
+
+    // Appending to a single string would put the workers' pieces in any order.
+    let out = run(r#"{ s = s $1 "," } END { print length(s), substr(s, 1, 8) }"#);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "48894 1,2,3,4,\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("warning: running serially"), "{}", stderr);
+
+    // The last value assigned depends on the records before it, so this runs serially.
+    let out = run("{ last = $1 } END { print last }");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "10000\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("warning: running serially: END uses last"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn parallel_files() {
+    let tmpdir = tempdir().unwrap();
+    let mut files = Vec::new();
+    // The empty file holds back the output for the files after it until the others are done.
+    for (i, n) in [3000, 0, 5000, 1000, 4000].iter().enumerate() {
+        let path = tmpdir.path().join(format!("log{}", i));
+        let data: String = (1..=*n).map(|j| format!("{} {}\n", i, j)).collect();
+        std::fs::write(&path, data).unwrap();
+        files.push(fname_to_string(&path));
+    }
+    let prog = r#"FNR == 3 { nextfile } { print FILENAME, FNR, $0; n++ } END { print n }"#;
+    let run = |args: &[&str]| {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .args(args)
+            .arg(prog)
+            .args(&files)
+            .output()
+            .unwrap()
+    };
+    let serial = run(&[]);
+    assert!(serial.status.success());
+    assert_eq!(String::from_utf8_lossy(&serial.stdout).lines().count(), 9);
+
+    for backend_arg in BACKEND_ARGS {
+        let out = run(&[backend_arg, "-pf", "-j3", "--output-order", "input"]);
+        assert!(out.status.success());
+        assert_eq!(out.stdout, serial.stdout, "{}", backend_arg);
+
+        // Each file's output is kept together, but the files may come in any order.
+        let out = run(&[backend_arg, "-pf", "-j3", "--output-order", "file"]);
+        assert!(out.status.success());
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let mut blocks: Vec<Vec<&str>> = Vec::new();
+        for line in stdout.lines() {
+            let file = line.split(' ').next();
+            match blocks.last_mut() {
+                Some(block) if block[0].split(' ').next() == file => block.push(line),
+                _ => blocks.push(vec![line]),
+            }
+        }
+        let end = blocks.iter().position(|b| b == &["8"]).unwrap();
+        blocks.remove(end);
+        blocks.sort();
+        let expected: Vec<_> = String::from_utf8_lossy(&serial.stdout)
+            .lines()
+            .map(String::from)
+            .collect();
+        let got: Vec<_> = blocks.concat().into_iter().map(String::from).collect();
+        assert_eq!(got[..], expected[..expected.len() - 1]);
+    }
+
+    // --parallel hands whole files to the workers, since the program uses FNR and nextfile.
+    let out = run(&["--parallel", "3", "--output-order", "input"]);
+    assert!(out.status.success());
+    assert!(
+        out.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert_eq!(out.stdout, serial.stdout);
+
+    // Output orders other than the default only apply to files processed in parallel.
+    let out = run(&["-pr", "--output-order", "input"]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn worker_procinfo() {
+    let tmpdir = tempdir().unwrap();
+    let mut files = Vec::new();
+    for i in 0..4 {
+        let path = tmpdir.path().join(format!("in{}", i));
+        let data: String = (0..2000).map(|j| format!("{}\n", i * 2000 + j)).collect();
+        std::fs::write(&path, data).unwrap();
+        files.push(fname_to_string(&path));
+    }
+    let prefix = fname_to_string(&tmpdir.path().join("out-"));
+    let prog = format!(
+        r#"BEGIN {{ print PROCINFO["worker"], PROCINFO["workers"], (PROCINFO["pid"] > 0); }}
+        {{ print > ("{}" PROCINFO["worker"]) }}
+        END {{ print PROCINFO["worker"], PROCINFO["workers"]; }}"#,
+        prefix
+    );
+    for (args, expected) in [
+        (&[][..], "0 1 1\n0 1\n"),
+        (&["-pr", "-j3"][..], "0 1 1\n0 3\n"),
+        (&["-pf", "-j3"][..], "0 1 1\n0 3\n"),
+    ] {
+        for backend_arg in BACKEND_ARGS {
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(backend_arg)
+                .args(args)
+                .arg(&prog)
+                .args(&files)
+                .assert()
+                .stdout(String::from(expected));
+            // Each worker wrote to its own file, and together they hold every record.
+            let mut outputs = Vec::new();
+            let mut lines = Vec::new();
+            for entry in std::fs::read_dir(tmpdir.path()).unwrap() {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_str().unwrap().to_string();
+                if let Some(worker) = name.strip_prefix("out-") {
+                    let contents = std::fs::read_to_string(&path).unwrap();
+                    lines.extend(contents.lines().map(|l| l.parse::<usize>().unwrap()));
+                    outputs.push(worker.parse::<usize>().unwrap());
+                    std::fs::remove_file(&path).unwrap();
+                }
+            }
+            if args.is_empty() {
+                assert_eq!(outputs, vec![0]);
+            } else {
+                assert!(outputs.iter().all(|w| *w >= 1), "{:?}", outputs);
+            }
+            lines.sort_unstable();
+            assert_eq!(lines, (0..8000).collect::<Vec<_>>(), "{:?}", args);
+        }
+    }
+}
+
+#[test]
+fn sandbox() {
+    for (prog, expected) in [
+        (r#"BEGIN { system("echo hi") }"#, "system()"),
+        (r#"{ print | "sort" }"#, "piping output to a command"),
+        (r#"{ print > "out.txt" }"#, "redirecting output to a file"),
+        (r#"BEGIN { "date" | getline d }"#, "getline from a command"),
+        (
+            r#"function f(file) { getline < file } { f($1) }"#,
+            "getline from a file",
+        ),
+        (r#"END { writea("state", m) }"#, "writea()"),
+    ] {
+        let out = Command::cargo_bin("frawk")
+            .unwrap()
+            .args(["--sandbox", prog])
+            .write_stdin("x\n")
+            .output()
+            .unwrap();
+        assert!(!out.status.success(), "{}", prog);
+        assert!(out.stdout.is_empty(), "{}", prog);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let expected = format!("{} is not allowed with --sandbox", expected);
+        assert!(stderr.contains(&expected), "{}: {}", prog, stderr);
+    }
+    // Standard output and standard error can still be named explicitly.
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .args([
+            "--sandbox",
+            r#"{ print $1 > "/dev/stdout"; print "err" > "/dev/stderr" }"#,
+        ])
+        .write_stdin("a b\n")
+        .assert()
+        .success()
+        .stdout("a\n")
+        .stderr("err\n");
+}
+
+#[test]
+fn lint() {
+    let prog = r#"function fill(a) { a[1] = "x" }
+function unused(x) { return x }
+BEGIN { print y; y = 1; for (i = 0; i < 3; i++) { if (i > 0) print prev; prev = i } }
+{ fill(arr); if (n = $1) print totl, arr[1]; }
+$2 < "10" { print gensub(/a/, "b", "g"), hex($3) }
+"#;
+    let (_tmp, prog_file) = file_from_string("prog.awk", prog);
+    let prog_file = prog_file.to_str().unwrap();
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .args(["--lint", "-f", prog_file])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let expected = [
+        "2:1: warning: function unused is never called [unused-function]",
+        "3:9: warning: BEGIN reads y before assigning to it [uninitialized]",
+        "4:14: warning: assignment used as a condition; did you mean ==? [assign-in-condition]",
+        "4:26: warning: totl is read but never assigned to [uninitialized]",
+        "5:1: warning: comparison with the string \"10\" compares strings, not numbers; write 10 to compare numerically [string-comparison]",
+        "5:13: warning: gensub is a gawk extension [non-portable]",
+        "5:13: warning: hex is a frawk extension [non-portable]",
+    ]
+    .iter()
+    .map(|w| format!("{}:{}\n", prog_file, w))
+    .collect::<String>();
+    assert_eq!(String::from_utf8_lossy(&out.stdout), expected);
+
+    // Variables assigned with var=value operands are only assigned after BEGIN runs.
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .args(["--lint=json", r#"BEGIN { print v } { print ($1 == v) }"#, "v=1"])
+        .assert()
+        .failure()
+        .stdout(
+            r#"{"file":"<program>","line":1,"column":9,"kind":"uninitialized","message":"BEGIN reads v before assigning to it"}
+"#,
+        );
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .args([
+            "--lint",
+            "-v",
+            "v=1",
+            r#"BEGIN { print v } $1 == "x" { n++ } END { print n }"#,
+        ])
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn dump_ast() {
+    let prog = r#"function f(x,  y) { y = x * (2 + x); return y }
+BEGIN { m["a","b"] = -(-1) }
+$1 ~ /a\/b/ && !($2 == "x") { print f($3), (("a", "b") in m) > "/dev/stderr"; next }
+{ if (NR > 1) n++; else { print $1 $2 }
+  while (("cmd" | getline line) > 0) c += length(line)
+}
+END { printf "%d\n", n; exit }"#;
+    let expected = r#"function f(x, y) {
+    y = x * (2 + x)
+    return y
+}
+
+BEGIN {
+    m["a", "b"] = -(-1)
+}
+
+$1 ~ /a\/b/ && $2 != "x" {
+    print f($3), (("a", "b") in m) > "/dev/stderr"
+    next
+}
+
+{
+    if (NR > 1) {
+        n++
+    } else {
+        print $1 $2
+    }
+    while (("cmd" | getline line) > 0) {
+        c += length(line)
+    }
+}
+
+END {
+    printf "%d\n", n
+    exit
+}
+"#;
+    let dump = |prog: &str| {
+        let out = Command::cargo_bin("frawk")
+            .unwrap()
+            .args(["--dump-ast", prog])
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        String::from_utf8(out.stdout).unwrap()
+    };
+    let formatted = dump(prog);
+    assert_eq!(formatted, expected);
+    // Formatting is idempotent.
+    assert_eq!(dump(&formatted), expected);
+
+    Command::cargo_bin("frawk")
+        .unwrap()
+        .args(["--dump-ast=typed", "{ s += $1; m[$1] = s }"])
+        .assert()
+        .success()
+        .stdout("# m: map[str]float\n# s: float\n\n{\n    s += $1\n    m[$1] = s\n}\n");
+}
+
+#[test]
+fn timing() {
+    let out = Command::cargo_bin("frawk")
+        .unwrap()
+        .args(["--timing", "{ s += $1 } END { print s }"])
+        .write_stdin("1\n2\n3\n")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "6\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let phases: Vec<_> = stderr
+        .lines()
+        .filter_map(|line| line.split("  ").next())
+        .collect();
+    assert_eq!(
+        &phases[..phases.len() - 1],
+        &[
+            "startup",
+            "parsing",
+            "SSA construction",
+            "type inference",
+            "bytecode generation",
+            "execution",
+            "total"
+        ],
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.ends_with("\n") && stderr.contains("3 records, 6 bytes of input ("),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn persisted_arrays() {
+    let prog = r#"BEGIN { print reada(state, counts) }
+{ counts[$1] += $2 }
+END { for (k in counts) print k, counts[k]; print writea(state, counts) }"#;
+    let (_tmp1, day1) = file_from_string("day1", "a 1\nb 2\na 3\n");
+    let (_tmp2, day2) = file_from_string("day2", "b 5\nc 0.5\n");
+    for backend_arg in BACKEND_ARGS {
+        let tmp = tempdir().unwrap();
+        let state = fname_to_string(&tmp.path().join("counts"));
+        for (input, expected) in [
+            (&day1, "0\na 4\nb 2\n1\n"),
+            (&day2, "1\na 4\nb 7\nc 0.5\n1\n"),
+        ] {
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg("--sorted-in=str-asc")
+                .arg(format!("-vstate={}", state))
+                .arg(prog)
+                .arg(fname_to_string(input))
+                .assert()
+                .stdout(String::from(expected));
+        }
+    }
+}
+
+#[test]
+fn output_redirection() {
+    let prog = r#"BEGIN {
+    print "x" > out; print "y" > out;
+    print "z" >> app;
+    print "1" > re; close(re); print "2" > re;
+}"#;
+    for backend_arg in BACKEND_ARGS {
+        let tmp = tempdir().unwrap();
+        let [out, app, re] = ["out", "app", "re"].map(|f| tmp.path().join(f));
+        for f in [&out, &app, &re] {
+            std::fs::write(f, "some older and longer contents\n").unwrap();
+        }
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(format!("-vout={}", fname_to_string(&out)))
+            .arg(format!("-vapp={}", fname_to_string(&app)))
+            .arg(format!("-vre={}", fname_to_string(&re)))
+            .arg(prog)
+            .assert()
+            .success();
+        assert_eq!(read_to_string(&out).unwrap(), "x\ny\n");
+        assert_eq!(
+            read_to_string(&app).unwrap(),
+            "some older and longer contents\nz\n"
+        );
+        // Redirecting with > after a close truncates the file again.
+        assert_eq!(read_to_string(&re).unwrap(), "2\n");
+    }
+}
+
+#[test]
+fn redirect_to_shared_stderr() {
+    // With stdout and stderr pointing at the same file, printing to /dev/stderr must not truncate
+    // or overwrite what was already written to stdout.
+    let prog = r#"BEGIN { print "a"; print "b" > "/dev/stderr"; print "c" > "/dev/stdout" }"#;
+    for backend_arg in BACKEND_ARGS {
+        let tmp = tempdir().unwrap();
+        let out_path = tmp.path().join("out");
+        let out = File::create(&out_path).unwrap();
+        let status = std::process::Command::new(assert_cmd::cargo::cargo_bin("frawk"))
+            .arg(String::from(*backend_arg))
+            .arg(prog)
+            .stdout(out.try_clone().unwrap())
+            .stderr(out)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        unordered_output_equals(&std::fs::read(&out_path).unwrap(), b"a\nb\nc\n");
+    }
+}
+
+#[test]
+fn max_open_files() {
+    let tmpdir = tempdir().unwrap();
+    let dir = fname_to_string(tmpdir.path());
+    let prog = format!(
+        r#"BEGIN {{
+    for (r = 0; r < 3; r++) for (i = 0; i < 10; i++) print r > ("{dir}/f" i);
+    for (i = 0; i < 10; i++) close("{dir}/f" i);
+    for (r = 0; r < 3; r++) for (i = 0; i < 10; i++) {{ getline l < ("{dir}/f" i); s = s l }}
+    print s;
+}}"#,
+        dir = dir
+    );
+    for backend_arg in BACKEND_ARGS {
+        // Files closed to stay under the limit are reopened where they left off.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .args(["--max-open-files", "3"])
+            .arg(&prog)
+            .assert()
+            .success()
+            .stdout(format!(
+                "{}{}{}\n",
+                "0".repeat(10),
+                "1".repeat(10),
+                "2".repeat(10)
+            ));
+    }
+}
+
+#[test]
+fn piped_output() {
+    let prog = r#"BEGIN {
+    print "b" | "sort"; print "a" | "sort"; print close("sort");
+    print "x" | "cat >/dev/null; exit 3"; print close("cat >/dev/null; exit 3");
+    print close("never opened");
+    print "y" | "sort -r"; print "z" | "sort -r"; print "end";
+}"#;
+    for backend_arg in BACKEND_ARGS {
+        // Commands that are never closed are waited for at exit, after stdout is flushed.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(prog)
+            .assert()
+            .stdout("a\nb\n0\n3\n-1\nend\nz\ny\n");
+    }
+}
+
+#[test]
+fn getline_from_command() {
+    let prog = r#"BEGIN {
+    c = "printf 'a b\\nc\\n'; exit 4";
+    while ((c | getline l) > 0) print "got", l;
+    print (c | getline l), l; print close(c); print close(c);
+    print ("true" | getline); print close("true");
+}"#;
+    for backend_arg in BACKEND_ARGS {
+        // A failing command's output is still read, and its status is reported by close.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(prog)
+            .assert()
+            .stdout("got a b\ngot c\n0 c\n4\n-1\n0\n0\n");
+    }
+}
+
+#[test]
+fn inet_files() {
+    use std::io::{Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        for _ in BACKEND_ARGS {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut req = String::new();
+            conn.read_to_string(&mut req).unwrap();
+            conn.write_all(req.to_uppercase().as_bytes()).unwrap();
+        }
+    });
+    let prog = format!(
+        r#"BEGIN {{
+    s = "/inet/tcp/0/127.0.0.1/{}";
+    print "hello" > s; print "world" > s; close(s);
+    while ((getline line < s) > 0) print "got", line;
+    close(s);
+}}"#,
+        port
+    );
+    for backend_arg in BACKEND_ARGS {
+        // Closing the output side sends the end of the request; the response is read afterwards.
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(&prog)
+            .assert()
+            .success()
+            .stdout("got HELLO\ngot WORLD\n");
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn flush_output() {
+    let prog = r#"BEGIN {
+    print "a"; system("echo b");
+    print "c"; fflush(); print "d" | "cat"; r = fflush("cat"); close("cat");
+    print r, fflush("never opened"), fflush();
+}"#;
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(prog)
+            .assert()
+            .stdout("a\nb\nc\nd\n0 -1 0\n");
+    }
+}
+
+#[test]
+fn getline_from_file() {
+    let (_tmp, input) = file_from_string("input", "l1 a\nl2 b c\n");
+    let prog = r#"BEGIN {
+    r = (getline x < f); print r, x, NF;
+    r = (getline < f); print r, $0, NF, $2;
+    r = (getline < f); print r, $0, NF;
+    r = (getline x < f); print r, x;
+    close(f); while ((getline a[n++] < f) > 0) {} print n, a[0], a[1];
+    r = (getline y < "/does/not/exist"); print r, "[" y "]";
+    r = (getline < "/does/not/exist"); print r, $0;
+}"#;
+    for backend_arg in BACKEND_ARGS {
+        Command::cargo_bin("frawk")
+            .unwrap()
+            .arg(String::from(*backend_arg))
+            .arg(format!("-vf={}", fname_to_string(&input)))
+            .arg(prog)
+            .assert()
+            .stdout(
+                "1 l1 a 0\n1 l2 b c 3 b\n0 l2 b c 3\n0 l1 a\n3 l1 a l2 b c\n-1 []\n-1 l2 b c\n",
+            );
+    }
+}
+
 fn fname_to_string(path: &std::path::Path) -> String {
     path.to_owned().into_os_string().into_string().unwrap()
 }
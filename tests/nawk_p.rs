@@ -16,10 +16,14 @@ use std::fs::{read_to_string, File};
 use std::io::Write;
 use tempfile::tempdir;
 
-#[cfg(feature = "llvm_backend")]
+#[cfg(all(feature = "llvm_backend", feature = "cranelift_backend"))]
 const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm", "-Bcranelift"];
-#[cfg(not(feature = "llvm_backend"))]
+#[cfg(all(feature = "llvm_backend", not(feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm"];
+#[cfg(all(not(feature = "llvm_backend"), feature = "cranelift_backend"))]
 const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bcranelift"];
+#[cfg(not(any(feature = "llvm_backend", feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp"];
 
 const COUNTRIES: &str = r#"Russia	8650	262	Asia
 Canada	3852	24	North America
@@ -19,10 +19,14 @@ fn numbers_str(n: usize) -> (String, String) {
 
 const N: usize = 10_000;
 
-#[cfg(feature = "llvm_backend")]
+#[cfg(all(feature = "llvm_backend", feature = "cranelift_backend"))]
 const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm", "-Bcranelift"];
-#[cfg(not(feature = "llvm_backend"))]
+#[cfg(all(feature = "llvm_backend", not(feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bllvm"];
+#[cfg(all(not(feature = "llvm_backend"), feature = "cranelift_backend"))]
 const BACKEND_ARGS: &[&str] = &["-Binterp", "-Bcranelift"];
+#[cfg(not(any(feature = "llvm_backend", feature = "cranelift_backend")))]
+const BACKEND_ARGS: &[&str] = &["-Binterp"];
 
 #[cfg(not(target_os = "windows"))]
 #[test]